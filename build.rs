@@ -4,14 +4,43 @@ use std::process::Command;
 
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/crt0.c");
     Command::new("gcc")
-        .args(&["src/crt0.c", "-fPIC", "-c", "-o"])
-        .arg(&format!("{}/crt0.o", out_dir))
+        // the runtime keeps its frame pointers (so that stacks can be walked through
+        // it, as they can through generated code) and supports CET (so that it
+        // doesn't stop '--harden' programs from using it)
+        .args([
+            "src/crt0.c",
+            "-fPIC",
+            "-fno-omit-frame-pointer",
+            "-fcf-protection=full",
+            "-pthread",
+            "-c",
+            "-o",
+        ])
+        .arg(format!("{}/crt0.o", out_dir))
         .status()
         .unwrap();
     Command::new("ar")
-        .args(&["-cvq", "libslangrt.a", "crt0.o"])
-        .current_dir(&Path::new(&out_dir))
+        .args(["-crs", "libslangrt.a", "crt0.o"])
+        .current_dir(Path::new(&out_dir))
+        .status()
+        .unwrap();
+    // code compiled in memory calls into a copy of the runtime that's loaded
+    // into the process, which has no 'main' (its own symbols are bound within
+    // it, so that they can't clash with the host's)
+    Command::new("gcc")
+        .args([
+            "src/crt0.c",
+            "-DSLANG_JIT",
+            "-shared",
+            "-fPIC",
+            "-fno-omit-frame-pointer",
+            "-Wl,-Bsymbolic",
+            "-pthread",
+            "-o",
+        ])
+        .arg(format!("{}/libslangjit.so", out_dir))
         .status()
         .unwrap();
 }
@@ -0,0 +1,231 @@
+use super::frontend::{self, quote};
+use super::{backend, ir, opt, read, write, CompilerOptions};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use termion::{color, style};
+
+// what a program is compiled to
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Assembly,
+    C,
+    Wasm,
+}
+
+impl Target {
+    fn name(self) -> &'static str {
+        match self {
+            Target::Assembly => "x86-64",
+            Target::C => "c",
+            Target::Wasm => "wasm",
+        }
+    }
+}
+
+// something written for a program, each kind in a directory of its own
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Artifact {
+    Assembly,
+    C,
+    Wasm,
+    Ir,
+    SourceMap,
+    Object,
+    Executable,
+}
+
+impl Artifact {
+    fn name(self) -> &'static str {
+        match self {
+            Artifact::Assembly => "assembly",
+            Artifact::C => "c",
+            Artifact::Wasm => "wasm",
+            Artifact::Ir => "ir",
+            Artifact::SourceMap => "source-map",
+            Artifact::Object => "object",
+            Artifact::Executable => "executable",
+        }
+    }
+
+    // where it goes, relative to the output directory
+    fn path(self, stem: &str) -> PathBuf {
+        let (directory, extension) = match self {
+            Artifact::Assembly => ("asm", ".s"),
+            Artifact::C => ("c", ".c"),
+            Artifact::Wasm => ("wasm", ".wasm"),
+            Artifact::Ir => ("ir", ".ir"),
+            Artifact::SourceMap => ("map", ".json"),
+            Artifact::Object => ("obj", ".o"),
+            Artifact::Executable => ("bin", ""),
+        };
+        Path::new(directory).join(format!("{}{}", stem, extension))
+    }
+}
+
+fn error(message: String) -> String {
+    format!(
+        "{}{}error{}{}: {}",
+        style::Bold,
+        color::Fg(color::Red),
+        color::Fg(color::Reset),
+        style::Reset,
+        message
+    )
+}
+
+// everything '--out-dir' has written for a program so far, which is listed in
+// the manifest it writes alongside them
+pub struct Artifacts {
+    directory: PathBuf,
+    input: PathBuf,
+    target: Target,
+    written: Vec<(Artifact, PathBuf)>,
+}
+
+impl Artifacts {
+    fn stem(&self) -> String {
+        match self.input.file_stem() {
+            Some(stem) => stem.to_string_lossy().into_owned(),
+            None => "program".to_string(),
+        }
+    }
+
+    // where an artifact goes (making its directory if it isn't there yet)
+    fn prepare(&self, artifact: Artifact) -> Result<PathBuf, String> {
+        let path = self.directory.join(artifact.path(&self.stem()));
+        if let Some(parent) = path.parent() {
+            if fs::create_dir_all(parent).is_err() {
+                return Err(error(format!(
+                    "failed to create directory '{}{}{}'",
+                    style::Bold,
+                    parent.display(),
+                    style::Reset
+                )));
+            }
+        }
+        Ok(path)
+    }
+
+    fn add(&mut self, artifact: Artifact, contents: &[u8]) -> Result<(), String> {
+        let path = self.prepare(artifact)?;
+        write(&path, contents)?;
+        self.written.push((artifact, path));
+        Ok(())
+    }
+
+    fn find(&self, artifact: Artifact) -> Option<&Path> {
+        self.written
+            .iter()
+            .find(|(written, _)| *written == artifact)
+            .map(|(_, path)| path.as_path())
+    }
+
+    // the executable, once it's been linked
+    pub fn executable(&self) -> Option<&Path> {
+        self.find(Artifact::Executable)
+    }
+
+    // assembles (or compiles) the generated code into an object, then links
+    // that (along with the runtime, unless it's C, which carries its own) into
+    // an executable. A WebAssembly module doesn't need linking
+    pub fn link(&mut self) -> Result<(), String> {
+        let (compiler, flags, source): (_, &[&str], _) = match self.target {
+            Target::Assembly => ("gcc", &[], Artifact::Assembly),
+            Target::C => ("cc", &["-std=c99", "-O2"], Artifact::C),
+            Target::Wasm => return Ok(()),
+        };
+        let source = format!("{}", self.find(source).unwrap().display());
+        let object = self.prepare(Artifact::Object)?;
+        let object_name = format!("{}", object.display());
+        match Command::new(compiler)
+            .args(flags)
+            .args(["-c", "-o", &object_name, &source])
+            .status()
+        {
+            Ok(status) if status.success() => self.written.push((Artifact::Object, object)),
+            _ => return Err(error("failed to assemble generated code".to_string())),
+        }
+        let executable = self.prepare(Artifact::Executable)?;
+        let mut linker = Command::new(compiler);
+        linker.args(["-o", &format!("{}", executable.display()), &object_name]);
+        if self.target == Target::Assembly {
+            linker.args([concat!("-L", env!("OUT_DIR")), "-lslangrt", "-pthread"]);
+        }
+        match linker.status() {
+            Ok(status) if status.success() => self.written.push((Artifact::Executable, executable)),
+            _ => return Err(error("failed to link generated code".to_string())),
+        }
+        Ok(())
+    }
+
+    // lists what's been written (relative to the output directory), as JSON
+    pub fn manifest(&self) -> String {
+        let artifacts = self
+            .written
+            .iter()
+            .map(|(artifact, path)| {
+                let relative = path.strip_prefix(&self.directory).unwrap_or(path);
+                format!(
+                    "    {{\"kind\": \"{}\", \"path\": {}}}",
+                    artifact.name(),
+                    quote(&format!("{}", relative.display()))
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\n  \"input\": {},\n  \"target\": \"{}\",\n  \"artifacts\": [\n{}\n  ]\n}}\n",
+            quote(&format!("{}", self.input.display())),
+            self.target.name(),
+            artifacts.join(",\n")
+        )
+    }
+
+    // writes the manifest to 'manifest.json' in the output directory (so it
+    // describes the last program compiled there)
+    pub fn write_manifest(&self) -> Result<PathBuf, String> {
+        let path = self.directory.join("manifest.json");
+        write(&path, self.manifest().as_bytes())?;
+        Ok(path)
+    }
+}
+
+// compiles a program into a directory, writing the lowered program and the
+// generated code (and for assembly, the source map tying its labels back to the
+// program) each into a directory of their own, named after the input
+pub fn compile_to(
+    input: &Path,
+    directory: &Path,
+    options: &CompilerOptions,
+    target: Target,
+) -> Result<(Artifacts, Vec<String>), String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    let ast = opt::optimise(ast, options);
+    let block = ir::lower(ast);
+    let mut artifacts = Artifacts {
+        directory: directory.to_path_buf(),
+        input: input.to_path_buf(),
+        target,
+        written: vec![],
+    };
+    artifacts.add(Artifact::Ir, block.to_string().as_bytes())?;
+    match target {
+        Target::Assembly => {
+            let code = backend::generate(block, options);
+            artifacts.add(Artifact::Assembly, code.to_string().as_bytes())?;
+            artifacts.add(Artifact::SourceMap, code.source_map().as_bytes())?;
+        }
+        Target::C => {
+            artifacts.add(
+                Artifact::C,
+                backend::c::generate(block, options)?.as_bytes(),
+            )?;
+        }
+        Target::Wasm => {
+            artifacts.add(Artifact::Wasm, &backend::wasm::generate(block, options)?)?;
+        }
+    }
+    Ok((artifacts, warnings))
+}
@@ -0,0 +1,955 @@
+use super::super::frontend::ast::{BinOp, Free};
+use super::super::frontend::Location as SourceLocation;
+use super::super::ir::{self, Atom, Binding, Block, Op};
+use super::super::{CompilerOptions, IntWidth, Mangling};
+use super::mangle::{mangle, Symbol};
+
+use termion::{color, style};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// a program compiles to a single C99 file, with a small runtime of its own in
+// front of it, so it builds anywhere there's a C compiler ('cc -o p p.c')
+//
+// every value is a 'value' (an 'int64_t'), as in the native code: ints and bools
+// are untagged, a float is the bits of a 'double', and anything on the heap is a pointer converted to an integer.
+// a closure points to a struct holding a pointer to its code and a pointer to
+// its environment (an array of values), and the code for every function takes
+// its argument and its environment, so every closure is called the same way
+const PRELUDE: &str = r#"#include <inttypes.h>
+#include <setjmp.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef int64_t value;
+
+typedef value (*slang_code)(value, value *);
+
+typedef struct slang_closure {
+  slang_code code;
+  value *env;
+} slang_closure;
+
+#define SLANG_DELAYED 0
+#define SLANG_FORCING 1
+#define SLANG_FORCED 2
+
+// a lazy value is a closure that ignores its argument, with a tag saying
+// whether it's been forced (and what it computed if it has)
+typedef struct slang_thunk {
+  value tag;
+  slang_code code;
+  value *env;
+  value forced;
+} slang_thunk;
+
+// where a runtime error can happen
+typedef struct slang_location {
+  const char *filename;
+  long line;
+  long column;
+} slang_location;
+
+// a string is its length followed by its bytes (with nothing after them)
+typedef struct slang_string {
+  int64_t length;
+  char bytes[];
+} slang_string;
+
+// an array is its length followed by its elements
+typedef struct slang_array {
+  int64_t length;
+  value elements[];
+} slang_array;
+
+#define SLANG_POINTER(v) ((void *)(intptr_t)(v))
+#define SLANG_VALUE(p) ((value)(intptr_t)(p))
+#define SLANG_FIELD(v, i) (((value *)SLANG_POINTER(v))[i])
+
+// a condition the program expects to be true (or false), for the compilers
+// that can use the hint
+#ifdef __GNUC__
+#define SLANG_EXPECT(c, likely) __builtin_expect(!!(c), likely)
+#else
+#define SLANG_EXPECT(c, likely) (c)
+#endif
+
+"#;
+
+// the runtime's functions, which come after 'slang_wrap' (they're all 'inline',
+// so that the ones a program doesn't use aren't warned about)
+const RUNTIME: &str = r#"static inline void slang_fail(const slang_location *at, const char *message) {
+  fflush(stdout);
+  fprintf(stderr, "%s: line %ld: column %ld: runtime error: %s\n", at->filename,
+          at->line, at->column, message);
+  exit(1);
+}
+
+// the heap is never freed, as in the native runtime
+static inline void *slang_alloc(size_t size) {
+  void *p = malloc(size ? size : 1);
+  if (!p) {
+    fflush(stdout);
+    fprintf(stderr, "runtime error: out of memory\n");
+    exit(1);
+  }
+  return p;
+}
+
+static inline value *slang_env(size_t size) {
+  return slang_alloc(size * sizeof(value));
+}
+
+static inline value slang_new_pair(value left, value right) {
+  value *pair = slang_env(2);
+  pair[0] = left;
+  pair[1] = right;
+  return SLANG_VALUE(pair);
+}
+
+static inline value slang_new_ref(value contents) {
+  value *ref = slang_env(1);
+  ref[0] = contents;
+  return SLANG_VALUE(ref);
+}
+
+static inline value slang_new_closure(slang_code code, value *env) {
+  slang_closure *closure = slang_alloc(sizeof(slang_closure));
+  closure->code = code;
+  closure->env = env;
+  return SLANG_VALUE(closure);
+}
+
+static inline value slang_apply(value f, value arg) {
+  slang_closure *closure = SLANG_POINTER(f);
+  return closure->code(arg, closure->env);
+}
+
+static inline value slang_new_thunk(slang_code code, value *env) {
+  slang_thunk *thunk = slang_alloc(sizeof(slang_thunk));
+  thunk->tag = SLANG_DELAYED;
+  thunk->code = code;
+  thunk->env = env;
+  thunk->forced = 0;
+  return SLANG_VALUE(thunk);
+}
+
+static inline value slang_force(value t, const slang_location *at) {
+  slang_thunk *thunk = SLANG_POINTER(t);
+  if (thunk->tag == SLANG_FORCED)
+    return thunk->forced;
+  if (thunk->tag == SLANG_FORCING)
+    slang_fail(at, "lazy value forced while it was being computed");
+  thunk->tag = SLANG_FORCING;
+  thunk->forced = thunk->code(0, thunk->env);
+  thunk->tag = SLANG_FORCED;
+  return thunk->forced;
+}
+
+static inline value slang_new_string(const char *bytes, int64_t length) {
+  slang_string *string = slang_alloc(sizeof(slang_string) + length);
+  string->length = length;
+  memcpy(string->bytes, bytes, length);
+  return SLANG_VALUE(string);
+}
+
+static inline value slang_concat(value left, value right) {
+  slang_string *l = SLANG_POINTER(left), *r = SLANG_POINTER(right);
+  slang_string *string = slang_alloc(sizeof(slang_string) + l->length + r->length);
+  string->length = l->length + r->length;
+  memcpy(string->bytes, l->bytes, l->length);
+  memcpy(string->bytes + l->length, r->bytes, r->length);
+  return SLANG_VALUE(string);
+}
+
+// strings are compared by their bytes, and a string comes before any longer
+// string it starts
+static inline value slang_compare(value left, value right) {
+  slang_string *l = SLANG_POINTER(left), *r = SLANG_POINTER(right);
+  int order = memcmp(l->bytes, r->bytes, l->length < r->length ? l->length : r->length);
+  if (order == 0)
+    return (l->length > r->length) - (l->length < r->length);
+  return order < 0 ? -1 : 1;
+}
+
+static inline double slang_double(value v) {
+  double d;
+  memcpy(&d, &v, sizeof d);
+  return d;
+}
+
+static inline value slang_float(double d) {
+  value v;
+  memcpy(&v, &d, sizeof v);
+  return v;
+}
+
+static inline value slang_print_string(value s) {
+  slang_string *string = SLANG_POINTER(s);
+  fwrite(string->bytes, 1, string->length, stdout);
+  return 0;
+}
+
+static inline value slang_new_array(value length, value init, const slang_location *at) {
+  if (length < 0)
+    slang_fail(at, "negative array length");
+  slang_array *array = slang_alloc(sizeof(slang_array) + (size_t)length * sizeof(value));
+  array->length = length;
+  for (value i = 0; i < length; i++)
+    array->elements[i] = init;
+  return SLANG_VALUE(array);
+}
+
+// where an element of an array is, once its index has been checked (as an
+// unsigned number, so that a negative index is out of bounds too)
+static inline value *slang_element(value a, value i, const slang_location *at) {
+  slang_array *array = SLANG_POINTER(a);
+  if ((uint64_t)i >= (uint64_t)array->length)
+    slang_fail(at, "array index out of bounds");
+  return &array->elements[i];
+}
+
+// one part of a list that isn't empty (the empty list is 0, and any other is
+// a pair of its head and its tail)
+static inline value slang_list_part(value l, int part, const slang_location *at) {
+  if (l == 0)
+    slang_fail(at, part == 0 ? "head of empty list" : "tail of empty list");
+  return SLANG_FIELD(l, part);
+}
+
+// C leaves dividing the smallest integer by -1 undefined, so dividing by -1 is
+// negation instead (and the remainder is always 0)
+static inline value slang_div(value left, value right, const slang_location *at) {
+  if (right == 0)
+    slang_fail(at, "division by zero");
+  if (right == -1) {
+    if (left == SLANG_MIN)
+      slang_fail(at, "overflow in division");
+    return -left;
+  }
+  return left / right;
+}
+
+static inline value slang_mod(value left, value right, const slang_location *at) {
+  if (right == 0)
+    slang_fail(at, "division by zero");
+  if (right == -1)
+    return 0;
+  return left % right;
+}
+
+// the 'try's that are running, innermost first, each with where to go back to
+// when an exception is raised (the exception itself is left in 'slang_raised')
+typedef struct slang_handler {
+  struct slang_handler *previous;
+  jmp_buf target;
+} slang_handler;
+
+static slang_handler *slang_handlers = NULL;
+static value slang_raised = 0;
+
+static inline value slang_try(value body, value handler) {
+  slang_handler h;
+  h.previous = slang_handlers;
+  slang_handlers = &h;
+  if (setjmp(h.target) == 0) {
+    value result = slang_apply(body, 0);
+    slang_handlers = h.previous;
+    return result;
+  }
+  slang_handlers = h.previous;
+  return slang_apply(handler, slang_raised);
+}
+
+static inline value slang_raise(value exception, const slang_location *at) {
+  if (!slang_handlers) {
+    fflush(stdout);
+    fprintf(stderr, "%s: line %ld: column %ld: runtime error: uncaught exception %" PRId64 "\n",
+            at->filename, at->line, at->column, exception);
+    exit(1);
+  }
+  slang_raised = exception;
+  longjmp(slang_handlers->target, 1);
+}
+
+static FILE *slang_script = NULL;
+static int slang_seeded = 0;
+static uint64_t slang_state = 0;
+
+// reads a number for '?' in the same way as the native runtime: from
+// 'SLANG_INPUT', from a generator seeded with 'SLANG_SEED', or from stdin
+static inline value slang_what(void) {
+  int64_t got = 0;
+  if (slang_script) {
+    if (fscanf(slang_script, "%" SCNd64, &got) != 1) {
+      fprintf(stderr, "ran out of input\n");
+      exit(1);
+    }
+    return slang_wrap((uint64_t)got);
+  }
+  if (slang_seeded) {
+    uint64_t z = (slang_state += UINT64_C(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)) * UINT64_C(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)) * UINT64_C(0x94d049bb133111eb);
+    return (value)((z ^ (z >> 31)) % 100);
+  }
+  printf("> ");
+  fflush(stdout);
+  int result = scanf("%" SCNd64, &got);
+  if (result == EOF) {
+    fprintf(stderr, "stdin died :(\n");
+    exit(1);
+  }
+  if (result == 0) {
+    int c;
+    while ((c = fgetc(stdin)) != '\n' && c != EOF)
+      ;
+  }
+  return slang_wrap((uint64_t)got);
+}
+"#;
+
+// ints narrower than 64 bits are kept sign-extended to 64 bits, as they are in
+// the native code (arithmetic is done on unsigned integers, as signed overflow
+// is undefined in C)
+fn wrap(int_width: IntWidth) -> &'static str {
+    match int_width {
+        IntWidth::I64 => {
+            "#define SLANG_MIN INT64_MIN\n\n\
+             static inline value slang_wrap(uint64_t n) { return (value)n; }\n\n"
+        }
+        IntWidth::I32 => {
+            "#define SLANG_MIN INT32_MIN\n\n\
+             static inline value slang_wrap(uint64_t n) { return (value)(int32_t)(uint32_t)n; }\n\n"
+        }
+    }
+}
+
+// a string as a C string literal
+fn literal(s: &str) -> String {
+    let mut literal = "\"".to_string();
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            ' '..='~' => literal.push(c),
+            _ => {
+                let mut bytes = [0; 4];
+                for byte in c.encode_utf8(&mut bytes).bytes() {
+                    let _ = write!(literal, "\\{:03o}", byte);
+                }
+            }
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+// a name from the program that can go in a C identifier (names are numbered as
+// well, so dropping anything that can't go in one never makes two the same)
+fn identifier(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .collect()
+}
+
+// a function compiled from the program, which assigns the value of its body to
+// 'result' (every variable the function binds gets a local of its own, as
+// variables are only bound once in a program)
+struct Function {
+    param: String,
+    // how many locals have been named so far
+    count: usize,
+    locals: Vec<String>,
+    vars: HashMap<String, String>,
+    // whether the value being computed is the function's result
+    tail: bool,
+    indent: usize,
+    body: String,
+}
+
+impl Function {
+    fn new(v: String) -> Function {
+        let mut f = Function {
+            param: String::new(),
+            count: 0,
+            locals: vec![],
+            vars: HashMap::new(),
+            tail: true,
+            indent: 1,
+            body: String::new(),
+        };
+        f.param = f.local(&v);
+        f.vars.insert(v, f.param.clone());
+        f
+    }
+
+    fn line(&mut self, line: String) {
+        for _ in 0..self.indent {
+            self.body.push_str("  ");
+        }
+        self.body.push_str(&line);
+        self.body.push('\n');
+    }
+
+    fn local(&mut self, v: &str) -> String {
+        self.count += 1;
+        format!("v{}_{}", self.count, identifier(ir::source_name(v)))
+    }
+
+    fn temporary(&mut self) -> String {
+        self.count += 1;
+        let local = format!("tmp{}", self.count);
+        self.locals.push(local.clone());
+        local
+    }
+
+    fn bind(&mut self, v: String) -> String {
+        let local = self.local(&v);
+        self.locals.push(local.clone());
+        self.vars.insert(v, local.clone());
+        local
+    }
+
+    fn atom(&self, atom: &Atom) -> String {
+        match *atom {
+            Atom::Unit => "0".to_string(),
+            Atom::Int(i64::MIN) => "INT64_MIN".to_string(),
+            Atom::Int(i) => format!("INT64_C({})", i),
+            Atom::Float(f) => self.atom(&Atom::Int(f.to_bits() as i64)),
+            Atom::Bool(b) => format!("{}", b as i64),
+            Atom::Var(ref v) => self.vars[v].clone(),
+        }
+    }
+}
+
+// how the runtime lays out each kind of object built around a function
+#[derive(Copy, Clone)]
+enum Kind {
+    Closure,
+    // the environment also starts with the closure itself
+    Recursive,
+    Thunk,
+}
+
+struct Generator {
+    int_width: IntWidth,
+    // the functions compiled from the program, in the order they're declared
+    // (they're reserved before they're compiled, so that a function's name is
+    // known while its body is)
+    functions: Vec<Option<Function>>,
+    // what each function is named after, as soon as it's reserved
+    names: Vec<String>,
+    mangling: Mangling,
+    locations: Vec<String>,
+}
+
+impl Generator {
+    // the top level is never mangled, as nothing outside the file refers to it
+    fn function(&self, index: usize) -> String {
+        let name = &self.names[index];
+        match self.mangling {
+            Mangling::Slang if index > 0 => {
+                let before = self.names[..index].iter().filter(|n| *n == name);
+                mangle(&Symbol::new(name, before.count()))
+            }
+            _ => format!("f{}_{}", index, identifier(name)),
+        }
+    }
+
+    // the location of a runtime error, as a pointer to a constant
+    fn location(&mut self, location: &SourceLocation) -> String {
+        let constant = format!(
+            "{{{}, {}, {}}}",
+            literal(location.filename()),
+            location.line(),
+            location.column()
+        );
+        let index = match self.locations.iter().position(|l| *l == constant) {
+            Some(index) => index,
+            None => {
+                self.locations.push(constant);
+                self.locations.len() - 1
+            }
+        };
+        format!("&location{}", index)
+    }
+}
+
+fn unsupported(what: &str) -> String {
+    format!(
+        "{}{}error{}{}: {} aren't supported by the C backend",
+        style::Bold,
+        color::Fg(color::Red),
+        color::Fg(color::Reset),
+        style::Reset,
+        what
+    )
+}
+
+fn lower_binop(f: &Function, op: BinOp, left: &Atom, right: &Atom) -> String {
+    let (left, right) = (f.atom(left), f.atom(right));
+    match op {
+        BinOp::Add => format!("slang_wrap((uint64_t){} + (uint64_t){})", left, right),
+        BinOp::Sub => format!("slang_wrap((uint64_t){} - (uint64_t){})", left, right),
+        BinOp::Mul => format!("slang_wrap((uint64_t){} * (uint64_t){})", left, right),
+        BinOp::Lt => format!("{} < {}", left, right),
+        // pointers are compared by address, like everything else
+        BinOp::Eq | BinOp::PhysEq => format!("{} == {}", left, right),
+        BinOp::Concat => format!("slang_concat({}, {})", left, right),
+        BinOp::Compare => format!("slang_compare({}, {})", left, right),
+        BinOp::FloatAdd | BinOp::FloatSub | BinOp::FloatMul | BinOp::FloatDiv => format!(
+            "slang_float(slang_double({}) {} slang_double({}))",
+            left,
+            match op {
+                BinOp::FloatAdd => "+",
+                BinOp::FloatSub => "-",
+                BinOp::FloatMul => "*",
+                _ => "/",
+            },
+            right
+        ),
+        BinOp::FloatLt => format!("slang_double({}) < slang_double({})", left, right),
+        BinOp::FloatEq => format!("slang_double({}) == slang_double({})", left, right),
+        // '/' and '%' are 'Op::Div' and 'Op::Mod', and '&&' and '||' are
+        // lowered to 'if'
+        _ => unreachable!(),
+    }
+}
+
+// compiles a function of its own for 'body', then builds the object that
+// holds it and its environment, leaving it in 'target'
+fn lower_closure(
+    f: &mut Function,
+    generator: &mut Generator,
+    kind: Kind,
+    name: String,
+    v: String,
+    body: Block,
+    target: &str,
+) -> Result<(), String> {
+    // sorted, so that the same program always gets the same closure layout
+    let mut fv = body
+        .fv()
+        .into_iter()
+        .filter(|&x| x != &v && (!matches!(kind, Kind::Recursive) || x != &name))
+        .cloned()
+        .collect::<Vec<_>>();
+    fv.sort();
+    let index = generator.functions.len();
+    generator.functions.push(None);
+    // named as the x86 backend names them, so that they're mangled the same
+    generator.names.push(match kind {
+        // renamed functions are still named after what they were given
+        Kind::Recursive => ir::source_name(&name).to_string(),
+        Kind::Closure => "<fun>".to_string(),
+        Kind::Thunk => "<lazy>".to_string(),
+    });
+    let mut g = Function::new(v);
+    let skip = match kind {
+        Kind::Recursive => {
+            let local = g.bind(name);
+            g.line(format!("{} = env[0];", local));
+            1
+        }
+        _ => 0,
+    };
+    for (i, x) in fv.iter().enumerate() {
+        let local = g.bind(x.clone());
+        g.line(format!("{} = env[{}];", local, skip + i));
+    }
+    emit(&mut g, generator, body, "result")?;
+    let code = generator.function(index);
+    generator.functions[index] = Some(g);
+    f.line("{".to_string());
+    f.indent += 1;
+    f.line(format!("value *captured = slang_env({});", skip + fv.len()));
+    for (i, x) in fv.iter().enumerate() {
+        let atom = f.atom(&Atom::Var(x.clone()));
+        f.line(format!("captured[{}] = {};", skip + i, atom));
+    }
+    match kind {
+        Kind::Thunk => f.line(format!("{} = slang_new_thunk({}, captured);", target, code)),
+        _ => f.line(format!(
+            "{} = slang_new_closure({}, captured);",
+            target, code
+        )),
+    }
+    if let Kind::Recursive = kind {
+        f.line(format!("captured[0] = {};", target));
+    }
+    f.indent -= 1;
+    f.line("}".to_string());
+    Ok(())
+}
+
+// compiles functions defined together, which share one environment: it starts
+// with the closures for each of them, then holds everything any of them
+// captures
+fn lower_group(
+    f: &mut Function,
+    generator: &mut Generator,
+    functions: Vec<(String, String, Box<Block>)>,
+) -> Result<(), String> {
+    let names = functions
+        .iter()
+        .map(|(name, _, _)| name.clone())
+        .collect::<Vec<_>>();
+    let mut fv = functions
+        .iter()
+        .flat_map(|(_, v, body)| body.fv().into_iter().filter(move |&x| x != v))
+        .filter(|&x| !names.contains(x))
+        .cloned()
+        .collect::<Vec<_>>();
+    fv.sort();
+    fv.dedup();
+    let skip = names.len();
+    let indices = names
+        .iter()
+        .map(|name| {
+            generator.functions.push(None);
+            generator.names.push(ir::source_name(name).to_string());
+            generator.functions.len() - 1
+        })
+        .collect::<Vec<_>>();
+    for (&index, (_, v, body)) in indices.iter().zip(functions) {
+        let mut g = Function::new(v);
+        for (i, name) in names.iter().enumerate() {
+            let local = g.bind(name.clone());
+            g.line(format!("{} = env[{}];", local, i));
+        }
+        for (i, x) in fv.iter().enumerate() {
+            let local = g.bind(x.clone());
+            g.line(format!("{} = env[{}];", local, skip + i));
+        }
+        emit(&mut g, generator, *body, "result")?;
+        generator.functions[index] = Some(g);
+    }
+    let targets = names
+        .into_iter()
+        .map(|name| f.bind(name))
+        .collect::<Vec<_>>();
+    f.line("{".to_string());
+    f.indent += 1;
+    f.line(format!("value *captured = slang_env({});", skip + fv.len()));
+    for (i, x) in fv.iter().enumerate() {
+        let atom = f.atom(&Atom::Var(x.clone()));
+        f.line(format!("captured[{}] = {};", skip + i, atom));
+    }
+    for (i, (target, &index)) in targets.iter().zip(indices.iter()).enumerate() {
+        let code = generator.function(index);
+        f.line(format!(
+            "{} = slang_new_closure({}, captured);",
+            target, code
+        ));
+        f.line(format!("captured[{}] = {};", i, target));
+    }
+    f.indent -= 1;
+    f.line("}".to_string());
+    Ok(())
+}
+
+// assigns the value of an operation to 'target' (or returns it, for a call in
+// tail position)
+fn lower(f: &mut Function, generator: &mut Generator, op: Op, target: &str) -> Result<(), String> {
+    let value = match op {
+        Op::Atom(atom) => f.atom(&atom),
+        Op::What => "slang_what()".to_string(),
+        Op::Neg(sub) => format!("slang_wrap(-(uint64_t){})", f.atom(&sub)),
+        Op::Not(sub) => format!("!{}", f.atom(&sub)),
+        Op::Binary(op, left, right) => lower_binop(f, op, &left, &right),
+        Op::Div(location, left, right) => format!(
+            "slang_div({}, {}, {})",
+            f.atom(&left),
+            f.atom(&right),
+            generator.location(&location)
+        ),
+        Op::Mod(location, left, right) => format!(
+            "slang_mod({}, {}, {})",
+            f.atom(&left),
+            f.atom(&right),
+            generator.location(&location)
+        ),
+        Op::If(condition, left, right, likely) => {
+            let condition = match likely {
+                Some(likely) => format!("SLANG_EXPECT({}, {})", f.atom(&condition), likely as u8),
+                None => f.atom(&condition),
+            };
+            f.line(format!("if ({}) {{", condition));
+            f.indent += 1;
+            emit(f, generator, *left, target)?;
+            f.indent -= 1;
+            f.line("} else {".to_string());
+            f.indent += 1;
+            emit(f, generator, *right, target)?;
+            f.indent -= 1;
+            f.line("}".to_string());
+            return Ok(());
+        }
+        // nothing in a loop is in tail position, as the loop still has to finish
+        Op::While(condition, body) => {
+            let tail = std::mem::replace(&mut f.tail, false);
+            let test = f.temporary();
+            f.line("for (;;) {".to_string());
+            f.indent += 1;
+            emit(f, generator, *condition, &test)?;
+            f.line(format!("if (!{})", test));
+            f.line("  break;".to_string());
+            emit(f, generator, *body, &test)?;
+            f.indent -= 1;
+            f.line("}".to_string());
+            f.tail = tail;
+            "0".to_string()
+        }
+        // the counter is compared with the upper bound after the body, so it
+        // never steps past it
+        Op::For(from, to, (v, body)) => {
+            let tail = std::mem::replace(&mut f.tail, false);
+            let (from, to) = (f.atom(&from), f.atom(&to));
+            let counter = f.bind(v);
+            let result = f.temporary();
+            f.line(format!("if ({} <= {}) {{", from, to));
+            f.indent += 1;
+            f.line(format!("for ({} = {};; {}++) {{", counter, from, counter));
+            f.indent += 1;
+            emit(f, generator, *body, &result)?;
+            f.line(format!("if ({} >= {})", counter, to));
+            f.line("  break;".to_string());
+            f.indent -= 1;
+            f.line("}".to_string());
+            f.indent -= 1;
+            f.line("}".to_string());
+            f.tail = tail;
+            "0".to_string()
+        }
+        Op::Pair(left, right) => format!("slang_new_pair({}, {})", f.atom(&left), f.atom(&right)),
+        Op::Fst(sub) | Op::Deref(sub) => format!("SLANG_FIELD({}, 0)", f.atom(&sub)),
+        Op::Snd(sub) => format!("SLANG_FIELD({}, 1)", f.atom(&sub)),
+        Op::Inl(sub) => format!("slang_new_pair(0, {})", f.atom(&sub)),
+        Op::Inr(sub) => format!("slang_new_pair(1, {})", f.atom(&sub)),
+        Op::Case(sub, (x, left), (y, right)) => {
+            let sub = f.atom(&sub);
+            f.line(format!("if (SLANG_FIELD({}, 0) == 0) {{", sub));
+            f.indent += 1;
+            let local = f.bind(x);
+            f.line(format!("{} = SLANG_FIELD({}, 1);", local, sub));
+            emit(f, generator, *left, target)?;
+            f.indent -= 1;
+            f.line("} else {".to_string());
+            f.indent += 1;
+            let local = f.bind(y);
+            f.line(format!("{} = SLANG_FIELD({}, 1);", local, sub));
+            emit(f, generator, *right, target)?;
+            f.indent -= 1;
+            f.line("}".to_string());
+            return Ok(());
+        }
+        Op::Ref(sub) => format!("slang_new_ref({})", f.atom(&sub)),
+        Op::Assign(left, right) => {
+            let (left, right) = (f.atom(&left), f.atom(&right));
+            f.line(format!("SLANG_FIELD({}, 0) = {};", left, right));
+            "0".to_string()
+        }
+        Op::MakeArray(location, length, sub) => format!(
+            "slang_new_array({}, {}, {})",
+            f.atom(&length),
+            f.atom(&sub),
+            generator.location(&location)
+        ),
+        Op::Index(location, left, right) => format!(
+            "*slang_element({}, {}, {})",
+            f.atom(&left),
+            f.atom(&right),
+            generator.location(&location)
+        ),
+        Op::Update(location, left, index, right) => {
+            let (left, index, right) = (f.atom(&left), f.atom(&index), f.atom(&right));
+            f.line(format!(
+                "*slang_element({}, {}, {}) = {};",
+                left,
+                index,
+                generator.location(&location),
+                right
+            ));
+            "0".to_string()
+        }
+        Op::Nil => "0".to_string(),
+        Op::Cons(left, right) => format!("slang_new_pair({}, {})", f.atom(&left), f.atom(&right)),
+        Op::Head(location, sub) => format!(
+            "slang_list_part({}, 0, {})",
+            f.atom(&sub),
+            generator.location(&location)
+        ),
+        Op::Tail(location, sub) => format!(
+            "slang_list_part({}, 1, {})",
+            f.atom(&sub),
+            generator.location(&location)
+        ),
+        Op::IsEmpty(sub) => format!("{} == 0", f.atom(&sub)),
+        // the computation is compiled as a function that ignores its argument
+        Op::Lazy(sub) => {
+            return lower_closure(
+                f,
+                generator,
+                Kind::Thunk,
+                "<lazy>".to_string(),
+                "%lazy".to_string(),
+                *sub,
+                target,
+            )
+        }
+        Op::Force(location, sub) => format!(
+            "slang_force({}, {})",
+            f.atom(&sub),
+            generator.location(&location)
+        ),
+        Op::Chan | Op::Send(..) | Op::Recv(..) => return Err(unsupported("channels")),
+        Op::Spawn(_) => return Err(unsupported("threads")),
+        Op::Raise(location, sub) => format!(
+            "slang_raise({}, {})",
+            f.atom(&sub),
+            generator.location(&location)
+        ),
+        Op::Try(body, handler) => format!("slang_try({}, {})", f.atom(&body), f.atom(&handler)),
+        Op::Str(string) => format!("slang_new_string({}, {})", literal(&string), string.len()),
+        Op::Print(sub) => format!("slang_print_string({})", f.atom(&sub)),
+        Op::Lambda(v, body) => {
+            return lower_closure(
+                f,
+                generator,
+                Kind::Closure,
+                "<fun>".to_string(),
+                v,
+                *body,
+                target,
+            )
+        }
+        // C compilers turn a call that's returned straight away into a jump
+        // when they can (although C doesn't promise it)
+        Op::App(left, right) if f.tail => {
+            let (left, right) = (f.atom(&left), f.atom(&right));
+            f.line(format!("return slang_apply({}, {});", left, right));
+            return Ok(());
+        }
+        Op::App(left, right) => format!("slang_apply({}, {})", f.atom(&left), f.atom(&right)),
+    };
+    f.line(format!("{} = {};", target, value));
+    Ok(())
+}
+
+// runs each binding in a block, then assigns the block's value to 'target'
+fn emit(
+    f: &mut Function,
+    generator: &mut Generator,
+    block: Block,
+    target: &str,
+) -> Result<(), String> {
+    // only the block's result can be in tail position
+    let tail = std::mem::replace(&mut f.tail, false);
+    for binding in block.bindings {
+        match binding {
+            Binding::Let(v, op) => {
+                let local = f.bind(v);
+                lower(f, generator, op, &local)?;
+            }
+            Binding::LetFun(name, v, body) => {
+                let local = f.bind(name.clone());
+                lower_closure(f, generator, Kind::Recursive, name, v, *body, &local)?;
+            }
+            Binding::LetRec(functions) => lower_group(f, generator, functions)?,
+            Binding::Do(op) => {
+                let local = f.temporary();
+                lower(f, generator, op, &local)?;
+            }
+        }
+    }
+    f.tail = tail;
+    lower(f, generator, block.result, target)
+}
+
+// compiles a program to C, or fails if it uses something the backend doesn't
+// support
+pub fn generate(block: Block, options: &CompilerOptions) -> Result<String, String> {
+    let mut generator = Generator {
+        int_width: options.int_width,
+        functions: vec![None],
+        names: vec!["top_level".to_string()],
+        mangling: options.mangling,
+        locations: vec![],
+    };
+    let mut entry = Function::new("%entry".to_string());
+    emit(&mut entry, &mut generator, block, "result")?;
+    generator.functions[0] = Some(entry);
+    let functions = generator
+        .functions
+        .iter()
+        .map(|function| function.as_ref().unwrap())
+        .collect::<Vec<_>>();
+
+    let mut c = "// generated by slang\n\n".to_string();
+    c.push_str(PRELUDE);
+    c.push_str(wrap(generator.int_width));
+    c.push_str(RUNTIME);
+    c.push('\n');
+    for (i, location) in generator.locations.iter().enumerate() {
+        let _ = writeln!(
+            c,
+            "static const slang_location location{} = {};",
+            i, location
+        );
+    }
+    if !generator.locations.is_empty() {
+        c.push('\n');
+    }
+    for i in 0..functions.len() {
+        let _ = writeln!(c, "static value {}(value, value *);", generator.function(i));
+    }
+    for (i, function) in functions.iter().enumerate() {
+        let _ = writeln!(
+            c,
+            "\nstatic value {}(value {}, value *env) {{",
+            generator.function(i),
+            function.param
+        );
+        c.push_str("  value result = 0;\n");
+        for local in function.locals.iter() {
+            let _ = writeln!(c, "  value {};", local);
+        }
+        c.push_str(&function.body);
+        c.push_str("  return result;\n}\n");
+    }
+    // where '?' reads from is only looked for where the program is allowed to
+    let capabilities = options.capabilities;
+    c.push_str("\nint main(void) {\n");
+    if capabilities.env && capabilities.filesystem {
+        c.push_str(
+            "  const char *input = getenv(\"SLANG_INPUT\");\n  \
+               if (input && !(slang_script = fopen(input, \"r\"))) {\n    \
+                 fprintf(stderr, \"failed to open '%s'\\n\", input);\n    \
+                 return 1;\n  \
+               }\n",
+        );
+    }
+    if capabilities.env && capabilities.random {
+        c.push_str(
+            "  const char *seed = getenv(\"SLANG_SEED\");\n  \
+               if (seed) {\n    \
+                 slang_seeded = 1;\n    \
+                 slang_state = strtoull(seed, NULL, 10);\n  \
+               }\n",
+        );
+    }
+    let _ = write!(
+        c,
+        "  printf(\"%\" PRId64 \"\\n\", {}(0, NULL));\n  \
+           return 0;\n\
+         }}\n",
+        generator.function(0)
+    );
+    Ok(c)
+}
@@ -0,0 +1,238 @@
+use super::{Function, GeneratedCode};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// how many unchanged lines are shown around each change
+const CONTEXT: usize = 3;
+
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+impl Line<'_> {
+    fn changed(&self) -> bool {
+        !matches!(*self, Line::Same(_))
+    }
+}
+
+// what each function is called in the diff: its name, along with which of the
+// functions with that name it is if there's more than one (counting from 0)
+fn names(code: &GeneratedCode) -> Vec<String> {
+    let mut counts = HashMap::new();
+    code.functions()
+        .iter()
+        .map(|function| {
+            let count = counts.entry(function.name()).or_insert(0);
+            *count += 1;
+            match *count - 1 {
+                0 => function.name().to_string(),
+                n => format!("{}#{}", function.name(), n),
+            }
+        })
+        .collect()
+}
+
+// the function's code with its labels renamed so that two compilations can be
+// compared: a call to a function is to its name (in '<' and '>'), and any other
+// label is numbered from 0 in the order it first appears in the function
+fn normalise(function: &Function, functions: &HashMap<String, String>) -> Vec<String> {
+    let mut labels: HashMap<String, String> = HashMap::new();
+    let mut text = String::new();
+    for instruction in function.instructions() {
+        write!(text, "{}", instruction).unwrap();
+    }
+    text.lines()
+        .map(|line| {
+            let mut normalised = String::new();
+            let mut rest = line;
+            while let Some(i) = rest.find(".L") {
+                let digits = rest[i + 2..]
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len() - i - 2);
+                normalised.push_str(&rest[..i]);
+                if digits == 0 {
+                    normalised.push_str(".L");
+                } else {
+                    let label = &rest[i..i + 2 + digits];
+                    let count = labels.len();
+                    let renamed =
+                        labels.entry(label.to_string()).or_insert_with(|| {
+                            match functions.get(label) {
+                                Some(name) => format!("<{}>", name),
+                                None => format!(".L{}", count),
+                            }
+                        });
+                    normalised.push_str(renamed);
+                }
+                rest = &rest[i + 2 + digits..];
+            }
+            normalised.push_str(rest);
+            normalised
+        })
+        .collect()
+}
+
+// every function in the program, by what it's called in the diff, with its
+// normalised code
+fn functions(code: &GeneratedCode) -> Vec<(String, Vec<String>)> {
+    let names = names(code);
+    let labels = code
+        .functions()
+        .iter()
+        .zip(names.iter())
+        .map(|(function, name)| (function.label().to_string(), name.clone()))
+        .collect::<HashMap<_, _>>();
+    code.functions()
+        .iter()
+        .zip(names)
+        .map(|(function, name)| (name, normalise(function, &labels)))
+        .collect()
+}
+
+// a line by line diff of 'left' and 'right' (the lines the two start and end
+// with are matched up first, and the longest common subsequence of whatever's
+// left between them is kept)
+fn lines<'a>(left: &'a [String], right: &'a [String]) -> Vec<Line<'a>> {
+    let prefix = left
+        .iter()
+        .zip(right.iter())
+        .take_while(|(l, r)| l == r)
+        .count();
+    let suffix = left[prefix..]
+        .iter()
+        .rev()
+        .zip(right[prefix..].iter().rev())
+        .take_while(|(l, r)| l == r)
+        .count();
+    let middle_left = &left[prefix..left.len() - suffix];
+    let middle_right = &right[prefix..right.len() - suffix];
+    let (n, m) = (middle_left.len(), middle_right.len());
+    // 'common[i][j]' is the length of the longest common subsequence of the
+    // lines from 'i' and 'j' on
+    let mut common = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if middle_left[i] == middle_right[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut lines = left[..prefix]
+        .iter()
+        .map(|line| Line::Same(line))
+        .collect::<Vec<_>>();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && middle_left[i] == middle_right[j] {
+            lines.push(Line::Same(&middle_left[i]));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(Line::Removed(&middle_left[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(&middle_right[j]));
+            j += 1;
+        }
+    }
+    lines.extend(
+        left[left.len() - suffix..]
+            .iter()
+            .map(|line| Line::Same(line)),
+    );
+    lines
+}
+
+// writes out the changed lines with a few of the unchanged ones around them,
+// with '...' where unchanged lines are left out
+fn write_lines(out: &mut String, lines: &[Line]) {
+    let changed = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.changed())
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let shown = |i: usize| {
+        changed
+            .iter()
+            .any(|&c| c + CONTEXT >= i && i + CONTEXT >= c)
+    };
+    let mut skipped = false;
+    for (i, line) in lines.iter().enumerate() {
+        if !shown(i) {
+            skipped = true;
+            continue;
+        }
+        if skipped {
+            writeln!(out, "...").unwrap();
+        }
+        skipped = false;
+        match *line {
+            Line::Same(line) => writeln!(out, " {}", line).unwrap(),
+            Line::Removed(line) => writeln!(out, "-{}", line).unwrap(),
+            Line::Added(line) => writeln!(out, "+{}", line).unwrap(),
+        }
+    }
+    if skipped {
+        writeln!(out, "...").unwrap();
+    }
+}
+
+// compares the code generated for two compilations function by function
+// (matching functions up by name), giving the diff of each function that
+// changed (the ones only in 'left' are all removed lines, and the ones only in
+// 'right' are all added ones) followed by a summary
+pub fn diff(left: &GeneratedCode, right: &GeneratedCode) -> String {
+    let left = functions(left);
+    let mut right = functions(right);
+    let mut out = String::new();
+    let (mut same, mut changed, mut removed) = (0, 0, 0);
+    for (name, code) in left.iter() {
+        match right.iter().position(|(other, _)| other == name) {
+            Some(i) => {
+                let (_, other) = right.remove(i);
+                let lines = lines(code, &other);
+                if lines.iter().any(Line::changed) {
+                    changed += 1;
+                    writeln!(out, "@@ {} @@", name).unwrap();
+                    write_lines(&mut out, &lines);
+                } else {
+                    same += 1;
+                }
+            }
+            None => {
+                removed += 1;
+                writeln!(out, "@@ {} (only in the first) @@", name).unwrap();
+                let lines = code
+                    .iter()
+                    .map(|line| Line::Removed(line))
+                    .collect::<Vec<_>>();
+                write_lines(&mut out, &lines);
+            }
+        }
+    }
+    for (name, code) in right.iter() {
+        writeln!(out, "@@ {} (only in the second) @@", name).unwrap();
+        let lines = code
+            .iter()
+            .map(|line| Line::Added(line))
+            .collect::<Vec<_>>();
+        write_lines(&mut out, &lines);
+    }
+    writeln!(
+        out,
+        "{} function{} changed, {} only in the first, {} only in the second, {} unchanged",
+        changed,
+        if changed == 1 { "" } else { "s" },
+        removed,
+        right.len(),
+        same
+    )
+    .unwrap();
+    out
+}
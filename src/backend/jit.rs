@@ -0,0 +1,680 @@
+use super::x86::{Instruction, Label, Location, Register, Xmm};
+use super::{GeneratedCode, Representation};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::sync::{Mutex, OnceLock};
+use termion::{color, style};
+
+extern "C" {
+    fn mmap(
+        address: *mut c_void,
+        length: usize,
+        protection: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn mprotect(address: *mut c_void, length: usize, protection: c_int) -> c_int;
+    fn munmap(address: *mut c_void, length: usize) -> c_int;
+    fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlerror() -> *const c_char;
+}
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const PROT_EXEC: c_int = 4;
+const MAP_PRIVATE: c_int = 2;
+const MAP_ANONYMOUS: c_int = 0x20;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+const RTLD_NOW: c_int = 2;
+
+const PAGE_SIZE: usize = 4096;
+
+// the copy of the runtime built for code compiled in memory (see 'build.rs')
+const RUNTIME: &str = concat!(env!("OUT_DIR"), "/libslangjit.so");
+
+type Run = unsafe extern "C" fn(usize, usize, i64, *mut *const c_char) -> i64;
+
+// the runtime is loaded the first time it's needed and kept for as long as the
+// process runs (its address is kept rather than the function pointer, which
+// isn't 'Sync')
+static LOADED: OnceLock<Result<usize, String>> = OnceLock::new();
+
+// the runtime keeps its state in globals and handles crashes for the whole
+// process, so only one program runs at a time
+static RUNNING: Mutex<()> = Mutex::new(());
+
+fn error(message: String) -> String {
+    format!(
+        "{}{}error{}{}: {}",
+        style::Bold,
+        color::Fg(color::Red),
+        color::Fg(color::Reset),
+        style::Reset,
+        message
+    )
+}
+
+fn last_error() -> String {
+    unsafe {
+        let message = dlerror();
+        if message.is_null() {
+            "unknown error".to_string()
+        } else {
+            CStr::from_ptr(message).to_string_lossy().into_owned()
+        }
+    }
+}
+
+fn runtime() -> Result<*mut c_void, String> {
+    let loaded = LOADED.get_or_init(|| {
+        let filename = CString::new(RUNTIME).unwrap();
+        let handle = unsafe { dlopen(filename.as_ptr(), RTLD_NOW) };
+        if handle.is_null() {
+            return Err(error(format!(
+                "failed to load the runtime ('{}')",
+                last_error()
+            )));
+        }
+        Ok(handle as usize)
+    });
+    loaded.clone().map(|handle| handle as *mut c_void)
+}
+
+fn symbol(handle: *mut c_void, name: &str) -> Result<usize, String> {
+    let symbol = CString::new(name).unwrap();
+    let address = unsafe { dlsym(handle, symbol.as_ptr()) };
+    if address.is_null() {
+        return Err(error(format!("failed to find '{}' in the runtime", name)));
+    }
+    Ok(address as usize)
+}
+
+// what a 32-bit displacement points to (relative to the end of its instruction)
+enum Target {
+    Label(Label),
+    // the stub that jumps on to a runtime function
+    Stub(&'static str),
+    // where a stub finds the runtime function's address
+    Slot(&'static str),
+}
+
+fn number(reg: Register) -> Result<u8, String> {
+    use self::Register::*;
+    Ok(match reg {
+        Rax => 0,
+        Rcx => 1,
+        Rdx => 2,
+        Rbx => 3,
+        Rsp => 4,
+        Rbp => 5,
+        Rsi => 6,
+        Rdi => 7,
+        R8 => 8,
+        R9 => 9,
+        R10 => 10,
+        R11 => 11,
+        R12 => 12,
+        R13 => 13,
+        R14 => 14,
+        R15 => 15,
+        Rip => return Err(unsupported("'%rip' as an operand")),
+    })
+}
+
+fn unsupported(what: &str) -> String {
+    error(format!(
+        "{} isn't supported in code compiled in memory",
+        what
+    ))
+}
+
+fn imm8(c: i64) -> Option<u8> {
+    i8::try_from(c).ok().map(|c| c as u8)
+}
+
+fn imm32(c: i64) -> Result<[u8; 4], String> {
+    match i32::try_from(c) {
+        Ok(c) => Ok(c.to_le_bytes()),
+        Err(_) => Err(error(format!("'{}' doesn't fit in an instruction", c))),
+    }
+}
+
+// the arithmetic instructions that have the same forms: the opcode that stores
+// to the 'r/m' operand, the one that loads from it, and the extension of the
+// opcode that takes an immediate
+const ADD: (u8, u8, u8) = (0x01, 0x03, 0);
+const SUB: (u8, u8, u8) = (0x29, 0x2b, 5);
+const XOR: (u8, u8, u8) = (0x31, 0x33, 6);
+const CMP: (u8, u8, u8) = (0x39, 0x3b, 7);
+
+// encodes the instructions the backend generates into machine code (writing
+// them out in the same order as the assembly would be)
+#[derive(Default)]
+struct Assembler {
+    bytes: Vec<u8>,
+    labels: HashMap<Label, usize>,
+    // each displacement still to be filled in, with what it refers to and where
+    // its instruction ends
+    fixups: Vec<(usize, Target, usize)>,
+    // a '%rip'-relative operand in the instruction being encoded, which is
+    // only fixed up once the rest of the instruction (like an immediate) is
+    // there
+    pending: Option<(usize, Label)>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // the end of an instruction
+    fn end(&mut self) {
+        if let Some((at, label)) = self.pending.take() {
+            self.fixups
+                .push((at, Target::Label(label), self.bytes.len()));
+        }
+    }
+
+    // an instruction with a register (or an opcode extension) in the 'reg' field
+    // and an operand in the 'r/m' field, where 'byte' says whether registers
+    // are used by their lowest byte
+    fn op(
+        &mut self,
+        wide: bool,
+        byte: bool,
+        opcode: &[u8],
+        reg: u8,
+        rm: Location,
+    ) -> Result<(), String> {
+        let base = match rm {
+            Location::Register(r) | Location::Memory(r, _) => number(r)?,
+            Location::Relative(Register::Rip, _) => 0,
+            Location::Relative(_, _) => return Err(unsupported("a label relative to a register")),
+            Location::ThreadLocal(_) => return Err(unsupported("a thread-local variable")),
+            Location::Constant(_) => unreachable!(),
+        };
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | base >> 3;
+        // without a prefix, the lowest bytes of '%rsp', '%rbp', '%rsi' and
+        // '%rdi' would be the second bytes of the first four registers
+        if rex != 0x40 || byte && matches!(rm, Location::Register(_)) && (4..8).contains(&base) {
+            self.emit(&[rex]);
+        }
+        self.emit(opcode);
+        let reg = (reg & 7) << 3;
+        match rm {
+            Location::Register(_) => self.emit(&[0xc0 | reg | base & 7]),
+            Location::Memory(_, offset) => {
+                // '%rsp' and '%r12' can only be addressed through an index byte
+                let sib: &[u8] = if base & 7 == 4 { &[0x24] } else { &[] };
+                // and '%rbp' and '%r13' always need a displacement
+                if offset == 0 && base & 7 != 5 {
+                    self.emit(&[reg | base & 7]);
+                    self.emit(sib);
+                } else if let Some(offset) = imm8(offset) {
+                    self.emit(&[0x40 | reg | base & 7]);
+                    self.emit(sib);
+                    self.emit(&[offset]);
+                } else {
+                    self.emit(&[0x80 | reg | base & 7]);
+                    self.emit(sib);
+                    self.emit(&imm32(offset)?);
+                }
+            }
+            Location::Relative(_, label) => {
+                self.emit(&[reg | 5]);
+                self.pending = Some((self.bytes.len(), label));
+                self.emit(&[0; 4]);
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn arithmetic(
+        &mut self,
+        wide: bool,
+        (store, load, extension): (u8, u8, u8),
+        source: Location,
+        target: Location,
+    ) -> Result<(), String> {
+        match (source, target) {
+            (Location::Constant(c), target) => match imm8(c) {
+                Some(c) => {
+                    self.op(wide, false, &[0x83], extension, target)?;
+                    self.emit(&[c]);
+                }
+                None => {
+                    self.op(wide, false, &[0x81], extension, target)?;
+                    self.emit(&imm32(c)?);
+                }
+            },
+            (Location::Register(r), target) => {
+                self.op(wide, false, &[store], number(r)?, target)?
+            }
+            (source, Location::Register(r)) => self.op(wide, false, &[load], number(r)?, source)?,
+            _ => return Err(unsupported("an operation between two memory operands")),
+        }
+        Ok(())
+    }
+
+    fn mul(&mut self, wide: bool, source: Location, target: Location) -> Result<(), String> {
+        let target = match target {
+            Location::Register(r) => r,
+            _ => return Err(unsupported("multiplying into memory")),
+        };
+        match source {
+            Location::Constant(c) => {
+                self.op(
+                    wide,
+                    false,
+                    &[0x69],
+                    number(target)?,
+                    Location::Register(target),
+                )?;
+                self.emit(&imm32(c)?);
+            }
+            source => self.op(wide, false, &[0x0f, 0xaf], number(target)?, source)?,
+        }
+        Ok(())
+    }
+
+    fn mov(&mut self, source: Location, target: Location) -> Result<(), String> {
+        match (source, target) {
+            (Location::Constant(c), Location::Register(r)) if i32::try_from(c).is_err() => {
+                let r = number(r)?;
+                self.emit(&[0x48 | r >> 3, 0xb8 | r & 7]);
+                self.emit(&c.to_le_bytes());
+            }
+            (Location::Constant(c), target) => {
+                self.op(true, false, &[0xc7], 0, target)?;
+                self.emit(&imm32(c)?);
+            }
+            (Location::Register(r), target) => self.op(true, false, &[0x89], number(r)?, target)?,
+            (source, Location::Register(r)) => self.op(true, false, &[0x8b], number(r)?, source)?,
+            _ => return Err(unsupported("moving between two memory operands")),
+        }
+        Ok(())
+    }
+
+    // an SSE instruction between two of the registers floats are worked on in,
+    // which comes after its prefix (the target is in the 'reg' field)
+    fn sse(&mut self, prefix: u8, opcode: u8, source: Xmm, target: Xmm) {
+        self.emit(&[
+            prefix,
+            0x0f,
+            opcode,
+            0xc0 | target.number() << 3 | source.number(),
+        ]);
+    }
+
+    fn jump(&mut self, opcode: &[u8], label: Label) {
+        self.emit(opcode);
+        let at = self.bytes.len();
+        self.emit(&[0; 4]);
+        self.fixups
+            .push((at, Target::Label(label), self.bytes.len()));
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) -> Result<(), String> {
+        use self::Instruction::*;
+        match *instruction {
+            Label(label) => {
+                self.labels.insert(label, self.bytes.len());
+            }
+            Push(Location::Register(r)) => {
+                let r = number(r)?;
+                if r >= 8 {
+                    self.emit(&[0x41]);
+                }
+                self.emit(&[0x50 | r & 7]);
+            }
+            Push(Location::Constant(c)) => match imm8(c) {
+                Some(c) => self.emit(&[0x6a, c]),
+                None => {
+                    self.emit(&[0x68]);
+                    self.emit(&imm32(c)?);
+                }
+            },
+            Push(loc) => self.op(false, false, &[0xff], 6, loc)?,
+            Pop(Location::Register(r)) => {
+                let r = number(r)?;
+                if r >= 8 {
+                    self.emit(&[0x41]);
+                }
+                self.emit(&[0x58 | r & 7]);
+            }
+            Pop(loc) => self.op(false, false, &[0x8f], 0, loc)?,
+            Neg(loc) => self.op(true, false, &[0xf7], 3, loc)?,
+            NegL(loc) => self.op(false, false, &[0xf7], 3, loc)?,
+            Add(source, target) => self.arithmetic(true, ADD, source, target)?,
+            AddL(source, target) => self.arithmetic(false, ADD, source, target)?,
+            Sub(source, target) => self.arithmetic(true, SUB, source, target)?,
+            SubL(source, target) => self.arithmetic(false, SUB, source, target)?,
+            Xor(source, target) => self.arithmetic(true, XOR, source, target)?,
+            Cmp(source, target) => self.arithmetic(true, CMP, source, target)?,
+            Mul(source, target) => self.mul(true, source, target)?,
+            MulL(source, target) => self.mul(false, source, target)?,
+            Div(loc) => self.op(true, false, &[0xf7], 7, loc)?,
+            DivL(loc) => self.op(false, false, &[0xf7], 7, loc)?,
+            Cqto => self.emit(&[0x48, 0x99]),
+            Cltd => self.emit(&[0x99]),
+            Cltq => self.emit(&[0x48, 0x98]),
+            Sar(Location::Constant(c), target) => {
+                self.op(true, false, &[0xc1], 7, target)?;
+                self.emit(&[c as u8]);
+            }
+            Shl(Location::Constant(c), target) => {
+                self.op(true, false, &[0xc1], 4, target)?;
+                self.emit(&[c as u8]);
+            }
+            Sar(_, _) | Shl(_, _) => return Err(unsupported("shifting by a register")),
+            // 'test' is symmetric, so a memory operand always goes in 'r/m'
+            Test(Location::Constant(c), target) => {
+                self.op(true, false, &[0xf7], 0, target)?;
+                self.emit(&imm32(c)?);
+            }
+            Test(Location::Register(r), other) | Test(other, Location::Register(r)) => {
+                self.op(true, false, &[0x85], number(r)?, other)?
+            }
+            Test(_, _) => return Err(unsupported("testing two memory operands")),
+            Sete(loc) => self.op(false, true, &[0x0f, 0x94], 0, loc)?,
+            Setl(loc) => self.op(false, true, &[0x0f, 0x9c], 0, loc)?,
+            Seta(loc) => self.op(false, true, &[0x0f, 0x97], 0, loc)?,
+            // the operand-size prefix goes before the REX prefix 'op' emits
+            MovToXmm(source, target) => {
+                self.emit(&[0x66]);
+                self.op(true, false, &[0x0f, 0x6e], target.number(), source)?
+            }
+            MovFromXmm(source, target) => {
+                self.emit(&[0x66]);
+                self.op(true, false, &[0x0f, 0x7e], source.number(), target)?
+            }
+            AddSd(source, target) => self.sse(0xf2, 0x58, source, target),
+            SubSd(source, target) => self.sse(0xf2, 0x5c, source, target),
+            MulSd(source, target) => self.sse(0xf2, 0x59, source, target),
+            DivSd(source, target) => self.sse(0xf2, 0x5e, source, target),
+            Ucomisd(source, target) => self.sse(0x66, 0x2e, source, target),
+            // 'cmpsd' with the predicate for equality
+            CmpEqSd(source, target) => {
+                self.sse(0xf2, 0xc2, source, target);
+                self.emit(&[0]);
+            }
+            Movzb(source, Location::Register(r)) => {
+                self.op(true, true, &[0x0f, 0xb6], number(r)?, source)?
+            }
+            Movzb(_, _) => return Err(unsupported("extending a byte into memory")),
+            Jmp(label) => self.jump(&[0xe9], label),
+            Je(label) => self.jump(&[0x0f, 0x84], label),
+            Jne(label) => self.jump(&[0x0f, 0x85], label),
+            Jno(label) => self.jump(&[0x0f, 0x81], label),
+            Jbe(label) => self.jump(&[0x0f, 0x86], label),
+            Jb(label) => self.jump(&[0x0f, 0x82], label),
+            Jg(label) => self.jump(&[0x0f, 0x8f], label),
+            Jge(label) => self.jump(&[0x0f, 0x8d], label),
+            Mov(source, target) => self.mov(source, target)?,
+            Lea(source, Location::Register(r)) => {
+                self.op(true, false, &[0x8d], number(r)?, source)?
+            }
+            Lea(_, _) => return Err(unsupported("loading an address into memory")),
+            Call(loc) => self.op(false, false, &[0xff], 2, loc)?,
+            // threads would outlive the run, and the code they run along with it
+            CallRuntime("spawn") => return Err(unsupported("spawning a thread")),
+            CallRuntime(name) => {
+                self.emit(&[0xe8]);
+                let at = self.bytes.len();
+                self.emit(&[0; 4]);
+                self.fixups.push((at, Target::Stub(name), self.bytes.len()));
+            }
+            Comment(_) => (),
+            Endbr64 => self.emit(&[0xf3, 0x0f, 0x1e, 0xfa]),
+            Ret => self.emit(&[0xc3]),
+        }
+        self.end();
+        Ok(())
+    }
+}
+
+// the data that goes after the code, whose pointers are only known once it's
+// been placed in memory
+#[derive(Default)]
+struct Data {
+    bytes: Vec<u8>,
+    labels: HashMap<Label, usize>,
+    // where each pointer goes, and the label it points to
+    pointers: Vec<(usize, Label)>,
+}
+
+impl Data {
+    fn label(&mut self, label: Label) {
+        self.labels.insert(label, self.bytes.len());
+    }
+
+    fn quad(&mut self, quad: i64) {
+        self.bytes.extend_from_slice(&quad.to_le_bytes());
+    }
+
+    fn pointer(&mut self, label: Label) {
+        self.pointers.push((self.bytes.len(), label));
+        self.quad(0);
+    }
+
+    fn string(&mut self, label: Label, string: &str) {
+        self.label(label);
+        self.bytes.extend_from_slice(string.as_bytes());
+        self.bytes.push(0);
+    }
+}
+
+// a program laid out as it will be in memory: the code (followed by a stub for
+// each runtime function it calls, which jumps on to wherever the runtime was
+// loaded) takes up the first pages, and the data follows on a page of its own,
+// as it has to stay writable (everything is an offset from the start)
+struct Layout {
+    code: Vec<u8>,
+    data: Vec<u8>,
+    data_start: usize,
+    // where each pointer goes, and where it points to
+    pointers: Vec<(usize, usize)>,
+    // where the address of each runtime function goes
+    slots: Vec<(&'static str, usize)>,
+    entry: usize,
+    functions: usize,
+}
+
+fn lay_out(code: &GeneratedCode) -> Result<Layout, String> {
+    let mut assembler = Assembler::default();
+    for instruction in code.instructions() {
+        assembler.instruction(instruction)?;
+    }
+    assembler.labels.insert(code.end, assembler.bytes.len());
+    let mut called = assembler
+        .fixups
+        .iter()
+        .filter_map(|(_, target, _)| match *target {
+            Target::Stub(name) => Some(name),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    called.sort_unstable();
+    called.dedup();
+    let mut stubs = HashMap::new();
+    for name in called.iter() {
+        stubs.insert(*name, assembler.bytes.len());
+        // jmp *slot(%rip)
+        assembler.emit(&[0xff, 0x25]);
+        let at = assembler.bytes.len();
+        assembler.emit(&[0; 4]);
+        let end = assembler.bytes.len();
+        assembler.fixups.push((at, Target::Slot(name), end));
+    }
+    let data_start = assembler.bytes.len().div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    let mut data = Data::default();
+    // the runtime's own 'slang_stack_low' is out of reach of a '%rip'-relative
+    // operand, so the code keeps track of its depth here instead
+    data.label("slang_stack_low".into());
+    data.quad(-1);
+    let mut slots = HashMap::new();
+    for name in called.iter() {
+        slots.insert(*name, data_start + data.bytes.len());
+        data.quad(0);
+    }
+    for (label, i) in code.constants.iter() {
+        data.label(*label);
+        data.quad(*i);
+    }
+    // each record matches a 'slang_trap' in the runtime
+    for trap in code.traps.iter() {
+        data.label(trap.label);
+        data.pointer(trap.filename);
+        data.quad(trap.location.line() as i64);
+        data.quad(trap.location.column() as i64);
+        data.pointer(trap.text);
+    }
+    // each entry matches a 'slang_function' in the runtime, and the last one
+    // (which has no name) marks the end of the code
+    let functions = data_start + data.bytes.len();
+    for function in code.functions.iter() {
+        data.pointer(function.label);
+        data.pointer(function.text);
+    }
+    data.pointer(code.end);
+    data.quad(0);
+    for trap in code.traps.iter() {
+        data.string(trap.filename, trap.location.filename());
+        data.string(trap.text, trap.message);
+    }
+    for function in code.functions.iter() {
+        data.string(function.text, &function.name);
+    }
+    for (label, string) in code.strings.iter() {
+        data.string(*label, string);
+    }
+    for (label, offset) in data.labels.iter() {
+        assembler.labels.insert(*label, data_start + offset);
+    }
+    let labels = &assembler.labels;
+    let find = |label: &Label| match labels.get(label) {
+        Some(offset) => Ok(*offset),
+        None => Err(error(format!("undefined label '{}'", label))),
+    };
+    let mut displacements = vec![];
+    for (at, target, end) in assembler.fixups.iter() {
+        let offset = match *target {
+            Target::Label(ref label) => find(label)?,
+            Target::Stub(name) => stubs[name],
+            Target::Slot(name) => slots[name],
+        };
+        displacements.push((*at, imm32(offset as i64 - *end as i64)?));
+    }
+    let mut pointers = vec![];
+    for (at, label) in data.pointers.iter() {
+        pointers.push((data_start + at, find(label)?));
+    }
+    let entry = find(&"entry".into())?;
+    let mut code = assembler.bytes;
+    for (at, displacement) in displacements {
+        code[at..at + 4].copy_from_slice(&displacement);
+    }
+    Ok(Layout {
+        code,
+        data: data.bytes,
+        data_start,
+        pointers,
+        slots: slots.into_iter().collect(),
+        entry,
+        functions,
+    })
+}
+
+// a program mapped into memory, which is unmapped when dropped
+struct Image {
+    base: *mut u8,
+    length: usize,
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.base as *mut c_void, self.length);
+        }
+    }
+}
+
+// compiles generated code into memory and runs it there, calling into a copy
+// of the runtime loaded into this process, which reads the numbers for '?' as
+// an executable would; a runtime error (including a crash) is returned rather
+// than ending the process
+pub fn run(code: &GeneratedCode) -> Result<i64, String> {
+    // the runtime only converts ints to and from their untagged representation
+    // here, and has no red zones to check
+    if code.representation != Representation::Untagged {
+        return Err(unsupported("'--representation'"));
+    }
+    if code.sanitise {
+        return Err(unsupported("'--sanitise'"));
+    }
+    let layout = lay_out(code)?;
+    let _running = RUNNING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let handle = runtime()?;
+    let run = unsafe { std::mem::transmute::<usize, Run>(symbol(handle, "slang_jit_run")?) };
+    let mut addresses = vec![];
+    for (name, at) in layout.slots.iter() {
+        addresses.push((*at, symbol(handle, name)?));
+    }
+    let length = layout.data_start + layout.data.len().div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    let base = unsafe {
+        mmap(
+            ptr::null_mut(),
+            length,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if base == MAP_FAILED {
+        return Err(error("failed to map memory for the code".to_string()));
+    }
+    let image = Image {
+        base: base as *mut u8,
+        length,
+    };
+    let mut failure = ptr::null();
+    let result = unsafe {
+        ptr::copy_nonoverlapping(layout.code.as_ptr(), image.base, layout.code.len());
+        ptr::copy_nonoverlapping(
+            layout.data.as_ptr(),
+            image.base.add(layout.data_start),
+            layout.data.len(),
+        );
+        for (at, offset) in layout.pointers.iter() {
+            let pointer = image.base as usize + offset;
+            ptr::write_unaligned(image.base.add(*at) as *mut usize, pointer);
+        }
+        for (at, address) in addresses {
+            ptr::write_unaligned(image.base.add(at) as *mut usize, address);
+        }
+        if mprotect(base, layout.data_start, PROT_READ | PROT_EXEC) != 0 {
+            return Err(error("failed to make the code executable".to_string()));
+        }
+        run(
+            image.base as usize + layout.entry,
+            image.base as usize + layout.functions,
+            code.capabilities.bits(),
+            &mut failure,
+        )
+    };
+    if !failure.is_null() {
+        let failure = unsafe { CStr::from_ptr(failure) };
+        return Err(failure.to_string_lossy().trim_end().to_string());
+    }
+    Ok(result)
+}
@@ -0,0 +1,292 @@
+use super::super::frontend::{self, TypeExpr};
+use super::super::Type;
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+// what a symbol in generated code names: a function, by the modules it's
+// defined in and its own name (in 'path', outermost first), the types it was
+// instantiated at, and which of the functions with that path it is (the first
+// is 0), as a program can define more than one with the same name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub path: Vec<String>,
+    pub types: Vec<Type>,
+    pub disambiguator: usize,
+}
+
+impl Symbol {
+    // the symbol for a function that's reported as 'name' (with any modules
+    // it's defined in before it, separated by '.', and for a copy of a
+    // polymorphic function, the types it was copied at after it, in '<' and
+    // '>'). Types that can't be encoded are left in the name
+    pub fn new(name: &str, disambiguator: usize) -> Symbol {
+        let (base, types) = match name.find('<') {
+            Some(i) if name.ends_with('>') => match instantiation(&name[i + 1..name.len() - 1]) {
+                Some(types) => (&name[..i], types),
+                None => (name, vec![]),
+            },
+            _ => (name, vec![]),
+        };
+        // a '.' in the types doesn't separate modules
+        let mut path = vec![];
+        let mut rest = base;
+        while let Some(i) = rest.find('.') {
+            if rest[..i].contains('<') {
+                break;
+            }
+            path.push(rest[..i].to_string());
+            rest = &rest[i + 1..];
+        }
+        path.push(rest.to_string());
+        Symbol {
+            path,
+            types,
+            disambiguator,
+        }
+    }
+}
+
+// the types a polymorphic function was copied at, as they're printed, if
+// they're all types a host program can see
+fn instantiation(types: &str) -> Option<Vec<Type>> {
+    types
+        .split(", ")
+        .map(|t| plain(&frontend::parse_type(t)?))
+        .collect()
+}
+
+fn plain(type_expr: &TypeExpr) -> Option<Type> {
+    let sub = |sub: &TypeExpr| plain(sub).map(Box::new);
+    let t = match *type_expr {
+        TypeExpr::Unit => Type::Unit,
+        TypeExpr::Bool => Type::Bool,
+        TypeExpr::Int => Type::Int,
+        TypeExpr::Float => Type::Float,
+        TypeExpr::String => Type::String,
+        TypeExpr::Ref(ref t) => Type::Ref(sub(t)?),
+        TypeExpr::Lazy(ref t) => Type::Lazy(sub(t)?),
+        TypeExpr::Chan(ref t) => Type::Chan(sub(t)?),
+        TypeExpr::Array(ref t) => Type::Array(sub(t)?),
+        TypeExpr::List(ref t) => Type::List(sub(t)?),
+        TypeExpr::Arrow(ref left, ref right) => Type::Arrow(sub(left)?, sub(right)?),
+        TypeExpr::Product(ref left, ref right) => Type::Product(sub(left)?, sub(right)?),
+        TypeExpr::Union(ref left, ref right) => Type::Union(sub(left)?, sub(right)?),
+        _ => return None,
+    };
+    Some(t)
+}
+
+// symbols are printed as the function's name would be written in a program,
+// followed by the types it was instantiated at, and then which it is (unless
+// it's the first)
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.join("."))?;
+        if !self.types.is_empty() {
+            let types = self.types.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+            write!(f, "<{}>", types.join(", "))?;
+        }
+        if self.disambiguator > 0 {
+            write!(f, "#{}", self.disambiguator)?;
+        }
+        Ok(())
+    }
+}
+
+// each part of a path is written as its length and then its characters, with
+// an '_' between the two if the characters start with a digit or an '_'. Names
+// with anything but letters, digits and '_' in them (a prime, say, or the '<'
+// and '>' around the names of anonymous functions) are marked with a 'u', and
+// each '_' and each of those characters is written as '_' and its code in two
+// hex digits
+fn identifier(mangled: &mut String, part: &str) {
+    let encoded = if part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        part.to_string()
+    } else {
+        mangled.push('u');
+        let mut encoded = String::new();
+        for c in part.chars() {
+            if c.is_ascii_alphanumeric() {
+                encoded.push(c);
+            } else {
+                // only ASCII can appear in a name
+                encoded.push_str(&format!("_{:02x}", c as u32 & 0xff));
+            }
+        }
+        encoded
+    };
+    mangled.push_str(&encoded.len().to_string());
+    if encoded.starts_with(|c: char| c.is_ascii_digit() || c == '_') {
+        mangled.push('_');
+    }
+    mangled.push_str(&encoded);
+}
+
+// each type is a letter, followed by the types it's built from
+fn type_expr(mangled: &mut String, t: &Type) {
+    let (letter, subs) = match *t {
+        Type::Unit => ('u', vec![]),
+        Type::Bool => ('b', vec![]),
+        Type::Int => ('i', vec![]),
+        Type::Float => ('f', vec![]),
+        Type::String => ('s', vec![]),
+        Type::Ref(ref sub) => ('R', vec![sub]),
+        Type::Lazy(ref sub) => ('Z', vec![sub]),
+        Type::Chan(ref sub) => ('C', vec![sub]),
+        Type::Array(ref sub) => ('A', vec![sub]),
+        Type::List(ref sub) => ('L', vec![sub]),
+        Type::Arrow(ref left, ref right) => ('F', vec![left, right]),
+        Type::Product(ref left, ref right) => ('P', vec![left, right]),
+        Type::Union(ref left, ref right) => ('U', vec![left, right]),
+    };
+    mangled.push(letter);
+    for sub in subs {
+        type_expr(mangled, sub);
+    }
+}
+
+// '_SN', each part of the path, 'E', then (if there are any) 'I', the types
+// and 'E', then (unless it's the first) '_' and which it is. Only letters,
+// digits and '_' are used, so a mangled symbol is also a C identifier
+pub fn mangle(symbol: &Symbol) -> String {
+    let mut mangled = "_SN".to_string();
+    for part in symbol.path.iter() {
+        identifier(&mut mangled, part);
+    }
+    mangled.push('E');
+    if !symbol.types.is_empty() {
+        mangled.push('I');
+        for t in symbol.types.iter() {
+            type_expr(&mut mangled, t);
+        }
+        mangled.push('E');
+    }
+    if symbol.disambiguator > 0 {
+        mangled.push_str(&format!("_{}", symbol.disambiguator));
+    }
+    mangled
+}
+
+fn number(chars: &mut Peekable<Chars>) -> Option<usize> {
+    let mut digits = String::new();
+    while let Some(&c) = chars.peek() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        digits.push(c);
+        chars.next();
+    }
+    digits.parse().ok()
+}
+
+fn demangle_identifier(chars: &mut Peekable<Chars>) -> Option<String> {
+    let escaped = chars.peek() == Some(&'u');
+    if escaped {
+        chars.next();
+    }
+    let length = number(chars)?;
+    if chars.peek() == Some(&'_') {
+        chars.next();
+    }
+    let encoded = (0..length)
+        .map(|_| chars.next())
+        .collect::<Option<String>>()?;
+    if !escaped {
+        return Some(encoded);
+    }
+    let mut part = String::new();
+    let mut encoded = encoded.chars();
+    while let Some(c) = encoded.next() {
+        if c == '_' {
+            let code = encoded.next()?.to_digit(16)? * 16 + encoded.next()?.to_digit(16)?;
+            part.push(char::from(code as u8));
+        } else {
+            part.push(c);
+        }
+    }
+    Some(part)
+}
+
+fn demangle_type(chars: &mut Peekable<Chars>) -> Option<Type> {
+    let sub = |chars: &mut Peekable<Chars>| demangle_type(chars).map(Box::new);
+    let t = match chars.next()? {
+        'u' => Type::Unit,
+        'b' => Type::Bool,
+        'i' => Type::Int,
+        'f' => Type::Float,
+        's' => Type::String,
+        'R' => Type::Ref(sub(chars)?),
+        'Z' => Type::Lazy(sub(chars)?),
+        'C' => Type::Chan(sub(chars)?),
+        'A' => Type::Array(sub(chars)?),
+        'L' => Type::List(sub(chars)?),
+        'F' => Type::Arrow(sub(chars)?, sub(chars)?),
+        'P' => Type::Product(sub(chars)?, sub(chars)?),
+        'U' => Type::Union(sub(chars)?, sub(chars)?),
+        _ => return None,
+    };
+    Some(t)
+}
+
+// what a mangled symbol names, if it is one (all of it has to be)
+pub fn demangle(mangled: &str) -> Option<Symbol> {
+    let mut chars = mangled.strip_prefix("_SN")?.chars().peekable();
+    let mut path = vec![];
+    while chars.peek() != Some(&'E') {
+        path.push(demangle_identifier(&mut chars)?);
+    }
+    chars.next();
+    if path.is_empty() {
+        return None;
+    }
+    let mut types = vec![];
+    if chars.peek() == Some(&'I') {
+        chars.next();
+        while chars.peek() != Some(&'E') {
+            types.push(demangle_type(&mut chars)?);
+        }
+        chars.next();
+    }
+    let disambiguator = match chars.next() {
+        Some('_') => match number(&mut chars)? {
+            0 => return None,
+            n => n,
+        },
+        Some(_) => return None,
+        None => 0,
+    };
+    if chars.next().is_some() {
+        return None;
+    }
+    Some(Symbol {
+        path,
+        types,
+        disambiguator,
+    })
+}
+
+// replaces every mangled symbol in some text (like the output of 'nm' or
+// 'objdump') with what it names, leaving everything else as it is
+pub fn demangle_text(text: &str) -> String {
+    let mut demangled = String::new();
+    let mut word = String::new();
+    let flush = |word: &mut String, demangled: &mut String| {
+        match demangle(word) {
+            Some(symbol) => demangled.push_str(&symbol.to_string()),
+            None => demangled.push_str(word),
+        }
+        word.clear();
+    };
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            word.push(c);
+        } else {
+            flush(&mut word, &mut demangled);
+            demangled.push(c);
+        }
+    }
+    flush(&mut word, &mut demangled);
+    demangled
+}
@@ -1,401 +1,1800 @@
-use super::frontend::ast::{BinOp, Expr, Free, UnOp};
+use super::frontend::ast::{BinOp, Free, UnOp};
+use super::frontend::quote;
+use super::frontend::Location as SourceLocation;
+use super::ir::{self, Atom, Binding, Block, Op};
+use super::{Capabilities, CompilerOptions, IntWidth, Mangling, Representation};
 
+pub mod c;
+pub mod diff;
+pub mod jit;
+pub mod mangle;
+mod mutate;
+mod parse;
+mod peephole;
+mod regalloc;
+pub mod wasm;
 mod x86;
 
+pub use mutate::Mutant;
+pub use parse::parse_assembly;
 use x86::*;
+pub use x86::{Instruction, Label, Location, Register};
 
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// a function in the generated code, along with what the runtime's crash reporter
+// calls it
+#[derive(Clone)]
+pub struct Function {
+    label: Label,
+    name: String,
+    // the symbol it's given in the assembly, if functions get symbols (the top
+    // level is always 'entry')
+    symbol: Option<String>,
+    // where the name is kept
+    text: Label,
+    instructions: Vec<Instruction>,
+    // the return address of each call, along with the frame slots (relative to
+    // '%rbp') holding values that are in scope there
+    stack_maps: Vec<(Label, Vec<i64>)>,
+    // whether some path through it skips saving each callee-saved register it
+    // uses
+    skipped: Vec<bool>,
+}
+
+impl Function {
+    pub fn label(&self) -> Label {
+        self.label
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn symbol(&self) -> Option<&str> {
+        self.symbol.as_deref()
+    }
+
+    // how many callee-saved registers it saves, and how many of those some
+    // path through it skips saving
+    pub fn saved_registers(&self) -> (usize, usize) {
+        let skipped = self.skipped.iter().filter(|skipped| **skipped).count();
+        (self.skipped.len(), skipped)
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    // the most stack a call to it takes (from its return address down), not
+    // counting what it calls (everything pushed while evaluating an expression
+    // is popped again, so the instructions can be followed in order)
+    pub fn frame(&self) -> usize {
+        let mut depth = 8;
+        let mut deepest = depth;
+        for instruction in self.instructions.iter() {
+            match *instruction {
+                Instruction::Push(_) => depth += 8,
+                Instruction::Pop(_) => depth -= 8,
+                Instruction::Sub(Location::Constant(c), Location::Register(Register::Rsp)) => {
+                    depth += c
+                }
+                Instruction::Add(Location::Constant(c), Location::Register(Register::Rsp)) => {
+                    depth -= c
+                }
+                _ => (),
+            }
+            deepest = deepest.max(depth);
+        }
+        deepest as usize
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for instruction in self.instructions.iter() {
+            write!(f, "{}", instruction)?;
+        }
+        Ok(())
+    }
+}
+
+// somewhere a runtime error can happen (each matches a 'slang_trap' in the
+// runtime)
+#[derive(Clone)]
+struct Trap {
+    label: Label,
+    location: SourceLocation,
+    message: &'static str,
+    // where the filename and message are kept
+    filename: Label,
+    text: Label,
+}
+
+// everything generated for a program, which can be looked at (or changed)
+// before it's written out as assembly (every label it uses is made up front, so
+// writing it out twice gives the same text)
+#[derive(Clone)]
+pub struct GeneratedCode {
+    sanitise: bool,
+    representation: Representation,
+    harden: bool,
+    stack_maps: bool,
+    // what the runtime is allowed to do on the program's behalf
+    capabilities: Capabilities,
+    functions: Vec<Function>,
+    // marks the end of the code (for the runtime's crash reporter)
+    end: Label,
+    traps: Vec<Trap>,
+    // literals too big for an instruction, which are loaded from '.rodata' instead
+    // (each value only appears once)
+    constants: Vec<(Label, i64)>,
+    // the bytes of each string literal, which the runtime copies into a new
+    // string each time it's run (each string only appears once)
+    strings: Vec<(Label, String)>,
+}
+
+impl GeneratedCode {
+    pub fn functions(&self) -> &[Function] {
+        &self.functions
+    }
+
+    // every instruction in the program, in the order they're written out
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.functions
+            .iter()
+            .flat_map(|function| function.instructions.iter())
+    }
+
+    pub fn constants(&self) -> &[(Label, i64)] {
+        &self.constants
+    }
+
+    // how much stack each function takes on its own, in the order their code
+    // was started (each function's label is made just before its code, so this
+    // is the order of their labels, after the top level's)
+    pub fn frames(&self) -> Vec<usize> {
+        let mut functions = self.functions.iter().collect::<Vec<_>>();
+        functions.sort_by_key(|function| match function.label {
+            Label::Given(_) => 0,
+            Label::Generated(n) => n + 1,
+        });
+        functions.iter().map(|function| function.frame()).collect()
+    }
+
+    // ties the labels in the assembly back to the program, as JSON: what each
+    // function is called, and where in the source each runtime error's record
+    // (which the code loads before calling 'trap') comes from
+    pub fn source_map(&self) -> String {
+        let functions = self
+            .functions
+            .iter()
+            .map(|function| {
+                let symbol = match function.symbol {
+                    Some(ref symbol) => format!(", \"symbol\": \"{}\"", symbol),
+                    None => String::new(),
+                };
+                format!(
+                    "    {{\"label\": \"{}\", \"name\": {}{}}}",
+                    function.label,
+                    quote(&function.name),
+                    symbol
+                )
+            })
+            .collect::<Vec<_>>();
+        let traps = self
+            .traps
+            .iter()
+            .map(|trap| {
+                format!(
+                    "    {{\"label\": \"{}\", \"file\": {}, \"line\": {}, \"column\": {}, \"message\": {}}}",
+                    trap.label,
+                    quote(trap.location.filename()),
+                    trap.location.line(),
+                    trap.location.column(),
+                    quote(trap.message)
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\n  \"functions\": [\n{}\n  ],\n  \"traps\": [\n{}\n  ]\n}}\n",
+            functions.join(",\n"),
+            traps.join(",\n")
+        )
+    }
+
+    pub fn write_to(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    // the same code with its labels renumbered in the order they're first used,
+    // so that code generated by different versions of the compiler (or with
+    // different options) can be compared without every label being a difference
+    pub fn normalized(&self) -> GeneratedCode {
+        let mut code = self.clone();
+        let mut renumbered = HashMap::new();
+        let mut renumber = |label: &mut Label| {
+            if let Label::Generated(n) = *label {
+                let next = renumbered.len();
+                *label = Label::Generated(*renumbered.entry(n).or_insert(next));
+            }
+        };
+        // this follows the order everything is written out in
+        for function in code.functions.iter_mut() {
+            for instruction in function.instructions.iter_mut() {
+                instruction.labels_mut().into_iter().for_each(&mut renumber);
+            }
+        }
+        renumber(&mut code.end);
+        for function in code.functions.iter_mut() {
+            renumber(&mut function.label);
+            renumber(&mut function.text);
+        }
+        for function in code.functions.iter_mut() {
+            for (label, _) in function.stack_maps.iter_mut() {
+                renumber(label);
+            }
+        }
+        for (label, _) in code.constants.iter_mut() {
+            renumber(label);
+        }
+        for (label, _) in code.strings.iter_mut() {
+            renumber(label);
+        }
+        for trap in code.traps.iter_mut() {
+            renumber(&mut trap.label);
+            renumber(&mut trap.filename);
+            renumber(&mut trap.text);
+        }
+        code
+    }
+}
 
 struct Generator {
     comments: bool,
-    functions: Vec<GeneratedCode>,
+    // whether to generate code that makes memory errors easier to find, whether
+    // under a tool like Valgrind or not: dead stack slots are poisoned and the
+    // runtime surrounds each allocation with red zones that are checked at exit
+    // (this also turns off tail calls, so that every call gets a fresh frame)
+    sanitise: bool,
+    // whether a recursive function calling itself as the last thing it does
+    // jumps back to the start of its body instead, reusing its frame
+    tail_calls: bool,
+    // integers narrower than 64 bits are computed with the narrower
+    // instructions, then sign-extended back to 64 bits
+    int_width: IntWidth,
+    // ints and bools are always computed on as they are, so operations decode
+    // their operands from this representation and encode their results into it
+    representation: Representation,
+    // whether to generate code that works with control-flow enforcement (CET)
+    harden: bool,
+    // only when nobody will be reading the generated code (so not with comments
+    // or when sanitising)
+    omit_frame_pointer: bool,
+    // also only when nobody will be reading the generated code, as the comments
+    // say where everything is on the stack
+    allocate_registers: bool,
+    // a collector needs every value to be in the frame (and frames to be linked)
+    // when it reads the stack maps, so this turns off both of the above
+    stack_maps: bool,
+    // the same goes for the shadow stack, whose records list the frame's slots
+    shadow_stack: bool,
+    mangling: Mangling,
+    // how many functions with each name have been given symbols so far
+    symbols: HashMap<String, usize>,
+    code: GeneratedCode,
 }
 
 impl Generator {
-    fn new() -> Generator {
+    fn new(options: &CompilerOptions) -> Generator {
         Generator {
-            comments: false,
-            functions: vec![],
+            comments: options.comments,
+            sanitise: options.sanitise,
+            tail_calls: !options.sanitise,
+            int_width: options.int_width,
+            representation: options.representation,
+            harden: options.harden,
+            omit_frame_pointer: options.omit_frame_pointer
+                && !options.comments
+                && !options.sanitise
+                && !options.stack_maps
+                && !options.shadow_stack,
+            allocate_registers: !options.comments
+                && !options.sanitise
+                && !options.stack_maps
+                && !options.shadow_stack,
+            stack_maps: options.stack_maps,
+            shadow_stack: options.shadow_stack,
+            mangling: options.mangling,
+            symbols: HashMap::new(),
+            code: GeneratedCode {
+                sanitise: options.sanitise,
+                representation: options.representation,
+                harden: options.harden,
+                stack_maps: options.stack_maps,
+                capabilities: options.capabilities,
+                functions: vec![],
+                end: Label::new(),
+                traps: vec![],
+                constants: vec![],
+                strings: vec![],
+            },
         }
     }
 
-    fn new_with_comments() -> Generator {
-        Generator {
-            comments: true,
-            functions: vec![],
+    fn add(&mut self, label: Label, name: String, code: &mut Code) {
+        let instructions = code.ret();
+        let symbol = match label {
+            Label::Generated(_) if self.mangling == Mangling::Slang => {
+                let count = self.symbols.entry(name.clone()).or_insert(0);
+                *count += 1;
+                Some(mangle::mangle(&mangle::Symbol::new(&name, *count - 1)))
+            }
+            _ => None,
+        };
+        self.code.functions.push(Function {
+            label,
+            name,
+            symbol,
+            text: Label::new(),
+            instructions,
+            stack_maps: code.stack_maps(),
+            skipped: code.skipped(),
+        })
+    }
+
+    // records where a runtime error can happen, returning the label of the
+    // record that should be passed to 'trap' if it does
+    fn trap(&mut self, location: SourceLocation, message: &'static str) -> Label {
+        let label = Label::new();
+        self.code.traps.push(Trap {
+            label,
+            location,
+            message,
+            filename: Label::new(),
+            text: Label::new(),
+        });
+        label
+    }
+
+    // where an integer is kept in the constant pool
+    fn pooled(&mut self, i: i64) -> Label {
+        match self.code.constants.iter().find(|(_, c)| *c == i) {
+            Some((label, _)) => *label,
+            None => {
+                let label = Label::new();
+                self.code.constants.push((label, i));
+                label
+            }
         }
     }
 
-    fn add(&mut self, code: GeneratedCode) {
-        self.functions.push(code)
+    // where the bytes of a string literal are kept
+    fn string(&mut self, string: String) -> Label {
+        match self.code.strings.iter().find(|(_, s)| *s == string) {
+            Some((label, _)) => *label,
+            None => {
+                let label = Label::new();
+                self.code.strings.push((label, string));
+                label
+            }
+        }
+    }
+
+    // where an integer can be loaded from: immediates are sign-extended from 32
+    // bits, so anything bigger goes in the constant pool
+    fn int(&mut self, i: i64) -> Location {
+        if i32::try_from(i).is_ok() {
+            return constant(i);
+        }
+        relative(rip(), self.pooled(i))
     }
 }
 
-impl fmt::Display for Generator {
+// escapes a string for use in a '.string' directive
+fn escape(string: &str) -> String {
+    let mut escaped = String::new();
+    for byte in string.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(byte as char);
+            }
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    escaped
+}
+
+impl fmt::Display for GeneratedCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "\t.text")?;
-        writeln!(f, "\t.extern alloc")?;
-        writeln!(f, "\t.extern make_closure")?;
-        writeln!(f, "\t.extern make_recursive_closure")?;
-        writeln!(f, "\t.extern what")?;
+        for name in x86::RUNTIME.iter() {
+            // a program without the 'io' capability never prints or reads input
+            if !self.capabilities.io && (*name == "print_string" || *name == "what") {
+                continue;
+            }
+            writeln!(f, "\t.extern {}", name)?;
+        }
+        writeln!(f, "\t.extern slang_stack_low")?;
         writeln!(f, "\t.globl entry")?;
         writeln!(f, "\t.type entry, @function")?;
         for function in self.functions.iter() {
+            // the symbol only marks the function for tools to find it by, so it
+            // isn't global (the code itself only ever refers to its label)
+            if let Some(ref symbol) = function.symbol {
+                writeln!(f, "\t.type {}, @function", symbol)?;
+                writeln!(f, "{}:", symbol)?;
+            }
             write!(f, "{}", function)?;
+            if let Some(ref symbol) = function.symbol {
+                writeln!(f, "\t.size {}, .-{}", symbol, symbol)?;
+            }
+        }
+        writeln!(f, "{}:", self.end)?;
+        writeln!(f, "\t.section .data.rel.ro")?;
+        // each entry matches a 'slang_function' in the runtime, and the last one
+        // (which has no name) marks the end of the code
+        writeln!(f, "\t.globl slang_functions")?;
+        writeln!(f, "slang_functions:")?;
+        for function in self.functions.iter() {
+            writeln!(f, "\t.quad {}", function.label)?;
+            writeln!(f, "\t.quad {}", function.text)?;
+        }
+        writeln!(f, "\t.quad {}", self.end)?;
+        writeln!(f, "\t.quad 0")?;
+        for function in self.functions.iter() {
+            writeln!(f, "{}:", function.text)?;
+            writeln!(f, "\t.string \"{}\"", escape(&function.name))?;
+        }
+        if self.sanitise {
+            // the runtime checks for this to decide whether to guard allocations
+            writeln!(f, "\t.globl slang_sanitise")?;
+            writeln!(f, "slang_sanitise:")?;
+            writeln!(f, "\t.quad 1")?;
         }
+        if self.capabilities != Capabilities::all() {
+            // the runtime checks for this before doing anything on the
+            // program's behalf that it might not be allowed to
+            writeln!(f, "\t.globl slang_capabilities")?;
+            writeln!(f, "slang_capabilities:")?;
+            writeln!(f, "\t.quad {}", self.capabilities.bits())?;
+        }
+        if self.representation != Representation::Untagged {
+            // the runtime checks for this to decide how to convert the ints it
+            // passes to and from compiled code
+            writeln!(f, "\t.globl slang_representation")?;
+            writeln!(f, "slang_representation:")?;
+            writeln!(
+                f,
+                "\t.quad {}",
+                match self.representation {
+                    Representation::Tagged => 1,
+                    _ => 2,
+                }
+            )?;
+        }
+        if !self.constants.is_empty() {
+            writeln!(f, "\t.section .rodata")?;
+            writeln!(f, "\t.align 8")?;
+            for (label, i) in self.constants.iter() {
+                writeln!(f, "{}:", label)?;
+                writeln!(f, "\t.quad {}", i)?;
+            }
+        }
+        if !self.strings.is_empty() {
+            // the runtime is told how long each string is, so the terminating
+            // zero is only there for anyone reading the assembly
+            writeln!(f, "\t.section .rodata")?;
+            for (label, string) in self.strings.iter() {
+                writeln!(f, "{}:", label)?;
+                writeln!(f, "\t.string \"{}\"", escape(string))?;
+            }
+        }
+        if !self.traps.is_empty() {
+            // the records point at their strings, so they can't go in '.rodata'
+            // with the constants
+            writeln!(f, "\t.section .data.rel.ro")?;
+            // each record matches a 'slang_trap' in the runtime
+            for trap in self.traps.iter() {
+                writeln!(f, "{}:", trap.label)?;
+                writeln!(f, "\t.quad {}", trap.filename)?;
+                writeln!(f, "\t.quad {}", trap.location.line())?;
+                writeln!(f, "\t.quad {}", trap.location.column())?;
+                writeln!(f, "\t.quad {}", trap.text)?;
+                writeln!(f, "{}:", trap.filename)?;
+                writeln!(f, "\t.string \"{}\"", escape(trap.location.filename()))?;
+                writeln!(f, "{}:", trap.text)?;
+                writeln!(f, "\t.string \"{}\"", escape(trap.message))?;
+            }
+        }
+        if self.stack_maps {
+            // for each call, its return address, how many slots are listed and
+            // their offsets from '%rbp', ending with a return address of 0
+            writeln!(f, "\t.section .data.rel.ro.slang_stack_maps,\"aw\"")?;
+            writeln!(f, "\t.globl slang_stack_maps")?;
+            writeln!(f, "slang_stack_maps:")?;
+            for function in self.functions.iter() {
+                for (label, slots) in function.stack_maps.iter() {
+                    writeln!(f, "\t.quad {}", label)?;
+                    writeln!(f, "\t.quad {}", slots.len())?;
+                    for slot in slots.iter() {
+                        writeln!(f, "\t.quad {}", slot)?;
+                    }
+                }
+            }
+            writeln!(f, "\t.quad 0")?;
+        }
+        if self.harden {
+            // marks the code as supporting indirect branch tracking (every function
+            // starts with 'endbr64') and shadow stacks (every call returns normally)
+            writeln!(f, "\t.section .note.gnu.property,\"a\"")?;
+            writeln!(f, "\t.align 8")?;
+            writeln!(f, "\t.long 4")?;
+            writeln!(f, "\t.long 16")?;
+            writeln!(f, "\t.long 5")?;
+            writeln!(f, "\t.string \"GNU\"")?;
+            writeln!(f, "\t.long 0xc0000002")?;
+            writeln!(f, "\t.long 4")?;
+            writeln!(f, "\t.long 3")?;
+            writeln!(f, "\t.align 8")?;
+        }
+        // nothing is ever run from the stack, so it shouldn't be executable
+        // (which is also required to load the code as a shared library)
+        writeln!(f, "\t.section .note.GNU-stack,\"\",@progbits")?;
         Ok(())
     }
 }
 
-impl Code {
-    fn emit_var(&mut self, v: String) -> &mut Code {
-        let loc = self.get(&v);
-        self.comment(format!(
-            "'{}' is in '{}' so move it to the accumulator ('{}')",
-            v,
-            loc,
-            rax()
-        ))
-        .mov(loc, rax())
+// booleans are always encoded as 0 ('false') or 1 ('true'): everything that
+// produces one ('true', 'false', '~', '<', '=' and '==') leaves exactly one of
+// these in the accumulator (in the chosen representation), so everything that
+// consumes one can just test whether it's 0, and equality can compare the
+// encodings directly
+
+// converts the int or bool in the accumulator from its binary encoding to the
+// chosen representation
+fn encode(code: &mut Code, generator: &Generator) {
+    match generator.representation {
+        Representation::Untagged => (),
+        Representation::Tagged => {
+            code.comment(format!(
+                "tag the value in the accumulator ('{}') by shifting it left and setting its lowest bit",
+                rax()
+            ))
+            .add(rax(), rax())
+            .add(constant(1), rax());
+        }
+        Representation::Boxed => {
+            code.comment(format!(
+                "box the value in the accumulator ('{}') by passing it to the runtime in '{}'",
+                rax(),
+                rdi()
+            ))
+            .mov(rax(), rdi())
+            .call_rt("make_box");
+        }
     }
+}
 
-    fn emit_unop(&mut self, op: UnOp, expr: Expr, generator: &mut Generator) -> &mut Code {
-        use self::UnOp::*;
-        self.comment(format!(
-            "compute the operand for '{}' and leave it in the accumulator ('{}')",
-            op,
-            rax()
-        ))
-        .emit(expr, generator)
-        .comment(format!(
-            "apply the '{}' and leave the result in the accumulator ('{}')",
-            op,
-            rax()
-        ));
-        match op {
-            Neg => self.neg(rax()),
-            Not => self.not(rax()),
+// converts the int or bool in a register from the chosen representation back
+// to its binary encoding
+fn decode(code: &mut Code, generator: &Generator, register: Location) {
+    match generator.representation {
+        Representation::Untagged => (),
+        Representation::Tagged => {
+            code.comment(format!(
+                "untag the value in '{}' by shifting it right",
+                register
+            ))
+            .sar(constant(1), register);
+        }
+        Representation::Boxed => {
+            code.comment(format!(
+                "unbox the value in '{}' by loading it from the heap",
+                register
+            ))
+            .mov(deref(register, 0), register);
         }
     }
+}
 
-    fn emit_binop(
-        &mut self,
-        op: BinOp,
-        left: Expr,
-        right: Expr,
-        generator: &mut Generator,
-    ) -> &mut Code {
-        use self::BinOp::*;
-        match op {
-            And => {
-                let label = Label::new();
-                self.comment(format!(
-                    "for '&&' we only evaluate the right operand if the left evaluated to 'true'"
-                ))
-                .comment(format!("compute the left operand for the '&&'"))
-                .emit(left, generator)
-                .comment(format!(
-                    "the value of the left operand of the '&&' is left in the accumulator ('{}')",
-                    rax()
-                ))
-                .comment(format!(
-                    "we compare this with the binary encoding of 'true'"
-                ))
-                .cmp(constant(1), rax())
-                .comment(format!(
-                    "if the value in the accumulator ('{}') is not true we jump passed the right operand to '{}'", rax(), label
-                ))
-                .jne(label)
-                .comment(format!("if we did not make the jump, the result of the '&&' should be the result of the right operand"))
-                .emit(right, generator)
-                .comment(format!(
-                    "the value of the right operand of the '&&' is left in the accumulator ('{}')",
-                    rax()
-                ))
-                .comment(format!("this becomes the value of the '&&'"))
-                .label(label)
-            }
-            Or => {
-                let label = Label::new();
-                self.emit(left, generator)
-                    .cmp(constant(1), rax())
-                    .je(label)
-                    .emit(right, generator)
-                    .label(label)
+// moves an int or bool known at compile time into a register, in the chosen
+// representation
+fn literal(code: &mut Code, generator: &mut Generator, i: i64, register: Location) {
+    let loc = match generator.representation {
+        Representation::Untagged => generator.int(i),
+        Representation::Tagged => generator.int(i.wrapping_mul(2).wrapping_add(1)),
+        Representation::Boxed => {
+            // a literal's box is never written to, so it can be shared
+            let label = generator.pooled(i);
+            code.comment(format!(
+                "the box for {} is in the constant pool at '{}', so load its address into '{}'",
+                i, label, register
+            ))
+            .lea(relative(rip(), label), register);
+            return;
+        }
+    };
+    code.comment(format!("move {} ('{}') into '{}'", i, loc, register))
+        .mov(loc, register);
+}
+
+// sets the zero flag if the bool in the accumulator is 'false' (leaving the
+// accumulator as it is)
+fn test_false(code: &mut Code, generator: &Generator) {
+    code.comment("test if this is the encoding for 'false'");
+    match generator.representation {
+        Representation::Untagged => code.test(rax(), rax()),
+        Representation::Tagged => code.cmp(constant(1), rax()),
+        Representation::Boxed => code.cmp(constant(0), deref(rax(), 0)),
+    };
+}
+
+// moves the value of an atom into a register
+fn load(code: &mut Code, generator: &mut Generator, atom: &Atom, register: Location) {
+    match *atom {
+        Atom::Unit => {
+            code.comment(format!(
+                "move the binary encoding of '()' into '{}'",
+                register
+            ))
+            .mov(constant(0), register);
+        }
+        Atom::Int(i) => literal(code, generator, i, register),
+        // floats are never tagged or boxed, whatever ints are
+        Atom::Float(f) => {
+            let loc = generator.int(f.to_bits() as i64);
+            code.comment(format!(
+                "move the bits of {} ('{}') into '{}'",
+                f, loc, register
+            ))
+            .mov(loc, register);
+        }
+        Atom::Bool(b) => {
+            code.comment(format!("'{}' is encoded as {}", b, b as i64));
+            literal(code, generator, b as i64, register);
+        }
+        Atom::Var(ref v) => {
+            let loc = code.get(v);
+            code.comment(format!(
+                "'{}' is in '{}' so move it to '{}'",
+                v, loc, register
+            ))
+            .mov(loc, register);
+        }
+    }
+}
+
+fn lower_unop(code: &mut Code, generator: &mut Generator, op: UnOp, sub: &Atom) {
+    use self::UnOp::*;
+    code.comment(format!(
+        "the operand for '{}' goes in the accumulator ('{}')",
+        op,
+        rax()
+    ));
+    load(code, generator, sub, rax());
+    decode(code, generator, rax());
+    code.comment(format!(
+        "apply the '{}' and leave the result in the accumulator ('{}')",
+        op,
+        rax()
+    ));
+    match (op, generator.int_width) {
+        (Neg, IntWidth::I64) => code.neg(rax()),
+        (Neg, IntWidth::I32) => code.negl(rax()).cltq(),
+        (Not, _) => code
+            .comment("flipping the lowest bit swaps the binary encodings of 'true' and 'false'")
+            .xor(constant(1), rax()),
+    };
+    encode(code, generator);
+}
+
+// moves the operands of a binary operator into the accumulator and '%rbx'
+fn load_operands(code: &mut Code, generator: &mut Generator, op: &str, left: &Atom, right: &Atom) {
+    code.comment(format!(
+        "the left operand of the '{}' goes in the accumulator ('{}') and the right in '{}'",
+        op,
+        rax(),
+        rbx()
+    ));
+    load(code, generator, left, rax());
+    load(code, generator, right, rbx());
+}
+
+fn lower_binop(code: &mut Code, generator: &mut Generator, op: BinOp, left: &Atom, right: &Atom) {
+    use self::BinOp::*;
+    let long = generator.int_width == IntWidth::I32;
+    load_operands(code, generator, &op.to_string(), left, right);
+    // '==' only ever compares pointers (ints, bools and '()' are compared with
+    // '=' instead)
+    if op != PhysEq {
+        decode(code, generator, rax());
+        decode(code, generator, rbx());
+    }
+    match op {
+        Add | Sub | Mul if long => {
+            code.comment(format!("for the '{}', use the 32-bit instruction on the bottom halves of '{}' and '{}', then sign extend the result back to 64 bits", op, rbx(), rax()));
+            match op {
+                Add => code.addl(rbx(), rax()),
+                Sub => code.subl(rbx(), rax()),
+                _ => code.imull(rbx(), rax()),
             }
-            _ => {
-                self.comment(format!("compute the operands for the '{}'", op))
-                    .emit(left, generator)
-                    .comment(format!("the value of the left operand of the '{}' is left in the accumulator ('{}') so we save this", op, rax()))
-                    .push(rax())
-                    .emit(right, generator)
-                    .comment(format!("the value of the right operand of the '{}' is left in the accumulator ('{}')", op, rax()))
-                    .comment(format!("move this into '{}' to make way for the first operand", rbx()))
-                    .mov(rax(), rbx())
-                    .comment(format!("now restore the first operand to the accumulator ('{}')", rax()))
-                    .pop(rax());
-                match op {
-                    Add => self.comment(format!("for the '+', add the value in '{}' to the value in '{}' and leave the result in the accumulator ('{}')", rbx(), rax(), rax())).add(rbx(), rax()),
-                    Sub => self.comment(format!("for the '-', subtract the value in '{}' from the value in '{}' and leave the result in the accumulator ('{}')", rbx(), rax(), rax())).sub(rbx(), rax()),
-                    Mul => self.comment(format!("for the '*', multiply the value in '{}' by the value in '{}' and leave the result in the accumulator ('{}')", rax(), rbx(), rax())).mul(rbx(), rax()),
-                    Div => self
-                        .comment(format!(
-                            "sign extend the accumulator ('{}') into '{}'",
-                            rax(),
-                            rdx()
-                        ))
-                        .cqto()
-                        .comment(format!("for the '/', divide '{}:{}' by '{}' and leave the result in '{}:{}'", rdx(), rax(), rbx(), rdx(), rax()))
-                        .div(rbx()),
-                    Lt => {
-                        let false_label = Label::new();
-                        let exit_label = Label::new();
-                        self.comment(format!("for '<' we compare the values in '{}' and '{}' and branch depending on the result", rax(), rbx())).cmp(rbx(), rax())
-                            .comment(format!("if '{}' >= '{}', we jump to '{}'", rbx(), rax(), false_label))
-                            .jge(false_label)
-                            .comment(format!("if we don't make the jump, move the binary encoding of 'true' into the accumulator ('{}')", rax()))
-                            .mov(constant(1), rax())
-                            .comment(format!("jump over '{}' so that we don't overwrite the accumulator ('{}')", false_label, rax()))
-                            .jmp(exit_label)
-                            .label(false_label)
-                            .comment(format!("we've made the jump so move the binary encoding of 'false' into the accumulator ('{}')", rax()))
-                            .mov(constant(0), rax())
-                            .label(exit_label)
-                    }
-                    Eq => {
-                        let false_label = Label::new();
-                        let exit_label = Label::new();
-                        self.comment(format!("for '=' we compare the values in '{}' and '{}' and branch depending on the result", rax(), rbx())).cmp(rbx(), rax())
-                            .comment(format!("if the values are unequal, we jump to '{}'", false_label))
-                            .jne(false_label)
-                            .comment(format!("if we don't make the jump, move the binary encoding of 'true' into the accumulator ('{}')", rax()))
-                            .mov(constant(1), rax())
-                            .comment(format!("jump over '{}' so that we don't overwrite the accumulator ('{}')", false_label, rax()))
-                            .jmp(exit_label)
-                            .label(false_label)
-                            .comment(format!("we've made the jump so move the binary encoding of 'false' into the accumulator ('{}')", rax()))
-                            .mov(constant(0), rax())
-                            .label(exit_label)
-                    }
-                    _ => unreachable!(),
-                }
+            .cltq()
+        }
+        Add => code.comment(format!("for the '+', add the value in '{}' to the value in '{}' and leave the result in the accumulator ('{}')", rbx(), rax(), rax())).add(rbx(), rax()),
+        Sub => code.comment(format!("for the '-', subtract the value in '{}' from the value in '{}' and leave the result in the accumulator ('{}')", rbx(), rax(), rax())).sub(rbx(), rax()),
+        Mul => code.comment(format!("for the '*', multiply the value in '{}' by the value in '{}' and leave the result in the accumulator ('{}')", rax(), rbx(), rax())).mul(rbx(), rax()),
+        Lt => code
+            .comment(format!("for '<' we compare the values in '{}' and '{}'", rax(), rbx()))
+            .cmp(rbx(), rax())
+            .comment(format!("set the lowest byte of the accumulator ('{}') to 1 if '{}' < '{}' and 0 otherwise", rax(), rax(), rbx()))
+            .setl(rax())
+            .comment("then clear the rest of the accumulator, leaving the binary encoding of the result")
+            .movzb(rax(), rax()),
+        Eq | PhysEq => code
+            .comment(format!("for '{}' we compare the values in '{}' and '{}'", op, rax(), rbx()))
+            .cmp(rbx(), rax())
+            .comment(format!("set the lowest byte of the accumulator ('{}') to 1 if they're equal and 0 otherwise", rax()))
+            .sete(rax())
+            .comment("then clear the rest of the accumulator, leaving the binary encoding of the result")
+            .movzb(rax(), rax()),
+        // '&&' and '||' are lowered to 'if' before they get here
+        _ => unreachable!(),
+    };
+    encode(code, generator);
+}
+
+// floats are kept as their bits in general registers, so they're moved into
+// SSE registers to be worked on ('%xmm0' and '%xmm1', which nothing else uses)
+fn lower_floats(code: &mut Code, generator: &mut Generator, op: BinOp, left: &Atom, right: &Atom) {
+    use self::BinOp::*;
+    use self::Xmm::*;
+    load_operands(code, generator, &op.to_string(), left, right);
+    code.comment(format!(
+        "move the bits of the left operand to '{}' and the right to '{}'",
+        Xmm0, Xmm1
+    ))
+    .movq_to_xmm(rax(), Xmm0)
+    .movq_to_xmm(rbx(), Xmm1);
+    match op {
+        FloatAdd | FloatSub | FloatMul | FloatDiv => {
+            code.comment(format!(
+                "for the '{}', apply it to the values in '{}' and '{}', leaving the result in '{}'",
+                op, Xmm0, Xmm1, Xmm0
+            ));
+            match op {
+                FloatAdd => code.addsd(Xmm1, Xmm0),
+                FloatSub => code.subsd(Xmm1, Xmm0),
+                FloatMul => code.mulsd(Xmm1, Xmm0),
+                _ => code.divsd(Xmm1, Xmm0),
             }
+            .comment(format!(
+                "then move its bits to the accumulator ('{}')",
+                rax()
+            ))
+            .movq_from_xmm(Xmm0, rax());
+            return;
+        }
+        FloatLt => code
+            .comment(format!("for '<' we compare the value in '{}' with the value in '{}'", Xmm1, Xmm0))
+            .ucomisd(Xmm0, Xmm1)
+            .comment(format!("set the lowest byte of the accumulator ('{}') to 1 if '{}' > '{}' (which is never the case if either is a NaN) and 0 otherwise", rax(), Xmm1, Xmm0))
+            .seta(rax())
+            .comment("then clear the rest of the accumulator, leaving the binary encoding of the result")
+            .movzb(rax(), rax()),
+        FloatEq => code
+            .comment(format!("for '=' we set every bit of '{}' if the values in '{}' and '{}' are equal, and clear them otherwise", Xmm0, Xmm0, Xmm1))
+            .cmpeqsd(Xmm1, Xmm0)
+            .comment(format!("move those bits to the accumulator ('{}'), where they're -1 or 0, and negate them to leave the binary encoding of the result", rax()))
+            .movq_from_xmm(Xmm0, rax())
+            .neg(rax()),
+        _ => unreachable!(),
+    };
+    encode(code, generator);
+}
+
+// strings are built and compared by the runtime, which is passed pointers to
+// them (so neither operand is decoded)
+fn lower_strings(code: &mut Code, generator: &mut Generator, op: BinOp, left: &Atom, right: &Atom) {
+    code.comment(format!(
+        "pass the runtime the left operand of the '{}' in '{}' and the right in '{}'",
+        op,
+        rdi(),
+        rsi()
+    ));
+    load(code, generator, left, rdi());
+    load(code, generator, right, rsi());
+    code.comment(format!(
+        "empty '{}' as the C runtime expects this to be 0",
+        rax()
+    ))
+    .xor(rax(), rax());
+    match op {
+        BinOp::Concat => {
+            code.comment(format!(
+                "actually call into the runtime, which leaves the new string in the accumulator ('{}')",
+                rax()
+            ))
+            .call_rt("concat_strings");
+        }
+        _ => {
+            code.comment(format!(
+                "actually call into the runtime, which leaves -1, 0 or 1 in the accumulator ('{}') as the left string comes before, is the same as or comes after the right",
+                rax()
+            ))
+            .call_rt("compare_strings");
+            encode(code, generator);
         }
     }
+}
 
-    fn emit_if(
-        &mut self,
-        condition: Expr,
-        left: Expr,
-        right: Expr,
-        generator: &mut Generator,
-    ) -> &mut Code {
-        let false_label = Label::new();
-        let exit_label = Label::new();
-        self.comment(format!(
-            "compute the condition value for the 'if' expression"
-        ))
-        .emit(condition, generator)
-        .comment(format!(
-            "the condition value for the 'if' expression is left in the accumulator ('{}')",
+// '/' and '%' both come from one 'idiv', which leaves the quotient in '%rax'
+// and the remainder in '%rdx'
+fn lower_div(
+    code: &mut Code,
+    generator: &mut Generator,
+    location: SourceLocation,
+    left: &Atom,
+    right: &Atom,
+    remainder: bool,
+) {
+    let op = if remainder { "%" } else { "/" };
+    let division_by_zero = generator.trap(location.clone(), "division by zero");
+    let nonzero_label = Label::new();
+    let divide_label = Label::new();
+    let exit_label = Label::new();
+    load_operands(code, generator, op, left, right);
+    decode(code, generator, rax());
+    decode(code, generator, rbx());
+    code.comment(format!("if the divisor in '{}' is not 0, we jump to '{}'", rbx(), nonzero_label))
+        .cmp(constant(0), rbx())
+        .jne(nonzero_label)
+        .comment("otherwise, pass the runtime a record of where the division happened so it can report the error")
+        .lea(relative(rip(), division_by_zero), rdi())
+        .call_rt("trap")
+        .label(nonzero_label)
+        .comment(format!("'idivq' faults when dividing the smallest integer by -1, so if the divisor isn't -1 we jump to '{}'", divide_label))
+        .cmp(constant(-1), rbx())
+        .jne(divide_label);
+    if remainder {
+        code.comment("the remainder of dividing by -1 is always 0")
+            .mov(constant(0), rax())
+            .jmp(exit_label);
+    } else {
+        let overflow = generator.trap(location, "overflow in division");
+        code.comment(format!("dividing by -1 is negation, which only overflows for the smallest integer, so otherwise jump to '{}'", exit_label));
+        match generator.int_width {
+            IntWidth::I64 => code.neg(rax()),
+            IntWidth::I32 => code.negl(rax()),
+        };
+        code.jno(exit_label)
+            .lea(relative(rip(), overflow), rdi())
+            .call_rt("trap");
+    }
+    code.label(divide_label).comment(format!(
+        "sign extend the accumulator ('{}') into '{}'",
+        rax(),
+        rdx()
+    ));
+    match generator.int_width {
+        IntWidth::I64 => code
+            .cqto()
+            .comment(format!("for the '{}', divide '{}:{}' by '{}' and leave the quotient in '{}' and the remainder in '{}'", op, rdx(), rax(), rbx(), rax(), rdx()))
+            .div(rbx()),
+        IntWidth::I32 => code
+            .cltd()
+            .comment(format!("for the '{}', divide the bottom halves of '{}:{}' by '{}' and leave the quotient in '{}' and the remainder in '{}'", op, rdx(), rax(), rbx(), rax(), rdx()))
+            .idivl(rbx()),
+    };
+    if remainder {
+        code.comment(format!(
+            "the result of the '%' is the remainder, so move it into the accumulator ('{}')",
             rax()
         ))
-        .comment(format!(
-            "test if this is equal to the binary encoding for 'true'"
-        ))
-        .cmp(constant(1), rax())
-        .comment(format!("if it is not, we jump to '{}'", false_label))
-        .jne(false_label)
-        .comment(format!(
-            "if we haven't made the jump, we continue the computation for the 'true' branch"
+        .mov(rdx(), rax());
+    }
+    code.label(exit_label);
+    if generator.int_width == IntWidth::I32 {
+        code.comment("sign extend the result back to 64 bits")
+            .cltq();
+    }
+    encode(code, generator);
+}
+
+fn lower_if(
+    code: &mut Code,
+    generator: &mut Generator,
+    condition: &Atom,
+    left: Block,
+    right: Block,
+    likely: Option<bool>,
+) {
+    let false_label = Label::new();
+    let exit_label = Label::new();
+    code.comment(format!(
+        "the condition value for the 'if' expression goes in the accumulator ('{}')",
+        rax()
+    ));
+    load(code, generator, condition, rax());
+    test_false(code, generator);
+    if likely == Some(false) {
+        // the branch that's expected to run is the one that's fallen into
+        let true_label = Label::new();
+        code.comment(format!(
+            "if it isn't, we jump to '{}' (which isn't expected)",
+            true_label
         ))
+        .jne(true_label)
+        .comment("if we haven't made the jump, we continue the computation for the 'false' branch")
+        .emit(right, generator)
+        .comment("when we're done, jump over the 'true' branch")
+        .jmp(exit_label)
+        .label(true_label)
+        .comment("if we have made the jump, we continue the computation for the 'true' branch")
+        .emit(left, generator)
+        .label(exit_label);
+        return;
+    }
+    code.comment(format!("if it is, we jump to '{}'", false_label))
+        .je(false_label)
+        .comment("if we haven't made the jump, we continue the computation for the 'true' branch")
         .emit(left, generator)
-        .comment(format!("when we're done, jump over the 'false' branch"))
+        .comment("when we're done, jump over the 'false' branch")
         .jmp(exit_label)
         .label(false_label)
-        .comment(format!(
-            "if we have made the jump, we continue the computation for the 'false' branch"
-        ))
+        .comment("if we have made the jump, we continue the computation for the 'false' branch")
         .emit(right, generator)
+        .label(exit_label);
+}
+
+fn lower_while(code: &mut Code, generator: &mut Generator, condition: Block, body: Block) {
+    let loop_label = Label::new();
+    let exit_label = Label::new();
+    // nothing in a loop is in tail position, as the loop still has to finish
+    let tail = code.in_tail(false);
+    code.label(loop_label)
+        .comment("compute the condition value for the 'while' loop")
+        .emit(condition, generator)
+        .comment(format!(
+            "the condition value for the 'while' loop is left in the accumulator ('{}')",
+            rax()
+        ));
+    test_false(code, generator);
+    code.comment(format!(
+        "if it is, we jump to '{}' as we have finished the loop",
+        exit_label
+    ))
+    .je(exit_label)
+    .comment("if we haven't made the jump, recompute the body of the while loop")
+    .emit(body, generator)
+    .comment(format!(
+        "once we've recomputed the body of the loop, we jump back to '{}' to loop again",
+        loop_label
+    ))
+    .jmp(loop_label)
+    .label(exit_label)
+    .comment("the value of a 'while' loop is '()'")
+    .xor(rax(), rax())
+    .in_tail(tail);
+}
+
+// the counter of a 'for' loop lives in the loop variable's location, and is
+// compared with the upper bound after the body rather than before it, so the
+// loop stops on reaching the bound without ever stepping past it (which would
+// overflow if the bound were the largest int)
+fn lower_for(
+    code: &mut Code,
+    generator: &mut Generator,
+    from: &Atom,
+    to: &Atom,
+    v: String,
+    body: Block,
+) {
+    let loop_label = Label::new();
+    let exit_label = Label::new();
+    // nothing in a loop is in tail position, as the loop still has to finish
+    let tail = code.in_tail(false);
+    code.comment(format!(
+        "the lower bound of the 'for' loop goes in the accumulator ('{}')",
+        rax()
+    ));
+    load(code, generator, from, rax());
+    let loc = code.allocate(v.clone());
+    code.comment(format!(
+        "the counter starts at the lower bound, so move it into the allocated location of '{}' ('{}')",
+        v, loc
+    ))
+    .mov(rax(), loc)
+    .comment(format!("the upper bound goes in '{}'", rbx()));
+    load(code, generator, to, rbx());
+    decode(code, generator, rax());
+    decode(code, generator, rbx());
+    code.comment(format!(
+        "if the lower bound is greater than the upper bound the body never runs, so jump to '{}'",
+        exit_label
+    ))
+    .cmp(rbx(), rax())
+    .jg(exit_label)
+    .label(loop_label)
+    .comment("compute the body of the 'for' loop")
+    .emit(body, generator)
+    .comment(format!(
+        "move the counter into the accumulator ('{}') and the upper bound into '{}'",
+        rax(),
+        rbx()
+    ))
+    .mov(loc, rax());
+    load(code, generator, to, rbx());
+    decode(code, generator, rax());
+    decode(code, generator, rbx());
+    code.comment(format!(
+        "if the counter has reached the upper bound, we jump to '{}' as we have finished the loop",
+        exit_label
+    ))
+    .cmp(rbx(), rax())
+    .jge(exit_label)
+    .comment("otherwise increment the counter")
+    .add(constant(1), rax());
+    encode(code, generator);
+    code.mov(rax(), loc)
+        .comment(format!("then jump back to '{}' to loop again", loop_label))
+        .jmp(loop_label)
         .label(exit_label)
-    }
+        .comment(format!("'{}' goes out of scope here", v));
+    code.deallocate(v);
+    code.comment("the value of a 'for' loop is '()'")
+        .xor(rax(), rax())
+        .in_tail(tail);
+}
 
-    fn emit_while(&mut self, condition: Expr, sub: Expr, generator: &mut Generator) -> &mut Code {
-        let loop_label = Label::new();
-        let exit_label = Label::new();
-        self.label(loop_label)
-            .comment(format!("compute the condition value for the 'while' loop"))
-            .emit(condition, generator)
-            .comment(format!(
-                "the condition value for the 'while' loop is left in the accumulator ('{}')",
-                rax()
-            ))
-            .comment(format!(
-                "test if this is equal to the binary encoding for 'true'"
-            ))
-            .cmp(constant(1), rax())
-            .comment(format!(
-                "if it is not, we jump to '{}' as we have finished the loop",
-                exit_label
-            ))
-            .jne(exit_label)
-            .comment(format!(
-                "if we haven't made the jump, recompute the body of the while loop"
-            ))
-            .emit(sub, generator)
-            .comment(format!(
-                "once we've recomputed the body of the loop, we jump back to '{}' to loop again",
-                loop_label
-            ))
-            .jmp(loop_label)
-            .label(exit_label)
+// every object on the heap has a header saying what kind of object it is,
+// which the runtime fills in from the kind passed to 'alloc' (the kinds have to
+// match those in the runtime)
+const KIND_PAIR: i64 = 0;
+const KIND_UNION: i64 = 1;
+const KIND_REF: i64 = 2;
+
+// builds a block on the heap by calling into the runtime, then fills it in with
+// the values of some atoms (at the given offsets)
+fn lower_alloc(
+    code: &mut Code,
+    generator: &mut Generator,
+    what: &str,
+    kind: i64,
+    fields: &[(i64, &Atom)],
+) {
+    code.comment(format!(
+        "to build the {} we need to call into the runtime, passing it the kind of object in '{}'",
+        what,
+        rdi()
+    ))
+    .mov(constant(kind), rdi())
+    .comment(format!(
+        "empty '{}' as the C runtime expects this to be 0",
+        rax()
+    ))
+    .xor(rax(), rax())
+    .comment("actually call the runtime")
+    .call_rt("alloc")
+    .comment(format!(
+        "the heap pointer for the {} will be left in the accumulator ('{}')",
+        what,
+        rax()
+    ));
+    for &(offset, atom) in fields {
+        load(code, generator, atom, rbx());
+        code.comment(format!(
+            "move the value in '{}' into its place on the heap ('{}')",
+            rbx(),
+            deref(rax(), offset)
+        ))
+        .mov(rbx(), deref(rax(), offset));
     }
+}
+
+// a list that isn't empty is a pair of its head and its tail, and the empty list
+// is 0 (which no pair is ever at), so this leaves one part of a pair in the
+// accumulator, trapping if there's no pair
+fn lower_list_part(
+    code: &mut Code,
+    generator: &mut Generator,
+    location: SourceLocation,
+    sub: &Atom,
+    head: bool,
+) {
+    let (part, offset, message) = if head {
+        ("head", 0, "head of empty list")
+    } else {
+        ("tail", 8, "tail of empty list")
+    };
+    let empty = generator.trap(location, message);
+    let cons = Label::new();
+    load(code, generator, sub, rax());
+    code.comment(format!(
+        "test whether the list in the accumulator ('{}') is empty, and if it isn't jump to '{}'",
+        rax(),
+        cons
+    ))
+    .test(rax(), rax())
+    .jne(cons)
+    .comment(format!(
+        "otherwise, pass the runtime a record of where the {} was taken so it can report the error",
+        part
+    ))
+    .lea(relative(rip(), empty), rdi())
+    .call_rt("trap")
+    .label(cons)
+    .comment(format!(
+        "take the {} from the pair and leave it in the accumulator ('{}')",
+        part,
+        rax()
+    ))
+    .mov(deref(rax(), offset), rax());
+}
+
+fn lower_assign(code: &mut Code, generator: &mut Generator, left: &Atom, right: &Atom) {
+    code.comment(format!(
+        "the reference for the assignment goes in '{}' and the value in the accumulator ('{}')",
+        rbx(),
+        rax()
+    ));
+    load(code, generator, left, rbx());
+    load(code, generator, right, rax());
+    code.comment(format!(
+        "move the value in the accumulator ('{}') into the referenced memory location",
+        rax()
+    ))
+    .mov(rax(), deref(rbx(), 0))
+    .comment("after assignment we zero the accumulator to represent returning ()")
+    .xor(rax(), rax());
+}
+
+fn lower_make_array(
+    code: &mut Code,
+    generator: &mut Generator,
+    location: SourceLocation,
+    length: &Atom,
+    sub: &Atom,
+) {
+    let negative = generator.trap(location, "negative array length");
+    code.comment(format!(
+        "pass the runtime the length in '{}', the value every element starts out as in '{}' and a record of where the array is made in '{}' (in case the length is negative)",
+        rdi(),
+        rsi(),
+        rdx()
+    ));
+    load(code, generator, length, rdi());
+    decode(code, generator, rdi());
+    load(code, generator, sub, rsi());
+    code.lea(relative(rip(), negative), rdx())
+        .comment(format!(
+            "empty '{}' as the C runtime expects this to be 0",
+            rax()
+        ))
+        .xor(rax(), rax())
+        .comment(format!(
+            "actually call into the runtime, which leaves the new array in the accumulator ('{}')",
+            rax()
+        ))
+        .call_rt("make_array");
+}
+
+// leaves the address of an array's element in the accumulator, with the array
+// in the accumulator and the index in '%rbx' to begin with, trapping if the
+// index is out of bounds
+fn lower_element(code: &mut Code, generator: &mut Generator, location: SourceLocation) {
+    let out_of_bounds = generator.trap(location, "array index out of bounds");
+    let in_bounds = Label::new();
+    decode(code, generator, rbx());
+    code.comment(format!(
+        "compare the index in '{}' with the length of the array (at '{}') as unsigned numbers, so that a negative index is out of bounds too, and if it's below the length jump to '{}'",
+        rbx(),
+        deref(rax(), 0),
+        in_bounds
+    ))
+    .cmp(deref(rax(), 0), rbx())
+    .jb(in_bounds)
+    .comment("otherwise, pass the runtime a record of where the array was indexed so it can report the error")
+    .lea(relative(rip(), out_of_bounds), rdi())
+    .call_rt("trap")
+    .label(in_bounds)
+    .comment(format!(
+        "each element takes 8 bytes, so multiply the index by 8 and add it to the address of the array in the accumulator ('{}')",
+        rax()
+    ))
+    .shl(constant(3), rbx())
+    .add(rbx(), rax())
+    .comment(format!(
+        "the elements come after the length, so the element is at '{}'",
+        deref(rax(), 8)
+    ));
+}
+
+fn lower_index(
+    code: &mut Code,
+    generator: &mut Generator,
+    location: SourceLocation,
+    left: &Atom,
+    right: &Atom,
+) {
+    load_operands(code, generator, "[]", left, right);
+    lower_element(code, generator, location);
+    code.comment(format!(
+        "load the element into the accumulator ('{}')",
+        rax()
+    ))
+    .mov(deref(rax(), 8), rax());
+}
+
+fn lower_update(
+    code: &mut Code,
+    generator: &mut Generator,
+    location: SourceLocation,
+    left: &Atom,
+    index: &Atom,
+    right: &Atom,
+) {
+    load_operands(code, generator, "[] :=", left, index);
+    lower_element(code, generator, location);
+    code.comment(format!("the value to write goes in '{}'", rbx()));
+    load(code, generator, right, rbx());
+    code.comment(format!("move the value in '{}' into the element", rbx()))
+        .mov(rbx(), deref(rax(), 8))
+        .comment("after the write we zero the accumulator to represent returning ()")
+        .xor(rax(), rax());
+}
+
+fn lower_app(code: &mut Code, generator: &mut Generator, left: &Atom, right: &Atom) {
+    code.comment(format!(
+        "the argument goes in '{}' as this is where our closure will expect it to be",
+        rdi()
+    ));
+    load(code, generator, right, rdi());
+    code.comment(format!(
+        "the closure pointer goes in the accumulator ('{}')",
+        rax()
+    ));
+    load(code, generator, left, rax());
+    code.comment(format!("move the pointer to the closure's environment from '{}' into '{}' as this is where it will expect it to be", deref(rax(), 8), rsi()))
+        .mov(deref(rax(), 8), rsi())
+        .comment(format!("move the the code pointer for the closure from '{}' into the accumulator ('{}')", deref(rax(), 0), rax()))
+        .mov(deref(rax(), 0), rax())
+        .comment("call the closure")
+        .call(rax());
+}
+
+// a recursive function calling itself as the last thing it does doesn't need
+// a new frame: the argument is passed in the same register as for a call, and
+// the function jumps back to just before it stores it (its environment is the
+// same, so the rest of the frame is already set up)
+fn lower_tail_call(code: &mut Code, generator: &mut Generator, start: Label, right: &Atom) {
+    code.comment(format!(
+        "this call is the last thing the function does, so we reuse its frame, passing the argument in '{}' as usual",
+        rdi()
+    ));
+    load(code, generator, right, rdi());
+    code.comment(format!(
+        "rather than calling the function, jump back to the start of its body ('{}')",
+        start
+    ))
+    .jmp(start);
+}
 
-    fn emit_seq(&mut self, seq: Vec<Expr>, generator: &mut Generator) -> &mut Code {
-        for (i, sub) in seq.into_iter().enumerate() {
-            if i > 0 {
-                self.comment(format!("we move onto the next instruction in a sequence here, so we simply ignore the value in the accumulator ('{}')", rax()));
+// whether a function's body is nothing but the function taking its next
+// parameter, as for each parameter but the last of a curried function
+fn is_curried(body: &Block) -> bool {
+    body.bindings.is_empty() && matches!(body.result, Op::Lambda(..))
+}
+
+// the arguments, in order, if the block's result gives the curried function 'f'
+// all 'arity' of them, through partial applications bound to variables used for
+// nothing else, moving those bindings to the end of the block (nothing needs
+// them until the result, and they're only applications of 'f', which have no
+// effects until the last)
+fn saturated_call(f: &str, arity: usize, block: &mut Block) -> Option<Vec<Atom>> {
+    let (mut function, last) = match block.result {
+        Op::App(Atom::Var(ref function), ref last) => (function.clone(), last.clone()),
+        _ => return None,
+    };
+    let mut args = vec![last];
+    let mut partial = vec![];
+    while args.len() < arity {
+        let uses = block
+            .bindings
+            .iter()
+            .filter(|binding| binding.fv().contains(&function))
+            .count()
+            + block.result.fv().contains(&function) as usize;
+        let i = block.bindings.iter().position(|binding| match *binding {
+            Binding::Let(ref v, _) => *v == function,
+            _ => false,
+        })?;
+        match block.bindings[i] {
+            Binding::Let(_, Op::App(Atom::Var(ref g), ref arg))
+                if uses == 1 && !arg.fv().contains(&function) =>
+            {
+                function = g.clone();
+                args.push(arg.clone());
+                partial.push(i);
             }
-            self.emit(sub, generator);
+            _ => return None,
         }
-        self
     }
+    if function != f {
+        return None;
+    }
+    args.reverse();
+    partial.sort();
+    let mut moved = partial
+        .into_iter()
+        .rev()
+        .map(|i| block.bindings.remove(i))
+        .collect::<Vec<_>>();
+    moved.reverse();
+    block.bindings.extend(moved);
+    Some(args)
+}
 
-    fn emit_ref(&mut self, sub: Expr, generator: &mut Generator) -> &mut Code {
-        self.comment(format!("compute the value that we want to reference"))
-            .emit(sub, generator)
-            .comment(format!(
-                "the value we want to reference is left in the accumulator ('{}') so we save this",
-                rax()
-            ))
-            .push(rax())
-            .comment(format!(
-                "to build the reference, we need to call into the runtime"
-            ))
-            .comment(format!(
-                "empty '{}' as the C runtime expects this to be 0",
-                rax()
-            ))
-            .xor(rax(), rax())
-            .comment(format!("actually call the runtime"))
-            .call_rt("alloc")
-            .comment(format!(
-                "the newly constructed heap pointer will be left in the accumulator ('{}')",
-                rax()
-            ))
-            .comment(format!(
-                "restore the value we wanted to reference into its new heap location ('{}')",
-                deref(rax(), 0)
-            ))
-            .pop(deref(rax(), 0))
+fn lower_curried_tail_call(
+    code: &mut Code,
+    generator: &mut Generator,
+    start: Label,
+    slots: &[Option<Location>],
+    args: &[Atom],
+) {
+    code.comment(format!(
+        "this call gives the function all of its arguments and is the last thing it does, so we reuse its frame, passing the last argument in '{}' as usual and the others where the parameters before it are kept",
+        rdi()
+    ));
+    // through the stack, as the arguments might be the parameters themselves
+    for (slot, arg) in slots.iter().zip(args) {
+        if slot.is_some() {
+            load(code, generator, arg, rax());
+            code.push(rax());
+        }
     }
+    load(code, generator, &args[args.len() - 1], rdi());
+    for slot in slots.iter().rev().flatten() {
+        code.pop(*slot);
+    }
+    code.comment(format!(
+        "rather than calling the function, jump back to the start of its body ('{}')",
+        start
+    ))
+    .jmp(start);
+}
 
-    fn emit_pair(&mut self, left: Expr, right: Expr, generator: &mut Generator) -> &mut Code {
-        self.comment(format!("compute the left hand value for the pair"))
-            .emit(left, generator)
-            .comment(format!(
-                "the left hand value for the pair is left in the accumulator ('{}') so we save this",
-                rax()
-            ))
-            .push(rax())
-            .comment(format!("compute the right hand value for the pair"))
-            .emit(right, generator)
-            .comment(format!(
-                "the right hand value for the pair is left in the accumulator ('{}') so we save this",
-                rax()
-            ))
-            .push(rax())
-            .comment(format!("to build the pair we need to call into the runtime"))
-            .comment(format!(
-                "empty '{}' as the C runtime expects this to be 0",
+fn lower_case(
+    code: &mut Code,
+    generator: &mut Generator,
+    sub: &Atom,
+    left: (String, Box<Block>),
+    right: (String, Box<Block>),
+) {
+    let inr = Label::new();
+    let skip = Label::new();
+    let ((v_left, left), (v_right, right)) = (left, right);
+    code.comment(format!(
+        "the heap pointer to the union goes in the accumulator ('{}')",
+        rax()
+    ));
+    load(code, generator, sub, rax());
+    code.comment(format!(
+        "extract the position (left: 0, right: 1) from the heap and place it in '{}'",
+        rbx()
+    ))
+    .mov(deref(rax(), 0), rbx())
+    .comment("test if the union is in left or in right")
+    .cmp(constant(0), rbx())
+    .comment(format!("at this point we can move the contents of the union from the heap ('{}') into the accumulator ('{}')", deref(rax(), 8), rax()))
+    .mov(deref(rax(), 8), rax())
+    .comment(format!("if the union is in right, we jump to '{}'", inr))
+    .jne(inr);
+    let loc = code.allocate(v_left.clone());
+    code.comment(format!("if we haven't made the jump, move the union value in the accumulator ('{}') into it's allocated location as '{}' ('{}')", rax(), v_left, loc))
+        .comment("continue with the body of the case")
+        .mov(rax(), loc)
+        .emit(*left, generator)
+        .comment(format!("'{}' goes out of scope here", v_left));
+    code.deallocate(v_left);
+    code.comment(format!(
+        "as we don't want to execute the right branch of the case, we jump over it to '{}'",
+        skip
+    ))
+    .jmp(skip)
+    .label(inr);
+    let loc = code.allocate(v_right.clone());
+    code.comment(format!("if we have made the jump, we move the union value in the accumulator ('{}') into it's allocated location as '{}' ('{}')", rax(), v_right, loc))
+        .mov(rax(), loc)
+        .comment("continue with the body of the case")
+        .emit(*right, generator)
+        .comment(format!("'{}' goes out of scope here", v_right));
+    code.deallocate(v_right);
+    code.label(skip);
+}
+
+// a lazy value is a thunk on the heap: a tag saying whether it's been forced,
+// the code that computes it (which is called like a closure, with a pointer to
+// the rest of the thunk as its environment) and then the value it computed
+// (the tags have to match those in the runtime)
+const THUNK_FORCED: i64 = 2;
+
+fn lower_force(code: &mut Code, generator: &mut Generator, location: SourceLocation, sub: &Atom) {
+    let forcing = generator.trap(location, "lazy value forced while it was being computed");
+    let slow_label = Label::new();
+    let exit_label = Label::new();
+    code.comment(format!(
+        "the thunk to force goes in the accumulator ('{}')",
+        rax()
+    ));
+    load(code, generator, sub, rax());
+    code.comment(format!(
+        "if its tag ('{}') says it's already been forced we just load its value",
+        deref(rax(), 0)
+    ))
+    .cmp(constant(THUNK_FORCED), deref(rax(), 0))
+    .jne(slow_label)
+    .mov(deref(rax(), 16), rax())
+    .jmp(exit_label)
+    .label(slow_label)
+    .comment(format!(
+        "otherwise the runtime computes it, so pass it the thunk in '{}' and a record of where it was forced in '{}' (in case it's forced again while it's being computed)",
+        rdi(),
+        rsi()
+    ))
+    .mov(rax(), rdi())
+    .lea(relative(rip(), forcing), rsi())
+    .comment(format!(
+        "empty '{}' as the C runtime expects this to be 0",
+        rax()
+    ))
+    .xor(rax(), rax())
+    .call_rt("force")
+    .label(exit_label);
+}
+
+// sending and receiving wait in the runtime until another thread is ready to
+// receive or send, which is where a program that deadlocks fails
+fn lower_send(
+    code: &mut Code,
+    generator: &mut Generator,
+    location: SourceLocation,
+    left: &Atom,
+    right: &Atom,
+) {
+    let deadlock = generator.trap(location, "deadlock: every thread is waiting on a channel");
+    code.comment(format!(
+        "pass the runtime the value in '{}', the channel in '{}' and a record of where we're waiting in '{}' (in case no other thread ever receives it)",
+        rsi(),
+        rdi(),
+        rdx()
+    ));
+    load(code, generator, right, rsi());
+    load(code, generator, left, rdi());
+    code.lea(relative(rip(), deadlock), rdx())
+        .comment(format!(
+            "empty '{}' as the C runtime expects this to be 0",
+            rax()
+        ))
+        .xor(rax(), rax())
+        .comment("actually call into the runtime, which leaves '()' in the accumulator")
+        .call_rt("send");
+}
+
+fn lower_recv(code: &mut Code, generator: &mut Generator, location: SourceLocation, sub: &Atom) {
+    let deadlock = generator.trap(location, "deadlock: every thread is waiting on a channel");
+    code.comment(format!(
+        "pass the runtime the channel in '{}' and a record of where we're waiting in '{}' (in case no other thread ever sends on it)",
+        rdi(),
+        rsi()
+    ));
+    load(code, generator, sub, rdi());
+    code.lea(relative(rip(), deadlock), rsi())
+        .comment(format!(
+            "empty '{}' as the C runtime expects this to be 0",
+            rax()
+        ))
+        .xor(rax(), rax())
+        .comment(format!(
+            "actually call into the runtime, which leaves the value it received in the accumulator ('{}')",
+            rax()
+        ))
+        .call_rt("recv");
+}
+
+// an exception is raised by the runtime, which goes back to the innermost
+// 'try' that's running (or fails where it was raised if there isn't one), so the
+// call never returns
+fn lower_raise(code: &mut Code, generator: &mut Generator, location: SourceLocation, sub: &Atom) {
+    let uncaught = generator.trap(location, "uncaught exception");
+    code.comment(format!(
+        "pass the runtime the exception in '{}' and a record of where it was raised in '{}' (in case nothing handles it)",
+        rdi(),
+        rsi()
+    ));
+    load(code, generator, sub, rdi());
+    code.lea(relative(rip(), uncaught), rsi())
+        .comment(format!(
+            "empty '{}' as the C runtime expects this to be 0",
+            rax()
+        ))
+        .xor(rax(), rax())
+        .comment("actually call into the runtime, which never comes back here")
+        .call_rt("raise_exception");
+}
+
+// the body and the handler of a 'try' are both closures, which the runtime
+// calls so that it can go back to where it called the body from if it raises
+// an exception
+fn lower_try(code: &mut Code, generator: &mut Generator, body: &Atom, handler: &Atom) {
+    code.comment(format!(
+        "pass the runtime the body in '{}' and the handler in '{}'",
+        rdi(),
+        rsi()
+    ));
+    load(code, generator, handler, rsi());
+    load(code, generator, body, rdi());
+    code.comment(format!(
+        "empty '{}' as the C runtime expects this to be 0",
+        rax()
+    ))
+    .xor(rax(), rax())
+    .comment(format!(
+        "actually call into the runtime, which leaves the value of whichever it called in the accumulator ('{}')",
+        rax()
+    ))
+    .call_rt("try_with");
+}
+
+// leaves the value of an operation in the accumulator
+fn lower(code: &mut Code, generator: &mut Generator, op: Op) {
+    match op {
+        Op::Atom(atom) => load(code, generator, &atom, rax()),
+        Op::What => {
+            code.comment("to compute the value of a '?' we need to call the runtime")
+                .comment(format!(
+                    "empty '{}' as the C runtime expects this to be 0",
+                    rax()
+                ))
+                .xor(rax(), rax())
+                .comment("actually call into the runtime")
+                .call_rt("what");
+            if generator.int_width == IntWidth::I32 {
+                code.comment("the runtime reads 64-bit integers, so wrap the result to 32 bits")
+                    .cltq();
+            }
+            encode(code, generator);
+        }
+        Op::Neg(sub) => lower_unop(code, generator, UnOp::Neg, &sub),
+        Op::Not(sub) => lower_unop(code, generator, UnOp::Not, &sub),
+        Op::Binary(op @ BinOp::Concat, left, right)
+        | Op::Binary(op @ BinOp::Compare, left, right) => {
+            lower_strings(code, generator, op, &left, &right)
+        }
+        Op::Binary(
+            op @ (BinOp::FloatAdd
+            | BinOp::FloatSub
+            | BinOp::FloatMul
+            | BinOp::FloatDiv
+            | BinOp::FloatLt
+            | BinOp::FloatEq),
+            left,
+            right,
+        ) => lower_floats(code, generator, op, &left, &right),
+        Op::Binary(op, left, right) => lower_binop(code, generator, op, &left, &right),
+        Op::Div(location, left, right) => {
+            lower_div(code, generator, location, &left, &right, false)
+        }
+        Op::Mod(location, left, right) => lower_div(code, generator, location, &left, &right, true),
+        Op::If(condition, left, right, likely) => {
+            lower_if(code, generator, &condition, *left, *right, likely)
+        }
+        Op::While(condition, body) => lower_while(code, generator, *condition, *body),
+        Op::For(from, to, (v, body)) => lower_for(code, generator, &from, &to, v, *body),
+        Op::Pair(left, right) => lower_alloc(
+            code,
+            generator,
+            "pair",
+            KIND_PAIR,
+            &[(0, &left), (8, &right)],
+        ),
+        Op::Ref(sub) => lower_alloc(code, generator, "reference", KIND_REF, &[(0, &sub)]),
+        Op::Inl(sub) => {
+            lower_alloc(code, generator, "union", KIND_UNION, &[(8, &sub)]);
+            code.comment("we need to mark that we are in the left of a union, so we put a zero into the heap as well")
+                .mov(constant(0), deref(rax(), 0));
+        }
+        Op::Inr(sub) => {
+            lower_alloc(code, generator, "union", KIND_UNION, &[(8, &sub)]);
+            code.comment("we need to mark that we are in the right of a union, so we put a one into the heap as well")
+                .mov(constant(1), deref(rax(), 0));
+        }
+        Op::Fst(sub) => {
+            load(code, generator, &sub, rax());
+            code.comment(format!(
+                "project the first element from the pair and leave it the accumulator ('{}')",
                 rax()
             ))
-            .xor(rax(), rax())
-            .comment(format!("actually call the runtime"))
-            .call_rt("alloc")
-            .comment(format!("the heap pointer for the pair will be left in the accumulator ('{}')", rax()))
-            .comment(format!("we recover the right hand value and move it into the heap ('{}')", deref(rax(), 8)))
-            .pop(deref(rax(), 8))
-            .comment(format!("we recover the left hand value and move it into the heap ('{}')", deref(rax(), 8)))
-            .pop(deref(rax(), 0))
-    }
-
-    fn emit_assign(&mut self, left: Expr, right: Expr, generator: &mut Generator) -> &mut Code {
-        self.comment(format!("compute the reference to assign to"))
-            .emit(left, generator)
-            .comment(format!(
-                "the reference for the assignment is left in the accumulator ('{}') so we save this",
+            .mov(deref(rax(), 0), rax());
+        }
+        Op::Snd(sub) => {
+            load(code, generator, &sub, rax());
+            code.comment(format!(
+                "project the second element from the pair and leave it the accumulator ('{}')",
                 rax()
             ))
-            .push(rax())
-            .comment(format!("compute the value we want to assign"))
-            .emit(right, generator)
-            .comment(format!(
-                "the value for the assignment is left in the accumulaotr ('{}')",
+            .mov(deref(rax(), 8), rax());
+        }
+        Op::Deref(sub) => {
+            load(code, generator, &sub, rax());
+            code.mov(deref(rax(), 0), rax());
+        }
+        Op::Assign(left, right) => lower_assign(code, generator, &left, &right),
+        Op::MakeArray(location, length, sub) => {
+            lower_make_array(code, generator, location, &length, &sub)
+        }
+        Op::Index(location, left, right) => lower_index(code, generator, location, &left, &right),
+        Op::Update(location, left, index, right) => {
+            lower_update(code, generator, location, &left, &index, &right)
+        }
+        Op::Case(sub, left, right) => lower_case(code, generator, &sub, left, right),
+        Op::Nil => {
+            code.comment("the empty list is represented by 0")
+                .xor(rax(), rax());
+        }
+        Op::Cons(left, right) => lower_alloc(
+            code,
+            generator,
+            "list",
+            KIND_PAIR,
+            &[(0, &left), (8, &right)],
+        ),
+        Op::Head(location, sub) => lower_list_part(code, generator, location, &sub, true),
+        Op::Tail(location, sub) => lower_list_part(code, generator, location, &sub, false),
+        Op::IsEmpty(sub) => {
+            load(code, generator, &sub, rax());
+            code.comment(format!(
+                "test whether the list in the accumulator ('{}') is empty (which is 0)",
                 rax()
             ))
-            .comment(format!("recover the reference and leave it in '{}'", rbx()))
-            .pop(rbx())
+            .test(rax(), rax())
             .comment(format!(
-                "move the value in the accumulator ('{}') into the referenced memory location)",
+                "set the lowest byte of the accumulator ('{}') to 1 if it is and 0 otherwise",
                 rax()
             ))
-            .mov(rax(), deref(rbx(), 0))
-            .comment(format!(
-                "after assignment we zero the accumulator to represent returning ()"
+            .sete(rax())
+            .comment(
+                "then clear the rest of the accumulator, leaving the binary encoding of the result",
+            )
+            .movzb(rax(), rax());
+            encode(code, generator);
+        }
+        // the computation is compiled as a function that ignores its argument
+        Op::Lazy(sub) => {
+            code.emit_closure(
+                ("%lazy".to_string(), sub),
+                "<lazy>",
+                "make_thunk",
+                generator,
+            );
+        }
+        Op::Force(location, sub) => lower_force(code, generator, location, &sub),
+        Op::Chan => {
+            code.comment("to make a channel we need to call the runtime")
+                .comment(format!(
+                    "empty '{}' as the C runtime expects this to be 0",
+                    rax()
+                ))
+                .xor(rax(), rax())
+                .comment("actually call into the runtime")
+                .call_rt("make_channel");
+        }
+        // the thread is compiled as a function that ignores its argument, which
+        // the runtime starts a thread to call
+        Op::Spawn(sub) => {
+            code.emit_closure(("%spawn".to_string(), sub), "<thread>", "spawn", generator);
+        }
+        Op::Str(string) => {
+            let length = generator.int(string.len() as i64);
+            let label = generator.string(string);
+            code.comment(format!(
+                "to make a string we pass the runtime its bytes (at '{}') in '{}' and how many there are in '{}'",
+                label,
+                rdi(),
+                rsi()
             ))
-            .xor(rax(), rax())
-    }
-
-    fn emit_app(&mut self, left: Expr, right: Expr, generator: &mut Generator) -> &mut Code {
-        self.comment(format!("get the closure pointer for the application"))
-            .emit(left, generator)
+            .lea(relative(rip(), label), rdi())
+            .mov(length, rsi())
             .comment(format!(
-                "the closure pointer is left in the accumulator ('{}'), so we save this",
+                "empty '{}' as the C runtime expects this to be 0",
                 rax()
             ))
-            .push(rax())
-            .comment(format!("compute the argument for the application"))
-            .emit(right, generator)
+            .xor(rax(), rax())
             .comment(format!(
-                "the argument is left in the accumulator ('{}')",
+                "actually call into the runtime, which leaves the new string in the accumulator ('{}')",
                 rax()
             ))
-            .comment(format!(
-                "move this into '{}' as this is where our closure will expect it to be",
+            .call_rt("make_string");
+        }
+        Op::Print(sub) => {
+            code.comment(format!(
+                "pass the runtime the string to print in '{}'",
                 rdi()
-            ))
-            .mov(rax(), rdi())
-            .comment(format!(
-                "restore the closure pointer to the accumulator ('{}')",
+            ));
+            load(code, generator, &sub, rdi());
+            code.comment(format!(
+                "empty '{}' as the C runtime expects this to be 0",
                 rax()
             ))
-            .pop(rax())
-            .comment(format!("move the pointer to the closure's environment from '{}' into '{}' as this is where it will expect it to be", deref(rax(), 8), rsi()))
-            .mov(deref(rax(), 8), rsi())
-            .comment(format!("move the the code pointer for the closure from '{}' into the accumulator ('{}')", deref(rax(), 0), rax()))
-            .mov(deref(rax(), 0), rax())
-            .comment(format!("call the closure"))
-            .call(rax())
+            .xor(rax(), rax())
+            .comment("actually call into the runtime, which leaves '()' in the accumulator")
+            .call_rt("print_string");
+        }
+        Op::Send(location, left, right) => lower_send(code, generator, location, &left, &right),
+        Op::Recv(location, sub) => lower_recv(code, generator, location, &sub),
+        Op::Raise(location, sub) => lower_raise(code, generator, location, &sub),
+        Op::Try(body, handler) => lower_try(code, generator, &body, &handler),
+        Op::Lambda(v, body) => {
+            code.emit_closure((v, body), "<fun>", "make_closure", generator);
+        }
+        Op::App(left, right) => {
+            let start = match left {
+                Atom::Var(ref f) => code.tail_call(f),
+                _ => None,
+            };
+            match start {
+                Some(start) => lower_tail_call(code, generator, start, &right),
+                None => lower_app(code, generator, &left, &right),
+            }
+        }
     }
+}
 
-    fn emit_lambda(&mut self, lambda: (String, Box<Expr>), generator: &mut Generator) -> &mut Code {
-        let fv = lambda
+impl Code {
+    // builds a closure (or anything else the runtime builds in the same way) by
+    // calling 'constructor' with the code for the function and its environment
+    fn emit_closure(
+        &mut self,
+        lambda: (String, Box<Block>),
+        name: &str,
+        constructor: &'static str,
+        generator: &mut Generator,
+    ) -> &mut Code {
+        let (v, body) = (lambda.0, *lambda.1);
+        // sorted, so that the same program always gets the same closure layout
+        let mut fv = body
             .fv()
             .into_iter()
-            .map(|x| x.clone())
+            .filter(|&x| x != &v)
+            .cloned()
             .collect::<Vec<_>>();
-        let (v, expr) = (lambda.0, *lambda.1);
+        fv.sort();
         let label = Label::new();
-        let mut lambda = Code::new(label, generator.comments);
+        let mut lambda = Code::new(
+            label,
+            generator.comments,
+            generator.sanitise,
+            generator.harden,
+            generator.omit_frame_pointer,
+            generator.allocate_registers,
+            generator.stack_maps,
+            generator.shadow_stack,
+        );
         lambda.comment(format!(
             "the formal parameter of the function will be left in '{}' and a pointer to the closure's environment will be left in '{}'", rdi(), rsi()
         ));
         let vloc = lambda.allocate(v.clone());
-        if fv.len() > 0 {
-            lambda.comment(format!(
-                "now each of the free variables of the closure is moved into our stack frame"
-            ));
+        if !fv.is_empty() {
+            lambda.comment(
+                "now each of the free variables of the closure is moved into our stack frame",
+            );
         }
         for (i, envv) in fv.iter().enumerate() {
             let loc = lambda.allocate(envv.clone());
@@ -403,27 +1802,58 @@ impl Code {
                 .comment(format!(
                     "'{}' is loaded from the environment ('{}') into its allocated space ('{}')",
                     envv,
-                    deref(rsi(), 8 * (i + 1) as i64),
+                    deref(rsi(), 8 * i as i64),
                     loc
                 ))
                 .mov(deref(rsi(), 8 * i as i64), rax())
                 .mov(rax(), loc);
         }
-        lambda
-            .comment(format!(
-                "move the formal parameter '{}' from '{}' into its allocated space ('{}')",
-                v,
-                rdi(),
-                vloc
-            ))
-            .mov(rdi(), vloc)
-            .emit(expr, generator);
-        generator.add(lambda.ret());
-        self.comment(format!(
-            "to construct the closure, we need to pass the enviroment to the runtime"
+        lambda.comment(format!(
+            "move the formal parameter '{}' from '{}' into its allocated space ('{}')",
+            v,
+            rdi(),
+            vloc
         ));
+        // the rest of a curried recursive function, which a call giving it all
+        // of its arguments can jump back into once it's taken the last
+        if let Some((f, mut params)) = self.curried_params() {
+            if is_curried(&body) {
+                params.push(v.clone());
+                lambda.curried(f, params);
+            } else {
+                lambda.curried_tail_calls(f, &params);
+            }
+        }
+        lambda.mov(rdi(), vloc).emit(body, generator);
+        generator.add(label, name.to_string(), &mut lambda);
+        self.comment("to construct the closure, we need to pass the enviroment to the runtime");
+        self.pass_env(&fv);
+        self.comment(format!(
+            "now we place a pointer to the code for the closure in '{}'",
+            rdi()
+        ))
+        .comment(format!(
+            "note how 'leaq' is used relative to the program counter ('{}')",
+            rip()
+        ))
+        .lea(relative(rip(), label), rdi())
+        .comment(format!("we also need to let the runtime know how many variables are in our environment (in this case {})", fv.len()))
+        .comment(format!("this information is placed in '{}'", rsi()))
+        .mov(constant(fv.len() as i64), rsi())
+        .comment(format!(
+            "empty '{}' as the C runtime expects this to be 0",
+            rax()
+        ))
+        .xor(rax(), rax())
+        .comment("actually call into the runtime")
+        .call_rt(constructor);
+        self.pop_env(fv.len())
+    }
+
+    // passes the values of some variables to a variadic function in the runtime
+    fn pass_env(&mut self, fv: &[String]) {
         for (i, envv) in fv.iter().enumerate().rev() {
-            let loc = self.get(&envv);
+            let loc = self.get(envv);
             match i {
                 0 => self
                     .comment(format!("'{}' ('{}') is moved to '{}'", envv, loc, rdx()))
@@ -442,30 +1872,13 @@ impl Code {
                     .push(loc),
             };
         }
-        let env_len = lambda.get_env().len() - 1;
-        self.comment(format!(
-            "now we place a pointer to the code for the closure in '{}'",
-            rdi()
-        ))
-        .comment(format!(
-            "note how 'leaq' is used relative to the program counter ('{}')",
-            rip()
-        ))
-        .lea(relative(rip(), label), rdi())
-        .comment(format!("we also need to let the runtime know how many variables are in our environment (in this case {})", env_len))
-        .comment(format!("this information is placed in '{}'", rsi()))
-        .mov(constant(env_len as i64), rsi())
-        .comment(format!(
-            "empty '{}' as the C runtime expects this to be 0",
-            rax()
-        ))
-        .xor(rax(), rax())
-        .comment(format!("actually call into the runtime"))
-        .call_rt("make_closure");
+    }
+
+    // drops whatever 'pass_env' pushed once the call has returned
+    fn pop_env(&mut self, env_len: usize) -> &mut Code {
         if env_len > 4 {
             let allocated = (env_len - 4) * 8;
-            self.add(constant(allocated as i64), rsp())
-                .comment(format!("while building the closure we pushed {} bytes onto the stack that we no longer need, so we increment '{}' by {}", allocated, rsp(), allocated)).add(constant(allocated as i64), rsp())
+            self.comment(format!("while building the closure we pushed {} bytes onto the stack that we no longer need, so we increment '{}' by {}", allocated, rsp(), allocated)).add(constant(allocated as i64), rsp())
         } else {
             self
         }
@@ -474,18 +1887,28 @@ impl Code {
     fn emit_recursive_lambda(
         &mut self,
         f: String,
-        lambda: (String, Box<Expr>),
+        lambda: (String, Box<Block>),
         generator: &mut Generator,
     ) -> &mut Code {
-        let fv = lambda
+        let (v, body) = (lambda.0, *lambda.1);
+        let mut fv = body
             .fv()
             .into_iter()
-            .map(|x| x.clone())
-            .filter(|x| x != &f)
+            .filter(|&x| x != &f && x != &v)
+            .cloned()
             .collect::<Vec<_>>();
-        let (v, expr) = (lambda.0, *lambda.1);
+        fv.sort();
         let label = Label::new();
-        let mut lambda = Code::new(label, generator.comments);
+        let mut lambda = Code::new(
+            label,
+            generator.comments,
+            generator.sanitise,
+            generator.harden,
+            generator.omit_frame_pointer,
+            generator.allocate_registers,
+            generator.stack_maps,
+            generator.shadow_stack,
+        );
         let vloc = lambda.allocate(v.clone());
         let floc = lambda.allocate(f.clone());
         lambda
@@ -495,15 +1918,15 @@ impl Code {
             .comment(format!(
                 "the formal parameter of the function will be left in '{}' and a pointer to the closure's environment will be left in '{}'", rdi(), rsi()
             ))
-            .comment(format!("as this is a recursive function, the first item in the environment will be a pointer to the closure itself"))
+            .comment("as this is a recursive function, the first item in the environment will be a pointer to the closure itself")
             .comment(format!("load this into the accumulator ('{}') from '{}'", rax(), deref(rsi(), 0)))
             .mov(deref(rsi(), 0), rax())
             .comment(format!("move '{}'s closure pointer into its allocated space ('{}')", f, floc))
             .mov(rax(), floc);
-        if fv.len() > 0 {
-            lambda.comment(format!(
-                "now each of the free variables of the closure is moved into our stack frame"
-            ));
+        if !fv.is_empty() {
+            lambda.comment(
+                "now each of the free variables of the closure is moved into our stack frame",
+            );
         }
         for (i, envv) in fv.iter().enumerate() {
             let loc = lambda.allocate(envv.clone());
@@ -517,50 +1940,31 @@ impl Code {
                 .mov(deref(rsi(), 8 * (i + 1) as i64), rax())
                 .mov(rax(), loc);
         }
-        lambda
-            .comment(format!(
-                "move the formal parameter '{}' from '{}' into its allocated space ('{}')",
-                v,
-                rdi(),
-                vloc
-            ))
-            .mov(rdi(), vloc)
-            .emit(expr, generator);
-        generator.add(lambda.ret());
+        lambda.comment(format!(
+            "move the formal parameter '{}' from '{}' into its allocated space ('{}')",
+            v,
+            rdi(),
+            vloc
+        ));
+        if generator.tail_calls {
+            lambda.tail_calls(f.clone());
+            if is_curried(&body) {
+                lambda.curried(f.clone(), vec![v.clone()]);
+            }
+        }
+        lambda.mov(rdi(), vloc).emit(body, generator);
+        // renamed functions are still reported by the name they were given
+        generator.add(label, ir::source_name(&f).to_string(), &mut lambda);
         self.comment(format!(
             "to construct the closure for '{}', we need to pass the enviroment to the runtime",
             f
         ));
-        if fv.len() > 0 {
-            self.comment(format!(
-                "here we save each free variable in a way that will be understood by a variadic C function"
-            ));
+        if !fv.is_empty() {
+            self.comment("here we save each free variable in a way that will be understood by a variadic C function");
         } else {
-            self.comment(format!(
-                "in this case, there is no environment so we move on"
-            ));
-        }
-        for (i, envv) in fv.iter().enumerate().rev() {
-            let loc = self.get(&envv);
-            match i {
-                0 => self
-                    .comment(format!("'{}' ('{}') is moved to '{}'", envv, loc, rdx()))
-                    .mov(loc, rdx()),
-                1 => self
-                    .comment(format!("'{}' ('{}') is moved to '{}'", envv, loc, rcx()))
-                    .mov(loc, rcx()),
-                2 => self
-                    .comment(format!("'{}' ('{}') is moved to '{}'", envv, loc, r8()))
-                    .mov(loc, r8()),
-                3 => self
-                    .comment(format!("'{}' ('{}') is moved to '{}'", envv, loc, r9()))
-                    .mov(loc, r9()),
-                _ => self
-                    .comment(format!("'{}' ('{}') is pushed to the stack", envv, loc))
-                    .push(loc),
-            };
+            self.comment("in this case, there is no environment so we move on");
         }
-        let env_len = lambda.get_env().len() - 2;
+        self.pass_env(&fv);
         self.comment(format!(
             "now we place a pointer to the code for the closure in '{}'",
             rdi()
@@ -570,274 +1974,275 @@ impl Code {
             rip()
         ))
         .lea(relative(rip(), label), rdi())
-        .comment(format!("we also need to let the runtime know how many variables are in our environment (in this case {})", env_len))
+        .comment(format!("we also need to let the runtime know how many variables are in our environment (in this case {})", fv.len()))
         .comment(format!("this information is placed in '{}'", rsi()))
-        .mov(constant(env_len as i64), rsi())
+        .mov(constant(fv.len() as i64), rsi())
         .comment(format!(
             "empty '{}' as the C runtime expects this to be 0",
             rax()
         ))
         .xor(rax(), rax())
-        .comment(format!("actually call into the runtime"))
+        .comment("actually call into the runtime")
         .call_rt("make_recursive_closure");
-        if env_len > 4 {
-            let allocated = (env_len - 4) * 8;
-            self.comment(format!("while building the closure we pushed {} bytes onto the stack that we no longer need, so we increment '{}' by {}", allocated, rsp(), allocated)).add(constant(allocated as i64), rsp())
-        } else {
-            self
-        }
-    }
-
-    fn emit_inl(&mut self, sub: Expr, generator: &mut Generator) -> &mut Code {
-        self.comment(format!(
-            "compute the value we want to put in the left of the union"
-        ))
-        .emit(sub, generator)
-        .comment(format!(
-            "the value we want in the left is left in the accumulator ('{}') so we save this",
-            rax()
-        ))
-        .push(rax())
-        .comment(format!(
-            "to build the union we need to call into the runtime"
-        ))
-        .comment(format!(
-            "empty '{}' as the C runtime expects this to be 0",
-            rax()
-        ))
-        .xor(rax(), rax())
-        .comment(format!("actually call into the runtime"))
-        .call_rt("alloc")
-        .comment(format!(
-            "the heap pointer for the union will be left in the accumulator ('{}')",
-            rax()
-        ))
-        .comment(format!(
-            "we restore the computed value into it's new heap location ('{}')",
-            deref(rax(), 8)
-        ))
-        .pop(deref(rax(), 8))
-        .comment(format!(
-            "we need to mark that we are in the left of a union, so we put a zero into the heap as well"
-        ))
-        .mov(constant(0), deref(rax(), 0))
-    }
-
-    fn emit_inr(&mut self, sub: Expr, generator: &mut Generator) -> &mut Code {
-        self.comment(format!(
-            "compute the value we want to put in the right of the union"
-        ))
-        .emit(sub, generator)
-        .comment(format!(
-            "the value we want in the right is left in the accumulator ('{}') so we save this",
-            rax()
-        ))
-        .push(rax())
-        .comment(format!(
-            "to build the union we need to call into the runtime"
-        ))
-        .comment(format!(
-            "empty '{}' as the C runtime expects this to be 0",
-            rax()
-        ))
-        .xor(rax(), rax())
-        .comment(format!("actually call into the runtime"))
-        .call_rt("alloc")
-        .comment(format!(
-            "the heap pointer for the union will be left in the accumulator ('{}')",
-            rax()
-        ))
-        .comment(format!(
-            "we restore the computed value into it's new heap location ('{}')",
-            deref(rax(), 8)
-        ))
-        .pop(deref(rax(), 8))
-        .comment(format!(
-            "we need to mark that we are in the right of a union, so we put a one into the heap as well"
-        ))
-        .mov(constant(1), deref(rax(), 0))
+        self.pop_env(fv.len())
     }
 
-    fn emit_case(
+    // builds the closures for functions defined together, which share one
+    // environment (the closures first, then the free variables of all of
+    // them), leaving a pointer to that environment in the accumulator
+    fn emit_recursive_group(
         &mut self,
-        sub: Expr,
-        left: (String, Box<Expr>),
-        right: (String, Box<Expr>),
+        functions: Vec<(String, String, Box<Block>)>,
         generator: &mut Generator,
     ) -> &mut Code {
-        let inr = Label::new();
-        let skip = Label::new();
-        self.comment(format!(
-            "compute the union that we want to apply the cases to"
-        ))
-        .emit(sub, generator)
-        .comment(format!(
-            "the heap pointer to the union is left in the accumulator ('{}')",
-            rax()
-        ))
-        .comment(format!(
-            "extract the position (left: 0, right: 1) from the heap and place it in '{}'",
-            rbx()
-        ))
-        .mov(deref(rax(), 0), rbx())
-        .comment(format!("test if the union is in left or in right"))
-        .cmp(constant(0), rbx())
-        .comment(format!("at this point we can move the contents of the union from the heap ('{}') into the accumulator ('{}')", deref(rax(), 8), rax()))
-        .mov(deref(rax(), 8), rax())
-        .comment(format!("if the union is in right, we jump to '{}'", inr))
-        .jne(inr);
-        let v_left = self.allocate(left.0.clone());
-        self.comment(format!("if we haven't made the jump, move the union value in the accumulator ('{}') into it's allocated location as '{}' ('{}')", rax(), left.0, v_left))
-            .comment(format!("continue with the body of the case"))
-            .mov(rax(), v_left).emit(*left.1, generator)
-            .comment(format!("'{}' goes out of scope here", left.0));
-        self.deallocate(left.0);
+        let names = functions
+            .iter()
+            .map(|(f, _, _)| f.clone())
+            .collect::<Vec<_>>();
+        let mut fv = functions
+            .iter()
+            .flat_map(|(_, v, body)| body.fv().into_iter().filter(move |&x| x != v))
+            .filter(|&x| !names.contains(x))
+            .cloned()
+            .collect::<Vec<_>>();
+        fv.sort();
+        fv.dedup();
+        let mut labels = vec![];
+        for (f, v, body) in functions {
+            let label = Label::new();
+            let mut lambda = Code::new(
+                label,
+                generator.comments,
+                generator.sanitise,
+                generator.harden,
+                generator.omit_frame_pointer,
+                generator.allocate_registers,
+                generator.stack_maps,
+                generator.shadow_stack,
+            );
+            let vloc = lambda.allocate(v.clone());
+            lambda
+                .comment(format!("this is the emitted code for the function '{}'", f))
+                .comment(format!(
+                    "the formal parameter of the function will be left in '{}' and a pointer to the closure's environment will be left in '{}'", rdi(), rsi()
+                ))
+                .comment("as this function was defined along with others, the environment starts with a pointer to each of their closures (its own included), followed by the free variables of all of them");
+            for (i, envv) in names.iter().chain(fv.iter()).enumerate() {
+                let loc = lambda.allocate(envv.clone());
+                lambda
+                    .comment(format!(
+                        "'{}' is loaded from the environment ('{}') into its allocated space ('{}')",
+                        envv,
+                        deref(rsi(), 8 * i as i64),
+                        loc
+                    ))
+                    .mov(deref(rsi(), 8 * i as i64), rax())
+                    .mov(rax(), loc);
+            }
+            lambda.comment(format!(
+                "move the formal parameter '{}' from '{}' into its allocated space ('{}')",
+                v,
+                rdi(),
+                vloc
+            ));
+            if generator.tail_calls {
+                lambda.tail_calls(f.clone());
+                if is_curried(&body) {
+                    lambda.curried(f.clone(), vec![v.clone()]);
+                }
+            }
+            lambda.mov(rdi(), vloc).emit(*body, generator);
+            // renamed functions are still reported by the name they were given
+            generator.add(label, ir::source_name(&f).to_string(), &mut lambda);
+            labels.push(label);
+        }
         self.comment(format!(
-            "as we don't want to execute the right branch of the case, we jump over it to '{}'",
-            skip
-        ))
-        .jmp(skip)
-        .label(inr);
-        let v_right = self.allocate(right.0.clone());
-        self.comment(format!("if we have made the jump, we move the union value in the accumulator ('{}') into it's allocated location as '{}' ('{}')", rax(), right.0, v_right))
-            .mov(rax(), v_right)
-            .comment(format!("continue with the body of the case"))
-.emit(*right.1, generator)
-            .comment(format!("'{}' goes out of scope here", right.0));
-        self.deallocate(right.0);
-        self.label(skip)
-    }
-
-    fn emit_let(
-        &mut self,
-        v: String,
-        sub: Expr,
-        body: Expr,
-        generator: &mut Generator,
-    ) -> &mut Code {
-        let loc = self.allocate(v.clone());
+            "to construct the closures for {}, we pass the code for each of them, then the shared environment, to the runtime",
+            names
+                .iter()
+                .map(|f| format!("'{}'", f))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+        let count = labels.len();
+        for i in (0..count + fv.len()).rev() {
+            let target = match i {
+                0 => Some(rdx()),
+                1 => Some(rcx()),
+                2 => Some(r8()),
+                3 => Some(r9()),
+                _ => None,
+            };
+            match (i < count, target) {
+                (true, Some(target)) => {
+                    self.comment(format!(
+                        "the code for '{}' is loaded into '{}'",
+                        names[i], target
+                    ))
+                    .lea(relative(rip(), labels[i]), target);
+                }
+                (true, None) => {
+                    self.comment(format!(
+                        "the code for '{}' is pushed to the stack",
+                        names[i]
+                    ))
+                    .lea(relative(rip(), labels[i]), rax())
+                    .push(rax());
+                }
+                (false, Some(target)) => {
+                    let loc = self.get(&fv[i - count]);
+                    self.comment(format!(
+                        "'{}' ('{}') is moved to '{}'",
+                        fv[i - count],
+                        loc,
+                        target
+                    ))
+                    .mov(loc, target);
+                }
+                (false, None) => {
+                    let loc = self.get(&fv[i - count]);
+                    self.comment(format!(
+                        "'{}' ('{}') is pushed to the stack",
+                        fv[i - count],
+                        loc
+                    ))
+                    .push(loc);
+                }
+            }
+        }
         self.comment(format!(
-            "calculate the value for '{}' and leave it in the accumulator ('{}')",
-            v,
-            rax()
+            "the runtime needs to know how many functions there are (in '{}') and how many free variables (in '{}')",
+            rdi(),
+            rsi()
         ))
-        .emit(sub, generator)
+        .mov(constant(count as i64), rdi())
+        .mov(constant(fv.len() as i64), rsi())
         .comment(format!(
-            "move '{}'s calculated value to its allocated space ('{}')",
-            v, loc
+            "empty '{}' as the C runtime expects this to be 0",
+            rax()
         ))
-        .mov(rax(), loc)
-        .comment(format!("run subsequent computation (body of let)"))
-        .emit(body, generator)
-        .comment(format!("'{}' goes out of scope here", v));
-        self.deallocate(v);
-        self
+        .xor(rax(), rax())
+        .comment("actually call into the runtime")
+        .call_rt("make_recursive_closures");
+        self.pop_env(count + fv.len())
     }
 
-    fn emit_let_fun(
-        &mut self,
-        f: String,
-        lambda: (String, Box<Expr>),
-        body: Expr,
-        generator: &mut Generator,
-    ) -> &mut Code {
-        self.comment(format!(
-            "produce the closure for '{}' and leave a pointer to it in the accumulator ('{}')",
-            f,
-            rax()
-        ))
-        .emit_recursive_lambda(f.clone(), lambda, generator);
-        let loc = self.allocate(f.clone());
-        self.comment(format!(
-            "move '{}'s closure pointer to its allocated space ('{}')",
-            f, loc
-        ))
-        .mov(rax(), loc)
-        .comment(format!("run subsequent computation (body of let)"))
-        .emit(body, generator)
-        .comment(format!("'{}' goes out of scope here", f));
-        self.deallocate(f);
+    // runs each binding in a block, then leaves the block's value in the
+    // accumulator (each variable's space is given up as soon as nothing else
+    // uses it, so that a long block doesn't need a frame as long as it is)
+    fn emit(&mut self, mut block: Block, generator: &mut Generator) -> &mut Code {
+        // only the block's result can be in tail position
+        let saturated = self.curried_tail_call().and_then(|(f, slots, start)| {
+            saturated_call(&f, slots.len() + 1, &mut block).map(|args| (slots, args, start))
+        });
+        let mut last_uses = block
+            .last_uses()
+            .into_iter()
+            .map(|vs| vs.into_iter().cloned().collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+            .into_iter();
+        let tail = self.in_tail(false);
+        // the partial applications of a saturated call are never made, but
+        // their arguments are still needed for the call
+        let lowered =
+            block.bindings.len() - saturated.as_ref().map_or(0, |(slots, _, _)| slots.len());
+        let mut skipped = vec![];
+        for (i, binding) in block.bindings.into_iter().enumerate() {
+            if i >= lowered {
+                skipped.extend(last_uses.next().unwrap());
+                continue;
+            }
+            match binding {
+                Binding::Let(v, op) => {
+                    self.comment(format!(
+                        "calculate the value for '{}' and leave it in the accumulator ('{}')",
+                        v,
+                        rax()
+                    ));
+                    lower(self, generator, op);
+                    let loc = self.allocate(v.clone());
+                    self.comment(format!(
+                        "move '{}'s calculated value to its allocated space ('{}')",
+                        v, loc
+                    ))
+                    .mov(rax(), loc);
+                }
+                Binding::LetFun(f, v, body) => {
+                    self.comment(format!(
+                        "produce the closure for '{}' and leave a pointer to it in the accumulator ('{}')",
+                        f,
+                        rax()
+                    ))
+                    .emit_recursive_lambda(f.clone(), (v, body), generator);
+                    let loc = self.allocate(f.clone());
+                    self.comment(format!(
+                        "move '{}'s closure pointer to its allocated space ('{}')",
+                        f, loc
+                    ))
+                    .mov(rax(), loc);
+                }
+                Binding::LetRec(functions) => {
+                    let names = functions
+                        .iter()
+                        .map(|(f, _, _)| f.clone())
+                        .collect::<Vec<_>>();
+                    self.comment(format!(
+                        "produce the closures for the group and leave a pointer to their shared environment in the accumulator ('{}')",
+                        rax()
+                    ))
+                    .emit_recursive_group(functions, generator);
+                    for (i, f) in names.into_iter().enumerate() {
+                        let loc = self.allocate(f.clone());
+                        self.comment(format!(
+                            "move '{}'s closure pointer from the environment ('{}') to its allocated space ('{}')",
+                            f,
+                            deref(rax(), 8 * i as i64),
+                            loc
+                        ))
+                        .mov(deref(rax(), 8 * i as i64), rcx())
+                        .mov(rcx(), loc);
+                    }
+                }
+                Binding::Do(op) => {
+                    lower(self, generator, op);
+                    self.comment(format!("this is only run for its effects, so we simply ignore the value in the accumulator ('{}')", rax()));
+                }
+            }
+            self.release(last_uses.next().unwrap());
+        }
+        self.in_tail(tail);
+        match saturated {
+            Some((slots, args, start)) => {
+                lower_curried_tail_call(self, generator, start, &slots, &args)
+            }
+            None => lower(self, generator, block.result),
+        }
+        self.release(skipped);
+        self.release(last_uses.next().unwrap());
         self
     }
 
-    fn emit(&mut self, expr: Expr, generator: &mut Generator) -> &mut Code {
-        use Expr::*;
-        match expr {
-            Int(i) => self
-                .comment(format!("move {} into the accumulator ('{}')", i, rax()))
-                .mov(constant(i), rax()),
-            Bool(b) => self
-                .comment(format!(
-                    "move the binary encoding of '{}' into the accumulator ('{}')",
-                    b,
-                    rax()
-                ))
-                .mov(constant(if b { 1 } else { 0 }), rax()),
-            Unit => self
-                .comment(format!(
-                    "move the binary encoding of '()' into the accumulator ('{}')",
-                    rax()
-                ))
-                .mov(constant(0), rax()),
-            What => self
-                .comment(format!(
-                    "to compute the value of a '?' we need to call the runtime"
-                ))
-                .comment(format!(
-                    "empty '{}' as the C runtime expects this to be 0",
-                    rax()
-                ))
-                .xor(rax(), rax())
-                .comment(format!("actually call into the runtime"))
-                .call_rt("what"),
-            Var(v) => self.emit_var(v),
-            UnOp(op, sub) => self.emit_unop(op, *sub, generator),
-            BinOp(op, left, right) => self.emit_binop(op, *left, *right, generator),
-            If(condition, left, right) => self.emit_if(*condition, *left, *right, generator),
-            While(condition, sub) => self.emit_while(*condition, *sub, generator),
-            Seq(seq) => self.emit_seq(seq, generator),
-            Ref(sub) => self.emit_ref(*sub, generator),
-            Deref(sub) => self.emit(*sub, generator).mov(deref(rax(), 0), rax()),
-            Fst(sub) => self
-                .emit(*sub, generator)
-                .comment(format!(
-                    "project the first element from the pair and leave it the accumulator ('{}')",
-                    rax()
-                ))
-                .mov(deref(rax(), 0), rax()),
-            Snd(sub) => self
-                .emit(*sub, generator)
-                .comment(format!(
-                    "project the second element from the pair and leave it the accumulator ('{}')",
-                    rax()
-                ))
-                .mov(deref(rax(), 8), rax()),
-            Pair(left, right) => self.emit_pair(*left, *right, generator),
-            Assign(left, right) => self.emit_assign(*left, *right, generator),
-            App(left, right) => self.emit_app(*left, *right, generator),
-            Lambda(lambda) => self.emit_lambda(lambda, generator),
-            Inl(sub) => self.emit_inl(*sub, generator),
-            Inr(sub) => self.emit_inr(*sub, generator),
-            Case(sub, left, right) => self.emit_case(*sub, left, right, generator),
-            Let(v, sub, body) => self.emit_let(v, *sub, *body, generator),
-            LetFun(f, lambda, body) => self.emit_let_fun(f, lambda, *body, generator),
+    fn release(&mut self, vs: Vec<String>) {
+        for v in vs {
+            self.comment(format!("'{}' isn't used after here", v));
+            self.deallocate(v);
         }
     }
 }
 
-fn generate_using(mut generator: Generator, expr: Expr) -> String {
-    let mut entry = Code::new("entry".into(), generator.comments);
-    let entry = entry.emit(expr, &mut generator);
-    generator.add(entry.ret());
-    format!("{}", generator)
-}
-
-pub fn generate(expr: Expr) -> String {
-    let generator = Generator::new();
-    generate_using(generator, expr)
-}
-
-pub fn generate_with_comments(expr: Expr) -> String {
-    let generator = Generator::new_with_comments();
-    generate_using(generator, expr)
+pub fn generate(block: Block, options: &CompilerOptions) -> GeneratedCode {
+    let mut generator = Generator::new(options);
+    let mut entry = Code::new(
+        "entry".into(),
+        generator.comments,
+        generator.sanitise,
+        generator.harden,
+        generator.omit_frame_pointer,
+        generator.allocate_registers,
+        generator.stack_maps,
+        generator.shadow_stack,
+    );
+    let entry = entry.emit(block, &mut generator);
+    generator.add("entry".into(), "<top level>".to_string(), entry);
+    generator.code
 }
@@ -0,0 +1,74 @@
+use super::x86::{Instruction, Location};
+use super::GeneratedCode;
+
+// a copy of some generated code with one small change made to it, which
+// testing should notice
+pub struct Mutant {
+    // what was changed, and in which function
+    pub description: String,
+    pub code: GeneratedCode,
+}
+
+// the instruction with its operands the other way around, if that's still an
+// instruction (the target can't be a constant, and some instructions can only
+// target a register)
+fn swapped(instruction: &Instruction) -> Option<Instruction> {
+    use self::Instruction::*;
+    let register = |loc: &Location| matches!(loc, Location::Register(_));
+    let (make, source, target): (fn(Location, Location) -> Instruction, _, _) = match *instruction {
+        Add(s, t) => (Add, s, t),
+        Sub(s, t) => (Sub, s, t),
+        AddL(s, t) => (AddL, s, t),
+        SubL(s, t) => (SubL, s, t),
+        Xor(s, t) => (Xor, s, t),
+        Cmp(s, t) => (Cmp, s, t),
+        Mov(s, t) => (Mov, s, t),
+        Mul(s, t) if register(&s) => (Mul, s, t),
+        MulL(s, t) if register(&s) => (MulL, s, t),
+        Movzb(s, t) if register(&s) => (Movzb, s, t),
+        // shifting and testing can't be told apart (or written) the other way
+        // around, and 'lea' needs an address
+        _ => return None,
+    };
+    // moving between two memory operands isn't an instruction, but swapping
+    // one never gives two of them
+    if source == target || matches!(source, Location::Constant(_)) {
+        return None;
+    }
+    Some(make(target, source))
+}
+
+impl GeneratedCode {
+    // every program that differs from this one by a single dropped instruction
+    // or a single swap of an instruction's operands (labels and comments are
+    // left alone, as dropping them either can't be assembled or changes nothing)
+    pub fn mutants(&self) -> Vec<Mutant> {
+        let mut mutants = vec![];
+        for (f, function) in self.functions.iter().enumerate() {
+            for (i, instruction) in function.instructions.iter().enumerate() {
+                if let Instruction::Label(_) | Instruction::Comment(_) = *instruction {
+                    continue;
+                }
+                let shown = instruction.to_string().trim().to_string();
+                let mut code = self.clone();
+                code.functions[f].instructions.remove(i);
+                mutants.push(Mutant {
+                    description: format!("dropped '{}' from '{}'", shown, function.name),
+                    code,
+                });
+                if let Some(swapped) = swapped(instruction) {
+                    let mut code = self.clone();
+                    code.functions[f].instructions[i] = swapped;
+                    mutants.push(Mutant {
+                        description: format!(
+                            "swapped the operands of '{}' in '{}'",
+                            shown, function.name
+                        ),
+                        code,
+                    });
+                }
+            }
+        }
+        mutants
+    }
+}
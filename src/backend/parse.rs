@@ -0,0 +1,207 @@
+use super::mangle::demangle;
+use super::x86::{Instruction, Label, Location, Register, Xmm, RUNTIME};
+
+use termion::{color, style};
+
+// the symbols generated code refers to by name, other than the runtime's
+// functions ('Label::Given' and 'Instruction::CallRuntime' only hold static
+// strings, so only these can be parsed back)
+const SYMBOLS: [&str; 3] = ["entry", "slang_stack_low", "slang_shadow_stack@gottpoff"];
+
+fn symbol(name: &str) -> Option<&'static str> {
+    SYMBOLS
+        .iter()
+        .chain(RUNTIME.iter())
+        .find(|symbol| **symbol == name)
+        .copied()
+}
+
+fn label(name: &str) -> Option<Label> {
+    match name.strip_prefix(".L") {
+        Some(n) => n.parse().ok().map(Label::Generated),
+        None => symbol(name).map(Label::Given),
+    }
+}
+
+// registers are accepted by any of their names, as the instruction already says
+// how wide its operands are
+fn register(name: &str) -> Option<Register> {
+    use super::x86::Register::*;
+    let reg = match name {
+        "rax" | "eax" | "al" => Rax,
+        "rbx" | "ebx" | "bl" => Rbx,
+        "rcx" | "ecx" | "cl" => Rcx,
+        "rdx" | "edx" | "dl" => Rdx,
+        "rsp" | "esp" | "spl" => Rsp,
+        "rbp" | "ebp" | "bpl" => Rbp,
+        "rsi" | "esi" | "sil" => Rsi,
+        "rdi" | "edi" | "dil" => Rdi,
+        "r8" | "r8d" | "r8b" => R8,
+        "r9" | "r9d" | "r9b" => R9,
+        "r10" | "r10d" | "r10b" => R10,
+        "r11" | "r11d" | "r11b" => R11,
+        "r12" | "r12d" | "r12b" => R12,
+        "r13" | "r13d" | "r13b" => R13,
+        "r14" | "r14d" | "r14b" => R14,
+        "r15" | "r15d" | "r15b" => R15,
+        "rip" | "eip" => Rip,
+        _ => return None,
+    };
+    Some(reg)
+}
+
+fn xmm(operand: &str) -> Option<Xmm> {
+    match operand {
+        "%xmm0" => Some(Xmm::Xmm0),
+        "%xmm1" => Some(Xmm::Xmm1),
+        _ => None,
+    }
+}
+
+fn location(operand: &str) -> Option<Location> {
+    if let Some(c) = operand.strip_prefix('$') {
+        return c.parse().ok().map(Location::Constant);
+    }
+    if let Some(reg) = operand.strip_prefix("%fs:(%") {
+        return register(reg.strip_suffix(')')?).map(Location::ThreadLocal);
+    }
+    if let Some(reg) = operand.strip_prefix('%') {
+        return register(reg).map(Location::Register);
+    }
+    let (base, reg) = operand.strip_suffix(')')?.split_once("(%")?;
+    let reg = register(reg)?;
+    if base.is_empty() {
+        Some(Location::Memory(reg, 0))
+    } else if let Ok(offset) = base.parse() {
+        Some(Location::Memory(reg, offset))
+    } else {
+        label(base).map(|label| Location::Relative(reg, label))
+    }
+}
+
+fn instruction(line: &str) -> Option<Instruction> {
+    use super::x86::Instruction::*;
+    if let Some(comment) = line.strip_prefix('#') {
+        return Some(Comment(comment.trim_start().to_string()));
+    }
+    if let Some(name) = line.strip_suffix(':') {
+        return label(name).map(Label);
+    }
+    let (mnemonic, operands) = match line.split_once(char::is_whitespace) {
+        Some((mnemonic, operands)) => (mnemonic, operands.trim()),
+        None => (line, ""),
+    };
+    // jumps and calls to the runtime take a label rather than a location
+    match mnemonic {
+        "jmp" => return label(operands).map(Jmp),
+        "je" => return label(operands).map(Je),
+        "jne" => return label(operands).map(Jne),
+        "jno" => return label(operands).map(Jno),
+        "jbe" => return label(operands).map(Jbe),
+        "jb" => return label(operands).map(Jb),
+        "jg" => return label(operands).map(Jg),
+        "jge" => return label(operands).map(Jge),
+        "call" => {
+            return match operands.strip_prefix('*') {
+                Some(operand) => location(operand).map(Call),
+                None => symbol(operands).map(CallRuntime),
+            }
+        }
+        _ => (),
+    }
+    // SSE registers aren't locations, as values are never kept in them
+    if let Some((source, target)) = operands.split_once(',') {
+        let (source, target) = (source.trim(), target.trim());
+        match (mnemonic, xmm(source), xmm(target)) {
+            ("movq", None, Some(target)) => return location(source).map(|s| MovToXmm(s, target)),
+            ("movq", Some(source), None) => return location(target).map(|t| MovFromXmm(source, t)),
+            ("addsd", Some(source), Some(target)) => return Some(AddSd(source, target)),
+            ("subsd", Some(source), Some(target)) => return Some(SubSd(source, target)),
+            ("mulsd", Some(source), Some(target)) => return Some(MulSd(source, target)),
+            ("divsd", Some(source), Some(target)) => return Some(DivSd(source, target)),
+            ("ucomisd", Some(source), Some(target)) => return Some(Ucomisd(source, target)),
+            ("cmpeqsd", Some(source), Some(target)) => return Some(CmpEqSd(source, target)),
+            _ => (),
+        }
+    }
+    let operands = if operands.is_empty() {
+        vec![]
+    } else {
+        operands
+            .split(',')
+            .map(|operand| location(operand.trim()))
+            .collect::<Option<Vec<_>>>()?
+    };
+    let instruction = match (mnemonic, &operands[..]) {
+        ("pushq", &[loc]) => Push(loc),
+        ("popq", &[loc]) => Pop(loc),
+        ("negq", &[loc]) => Neg(loc),
+        ("addq", &[source, target]) => Add(source, target),
+        ("subq", &[source, target]) => Sub(source, target),
+        ("imulq", &[source, target]) => Mul(source, target),
+        ("idivq", &[source]) => Div(source),
+        ("cqto", &[]) => Cqto,
+        ("sarq", &[source, target]) => Sar(source, target),
+        ("shlq", &[source, target]) => Shl(source, target),
+        ("negl", &[loc]) => NegL(loc),
+        ("addl", &[source, target]) => AddL(source, target),
+        ("subl", &[source, target]) => SubL(source, target),
+        ("imull", &[source, target]) => MulL(source, target),
+        ("idivl", &[source]) => DivL(source),
+        ("cltd", &[]) => Cltd,
+        ("cltq", &[]) => Cltq,
+        ("xorq", &[source, target]) => Xor(source, target),
+        ("cmpq", &[source, target]) => Cmp(source, target),
+        ("testq", &[source, target]) => Test(source, target),
+        ("sete", &[loc]) => Sete(loc),
+        ("setl", &[loc]) => Setl(loc),
+        ("seta", &[loc]) => Seta(loc),
+        ("movzbq", &[source, target]) => Movzb(source, target),
+        ("movq", &[source, target]) => Mov(source, target),
+        ("leaq", &[source, target]) => Lea(source, target),
+        ("endbr64", &[]) => Endbr64,
+        ("ret", &[]) => Ret,
+        _ => return None,
+    };
+    Some(instruction)
+}
+
+// reads assembly written out by the backend back into instructions, so that
+// generated code can be compared without depending on how it's laid out (only
+// the code is read: directives are skipped and everything from the first
+// '.section' onwards is data, although the label that marks the end of the code
+// comes before it)
+pub fn parse_assembly(text: &str) -> Result<Vec<Instruction>, String> {
+    let mut instructions = vec![];
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with(".section") {
+            break;
+        }
+        if line.is_empty() || (line.starts_with('.') && !line.ends_with(':')) {
+            continue;
+        }
+        // a function's symbol is only there for tools, and marks the same place
+        // as its label
+        if let Some(name) = line.strip_suffix(':') {
+            if demangle(name).is_some() {
+                continue;
+            }
+        }
+        match instruction(line) {
+            Some(instruction) => instructions.push(instruction),
+            None => {
+                return Err(format!(
+                    "{}{}error{}{}: line {}: unrecognised assembly '{}'",
+                    style::Bold,
+                    color::Fg(color::Red),
+                    color::Fg(color::Reset),
+                    style::Reset,
+                    n + 1,
+                    line
+                ))
+            }
+        }
+    }
+    Ok(instructions)
+}
@@ -0,0 +1,146 @@
+use super::x86::{Instruction, Location};
+
+fn in_memory(loc: &Location) -> bool {
+    matches!(
+        *loc,
+        Location::Memory(_, _) | Location::Relative(_, _) | Location::ThreadLocal(_)
+    )
+}
+
+// whether the address of one operand is worked out from the other
+fn addressed_by(loc: &Location, by: &Location) -> bool {
+    match (*loc, *by) {
+        (Location::Memory(base, _), Location::Register(reg))
+        | (Location::Relative(base, _), Location::Register(reg))
+        | (Location::ThreadLocal(base), Location::Register(reg)) => base == reg,
+        _ => false,
+    }
+}
+
+// what a pair of neighbouring instructions can be replaced with, if there's a
+// simpler way of doing the same thing
+fn rewrite(first: &Instruction, second: &Instruction) -> Option<Vec<Instruction>> {
+    use self::Instruction::*;
+    match (first, second) {
+        // pushing a value only to pop it straight back off
+        (Push(source), Pop(target)) if source == target => Some(vec![]),
+        (Push(source), Pop(target)) if !(in_memory(source) && in_memory(target)) => {
+            Some(vec![Mov(*source, *target)])
+        }
+        // copying a value back to where it just came from
+        (Mov(source, target), Mov(back, to))
+            if source == to
+                && target == back
+                && !addressed_by(source, target)
+                && !addressed_by(target, source) =>
+        {
+            Some(vec![first.clone()])
+        }
+        // jumping to the next instruction
+        (Jmp(label), Label(next)) if label == next => Some(vec![second.clone()]),
+        _ => None,
+    }
+}
+
+// cleans up what's left of the patterns code generation leaves behind once
+// registers have been allocated (a rewrite can expose another, so each is
+// checked against what came before it)
+pub fn optimise(asm: &mut Vec<Instruction>) {
+    let mut optimised: Vec<Instruction> = Vec::with_capacity(asm.len());
+    for instruction in asm.drain(..) {
+        optimised.push(instruction);
+        while optimised.len() >= 2 {
+            let n = optimised.len();
+            match rewrite(&optimised[n - 2], &optimised[n - 1]) {
+                Some(replacement) => {
+                    optimised.truncate(n - 2);
+                    optimised.extend(replacement);
+                }
+                None => break,
+            }
+        }
+    }
+    *asm = optimised;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::x86::{deref, rax, rbp, rbx, Instruction, Label};
+    use super::optimise;
+    use super::Instruction::*;
+
+    fn optimised(mut asm: Vec<Instruction>) -> Vec<Instruction> {
+        optimise(&mut asm);
+        asm
+    }
+
+    #[test]
+    fn push_pop_same() {
+        assert_eq!(optimised(vec![Push(rax()), Pop(rax())]), vec![]);
+    }
+
+    #[test]
+    fn push_pop_move() {
+        assert_eq!(
+            optimised(vec![Push(rax()), Pop(rbx())]),
+            vec![Mov(rax(), rbx())]
+        );
+        assert_eq!(
+            optimised(vec![Push(deref(rbp(), -8)), Pop(rbx())]),
+            vec![Mov(deref(rbp(), -8), rbx())]
+        );
+    }
+
+    #[test]
+    fn push_pop_nested() {
+        // dropping the inner pair leaves the outer one next to each other
+        assert_eq!(
+            optimised(vec![Push(rax()), Push(rbx()), Pop(rbx()), Pop(rax())]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn push_pop_memory_to_memory() {
+        // there's no move from memory to memory
+        let asm = vec![Push(deref(rbp(), -8)), Pop(deref(rbp(), -16))];
+        assert_eq!(optimised(asm.clone()), asm);
+    }
+
+    #[test]
+    fn mov_back() {
+        assert_eq!(
+            optimised(vec![Mov(rax(), rbx()), Mov(rbx(), rax())]),
+            vec![Mov(rax(), rbx())]
+        );
+        assert_eq!(
+            optimised(vec![
+                Mov(rax(), deref(rbp(), -8)),
+                Mov(deref(rbp(), -8), rax())
+            ]),
+            vec![Mov(rax(), deref(rbp(), -8))]
+        );
+    }
+
+    #[test]
+    fn mov_back_addressed_by() {
+        // the first move changes the register the address is worked out from,
+        // so the second writes somewhere else
+        let asm = vec![Mov(deref(rax(), 0), rax()), Mov(rax(), deref(rax(), 0))];
+        assert_eq!(optimised(asm.clone()), asm);
+        let asm = vec![Mov(rax(), deref(rax(), 8)), Mov(deref(rax(), 8), rax())];
+        assert_eq!(optimised(asm.clone()), asm);
+    }
+
+    #[test]
+    fn jmp_to_next() {
+        let next = Label::new();
+        assert_eq!(optimised(vec![Jmp(next), Label(next)]), vec![Label(next)]);
+    }
+
+    #[test]
+    fn jmp_elsewhere() {
+        let asm = vec![Jmp(Label::new()), Label(Label::new())];
+        assert_eq!(optimised(asm.clone()), asm);
+    }
+}
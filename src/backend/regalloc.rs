@@ -0,0 +1,660 @@
+use super::x86::{
+    deref, r10, r11, r12, r13, r14, r15, r8, r9, rbp, Instruction, Location, Register,
+};
+
+use std::collections::{HashMap, HashSet};
+
+// the registers values can be kept in instead of on the stack (generated code
+// only ever uses them to pass arguments to the runtime): the first few are
+// caller-saved, so they're free to use but don't survive calls, and the rest are
+// callee-saved, so they survive calls but have to be saved to be used
+fn registers() -> [Location; 8] {
+    [r8(), r9(), r10(), r11(), r12(), r13(), r14(), r15()]
+}
+
+const CALLER_SAVED: usize = 4;
+
+// past this many times a value is found to be live where another is written, a
+// function has so many values live at once that colouring them would take more
+// time and memory than it's worth, so they're all left where they are (as with
+// '-C')
+const MAX_INTERFERENCES: usize = 1 << 22;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Write,
+    Update,
+    // only the registers in the address are read (as by 'leaq')
+    Address,
+}
+
+fn accesses(instruction: &Instruction) -> Vec<(Location, Access)> {
+    use self::Access::*;
+    use self::Instruction::*;
+    match *instruction {
+        Push(loc) | Div(loc) | DivL(loc) | Call(loc) => vec![(loc, Read)],
+        Pop(loc) => vec![(loc, Write)],
+        Neg(loc) | NegL(loc) | Sete(loc) | Setl(loc) | Seta(loc) => vec![(loc, Update)],
+        MovToXmm(loc, _) => vec![(loc, Read)],
+        MovFromXmm(_, loc) => vec![(loc, Write)],
+        Add(source, target)
+        | Sub(source, target)
+        | Mul(source, target)
+        | Sar(source, target)
+        | Shl(source, target)
+        | AddL(source, target)
+        | SubL(source, target)
+        | MulL(source, target)
+        | Xor(source, target) => vec![(source, Read), (target, Update)],
+        Cmp(source, target) | Test(source, target) => vec![(source, Read), (target, Read)],
+        Mov(source, target) | Movzb(source, target) => vec![(source, Read), (target, Write)],
+        Lea(source, target) => vec![(source, Address), (target, Write)],
+        _ => vec![],
+    }
+}
+
+// matches each 'pushq' that saves a temporary with the 'popq' that restores it
+// (everything pushed while evaluating an expression is popped again, on every
+// path, so they nest), leaving out the arguments pushed for the runtime, which
+// are dropped by adding to '%rsp' instead
+fn temporaries(asm: &[Instruction]) -> Vec<(usize, usize)> {
+    let mut pairs = vec![];
+    let mut pushed = vec![];
+    let mut depths = HashMap::new();
+    for (i, instruction) in asm.iter().enumerate() {
+        match *instruction {
+            Instruction::Push(_) => pushed.push(i),
+            Instruction::Pop(_) => match pushed.pop() {
+                Some(push) => pairs.push((push, i)),
+                None => return vec![],
+            },
+            Instruction::Add(Location::Constant(c), Location::Register(Register::Rsp)) => {
+                for _ in 0..c / 8 {
+                    match pushed.pop() {
+                        Some(_) => (),
+                        None => return vec![],
+                    }
+                }
+            }
+            // give up if the stack isn't as deep everywhere a label is reached from
+            Instruction::Label(label)
+            | Instruction::Jmp(label)
+            | Instruction::Je(label)
+            | Instruction::Jne(label)
+            | Instruction::Jno(label)
+            | Instruction::Jbe(label)
+            | Instruction::Jb(label)
+            | Instruction::Jg(label)
+            | Instruction::Jge(label)
+                if *depths.entry(label).or_insert(pushed.len()) != pushed.len() =>
+            {
+                return vec![];
+            }
+            _ => (),
+        }
+    }
+    pairs
+}
+
+struct Allocation<'a> {
+    asm: &'a [Instruction],
+    // values are numbered with the registers first, then every local variable's
+    // stack slot (by its offset from '%rbp'), then every temporary (by the
+    // indices of its 'pushq' and 'popq')
+    slots: HashMap<i64, usize>,
+    temporaries: HashMap<usize, usize>,
+    // the registers each call to the runtime is passed arguments in (which are
+    // set just before it)
+    arguments: HashMap<usize, Vec<usize>>,
+    values: usize,
+}
+
+impl<'a> Allocation<'a> {
+    fn new(asm: &'a [Instruction]) -> Allocation<'a> {
+        let mut allocation = Allocation {
+            asm,
+            slots: HashMap::new(),
+            temporaries: HashMap::new(),
+            arguments: HashMap::new(),
+            values: registers().len(),
+        };
+        for instruction in asm.iter() {
+            for (loc, _) in accesses(instruction) {
+                if let Location::Memory(Register::Rbp, offset) = loc {
+                    if offset < 0 && !allocation.slots.contains_key(&offset) {
+                        allocation.slots.insert(offset, allocation.values);
+                        allocation.values += 1;
+                    }
+                }
+            }
+        }
+        let mut set = vec![];
+        for (i, instruction) in asm.iter().enumerate() {
+            match *instruction {
+                Instruction::CallRuntime(_) => {
+                    allocation.arguments.insert(i, std::mem::take(&mut set));
+                }
+                Instruction::Label(_) | Instruction::Call(_) => set.clear(),
+                _ => set.extend(accesses(instruction).into_iter().filter_map(
+                    |(loc, access)| match (loc, access) {
+                        (Location::Register(_), Access::Write | Access::Update) => {
+                            registers().iter().position(|reg| *reg == loc)
+                        }
+                        _ => None,
+                    },
+                )),
+            }
+        }
+        for (push, pop) in temporaries(asm) {
+            allocation.temporaries.insert(push, allocation.values);
+            allocation.temporaries.insert(pop, allocation.values);
+            allocation.values += 1;
+        }
+        allocation
+    }
+
+    fn value(&self, loc: Location) -> Option<usize> {
+        match loc {
+            Location::Register(_) => registers().iter().position(|reg| *reg == loc),
+            Location::Memory(Register::Rbp, offset) => self.slots.get(&offset).copied(),
+            _ => None,
+        }
+    }
+
+    // the values an instruction reads and writes
+    fn effects(&self, i: usize) -> (Vec<usize>, Vec<usize>) {
+        let mut uses = vec![];
+        let mut defs = vec![];
+        let temporary = self.temporaries.get(&i).copied();
+        for (loc, access) in accesses(&self.asm[i]) {
+            match loc {
+                Location::Memory(reg, _)
+                | Location::Relative(reg, _)
+                | Location::ThreadLocal(reg)
+                    if self.value(loc).is_none() =>
+                {
+                    uses.extend(self.value(Location::Register(reg)))
+                }
+                _ => (),
+            }
+            let value = self.value(loc);
+            match access {
+                Access::Read => uses.extend(value),
+                Access::Write => defs.extend(value),
+                Access::Update => {
+                    uses.extend(value);
+                    defs.extend(value);
+                }
+                Access::Address => (),
+            }
+        }
+        // a temporary is written by its 'pushq' and read by its 'popq'
+        match (temporary, &self.asm[i]) {
+            (Some(temporary), Instruction::Push(_)) => defs.push(temporary),
+            (Some(temporary), _) => uses.push(temporary),
+            _ => (),
+        }
+        // calls can leave anything in the caller-saved registers, and the runtime
+        // takes its fifth and sixth arguments in '%r8' and '%r9'
+        if let Instruction::Call(_) | Instruction::CallRuntime(_) = self.asm[i] {
+            defs.extend(0..CALLER_SAVED);
+        }
+        if let Some(arguments) = self.arguments.get(&i) {
+            uses.extend(arguments.iter().copied());
+        }
+        (uses, defs)
+    }
+
+    // the values that are live at the end of each of a function's blocks,
+    // worked out from what each block reads before writing and what it writes
+    fn liveness(&self, graph: &Graph, effects: &[(Vec<usize>, Vec<usize>)]) -> Vec<HashSet<usize>> {
+        let mut gen = vec![HashSet::new(); graph.blocks.len()];
+        let mut kill = vec![HashSet::new(); graph.blocks.len()];
+        for (b, &(start, end)) in graph.blocks.iter().enumerate() {
+            for (uses, defs) in effects[start..end].iter().rev() {
+                for def in defs.iter() {
+                    gen[b].remove(def);
+                    kill[b].insert(*def);
+                }
+                gen[b].extend(uses.iter().copied());
+            }
+        }
+        let mut live_in = vec![HashSet::new(); graph.blocks.len()];
+        let mut live_out = vec![HashSet::new(); graph.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..graph.blocks.len()).rev() {
+                let out = graph.successors[b]
+                    .iter()
+                    .flat_map(|successor| live_in[*successor].iter().copied())
+                    .collect::<HashSet<_>>();
+                let live = out
+                    .difference(&kill[b])
+                    .chain(gen[b].iter())
+                    .copied()
+                    .collect::<HashSet<_>>();
+                if live != live_in[b] || out != live_out[b] {
+                    changed = true;
+                    live_in[b] = live;
+                    live_out[b] = out;
+                }
+            }
+        }
+        live_out
+    }
+
+    // goes back through each block from what's live at its end, calling 'visit'
+    // with each value an instruction writes and everything live after it (which
+    // the value interferes with)
+    fn interferences<F>(
+        &self,
+        graph: &Graph,
+        effects: &[(Vec<usize>, Vec<usize>)],
+        live_out: &[HashSet<usize>],
+        mut visit: F,
+    ) where
+        F: FnMut(usize, &HashSet<usize>),
+    {
+        for (b, &(start, end)) in graph.blocks.iter().enumerate() {
+            let mut live = live_out[b].clone();
+            for (uses, defs) in effects[start..end].iter().rev() {
+                for def in defs.iter() {
+                    visit(*def, &live);
+                    live.remove(def);
+                }
+                live.extend(uses.iter().copied());
+            }
+        }
+    }
+
+    // gives each value a register that nothing it's live at the same time as
+    // uses, starting with those used the most, and leaves the rest where they are
+    // (which is their spill code: local variables stay in their stack slots, and
+    // temporaries are still pushed and popped)
+    fn colour(&self) -> Vec<Option<Location>> {
+        let mut colours = registers().iter().copied().map(Some).collect::<Vec<_>>();
+        colours.resize(self.values, None);
+        let effects = (0..self.asm.len())
+            .map(|i| self.effects(i))
+            .collect::<Vec<_>>();
+        let graph = Graph::new(self.asm);
+        let live_out = self.liveness(&graph, &effects);
+        let mut interferences = 0;
+        self.interferences(&graph, &effects, &live_out, |_, live| {
+            interferences += live.len()
+        });
+        if interferences > MAX_INTERFERENCES {
+            return colours;
+        }
+        let mut interferes = vec![HashSet::new(); self.values];
+        self.interferences(&graph, &effects, &live_out, |def, live| {
+            for other in live.iter().filter(|other| **other != def) {
+                interferes[def].insert(*other);
+                interferes[*other].insert(def);
+            }
+        });
+        let mut uses = vec![0; self.values];
+        for (used, defs) in effects.iter() {
+            for value in used.iter().chain(defs.iter()) {
+                uses[*value] += 1;
+            }
+        }
+        // the address of a slot that's been taken has to stay in memory
+        for instruction in self.asm.iter() {
+            if let Instruction::Lea(Location::Memory(Register::Rbp, offset), _) = *instruction {
+                if let Some(slot) = self.slots.get(&offset) {
+                    uses[*slot] = 0;
+                }
+            }
+        }
+        let mut order = (registers().len()..self.values)
+            .filter(|value| uses[*value] > 0)
+            .collect::<Vec<_>>();
+        order.sort_by_key(|value| std::cmp::Reverse(uses[*value]));
+        for value in order {
+            colours[value] = registers().iter().copied().find(|reg| {
+                interferes[value]
+                    .iter()
+                    .all(|other| colours[*other] != Some(*reg))
+            });
+        }
+        colours
+    }
+}
+
+// whether an instruction can go anywhere other than the next one
+fn jumps(instruction: &Instruction) -> bool {
+    use self::Instruction::*;
+    matches!(
+        *instruction,
+        Jmp(_) | Je(_) | Jne(_) | Jno(_) | Jbe(_) | Jb(_) | Jg(_) | Jge(_) | Ret
+    )
+}
+
+// a function's basic blocks (each a run of instructions that's only ever entered
+// at the top and left at the bottom), with a block before the first for coming
+// in and one after the last for going out
+struct Graph<'a> {
+    asm: &'a [Instruction],
+    // where each block starts in the function's instructions, and where it ends
+    blocks: Vec<(usize, usize)>,
+    successors: Vec<Vec<usize>>,
+    predecessors: Vec<Vec<usize>>,
+}
+
+impl<'a> Graph<'a> {
+    const ENTRY: usize = 0;
+
+    fn new(asm: &'a [Instruction]) -> Graph<'a> {
+        use self::Instruction::*;
+        let mut starts = vec![0];
+        for (i, instruction) in asm.iter().enumerate() {
+            match *instruction {
+                Label(_) => starts.push(i),
+                _ if jumps(instruction) => starts.push(i + 1),
+                _ => (),
+            }
+        }
+        starts.push(asm.len());
+        starts.dedup();
+        let mut blocks = vec![(0, 0)];
+        blocks.extend(starts.windows(2).map(|w| (w[0], w[1])));
+        blocks.push((asm.len(), asm.len()));
+        let exit = blocks.len() - 1;
+        let labels = blocks
+            .iter()
+            .enumerate()
+            .take(exit)
+            .skip(1)
+            .filter_map(|(b, (start, _))| match asm[*start] {
+                Label(label) => Some((label, b)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+        let mut successors = vec![vec![]; blocks.len()];
+        successors[Graph::ENTRY].push(1);
+        for (b, &(start, end)) in blocks.iter().enumerate().take(exit).skip(1) {
+            let next = b + 1;
+            successors[b] = match asm[start..end].last() {
+                Some(&Jmp(label)) => labels.get(&label).copied().into_iter().collect(),
+                Some(
+                    &(Je(label) | Jne(label) | Jno(label) | Jbe(label) | Jb(label) | Jg(label)
+                    | Jge(label)),
+                ) => Some(next)
+                    .into_iter()
+                    .chain(labels.get(&label).copied())
+                    .collect(),
+                Some(&Ret) => vec![exit],
+                _ => vec![next],
+            };
+        }
+        let mut predecessors = vec![vec![]; blocks.len()];
+        for (b, successors) in successors.iter().enumerate() {
+            for successor in successors.iter() {
+                predecessors[*successor].push(b);
+            }
+        }
+        Graph {
+            asm,
+            blocks,
+            successors,
+            predecessors,
+        }
+    }
+
+    fn exit(&self) -> usize {
+        self.blocks.len() - 1
+    }
+
+    // the blocks that can be reached from 'from' going along 'edges'
+    fn reachable(from: usize, edges: &[Vec<usize>]) -> Vec<bool> {
+        let mut seen = vec![false; edges.len()];
+        let mut pending = vec![from];
+        while let Some(b) = pending.pop() {
+            if !std::mem::replace(&mut seen[b], true) {
+                pending.extend(edges[b].iter().copied());
+            }
+        }
+        seen
+    }
+
+    // the block each block is immediately dominated by, going from 'root'
+    // along 'edges' (so post-dominated by, going back from the exit), found as
+    // by Cooper, Harvey and Kennedy
+    fn dominators(root: usize, edges: &[Vec<usize>], back: &[Vec<usize>]) -> Vec<usize> {
+        let mut order = vec![];
+        let mut seen = vec![false; edges.len()];
+        let mut pending = vec![(root, 0)];
+        seen[root] = true;
+        while let Some((b, i)) = pending.pop() {
+            match edges[b].get(i) {
+                Some(next) => {
+                    pending.push((b, i + 1));
+                    if !std::mem::replace(&mut seen[*next], true) {
+                        pending.push((*next, 0));
+                    }
+                }
+                None => order.push(b),
+            }
+        }
+        let mut position = vec![0; edges.len()];
+        for (i, b) in order.iter().enumerate() {
+            position[*b] = i;
+        }
+        let mut dominator = vec![usize::MAX; edges.len()];
+        dominator[root] = root;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in order.iter().rev().filter(|b| **b != root) {
+                let mut found = None;
+                for p in back[*b].iter().filter(|p| dominator[**p] != usize::MAX) {
+                    found = Some(match found {
+                        None => *p,
+                        Some(mut other) => {
+                            let mut p = *p;
+                            while p != other {
+                                while position[p] < position[other] {
+                                    p = dominator[p];
+                                }
+                                while position[other] < position[p] {
+                                    other = dominator[other];
+                                }
+                            }
+                            p
+                        }
+                    });
+                }
+                if let Some(found) = found.filter(|found| dominator[*b] != *found) {
+                    dominator[*b] = found;
+                    changed = true;
+                }
+            }
+        }
+        dominator
+    }
+
+    // which blocks are in a loop (and so could be run more than once), which
+    // means being on the way from where a jump back up goes to the jump
+    fn looped(&self) -> Vec<bool> {
+        let mut looped = vec![false; self.blocks.len()];
+        for (b, successors) in self.successors.iter().enumerate() {
+            for back in successors.iter().filter(|next| **next <= b) {
+                let after = Graph::reachable(*back, &self.successors);
+                let before = Graph::reachable(b, &self.predecessors);
+                for (looped, (after, before)) in looped.iter_mut().zip(after.iter().zip(before)) {
+                    *looped |= *after && before;
+                }
+            }
+        }
+        looped
+    }
+
+    // whether 'a' is 'b' or above it in a tree of dominators
+    fn dominates(tree: &[usize], a: usize, mut b: usize) -> bool {
+        while b != a && tree[b] != b {
+            b = tree[b];
+        }
+        b == a
+    }
+
+    // the lowest block in a tree of dominators above all of 'blocks'
+    fn common(tree: &[usize], blocks: &[usize]) -> usize {
+        let mut found = blocks[0];
+        for b in blocks.iter() {
+            while !Graph::dominates(tree, found, *b) {
+                found = tree[found];
+            }
+        }
+        found
+    }
+
+    // where each of the callee-saved registers a function uses can be saved and
+    // restored (as indices of its instructions), so that paths that never use
+    // one don't have to: saving has to come before every use, restoring after,
+    // and each only once on any path that gets to either, which starting and
+    // ending the function always satisfies. Along with each is whether there's
+    // a path through the function that skips saving it
+    fn wrap(&self, registers: &[Location]) -> Vec<(usize, usize, bool)> {
+        let (entry, exit) = (Graph::ENTRY, self.exit());
+        let fallback = vec![(0, self.blocks[exit].0, false); registers.len()];
+        let forwards = Graph::reachable(entry, &self.successors);
+        let backwards = Graph::reachable(exit, &self.predecessors);
+        if forwards.iter().zip(backwards.iter()).any(|(f, b)| !f || !b) {
+            return fallback;
+        }
+        let dominators = Graph::dominators(entry, &self.successors, &self.predecessors);
+        let post_dominators = Graph::dominators(exit, &self.predecessors, &self.successors);
+        let looped = self.looped();
+        let mut wrapped = vec![];
+        for reg in registers.iter() {
+            let uses = (0..self.blocks.len())
+                .filter(|b| {
+                    let (start, end) = self.blocks[*b];
+                    (start..end).any(|i| self.mentions(i, *reg))
+                })
+                .collect::<Vec<_>>();
+            if uses.is_empty() {
+                wrapped.push(fallback[0]);
+                continue;
+            }
+            let mut save = Graph::common(&dominators, &uses);
+            let mut restore = Graph::common(&post_dominators, &uses);
+            loop {
+                if save != entry && (looped[save] || !Graph::dominates(&dominators, save, restore))
+                {
+                    save = dominators[save];
+                } else if restore != exit
+                    && (looped[restore] || !Graph::dominates(&post_dominators, restore, save))
+                {
+                    restore = post_dominators[restore];
+                } else {
+                    break;
+                }
+            }
+            let skipped = !Graph::dominates(&post_dominators, save, entry);
+            // saving goes after the label that starts the block, and restoring
+            // before the jump that ends it
+            let (start, _) = self.blocks[save];
+            let (_, end) = self.blocks[restore];
+            let save = match self.asm.get(start) {
+                Some(Instruction::Label(_)) if save != entry => start + 1,
+                _ => start,
+            };
+            let restore = match self.asm[..end].last() {
+                Some(instruction) if restore != exit && jumps(instruction) => end - 1,
+                _ => end,
+            };
+            wrapped.push((save, restore, skipped));
+        }
+        wrapped
+    }
+
+    // whether an instruction reads or writes a register
+    fn mentions(&self, i: usize, reg: Location) -> bool {
+        accesses(&self.asm[i])
+            .into_iter()
+            .any(|(loc, _)| match loc {
+                Location::Memory(base, _)
+                | Location::Relative(base, _)
+                | Location::ThreadLocal(base) => Location::Register(base) == reg,
+                _ => loc == reg,
+            })
+    }
+}
+
+// keeps local variables and temporaries in registers where it can, returning
+// how much stack the function still needs for the local variables that are left
+// (which are packed together at the top of its frame) and for saving the
+// callee-saved registers it uses (which go below them, and are only saved on the
+// paths that use them), along with whether some path skips saving each of those
+// registers. Only the saves move: the frame is still set up on entry, on every
+// path, as the check on the stack's depth and the frame pointer go there
+pub fn allocate(asm: &mut Vec<Instruction>) -> (usize, Vec<bool>) {
+    let allocation = Allocation::new(asm);
+    let colours = allocation.colour();
+    let mut spilled = allocation
+        .slots
+        .iter()
+        .filter(|(_, value)| colours[**value].is_none())
+        .map(|(offset, _)| *offset)
+        .collect::<Vec<_>>();
+    spilled.sort_by_key(|offset| std::cmp::Reverse(*offset));
+    let mut slots = spilled
+        .iter()
+        .enumerate()
+        .map(|(i, offset)| (*offset, deref(rbp(), -8 * (i as i64 + 1))))
+        .collect::<HashMap<_, _>>();
+    for (offset, value) in allocation.slots.iter() {
+        if let Some(reg) = colours[*value] {
+            slots.insert(*offset, reg);
+        }
+    }
+    let temporaries = allocation
+        .temporaries
+        .iter()
+        .filter_map(|(i, value)| colours[*value].map(|reg| (*i, reg)))
+        .collect::<HashMap<_, _>>();
+    for (i, instruction) in asm.iter_mut().enumerate() {
+        *instruction = match (temporaries.get(&i), instruction.clone()) {
+            (Some(reg), Instruction::Push(loc)) => Instruction::Mov(loc, *reg),
+            (Some(reg), Instruction::Pop(loc)) => Instruction::Mov(*reg, loc),
+            (_, instruction) => instruction,
+        };
+        for loc in instruction.locations_mut() {
+            if let Location::Memory(Register::Rbp, offset) = *loc {
+                if let Some(slot) = slots.get(&offset) {
+                    *loc = *slot;
+                }
+            }
+        }
+    }
+    asm.retain(
+        |instruction| !matches!(*instruction, Instruction::Mov(source, target) if source == target),
+    );
+    let saved = registers()[CALLER_SAVED..]
+        .iter()
+        .copied()
+        .filter(|reg| {
+            colours
+                .iter()
+                .skip(registers().len())
+                .any(|c| *c == Some(*reg))
+        })
+        .collect::<Vec<_>>();
+    let places = Graph::new(asm).wrap(&saved);
+    let skipped = places.iter().map(|(_, _, skipped)| *skipped).collect();
+    let mut wrapped = vec![];
+    for (i, (reg, (save, restore, _))) in saved.iter().zip(places).enumerate() {
+        let slot = deref(rbp(), -8 * (spilled.len() + i + 1) as i64);
+        wrapped.push((save, Instruction::Mov(*reg, slot)));
+        wrapped.push((restore, Instruction::Mov(slot, *reg)));
+    }
+    wrapped.sort_by_key(|(i, _)| std::cmp::Reverse(*i));
+    for (i, instruction) in wrapped {
+        asm.insert(i, instruction);
+    }
+    (8 * (spilled.len() + saved.len()), skipped)
+}
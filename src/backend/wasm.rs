@@ -0,0 +1,1229 @@
+use super::super::frontend::ast::{BinOp, Free};
+use super::super::frontend::Location as SourceLocation;
+use super::super::ir::{self, Atom, Binding, Block, Op};
+use super::super::{CompilerOptions, IntWidth};
+
+use termion::{color, style};
+
+use std::collections::HashMap;
+
+// a program compiles to a WASI command: a module that exports its memory and a
+// '_start' function, and only imports what it needs from WASI to read the input
+// for '?', print its result and exit with an error, so it runs under wasmtime,
+// Node.js or any other WASI implementation (including the polyfills for
+// browsers)
+//
+// every value is an 'i64', as in the native code (ints and bools are untagged,
+// and heap pointers are addresses in the module's memory), and every closure is
+// called through the module's table with its argument and its environment, so
+// every function compiled from the program has the same type
+
+const CLOSURE_TYPE: u32 = 0;
+const FD_TYPE: u32 = 1;
+const EXIT_TYPE: u32 = 2;
+const START_TYPE: u32 = 3;
+const ALLOC_TYPE: u32 = 4;
+const GETC_TYPE: u32 = 5;
+const READ_TYPE: u32 = 6;
+const PRINT_TYPE: u32 = 7;
+const FAIL_TYPE: u32 = 8;
+
+// imported from WASI
+const FD_WRITE: u32 = 0;
+const FD_READ: u32 = 1;
+const PROC_EXIT: u32 = 2;
+// the runtime, which is generated along with the program
+const ALLOC: u32 = 3;
+const GETC: u32 = 4;
+const READ: u32 = 5;
+const PRINT: u32 = 6;
+const FAIL: u32 = 7;
+const START: u32 = 8;
+// the first function compiled from the program (its top level), after which
+// the rest come in the same order as in the table
+const ENTRY: u32 = 9;
+
+// the bottom of memory is scratch space for the runtime: an 'iovec' for WASI
+// at 0, the count of bytes read or written at 16, the byte read at 24 and the
+// digits of the result in front of the newline at 63
+const IOVEC: i32 = 0;
+const COUNT: i32 = 16;
+const BYTE: i32 = 24;
+const DIGITS: i32 = 64;
+// then the strings the program prints, then the heap
+const DATA: u32 = 64;
+
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+// the block type of a block that leaves nothing on the stack
+const EMPTY: u8 = 0x40;
+
+fn uleb(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    uleb(out, contents.len() as u64);
+    out.extend_from_slice(contents);
+}
+
+// the instructions of a function body
+#[derive(Default)]
+struct Body {
+    bytes: Vec<u8>,
+}
+
+impl Body {
+    fn op(&mut self, opcode: u8) -> &mut Body {
+        self.bytes.push(opcode);
+        self
+    }
+
+    fn index(&mut self, opcode: u8, index: u32) -> &mut Body {
+        self.bytes.push(opcode);
+        uleb(&mut self.bytes, index as u64);
+        self
+    }
+
+    // a load or store, which takes the alignment (as a power of two) and an
+    // offset from the address on the stack
+    fn memory(&mut self, opcode: u8, align: u32, offset: u32) -> &mut Body {
+        self.bytes.push(opcode);
+        uleb(&mut self.bytes, align as u64);
+        uleb(&mut self.bytes, offset as u64);
+        self
+    }
+
+    fn i32_const(&mut self, i: i32) -> &mut Body {
+        self.bytes.push(0x41);
+        sleb(&mut self.bytes, i as i64);
+        self
+    }
+
+    fn i64_const(&mut self, i: i64) -> &mut Body {
+        self.bytes.push(0x42);
+        sleb(&mut self.bytes, i);
+        self
+    }
+
+    fn block(&mut self, block_type: u8) -> &mut Body {
+        self.op(0x02).op(block_type)
+    }
+
+    fn loop_(&mut self, block_type: u8) -> &mut Body {
+        self.op(0x03).op(block_type)
+    }
+
+    fn if_(&mut self, block_type: u8) -> &mut Body {
+        self.op(0x04).op(block_type)
+    }
+
+    fn else_(&mut self) -> &mut Body {
+        self.op(0x05)
+    }
+
+    fn end(&mut self) -> &mut Body {
+        self.op(0x0b)
+    }
+
+    fn br(&mut self, depth: u32) -> &mut Body {
+        self.index(0x0c, depth)
+    }
+
+    fn br_if(&mut self, depth: u32) -> &mut Body {
+        self.index(0x0d, depth)
+    }
+
+    fn call(&mut self, function: u32) -> &mut Body {
+        self.index(0x10, function)
+    }
+
+    // calls the closure code whose index in the table is on top of the stack
+    fn call_indirect(&mut self) -> &mut Body {
+        self.index(0x11, CLOSURE_TYPE).op(0x00)
+    }
+
+    // the same, but returning whatever the closure returns, in place of the
+    // function making the call (which is how a call in tail position is made)
+    fn return_call_indirect(&mut self) -> &mut Body {
+        self.index(0x13, CLOSURE_TYPE).op(0x00)
+    }
+
+    fn drop_(&mut self) -> &mut Body {
+        self.op(0x1a)
+    }
+
+    fn local_get(&mut self, local: u32) -> &mut Body {
+        self.index(0x20, local)
+    }
+
+    fn local_set(&mut self, local: u32) -> &mut Body {
+        self.index(0x21, local)
+    }
+
+    fn local_tee(&mut self, local: u32) -> &mut Body {
+        self.index(0x22, local)
+    }
+
+    // the heap pointer is the only global
+    fn heap_get(&mut self) -> &mut Body {
+        self.index(0x23, 0)
+    }
+
+    fn heap_set(&mut self) -> &mut Body {
+        self.index(0x24, 0)
+    }
+
+    fn i32_load(&mut self, offset: u32) -> &mut Body {
+        self.memory(0x28, 2, offset)
+    }
+
+    fn i32_store(&mut self, offset: u32) -> &mut Body {
+        self.memory(0x36, 2, offset)
+    }
+
+    fn i32_load8(&mut self, offset: u32) -> &mut Body {
+        self.memory(0x2d, 0, offset)
+    }
+
+    fn i32_store8(&mut self, offset: u32) -> &mut Body {
+        self.memory(0x3a, 0, offset)
+    }
+
+    // the field at 'offset' in the object pointed to by the value on the stack
+    fn field(&mut self, offset: u32) -> &mut Body {
+        self.wrap().memory(0x29, 3, offset)
+    }
+
+    fn store(&mut self, offset: u32) -> &mut Body {
+        self.memory(0x37, 3, offset)
+    }
+
+    // an 'i64' used as an address (or as an index into the table)
+    fn wrap(&mut self) -> &mut Body {
+        self.op(0xa7)
+    }
+
+    // writes the 'iovec' at the bottom of memory for a single buffer
+    fn iovec(&mut self, buffer: impl FnOnce(&mut Body), len: impl FnOnce(&mut Body)) -> &mut Body {
+        self.i32_const(IOVEC);
+        buffer(self);
+        self.i32_store(0).i32_const(IOVEC + 4);
+        len(self);
+        self.i32_store(0)
+    }
+
+    // prints the string at 'ptr' to stderr and exits
+    fn fail(&mut self, (ptr, len): (u32, u32)) -> &mut Body {
+        self.i32_const(ptr as i32).i32_const(len as i32).call(FAIL)
+    }
+}
+
+// a function compiled from the program: the argument is local 0 and the
+// environment is local 1, then every variable the function binds gets a local
+// of its own (variables are only bound once in a program, so these never
+// clash), along with any temporaries
+struct Function {
+    name: String,
+    locals: u32,
+    vars: HashMap<String, u32>,
+    // whether the value being computed is the function's result
+    tail: bool,
+    body: Body,
+}
+
+impl Function {
+    fn new(name: String, v: String) -> Function {
+        let mut vars = HashMap::new();
+        vars.insert(v, 0);
+        Function {
+            name,
+            locals: 0,
+            vars,
+            tail: true,
+            body: Body::default(),
+        }
+    }
+
+    fn temporary(&mut self) -> u32 {
+        self.locals += 1;
+        self.locals + 1
+    }
+
+    fn bind(&mut self, v: String) -> u32 {
+        let local = self.temporary();
+        self.vars.insert(v, local);
+        local
+    }
+
+    fn atom(&mut self, atom: &Atom) -> &mut Body {
+        match *atom {
+            Atom::Unit => self.body.i64_const(0),
+            Atom::Int(i) => self.body.i64_const(i),
+            Atom::Float(f) => self.body.i64_const(f.to_bits() as i64),
+            Atom::Bool(b) => self.body.i64_const(b as i64),
+            Atom::Var(ref v) => {
+                let local = self.vars[v];
+                self.body.local_get(local)
+            }
+        }
+    }
+
+    // the field at 'offset' in the object an atom points to
+    fn field(&mut self, atom: &Atom, offset: u32) -> &mut Body {
+        self.atom(atom).field(offset)
+    }
+}
+
+// how the runtime lays out each kind of object built around a function
+#[derive(Copy, Clone)]
+enum Kind {
+    // the table index of the code, then the environment
+    Closure,
+    // the environment also starts with the closure itself
+    Recursive,
+    // a tag saying whether it's been forced, the table index of the code that
+    // computes it, the environment and then the value it computed
+    Thunk,
+}
+
+const THUNK_FORCING: i64 = 1;
+const THUNK_FORCED: i64 = 2;
+
+struct Generator {
+    int_width: IntWidth,
+    // the functions compiled from the program, in the order they're in the
+    // table (they're reserved before they're compiled, so that a function's
+    // index is known while its body is)
+    functions: Vec<Option<Function>>,
+    // the strings the program prints, which are kept from 'DATA' onwards
+    data: Vec<u8>,
+    strings: HashMap<String, (u32, u32)>,
+}
+
+impl Generator {
+    fn string(&mut self, string: String) -> (u32, u32) {
+        if let Some(&found) = self.strings.get(&string) {
+            return found;
+        }
+        let found = (DATA + self.data.len() as u32, string.len() as u32);
+        self.data.extend_from_slice(string.as_bytes());
+        self.strings.insert(string, found);
+        found
+    }
+
+    // the message printed when a runtime error happens at 'location'
+    fn trap(&mut self, location: &SourceLocation, message: &str) -> (u32, u32) {
+        self.string(format!(
+            "{}: line {}: column {}: runtime error: {}\n",
+            location.filename(),
+            location.line(),
+            location.column(),
+            message
+        ))
+    }
+
+    // ints narrower than 64 bits are kept sign-extended to 64 bits, as they are
+    // in the native code
+    fn wrap(&self, body: &mut Body) {
+        if self.int_width == IntWidth::I32 {
+            body.op(0xc4);
+        }
+    }
+}
+
+fn unsupported(what: &str) -> String {
+    format!(
+        "{}{}error{}{}: {} aren't supported by the WebAssembly backend",
+        style::Bold,
+        color::Fg(color::Red),
+        color::Fg(color::Reset),
+        style::Reset,
+        what
+    )
+}
+
+// builds an object with 'size' bytes on the heap, leaving it in 'local'
+fn alloc(f: &mut Function, size: i64) -> u32 {
+    let local = f.temporary();
+    f.body.i64_const(size).call(ALLOC).local_set(local);
+    local
+}
+
+fn lower_alloc(f: &mut Function, fields: &[(u32, &Atom)]) {
+    let object = alloc(f, 8 * fields.len() as i64);
+    for &(offset, atom) in fields {
+        f.body.local_get(object).wrap();
+        f.atom(atom).store(offset);
+    }
+    f.body.local_get(object);
+}
+
+fn lower_binop(f: &mut Function, generator: &Generator, op: BinOp, left: &Atom, right: &Atom) {
+    // a float is kept as its bits in an 'i64', so it's reinterpreted as an
+    // 'f64' to work on it (and its result is reinterpreted back)
+    let float = match op {
+        BinOp::FloatAdd => Some(0xa0),
+        BinOp::FloatSub => Some(0xa1),
+        BinOp::FloatMul => Some(0xa2),
+        BinOp::FloatDiv => Some(0xa3),
+        BinOp::FloatLt => Some(0x63),
+        BinOp::FloatEq => Some(0x61),
+        _ => None,
+    };
+    if let Some(opcode) = float {
+        f.atom(left).op(0xbf);
+        f.atom(right).op(0xbf);
+        f.body.op(opcode);
+        match op {
+            BinOp::FloatLt | BinOp::FloatEq => f.body.op(0xad),
+            _ => f.body.op(0xbd),
+        };
+        return;
+    }
+    f.atom(left);
+    f.atom(right);
+    match op {
+        BinOp::Add => {
+            f.body.op(0x7c);
+            generator.wrap(&mut f.body);
+        }
+        BinOp::Sub => {
+            f.body.op(0x7d);
+            generator.wrap(&mut f.body);
+        }
+        BinOp::Mul => {
+            f.body.op(0x7e);
+            generator.wrap(&mut f.body);
+        }
+        BinOp::Lt => {
+            f.body.op(0x53).op(0xad);
+        }
+        // pointers are compared by address, like everything else
+        BinOp::Eq | BinOp::PhysEq => {
+            f.body.op(0x51).op(0xad);
+        }
+        // '/' and '%' are 'Op::Div' and 'Op::Mod', and '&&' and '||' are
+        // lowered to 'if'
+        _ => unreachable!(),
+    }
+}
+
+fn lower_div(
+    f: &mut Function,
+    generator: &mut Generator,
+    location: &SourceLocation,
+    left: &Atom,
+    right: &Atom,
+    remainder: bool,
+) {
+    let division_by_zero = generator.trap(location, "division by zero");
+    f.atom(right)
+        .op(0x50)
+        .if_(EMPTY)
+        .fail(division_by_zero)
+        .end();
+    // 'i64.div_s' traps when dividing the smallest integer by -1, so dividing
+    // by -1 is negation instead (and the remainder is always 0)
+    f.atom(right).i64_const(-1).op(0x51).if_(I64);
+    if remainder {
+        f.body.i64_const(0);
+    } else {
+        let overflow = generator.trap(location, "overflow in division");
+        let smallest = match generator.int_width {
+            IntWidth::I64 => i64::MIN,
+            IntWidth::I32 => i32::MIN as i64,
+        };
+        f.atom(left)
+            .i64_const(smallest)
+            .op(0x51)
+            .if_(EMPTY)
+            .fail(overflow)
+            .end()
+            .i64_const(0);
+        f.atom(left).op(0x7d);
+    }
+    f.body.else_();
+    f.atom(left);
+    f.atom(right).op(if remainder { 0x81 } else { 0x7f }).end();
+}
+
+fn lower_case(
+    f: &mut Function,
+    generator: &mut Generator,
+    sub: &Atom,
+    (x, left): (String, Box<Block>),
+    (y, right): (String, Box<Block>),
+) -> Result<(), String> {
+    f.field(sub, 0).op(0x50).if_(I64);
+    let local = f.bind(x);
+    f.field(sub, 8).local_set(local);
+    emit(f, generator, *left)?;
+    f.body.else_();
+    let local = f.bind(y);
+    f.field(sub, 8).local_set(local);
+    emit(f, generator, *right)?;
+    f.body.end();
+    Ok(())
+}
+
+fn lower_force(f: &mut Function, generator: &mut Generator, location: &SourceLocation, sub: &Atom) {
+    let forcing = generator.trap(location, "lazy value forced while it was being computed");
+    let value = f.temporary();
+    f.field(sub, 0).i64_const(THUNK_FORCED).op(0x51).if_(I64);
+    f.field(sub, 24).else_();
+    f.field(sub, 0)
+        .i64_const(THUNK_FORCING)
+        .op(0x51)
+        .if_(EMPTY)
+        .fail(forcing)
+        .end();
+    f.atom(sub).wrap().i64_const(THUNK_FORCING).store(0);
+    f.body.i64_const(0);
+    f.field(sub, 16);
+    f.field(sub, 8).wrap().call_indirect().local_set(value);
+    f.atom(sub).wrap().local_get(value).store(24);
+    f.atom(sub)
+        .wrap()
+        .i64_const(THUNK_FORCED)
+        .store(0)
+        .local_get(value)
+        .end();
+}
+
+// compiles a function of its own for 'body', then builds the object that
+// holds it and its environment (in the layout for 'kind')
+fn lower_closure(
+    f: &mut Function,
+    generator: &mut Generator,
+    kind: Kind,
+    name: String,
+    v: String,
+    body: Block,
+) -> Result<(), String> {
+    // sorted, so that the same program always gets the same closure layout
+    let mut fv = body
+        .fv()
+        .into_iter()
+        .filter(|&x| x != &v && (!matches!(kind, Kind::Recursive) || x != &name))
+        .cloned()
+        .collect::<Vec<_>>();
+    fv.sort();
+    let index = generator.functions.len();
+    generator.functions.push(None);
+    let mut g = Function::new(name.clone(), v);
+    let skip = match kind {
+        Kind::Recursive => {
+            let local = g.bind(name.clone());
+            g.body.local_get(1).field(0).local_set(local);
+            1
+        }
+        _ => 0,
+    };
+    for (i, x) in fv.iter().enumerate() {
+        let local = g.bind(x.clone());
+        g.body
+            .local_get(1)
+            .field(8 * (skip + i) as u32)
+            .local_set(local);
+    }
+    emit(&mut g, generator, body)?;
+    g.name = match kind {
+        // renamed functions are still reported by the name they were given
+        Kind::Recursive => ir::source_name(&name).to_string(),
+        _ => name,
+    };
+    generator.functions[index] = Some(g);
+    let env = alloc(f, 8 * (skip + fv.len()) as i64);
+    for (i, x) in fv.iter().enumerate() {
+        f.body.local_get(env).wrap();
+        f.atom(&Atom::Var(x.clone())).store(8 * (skip + i) as u32);
+    }
+    let (size, code) = match kind {
+        Kind::Thunk => (32, 8),
+        _ => (16, 0),
+    };
+    let object = alloc(f, size);
+    f.body
+        .local_get(object)
+        .wrap()
+        .i64_const(index as i64)
+        .store(code)
+        .local_get(object)
+        .wrap()
+        .local_get(env)
+        .store(code + 8);
+    if let Kind::Recursive = kind {
+        f.body.local_get(env).wrap().local_get(object).store(0);
+    }
+    f.body.local_get(object);
+    Ok(())
+}
+
+// compiles functions defined together, which share one environment: it starts
+// with the closures for each of them, then holds everything any of them
+// captures (nothing is left on the stack, as each closure is bound instead)
+fn lower_group(
+    f: &mut Function,
+    generator: &mut Generator,
+    functions: Vec<(String, String, Box<Block>)>,
+) -> Result<(), String> {
+    let names = functions
+        .iter()
+        .map(|(name, _, _)| name.clone())
+        .collect::<Vec<_>>();
+    let mut fv = functions
+        .iter()
+        .flat_map(|(_, v, body)| body.fv().into_iter().filter(move |&x| x != v))
+        .filter(|&x| !names.contains(x))
+        .cloned()
+        .collect::<Vec<_>>();
+    fv.sort();
+    fv.dedup();
+    let skip = names.len();
+    let indices = names
+        .iter()
+        .map(|_| {
+            generator.functions.push(None);
+            generator.functions.len() - 1
+        })
+        .collect::<Vec<_>>();
+    for (&index, (name, v, body)) in indices.iter().zip(functions) {
+        let mut g = Function::new(ir::source_name(&name).to_string(), v);
+        for (i, x) in names.iter().chain(fv.iter()).enumerate() {
+            let local = g.bind(x.clone());
+            g.body.local_get(1).field(8 * i as u32).local_set(local);
+        }
+        emit(&mut g, generator, *body)?;
+        generator.functions[index] = Some(g);
+    }
+    let env = alloc(f, 8 * (skip + fv.len()) as i64);
+    for (i, x) in fv.iter().enumerate() {
+        f.body.local_get(env).wrap();
+        f.atom(&Atom::Var(x.clone())).store(8 * (skip + i) as u32);
+    }
+    for (i, (name, index)) in names.into_iter().zip(indices).enumerate() {
+        let object = alloc(f, 16);
+        f.body
+            .local_get(object)
+            .wrap()
+            .i64_const(index as i64)
+            .store(0)
+            .local_get(object)
+            .wrap()
+            .local_get(env)
+            .store(8)
+            .local_get(env)
+            .wrap()
+            .local_get(object)
+            .store(8 * i as u32);
+        let local = f.bind(name);
+        f.body.local_get(object).local_set(local);
+    }
+    Ok(())
+}
+
+// leaves the value of an operation on the stack
+fn lower(f: &mut Function, generator: &mut Generator, op: Op) -> Result<(), String> {
+    match op {
+        Op::Atom(atom) => {
+            f.atom(&atom);
+        }
+        Op::What => {
+            f.body.call(READ);
+            generator.wrap(&mut f.body);
+        }
+        Op::Neg(sub) => {
+            f.body.i64_const(0);
+            f.atom(&sub).op(0x7d);
+            generator.wrap(&mut f.body);
+        }
+        Op::Not(sub) => {
+            f.atom(&sub).op(0x50).op(0xad);
+        }
+        Op::Binary(BinOp::Concat, _, _) | Op::Binary(BinOp::Compare, _, _) => {
+            return Err(unsupported("strings"))
+        }
+        Op::Binary(op, left, right) => lower_binop(f, generator, op, &left, &right),
+        Op::Div(location, left, right) => lower_div(f, generator, &location, &left, &right, false),
+        Op::Mod(location, left, right) => lower_div(f, generator, &location, &left, &right, true),
+        // there's nowhere to put the hint
+        Op::If(condition, left, right, _) => {
+            f.atom(&condition).wrap().if_(I64);
+            emit(f, generator, *left)?;
+            f.body.else_();
+            emit(f, generator, *right)?;
+            f.body.end();
+        }
+        // nothing in a loop is in tail position, as the loop still has to finish
+        Op::While(condition, body) => {
+            let tail = std::mem::replace(&mut f.tail, false);
+            f.body.block(EMPTY).loop_(EMPTY);
+            emit(f, generator, *condition)?;
+            f.body.op(0x50).br_if(1);
+            emit(f, generator, *body)?;
+            f.body.drop_().br(0).end().end().i64_const(0);
+            f.tail = tail;
+        }
+        // the counter is compared with the upper bound after the body (with
+        // 'i64.ge_s'), so it never steps past it
+        Op::For(from, to, (v, body)) => {
+            let tail = std::mem::replace(&mut f.tail, false);
+            f.atom(&from);
+            let counter = f.bind(v);
+            f.body.local_set(counter).block(EMPTY).local_get(counter);
+            f.atom(&to).op(0x55).br_if(0).loop_(EMPTY);
+            emit(f, generator, *body)?;
+            f.body.drop_().local_get(counter);
+            f.atom(&to).op(0x59).br_if(1);
+            f.body
+                .local_get(counter)
+                .i64_const(1)
+                .op(0x7c)
+                .local_set(counter)
+                .br(0)
+                .end()
+                .end()
+                .i64_const(0);
+            f.tail = tail;
+        }
+        Op::Pair(left, right) => lower_alloc(f, &[(0, &left), (8, &right)]),
+        Op::Fst(sub) => {
+            f.field(&sub, 0);
+        }
+        Op::Snd(sub) => {
+            f.field(&sub, 8);
+        }
+        Op::Inl(sub) => lower_alloc(f, &[(0, &Atom::Int(0)), (8, &sub)]),
+        Op::Inr(sub) => lower_alloc(f, &[(0, &Atom::Int(1)), (8, &sub)]),
+        Op::Case(sub, left, right) => lower_case(f, generator, &sub, left, right)?,
+        Op::Ref(sub) => lower_alloc(f, &[(0, &sub)]),
+        Op::Deref(sub) => {
+            f.field(&sub, 0);
+        }
+        Op::Assign(left, right) => {
+            f.atom(&left).wrap();
+            f.atom(&right).store(0).i64_const(0);
+        }
+        // the computation is compiled as a function that ignores its argument
+        Op::Lazy(sub) => lower_closure(
+            f,
+            generator,
+            Kind::Thunk,
+            "<lazy>".to_string(),
+            "%lazy".to_string(),
+            *sub,
+        )?,
+        Op::Force(location, sub) => lower_force(f, generator, &location, &sub),
+        Op::Chan | Op::Send(..) | Op::Recv(..) => return Err(unsupported("channels")),
+        Op::Spawn(_) => return Err(unsupported("threads")),
+        Op::Raise(..) | Op::Try(..) => return Err(unsupported("exceptions")),
+        Op::Str(_) | Op::Print(_) => return Err(unsupported("strings")),
+        Op::MakeArray(..) | Op::Index(..) | Op::Update(..) => return Err(unsupported("arrays")),
+        Op::Nil | Op::Cons(..) | Op::Head(..) | Op::Tail(..) | Op::IsEmpty(_) => {
+            return Err(unsupported("lists"))
+        }
+        Op::Lambda(v, body) => {
+            lower_closure(f, generator, Kind::Closure, "<fun>".to_string(), v, *body)?
+        }
+        Op::App(left, right) => {
+            f.atom(&right);
+            f.field(&left, 8);
+            f.field(&left, 0).wrap();
+            if f.tail {
+                f.body.return_call_indirect();
+            } else {
+                f.body.call_indirect();
+            }
+        }
+    }
+    Ok(())
+}
+
+// runs each binding in a block, then leaves the block's value on the stack
+fn emit(f: &mut Function, generator: &mut Generator, block: Block) -> Result<(), String> {
+    // only the block's result can be in tail position
+    let tail = std::mem::replace(&mut f.tail, false);
+    for binding in block.bindings {
+        match binding {
+            Binding::Let(v, op) => {
+                lower(f, generator, op)?;
+                let local = f.bind(v);
+                f.body.local_set(local);
+            }
+            Binding::LetFun(name, v, body) => {
+                lower_closure(f, generator, Kind::Recursive, name.clone(), v, *body)?;
+                let local = f.bind(name);
+                f.body.local_set(local);
+            }
+            Binding::LetRec(functions) => lower_group(f, generator, functions)?,
+            Binding::Do(op) => {
+                lower(f, generator, op)?;
+                f.body.drop_();
+            }
+        }
+    }
+    f.tail = tail;
+    lower(f, generator, block.result)
+}
+
+// the runtime's functions, each with the types of its locals (after its
+// parameters) and its body
+fn runtime(generator: &mut Generator) -> Vec<(&'static str, Vec<u8>, Body)> {
+    let out_of_memory = generator.string("runtime error: out of memory\n".to_string());
+    let stdin_died = generator.string("stdin died :(\n".to_string());
+    let prompt = generator.string("> ".to_string());
+
+    // takes the size of an object, and returns where it starts (the heap is
+    // never freed, and only grows as far as it needs to)
+    let mut alloc = Body::default();
+    alloc
+        .heap_get()
+        .local_set(1)
+        .heap_get()
+        .local_get(0)
+        .op(0x7c)
+        .heap_set()
+        .block(EMPTY)
+        .loop_(EMPTY)
+        .heap_get()
+        .index(0x3f, 0)
+        .op(0xad)
+        .i64_const(16)
+        .op(0x86)
+        .op(0x58)
+        .br_if(1)
+        .i32_const(1)
+        .index(0x40, 0)
+        .i32_const(-1)
+        .op(0x46)
+        .if_(EMPTY)
+        .fail(out_of_memory)
+        .end()
+        .br(0)
+        .end()
+        .end()
+        .local_get(1);
+
+    // the next byte of input, or -1 at the end of it
+    let mut getc = Body::default();
+    getc.iovec(
+        |body| {
+            body.i32_const(BYTE);
+        },
+        |body| {
+            body.i32_const(1);
+        },
+    )
+    .i32_const(0)
+    .i32_const(IOVEC)
+    .i32_const(1)
+    .i32_const(COUNT)
+    .call(FD_READ)
+    .drop_()
+    .i32_const(COUNT)
+    .i32_load(0)
+    .op(0x45)
+    .if_(I32)
+    .i32_const(-1)
+    .else_()
+    .i32_const(BYTE)
+    .i32_load8(0)
+    .end();
+
+    // reads a number for '?' after prompting for it, as the native runtime
+    // does (something that isn't a number is read as 0, and the rest of its
+    // line is skipped)
+    let (c, negative, n) = (0, 1, 2);
+    let mut read = Body::default();
+    read.iovec(
+        |body| {
+            body.i32_const(prompt.0 as i32);
+        },
+        |body| {
+            body.i32_const(prompt.1 as i32);
+        },
+    )
+    .i32_const(1)
+    .i32_const(IOVEC)
+    .i32_const(1)
+    .i32_const(COUNT)
+    .call(FD_WRITE)
+    .drop_()
+    .loop_(EMPTY)
+    .call(GETC)
+    .local_tee(c)
+    .i32_const(-1)
+    .op(0x46)
+    .if_(EMPTY)
+    .fail(stdin_died)
+    .end()
+    .local_get(c)
+    .i32_const(b' ' as i32)
+    .op(0x46)
+    .local_get(c)
+    .i32_const(b'\t' as i32)
+    .op(0x6b)
+    .i32_const(5)
+    .op(0x49)
+    .op(0x72)
+    .br_if(0)
+    .end()
+    .local_get(c)
+    .i32_const(b'-' as i32)
+    .op(0x46)
+    .if_(EMPTY)
+    .i32_const(1)
+    .local_set(negative)
+    .call(GETC)
+    .local_set(c)
+    .end()
+    .local_get(c)
+    .i32_const(b'0' as i32)
+    .op(0x6b)
+    .i32_const(10)
+    .op(0x4f)
+    .if_(EMPTY)
+    .block(EMPTY)
+    .loop_(EMPTY)
+    .local_get(c)
+    .i32_const(b'\n' as i32)
+    .op(0x46)
+    .br_if(1)
+    .local_get(c)
+    .i32_const(-1)
+    .op(0x46)
+    .br_if(1)
+    .call(GETC)
+    .local_set(c)
+    .br(0)
+    .end()
+    .end()
+    .i64_const(0)
+    .op(0x0f)
+    .end()
+    .loop_(EMPTY)
+    .local_get(n)
+    .i64_const(10)
+    .op(0x7e)
+    .local_get(c)
+    .i32_const(b'0' as i32)
+    .op(0x6b)
+    .op(0xad)
+    .op(0x7c)
+    .local_set(n)
+    .call(GETC)
+    .local_tee(c)
+    .i32_const(b'0' as i32)
+    .op(0x6b)
+    .i32_const(10)
+    .op(0x49)
+    .br_if(0)
+    .end()
+    .local_get(negative)
+    .if_(I64)
+    .i64_const(0)
+    .local_get(n)
+    .op(0x7d)
+    .else_()
+    .local_get(n)
+    .end();
+
+    // prints a number and a newline (the digits are worked out from the
+    // number's negation if it's positive, as not every negative number can be
+    // negated)
+    let (position, n) = (1, 2);
+    let mut print = Body::default();
+    print
+        .i32_const(DIGITS - 1)
+        .i32_const(b'\n' as i32)
+        .i32_store8(0)
+        .i32_const(DIGITS - 1)
+        .local_set(position)
+        .i64_const(0)
+        .local_get(0)
+        .op(0x7d)
+        .local_get(0)
+        .local_get(0)
+        .i64_const(0)
+        .op(0x55)
+        .op(0x1b)
+        .local_set(n)
+        .loop_(EMPTY)
+        .local_get(position)
+        .i32_const(1)
+        .op(0x6b)
+        .local_tee(position)
+        .i32_const(b'0' as i32)
+        .i64_const(0)
+        .local_get(n)
+        .i64_const(10)
+        .op(0x81)
+        .op(0x7d)
+        .wrap()
+        .op(0x6a)
+        .i32_store8(0)
+        .local_get(n)
+        .i64_const(10)
+        .op(0x7f)
+        .local_tee(n)
+        .op(0x50)
+        .op(0x45)
+        .br_if(0)
+        .end()
+        .local_get(0)
+        .i64_const(0)
+        .op(0x53)
+        .if_(EMPTY)
+        .local_get(position)
+        .i32_const(1)
+        .op(0x6b)
+        .local_tee(position)
+        .i32_const(b'-' as i32)
+        .i32_store8(0)
+        .end()
+        .iovec(
+            |body| {
+                body.local_get(position);
+            },
+            |body| {
+                body.i32_const(DIGITS).local_get(position).op(0x6b);
+            },
+        )
+        .i32_const(1)
+        .i32_const(IOVEC)
+        .i32_const(1)
+        .i32_const(COUNT)
+        .call(FD_WRITE)
+        .drop_();
+
+    // prints a message to stderr and exits
+    let mut fail = Body::default();
+    fail.iovec(
+        |body| {
+            body.local_get(0);
+        },
+        |body| {
+            body.local_get(1);
+        },
+    )
+    .i32_const(2)
+    .i32_const(IOVEC)
+    .i32_const(1)
+    .i32_const(COUNT)
+    .call(FD_WRITE)
+    .drop_()
+    .i32_const(1)
+    .call(PROC_EXIT)
+    .op(0x00);
+
+    // runs the program (whose top level is called like a closure), then prints
+    // its result
+    let mut start = Body::default();
+    start.i64_const(0).i64_const(0).call(ENTRY).call(PRINT);
+
+    vec![
+        ("alloc", vec![I64], alloc),
+        ("getc", vec![], getc),
+        ("read", vec![I32, I32, I64], read),
+        ("print", vec![I32, I64], print),
+        ("fail", vec![], fail),
+        ("_start", vec![], start),
+    ]
+}
+
+fn function_type(out: &mut Vec<u8>, params: &[u8], results: &[u8]) {
+    out.push(0x60);
+    uleb(out, params.len() as u64);
+    out.extend_from_slice(params);
+    uleb(out, results.len() as u64);
+    out.extend_from_slice(results);
+}
+
+// a function's locals, grouped by type, then its body
+fn code(out: &mut Vec<u8>, locals: &[u8], body: &Body) {
+    let mut groups: Vec<(u32, u8)> = vec![];
+    for &local in locals {
+        match groups.last_mut() {
+            Some((count, t)) if *t == local => *count += 1,
+            _ => groups.push((1, local)),
+        }
+    }
+    let mut function = vec![];
+    uleb(&mut function, groups.len() as u64);
+    for (count, t) in groups {
+        uleb(&mut function, count as u64);
+        function.push(t);
+    }
+    function.extend_from_slice(&body.bytes);
+    function.push(0x0b);
+    uleb(out, function.len() as u64);
+    out.extend(function);
+}
+
+// compiles a program to a WebAssembly module (in the binary format), or fails
+// if it uses something the backend doesn't support
+pub fn generate(block: Block, options: &CompilerOptions) -> Result<Vec<u8>, String> {
+    let mut generator = Generator {
+        int_width: options.int_width,
+        functions: vec![None],
+        data: vec![],
+        strings: HashMap::new(),
+    };
+    let mut entry = Function::new("<top level>".to_string(), "%entry".to_string());
+    emit(&mut entry, &mut generator, block)?;
+    generator.functions[0] = Some(entry);
+    let runtime = runtime(&mut generator);
+    let functions = generator
+        .functions
+        .into_iter()
+        .map(|function| function.unwrap())
+        .collect::<Vec<_>>();
+    let heap = (DATA as u64 + generator.data.len() as u64 + 7) & !7;
+
+    let mut module = b"\0asm".to_vec();
+    module.extend_from_slice(&[1, 0, 0, 0]);
+
+    let mut types = vec![];
+    uleb(&mut types, 9);
+    function_type(&mut types, &[I64, I64], &[I64]);
+    function_type(&mut types, &[I32, I32, I32, I32], &[I32]);
+    function_type(&mut types, &[I32], &[]);
+    function_type(&mut types, &[], &[]);
+    function_type(&mut types, &[I64], &[I64]);
+    function_type(&mut types, &[], &[I32]);
+    function_type(&mut types, &[], &[I64]);
+    function_type(&mut types, &[I64], &[]);
+    function_type(&mut types, &[I32, I32], &[]);
+    section(&mut module, 1, &types);
+
+    let mut imports = vec![];
+    uleb(&mut imports, 3);
+    for (import, t) in [
+        ("fd_write", FD_TYPE),
+        ("fd_read", FD_TYPE),
+        ("proc_exit", EXIT_TYPE),
+    ]
+    .iter()
+    {
+        name(&mut imports, "wasi_snapshot_preview1");
+        name(&mut imports, import);
+        imports.push(0x00);
+        uleb(&mut imports, *t as u64);
+    }
+    section(&mut module, 2, &imports);
+
+    let mut declarations = vec![];
+    uleb(&mut declarations, (runtime.len() + functions.len()) as u64);
+    for t in [
+        ALLOC_TYPE, GETC_TYPE, READ_TYPE, PRINT_TYPE, FAIL_TYPE, START_TYPE,
+    ]
+    .iter()
+    {
+        uleb(&mut declarations, *t as u64);
+    }
+    for _ in functions.iter() {
+        uleb(&mut declarations, CLOSURE_TYPE as u64);
+    }
+    section(&mut module, 3, &declarations);
+
+    let mut table = vec![1, 0x70, 0x01];
+    uleb(&mut table, functions.len() as u64);
+    uleb(&mut table, functions.len() as u64);
+    section(&mut module, 4, &table);
+
+    section(&mut module, 5, &[1, 0x00, 1]);
+
+    let mut globals = vec![1, I64, 0x01, 0x42];
+    sleb(&mut globals, heap as i64);
+    globals.push(0x0b);
+    section(&mut module, 6, &globals);
+
+    let mut exports = vec![2];
+    name(&mut exports, "memory");
+    exports.extend_from_slice(&[0x02, 0]);
+    name(&mut exports, "_start");
+    exports.push(0x00);
+    uleb(&mut exports, START as u64);
+    section(&mut module, 7, &exports);
+
+    let mut elements = vec![1, 0x00, 0x41, 0, 0x0b];
+    uleb(&mut elements, functions.len() as u64);
+    for i in 0..functions.len() {
+        uleb(&mut elements, ENTRY as u64 + i as u64);
+    }
+    section(&mut module, 9, &elements);
+
+    let mut codes = vec![];
+    uleb(&mut codes, (runtime.len() + functions.len()) as u64);
+    for (_, locals, body) in runtime.iter() {
+        code(&mut codes, locals, body);
+    }
+    for function in functions.iter() {
+        code(
+            &mut codes,
+            &vec![I64; function.locals as usize],
+            &function.body,
+        );
+    }
+    section(&mut module, 10, &codes);
+
+    let mut data = vec![1, 0x00, 0x41];
+    sleb(&mut data, DATA as i64);
+    data.push(0x0b);
+    uleb(&mut data, generator.data.len() as u64);
+    data.extend_from_slice(&generator.data);
+    section(&mut module, 11, &data);
+
+    // names the functions, for stack traces
+    let mut names = vec![];
+    uleb(&mut names, 3 + (runtime.len() + functions.len()) as u64);
+    uleb(&mut names, FD_WRITE as u64);
+    name(&mut names, "fd_write");
+    uleb(&mut names, FD_READ as u64);
+    name(&mut names, "fd_read");
+    uleb(&mut names, PROC_EXIT as u64);
+    name(&mut names, "proc_exit");
+    for (i, (function, _, _)) in runtime.iter().enumerate() {
+        uleb(&mut names, ALLOC as u64 + i as u64);
+        name(&mut names, function);
+    }
+    for (i, function) in functions.iter().enumerate() {
+        uleb(&mut names, ENTRY as u64 + i as u64);
+        name(&mut names, &function.name);
+    }
+    let mut custom = vec![];
+    name(&mut custom, "name");
+    custom.push(1);
+    uleb(&mut custom, names.len() as u64);
+    custom.extend(names);
+    section(&mut module, 0, &custom);
+
+    Ok(module)
+}
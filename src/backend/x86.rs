@@ -1,9 +1,38 @@
+use super::{peephole, regalloc};
+
+use std::collections::HashMap;
 use std::fmt;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 static LABEL_COUNT: AtomicUsize = AtomicUsize::new(0);
 
-#[derive(Copy, Clone)]
+// the functions in the runtime that generated code can call (with 'call_rt'),
+// which are declared in this order at the top of the assembly, and are the only
+// names of calls the assembly parser accepts
+pub const RUNTIME: [&str; 20] = [
+    "alloc",
+    "make_closure",
+    "make_recursive_closure",
+    "make_recursive_closures",
+    "make_thunk",
+    "force",
+    "make_channel",
+    "make_box",
+    "make_string",
+    "concat_strings",
+    "compare_strings",
+    "print_string",
+    "make_array",
+    "spawn",
+    "send",
+    "recv",
+    "try_with",
+    "raise_exception",
+    "what",
+    "trap",
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Label {
     Generated(usize),
     Given(&'static str),
@@ -15,6 +44,12 @@ impl Label {
     }
 }
 
+impl Default for Label {
+    fn default() -> Label {
+        Label::new()
+    }
+}
+
 impl From<&'static str> for Label {
     fn from(string: &'static str) -> Label {
         Label::Given(string)
@@ -31,7 +66,7 @@ impl fmt::Display for Label {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Register {
     Rax,
     Rbx,
@@ -43,6 +78,12 @@ pub enum Register {
     Rdi,
     R8,
     R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
     Rip,
 }
 
@@ -86,6 +127,30 @@ pub fn r9() -> Location {
     Location::Register(Register::R9)
 }
 
+pub fn r10() -> Location {
+    Location::Register(Register::R10)
+}
+
+pub fn r11() -> Location {
+    Location::Register(Register::R11)
+}
+
+pub fn r12() -> Location {
+    Location::Register(Register::R12)
+}
+
+pub fn r13() -> Location {
+    Location::Register(Register::R13)
+}
+
+pub fn r14() -> Location {
+    Location::Register(Register::R14)
+}
+
+pub fn r15() -> Location {
+    Location::Register(Register::R15)
+}
+
 pub fn rip() -> Location {
     Location::Register(Register::Rip)
 }
@@ -123,17 +188,73 @@ impl fmt::Display for Register {
             Rdi => write!(f, "rdi"),
             R8 => write!(f, "r8"),
             R9 => write!(f, "r9"),
+            R10 => write!(f, "r10"),
+            R11 => write!(f, "r11"),
+            R12 => write!(f, "r12"),
+            R13 => write!(f, "r13"),
+            R14 => write!(f, "r14"),
+            R15 => write!(f, "r15"),
             Rip => write!(f, "rip"),
         }
     }
 }
 
-#[derive(Copy, Clone)]
+// the 32-bit names of registers, for instructions on 32-bit integers
+fn fmt_long(reg: Register, f: &mut fmt::Formatter) -> fmt::Result {
+    use self::Register::*;
+    match reg {
+        Rax => write!(f, "%eax"),
+        Rbx => write!(f, "%ebx"),
+        Rcx => write!(f, "%ecx"),
+        Rdx => write!(f, "%edx"),
+        Rsp => write!(f, "%esp"),
+        Rbp => write!(f, "%ebp"),
+        Rsi => write!(f, "%esi"),
+        Rdi => write!(f, "%edi"),
+        R8 => write!(f, "%r8d"),
+        R9 => write!(f, "%r9d"),
+        R10 => write!(f, "%r10d"),
+        R11 => write!(f, "%r11d"),
+        R12 => write!(f, "%r12d"),
+        R13 => write!(f, "%r13d"),
+        R14 => write!(f, "%r14d"),
+        R15 => write!(f, "%r15d"),
+        Rip => write!(f, "%eip"),
+    }
+}
+
+// the SSE registers floats are worked on in (values are never kept in them, so
+// only the two a binary operator needs are ever used)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Xmm {
+    Xmm0,
+    Xmm1,
+}
+
+impl Xmm {
+    pub fn number(self) -> u8 {
+        match self {
+            Xmm::Xmm0 => 0,
+            Xmm::Xmm1 => 1,
+        }
+    }
+}
+
+impl fmt::Display for Xmm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%xmm{}", self.number())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Location {
     Constant(i64),
     Register(Register),
     Memory(Register, i64),
     Relative(Register, Label),
+    // a thread-local variable, at the offset in the register from the thread
+    // pointer ('%fs')
+    ThreadLocal(Register),
 }
 
 impl fmt::Display for Location {
@@ -150,32 +271,118 @@ impl fmt::Display for Location {
                 }
             }
             Relative(r, l) => write!(f, "{}({})", l, r),
+            ThreadLocal(r) => write!(f, "%fs:({})", r),
         }
     }
 }
 
-enum Instruction {
+// the names of the lowest bytes of registers, for 'setcc'
+fn fmt_byte(reg: Register, f: &mut fmt::Formatter) -> fmt::Result {
+    use self::Register::*;
+    match reg {
+        Rax => write!(f, "%al"),
+        Rbx => write!(f, "%bl"),
+        Rcx => write!(f, "%cl"),
+        Rdx => write!(f, "%dl"),
+        Rsp => write!(f, "%spl"),
+        Rbp => write!(f, "%bpl"),
+        Rsi => write!(f, "%sil"),
+        Rdi => write!(f, "%dil"),
+        R8 => write!(f, "%r8b"),
+        R9 => write!(f, "%r9b"),
+        R10 => write!(f, "%r10b"),
+        R11 => write!(f, "%r11b"),
+        R12 => write!(f, "%r12b"),
+        R13 => write!(f, "%r13b"),
+        R14 => write!(f, "%r14b"),
+        R15 => write!(f, "%r15b"),
+        Rip => panic!("Attempted to use the lowest byte of '%rip'"),
+    }
+}
+
+// a location used as a byte operand
+struct Byte(Location);
+
+impl fmt::Display for Byte {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Location::Register(r) => fmt_byte(r, f),
+            loc => write!(f, "{}", loc),
+        }
+    }
+}
+
+// a location used as a 32-bit operand (which only changes how registers are named)
+struct Long(Location);
+
+impl fmt::Display for Long {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Location::Register(r) => fmt_long(r, f),
+            loc => write!(f, "{}", loc),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
     Label(Label),
     Push(Location),
     Pop(Location),
-    Not(Location),
     Neg(Location),
     Add(Location, Location),
     Sub(Location, Location),
     Mul(Location, Location),
     Div(Location),
     Cqto,
+    // an arithmetic shift right (by a constant), and a shift left
+    Sar(Location, Location),
+    Shl(Location, Location),
+    // the same arithmetic on 32-bit integers
+    NegL(Location),
+    AddL(Location, Location),
+    SubL(Location, Location),
+    MulL(Location, Location),
+    DivL(Location),
+    Cltd,
+    Cltq,
     Xor(Location, Location),
     Cmp(Location, Location),
+    Test(Location, Location),
+    Sete(Location),
+    Setl(Location),
+    // set if above (an unsigned comparison, which is how 'ucomisd' leaves the
+    // flags)
+    Seta(Location),
+    Movzb(Location, Location),
+    // the bits of a float, moved into or out of an SSE register
+    MovToXmm(Location, Xmm),
+    MovFromXmm(Xmm, Location),
+    AddSd(Xmm, Xmm),
+    SubSd(Xmm, Xmm),
+    MulSd(Xmm, Xmm),
+    DivSd(Xmm, Xmm),
+    Ucomisd(Xmm, Xmm),
+    // leaves every bit of the target set if the floats are equal, and every
+    // bit clear otherwise
+    CmpEqSd(Xmm, Xmm),
     Jmp(Label),
     Je(Label),
-    Jge(Label),
     Jne(Label),
+    Jno(Label),
+    Jbe(Label),
+    // jump if below (an unsigned comparison, so a negative number is above
+    // every other)
+    Jb(Label),
+    // jump if greater (or greater or equal), a signed comparison
+    Jg(Label),
+    Jge(Label),
     Mov(Location, Location),
     Lea(Location, Location),
     Call(Location),
     CallRuntime(&'static str),
     Comment(String),
+    Endbr64,
     Ret,
 }
 
@@ -186,52 +393,194 @@ impl fmt::Display for Instruction {
             Label(ref label) => writeln!(f, "{}:", label),
             Push(loc) => writeln!(f, "\tpushq {}", loc),
             Pop(loc) => writeln!(f, "\tpopq {}", loc),
-            Not(loc) => writeln!(f, "\tnotq {}", loc),
             Neg(loc) => writeln!(f, "\tnegq {}", loc),
             Add(source, target) => writeln!(f, "\taddq {},{}", source, target),
             Sub(source, target) => writeln!(f, "\tsubq {},{}", source, target),
             Mul(source, target) => writeln!(f, "\timulq {},{}", source, target),
             Div(source) => writeln!(f, "\tidivq {}", source),
             Cqto => writeln!(f, "\tcqto"),
+            Sar(source, target) => writeln!(f, "\tsarq {},{}", source, target),
+            Shl(source, target) => writeln!(f, "\tshlq {},{}", source, target),
+            NegL(loc) => writeln!(f, "\tnegl {}", Long(loc)),
+            AddL(source, target) => writeln!(f, "\taddl {},{}", Long(source), Long(target)),
+            SubL(source, target) => writeln!(f, "\tsubl {},{}", Long(source), Long(target)),
+            MulL(source, target) => writeln!(f, "\timull {},{}", Long(source), Long(target)),
+            DivL(source) => writeln!(f, "\tidivl {}", Long(source)),
+            Cltd => writeln!(f, "\tcltd"),
+            Cltq => writeln!(f, "\tcltq"),
             Xor(source, target) => writeln!(f, "\txorq {},{}", source, target),
             Cmp(source, target) => writeln!(f, "\tcmpq {},{}", source, target),
+            Test(source, target) => writeln!(f, "\ttestq {},{}", source, target),
+            Sete(loc) => writeln!(f, "\tsete {}", Byte(loc)),
+            Setl(loc) => writeln!(f, "\tsetl {}", Byte(loc)),
+            Seta(loc) => writeln!(f, "\tseta {}", Byte(loc)),
+            Movzb(source, target) => writeln!(f, "\tmovzbq {},{}", Byte(source), target),
+            MovToXmm(source, target) => writeln!(f, "\tmovq {},{}", source, target),
+            MovFromXmm(source, target) => writeln!(f, "\tmovq {},{}", source, target),
+            AddSd(source, target) => writeln!(f, "\taddsd {},{}", source, target),
+            SubSd(source, target) => writeln!(f, "\tsubsd {},{}", source, target),
+            MulSd(source, target) => writeln!(f, "\tmulsd {},{}", source, target),
+            DivSd(source, target) => writeln!(f, "\tdivsd {},{}", source, target),
+            Ucomisd(source, target) => writeln!(f, "\tucomisd {},{}", source, target),
+            CmpEqSd(source, target) => writeln!(f, "\tcmpeqsd {},{}", source, target),
             Jmp(ref label) => writeln!(f, "\tjmp {}", label),
             Je(ref label) => writeln!(f, "\tje {}", label),
-            Jge(ref label) => writeln!(f, "\tjge {}", label),
             Jne(ref label) => writeln!(f, "\tjne {}", label),
+            Jno(ref label) => writeln!(f, "\tjno {}", label),
+            Jbe(ref label) => writeln!(f, "\tjbe {}", label),
+            Jb(ref label) => writeln!(f, "\tjb {}", label),
+            Jg(ref label) => writeln!(f, "\tjg {}", label),
+            Jge(ref label) => writeln!(f, "\tjge {}", label),
             Mov(source, target) => writeln!(f, "\tmovq {},{}", source, target),
             Lea(source, target) => writeln!(f, "\tleaq {},{}", source, target),
             Call(loc) => writeln!(f, "\tcall *{}", loc),
             CallRuntime(name) => writeln!(f, "\tcall {}", name),
             Comment(ref comment) => writeln!(f, "\t# {}", comment),
+            Endbr64 => writeln!(f, "\tendbr64"),
             Ret => writeln!(f, "\tret"),
         }
     }
 }
 
-pub struct GeneratedCode(String);
+impl Instruction {
+    pub fn locations_mut(&mut self) -> Vec<&mut Location> {
+        use self::Instruction::*;
+        match *self {
+            Push(ref mut loc)
+            | Pop(ref mut loc)
+            | Neg(ref mut loc)
+            | Div(ref mut loc)
+            | NegL(ref mut loc)
+            | DivL(ref mut loc)
+            | Sete(ref mut loc)
+            | Setl(ref mut loc)
+            | Seta(ref mut loc)
+            | MovToXmm(ref mut loc, _)
+            | MovFromXmm(_, ref mut loc)
+            | Call(ref mut loc) => vec![loc],
+            Add(ref mut source, ref mut target)
+            | Sub(ref mut source, ref mut target)
+            | Mul(ref mut source, ref mut target)
+            | Sar(ref mut source, ref mut target)
+            | Shl(ref mut source, ref mut target)
+            | AddL(ref mut source, ref mut target)
+            | SubL(ref mut source, ref mut target)
+            | MulL(ref mut source, ref mut target)
+            | Xor(ref mut source, ref mut target)
+            | Cmp(ref mut source, ref mut target)
+            | Test(ref mut source, ref mut target)
+            | Movzb(ref mut source, ref mut target)
+            | Mov(ref mut source, ref mut target)
+            | Lea(ref mut source, ref mut target) => vec![source, target],
+            _ => vec![],
+        }
+    }
 
-impl fmt::Display for GeneratedCode {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+    // every label the instruction defines or refers to
+    pub fn labels_mut(&mut self) -> Vec<&mut Label> {
+        use self::Instruction::*;
+        match *self {
+            Label(ref mut label) | Jmp(ref mut label) | Je(ref mut label) | Jne(ref mut label)
+            | Jno(ref mut label) | Jbe(ref mut label) | Jb(ref mut label) | Jg(ref mut label)
+            | Jge(ref mut label) => vec![label],
+            _ => self
+                .locations_mut()
+                .into_iter()
+                .filter_map(|loc| match *loc {
+                    Location::Relative(_, ref mut label) => Some(label),
+                    _ => None,
+                })
+                .collect(),
+        }
     }
 }
 
+// where the thread's shadow stack is, relative to the thread pointer ('%fs'),
+// which the linker fills in from the runtime's thread-local variable
+fn shadow_stack() -> Location {
+    relative(rip(), "slang_shadow_stack@gottpoff".into())
+}
+
+// what unused stack slots are filled with in sanitised code: a pointer into
+// kernel space, so that anything that uses one as a pointer crashes right away
+pub const POISON: i64 = 0xffff_ffff_dead_beef_u64 as i64;
+
 pub struct Code {
     comments: bool,
+    sanitise: bool,
+    // whether functions start with 'endbr64', marking them as targets of indirect
+    // calls for CET (which closures always are)
+    harden: bool,
+    // whether locals are addressed relative to '%rsp' instead of '%rbp', so that
+    // functions don't need to set up a frame
+    omit_frame_pointer: bool,
+    // whether local variables and temporaries are kept in registers where they
+    // can be, rather than always on the stack
+    allocate_registers: bool,
+    // whether each call records which of the frame's slots hold values (for a
+    // precise garbage collector)
+    stack_maps: bool,
+    // whether each frame links a record of its local variables into the
+    // runtime's shadow stack
+    shadow_stack: bool,
+    safepoints: Vec<(Label, Vec<i64>)>,
+    // for a recursive function, its name and where its body starts, which a
+    // call to itself in tail position jumps back to instead of calling
+    self_call: Option<(String, Label)>,
+    // for a function that's only part of a curried recursive function (its
+    // body is the function taking the next parameter), that function's name
+    // and the parameters taken so far
+    curried: Option<(String, Vec<String>)>,
+    // for the function that takes the last parameter of a curried recursive
+    // function, that function's name, where the parameters taken before it are
+    // kept (if its body uses them) and where its body starts, which a call in
+    // tail position giving the function all of its arguments jumps back to
+    curried_call: Option<(String, Vec<Option<Location>>, Label)>,
+    // whether the value being computed is the function's result
+    tail: bool,
     label: Label,
-    env: Vec<(String, Location, bool)>,
+    // where each variable in scope is kept (every variable has a name of its
+    // own, so nothing is shadowed), and the slots of those that have gone out
+    // of scope, which are handed out again before the frame grows
+    env: HashMap<String, Location>,
+    free: Vec<Location>,
     allocated: usize,
+    // whether some path skips saving each callee-saved register the register
+    // allocator handed out
+    skipped: Vec<bool>,
     asm: Vec<Instruction>,
 }
 
 impl Code {
-    pub fn new(label: Label, comments: bool) -> Code {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        label: Label,
+        comments: bool,
+        sanitise: bool,
+        harden: bool,
+        omit_frame_pointer: bool,
+        allocate_registers: bool,
+        stack_maps: bool,
+        shadow_stack: bool,
+    ) -> Code {
         Code {
-            comments: comments,
-            label: label,
-            env: vec![],
+            comments,
+            sanitise,
+            harden,
+            omit_frame_pointer,
+            allocate_registers,
+            stack_maps,
+            shadow_stack,
+            safepoints: vec![],
+            self_call: None,
+            curried: None,
+            curried_call: None,
+            tail: false,
+            label,
+            env: HashMap::new(),
+            free: vec![],
             allocated: 0,
+            skipped: vec![],
             asm: vec![],
         }
     }
@@ -261,11 +610,6 @@ impl Code {
         self
     }
 
-    pub fn not(&mut self, loc: Location) -> &mut Code {
-        self.asm.push(Instruction::Not(loc));
-        self
-    }
-
     pub fn neg(&mut self, loc: Location) -> &mut Code {
         self.asm.push(Instruction::Neg(loc));
         self
@@ -291,11 +635,56 @@ impl Code {
         self
     }
 
+    pub fn sar(&mut self, source: Location, target: Location) -> &mut Code {
+        self.asm.push(Instruction::Sar(source, target));
+        self
+    }
+
+    pub fn shl(&mut self, source: Location, target: Location) -> &mut Code {
+        self.asm.push(Instruction::Shl(source, target));
+        self
+    }
+
     pub fn cqto(&mut self) -> &mut Code {
         self.asm.push(Instruction::Cqto);
         self
     }
 
+    pub fn negl(&mut self, loc: Location) -> &mut Code {
+        self.asm.push(Instruction::NegL(loc));
+        self
+    }
+
+    pub fn addl(&mut self, source: Location, target: Location) -> &mut Code {
+        self.asm.push(Instruction::AddL(source, target));
+        self
+    }
+
+    pub fn subl(&mut self, source: Location, target: Location) -> &mut Code {
+        self.asm.push(Instruction::SubL(source, target));
+        self
+    }
+
+    pub fn imull(&mut self, source: Location, target: Location) -> &mut Code {
+        self.asm.push(Instruction::MulL(source, target));
+        self
+    }
+
+    pub fn idivl(&mut self, source: Location) -> &mut Code {
+        self.asm.push(Instruction::DivL(source));
+        self
+    }
+
+    pub fn cltd(&mut self) -> &mut Code {
+        self.asm.push(Instruction::Cltd);
+        self
+    }
+
+    pub fn cltq(&mut self) -> &mut Code {
+        self.asm.push(Instruction::Cltq);
+        self
+    }
+
     pub fn xor(&mut self, source: Location, target: Location) -> &mut Code {
         self.asm.push(Instruction::Xor(source, target));
         self
@@ -306,6 +695,71 @@ impl Code {
         self
     }
 
+    pub fn test(&mut self, source: Location, target: Location) -> &mut Code {
+        self.asm.push(Instruction::Test(source, target));
+        self
+    }
+
+    pub fn sete(&mut self, loc: Location) -> &mut Code {
+        self.asm.push(Instruction::Sete(loc));
+        self
+    }
+
+    pub fn setl(&mut self, loc: Location) -> &mut Code {
+        self.asm.push(Instruction::Setl(loc));
+        self
+    }
+
+    pub fn seta(&mut self, loc: Location) -> &mut Code {
+        self.asm.push(Instruction::Seta(loc));
+        self
+    }
+
+    pub fn movq_to_xmm(&mut self, source: Location, target: Xmm) -> &mut Code {
+        self.asm.push(Instruction::MovToXmm(source, target));
+        self
+    }
+
+    pub fn movq_from_xmm(&mut self, source: Xmm, target: Location) -> &mut Code {
+        self.asm.push(Instruction::MovFromXmm(source, target));
+        self
+    }
+
+    pub fn addsd(&mut self, source: Xmm, target: Xmm) -> &mut Code {
+        self.asm.push(Instruction::AddSd(source, target));
+        self
+    }
+
+    pub fn subsd(&mut self, source: Xmm, target: Xmm) -> &mut Code {
+        self.asm.push(Instruction::SubSd(source, target));
+        self
+    }
+
+    pub fn mulsd(&mut self, source: Xmm, target: Xmm) -> &mut Code {
+        self.asm.push(Instruction::MulSd(source, target));
+        self
+    }
+
+    pub fn divsd(&mut self, source: Xmm, target: Xmm) -> &mut Code {
+        self.asm.push(Instruction::DivSd(source, target));
+        self
+    }
+
+    pub fn ucomisd(&mut self, source: Xmm, target: Xmm) -> &mut Code {
+        self.asm.push(Instruction::Ucomisd(source, target));
+        self
+    }
+
+    pub fn cmpeqsd(&mut self, source: Xmm, target: Xmm) -> &mut Code {
+        self.asm.push(Instruction::CmpEqSd(source, target));
+        self
+    }
+
+    pub fn movzb(&mut self, source: Location, target: Location) -> &mut Code {
+        self.asm.push(Instruction::Movzb(source, target));
+        self
+    }
+
     pub fn jmp(&mut self, label: Label) -> &mut Code {
         self.asm.push(Instruction::Jmp(label));
         self
@@ -316,53 +770,252 @@ impl Code {
         self
     }
 
-    pub fn jge(&mut self, label: Label) -> &mut Code {
-        self.asm.push(Instruction::Jge(label));
+    pub fn jne(&mut self, label: Label) -> &mut Code {
+        self.asm.push(Instruction::Jne(label));
         self
     }
 
-    pub fn jne(&mut self, label: Label) -> &mut Code {
-        self.asm.push(Instruction::Jne(label));
+    pub fn jno(&mut self, label: Label) -> &mut Code {
+        self.asm.push(Instruction::Jno(label));
+        self
+    }
+
+    pub fn jb(&mut self, label: Label) -> &mut Code {
+        self.asm.push(Instruction::Jb(label));
+        self
+    }
+
+    pub fn jg(&mut self, label: Label) -> &mut Code {
+        self.asm.push(Instruction::Jg(label));
+        self
+    }
+
+    pub fn jge(&mut self, label: Label) -> &mut Code {
+        self.asm.push(Instruction::Jge(label));
         self
     }
 
     pub fn call(&mut self, loc: Location) -> &mut Code {
         self.asm.push(Instruction::Call(loc));
-        self
+        self.safepoint()
     }
 
     pub fn call_rt(&mut self, name: &'static str) -> &mut Code {
+        debug_assert!(RUNTIME.contains(&name), "'{}' isn't in 'RUNTIME'", name);
         self.asm.push(Instruction::CallRuntime(name));
+        self.safepoint()
+    }
+
+    // labels the return address of the call just made, recording which of the
+    // frame's slots hold a variable that's in scope there (every slot is set as
+    // soon as its variable comes into scope, so none of them hold garbage)
+    fn safepoint(&mut self) -> &mut Code {
+        if !self.stack_maps {
+            return self;
+        }
+        let label = Label::new();
+        let mut slots = self
+            .env
+            .values()
+            .filter_map(|loc| match *loc {
+                Location::Memory(Register::Rbp, offset) => Some(offset),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        slots.sort_unstable();
+        self.safepoints.push((label, slots));
+        self.label(label)
+    }
+
+    pub fn stack_maps(&self) -> Vec<(Label, Vec<i64>)> {
+        self.safepoints.clone()
+    }
+
+    pub fn skipped(&self) -> Vec<bool> {
+        self.skipped.clone()
+    }
+
+    // marks where the body of the recursive function 'f' starts, which is in
+    // tail position
+    pub fn tail_calls(&mut self, f: String) -> &mut Code {
+        let label = Label::new();
+        self.self_call = Some((f, label));
+        self.tail = true;
+        self.label(label)
+    }
+
+    // where to jump to for a call to 'f' here, if it can reuse the frame
+    pub fn tail_call(&self, f: &str) -> Option<Label> {
+        match self.self_call {
+            Some((ref g, label)) if self.tail && f == g => Some(label),
+            _ => None,
+        }
+    }
+
+    // marks this as part of the curried recursive function 'f', which has taken
+    // 'params' by the time it's called
+    pub fn curried(&mut self, f: String, params: Vec<String>) -> &mut Code {
+        self.curried = Some((f, params));
         self
     }
 
-    pub fn comment(&mut self, comment: String) -> &mut Code {
+    pub fn curried_params(&self) -> Option<(String, Vec<String>)> {
+        self.curried.clone()
+    }
+
+    // marks where the body of the function taking the last parameter of the
+    // curried recursive function 'f' starts, which is in tail position
+    pub fn curried_tail_calls(&mut self, f: String, params: &[String]) -> &mut Code {
+        let label = Label::new();
+        let slots = params
+            .iter()
+            .map(|param| self.env.get(param).copied())
+            .collect();
+        self.curried_call = Some((f, slots, label));
+        self.tail = true;
+        self.label(label)
+    }
+
+    // the curried recursive function a call here giving it all of its
+    // arguments can reuse the frame for, with where the parameters before the
+    // last go and where to jump to
+    pub fn curried_tail_call(&self) -> Option<(String, Vec<Option<Location>>, Label)> {
+        match self.curried_call {
+            Some(ref call) if self.tail => Some(call.clone()),
+            _ => None,
+        }
+    }
+
+    // sets whether what's emitted next is in tail position, returning whether
+    // what came before it was
+    pub fn in_tail(&mut self, tail: bool) -> bool {
+        std::mem::replace(&mut self.tail, tail)
+    }
+
+    pub fn comment<S: Into<String>>(&mut self, comment: S) -> &mut Code {
         if self.comments {
-            self.asm.push(Instruction::Comment(comment));
+            self.asm.push(Instruction::Comment(comment.into()));
         }
         self
     }
 
-    pub fn ret(&mut self) -> GeneratedCode {
+    pub fn ret(&mut self) -> Vec<Instruction> {
+        if self.allocate_registers {
+            (self.allocated, self.skipped) = regalloc::allocate(&mut self.asm);
+            peephole::optimise(&mut self.asm);
+        }
+        if self.omit_frame_pointer {
+            return self.ret_without_frame_pointer();
+        }
+        // the record goes below the local variables, which it lists
+        let record = -(self.allocated as i64) - 16;
+        if self.shadow_stack {
+            self.comment("unlink this frame's record from the shadow stack")
+                .mov(shadow_stack(), rcx())
+                .mov(deref(rbp(), record), rdx())
+                .mov(rdx(), Location::ThreadLocal(Register::Rcx));
+        }
         self.comment(format!(
             "update stack pointer ('{}') to base pointer ('{}')",
             rsp(),
             rbp()
         ))
         .mov(rbp(), rsp())
-        .comment(format!("drop back into previous stack frame"))
+        .comment("drop back into previous stack frame")
         .pop(rbp());
-        if self.allocated > 0 {
+        // these are inserted in reverse, so that they come after the frame is set up
+        let stack_low = relative(rip(), "slang_stack_low".into());
+        let deep_enough = Label::new();
+        self.asm.insert(0, Instruction::Label(deep_enough));
+        self.asm.insert(0, Instruction::Mov(rsp(), stack_low));
+        self.asm.insert(0, Instruction::Jbe(deep_enough));
+        self.asm.insert(0, Instruction::Cmp(rsp(), stack_low));
+        if self.comments {
+            self.asm.insert(
+                0,
+                Instruction::Comment(format!(
+                    "the runtime keeps track of the lowest the stack pointer ('{}') has been in '{}' (for 'SLANG_STATS')",
+                    rsp(),
+                    stack_low
+                )),
+            );
+        }
+        if self.shadow_stack {
+            let prologue = vec![
+                Instruction::Mov(shadow_stack(), rcx()),
+                Instruction::Mov(Location::ThreadLocal(Register::Rcx), rax()),
+                Instruction::Mov(rax(), deref(rbp(), record)),
+                Instruction::Mov(
+                    constant(self.allocated as i64 / 8),
+                    deref(rbp(), record + 8),
+                ),
+                Instruction::Lea(deref(rbp(), record), rax()),
+                Instruction::Mov(rax(), Location::ThreadLocal(Register::Rcx)),
+            ];
+            for instruction in prologue.into_iter().rev() {
+                self.asm.insert(0, instruction);
+            }
+            if self.comments {
+                self.asm.insert(
+                    0,
+                    Instruction::Comment(format!(
+                        "link a record of the {} local variables (the pointer to the previous record, then how many there are) into the shadow stack at '{}'",
+                        self.allocated / 8,
+                        deref(rbp(), record)
+                    )),
+                );
+            }
+            // poisoned slots don't point to anything either
+            if !self.sanitise {
+                for slot in (1..=self.allocated / 8).rev() {
+                    self.asm.insert(
+                        0,
+                        Instruction::Mov(constant(0), deref(rbp(), -8 * slot as i64)),
+                    );
+                }
+                if self.comments && self.allocated > 0 {
+                    self.asm.insert(
+                        0,
+                        Instruction::Comment(
+                            "empty every local variable, so that the runtime never finds garbage in one".to_string(),
+                        ),
+                    );
+                }
+            }
+        }
+        if self.sanitise {
+            for slot in (1..=self.allocated / 8).rev() {
+                self.asm.insert(
+                    0,
+                    Instruction::Mov(constant(POISON), deref(rbp(), -8 * slot as i64)),
+                );
+            }
+            if self.comments && self.allocated > 0 {
+                self.asm.insert(
+                    0,
+                    Instruction::Comment(format!(
+                        "fill every local variable with poison ('{}') so that reading one before it's set stands out",
+                        constant(POISON)
+                    )),
+                );
+            }
+        }
+        let frame = if self.shadow_stack {
+            -record as usize
+        } else {
+            self.allocated
+        };
+        if frame > 0 {
             self.asm
-                .insert(0, Instruction::Sub(constant(self.allocated as i64), rsp()));
+                .insert(0, Instruction::Sub(constant(frame as i64), rsp()));
             if self.comments {
                 self.asm.insert(
                     0,
                     Instruction::Comment(format!(
                     "we need {} bytes for local variables so decrement stack pointer ('{}') by {}",
-                    self.allocated,
+                    frame,
                     rsp(),
-                    self.allocated
+                    frame
                 )),
                 );
             }
@@ -385,52 +1038,93 @@ impl Code {
                 Instruction::Comment(format!("save the base pointer ('{}')", rbp())),
             );
         }
+        if self.harden {
+            self.asm.insert(0, Instruction::Endbr64);
+        }
         self.asm.insert(0, Instruction::Label(self.label));
         self.asm.push(Instruction::Ret);
-        GeneratedCode(format!("{}", self))
+        std::mem::take(&mut self.asm)
     }
 
-    pub fn allocate(&mut self, v: String) -> Location {
-        for (envv, loc, enabled) in self.env.iter_mut().rev() {
-            if envv == &v && !*enabled {
-                *enabled = true;
-                return *loc;
+    // locals are still allocated relative to '%rbp' (as if there were a frame),
+    // then moved to '%rsp', which means keeping track of how far the stack has
+    // grown since the function started (this is the same at every label, as
+    // everything pushed while evaluating an expression is popped again)
+    fn ret_without_frame_pointer(&mut self) -> Vec<Instruction> {
+        let mut depth = self.allocated as i64;
+        for instruction in self.asm.iter_mut() {
+            // 'popq' works out where to pop to after it has incremented '%rsp'
+            if let Instruction::Pop(_) = instruction {
+                depth -= 8;
             }
-        }
-        self.allocated += 8;
-        let loc = deref(rbp(), -(self.allocated as i64));
-        self.env.push((v, loc, true));
-        loc
-    }
-
-    pub fn deallocate(&mut self, v: String) {
-        for (envv, _, enabled) in self.env.iter_mut().rev() {
-            if envv == &v && *enabled {
-                *enabled = false;
-                break;
+            for loc in instruction.locations_mut() {
+                if let Location::Memory(Register::Rbp, offset) = *loc {
+                    *loc = deref(rsp(), offset + depth);
+                }
+            }
+            match *instruction {
+                Instruction::Push(_) => depth += 8,
+                Instruction::Sub(Location::Constant(c), Location::Register(Register::Rsp)) => {
+                    depth += c
+                }
+                Instruction::Add(Location::Constant(c), Location::Register(Register::Rsp)) => {
+                    depth -= c
+                }
+                _ => (),
             }
         }
+        if self.allocated > 0 {
+            self.add(constant(self.allocated as i64), rsp());
+        }
+        let stack_low = relative(rip(), "slang_stack_low".into());
+        let deep_enough = Label::new();
+        self.asm.insert(0, Instruction::Label(deep_enough));
+        self.asm.insert(0, Instruction::Mov(rsp(), stack_low));
+        self.asm.insert(0, Instruction::Jbe(deep_enough));
+        self.asm.insert(0, Instruction::Cmp(rsp(), stack_low));
+        if self.allocated > 0 {
+            self.asm
+                .insert(0, Instruction::Sub(constant(self.allocated as i64), rsp()));
+        }
+        if self.harden {
+            self.asm.insert(0, Instruction::Endbr64);
+        }
+        self.asm.insert(0, Instruction::Label(self.label));
+        self.asm.push(Instruction::Ret);
+        std::mem::take(&mut self.asm)
     }
 
-    pub fn get_env(&self) -> &Vec<(String, Location, bool)> {
-        &self.env
+    pub fn allocate(&mut self, v: String) -> Location {
+        let loc = match self.free.pop() {
+            Some(loc) => loc,
+            None => {
+                self.allocated += 8;
+                deref(rbp(), -(self.allocated as i64))
+            }
+        };
+        self.env.insert(v, loc);
+        loc
     }
 
-    pub fn get(&self, v: &str) -> Location {
-        for (envv, loc, enabled) in self.env.iter().rev() {
-            if v == envv && *enabled {
-                return *loc;
-            }
+    pub fn deallocate(&mut self, v: String) {
+        let loc = match self.env.remove(&v) {
+            Some(loc) => loc,
+            None => return,
+        };
+        self.free.push(loc);
+        if self.sanitise {
+            self.comment(format!(
+                "'{}' has gone out of scope, so its space ('{}') is poisoned",
+                v, loc
+            ))
+            .mov(constant(POISON), loc);
         }
-        panic!("Attempted to get unbound variable")
     }
-}
 
-impl fmt::Display for Code {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for line in self.asm.iter() {
-            write!(f, "{}", line)?;
+    pub fn get(&self, v: &str) -> Location {
+        match self.env.get(v) {
+            Some(loc) => *loc,
+            None => panic!("Attempted to get unbound variable"),
         }
-        Ok(())
     }
 }
@@ -0,0 +1,147 @@
+use super::frontend::{self, ast, Location};
+use super::interp::{self, Env, Input, Interpreter, Limits};
+use super::{read, CompilerOptions, Value};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+use termion::{color, style};
+
+// how many times each expression in a program was run, over one or more runs
+pub struct Coverage {
+    pub warnings: Vec<String>,
+    // the value of each run (or the error it failed with), in order
+    pub results: Vec<Result<Value, String>>,
+    text: String,
+    // where each expression starts, along with its count (expressions that
+    // start in the same place share a count)
+    counts: Vec<(Location, u64)>,
+}
+
+impl Coverage {
+    // how many of the expressions were run at all, out of how many there are
+    pub fn covered(&self) -> (usize, usize) {
+        let run = self.counts.iter().filter(|(_, count)| *count > 0).count();
+        (run, self.counts.len())
+    }
+
+    // the lines and columns of the expressions that were never run
+    pub fn missed(&self) -> Vec<(usize, usize)> {
+        self.counts
+            .iter()
+            .filter(|(_, count)| *count == 0)
+            .map(|(location, _)| (location.line(), location.column()))
+            .collect()
+    }
+}
+
+// the source with each line's count down the side (the most any expression
+// starting on it was run), marking lines that were never run and pointing out
+// anything on a line that was run but was itself missed (like a branch that was
+// never taken)
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, line) in self.text.lines().enumerate() {
+            let counts = self
+                .counts
+                .iter()
+                .filter(|(location, _)| location.line() == i + 1)
+                .collect::<Vec<_>>();
+            match counts.iter().map(|(_, count)| *count).max() {
+                None => writeln!(f, "{:>9} | {}", "", line)?,
+                Some(0) => writeln!(
+                    f,
+                    "{}{:>9}{} | {}",
+                    color::Fg(color::Red),
+                    "#####",
+                    color::Fg(color::Reset),
+                    line
+                )?,
+                Some(count) => {
+                    writeln!(f, "{:>9} | {}", count, line)?;
+                    let missed = counts
+                        .iter()
+                        .filter(|(_, count)| *count == 0)
+                        .map(|(location, _)| location.column())
+                        .collect::<Vec<_>>();
+                    if missed.is_empty() {
+                        continue;
+                    }
+                    // tabs are copied so that the markers line up however wide
+                    // they're shown
+                    let mut marks = String::new();
+                    for (column, c) in line.chars().enumerate() {
+                        if column + 1 > *missed.iter().max().unwrap() {
+                            break;
+                        }
+                        marks.push(match c {
+                            _ if missed.contains(&(column + 1)) => '^',
+                            '\t' => '\t',
+                            _ => ' ',
+                        });
+                    }
+                    writeln!(
+                        f,
+                        "{:>9} | {}{}{}{}{}",
+                        "",
+                        style::Bold,
+                        color::Fg(color::Red),
+                        marks,
+                        color::Fg(color::Reset),
+                        style::Reset
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// runs a program in the interpreter once for each input (so a whole test suite
+// can be measured at once), counting how many times each of its expressions is
+// run
+pub fn coverage(
+    input: &Path,
+    whats: Vec<Input>,
+    options: &CompilerOptions,
+) -> Result<Coverage, String> {
+    let text = read(input)?;
+    // the program isn't optimised, so that every expression in it is still
+    // there to be counted
+    let (ast, locations, warnings) =
+        frontend::instrumented_frontend(&format!("{}", input.display()), text.clone(), options)?;
+    let names = (0..locations.len()).map(ast::probe).collect::<Vec<_>>();
+    let probes = names
+        .iter()
+        .map(|_| Rc::new(RefCell::new(interp::Value::Int(0))))
+        .collect::<Vec<_>>();
+    let mut env: Env = None;
+    for (name, probe) in names.iter().zip(probes.iter()) {
+        env = interp::bind(&env, name, interp::Value::Ref(probe.clone()));
+    }
+    let mut results = vec![];
+    for what in whats {
+        let mut interpreter = Interpreter::new(what, Limits::default(), options.int_width);
+        if let Some(seed) = options.schedule {
+            interpreter.schedule(seed);
+        }
+        results.push(interpreter.run(&env, &ast).map(|value| Value::from(&value)));
+    }
+    let counts = locations
+        .into_iter()
+        .zip(probes.iter())
+        .map(|(location, probe)| match *probe.borrow() {
+            // the counts are ordinary ints, so they wrap around at the width of
+            // an 'int' (which takes billions of runs, even at 32 bits)
+            interp::Value::Int(count) => (location, count as u64),
+            _ => unreachable!(),
+        })
+        .collect();
+    Ok(Coverage {
+        warnings,
+        results,
+        text,
+        counts,
+    })
+}
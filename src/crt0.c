@@ -1,7 +1,13 @@
+#define _GNU_SOURCE
+#include <pthread.h>
+#include <setjmp.h>
+#include <signal.h>
 #include <stdarg.h>
 #include <stdint.h>
 #include <stdio.h>
 #include <stdlib.h>
+#include <string.h>
+#include <ucontext.h>
 
 union slang_value;
 
@@ -25,6 +31,42 @@ typedef struct {
   slang_ptr *env;
 } slang_lambda;
 
+// a lazy value, which is computed by calling 'f' (like a closure, but with no
+// argument) the first time it's forced
+#define THUNK_DELAYED 0
+#define THUNK_FORCING 1
+#define THUNK_FORCED 2
+
+typedef struct {
+  int64_t tag;
+  slang_ptr (*f)(slang_ptr, slang_ptr *);
+  slang_ptr value;
+  slang_ptr *env;
+} slang_thunk;
+
+// a channel holds at most one value, which a sender leaves for a receiver to
+// take ('taken' counts the values that have been received, so that the sender
+// can tell when its own has been)
+typedef struct {
+  int64_t full;
+  slang_ptr value;
+  uint64_t taken;
+} slang_channel;
+
+// a string is its length followed by its bytes (which aren't followed by a
+// zero, as compiled code never passes them to C)
+typedef struct {
+  int64_t length;
+  char bytes[];
+} slang_string;
+
+// an array is its length followed by its elements, and compiled code checks
+// every index against the length before it reads or writes an element
+typedef struct {
+  int64_t length;
+  slang_ptr elements[];
+} slang_array;
+
 typedef union slang_value {
   slang_pair pair;
   slang_union in;
@@ -32,16 +74,360 @@ typedef union slang_value {
   slang_lambda lambda;
 } slang_value;
 
+// every object on the heap is preceded by a header saying what kind of object
+// it is and how big it is (not counting the header), so that the runtime can
+// describe the heap without knowing how each object was built (compiled code
+// passes the kind to 'alloc', so the kinds have to match those in the backend)
+#define KIND_PAIR 0
+#define KIND_UNION 1
+#define KIND_REF 2
+#define KIND_CLOSURE 3
+#define KIND_ENV 4
+#define KIND_THUNK 5
+#define KIND_CHANNEL 6
+#define KIND_BOX 7
+#define KIND_STRING 8
+#define KIND_ARRAY 9
+
+static const char *kind_names[] = {
+    "pair",        "union",      "reference", "closure",
+    "environment", "lazy value", "channel",   "box",
+    "string",      "array",
+};
+
+typedef struct {
+  uint32_t kind;
+  uint32_t size;
+} slang_header;
+
+typedef struct {
+  const char *filename;
+  int64_t line;
+  int64_t column;
+  const char *message;
+} slang_trap;
+
+typedef struct {
+  uintptr_t address;
+  const char *name;
+} slang_function;
+
+// where each compiled function starts, ending with an entry with no name at
+// the end of the compiled code
+#ifdef SLANG_JIT
+// code compiled in memory (by 'slang::jit_run') passes its table to
+// 'slang_jit_run' instead of linking against the runtime
+static const slang_function *slang_functions = NULL;
+#else
 slang_ptr entry();
 
-slang_ptr alloc() {
-  return (slang_ptr)(slang_value *)malloc(sizeof(slang_value));
+extern const slang_function slang_functions[];
+#endif
+
+// compiled code doesn't keep the stack 16-byte aligned, so every entry point
+// into the runtime has to realign it before calling into libc
+#define SLANG_RUNTIME __attribute__((force_align_arg_pointer))
+
+// with 'SLANG_STATS' set, the runtime reports how much memory the program used
+// when it exits (nothing is ever freed, so the peak heap usage is everything
+// that was allocated)
+static size_t allocations = 0;
+static size_t allocated = 0;
+
+// the top of the main thread's stack, which the stack depth is measured from
+// and a heap dump scans down from
+static uintptr_t stack_top = 0;
+
+// the lowest the stack pointer has been at the start of a function, which the
+// compiled code keeps up to date
+uintptr_t slang_stack_low = UINTPTR_MAX;
+
+// with '--shadow-stack', each function's frame holds a record of its local
+// variables just below them, which points to the record for the frame it was
+// called from, and compiled code keeps this pointing at the innermost record
+// (every slot is emptied when the frame is set up, so none of them hold garbage)
+typedef struct slang_shadow_frame {
+  struct slang_shadow_frame *previous;
+  int64_t count;
+  uintptr_t slots[];
+} slang_shadow_frame;
+
+__thread slang_shadow_frame *slang_shadow_stack = NULL;
+
+// programs compiled with '--sanitise' define this, in which case every
+// allocation is poisoned and surrounded by red zones, which are checked at exit
+extern const int64_t slang_sanitise __attribute__((weak));
+
+#define RED_ZONE 16
+#define RED 0xfa
+#define POISON 0xde
+
+// kept in front of each sanitised allocation (this is 16 bytes, so the
+// allocation stays aligned)
+typedef struct guard {
+  struct guard *next;
+  size_t size;
+} guard;
+
+static guard *guarded = NULL;
+
+static int red_zone_intact(const unsigned char *zone) {
+  for (size_t i = 0; i < RED_ZONE; i++) {
+    if (zone[i] != RED)
+      return 0;
+  }
+  return 1;
 }
 
-slang_ptr make_closure(slang_ptr (*f)(slang_ptr, slang_ptr *), size_t envc,
-                       ...) {
-  slang_ptr built = alloc();
-  slang_ptr *env = calloc(sizeof(slang_ptr), envc);
+static void check_red_zones() {
+  for (guard *g = guarded; g; g = g->next) {
+    unsigned char *before = (unsigned char *)(g + 1);
+    unsigned char *after = before + RED_ZONE + g->size;
+    if (!red_zone_intact(before) || !red_zone_intact(after)) {
+      // the header is inside the red zones, so it can still be trusted
+      slang_header *header = (slang_header *)(before + RED_ZONE);
+      fflush(stdout);
+      fprintf(stderr,
+              "runtime error: memory around a %u-byte %s at %p was "
+              "overwritten\n",
+              header->size, kind_names[header->kind], (void *)(header + 1));
+      _Exit(1);
+    }
+  }
+}
+
+// programs can allocate from several threads at once
+static pthread_mutex_t heap = PTHREAD_MUTEX_INITIALIZER;
+
+// with 'SLANG_HEAP_DUMP' or 'SLANG_HEAP_VERIFY' set, the runtime keeps track of
+// every object it allocates, so that it can dump the ones that are still
+// reachable or check them all
+static int tracking = 0;
+static slang_header **objects = NULL;
+static size_t object_count = 0;
+static size_t object_capacity = 0;
+
+// with 'SLANG_HEAP_VERIFY' set, every object's header is checked each
+// time anything is allocated, so that code writing past the end of an object
+// (and over the header of the next) is caught close to where it happens
+static int verifying = 0;
+
+static int header_intact(const slang_header *header) {
+  switch (header->kind) {
+  case KIND_PAIR:
+  case KIND_UNION:
+    return header->size == sizeof(slang_pair);
+  case KIND_REF:
+  case KIND_BOX:
+    return header->size == sizeof(slang_ptr);
+  case KIND_CLOSURE:
+    return header->size == sizeof(slang_lambda);
+  case KIND_ENV:
+    return header->size % sizeof(slang_ptr) == 0;
+  case KIND_THUNK:
+    return header->size == sizeof(slang_thunk);
+  case KIND_CHANNEL:
+    return header->size == sizeof(slang_channel);
+  case KIND_STRING:
+    return header->size >= sizeof(slang_string);
+  case KIND_ARRAY:
+    return header->size >= sizeof(slang_array) &&
+           (header->size - sizeof(slang_array)) % sizeof(slang_ptr) == 0;
+  default:
+    return 0;
+  }
+}
+
+// checks the header of every object allocated so far (with 'heap' held)
+static void verify_heap() {
+  for (size_t i = 0; i < object_count; i++) {
+    if (!header_intact(objects[i])) {
+      fflush(stdout);
+      fprintf(stderr,
+              "runtime error: the header of the object at %p was overwritten "
+              "(is something writing past the end of the object before it?)\n",
+              (void *)(objects[i] + 1));
+      _Exit(1);
+    }
+  }
+}
+
+// allocates an object of the given kind, returning a pointer to just after its
+// header
+static void *allocate(uint32_t kind, size_t object_size) {
+  size_t size = sizeof(slang_header) + object_size;
+  slang_header *header;
+  guard *g = NULL;
+  if (!&slang_sanitise) {
+    header = malloc(size);
+  } else {
+    g = malloc(sizeof(guard) + RED_ZONE + size + RED_ZONE);
+    g->size = size;
+    unsigned char *memory = (unsigned char *)(g + 1) + RED_ZONE;
+    memset(memory - RED_ZONE, RED, RED_ZONE);
+    memset(memory, POISON, size);
+    memset(memory + size, RED, RED_ZONE);
+    header = (slang_header *)memory;
+  }
+  header->kind = kind;
+  header->size = object_size;
+  pthread_mutex_lock(&heap);
+  if (verifying)
+    verify_heap();
+  allocations++;
+  allocated += size;
+  if (g) {
+    g->next = guarded;
+    guarded = g;
+  }
+  if (tracking) {
+    if (object_count == object_capacity) {
+      object_capacity = object_capacity ? 2 * object_capacity : 256;
+      objects = realloc(objects, sizeof(slang_header *) * object_capacity);
+    }
+    objects[object_count++] = header;
+  }
+  pthread_mutex_unlock(&heap);
+  return header + 1;
+}
+
+static int by_address(const void *a, const void *b) {
+  uintptr_t x = (uintptr_t) * (slang_header *const *)a;
+  uintptr_t y = (uintptr_t) * (slang_header *const *)b;
+  return (x > y) - (x < y);
+}
+
+// the index of the object that starts at 'address' (once 'objects' is sorted),
+// or 'object_count' if there isn't one
+static size_t object_at(uintptr_t address) {
+  size_t low = 0, high = object_count;
+  while (low < high) {
+    size_t middle = low + (high - low) / 2;
+    uintptr_t start = (uintptr_t)(objects[middle] + 1);
+    if (start == address)
+      return middle;
+    if (start < address)
+      low = middle + 1;
+    else
+      high = middle;
+  }
+  return object_count;
+}
+
+// prints every object reachable from 'roots' (with 'heap' held), going through
+// each object's words using the size in its header: compiled code doesn't say
+// which words are pointers, so any word that holds the address of an object is
+// treated as one, which can only make more of the heap look reachable
+static void dump_heap(const uintptr_t *roots, size_t root_count) {
+  qsort(objects, object_count, sizeof(slang_header *), by_address);
+  char *marked = calloc(object_count ? object_count : 1, 1);
+  size_t *work = malloc(sizeof(size_t) * (object_count ? object_count : 1));
+  size_t pending = 0;
+  for (size_t i = 0; i < root_count; i++) {
+    size_t found = object_at(roots[i]);
+    if (found < object_count && !marked[found]) {
+      marked[found] = 1;
+      work[pending++] = found;
+    }
+  }
+  while (pending) {
+    slang_header *header = objects[work[--pending]];
+    const uintptr_t *words = (const uintptr_t *)(header + 1);
+    for (size_t i = 0; i < header->size / sizeof(uintptr_t); i++) {
+      size_t found = object_at(words[i]);
+      if (found < object_count && !marked[found]) {
+        marked[found] = 1;
+        work[pending++] = found;
+      }
+    }
+  }
+  size_t reachable = 0, bytes = 0;
+  for (size_t i = 0; i < object_count; i++) {
+    if (marked[i]) {
+      reachable++;
+      bytes += sizeof(slang_header) + objects[i]->size;
+    }
+  }
+  fflush(stdout);
+  fprintf(stderr,
+          "heap dump: %zu of %zu objects reachable (%zu bytes including "
+          "headers)\n",
+          reachable, object_count, bytes);
+  for (size_t i = 0; i < object_count; i++) {
+    if (marked[i])
+      fprintf(stderr, "  %p: %s, %u bytes\n", (void *)(objects[i] + 1),
+              kind_names[objects[i]->kind], objects[i]->size);
+  }
+  free(marked);
+  free(work);
+}
+
+// set once the program has spawned a thread, as the stack depth is only
+// tracked for one stack
+static int spawned = 0;
+
+static void report() {
+  size_t stack = slang_stack_low < stack_top ? stack_top - slang_stack_low : 0;
+  fprintf(stderr, "peak heap usage: %zu bytes in %zu allocations\n", allocated,
+          allocations);
+  if (!spawned)
+    fprintf(stderr, "max stack depth: %zu bytes\n", stack);
+}
+
+// compiled code only builds pairs, unions and references itself
+SLANG_RUNTIME slang_ptr alloc(uint32_t kind) {
+  size_t size = kind == KIND_REF ? sizeof(slang_ptr) : sizeof(slang_pair);
+  return (slang_ptr)(slang_value *)allocate(kind, size);
+}
+
+// programs compiled with '--representation' define this as 1 if their ints
+// and bools are tagged and 2 if they're boxed (otherwise they're untagged)
+extern const int64_t slang_representation __attribute__((weak));
+
+#define REPRESENTATION_TAGGED 1
+#define REPRESENTATION_BOXED 2
+
+static int64_t representation() {
+  return &slang_representation ? slang_representation : 0;
+}
+
+SLANG_RUNTIME slang_ptr make_box(int64_t integer) {
+  slang_ptr *box = allocate(KIND_BOX, sizeof(slang_ptr));
+  box->integer = integer;
+  return (slang_ptr)(slang_value *)box;
+}
+
+// converts an int to the representation the compiled code uses
+static slang_ptr to_slang(int64_t integer) {
+  switch (representation()) {
+  case REPRESENTATION_TAGGED:
+    return (slang_ptr)(int64_t)((uint64_t)integer << 1 | 1);
+  case REPRESENTATION_BOXED:
+    return make_box(integer);
+  default:
+    return (slang_ptr)integer;
+  }
+}
+
+// converts an int (or a bool) back from the representation the compiled code
+// uses, which leaves anything else (including '()') as it is when ints are
+// tagged, and gives the first word it points to when they're boxed
+static int64_t from_slang(slang_ptr value) {
+  switch (representation()) {
+  case REPRESENTATION_TAGGED:
+    return value.integer & 1 ? value.integer >> 1 : value.integer;
+  case REPRESENTATION_BOXED:
+    return value.value ? value.value->ref.integer : 0;
+  default:
+    return value.integer;
+  }
+}
+
+SLANG_RUNTIME slang_ptr make_closure(slang_ptr (*f)(slang_ptr, slang_ptr *),
+                                   size_t envc, ...) {
+  slang_ptr built =
+      (slang_ptr)(slang_value *)allocate(KIND_CLOSURE, sizeof(slang_lambda));
+  slang_ptr *env = allocate(KIND_ENV, sizeof(slang_ptr) * envc);
   slang_lambda lambda = {.f = f, .env = env};
   built.value->lambda = lambda;
   va_list args;
@@ -52,10 +438,12 @@ slang_ptr make_closure(slang_ptr (*f)(slang_ptr, slang_ptr *), size_t envc,
   return built;
 }
 
-slang_ptr make_recursive_closure(slang_ptr (*f)(slang_ptr, slang_ptr *),
-                                 size_t envc, ...) {
-  slang_ptr built = alloc();
-  slang_ptr *env = calloc(sizeof(slang_ptr), envc + 1);
+SLANG_RUNTIME slang_ptr
+make_recursive_closure(slang_ptr (*f)(slang_ptr, slang_ptr *), size_t envc,
+                       ...) {
+  slang_ptr built =
+      (slang_ptr)(slang_value *)allocate(KIND_CLOSURE, sizeof(slang_lambda));
+  slang_ptr *env = allocate(KIND_ENV, sizeof(slang_ptr) * (envc + 1));
   env[0] = built;
   slang_lambda lambda = {.f = f, .env = env};
   built.value->lambda = lambda;
@@ -67,14 +455,130 @@ slang_ptr make_recursive_closure(slang_ptr (*f)(slang_ptr, slang_ptr *),
   return built;
 }
 
-slang_ptr what() {
+// builds the closures for functions defined together in a 'let rec', which
+// all share one environment: it holds each of the closures, and then the
+// values of the free variables. The arguments after 'envc' are the code for
+// each function ('count' of them), then the free variables ('envc' of them),
+// and the environment is returned (so the closures can be found in it)
+SLANG_RUNTIME slang_ptr *make_recursive_closures(size_t count, size_t envc,
+                                                 ...) {
+  slang_ptr *env = allocate(KIND_ENV, sizeof(slang_ptr) * (count + envc));
+  va_list args;
+  va_start(args, envc);
+  for (size_t i = 0; i < count; i++) {
+    slang_ptr built =
+        (slang_ptr)(slang_value *)allocate(KIND_CLOSURE, sizeof(slang_lambda));
+    slang_lambda lambda = {.f = va_arg(args, slang_ptr(*)(slang_ptr, slang_ptr *)),
+                           .env = env};
+    built.value->lambda = lambda;
+    env[i] = built;
+  }
+  for (size_t i = 0; i < envc; i++)
+    env[count + i] = va_arg(args, slang_ptr);
+  va_end(args);
+  return env;
+}
+
+SLANG_RUNTIME slang_ptr make_thunk(slang_ptr (*f)(slang_ptr, slang_ptr *),
+                                 size_t envc, ...) {
+  slang_thunk *thunk = allocate(KIND_THUNK, sizeof(slang_thunk));
+  slang_ptr *env = allocate(KIND_ENV, sizeof(slang_ptr) * envc);
+  thunk->tag = THUNK_DELAYED;
+  thunk->f = f;
+  thunk->value = (slang_ptr)(int64_t)0;
+  thunk->env = env;
+  va_list args;
+  va_start(args, envc);
+  for (size_t i = 0; i < envc; i++)
+    env[i] = va_arg(args, slang_ptr);
+  va_end(args);
+  return (slang_ptr)(slang_value *)thunk;
+}
+
+// calls a closure of type 'int -> int' from host code (which is how
+// 'slang::load' runs the functions it loads)
+SLANG_RUNTIME int64_t slang_apply(slang_ptr closure, int64_t arg) {
+  return from_slang(
+      closure.value->lambda.f(to_slang(arg), closure.value->lambda.env));
+}
+
+// what the runtime is allowed to do on the program's behalf, as given by
+// 'Capabilities::bits'
+#define CAN_IO 1
+#define CAN_FILESYSTEM 2
+#define CAN_ENV 4
+#define CAN_RANDOM 8
+
+#ifdef SLANG_JIT
+// which 'slang_jit_run' is told for each run
+static int64_t capabilities;
+#else
+// programs compiled without every capability define this
+extern const int64_t slang_capabilities __attribute__((weak));
+#endif
+
+static int capable(int64_t capability) {
+#ifdef SLANG_JIT
+  return (capabilities & capability) == capability;
+#else
+  return !&slang_capabilities ||
+         (slang_capabilities & capability) == capability;
+#endif
+}
+
+// '?' normally reads from stdin, but 'SLANG_INPUT' can name a file of numbers
+// to read instead, or 'SLANG_SEED' can seed a generator of numbers from 0 to 99
+static FILE *script = NULL;
+static int seeded = 0;
+static uint64_t state = 0;
+
+// splitmix64, which has to match 'random' in the interpreter
+static int64_t random_input() {
+  uint64_t z = (state += 0x9e3779b97f4a7c15);
+  z = (z ^ (z >> 30)) * 0xbf58476d1ce4e5b9;
+  z = (z ^ (z >> 27)) * 0x94d049bb133111eb;
+  return (int64_t)((z ^ (z >> 31)) % 100);
+}
+
+static pthread_mutex_t input = PTHREAD_MUTEX_INITIALIZER;
+
+#ifdef SLANG_JIT
+// where a run of code compiled in memory goes back to if it fails, along with
+// what went wrong
+static sigjmp_buf failed;
+static char failure[512];
+#endif
+
+// reports an error and ends the program (or, for code compiled in memory, the
+// run, which 'slang_jit_run' reports instead)
+static _Noreturn void fail(const char *format, ...) {
+  va_list args;
+  va_start(args, format);
+#ifdef SLANG_JIT
+  vsnprintf(failure, sizeof(failure), format, args);
+  va_end(args);
+  siglongjmp(failed, 1);
+#else
+  fflush(stdout);
+  vfprintf(stderr, format, args);
+  va_end(args);
+  exit(1);
+#endif
+}
+
+static slang_ptr read_input() {
   int64_t got = 0;
+  if (script) {
+    if (fscanf(script, "%ld", &got) != 1)
+      fail("ran out of input\n");
+    return (slang_ptr)got;
+  }
+  if (seeded)
+    return (slang_ptr)random_input();
   printf("> ");
   int result = scanf("%ld", &got);
-  if (result == EOF) {
-    fprintf(stderr, "stdin died :(\n");
-    exit(1);
-  }
+  if (result == EOF)
+    fail("stdin died :(\n");
   if (result == 0) {
     while (fgetc(stdin) != '\n')
       ;
@@ -82,7 +586,469 @@ slang_ptr what() {
   return (slang_ptr)got;
 }
 
+SLANG_RUNTIME slang_ptr what() {
+  pthread_mutex_lock(&input);
+  slang_ptr got = read_input();
+  pthread_mutex_unlock(&input);
+  return got;
+}
+
+SLANG_RUNTIME _Noreturn void trap(const slang_trap *trap) {
+  fail("%s: line %ld: column %ld: runtime error: %s\n", trap->filename,
+       trap->line, trap->column, trap->message);
+}
+
+// compiled code only calls this for a thunk that hasn't been forced yet (it
+// loads the value itself otherwise), so 't' is for when the thunk is already
+// being forced further up the stack
+SLANG_RUNTIME slang_ptr force(slang_thunk *thunk, const slang_trap *t) {
+  if (thunk->tag == THUNK_FORCING)
+    trap(t);
+  thunk->tag = THUNK_FORCING;
+  slang_ptr value = thunk->f((slang_ptr)(int64_t)0, thunk->env);
+  thunk->value = value;
+  thunk->tag = THUNK_FORCED;
+  return value;
+}
+
+// the 'try's running on this thread, innermost first, each with where to go
+// back to when an exception is raised and the shadow stack as it was when the
+// 'try' started (the exception itself is left in 'raised', as the handler's
+// own locals can't be relied on after a 'longjmp' if they've changed)
+typedef struct slang_handler {
+  struct slang_handler *previous;
+  jmp_buf target;
+  slang_shadow_frame *shadow_stack;
+} slang_handler;
+
+static __thread slang_handler *handlers = NULL;
+static __thread int64_t raised = 0;
+
+// calls 'body' (a closure that takes '()'), and if it raises an exception,
+// calls 'handler' with it instead (everything the body had on the stack by
+// then is simply abandoned, as nothing on the heap is ever freed anyway)
+SLANG_RUNTIME slang_ptr try_with(slang_ptr body, slang_ptr handler) {
+  slang_handler h = {.previous = handlers, .shadow_stack = slang_shadow_stack};
+  handlers = &h;
+  if (setjmp(h.target) == 0) {
+    slang_ptr value =
+        body.value->lambda.f((slang_ptr)(int64_t)0, body.value->lambda.env);
+    handlers = h.previous;
+    return value;
+  }
+  handlers = h.previous;
+  slang_shadow_stack = h.shadow_stack;
+  return handler.value->lambda.f(to_slang(raised), handler.value->lambda.env);
+}
+
+// goes back to the innermost 'try', or ends the program where the exception
+// was raised ('t') if there isn't one
+SLANG_RUNTIME _Noreturn void raise_exception(slang_ptr exception,
+                                             const slang_trap *t) {
+  if (!handlers)
+    fail("%s: line %ld: column %ld: runtime error: %s %ld\n", t->filename,
+         t->line, t->column, t->message, from_slang(exception));
+  raised = from_slang(exception);
+  longjmp(handlers->target, 1);
+}
+
+// the name of the compiled function containing 'pc' (or 'NULL' if it's
+// somewhere else, like in the runtime or libc)
+static const char *function_at(uintptr_t pc) {
+  const slang_function *nearest = NULL;
+  const slang_function *function = slang_functions;
+  for (; function->name; function++) {
+    if (function->address <= pc &&
+        (!nearest || nearest->address < function->address))
+      nearest = function;
+  }
+  if (!nearest || function->address <= pc)
+    return NULL;
+  return nearest->name;
+}
+
+// the crash reporter runs on its own stack, so that it still works when the
+// program has run out of stack
+static char crash_stack[64 * 1024];
+
+// the lowest address this thread's stack can grow down to (for the main
+// thread, as far as 'RLIMIT_STACK' lets it), which is found when the thread
+// starts, as the crash reporter can't safely ask for it
+static __thread uintptr_t stack_limit = 0;
+
+// how far either side of a thread's stack limit a fault counts as running out
+// of stack: below it is the guard region (which Linux makes 256 pages for the
+// main thread), and a frame that's bigger than that can skip over it, so this
+// is generous; where the limit ends up exactly depends on how the stack was
+// rounded to pages, so a fault just above it counts too
+#define STACK_GUARD (1024 * 1024)
+
+static void find_stack_limit() {
+  pthread_attr_t attributes;
+  if (pthread_getattr_np(pthread_self(), &attributes) != 0)
+    return;
+  void *low;
+  size_t size;
+  if (pthread_attr_getstack(&attributes, &low, &size) == 0)
+    stack_limit = (uintptr_t)low;
+  pthread_attr_destroy(&attributes);
+}
+
+static void crashed(int signal, siginfo_t *info, void *context) {
+  mcontext_t *registers = &((ucontext_t *)context)->uc_mcontext;
+  uintptr_t pc = registers->gregs[REG_RIP];
+  uintptr_t address = (uintptr_t)info->si_addr;
+  const char *error = "segmentation fault";
+  const char *hint = "likely unbound or uninitialised memory";
+  // whether the fault is in the guard region below the stack is what tells an
+  // overflow apart, rather than where it is relative to '%rsp' (frames are
+  // written above it once they're set up, and the runtime's own frames can be
+  // anywhere below it)
+  if (signal == SIGFPE) {
+    error = "arithmetic error";
+    hint = "likely a division by zero or an overflow";
+  } else if (stack_limit && address + STACK_GUARD >= stack_limit &&
+             address < stack_limit + STACK_GUARD) {
+    hint = "likely a stack overflow (is there unbounded recursion?)";
+  }
+  const char *function = function_at(pc);
+  if (function)
+    fail("runtime error: %s in '%s': %s\n", error, function, hint);
+  else
+    fail("runtime error: %s outside of compiled code: %s\n", error, hint);
+}
+
+static void report_crashes() {
+  find_stack_limit();
+  stack_t stack = {.ss_sp = crash_stack, .ss_size = sizeof(crash_stack)};
+  sigaltstack(&stack, NULL);
+  struct sigaction action = {.sa_sigaction = crashed,
+                             .sa_flags = SA_SIGINFO | SA_ONSTACK};
+  sigemptyset(&action.sa_mask);
+  sigaction(SIGSEGV, &action, NULL);
+  sigaction(SIGFPE, &action, NULL);
+}
+
+// 'SIGUSR1' dumps the heap as it is, with the registers and every word on the
+// main thread's stack as the roots (other threads block the signal, so it's
+// always taken on the main thread), or only the local variables in the shadow
+// stack instead of the whole stack if there is one; like the crash reporter,
+// this calls into libc from a signal handler, which is only good enough for
+// debugging
+static void dump_requested(int signal, siginfo_t *info, void *context) {
+  (void)signal;
+  (void)info;
+  mcontext_t *registers = &((ucontext_t *)context)->uc_mcontext;
+  uintptr_t sp = registers->gregs[REG_RSP];
+  if (pthread_mutex_trylock(&heap) != 0) {
+    fprintf(stderr, "heap dump: skipped, as the program was allocating\n");
+    return;
+  }
+  size_t count = NGREG;
+  if (slang_shadow_stack) {
+    for (slang_shadow_frame *frame = slang_shadow_stack; frame;
+         frame = frame->previous)
+      count += frame->count;
+  } else {
+    count += (stack_top - sp) / sizeof(uintptr_t);
+  }
+  uintptr_t *roots = malloc(sizeof(uintptr_t) * count);
+  for (size_t i = 0; i < NGREG; i++)
+    roots[i] = registers->gregs[i];
+  if (slang_shadow_stack) {
+    uintptr_t *next = roots + NGREG;
+    for (slang_shadow_frame *frame = slang_shadow_stack; frame;
+         frame = frame->previous) {
+      memcpy(next, frame->slots, sizeof(uintptr_t) * frame->count);
+      next += frame->count;
+    }
+  } else {
+    memcpy(roots + NGREG, (const void *)sp, stack_top - sp);
+  }
+  dump_heap(roots, count);
+  free(roots);
+  pthread_mutex_unlock(&heap);
+}
+
+static void dump_on_request() {
+  struct sigaction action = {.sa_sigaction = dump_requested,
+                             .sa_flags = SA_SIGINFO | SA_ONSTACK | SA_RESTART};
+  sigemptyset(&action.sa_mask);
+  sigaction(SIGUSR1, &action, NULL);
+}
+
+// every channel shares one lock, and threads waiting on any of them are woken
+// whenever any of them changes (after which they check whether they can go on)
+static pthread_mutex_t channels = PTHREAD_MUTEX_INITIALIZER;
+static pthread_cond_t changed = PTHREAD_COND_INITIALIZER;
+
+// a program deadlocks when every thread is waiting, which is reported where the
+// main thread is waiting ('waiting' only counts threads that have waited since
+// the last change, as the rest might be able to go on)
+static pthread_t main_thread;
+static size_t threads = 1;
+static size_t waiting = 0;
+static uint64_t changes = 0;
+static const slang_trap *main_waiting = NULL;
+
+static void change() {
+  changes++;
+  waiting = 0;
+  pthread_cond_broadcast(&changed);
+}
+
+static void check_deadlock() {
+  if (waiting == threads)
+    trap(main_waiting);
+}
+
+// waits for a change to any channel (with 'channels' held)
+static void wait_for_change(const slang_trap *t) {
+  if (pthread_equal(pthread_self(), main_thread))
+    main_waiting = t;
+  waiting++;
+  check_deadlock();
+  uint64_t seen = changes;
+  while (changes == seen)
+    pthread_cond_wait(&changed, &channels);
+}
+
+static slang_string *new_string(int64_t length) {
+  slang_string *string =
+      allocate(KIND_STRING, sizeof(slang_string) + (size_t)length);
+  string->length = length;
+  return string;
+}
+
+// compiled code makes a new string each time a literal is run, so no two
+// strings are ever '=='
+SLANG_RUNTIME slang_ptr make_string(const char *bytes, int64_t length) {
+  slang_string *string = new_string(length);
+  memcpy(string->bytes, bytes, length);
+  return (slang_ptr)(slang_value *)string;
+}
+
+SLANG_RUNTIME slang_ptr concat_strings(const slang_string *left,
+                                       const slang_string *right) {
+  slang_string *string = new_string(left->length + right->length);
+  memcpy(string->bytes, left->bytes, left->length);
+  memcpy(string->bytes + left->length, right->bytes, right->length);
+  return (slang_ptr)(slang_value *)string;
+}
+
+// strings are compared by their bytes, and a string comes before any longer
+// string it starts
+SLANG_RUNTIME int64_t compare_strings(const slang_string *left,
+                                      const slang_string *right) {
+  int64_t shorter = left->length < right->length ? left->length : right->length;
+  int order = memcmp(left->bytes, right->bytes, shorter);
+  if (order == 0)
+    return (left->length > right->length) - (left->length < right->length);
+  return order < 0 ? -1 : 1;
+}
+
+SLANG_RUNTIME slang_ptr print_string(const slang_string *string) {
+  fwrite(string->bytes, 1, string->length, stdout);
+#ifdef SLANG_JIT
+  // the compiler prints the result itself, which has to come after this
+  fflush(stdout);
+#endif
+  return (slang_ptr)(int64_t)0;
+}
+
+// compiled code passes the length as a plain int (whatever the representation),
+// and 't' is for when it's negative
+SLANG_RUNTIME slang_ptr make_array(int64_t length, slang_ptr init,
+                                   const slang_trap *t) {
+  if (length < 0)
+    trap(t);
+  // the size in an object's header has to fit in 32 bits
+  if ((uint64_t)length > (UINT32_MAX - sizeof(slang_array)) / sizeof(slang_ptr))
+    fail("runtime error: an array of %ld elements is too big\n", length);
+  slang_array *array = allocate(
+      KIND_ARRAY, sizeof(slang_array) + sizeof(slang_ptr) * (size_t)length);
+  array->length = length;
+  for (int64_t i = 0; i < length; i++)
+    array->elements[i] = init;
+  return (slang_ptr)(slang_value *)array;
+}
+
+SLANG_RUNTIME slang_ptr make_channel() {
+  slang_channel *channel = allocate(KIND_CHANNEL, sizeof(slang_channel));
+  channel->full = 0;
+  channel->value = (slang_ptr)(int64_t)0;
+  channel->taken = 0;
+  return (slang_ptr)(slang_value *)channel;
+}
+
+SLANG_RUNTIME slang_ptr send(slang_channel *channel, slang_ptr value,
+                             const slang_trap *t) {
+  pthread_mutex_lock(&channels);
+  while (channel->full)
+    wait_for_change(t);
+  channel->value = value;
+  channel->full = 1;
+  uint64_t taken = channel->taken;
+  change();
+  while (channel->taken == taken)
+    wait_for_change(t);
+  pthread_mutex_unlock(&channels);
+  return (slang_ptr)(int64_t)0;
+}
+
+SLANG_RUNTIME slang_ptr recv(slang_channel *channel, const slang_trap *t) {
+  pthread_mutex_lock(&channels);
+  while (!channel->full)
+    wait_for_change(t);
+  slang_ptr value = channel->value;
+  channel->full = 0;
+  channel->taken++;
+  change();
+  pthread_mutex_unlock(&channels);
+  return value;
+}
+
+static void *start(void *closure) {
+  // the crash reporter needs a stack of its own on every thread
+  stack_t stack = {.ss_sp = malloc(sizeof(crash_stack)),
+                   .ss_size = sizeof(crash_stack)};
+  sigaltstack(&stack, NULL);
+  find_stack_limit();
+  sigset_t blocked;
+  sigemptyset(&blocked);
+  sigaddset(&blocked, SIGUSR1);
+  pthread_sigmask(SIG_BLOCK, &blocked, NULL);
+  slang_lambda *lambda = closure;
+  lambda->f((slang_ptr)(int64_t)0, lambda->env);
+  pthread_mutex_lock(&channels);
+  threads--;
+  check_deadlock();
+  pthread_mutex_unlock(&channels);
+  return NULL;
+}
+
+// threads are never joined: the program ends when the main thread finishes,
+// whatever the others are doing
+SLANG_RUNTIME slang_ptr spawn(slang_ptr (*f)(slang_ptr, slang_ptr *),
+                              size_t envc, ...) {
+  slang_lambda *lambda = allocate(KIND_CLOSURE, sizeof(slang_lambda));
+  slang_ptr *env = allocate(KIND_ENV, sizeof(slang_ptr) * envc);
+  lambda->f = f;
+  lambda->env = env;
+  va_list args;
+  va_start(args, envc);
+  for (size_t i = 0; i < envc; i++)
+    env[i] = va_arg(args, slang_ptr);
+  va_end(args);
+  pthread_mutex_lock(&channels);
+  threads++;
+  spawned = 1;
+  pthread_mutex_unlock(&channels);
+  pthread_t thread;
+  if (pthread_create(&thread, NULL, start, lambda) != 0) {
+    fprintf(stderr, "failed to spawn a thread\n");
+    exit(1);
+  }
+  pthread_detach(thread);
+  return (slang_ptr)(int64_t)0;
+}
+
+// where '?' reads from (which starts afresh for each run of code compiled in
+// memory)
+static void open_input() {
+  if (script) {
+    fclose(script);
+    script = NULL;
+  }
+  seeded = 0;
+  const char *input =
+      capable(CAN_ENV | CAN_FILESYSTEM) ? getenv("SLANG_INPUT") : NULL;
+  if (input && !(script = fopen(input, "r")))
+    fail("failed to open '%s'\n", input);
+  const char *seed = capable(CAN_ENV | CAN_RANDOM) ? getenv("SLANG_SEED") : NULL;
+  if (seed) {
+    seeded = 1;
+    state = strtoull(seed, NULL, 10);
+  }
+}
+
+#ifdef SLANG_JIT
+// runs code compiled in memory, returning its result, or setting 'error' to
+// what went wrong if it fails (programs that spawn threads aren't compiled this
+// way, so everything happens on the calling thread)
+int64_t slang_jit_run(slang_ptr (*entry)(), const slang_function *functions,
+                      int64_t allowed, const char **error) {
+  slang_functions = functions;
+  capabilities = allowed;
+  main_thread = pthread_self();
+  threads = 1;
+  waiting = 0;
+  main_waiting = NULL;
+  // a run that failed can leave the handlers of the 'try's it was in behind
+  handlers = NULL;
+  // the host's own crash handlers (and the stack they run on) are put back
+  // afterwards
+  struct sigaction segv, fpe;
+  stack_t stack;
+  sigaction(SIGSEGV, NULL, &segv);
+  sigaction(SIGFPE, NULL, &fpe);
+  sigaltstack(NULL, &stack);
+  slang_ptr result = (slang_ptr)(int64_t)0;
+  *error = NULL;
+  if (sigsetjmp(failed, 1) == 0) {
+    report_crashes();
+    open_input();
+    stack_top = (uintptr_t)__builtin_frame_address(0);
+    result = entry();
+  } else {
+    *error = failure;
+    // a failure can jump out while '?' or a channel holds its lock (and
+    // there's only one thread, so nothing else can be holding them)
+    pthread_mutex_trylock(&input);
+    pthread_mutex_unlock(&input);
+    pthread_mutex_trylock(&channels);
+    pthread_mutex_unlock(&channels);
+  }
+  fflush(stdout);
+  sigaction(SIGSEGV, &segv, NULL);
+  sigaction(SIGFPE, &fpe, NULL);
+  sigaltstack(&stack, NULL);
+  return *error ? 0 : from_slang(result);
+}
+#else
 int main() {
-  printf("%ld\n", entry());
+  main_thread = pthread_self();
+  report_crashes();
+  if (&slang_sanitise)
+    atexit(check_red_zones);
+  open_input();
+  stack_top = (uintptr_t)__builtin_frame_address(0);
+  // the runtime's own settings are only read if the program can read them too
+  int env = capable(CAN_ENV);
+  const char *stats = env ? getenv("SLANG_STATS") : NULL;
+  if (stats && strcmp(stats, "0") != 0)
+    atexit(report);
+  const char *dump = env ? getenv("SLANG_HEAP_DUMP") : NULL;
+  if (dump && strcmp(dump, "0") != 0) {
+    tracking = 1;
+    dump_on_request();
+  }
+  const char *verify = env ? getenv("SLANG_HEAP_VERIFY") : NULL;
+  if (verify && strcmp(verify, "0") != 0)
+    tracking = verifying = 1;
+  slang_ptr result = entry();
+  printf("%ld\n", from_slang(result));
+  if (verifying) {
+    pthread_mutex_lock(&heap);
+    verify_heap();
+    pthread_mutex_unlock(&heap);
+  }
+  // when the program finishes, only its result is still reachable
+  if (dump && strcmp(dump, "0") != 0) {
+    pthread_mutex_lock(&heap);
+    dump_heap((const uintptr_t *)&result, 1);
+    pthread_mutex_unlock(&heap);
+  }
   return 0;
 }
+#endif
@@ -0,0 +1,618 @@
+use super::frontend::{self, ast, quote};
+use super::interp::{Input, Interpreter, Limits};
+use super::{backend, ir, opt, read, vm, write, CompilerOptions, Value, STACK_SIZE};
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::iter::Peekable;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::str::Chars;
+use std::thread;
+
+// how many programs the daemon keeps checked and optimised between requests
+const CACHE_SIZE: usize = 64;
+
+// how deeply arrays and objects can be nested in a request (which none needs
+// to be more than a few deep), so that reading one can't run out of stack
+const MAX_NESTING: usize = 128;
+
+// the error codes JSON-RPC reserves for requests it can't make sense of
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// as much of JSON as requests are written in. Numbers are kept as they're
+// written, so that integers don't lose any of their bits
+enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn render(&self) -> String {
+        match *self {
+            Json::Null => "null".to_string(),
+            Json::Bool(b) => format!("{}", b),
+            Json::Number(ref number) => number.clone(),
+            Json::Str(ref string) => escape(string),
+            Json::Array(ref elements) => format!(
+                "[{}]",
+                elements
+                    .iter()
+                    .map(Json::render)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Json::Object(ref members) => format!(
+                "{{{}}}",
+                members
+                    .iter()
+                    .map(|(name, value)| format!("{}:{}", escape(name), value.render()))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        }
+    }
+}
+
+// a string as JSON writes it ('quote' leaves control characters other than
+// newlines and tabs as they are, which JSON doesn't allow)
+fn escape(string: &str) -> String {
+    let mut escaped = String::new();
+    for c in quote(string).chars() {
+        match c {
+            '\r' => escaped.push_str("\\r"),
+            c if c.is_control() && c != '\n' && c != '\t' => {
+                escaped.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// a message without the escape codes that colour it on a terminal, which an
+// editor would only show as they are
+fn plain(message: &str) -> String {
+    let mut plain = String::new();
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // an escape code runs up to (and including) its first letter
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+struct Reader<'a> {
+    chars: Peekable<Chars<'a>>,
+    // how many arrays and objects the next value is inside, and whether
+    // reading stopped because there were too many
+    depth: usize,
+    too_deep: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn skip_whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some(c) if c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, word: &str) -> Option<()> {
+        for expected in word.chars() {
+            if self.chars.next() != Some(expected) {
+                return None;
+            }
+        }
+        Some(())
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let mut string = String::new();
+        loop {
+            match self.chars.next()? {
+                '"' => return Some(string),
+                '\\' => match self.chars.next()? {
+                    '"' => string.push('"'),
+                    '\\' => string.push('\\'),
+                    '/' => string.push('/'),
+                    'b' => string.push('\x08'),
+                    'f' => string.push('\x0c'),
+                    'n' => string.push('\n'),
+                    'r' => string.push('\r'),
+                    't' => string.push('\t'),
+                    'u' => {
+                        let digits = (0..4)
+                            .map(|_| self.chars.next())
+                            .collect::<Option<String>>()?;
+                        let code = u32::from_str_radix(&digits, 16).ok()?;
+                        // characters outside the basic plane (written as
+                        // surrogate pairs) aren't needed by any request
+                        string.push(char::from_u32(code)?);
+                    }
+                    _ => return None,
+                },
+                c if c.is_control() => return None,
+                c => string.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_whitespace();
+        if matches!(self.chars.peek(), Some('[' | '{')) && self.depth == MAX_NESTING {
+            self.too_deep = true;
+            return None;
+        }
+        match *self.chars.peek()? {
+            'n' => self.expect("null").map(|_| Json::Null),
+            't' => self.expect("true").map(|_| Json::Bool(true)),
+            'f' => self.expect("false").map(|_| Json::Bool(false)),
+            '"' => {
+                self.chars.next();
+                self.string().map(Json::Str)
+            }
+            '[' => {
+                self.chars.next();
+                let mut elements = vec![];
+                self.skip_whitespace();
+                if self.chars.peek() == Some(&']') {
+                    self.chars.next();
+                    return Some(Json::Array(elements));
+                }
+                self.depth += 1;
+                loop {
+                    elements.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.chars.next()? {
+                        ',' => continue,
+                        ']' => {
+                            self.depth -= 1;
+                            return Some(Json::Array(elements));
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            '{' => {
+                self.chars.next();
+                let mut members = vec![];
+                self.skip_whitespace();
+                if self.chars.peek() == Some(&'}') {
+                    self.chars.next();
+                    return Some(Json::Object(members));
+                }
+                self.depth += 1;
+                loop {
+                    self.skip_whitespace();
+                    self.expect("\"")?;
+                    let name = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    members.push((name, self.value()?));
+                    self.skip_whitespace();
+                    match self.chars.next()? {
+                        ',' => continue,
+                        '}' => {
+                            self.depth -= 1;
+                            return Some(Json::Object(members));
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if c.is_ascii_digit() || "+-.eE".contains(c) {
+                        number.push(c);
+                        self.chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                number.parse::<f64>().ok().map(|_| Json::Number(number))
+            }
+            _ => None,
+        }
+    }
+}
+
+// a request, or why it couldn't be read
+fn parse(text: &str) -> Result<Json, String> {
+    let mut reader = Reader {
+        chars: text.chars().peekable(),
+        depth: 0,
+        too_deep: false,
+    };
+    let value = reader.value();
+    reader.skip_whitespace();
+    match (value, reader.chars.next()) {
+        (Some(value), None) => Ok(value),
+        _ if reader.too_deep => Err(format!("JSON nested more than {} deep", MAX_NESTING)),
+        _ => Err("invalid JSON".to_string()),
+    }
+}
+
+fn strings(strings: &[String]) -> Json {
+    Json::Array(strings.iter().map(|s| Json::Str(plain(s))).collect())
+}
+
+// why a request couldn't be answered
+struct Failure {
+    code: i64,
+    message: String,
+}
+
+fn invalid(message: &str) -> Failure {
+    Failure {
+        code: INVALID_PARAMS,
+        message: message.to_string(),
+    }
+}
+
+// what a program has been turned into by the time it's run or compiled,
+// along with the warnings checking it gave (or why it couldn't be checked)
+type Checked = Result<(ast::Expr, Vec<String>), String>;
+
+struct Daemon {
+    options: CompilerOptions,
+    // programs by their names and text, with the names of the most recently
+    // checked last
+    cache: HashMap<(String, String), Checked>,
+    order: VecDeque<(String, String)>,
+    stopped: bool,
+}
+
+impl Daemon {
+    // the name and text of the program a request is about, which is either
+    // given as it is or read from a file
+    fn program(&self, params: &Json) -> Result<(String, String), Failure> {
+        match (params.get("source"), params.get("path")) {
+            (Some(Json::Str(ref source)), None) => {
+                let name = match params.get("name") {
+                    Some(Json::Str(ref name)) => name.clone(),
+                    None => "<source>".to_string(),
+                    Some(_) => return Err(invalid("'name' should be a string")),
+                };
+                Ok((name, source.clone()))
+            }
+            (None, Some(Json::Str(ref path))) => {
+                let path = Path::new(path);
+                match read(path) {
+                    Ok(text) => Ok((format!("{}", path.display()), text)),
+                    Err(err) => Err(invalid(&plain(&err))),
+                }
+            }
+            _ => Err(invalid("expected one of 'source' or 'path' (as a string)")),
+        }
+    }
+
+    // checks and optimises a program, unless it's been seen (with the same
+    // name and text) recently, saying which
+    fn check(&mut self, name: String, text: String) -> (&Checked, bool) {
+        let key = (name, text);
+        let cached = self.cache.contains_key(&key);
+        if cached {
+            self.order.retain(|other| *other != key);
+        } else {
+            if self.order.len() == CACHE_SIZE {
+                let oldest = self.order.pop_front().unwrap();
+                self.cache.remove(&oldest);
+            }
+            let checked = frontend::frontend(&key.0, key.1.clone(), &self.options)
+                .map(|(ast, warnings)| (opt::optimise(ast, &self.options), warnings));
+            self.cache.insert(key.clone(), checked);
+        }
+        self.order.push_back(key.clone());
+        (&self.cache[&key], cached)
+    }
+
+    fn compile(&mut self, params: &Json) -> Result<Json, Failure> {
+        let (name, text) = self.program(params)?;
+        let target = match params.get("target") {
+            Some(Json::Str(ref target)) => target.clone(),
+            None => "x86".to_string(),
+            Some(_) => return Err(invalid("'target' should be a string")),
+        };
+        let output = match params.get("output") {
+            Some(Json::Str(ref output)) => Some(output.clone()),
+            None => None,
+            Some(_) => return Err(invalid("'output' should be a string")),
+        };
+        if target == "wasm" && output.is_none() {
+            return Err(invalid(
+                "a WebAssembly module has to be written to an 'output'",
+            ));
+        }
+        let (ast, warnings, cached) = match self.check(name, text) {
+            (Ok((ast, warnings)), cached) => (ast.clone(), warnings.clone(), cached),
+            (Err(err), cached) => return Ok(failed(err, cached)),
+        };
+        let options = &self.options;
+        let generated = match target.as_str() {
+            "x86" => Ok(backend::generate(ir::lower(ast), options)
+                .to_string()
+                .into_bytes()),
+            "c" => backend::c::generate(ir::lower(ast), options).map(String::into_bytes),
+            "wasm" => backend::wasm::generate(ir::lower(ast), options),
+            _ => return Err(invalid("'target' should be one of 'x86', 'c' or 'wasm'")),
+        };
+        let code = match (generated, output) {
+            (Err(err), _) => return Ok(failed(&err, cached)),
+            (Ok(code), Some(output)) => match write(Path::new(&output), &code) {
+                Ok(()) => Json::Null,
+                Err(err) => return Ok(failed(&err, cached)),
+            },
+            (Ok(code), None) => Json::Str(String::from_utf8(code).unwrap()),
+        };
+        Ok(Json::Object(vec![
+            ("success".to_string(), Json::Bool(true)),
+            ("warnings".to_string(), strings(&warnings)),
+            ("code".to_string(), code),
+            ("cached".to_string(), Json::Bool(cached)),
+        ]))
+    }
+
+    fn run(&mut self, params: &Json) -> Result<Json, Failure> {
+        let (name, text) = self.program(params)?;
+        // the daemon's stdin may be where requests come from, so '?' never
+        // reads it
+        let input = match (params.get("input"), params.get("seed")) {
+            (Some(Json::Array(ref numbers)), None) => Input::Script(
+                numbers
+                    .iter()
+                    .map(|number| match *number {
+                        Json::Number(ref number) => number.parse::<i64>().ok(),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| invalid("'input' should be an array of integers"))?,
+            ),
+            (None, Some(Json::Number(ref seed))) => match seed.parse::<u64>() {
+                Ok(seed) => Input::Random(seed),
+                Err(_) => return Err(invalid("'seed' should be a non-negative integer")),
+            },
+            (None, None) => Input::Script(VecDeque::new()),
+            _ => return Err(invalid("expected at most one of 'input' or 'seed'")),
+        };
+        let steps = match params.get("steps") {
+            Some(Json::Number(ref steps)) => match steps.parse::<usize>() {
+                Ok(steps) => Some(steps),
+                Err(_) => return Err(invalid("'steps' should be a non-negative integer")),
+            },
+            None => None,
+            Some(_) => return Err(invalid("'steps' should be a non-negative integer")),
+        };
+        let on_vm = match params.get("engine") {
+            Some(Json::Str(ref engine)) if engine == "interpreter" => false,
+            Some(Json::Str(ref engine)) if engine == "vm" => true,
+            None => false,
+            Some(_) => return Err(invalid("'engine' should be one of 'interpreter' or 'vm'")),
+        };
+        if on_vm && steps.is_some() {
+            return Err(invalid("'steps' can only be given to the interpreter"));
+        }
+        let (width, schedule) = (self.options.int_width, self.options.schedule);
+        let (ast, warnings, cached) = match self.check(name, text) {
+            (Ok((ast, warnings)), cached) => (ast.clone(), warnings.clone(), cached),
+            (Err(err), cached) => return Ok(failed(err, cached)),
+        };
+        // as when grading, a program that recurses without end fails rather
+        // than taking the daemon down with it
+        let limits = Limits { steps, depth: None };
+        let (value, printed) = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                if on_vm {
+                    let bytecode = vm::compile(&ast);
+                    let mut machine = vm::Machine::new(&bytecode, input, width);
+                    if let Some(seed) = schedule {
+                        machine.schedule(seed);
+                    }
+                    machine.capture_output();
+                    let value = machine.run().map(|value| machine.reflect(&value));
+                    (value, machine.output())
+                } else {
+                    let mut interpreter = Interpreter::new(input, limits, width);
+                    if let Some(seed) = schedule {
+                        interpreter.schedule(seed);
+                    }
+                    interpreter.capture_output();
+                    let value = interpreter
+                        .run(&None, &ast)
+                        .map(|value| Value::from(&value));
+                    (value, interpreter.output())
+                }
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        let mut members = match value {
+            Ok(value) => vec![
+                ("success".to_string(), Json::Bool(true)),
+                ("value".to_string(), Json::Str(format!("{}", value))),
+            ],
+            Err(err) => vec![
+                ("success".to_string(), Json::Bool(false)),
+                ("error".to_string(), Json::Str(plain(&err))),
+            ],
+        };
+        members.push(("warnings".to_string(), strings(&warnings)));
+        members.push(("output".to_string(), Json::Str(printed)));
+        members.push(("cached".to_string(), Json::Bool(cached)));
+        Ok(Json::Object(members))
+    }
+
+    fn call(&mut self, method: &str, params: &Json) -> Result<Json, Failure> {
+        match method {
+            "check" => {
+                let (name, text) = self.program(params)?;
+                Ok(match self.check(name, text) {
+                    (Ok((_, warnings)), cached) => Json::Object(vec![
+                        ("success".to_string(), Json::Bool(true)),
+                        ("warnings".to_string(), strings(warnings)),
+                        ("cached".to_string(), Json::Bool(cached)),
+                    ]),
+                    (Err(err), cached) => failed(err, cached),
+                })
+            }
+            "compile" => self.compile(params),
+            "run" => self.run(params),
+            "shutdown" => {
+                self.stopped = true;
+                Ok(Json::Null)
+            }
+            _ => Err(Failure {
+                code: METHOD_NOT_FOUND,
+                message: format!("no method named '{}'", method),
+            }),
+        }
+    }
+
+    // answers a line of a request, unless it's a notification (which is
+    // never answered)
+    fn answer(&mut self, line: &str) -> Option<String> {
+        let request = match parse(line) {
+            Ok(request) => request,
+            Err(message) => {
+                return Some(response(
+                    Json::Null,
+                    Err(Failure {
+                        code: PARSE_ERROR,
+                        message,
+                    }),
+                ))
+            }
+        };
+        let id = request.get("id").map(|id| match *id {
+            Json::Number(ref number) => Json::Number(number.clone()),
+            Json::Str(ref string) => Json::Str(string.clone()),
+            _ => Json::Null,
+        });
+        let empty = Json::Object(vec![]);
+        let result = match (request.get("method"), request.get("params")) {
+            (Some(Json::Str(ref method)), None) => self.call(method, &empty),
+            (Some(Json::Str(ref method)), Some(params @ Json::Object(_))) => {
+                self.call(method, params)
+            }
+            _ => Err(Failure {
+                code: INVALID_REQUEST,
+                message: "expected a 'method' (and any 'params' as an object)".to_string(),
+            }),
+        };
+        id.map(|id| response(id, result))
+    }
+
+    fn serve<R: BufRead, W: Write>(&mut self, requests: R, mut responses: W) -> io::Result<()> {
+        for line in requests.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.answer(&line) {
+                writeln!(responses, "{}", response)?;
+                responses.flush()?;
+            }
+            if self.stopped {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// what a request about a program that couldn't be checked or compiled gives
+fn failed(err: &str, cached: bool) -> Json {
+    Json::Object(vec![
+        ("success".to_string(), Json::Bool(false)),
+        ("error".to_string(), Json::Str(plain(err))),
+        ("cached".to_string(), Json::Bool(cached)),
+    ])
+}
+
+fn response(id: Json, result: Result<Json, Failure>) -> String {
+    let outcome = match result {
+        Ok(result) => ("result".to_string(), result),
+        Err(failure) => (
+            "error".to_string(),
+            Json::Object(vec![
+                (
+                    "code".to_string(),
+                    Json::Number(format!("{}", failure.code)),
+                ),
+                ("message".to_string(), Json::Str(failure.message)),
+            ]),
+        ),
+    };
+    Json::Object(vec![
+        ("jsonrpc".to_string(), Json::Str("2.0".to_string())),
+        ("id".to_string(), id),
+        outcome,
+    ])
+    .render()
+}
+
+// answers JSON-RPC requests (one to a line) to check, compile and run
+// programs, on stdin or on the connections made to a Unix socket (one at a
+// time), until asked to shut down. Programs are kept checked and optimised
+// between requests, so asking about one again (as an editor does on every
+// keystroke) only costs what's done after that
+pub fn daemon(options: CompilerOptions, socket: Option<&Path>) -> Result<(), String> {
+    let mut daemon = Daemon {
+        options,
+        cache: HashMap::new(),
+        order: VecDeque::new(),
+        stopped: false,
+    };
+    let socket = match socket {
+        Some(socket) => socket,
+        None => {
+            let stdin = io::stdin();
+            return daemon
+                .serve(stdin.lock(), io::stdout())
+                .map_err(|err| format!("failed to serve requests on stdin ({})", err));
+        }
+    };
+    let listener = UnixListener::bind(socket)
+        .map_err(|err| format!("failed to listen on '{}' ({})", socket.display(), err))?;
+    for stream in listener.incoming() {
+        // a client that goes away mid-request doesn't stop the daemon
+        if let Ok(stream) = stream {
+            if let Ok(requests) = stream.try_clone() {
+                let _ = daemon.serve(BufReader::new(requests), stream);
+            }
+        }
+        if daemon.stopped {
+            break;
+        }
+    }
+    let _ = fs::remove_file(socket);
+    Ok(())
+}
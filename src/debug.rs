@@ -0,0 +1,153 @@
+use super::frontend;
+use super::interp::{Debugger, Input, Reference};
+use super::{read, CompilerOptions};
+
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use termion::{color, style};
+
+// how many steps 'back' can undo
+const HISTORY: usize = 10_000;
+
+fn error(message: String) -> String {
+    format!(
+        "{}{}error{}{}: {}",
+        style::Bold,
+        color::Fg(color::Red),
+        color::Fg(color::Reset),
+        style::Reset,
+        message
+    )
+}
+
+fn note(message: String) -> String {
+    format!(
+        "{}{}note{}{}: {}",
+        style::Bold,
+        color::Fg(color::Magenta),
+        color::Fg(color::Reset),
+        style::Reset,
+        message
+    )
+}
+
+// how many times to repeat a command ('1' if it isn't given)
+fn count(arg: &str) -> Result<usize, String> {
+    if arg.is_empty() {
+        return Ok(1);
+    }
+    arg.parse::<usize>()
+        .map_err(|_| error(format!("invalid number of steps '{}'", arg)))
+}
+
+// takes up to 'n' steps, stopping early if the program finishes or fails
+// ('None' for as many as it takes), then shows where it got to
+fn forward(debugger: &mut Debugger, n: Option<usize>) -> Result<(), String> {
+    let mut rule = None;
+    let mut taken = 0;
+    while taken < n.unwrap_or(usize::MAX) && debugger.value().is_none() {
+        match debugger.step() {
+            Ok(next) => rule = next,
+            Err(err) => {
+                show(debugger, None);
+                return Err(err);
+            }
+        }
+        taken += 1;
+    }
+    show(debugger, rule);
+    Ok(())
+}
+
+fn show(debugger: &Debugger, rule: Option<&str>) {
+    match rule {
+        Some(rule) => println!("[{}] {}", debugger.steps(), rule),
+        None => println!("[{}]", debugger.steps()),
+    }
+    print!("{}", debugger);
+    if let Some(value) = debugger.value() {
+        println!(
+            "{}",
+            note(format!(
+                "the program finished after {} steps with '{}{}{}'",
+                debugger.steps(),
+                style::Bold,
+                value,
+                style::Reset
+            ))
+        );
+    }
+}
+
+// runs a command, returning whether to carry on
+fn command(debugger: &mut Debugger, line: &str) -> Result<bool, String> {
+    let (command, arg) = match line.split_once(char::is_whitespace) {
+        Some((command, arg)) => (command, arg.trim()),
+        None => (line, ""),
+    };
+    match (command, arg) {
+        ("quit", "") | ("q", "") => return Ok(false),
+        ("step", n) | ("s", n) => forward(debugger, Some(count(n)?))?,
+        ("continue", "") | ("c", "") => forward(debugger, None)?,
+        ("back", n) | ("b", n) => {
+            for _ in 0..count(n)? {
+                if !debugger.back() {
+                    show(debugger, None);
+                    return Err(error("no earlier steps are remembered".to_string()));
+                }
+            }
+            show(debugger, None);
+        }
+        ("help", "") => {
+            println!("commands:");
+            println!("  step [N], s   take N steps (one if N isn't given)");
+            println!("  back [N], b   undo the last N steps");
+            println!("  continue, c   run until the program finishes");
+            println!("  help          display this information");
+            println!("  quit, q       leave the debugger");
+        }
+        _ => {
+            return Err(error(format!(
+                "unrecognised command '{}' (see 'help' for usage)",
+                line
+            )))
+        }
+    }
+    Ok(true)
+}
+
+// steps through a program by the small-step rules of the language, showing the
+// program as it's rewritten (an empty line takes another step)
+pub fn debug(input: &Path, what: Input, options: &CompilerOptions) -> Result<(), String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    for warning in warnings.iter() {
+        println!("{}", warning);
+    }
+    let mut reference = Reference::new(what, options.int_width);
+    if let Some(seed) = options.schedule {
+        reference.schedule(seed);
+    }
+    let mut debugger = Debugger::new(reference, &ast, HISTORY);
+    show(&debugger, None);
+    let stdin = io::stdin();
+    loop {
+        print!("(debug) ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+        let line = match line.trim() {
+            "" => "step",
+            line => line,
+        };
+        match command(&mut debugger, line) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(err) => println!("{}", err),
+        }
+    }
+    Ok(())
+}
@@ -1,16 +1,32 @@
-use super::past;
-use std::collections::HashSet;
+use super::types::TypeExpr;
+use super::{past, Locatable, Location};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum BinOp {
     Add,
     Mul,
     Div,
+    Mod,
     Sub,
     Lt,
     And,
     Or,
     Eq,
+    PhysEq,
+    Concat,
+    FloatAdd,
+    FloatSub,
+    FloatMul,
+    FloatDiv,
+    // '<' and '=' on floats (which is what they're lowered to, so these never
+    // appear in a program either)
+    FloatLt,
+    FloatEq,
+    // compares two strings, giving -1, 0 or 1 (which is what '=' and '<' on
+    // strings are lowered to, so it never appears in a program)
+    Compare,
 }
 
 impl fmt::Display for BinOp {
@@ -20,15 +36,26 @@ impl fmt::Display for BinOp {
             Add => write!(f, "+"),
             Mul => write!(f, "*"),
             Div => write!(f, "/"),
+            Mod => write!(f, "%"),
             Sub => write!(f, "-"),
             Lt => write!(f, "<"),
             And => write!(f, "&&"),
             Or => write!(f, "||"),
             Eq => write!(f, "="),
+            PhysEq => write!(f, "=="),
+            Concat => write!(f, "^"),
+            FloatAdd => write!(f, "+."),
+            FloatSub => write!(f, "-."),
+            FloatMul => write!(f, "*."),
+            FloatDiv => write!(f, "/."),
+            FloatLt => write!(f, "<."),
+            FloatEq => write!(f, "=."),
+            Compare => write!(f, "compare"),
         }
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum UnOp {
     Neg,
     Not,
@@ -44,6 +71,33 @@ impl fmt::Display for UnOp {
     }
 }
 
+// what the attributes on a function ('[@inline]', '[@noinline]' and
+// '[@optimize "size"]') ask of the optimiser
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attributes {
+    // whether calls to the function should always be unfolded ('Some(true)') or
+    // never are ('Some(false)'), if it says either
+    pub inline: Option<bool>,
+    // whether the function's body should be kept small, so that nothing in it
+    // is unfolded or unrolled
+    pub size: bool,
+}
+
+impl fmt::Display for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut attributes = vec![];
+        match self.inline {
+            Some(true) => attributes.push("[@inline]"),
+            Some(false) => attributes.push("[@noinline]"),
+            None => (),
+        }
+        if self.size {
+            attributes.push("[@optimize \"size\"]");
+        }
+        write!(f, "{}", attributes.join(" "))
+    }
+}
+
 pub trait Free {
     fn fv(&self) -> HashSet<&Var>;
 }
@@ -62,37 +116,287 @@ impl Free for Lambda {
     }
 }
 
+#[derive(Clone)]
 pub enum Expr {
     Unit,
     What,
     Var(Var),
     Int(i64),
+    Float(f64),
     Bool(bool),
+    // a string literal makes a new string each time it's run, so two of them
+    // are never '=='
+    Str(String),
     UnOp(UnOp, Box<Expr>),
     BinOp(BinOp, Box<Expr>, Box<Expr>),
+    // division can trap at runtime, so it keeps the location to report (as
+    // does taking a remainder)
+    Div(Location, Box<Expr>, Box<Expr>),
+    Mod(Location, Box<Expr>, Box<Expr>),
     If(Box<Expr>, Box<Expr>, Box<Expr>),
     Pair(Box<Expr>, Box<Expr>),
     Fst(Box<Expr>),
     Snd(Box<Expr>),
-    Inl(Box<Expr>),
-    Inr(Box<Expr>),
+    // injections keep the type of the other side of the union, so that their
+    // values can be printed
+    Inl(Box<Expr>, TypeExpr),
+    Inr(Box<Expr>, TypeExpr),
     Case(Box<Expr>, Lambda, Lambda),
     While(Box<Expr>, Box<Expr>),
+    // 'for i = a to b do e end', with the body run for each 'i'
+    For(Box<Expr>, Box<Expr>, Lambda),
     Seq(Vec<Expr>),
     Ref(Box<Expr>),
     Deref(Box<Expr>),
+    Lazy(Box<Expr>),
+    // forcing a lazy value while it's being computed traps at runtime, so this
+    // keeps the location to report too
+    Force(Location, Box<Expr>),
+    Chan,
+    Spawn(Box<Expr>),
+    // an exception that isn't handled stops the program, so this keeps the
+    // location to report
+    Raise(Location, Box<Expr>),
+    // 'try e with x -> e end'
+    Try(Box<Expr>, Lambda),
+    // a program that deadlocks fails where its main thread is waiting, so
+    // these keep their locations too
+    Send(Location, Box<Expr>, Box<Expr>),
+    Recv(Location, Box<Expr>),
+    Print(Box<Expr>),
+    // a condition, with which way it's likely to go (which only changes how
+    // an 'if' it's the condition of is laid out)
+    Expect(Box<Expr>, bool),
+    // the body of a function with attributes (beneath all of its parameters),
+    // which is run as it is
+    Attributed(Attributes, Box<Expr>),
     Assign(Box<Expr>, Box<Expr>),
+    // making an array of a negative length traps at runtime, as does reading
+    // or writing past either end of one, so these keep the location to report
+    MakeArray(Location, Box<Expr>, Box<Expr>),
+    Index(Location, Box<Expr>, Box<Expr>),
+    Update(Location, Box<Expr>, Box<Expr>, Box<Expr>),
+    Nil,
+    Cons(Box<Expr>, Box<Expr>),
+    // taking the head or tail of an empty list traps at runtime, so these keep
+    // the location to report too
+    Head(Location, Box<Expr>),
+    Tail(Location, Box<Expr>),
+    IsEmpty(Box<Expr>),
     Lambda(Lambda),
     App(Box<Expr>, Box<Expr>),
     Let(Var, Box<Expr>, Box<Expr>),
     LetFun(Var, Lambda, Box<Expr>),
+    // functions that can each call the others, all of which are in scope in
+    // each of their bodies
+    LetRec(Vec<(Var, Lambda)>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn children(&self) -> Vec<&Expr> {
+        use self::Expr::*;
+        match *self {
+            Unit | What | Var(_) | Int(_) | Float(_) | Bool(_) | Str(_) | Chan | Nil => vec![],
+            UnOp(_, ref sub)
+            | Fst(ref sub)
+            | Snd(ref sub)
+            | Inl(ref sub, _)
+            | Inr(ref sub, _)
+            | Lambda((_, ref sub))
+            | Ref(ref sub)
+            | Deref(ref sub)
+            | Lazy(ref sub)
+            | Force(_, ref sub)
+            | Spawn(ref sub)
+            | Raise(_, ref sub)
+            | Recv(_, ref sub)
+            | Head(_, ref sub)
+            | Tail(_, ref sub)
+            | IsEmpty(ref sub)
+            | Print(ref sub)
+            | Expect(ref sub, _)
+            | Attributed(_, ref sub) => vec![sub],
+            BinOp(_, ref left, ref right)
+            | Send(_, ref left, ref right)
+            | Div(_, ref left, ref right)
+            | Mod(_, ref left, ref right)
+            | Pair(ref left, ref right)
+            | While(ref left, ref right)
+            | Assign(ref left, ref right)
+            | MakeArray(_, ref left, ref right)
+            | Index(_, ref left, ref right)
+            | Cons(ref left, ref right)
+            | App(ref left, ref right)
+            | Let(_, ref left, ref right)
+            | LetFun(_, (_, ref left), ref right)
+            | Try(ref left, (_, ref right)) => vec![left, right],
+            If(ref condition, ref left, ref right)
+            | Update(_, ref condition, ref left, ref right)
+            | For(ref condition, ref left, (_, ref right))
+            | Case(ref condition, (_, ref left), (_, ref right)) => vec![condition, left, right],
+            Seq(ref seq) => seq.iter().collect(),
+            LetRec(ref functions, ref body) => {
+                let mut children = functions
+                    .iter()
+                    .map(|(_, (_, lambda))| &**lambda)
+                    .collect::<Vec<_>>();
+                children.push(body);
+                children
+            }
+        }
+    }
+
+    // the attributes of the function this is the body of (which are on its
+    // body beneath all of its parameters)
+    pub fn attributes(&self) -> Attributes {
+        match *self {
+            Expr::Lambda((_, ref body)) => body.attributes(),
+            Expr::Attributed(attributes, _) => attributes,
+            _ => Attributes::default(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        let mut size = 0;
+        let mut work = vec![self];
+        while let Some(expr) = work.pop() {
+            size += 1;
+            work.extend(expr.children());
+        }
+        size
+    }
+}
+
+// an operator in a chain of them ('a + b + c'), which is all there is to a
+// node of one besides its operands
+pub enum Link {
+    Op(BinOp),
+    Div(Location),
+    Mod(Location),
+}
+
+impl Link {
+    pub fn join(self, left: Expr, right: Expr) -> Expr {
+        let (left, right) = (Box::new(left), Box::new(right));
+        match self {
+            Link::Op(op) => Expr::BinOp(op, left, right),
+            Link::Div(location) => Expr::Div(location, left, right),
+            Link::Mod(location) => Expr::Mod(location, left, right),
+        }
+    }
+}
+
+// takes a chain of operators apart into its leftmost operand and each operator
+// after it along with its right operand, in the order they're run. A chain can
+// be as long as a program is, so passes walk down one in a loop (with this or
+// 'operands') rather than by recursing
+pub fn unchain(expr: Expr) -> (Expr, Vec<(Link, Expr)>) {
+    let mut links = vec![];
+    let mut expr = expr;
+    loop {
+        let (link, left, right) = match expr {
+            Expr::BinOp(op, left, right) => (Link::Op(op), left, right),
+            Expr::Div(location, left, right) => (Link::Div(location), left, right),
+            Expr::Mod(location, left, right) => (Link::Mod(location), left, right),
+            expr => {
+                links.reverse();
+                return (expr, links);
+            }
+        };
+        links.push((link, *right));
+        expr = *left;
+    }
+}
+
+// the leftmost operand of a chain of operators, and each operator in it after
+// that (the innermost first), for passes that need more of each one than its
+// right operand
+pub fn chain(expr: &Expr) -> (&Expr, Vec<&Expr>) {
+    let mut operators = vec![];
+    let mut expr = expr;
+    while let Expr::BinOp(_, ref left, _) | Expr::Div(_, ref left, _) | Expr::Mod(_, ref left, _) =
+        *expr
+    {
+        operators.push(expr);
+        expr = left;
+    }
+    operators.reverse();
+    (expr, operators)
+}
+
+// the operands of a chain of operators, the leftmost first
+pub fn operands(expr: &Expr) -> Vec<&Expr> {
+    let mut operands = vec![];
+    let mut expr = expr;
+    while let Expr::BinOp(_, ref left, ref right)
+    | Expr::Div(_, ref left, ref right)
+    | Expr::Mod(_, ref left, ref right) = *expr
+    {
+        operands.push(&**right);
+        expr = left;
+    }
+    operands.push(expr);
+    operands.reverse();
+    operands
+}
+
+// what a 'let' defines, which is all there is to one besides what's in its
+// scope
+pub enum Definition {
+    Let(Var, Box<Expr>),
+    LetFun(Var, Lambda),
+    LetRec(Vec<(Var, Lambda)>),
+}
+
+impl Definition {
+    pub fn scope(self, body: Expr) -> Expr {
+        let body = Box::new(body);
+        match self {
+            Definition::Let(v, sub) => Expr::Let(v, sub, body),
+            Definition::LetFun(f, lambda) => Expr::LetFun(f, lambda, body),
+            Definition::LetRec(functions) => Expr::LetRec(functions, body),
+        }
+    }
+}
+
+// takes a chain of definitions ('let x = e in let y = e in ...') apart into
+// each definition, the outermost first, and what's in the scope of the last of
+// them. A program of top-level definitions is one chain, so passes walk down
+// one in a loop (with this or 'definitions') rather than by recursing
+pub fn undefine(expr: Expr) -> (Vec<Definition>, Expr) {
+    let mut definitions = vec![];
+    let mut expr = expr;
+    loop {
+        let (definition, body) = match expr {
+            Expr::Let(v, sub, body) => (Definition::Let(v, sub), body),
+            Expr::LetFun(f, lambda, body) => (Definition::LetFun(f, lambda), body),
+            Expr::LetRec(functions, body) => (Definition::LetRec(functions), body),
+            expr => return (definitions, expr),
+        };
+        definitions.push(definition);
+        expr = *body;
+    }
+}
+
+// each 'let' in a chain of definitions, the outermost first, and what's in the
+// scope of the last of them
+pub fn definitions(expr: &Expr) -> (Vec<&Expr>, &Expr) {
+    let mut definitions = vec![];
+    let mut expr = expr;
+    while let Expr::Let(_, _, ref body) | Expr::LetFun(_, _, ref body) | Expr::LetRec(_, ref body) =
+        *expr
+    {
+        definitions.push(expr);
+        expr = body;
+    }
+    (definitions, expr)
 }
 
 impl Free for Expr {
     fn fv(&self) -> HashSet<&Var> {
         use self::Expr::*;
         match *self {
-            Unit | What | Int(_) | Bool(_) => HashSet::new(),
+            Unit | What | Int(_) | Float(_) | Bool(_) | Str(_) | Chan | Nil => HashSet::new(),
             Var(ref v) => {
                 let mut fv = HashSet::new();
                 fv.insert(v);
@@ -101,99 +405,696 @@ impl Free for Expr {
             UnOp(_, ref sub)
             | Fst(ref sub)
             | Snd(ref sub)
-            | Inl(ref sub)
-            | Inr(ref sub)
+            | Inl(ref sub, _)
+            | Inr(ref sub, _)
             | Ref(ref sub)
-            | Deref(ref sub) => sub.fv(),
-            BinOp(_, ref left, ref right)
+            | Deref(ref sub)
+            | Lazy(ref sub)
+            | Force(_, ref sub)
+            | Spawn(ref sub)
+            | Raise(_, ref sub)
+            | Recv(_, ref sub)
+            | Head(_, ref sub)
+            | Tail(_, ref sub)
+            | IsEmpty(ref sub)
+            | Print(ref sub)
+            | Expect(ref sub, _)
+            | Attributed(_, ref sub) => sub.fv(),
+            BinOp(..) | Div(..) | Mod(..) => operands(self)
+                .into_iter()
+                .flat_map(|sub| sub.fv())
+                .collect(),
+            Send(_, ref left, ref right)
             | Pair(ref left, ref right)
             | Assign(ref left, ref right)
+            | MakeArray(_, ref left, ref right)
+            | Index(_, ref left, ref right)
             | While(ref left, ref right)
-            | App(ref left, ref right) => left.fv().union(&right.fv()).map(|x| *x).collect(),
-            If(ref condition, ref left, ref right) => condition
+            | Cons(ref left, ref right)
+            | App(ref left, ref right) => left.fv().union(&right.fv()).copied().collect(),
+            If(ref condition, ref left, ref right)
+            | Update(_, ref condition, ref left, ref right) => condition
                 .fv()
                 .union(&left.fv())
-                .map(|x| *x)
+                .copied()
                 .collect::<HashSet<_>>()
                 .union(&right.fv())
-                .map(|x| *x)
+                .copied()
                 .collect(),
             Seq(ref seq) => {
                 let mut fv = HashSet::new();
                 for sub in seq.iter() {
-                    fv = fv.union(&sub.fv()).map(|x| *x).collect()
+                    fv = fv.union(&sub.fv()).copied().collect()
                 }
                 fv
             }
             Lambda(ref lambda) => lambda.fv(),
-            Let(ref v, ref sub, ref body) => {
-                let mut fv = body.fv();
-                if fv.contains(&v) {
-                    fv.remove(&v);
-                }
-                fv.union(&sub.fv()).map(|x| *x).collect()
-            }
-            LetFun(ref v, ref lambda, ref body) => {
+            // a chain of definitions is walked back out from what's in the
+            // scope of the last, in a loop, as a program of top-level
+            // definitions is one
+            Let(..) | LetFun(..) | LetRec(..) => {
+                let (definitions, body) = definitions(self);
                 let mut fv = body.fv();
-                if fv.contains(&v) {
-                    fv.remove(&v);
+                for definition in definitions.into_iter().rev() {
+                    match *definition {
+                        Let(ref v, ref sub, _) => {
+                            fv.remove(v);
+                            fv.extend(sub.fv());
+                        }
+                        // the function is in scope in its own body, as well as
+                        // after it
+                        LetFun(ref v, ref lambda, _) => {
+                            fv.extend(lambda.fv());
+                            fv.remove(v);
+                        }
+                        LetRec(ref functions, _) => {
+                            for (_, lambda) in functions.iter() {
+                                fv.extend(lambda.fv());
+                            }
+                            for (f, _) in functions.iter() {
+                                fv.remove(f);
+                            }
+                        }
+                        _ => unreachable!(),
+                    }
                 }
-                fv.union(&lambda.fv()).map(|x| *x).collect()
+                fv
             }
             Case(ref sub, ref left, ref right) => sub
                 .fv()
                 .union(&left.fv())
-                .map(|x| *x)
+                .copied()
                 .collect::<HashSet<_>>()
                 .union(&right.fv())
-                .map(|x| *x)
+                .copied()
+                .collect(),
+            Try(ref sub, ref handler) => sub.fv().union(&handler.fv()).copied().collect(),
+            For(ref from, ref to, ref body) => from
+                .fv()
+                .union(&to.fv())
+                .copied()
+                .collect::<HashSet<_>>()
+                .union(&body.fv())
+                .copied()
                 .collect(),
         }
     }
 }
 
-impl<'a> From<past::SubExpr> for Box<Expr> {
-    fn from(sub: past::SubExpr) -> Box<Expr> {
-        Box::new(Expr::from(sub.into_raw()))
-    }
+// strings are compared by how 'Compare' orders them (by the first byte that
+// differs, or by their lengths if one starts with the other)
+fn compare(op: BinOp, left: Expr, right: Expr) -> Expr {
+    let ordering = Expr::BinOp(BinOp::Compare, Box::new(left), Box::new(right));
+    Expr::BinOp(op, Box::new(ordering), Box::new(Expr::Int(0)))
+}
+
+// an expression that first counts that it's run in 'probe' (if it has one)
+fn counted(probe: Option<Var>, expr: Expr) -> Expr {
+    use self::Expr::*;
+    let probe = match probe {
+        Some(probe) => probe,
+        None => return expr,
+    };
+    let count = BinOp(
+        self::BinOp::Add,
+        Box::new(Deref(Box::new(Var(probe.clone())))),
+        Box::new(Int(1)),
+    );
+    Seq(vec![Assign(Box::new(Var(probe)), Box::new(count)), expr])
 }
 
-impl<'a> From<past::Expr> for Expr {
-    fn from(past: past::Expr) -> Expr {
+// the reference that an instrumented program counts the runs of the 'n'th
+// location it was given in
+pub fn probe(n: usize) -> Var {
+    format!("%probe{}", n)
+}
+
+pub struct Lowering {
+    fresh: usize,
+    // the probe each line and column that has one so far was given (only
+    // when instrumenting)
+    probed: Option<HashMap<(usize, usize), usize>>,
+    probes: Vec<Location>,
+    // where the expression being lowered starts
+    enclosing: Option<(usize, usize)>,
+}
+
+impl Lowering {
+    pub fn new() -> Lowering {
+        Lowering {
+            fresh: 0,
+            probed: None,
+            probes: vec![],
+            enclosing: None,
+        }
+    }
+
+    // lowers expressions so that they count how many times they're run (see
+    // 'probe')
+    pub fn instrumented() -> Lowering {
+        Lowering {
+            probed: Some(HashMap::new()),
+            ..Lowering::new()
+        }
+    }
+
+    // where each probe added while lowering is
+    pub fn probes(self) -> Vec<Location> {
+        self.probes
+    }
+
+    // '%' can't appear in an identifier, so these never capture user variables
+    fn fresh(&mut self) -> Var {
+        self.fresh += 1;
+        format!("%{}", self.fresh)
+    }
+
+    fn lower_sub(&mut self, sub: Locatable<past::Expr>) -> Box<Expr> {
+        Box::new(self.lower(sub))
+    }
+
+    // lazy values are compared by what they compute, so comparing them forces
+    // them (which can trap at 'location')
+    fn equal(
+        &mut self,
+        location: &Location,
+        type_expr: &TypeExpr,
+        left: Expr,
+        right: Expr,
+    ) -> Expr {
+        use self::Expr::*;
+        match *type_expr {
+            // there's only one '()', so both sides are only run for their effects
+            // (and nothing is left unknown about a type unless no value of it is
+            // ever made, as with the elements of '[]')
+            TypeExpr::Unit | TypeExpr::Unknown(_) => Seq(vec![left, right, Bool(true)]),
+            TypeExpr::Bool | TypeExpr::Int => {
+                BinOp(self::BinOp::Eq, Box::new(left), Box::new(right))
+            }
+            TypeExpr::Float => BinOp(self::BinOp::FloatEq, Box::new(left), Box::new(right)),
+            TypeExpr::String => compare(self::BinOp::Eq, left, right),
+            // two channels are only equal if they're the same channel (and the
+            // same goes for arrays)
+            TypeExpr::Chan(_) | TypeExpr::Array(_) => {
+                BinOp(self::BinOp::PhysEq, Box::new(left), Box::new(right))
+            }
+            TypeExpr::Ref(ref sub) => {
+                self.equal(location, sub, Deref(Box::new(left)), Deref(Box::new(right)))
+            }
+            TypeExpr::Lazy(ref sub) => self.equal(
+                location,
+                sub,
+                Force(location.clone(), Box::new(left)),
+                Force(location.clone(), Box::new(right)),
+            ),
+            TypeExpr::Product(ref t1, ref t2) => {
+                let (l, r) = (self.fresh(), self.fresh());
+                let fst = self.equal(
+                    location,
+                    t1,
+                    Fst(Box::new(Var(l.clone()))),
+                    Fst(Box::new(Var(r.clone()))),
+                );
+                let snd = self.equal(
+                    location,
+                    t2,
+                    Snd(Box::new(Var(l.clone()))),
+                    Snd(Box::new(Var(r.clone()))),
+                );
+                Let(
+                    l,
+                    Box::new(left),
+                    Box::new(Let(
+                        r,
+                        Box::new(right),
+                        Box::new(BinOp(self::BinOp::And, Box::new(fst), Box::new(snd))),
+                    )),
+                )
+            }
+            TypeExpr::Union(ref t1, ref t2) => {
+                let (l, r) = (self.fresh(), self.fresh());
+                let (x, y) = (self.fresh(), self.fresh());
+                let inl = self.equal(location, t1, Var(x.clone()), Var(y.clone()));
+                let inl = Case(
+                    Box::new(Var(r.clone())),
+                    (y.clone(), Box::new(inl)),
+                    (y.clone(), Box::new(Bool(false))),
+                );
+                let inr = self.equal(location, t2, Var(x.clone()), Var(y.clone()));
+                let inr = Case(
+                    Box::new(Var(r.clone())),
+                    (y.clone(), Box::new(Bool(false))),
+                    (y, Box::new(inr)),
+                );
+                Let(
+                    l.clone(),
+                    Box::new(left),
+                    Box::new(Let(
+                        r,
+                        Box::new(right),
+                        Box::new(Case(
+                            Box::new(Var(l)),
+                            (x.clone(), Box::new(inl)),
+                            (x, Box::new(inr)),
+                        )),
+                    )),
+                )
+            }
+            // two lists are walked along together for as long as their heads
+            // are equal, and are equal if they run out together
+            TypeExpr::List(ref sub) => {
+                let (l, r, same) = (self.fresh(), self.fresh(), self.fresh());
+                let head = |v: &self::Var| {
+                    Head(location.clone(), Box::new(Deref(Box::new(Var(v.clone())))))
+                };
+                let advance = |v: &self::Var| {
+                    Assign(
+                        Box::new(Var(v.clone())),
+                        Box::new(Tail(
+                            location.clone(),
+                            Box::new(Deref(Box::new(Var(v.clone())))),
+                        )),
+                    )
+                };
+                let empty = |v: &self::Var| IsEmpty(Box::new(Deref(Box::new(Var(v.clone())))));
+                let stop = Assign(Box::new(Var(same.clone())), Box::new(Bool(false)));
+                let heads = self.equal(location, sub, head(&l), head(&r));
+                let step = If(
+                    Box::new(empty(&r)),
+                    Box::new(stop.clone()),
+                    Box::new(If(
+                        Box::new(heads),
+                        Box::new(Seq(vec![advance(&l), advance(&r)])),
+                        Box::new(stop),
+                    )),
+                );
+                let going = BinOp(
+                    self::BinOp::And,
+                    Box::new(Deref(Box::new(Var(same.clone())))),
+                    Box::new(UnOp(self::UnOp::Not, Box::new(empty(&l)))),
+                );
+                let result = BinOp(
+                    self::BinOp::And,
+                    Box::new(Deref(Box::new(Var(same.clone())))),
+                    Box::new(empty(&r)),
+                );
+                Let(
+                    l.clone(),
+                    Box::new(Ref(Box::new(left))),
+                    Box::new(Let(
+                        r,
+                        Box::new(Ref(Box::new(right))),
+                        Box::new(Let(
+                            same,
+                            Box::new(Ref(Box::new(Bool(true)))),
+                            Box::new(Seq(vec![While(Box::new(going), Box::new(step)), result])),
+                        )),
+                    )),
+                )
+            }
+            TypeExpr::Arrow(_, _)
+            | TypeExpr::Error
+            | TypeExpr::Hole
+            | TypeExpr::Named(_)
+            | TypeExpr::Variable(_)
+            | TypeExpr::Forall(_, _)
+            | TypeExpr::Record(_)
+            | TypeExpr::Tuple(_) => unreachable!(),
+        }
+    }
+
+    // whether one value comes before another, where 'false' comes before
+    // 'true', strings are ordered by their bytes (as 'strcmp' orders them), pairs are ordered by their first parts and then their second,
+    // every 'inl' comes before every 'inr' and lists are ordered by their
+    // elements in turn (with a list coming after any it starts with)
+    fn less(&mut self, location: &Location, type_expr: &TypeExpr, left: Expr, right: Expr) -> Expr {
         use self::Expr::*;
+        let (l, r) = (self.fresh(), self.fresh());
+        let less = match *type_expr {
+            TypeExpr::Unit | TypeExpr::Unknown(_) => return Seq(vec![left, right, Bool(false)]),
+            TypeExpr::Int => return BinOp(self::BinOp::Lt, Box::new(left), Box::new(right)),
+            TypeExpr::Float => return BinOp(self::BinOp::FloatLt, Box::new(left), Box::new(right)),
+            TypeExpr::String => return compare(self::BinOp::Lt, left, right),
+            TypeExpr::Bool => If(
+                Box::new(Var(l.clone())),
+                Box::new(Bool(false)),
+                Box::new(Var(r.clone())),
+            ),
+            TypeExpr::Product(ref t1, ref t2) => {
+                let fst_less = self.less(
+                    location,
+                    t1,
+                    Fst(Box::new(Var(l.clone()))),
+                    Fst(Box::new(Var(r.clone()))),
+                );
+                let fst_equal = self.equal(
+                    location,
+                    t1,
+                    Fst(Box::new(Var(l.clone()))),
+                    Fst(Box::new(Var(r.clone()))),
+                );
+                let snd_less = self.less(
+                    location,
+                    t2,
+                    Snd(Box::new(Var(l.clone()))),
+                    Snd(Box::new(Var(r.clone()))),
+                );
+                If(
+                    Box::new(fst_less),
+                    Box::new(Bool(true)),
+                    Box::new(If(
+                        Box::new(fst_equal),
+                        Box::new(snd_less),
+                        Box::new(Bool(false)),
+                    )),
+                )
+            }
+            TypeExpr::Union(ref t1, ref t2) => {
+                let (x, y) = (self.fresh(), self.fresh());
+                let inl = self.less(location, t1, Var(x.clone()), Var(y.clone()));
+                let inl = Case(
+                    Box::new(Var(r.clone())),
+                    (y.clone(), Box::new(inl)),
+                    (y.clone(), Box::new(Bool(true))),
+                );
+                let inr = self.less(location, t2, Var(x.clone()), Var(y.clone()));
+                let inr = Case(
+                    Box::new(Var(r.clone())),
+                    (y.clone(), Box::new(Bool(false))),
+                    (y, Box::new(inr)),
+                );
+                Case(
+                    Box::new(Var(l.clone())),
+                    (x.clone(), Box::new(inl)),
+                    (x, Box::new(inr)),
+                )
+            }
+            TypeExpr::List(ref sub) => {
+                let (result, going) = (self.fresh(), self.fresh());
+                let (x, y) = (self.fresh(), self.fresh());
+                let deref = |v: &self::Var| Deref(Box::new(Var(v.clone())));
+                let set = |v: &self::Var, b| Assign(Box::new(Var(v.clone())), Box::new(Bool(b)));
+                let advance = |v: &self::Var| {
+                    Assign(
+                        Box::new(Var(v.clone())),
+                        Box::new(Tail(location.clone(), Box::new(deref(v)))),
+                    )
+                };
+                let heads_less = self.less(location, sub, Var(x.clone()), Var(y.clone()));
+                let heads_equal = self.equal(location, sub, Var(x.clone()), Var(y.clone()));
+                let compare_heads = Let(
+                    x,
+                    Box::new(Head(location.clone(), Box::new(deref(&l)))),
+                    Box::new(Let(
+                        y,
+                        Box::new(Head(location.clone(), Box::new(deref(&r)))),
+                        Box::new(If(
+                            Box::new(heads_less),
+                            Box::new(Seq(vec![set(&result, true), set(&going, false)])),
+                            Box::new(If(
+                                Box::new(heads_equal),
+                                Box::new(Seq(vec![advance(&l), advance(&r)])),
+                                Box::new(set(&going, false)),
+                            )),
+                        )),
+                    )),
+                );
+                let step = If(
+                    Box::new(IsEmpty(Box::new(deref(&r)))),
+                    Box::new(set(&going, false)),
+                    Box::new(If(
+                        Box::new(IsEmpty(Box::new(deref(&l)))),
+                        Box::new(Seq(vec![set(&result, true), set(&going, false)])),
+                        Box::new(compare_heads),
+                    )),
+                );
+                let walk = Seq(vec![
+                    While(Box::new(deref(&going)), Box::new(step)),
+                    deref(&result),
+                ]);
+                return Let(
+                    l,
+                    Box::new(Ref(Box::new(left))),
+                    Box::new(Let(
+                        r,
+                        Box::new(Ref(Box::new(right))),
+                        Box::new(Let(
+                            result,
+                            Box::new(Ref(Box::new(Bool(false)))),
+                            Box::new(Let(
+                                going,
+                                Box::new(Ref(Box::new(Bool(true)))),
+                                Box::new(walk),
+                            )),
+                        )),
+                    )),
+                );
+            }
+            // nothing else can be compared with '<'
+            _ => unreachable!(),
+        };
+        Let(
+            l,
+            Box::new(left),
+            Box::new(Let(r, Box::new(right), Box::new(less))),
+        )
+    }
+
+    // gives each expression a probe, which is shared by every copy of it (as
+    // the branches of a 'match' and polymorphic values can be lowered more than
+    // once), unless it starts where the expression it's in does (as 'f' does in
+    // 'f x'), as that one is run whenever it is
+    fn probe(&mut self, location: &Location) -> Option<Var> {
+        let at = (location.line(), location.column());
+        if self.enclosing == Some(at) {
+            return None;
+        }
+        let probed = self.probed.as_mut()?;
+        let n = match probed.get(&at) {
+            Some(n) => *n,
+            None => {
+                probed.insert(at, self.probes.len());
+                self.probes.push(location.clone());
+                self.probes.len() - 1
+            }
+        };
+        Some(probe(n))
+    }
+
+    pub fn lower(&mut self, past: Locatable<past::Expr>) -> Expr {
+        match past.t {
+            past::Expr::BinOp(..) => return self.lower_chain(past),
+            past::Expr::Let(..) | past::Expr::LetFun(..) | past::Expr::LetRec(..) => {
+                return self.lower_definitions(past)
+            }
+            _ => (),
+        }
+        let probe = self.probe(past.location());
+        let enclosing = self
+            .enclosing
+            .replace((past.location().line(), past.location().column()));
+        let expr = self.lower_unprobed(past);
+        self.enclosing = enclosing;
+        counted(probe, expr)
+    }
+
+    // a chain of operators ('a + b + c') is lowered from its leftmost operand
+    // on, in a loop, as it can be too long to recurse down
+    fn lower_chain(&mut self, past: Locatable<past::Expr>) -> Expr {
+        let enclosing = self.enclosing;
+        let mut links = vec![];
+        let mut past = past;
+        loop {
+            match past.t {
+                past::Expr::BinOp(op, left, right) => {
+                    let probe = self.probe(&past.location);
+                    self.enclosing = Some((past.location.line(), past.location.column()));
+                    links.push((past.location, op, right, probe));
+                    past = *left;
+                }
+                t => {
+                    past.t = t;
+                    break;
+                }
+            }
+        }
+        let mut expr = self.lower(past);
+        for (location, op, right, probe) in links.into_iter().rev() {
+            self.enclosing = Some((location.line(), location.column()));
+            let (left, right) = (Box::new(expr), self.lower_sub(*right));
+            expr = counted(
+                probe,
+                match op {
+                    self::BinOp::Div => Expr::Div(location, left, right),
+                    self::BinOp::Mod => Expr::Mod(location, left, right),
+                    _ => Expr::BinOp(op, left, right),
+                },
+            );
+        }
+        self.enclosing = enclosing;
+        expr
+    }
+
+    // so is a chain of definitions ('let x = e in let y = e in ...'), from the
+    // outermost on, as a program of top-level definitions is one
+    fn lower_definitions(&mut self, past: Locatable<past::Expr>) -> Expr {
+        let enclosing = self.enclosing;
+        let mut definitions = vec![];
+        let mut past = past;
+        loop {
+            let probe = self.probe(&past.location);
+            self.enclosing = Some((past.location.line(), past.location.column()));
+            let (definition, body) = match past.t {
+                past::Expr::Let(v, _, sub, body) => {
+                    (Definition::Let(v, self.lower_sub(*sub)), body)
+                }
+                past::Expr::LetFun(f, (v, _, sub), _, body) => {
+                    (Definition::LetFun(f, (v, self.lower_sub(*sub))), body)
+                }
+                past::Expr::LetRec(functions, body) => {
+                    let functions = functions
+                        .into_iter()
+                        .map(|(f, (v, _, sub), _)| (f, (v, self.lower_sub(*sub))))
+                        .collect();
+                    (Definition::LetRec(functions), body)
+                }
+                _ => unreachable!(),
+            };
+            definitions.push((definition, probe));
+            match body.t {
+                past::Expr::Let(..) | past::Expr::LetFun(..) | past::Expr::LetRec(..) => {
+                    past = *body
+                }
+                _ => {
+                    past = *body;
+                    break;
+                }
+            }
+        }
+        let mut expr = self.lower(past);
+        for (definition, probe) in definitions.into_iter().rev() {
+            expr = counted(probe, definition.scope(expr));
+        }
+        self.enclosing = enclosing;
+        expr
+    }
+
+    fn lower_unprobed(&mut self, past: Locatable<past::Expr>) -> Expr {
+        use self::Expr::*;
+        let Locatable { location, t: past } = past;
         match past {
             past::Expr::Unit => Unit,
             past::Expr::What => What,
+            // programs with holes in them never type check
+            past::Expr::Hole(_) => unreachable!(),
             past::Expr::Var(v) => Var(v),
             past::Expr::Bool(b) => Bool(b),
             past::Expr::Int(i) => Int(i),
-            past::Expr::UnOp(op, sub) => UnOp(op.into(), sub.into()),
-            past::Expr::BinOp(op, left, right) => BinOp(op.into(), left.into(), right.into()),
-            past::Expr::If(condition, left, right) => {
-                If(condition.into(), left.into(), right.into())
-            }
-            past::Expr::Pair(left, right) => Pair(left.into(), right.into()),
-            past::Expr::Fst(sub) => Fst(sub.into()),
-            past::Expr::Snd(sub) => Snd(sub.into()),
-            past::Expr::Inl(sub, _) => Inl(sub.into()),
-            past::Expr::Inr(sub, _) => Inr(sub.into()),
-            past::Expr::Case(sub, (v_left, _, sub_left), (v_right, _, sub_right)) => Expr::Case(
-                sub.into(),
-                (v_left, sub_left.into()),
-                (v_right, sub_right.into()),
+            past::Expr::Float(f) => Float(f),
+            past::Expr::Str(string) => Str(string),
+            past::Expr::UnOp(op, sub) => UnOp(op, self.lower_sub(*sub)),
+            past::Expr::Comparison(self::BinOp::Eq, left, right, type_expr) => {
+                let (left, right) = (self.lower_sub(*left), self.lower_sub(*right));
+                self.equal(&location, &type_expr, *left, *right)
+            }
+            past::Expr::Comparison(self::BinOp::Lt, left, right, type_expr) => {
+                let (left, right) = (self.lower_sub(*left), self.lower_sub(*right));
+                self.less(&location, &type_expr, *left, *right)
+            }
+            // values that aren't kept on the heap are the same whenever they're
+            // equal, so only pointers are ever compared with '=='
+            past::Expr::Comparison(op, left, right, type_expr) => {
+                let (left, right) = (self.lower_sub(*left), self.lower_sub(*right));
+                match type_expr {
+                    TypeExpr::Unit | TypeExpr::Bool | TypeExpr::Int | TypeExpr::Float => {
+                        self.equal(&location, &type_expr, *left, *right)
+                    }
+                    _ => BinOp(op, left, right),
+                }
+            }
+            // every other operator is lowered by 'lower_chain'
+            past::Expr::BinOp(_, _, _) => unreachable!(),
+            past::Expr::If(condition, left, right) => If(
+                self.lower_sub(*condition),
+                self.lower_sub(*left),
+                self.lower_sub(*right),
+            ),
+            past::Expr::Pair(left, right) => Pair(self.lower_sub(*left), self.lower_sub(*right)),
+            past::Expr::Fst(sub) => Fst(self.lower_sub(*sub)),
+            past::Expr::Snd(sub) => Snd(self.lower_sub(*sub)),
+            past::Expr::Ignore(sub) => Seq(vec![*self.lower_sub(*sub), Unit]),
+            past::Expr::Inl(sub, type_expr) => Inl(self.lower_sub(*sub), type_expr),
+            past::Expr::Inr(sub, type_expr) => Inr(self.lower_sub(*sub), type_expr),
+            past::Expr::Case(sub, (v_left, _, sub_left), (v_right, _, sub_right)) => Case(
+                self.lower_sub(*sub),
+                (v_left, self.lower_sub(*sub_left)),
+                (v_right, self.lower_sub(*sub_right)),
+            ),
+            past::Expr::Lambda((v, _, sub)) => Lambda((v, self.lower_sub(*sub))),
+            past::Expr::While(condition, sub) => {
+                While(self.lower_sub(*condition), self.lower_sub(*sub))
+            }
+            past::Expr::For(v, from, to, sub) => For(
+                self.lower_sub(*from),
+                self.lower_sub(*to),
+                (v, self.lower_sub(*sub)),
             ),
-            past::Expr::Lambda((v, _, sub)) => Lambda((v, sub.into())),
-            past::Expr::While(condition, sub) => While(condition.into(), sub.into()),
             past::Expr::Seq(seq) => Seq(seq
                 .into_iter()
-                .map(|x| x.into_raw().into())
+                .map(|sub| *self.lower_sub(*sub))
                 .collect::<Vec<Expr>>()),
-            past::Expr::Ref(sub) => Ref(sub.into()),
-            past::Expr::Deref(sub) => Deref(sub.into()),
-            past::Expr::Assign(left, right) => Assign(left.into(), right.into()),
-            past::Expr::App(left, right) => App(left.into(), right.into()),
-            past::Expr::Let(v, _, sub, body) => Let(v, sub.into(), body.into()),
-            past::Expr::LetFun(f, (v, _, sub), _, body) => LetFun(f, (v, sub.into()), body.into()),
+            past::Expr::Ref(sub) => Ref(self.lower_sub(*sub)),
+            past::Expr::Deref(sub) => Deref(self.lower_sub(*sub)),
+            past::Expr::Lazy(sub) => Lazy(self.lower_sub(*sub)),
+            past::Expr::Force(sub) => Force(location, self.lower_sub(*sub)),
+            past::Expr::Chan(_) => Chan,
+            past::Expr::Spawn(sub) => Spawn(self.lower_sub(*sub)),
+            past::Expr::Raise(sub) => Raise(location, self.lower_sub(*sub)),
+            past::Expr::Try(sub, (v, _, handler)) => {
+                Try(self.lower_sub(*sub), (v, self.lower_sub(*handler)))
+            }
+            past::Expr::Send(left, right) => {
+                Send(location, self.lower_sub(*left), self.lower_sub(*right))
+            }
+            past::Expr::Recv(sub) => Recv(location, self.lower_sub(*sub)),
+            past::Expr::Print(sub) => Print(self.lower_sub(*sub)),
+            past::Expr::Expect(sub, likely) => Expect(self.lower_sub(*sub), likely),
+            past::Expr::Attributed(attributes, sub) => Attributed(attributes, self.lower_sub(*sub)),
+            past::Expr::Assign(left, right) => {
+                Assign(self.lower_sub(*left), self.lower_sub(*right))
+            }
+            past::Expr::MakeArray(length, sub) => {
+                MakeArray(location, self.lower_sub(*length), self.lower_sub(*sub))
+            }
+            past::Expr::Index(array, index) => {
+                Index(location, self.lower_sub(*array), self.lower_sub(*index))
+            }
+            past::Expr::Update(array, index, sub) => Update(
+                location,
+                self.lower_sub(*array),
+                self.lower_sub(*index),
+                self.lower_sub(*sub),
+            ),
+            past::Expr::Nil => Nil,
+            past::Expr::Cons(left, right) => Cons(self.lower_sub(*left), self.lower_sub(*right)),
+            past::Expr::Head(sub) => Head(location, self.lower_sub(*sub)),
+            past::Expr::Tail(sub) => Tail(location, self.lower_sub(*sub)),
+            past::Expr::IsEmpty(sub) => IsEmpty(self.lower_sub(*sub)),
+            past::Expr::App(left, right) => App(self.lower_sub(*left), self.lower_sub(*right)),
+            // definitions are lowered by 'lower_definitions'
+            past::Expr::Let(..) | past::Expr::LetFun(..) | past::Expr::LetRec(..) => unreachable!(),
+            // the type checker erases modules, 'match'es, data types, records
+            // and tuples once it's checked them (and names are resolved, which
+            // gets rid of 'open's, before it does)
+            past::Expr::Module(_, _)
+            | past::Expr::Open(_, _)
+            | past::Expr::Match(_, _, _, _)
+            | past::Expr::Data(_, _)
+            | past::Expr::Construct(_, _, _)
+            | past::Expr::Record(_, _)
+            | past::Expr::Field(_, _, _)
+            | past::Expr::Tuple(_)
+            | past::Expr::Proj(_, _, _) => unreachable!(),
         }
     }
 }
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::mem;
+
+use super::past::{self, Datatype, Expr, Pattern, Var};
+use super::types::TypeExpr;
+use super::{Locatable, Location};
+
+// the types of what a data type's constructors are given, in order ('unit' for
+// a constructor that isn't given anything)
+fn payloads(datatype: &Datatype) -> Vec<TypeExpr> {
+    datatype
+        .constructors
+        .iter()
+        .map(|(_, type_expr)| type_expr.clone().unwrap_or(TypeExpr::Unit))
+        .collect()
+}
+
+fn union(payloads: &[TypeExpr]) -> TypeExpr {
+    match payloads.split_first() {
+        Some((first, [])) => first.clone(),
+        Some((first, rest)) => TypeExpr::Union(Box::new(first.clone()), Box::new(union(rest))),
+        // every data type has at least one constructor
+        None => unreachable!(),
+    }
+}
+
+// the type that represents a data type: what its first constructor is given,
+// on the left of a union with what represents the rest of its constructors
+// (unless it has no others). A value made by a constructor is tagged with
+// where the constructor is in the list, as that many 'inr's around an 'inl'
+// (or around nothing, for the last constructor)
+pub fn representation(datatype: &Datatype) -> TypeExpr {
+    union(&payloads(datatype))
+}
+
+// where a constructor is in its data type, and how many constructors it has
+fn tag(datatype: &Datatype, c: &str) -> (usize, usize) {
+    let k = datatype
+        .constructors
+        .iter()
+        .position(|(d, _)| d == c)
+        .unwrap();
+    (k, datatype.constructors.len())
+}
+
+struct Eraser<'a> {
+    datatypes: &'a HashMap<Var, Datatype>,
+    representations: &'a HashMap<Var, TypeExpr>,
+    fresh: usize,
+}
+
+impl<'a> Eraser<'a> {
+    // a '.' can't appear in a name a program binds, so these never capture the
+    // program's own variables
+    fn fresh(&mut self) -> Var {
+        self.fresh += 1;
+        format!("data.{}", self.fresh)
+    }
+
+    // a value made by a constructor from what it was given
+    fn inject(&self, location: &Location, t: &str, c: &str, value: Locatable<Expr>) -> Expr {
+        let datatype = &self.datatypes[t];
+        let payloads = payloads(datatype)
+            .iter()
+            .map(|type_expr| type_expr.expand(self.representations))
+            .collect::<Vec<_>>();
+        let (k, n) = tag(datatype, c);
+        let mut injected = value;
+        if k + 1 < n {
+            injected = (
+                location.clone(),
+                Expr::Inl(Box::new(injected), union(&payloads[k + 1..])),
+            )
+                .into();
+        }
+        for payload in payloads[..k].iter().rev() {
+            injected = (
+                location.clone(),
+                Expr::Inr(Box::new(injected), payload.clone()),
+            )
+                .into();
+        }
+        injected.into_raw()
+    }
+
+    // a pattern that matches what a constructor makes from what its own
+    // pattern matches
+    fn pattern(&self, pattern: &mut Pattern) {
+        match *pattern {
+            Pattern::Pair(ref mut left, ref mut right)
+            | Pattern::Cons(ref mut left, ref mut right) => {
+                self.pattern(left);
+                self.pattern(right);
+            }
+            Pattern::Tuple(ref mut patterns) => {
+                for sub in patterns.iter_mut() {
+                    self.pattern(sub);
+                }
+            }
+            Pattern::Inl(ref mut sub) | Pattern::Inr(ref mut sub) => self.pattern(sub),
+            Pattern::Construct(ref t, ref c, ref mut sub) => {
+                let mut injected = match sub.take() {
+                    Some(mut sub) => {
+                        self.pattern(&mut sub);
+                        *sub
+                    }
+                    None => Pattern::Unit,
+                };
+                let (k, n) = tag(&self.datatypes[t], c);
+                if k + 1 < n {
+                    injected = Pattern::Inl(Box::new(injected));
+                }
+                for _ in 0..k {
+                    injected = Pattern::Inr(Box::new(injected));
+                }
+                *pattern = injected;
+            }
+            _ => (),
+        }
+    }
+
+    fn erase(&mut self, expr: &mut Locatable<Expr>) {
+        // a chain of definitions ('let x = e in let y = e in ...') is walked
+        // down in a loop, as a program of top-level definitions is one (and
+        // all there is to erase from a 'let' itself is in its annotations)
+        let mut expr = expr;
+        while let Expr::Let(..) | Expr::LetFun(..) | Expr::LetRec(..) = expr.t {
+            for type_expr in expr.t.annotations_mut() {
+                *type_expr = type_expr.expand(self.representations);
+            }
+            let mut children = expr.t.children_mut();
+            let body = children.pop().unwrap();
+            for sub in children {
+                self.erase(sub);
+            }
+            expr = body;
+        }
+        if let Expr::BinOp(..) = expr.t {
+            for sub in past::operands_mut(expr) {
+                self.erase(sub);
+            }
+            return;
+        }
+        for type_expr in expr.t.annotations_mut() {
+            *type_expr = type_expr.expand(self.representations);
+        }
+        for sub in expr.t.children_mut() {
+            self.erase(sub);
+        }
+        let location = expr.location().clone();
+        expr.t = match mem::replace(&mut expr.t, Expr::Unit) {
+            Expr::Data(_, body) => body.into_raw(),
+            Expr::Construct(t, c, Some(sub)) => self.inject(&location, &t, &c, *sub),
+            Expr::Construct(t, c, None) => {
+                let datatype = &self.datatypes[&t];
+                match datatype.constructors[tag(datatype, &c).0].1 {
+                    // a constructor that isn't applied to what it's given is a
+                    // function that makes a value from it
+                    Some(ref payload) => {
+                        let payload = payload.expand(self.representations);
+                        let v = self.fresh();
+                        let var = (location.clone(), Expr::Var(v.clone())).into();
+                        let body = self.inject(&location, &t, &c, var);
+                        Expr::Lambda((v, payload, Box::new((location, body).into())))
+                    }
+                    None => {
+                        let unit = (location.clone(), Expr::Unit).into();
+                        self.inject(&location, &t, &c, unit)
+                    }
+                }
+            }
+            Expr::Match(sub, type_expr, mut arms, result) => {
+                for (pattern, _) in arms.iter_mut() {
+                    self.pattern(pattern);
+                }
+                Expr::Match(sub, type_expr, arms, result)
+            }
+            erased => erased,
+        };
+    }
+}
+
+// replaces each data type with the type that represents it, and each use of
+// its constructors (in a value or in a pattern) with the injections that tag
+// values of that type, once the program has been checked. Like modules, data
+// types are never seen by the rest of the compiler
+pub fn erase(
+    expr: &mut Locatable<Expr>,
+    datatypes: &HashMap<Var, Datatype>,
+    representations: &HashMap<Var, TypeExpr>,
+) {
+    Eraser {
+        datatypes,
+        representations,
+        fresh: 0,
+    }
+    .erase(expr);
+}
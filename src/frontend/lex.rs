@@ -2,21 +2,40 @@ use std::fmt;
 use std::iter::{FusedIterator, Peekable};
 use std::mem::discriminant;
 
-use super::{log, Locatable, Location};
+use super::super::IntWidth;
+use super::{log, Diagnostic, Locatable, Location};
 
-#[derive(Debug, Eq)]
+#[derive(Debug)]
 pub enum Kind {
     LParen,
     RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    // '[@', which opens an attribute
+    LAttribute,
+    // '[]', the empty list
+    Nil,
     Comma,
     Colon,
+    // '::'
+    Cons,
+    Dot,
     Semi,
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
+    FloatAdd,
+    FloatSub,
+    FloatMul,
+    FloatDiv,
+    Concat,
     Not,
     Eq,
+    PhysEq,
     Assign,
     Lt,
     AndOp,
@@ -25,32 +44,67 @@ pub enum Kind {
     Arrow,
     What,
     Bang,
+    Underscore,
     Unit,
     And,
     True,
     False,
     Ref,
+    Lazy,
+    Force,
+    Chan,
+    Spawn,
+    Raise,
+    Try,
+    Send,
+    Recv,
+    Expect,
+    PrintString,
     Inl,
     Inr,
     Fst,
     Snd,
+    Ignore,
     Case,
     Of,
+    Match,
+    With,
     If,
     Then,
     Else,
     Let,
+    Rec,
     Fun,
     In,
     Begin,
     End,
     While,
     Do,
+    For,
+    To,
+    Module,
+    Sig,
+    Struct,
+    Open,
+    Import,
+    Val,
+    Type,
+    Forall,
     BoolType,
     IntType,
     UnitType,
+    StringType,
+    FloatType,
+    ArrayType,
+    ListType,
     Int(i64),
+    Float(f64),
+    Str(String),
     Ident(String),
+    Hole(String),
+    TypeVariable(String),
+    // '#k', which takes the 'k'th component of a tuple
+    Proj(usize),
 }
 
 impl fmt::Display for Kind {
@@ -59,15 +113,30 @@ impl fmt::Display for Kind {
         match *self {
             LParen => write!(f, "'('"),
             RParen => write!(f, "')'"),
+            LBrace => write!(f, "'{{'"),
+            RBrace => write!(f, "'}}'"),
+            LBracket => write!(f, "'['"),
+            RBracket => write!(f, "']'"),
+            LAttribute => write!(f, "'[@'"),
+            Nil => write!(f, "'[]'"),
             Comma => write!(f, "','"),
             Colon => write!(f, "':'"),
+            Cons => write!(f, "'::'"),
+            Dot => write!(f, "'.'"),
             Semi => write!(f, "';'"),
             Add => write!(f, "'+'"),
             Sub => write!(f, "'-'"),
             Mul => write!(f, "'*'"),
             Div => write!(f, "'/'"),
+            Mod => write!(f, "'%'"),
+            FloatAdd => write!(f, "'+.'"),
+            FloatSub => write!(f, "'-.'"),
+            FloatMul => write!(f, "'*.'"),
+            FloatDiv => write!(f, "'/.'"),
+            Concat => write!(f, "'^'"),
             Not => write!(f, "'!'"),
             Eq => write!(f, "'='"),
+            PhysEq => write!(f, "'=='"),
             Assign => write!(f, "':='"),
             Lt => write!(f, "'<'"),
             AndOp => write!(f, "'&&'"),
@@ -76,39 +145,87 @@ impl fmt::Display for Kind {
             Arrow => write!(f, "'->'"),
             What => write!(f, "'?'"),
             Bang => write!(f, "'!'"),
+            Underscore => write!(f, "'_'"),
             Unit => write!(f, "unit '()'"),
             And => write!(f, "keyword 'and'"),
             True => write!(f, "boolean 'true'"),
             False => write!(f, "boolean 'false'"),
             Ref => write!(f, "keyword 'ref'"),
+            Lazy => write!(f, "keyword 'lazy'"),
+            Force => write!(f, "keyword 'force'"),
+            Chan => write!(f, "keyword 'chan'"),
+            Spawn => write!(f, "keyword 'spawn'"),
+            Raise => write!(f, "keyword 'raise'"),
+            Try => write!(f, "keyword 'try'"),
+            Send => write!(f, "keyword 'send'"),
+            Recv => write!(f, "keyword 'recv'"),
+            Expect => write!(f, "keyword 'expect'"),
+            PrintString => write!(f, "keyword 'print_string'"),
             Inl => write!(f, "keyword 'inl'"),
             Inr => write!(f, "keyword 'inr'"),
             Fst => write!(f, "keyword 'fst'"),
             Snd => write!(f, "keyword 'snd'"),
+            Ignore => write!(f, "keyword 'ignore'"),
             Case => write!(f, "keyword 'case'"),
             Of => write!(f, "keyword 'of'"),
+            Match => write!(f, "keyword 'match'"),
+            With => write!(f, "keyword 'with'"),
             If => write!(f, "keyword 'if'"),
             Then => write!(f, "keyword 'then'"),
             Else => write!(f, "keyword 'else'"),
             Let => write!(f, "keyword 'let'"),
+            Rec => write!(f, "keyword 'rec'"),
             Fun => write!(f, "keyword 'fun'"),
             In => write!(f, "keyword 'in'"),
             Begin => write!(f, "keyword 'begin'"),
             End => write!(f, "keyword 'end'"),
             While => write!(f, "keyword 'while'"),
             Do => write!(f, "keyword 'do'"),
+            For => write!(f, "keyword 'for'"),
+            To => write!(f, "keyword 'to'"),
+            Module => write!(f, "keyword 'module'"),
+            Sig => write!(f, "keyword 'sig'"),
+            Struct => write!(f, "keyword 'struct'"),
+            Open => write!(f, "keyword 'open'"),
+            Import => write!(f, "keyword 'import'"),
+            Val => write!(f, "keyword 'val'"),
+            Type => write!(f, "keyword 'type'"),
+            Forall => write!(f, "keyword 'forall'"),
             BoolType => write!(f, "typename 'bool'"),
             IntType => write!(f, "typename 'int'"),
             UnitType => write!(f, "typename 'unit'"),
+            StringType => write!(f, "typename 'string'"),
+            FloatType => write!(f, "typename 'float'"),
+            ArrayType => write!(f, "keyword 'array'"),
+            ListType => write!(f, "keyword 'list'"),
             Int(_) => write!(f, "integer"),
+            Float(_) => write!(f, "float"),
+            Str(_) => write!(f, "string"),
             Ident(ref ident) => {
                 write!(f, "identifier")?;
-                if ident.len() > 0 {
+                if !ident.is_empty() {
                     write!(f, " ('{}')", ident)
                 } else {
                     Ok(())
                 }
             }
+            Hole(ref name) => {
+                write!(f, "hole")?;
+                if !name.is_empty() {
+                    write!(f, " ('?{}')", name)
+                } else {
+                    Ok(())
+                }
+            }
+            TypeVariable(ref name) => {
+                write!(f, "type variable")?;
+                if !name.is_empty() {
+                    write!(f, " (''{}')", name)
+                } else {
+                    Ok(())
+                }
+            }
+            Proj(_) => write!(f, "projection"),
         }
     }
 }
@@ -119,8 +236,48 @@ impl PartialEq for Kind {
     }
 }
 
+// comparing kinds ignores what they carry, so even a float's kind is equal to
+// itself
+impl Eq for Kind {}
+
 pub type Token = Locatable<Kind>;
 
+// the literal that lexes as a string, escaping what the lexer expects to be
+// escaped
+pub fn quote(string: &str) -> String {
+    let mut quoted = "\"".to_string();
+    for c in string.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// how a float is written in a program: a literal that has a '.' in it (so
+// that it doesn't lex as an integer), or, for the floats no literal is, a
+// division that makes it
+pub fn float_literal(f: f64) -> String {
+    if f.is_nan() {
+        return "(0.0 /. 0.0)".to_string();
+    } else if f.is_infinite() {
+        return format!("({}1.0 /. 0.0)", if f < 0.0 { "-" } else { "" });
+    }
+    let literal = format!("{:?}", f);
+    if literal.contains('.') {
+        return literal;
+    }
+    match literal.find('e') {
+        Some(i) => format!("{}.0{}", &literal[..i], &literal[i..]),
+        None => format!("{}.0", literal),
+    }
+}
+
 pub struct Lexer<T>
 where
     T: Iterator<Item = char>,
@@ -128,23 +285,37 @@ where
     filename: String,
     line: usize,
     column: usize,
+    // where the token currently being lexed starts (after any whitespace or comments)
+    start: Location,
     chars: Peekable<T>,
+    // integer literals have to fit in an 'int' of this width
+    int_width: IntWidth,
+    // whether the last error was running out of input (which the lexer keeps
+    // reporting, rather than ending)
+    ended: bool,
 }
 
 impl<T> Lexer<T>
 where
     T: Iterator<Item = char>,
 {
-    pub fn over(filename: String, chars: T) -> Lexer<T> {
+    pub fn over(filename: String, chars: T, int_width: IntWidth) -> Lexer<T> {
         Lexer {
-            filename: filename,
+            int_width,
+            start: Location::new(filename.clone(), 1, 1),
+            filename,
             line: 0,
-            column: 0,
+            column: 1,
             chars: chars.peekable(),
+            ended: false,
         }
     }
 
-    pub fn location(&self) -> Location {
+    pub fn ended(&self) -> bool {
+        self.ended
+    }
+
+    fn location(&self) -> Location {
         Location::new(self.filename.clone(), self.line + 1, self.column)
     }
 
@@ -158,9 +329,9 @@ where
             match c {
                 ' ' | '\t' => self.advance(),
                 '\n' => {
-                    self.column = 0;
-                    self.line += 1;
                     self.advance();
+                    self.column = 1;
+                    self.line += 1;
                 }
                 _ => break,
             }
@@ -186,7 +357,7 @@ where
                 }
                 '\n' => {
                     self.advance();
-                    self.column = 0;
+                    self.column = 1;
                     self.line += 1;
                 }
                 _ => self.advance(),
@@ -194,51 +365,150 @@ where
         }
     }
 
-    fn next_int(&mut self) -> Kind {
-        let mut numeral = String::new();
-        while let Some(c @ '0'...'9') = self.chars.peek() {
+    fn digits(&mut self, numeral: &mut String) {
+        while let Some(c @ '0'..='9') = self.chars.peek() {
             numeral.push(*c);
             self.advance();
         }
-        Kind::Int(numeral.parse::<i64>().unwrap())
     }
 
-    fn next_keyword(&mut self) -> Kind {
-        use self::Kind::*;
-        let mut keyword = String::new();
-        if let Some(c @ 'a'...'z') | Some(c @ 'A'...'Z') = self.chars.peek() {
-            keyword.push(*c);
+    // an integer, or a float if it goes on with a '.' (then any more digits,
+    // and an exponent)
+    fn next_number(&mut self) -> Result<Kind, String> {
+        let mut numeral = String::new();
+        self.digits(&mut numeral);
+        if let Some('.') = self.chars.peek() {
+            numeral.push('.');
             self.advance();
-            while let Some(c @ 'a'...'z') | Some(c @ 'A'...'Z') | Some(c @ '_') | Some(c @ '\'')
-            | Some(c @ '0'...'9') = self.chars.peek()
-            {
-                keyword.push(*c);
+            self.digits(&mut numeral);
+            if let Some('e') | Some('E') = self.chars.peek() {
+                numeral.push('e');
                 self.advance();
+                if let Some(c @ '+') | Some(c @ '-') = self.chars.peek() {
+                    numeral.push(*c);
+                    self.advance();
+                }
+                if !matches!(self.chars.peek(), Some('0'..='9')) {
+                    return Err(format!("missing exponent in float literal '{}'", numeral));
+                }
+                self.digits(&mut numeral);
             }
+            // every literal made of digits is a valid float (even if it's
+            // rounded to infinity)
+            return Ok(Kind::Float(numeral.parse().unwrap()));
+        }
+        match numeral.parse::<i64>() {
+            Ok(i) if self.int_width.wrap(i) == i => Ok(Kind::Int(i)),
+            _ => Err(format!(
+                "integer literal '{}' is too large for a {}-bit 'int'",
+                numeral,
+                self.int_width.bits()
+            )),
+        }
+    }
+
+    // a string literal can use '\n', '\t', '\\' and '\"' for the characters it
+    // can't contain as they are, but has to end on the line it starts on
+    fn next_string(&mut self) -> Result<Kind, String> {
+        self.advance();
+        let mut string = String::new();
+        loop {
+            match self.chars.peek() {
+                Some('"') => {
+                    self.advance();
+                    return Ok(Kind::Str(string));
+                }
+                Some('\\') => {
+                    self.advance();
+                    let escaped = match self.chars.peek() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        Some(c) => {
+                            return Err(format!("unknown escape '\\{}' in string literal", c))
+                        }
+                        None => return Err("unterminated string literal".to_string()),
+                    };
+                    string.push(escaped);
+                    self.advance();
+                }
+                Some('\n') | None => return Err("unterminated string literal".to_string()),
+                Some(c) => {
+                    string.push(*c);
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn next_name(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(c @ 'a'..='z') | Some(c @ 'A'..='Z') | Some(c @ '_') | Some(c @ '\'')
+        | Some(c @ '0'..='9') = self.chars.peek()
+        {
+            name.push(*c);
+            self.advance();
+        }
+        name
+    }
+
+    fn next_keyword(&mut self) -> Kind {
+        use self::Kind::*;
+        if let Some('a'..='z') | Some('A'..='Z') = self.chars.peek() {
+            let keyword = self.next_name();
             match keyword.as_str() {
                 "and" => And,
                 "true" => True,
                 "false" => False,
                 "ref" => Ref,
+                "lazy" => Lazy,
+                "force" => Force,
+                "chan" => Chan,
+                "spawn" => Spawn,
+                "raise" => Raise,
+                "try" => Try,
+                "send" => Send,
+                "recv" => Recv,
+                "expect" => Expect,
+                "print_string" => PrintString,
                 "inl" => Inl,
                 "inr" => Inr,
                 "fst" => Fst,
                 "snd" => Snd,
+                "ignore" => Ignore,
                 "case" => Case,
                 "of" => Of,
+                "match" => Match,
+                "with" => With,
                 "if" => If,
                 "then" => Then,
                 "else" => Else,
                 "let" => Let,
+                "rec" => Rec,
                 "fun" => Fun,
                 "in" => In,
                 "begin" => Begin,
                 "end" => End,
                 "while" => While,
                 "do" => Do,
+                "for" => For,
+                "to" => To,
+                "module" => Module,
+                "sig" => Sig,
+                "struct" => Struct,
+                "open" => Open,
+                "import" => Import,
+                "val" => Val,
+                "type" => Type,
+                "forall" => Forall,
                 "bool" => BoolType,
                 "int" => IntType,
                 "unit" => UnitType,
+                "string" => StringType,
+                "float" => FloatType,
+                "array" => ArrayType,
+                "list" => ListType,
                 _ => Ident(keyword),
             }
         } else {
@@ -248,6 +518,7 @@ where
 
     fn next_kind(&mut self) -> Result<Kind, String> {
         use self::Kind::*;
+        self.start = self.location();
         if let Some(c) = self.chars.peek() {
             let kind = match c {
                 '(' => {
@@ -267,29 +538,71 @@ where
                     }
                 }
                 ')' => RParen,
+                '{' => LBrace,
+                '}' => RBrace,
+                '[' => {
+                    self.advance();
+                    match self.chars.peek() {
+                        Some(']') => Nil,
+                        Some('@') => LAttribute,
+                        _ => return Ok(LBracket),
+                    }
+                }
+                ']' => RBracket,
                 ',' => Comma,
                 ':' => {
                     self.advance();
-                    if let Some('=') = self.chars.peek() {
-                        Assign
-                    } else {
-                        return Ok(Colon);
+                    match self.chars.peek() {
+                        Some('=') => Assign,
+                        Some(':') => Cons,
+                        _ => return Ok(Colon),
                     }
                 }
+                '.' => Dot,
                 ';' => Semi,
-                '+' => Add,
+                '+' => {
+                    self.advance();
+                    if let Some('.') = self.chars.peek() {
+                        FloatAdd
+                    } else {
+                        return Ok(Add);
+                    }
+                }
                 '-' => {
                     self.advance();
-                    if let Some('>') = self.chars.peek() {
-                        Arrow
+                    match self.chars.peek() {
+                        Some('>') => Arrow,
+                        Some('.') => FloatSub,
+                        _ => return Ok(Sub),
+                    }
+                }
+                '*' => {
+                    self.advance();
+                    if let Some('.') = self.chars.peek() {
+                        FloatMul
                     } else {
-                        return Ok(Sub);
+                        return Ok(Mul);
                     }
                 }
-                '*' => Mul,
-                '/' => Div,
+                '/' => {
+                    self.advance();
+                    if let Some('.') = self.chars.peek() {
+                        FloatDiv
+                    } else {
+                        return Ok(Div);
+                    }
+                }
+                '%' => Mod,
+                '^' => Concat,
                 '~' => Not,
-                '=' => Eq,
+                '=' => {
+                    self.advance();
+                    if let Some('=') = self.chars.peek() {
+                        PhysEq
+                    } else {
+                        return Ok(Eq);
+                    }
+                }
                 '<' => Lt,
                 '&' => {
                     self.advance();
@@ -307,10 +620,43 @@ where
                         return Ok(Bar);
                     }
                 }
-                '?' => What,
+                '?' => {
+                    self.advance();
+                    // '?' straight before a name is a hole, rather than reading
+                    // from the input
+                    if let Some('a'..='z') | Some('A'..='Z') = self.chars.peek() {
+                        return Ok(Hole(self.next_name()));
+                    } else {
+                        return Ok(What);
+                    }
+                }
+                '\'' => {
+                    self.advance();
+                    if let Some('a'..='z') | Some('A'..='Z') = self.chars.peek() {
+                        return Ok(TypeVariable(self.next_name()));
+                    } else {
+                        return Err("no matching token class".to_string());
+                    }
+                }
+                '#' => {
+                    self.advance();
+                    let mut numeral = String::new();
+                    self.digits(&mut numeral);
+                    // components are counted from 1, as in Standard ML
+                    return match numeral.parse() {
+                        Ok(0) => Err("there's no '#0' (components are counted from 1)".to_string()),
+                        Ok(k) => Ok(Proj(k)),
+                        Err(_) if numeral.is_empty() => {
+                            Err("expected a number after '#'".to_string())
+                        }
+                        Err(_) => Err(format!("there's no '#{}'", numeral)),
+                    };
+                }
+                '_' => Underscore,
                 '!' => Bang,
-                'a'...'z' | 'A'...'Z' => return Ok(self.next_keyword()),
-                '0'...'9' => return Ok(self.next_int()),
+                'a'..='z' | 'A'..='Z' => return Ok(self.next_keyword()),
+                '0'..='9' => return self.next_number(),
+                '"' => return self.next_string(),
                 c if c.is_whitespace() => {
                     self.skip_whitespace();
                     return self.next_kind();
@@ -320,6 +666,7 @@ where
             self.advance();
             Ok(kind)
         } else {
+            self.ended = true;
             Err("unexpected end of file".to_string())
         }
     }
@@ -329,11 +676,12 @@ impl<T> Iterator for Lexer<T>
 where
     T: Iterator<Item = char>,
 {
-    type Item = Result<Token, String>;
+    type Item = Result<Token, Diagnostic>;
 
-    fn next(&mut self) -> Option<Result<Token, String>> {
-        let location = self.location();
-        match self.next_kind() {
+    fn next(&mut self) -> Option<Result<Token, Diagnostic>> {
+        let kind = self.next_kind();
+        let location = self.start.clone();
+        match kind {
             Ok(kind) => Some(Ok((location, kind).into())),
             Err(err) => Some(Err(log::parse_error(&location, err))),
         }
@@ -1,4 +1,4 @@
-use super::{past, Location};
+use super::{past, Diagnostic, Location};
 use std::fmt;
 use termion::{color, style};
 
@@ -10,39 +10,116 @@ impl fmt::Display for Location {
             style::Bold,
             self.filename,
             style::Reset,
-            self.line + 1,
+            self.line,
             self.column
         )
     }
 }
 
-pub fn parse_error(location: &Location, message: String) -> String {
+pub fn parse_error(location: &Location, message: String) -> Diagnostic {
+    Diagnostic::new(location.clone(), message)
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}syntax error{}{}: {}{}",
+            style::Bold,
+            color::Fg(color::Red),
+            color::Fg(color::Reset),
+            style::Reset,
+            self.location(),
+            self.message(),
+        )
+    }
+}
+
+pub fn runtime_error(location: &Location, message: &str) -> String {
     format!(
-        "{}{}syntax error{}{}: {}{}",
+        "{}{}{}runtime error{}{}: {}",
+        location,
         style::Bold,
         color::Fg(color::Red),
         color::Fg(color::Reset),
         style::Reset,
-        location,
         message,
     )
 }
 
-pub fn type_error(location: &Location, message: String, expr: &past::Expr) -> String {
+fn annotated<C: color::Color + Copy>(
+    kind: &str,
+    colour: C,
+    location: &Location,
+    message: String,
+    expr: &past::Expr,
+) -> String {
     let expr = format!("{}", expr);
     format!(
-        "{}{}type error{}{}: {}{}\n |\n `-> {}{}\n     {}{}{}{}",
+        "{}{}{}{}{}: {}{}\n |\n `-> {}{}\n     {}{}{}{}",
         style::Bold,
-        color::Fg(color::Red),
+        color::Fg(colour),
+        kind,
         color::Fg(color::Reset),
         style::Reset,
         location,
         message,
         style::Bold,
         expr,
-        color::Fg(color::Red),
+        color::Fg(colour),
         "^".repeat(expr.len()),
         color::Fg(color::Reset),
         style::Reset,
     )
 }
+
+pub fn type_error(location: &Location, message: String, expr: &past::Expr) -> String {
+    annotated("type error", color::Red, location, message, expr)
+}
+
+pub fn warning(location: &Location, message: String, expr: &past::Expr) -> String {
+    annotated("warning", color::Yellow, location, message, expr)
+}
+
+// how many letters have to be added, removed, changed or swapped with the next
+// to turn one name into the other
+fn distance(a: &str, b: &str) -> usize {
+    let (a, b) = (a.chars().collect::<Vec<_>>(), b.chars().collect::<Vec<_>>());
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let above = &rows[i - 1];
+            row[j] = (above[j - 1] + usize::from(a[i - 1] != b[j - 1]))
+                .min(above[j] + 1)
+                .min(row[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+// the one of 'names' most like 'name', as long as it's near enough to be a
+// slip of the fingers (the first of any that are equally near)
+pub fn closest<'a>(name: &str, names: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let near = (name.chars().count() / 3).max(1);
+    names
+        .into_iter()
+        .map(|other| (distance(name, other), other))
+        .filter(|(d, _)| *d <= near)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, other)| other)
+}
+
+// names quoted and joined as in a sentence ('a', 'b' and 'c')
+pub fn listed(names: &[&str]) -> String {
+    let quoted = names.iter().map(|x| format!("'{}'", x)).collect::<Vec<_>>();
+    match quoted.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+        Some((last, _)) => last.clone(),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,106 @@
+use super::past::Expr;
+use super::{parse, type_check, CompilerOptions, Locatable};
+
+fn size(expr: &Locatable<Expr>) -> usize {
+    let mut size = 0;
+    let mut work = vec![expr];
+    while let Some(expr) = work.pop() {
+        size += 1;
+        work.extend(expr.borrow_raw().children());
+    }
+    size
+}
+
+// the nth expression in a program, counting from the top down (and from left
+// to right)
+fn nth(expr: &mut Locatable<Expr>, n: usize) -> Option<&mut Locatable<Expr>> {
+    let mut n = n;
+    let mut work = vec![expr];
+    while let Some(expr) = work.pop() {
+        if n == 0 {
+            return Some(expr);
+        }
+        n -= 1;
+        work.extend(expr.t.children_mut().into_iter().rev());
+    }
+    None
+}
+
+// what an expression could be replaced by to make a program smaller: a literal,
+// one of its own subexpressions, or (for a sequence) itself with one expression
+// dropped, smallest first (most of these aren't of the right type, which is
+// left to the type checker)
+fn smaller(expr: &Locatable<Expr>) -> Vec<Locatable<Expr>> {
+    let literals = match *expr.borrow_raw() {
+        Expr::Unit | Expr::Int(0) | Expr::Bool(false) => vec![],
+        Expr::Int(_) => vec![Expr::Int(0)],
+        Expr::Bool(true) => vec![Expr::Bool(false)],
+        _ => vec![Expr::Unit, Expr::Int(0), Expr::Bool(false)],
+    };
+    let mut smaller = literals
+        .into_iter()
+        .map(|literal| (expr.location().clone(), literal).into())
+        .collect::<Vec<_>>();
+    smaller.extend(expr.borrow_raw().children().into_iter().cloned());
+    if let Expr::Seq(ref seq) = *expr.borrow_raw() {
+        for i in 0..seq.len() {
+            let mut seq = seq.clone();
+            seq.remove(i);
+            smaller.push((expr.location().clone(), Expr::Seq(seq)).into());
+        }
+    }
+    smaller
+}
+
+// shrinks a (well-typed) program for as long as 'interesting' says the smaller
+// program still is, one expression at a time, until no expression in it can be
+// made any smaller. Every program it's given is well-typed, and is given as the
+// text it's checked from, so it's exactly what 'interesting' sees
+pub fn minimise(
+    filename: &str,
+    text: String,
+    options: &CompilerOptions,
+    interesting: &mut dyn FnMut(&str) -> Result<bool, String>,
+) -> Result<String, String> {
+    let mut best = parse(filename, text.clone(), options)?;
+    // the type checker fills in any annotations that were left out, so it's
+    // given a copy to keep them out of the programs that are printed
+    type_check(&mut best.clone(), options)?;
+    let mut best_text = text;
+    loop {
+        let mut reduced = false;
+        let mut n = 0;
+        while n < size(&best) {
+            let replacements = smaller(nth(&mut best, n).unwrap());
+            let mut found = None;
+            for replacement in replacements {
+                let mut candidate = best.clone();
+                *nth(&mut candidate, n).unwrap() = replacement;
+                let candidate_text = format!("{}\n", candidate.borrow_raw());
+                let candidate = match parse(filename, candidate_text.clone(), options) {
+                    Ok(candidate) if type_check(&mut candidate.clone(), options).is_ok() => {
+                        candidate
+                    }
+                    _ => continue,
+                };
+                if interesting(&candidate_text)? {
+                    found = Some((candidate, candidate_text));
+                    break;
+                }
+            }
+            // whatever took the place of the expression is tried again, as it
+            // might be made smaller still
+            match found {
+                Some((candidate, candidate_text)) => {
+                    best = candidate;
+                    best_text = candidate_text;
+                    reduced = true;
+                }
+                None => n += 1,
+            }
+        }
+        if !reduced {
+            return Ok(best_text);
+        }
+    }
+}
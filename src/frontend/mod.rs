@@ -1,10 +1,28 @@
 pub mod ast;
+mod datatypes;
 mod lex;
-mod log;
+pub mod log;
+mod minimise;
+mod modules;
+mod monomorphise;
 mod parse;
 mod past;
+mod patterns;
+mod pragma;
+mod records;
 mod types;
 
+pub use self::lex::{float_literal, quote, Kind, Token};
+pub use self::minimise::minimise;
+pub use self::monomorphise::Copied;
+pub use self::past::{Expr, SubExpr};
+pub use self::types::TypeExpr;
+
+use super::{Capabilities, CompilerOptions};
+
+use std::fs;
+use std::path::Path;
+
 #[derive(Clone)]
 pub struct Location {
     filename: String,
@@ -20,8 +38,21 @@ impl Location {
             column,
         }
     }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
 }
 
+#[derive(Clone)]
 pub struct Locatable<T> {
     location: Location,
     t: T,
@@ -33,12 +64,12 @@ impl<T> From<(Location, T)> for Locatable<T> {
     }
 }
 
-impl<'a, T> Locatable<T> {
+impl<T> Locatable<T> {
     fn into_raw(self) -> T {
         self.t
     }
 
-    fn borrow_raw(&self) -> &T {
+    pub fn borrow_raw(&self) -> &T {
         &self.t
     }
 
@@ -47,21 +78,472 @@ impl<'a, T> Locatable<T> {
     }
 }
 
-impl<'a, T> Into<Location> for Locatable<T> {
-    fn into(self) -> Location {
-        self.location
+impl<T> From<Locatable<T>> for Location {
+    fn from(locatable: Locatable<T>) -> Location {
+        locatable.location
+    }
+}
+
+// a syntax error, and where it was found
+#[derive(Clone)]
+pub struct Diagnostic {
+    location: Location,
+    message: String,
+}
+
+impl Diagnostic {
+    pub fn new(location: Location, message: String) -> Diagnostic {
+        Diagnostic { location, message }
+    }
+
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+// splits a program's text into tokens, up to the end of it (after resolving
+// its '#if's)
+pub fn tokens(
+    filename: &str,
+    text: &str,
+    options: &CompilerOptions,
+) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    let text =
+        pragma::resolve(filename, text, &options.defines).map_err(|diagnostic| vec![diagnostic])?;
+    let mut lexer = self::lex::Lexer::over(filename.to_string(), text.chars(), options.int_width);
+    let mut tokens = vec![];
+    loop {
+        match lexer.next() {
+            Some(Ok(token)) => tokens.push(token),
+            Some(Err(_)) if lexer.ended() => return Ok(tokens),
+            Some(Err(diagnostic)) => return Err(vec![diagnostic]),
+            None => unreachable!(),
+        }
+    }
+}
+
+// parses an expression from any stream of tokens, rather than from a file.
+// Parsing stops at the first syntax error, so there's only ever one diagnostic
+// for now, and anything after the expression is left unread (as it is when
+// parsing a file)
+pub fn parse_expr<I>(tokens: I, options: &CompilerOptions) -> Result<SubExpr, Vec<Diagnostic>>
+where
+    I: IntoIterator<Item = Token>,
+{
+    let tokens = parse::Tokens::over(tokens.into_iter());
+    let mut parser = parse::Parser::new(tokens, options.max_depth);
+    match parser.parse() {
+        Ok(expr) => Ok(Box::new(expr)),
+        Err(diagnostic) => Err(vec![diagnostic]),
+    }
+}
+
+// reads a type as the compiler prints it (or as it's written in a program)
+pub fn parse_type(text: &str) -> Option<TypeExpr> {
+    let options = CompilerOptions::default();
+    let tokens = tokens("<type>", text, &options).ok()?;
+    let tokens = parse::Tokens::over(tokens.into_iter());
+    parse::Parser::new(tokens, options.max_depth)
+        .parse_type()
+        .ok()
+}
+
+fn parse(
+    filename: &str,
+    text: String,
+    options: &CompilerOptions,
+) -> Result<Locatable<past::Expr>, String> {
+    let text = pragma::resolve(filename, &text, &options.defines)
+        .map_err(|diagnostic| diagnostic.to_string())?;
+    let lexer = self::lex::Lexer::over(filename.to_string(), text.chars(), options.int_width);
+    let mut parser = parse::Parser::new(lexer, options.max_depth);
+    let mut expr = parser
+        .parse()
+        .map_err(|diagnostic| diagnostic.to_string())?;
+    let mut unknowns = parser.unknowns();
+    let mut importing = vec![identity(filename)];
+    import(filename, &mut expr, options, &mut importing, &mut unknowns)?;
+    sandbox(&expr, &options.capabilities)?;
+    Ok(expr)
+}
+
+// what a file is known as while it's being imported, which is the same however
+// the path to it is written
+fn identity(path: &str) -> String {
+    fs::canonicalize(path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+// reads in the structure of each module that's imported from another file,
+// which is found relative to the file that imports it ('filename'). The files
+// being imported are in 'importing', innermost last, so that a file that ends
+// up importing itself is reported rather than read forever. Each file's
+// unknowns are numbered from 'unknowns' on, after the ones already used
+fn import(
+    filename: &str,
+    expr: &mut Locatable<past::Expr>,
+    options: &CompilerOptions,
+    importing: &mut Vec<String>,
+    unknowns: &mut usize,
+) -> Result<(), String> {
+    let location = expr.location().clone();
+    let source = match expr.t {
+        past::Expr::Module(ref mut module, _) => match module.source {
+            Some(ref source) => {
+                let path = Path::new(filename)
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .join(source)
+                    .to_string_lossy()
+                    .into_owned();
+                if importing.contains(&identity(&path)) {
+                    return Err(log::parse_error(
+                        &location,
+                        format!(
+                            "can't import '{}', as it's already being imported (a file can't import itself, even through other files)",
+                            source
+                        ),
+                    )
+                    .to_string());
+                }
+                let text = fs::read_to_string(&path).map_err(|err| {
+                    log::parse_error(&location, format!("can't import '{}': {}", source, err))
+                        .to_string()
+                })?;
+                let tokens = tokens(&path, &text, options).map_err(|diagnostics| {
+                    let messages = diagnostics.iter().map(|d| d.to_string());
+                    messages.collect::<Vec<_>>().join("\n")
+                })?;
+                let tokens = parse::Tokens::over(tokens.into_iter());
+                let mut parser = parse::Parser::new(tokens, options.max_depth).after(*unknowns);
+                module.items = parser
+                    .parse_structure()
+                    .map_err(|diagnostic| diagnostic.to_string())?;
+                *unknowns = parser.unknowns();
+                Some(path)
+            }
+            None => None,
+        },
+        _ => None,
+    };
+    let mut children = match expr.t {
+        past::Expr::BinOp(..) => past::operands_mut(expr),
+        _ => expr.t.children_mut(),
+    };
+    if let Some(path) = source {
+        // the structure's imports are found relative to the file it's in, and
+        // the program it's imported into is all that's left after it
+        let body = children.pop().unwrap();
+        importing.push(identity(&path));
+        for sub in children {
+            import(&path, sub, options, importing, unknowns)?;
+        }
+        importing.pop();
+        return import(filename, body, options, importing, unknowns);
     }
+    for sub in children {
+        import(filename, sub, options, importing, unknowns)?;
+    }
+    Ok(())
 }
 
-fn check(expr: &Locatable<past::Expr>) -> Result<(), String> {
-    types::infer(&mut vec![], &expr)?;
+// checks that a program (with everything it imports) only uses what its
+// capabilities allow
+fn sandbox(expr: &Locatable<past::Expr>, capabilities: &Capabilities) -> Result<(), String> {
+    let needs = match expr.t {
+        past::Expr::What => Some(("'?'", "io", capabilities.io)),
+        past::Expr::Print(_) => Some(("'print_string'", "io", capabilities.io)),
+        _ => None,
+    };
+    if let Some((what, capability, false)) = needs {
+        return Err(log::type_error(
+            &expr.location,
+            format!(
+                "{} needs the '{}' capability, which this program isn't given",
+                what, capability
+            ),
+            &expr.t,
+        ));
+    }
+    let children = match expr.t {
+        past::Expr::BinOp(..) => past::operands(expr),
+        _ => expr.t.children(),
+    };
+    for sub in children {
+        sandbox(sub, capabilities)?;
+    }
     Ok(())
 }
 
-pub fn frontend(filename: &str, text: String) -> Result<ast::Expr, String> {
-    let lexer = self::lex::Lexer::over(filename.to_string(), text.chars());
-    let mut parser = parse::Parser::new(lexer);
-    let past = parser.parse()?;
-    check(&past)?;
-    Ok(past.into_raw().into())
+// checks a program, filling in any annotations that were left out of it
+fn type_check(
+    expr: &mut Locatable<past::Expr>,
+    options: &CompilerOptions,
+) -> Result<Vec<String>, String> {
+    let mut warnings = vec![];
+    types::elaborate(&mut vec![], &mut warnings, expr, options.strip_unused, None)?;
+    Ok(warnings)
+}
+
+pub fn check(
+    filename: &str,
+    text: String,
+    options: &CompilerOptions,
+) -> Result<Vec<String>, String> {
+    type_check(&mut parse(filename, text, options)?, options)
+}
+
+pub fn frontend(
+    filename: &str,
+    text: String,
+    options: &CompilerOptions,
+) -> Result<(ast::Expr, Vec<String>), String> {
+    let mut past = parse(filename, text, options)?;
+    let warnings = type_check(&mut past, options)?;
+    Ok((ast::Lowering::new().lower(past), warnings))
+}
+
+// like 'frontend', but the program counts how many times each of its
+// expressions is run, in the references named by 'ast::probe' (one for each of
+// the locations it gives)
+pub fn instrumented_frontend(
+    filename: &str,
+    text: String,
+    options: &CompilerOptions,
+) -> Result<(ast::Expr, Vec<Location>, Vec<String>), String> {
+    let mut past = parse(filename, text, options)?;
+    let warnings = type_check(&mut past, options)?;
+    let mut lowering = ast::Lowering::instrumented();
+    let expr = lowering.lower(past);
+    Ok((expr, lowering.probes(), warnings))
+}
+
+// the decision tree each 'match' in a program is compiled into, written out
+// in the order the matches are written in
+pub fn match_trees(
+    filename: &str,
+    text: String,
+    options: &CompilerOptions,
+) -> Result<(Vec<String>, Vec<String>), String> {
+    let mut past = parse(filename, text, options)?;
+    let mut warnings = vec![];
+    let mut trees = vec![];
+    types::elaborate(
+        &mut vec![],
+        &mut warnings,
+        &mut past,
+        options.strip_unused,
+        Some(&mut trees),
+    )?;
+    Ok((trees, warnings))
+}
+
+// like 'frontend', but also gives the type of the whole program
+pub fn typed_frontend(
+    filename: &str,
+    text: String,
+    options: &CompilerOptions,
+) -> Result<(ast::Expr, TypeExpr, Vec<String>), String> {
+    let mut past = parse(filename, text, options)?;
+    let mut warnings = vec![];
+    let (type_expr, _) = types::elaborate(
+        &mut vec![],
+        &mut warnings,
+        &mut past,
+        options.strip_unused,
+        None,
+    )?;
+    Ok((ast::Lowering::new().lower(past), type_expr, warnings))
+}
+
+// something entered at the REPL, checked and lowered in the environment of
+// everything defined before it
+pub struct Entry {
+    // the name being defined, if this is a definition rather than an expression
+    pub name: Option<String>,
+    // the source text for the entry, which is what gets saved
+    pub text: String,
+    pub type_expr: TypeExpr,
+    // nothing for a definition of something polymorphic, which is only run
+    // when it's copied at the types it's used at (see 'read_copy')
+    pub expr: Option<ast::Expr>,
+    // the copies it needs of what was defined before it that's polymorphic,
+    // which have to be in scope when it's run
+    pub copies: Vec<Copied>,
+    pub warnings: Vec<String>,
+}
+
+fn entry(
+    env: &mut Vec<(String, TypeExpr)>,
+    name: Option<String>,
+    text: String,
+    mut past: Locatable<past::Expr>,
+) -> Result<Entry, String> {
+    let mut warnings = vec![];
+    if let Some(ref name) = name {
+        if let Some(scheme) = types::scheme(env, &mut warnings, &past) {
+            // it's kept as the types it's copied at will have it, with its
+            // records and tuples erased
+            let type_expr = records::represent(&scheme);
+            env.push((name.clone(), type_expr.clone()));
+            return Ok(Entry {
+                name: Some(name.clone()),
+                text,
+                type_expr,
+                expr: None,
+                copies: vec![],
+                warnings,
+            });
+        }
+        warnings.clear();
+    }
+    // everything entered at the REPL is run as soon as it's entered, so
+    // there's nothing to gain from leaving out what isn't used
+    let (type_expr, copies) =
+        types::elaborate(&mut env.clone(), &mut warnings, &mut past, false, None)?;
+    let expr = ast::Lowering::new().lower(past);
+    if let Some(ref name) = name {
+        env.push((name.clone(), type_expr.clone()));
+    }
+    Ok(Entry {
+        name,
+        text,
+        type_expr,
+        expr: Some(expr),
+        copies,
+        warnings,
+    })
+}
+
+fn parse_entry(
+    text: &str,
+    options: &CompilerOptions,
+) -> Result<(Option<String>, Locatable<past::Expr>), String> {
+    let lexer = self::lex::Lexer::over("<repl>".to_string(), text.chars(), options.int_width);
+    let mut parser = parse::Parser::new(lexer, options.max_depth);
+    let (name, mut past) = parser
+        .parse_entry()
+        .map_err(|diagnostic| diagnostic.to_string())?;
+    let mut unknowns = parser.unknowns();
+    import("<repl>", &mut past, options, &mut vec![], &mut unknowns)?;
+    sandbox(&past, &options.capabilities)?;
+    Ok((name, past))
+}
+
+pub fn read_entry(
+    env: &[(String, TypeExpr)],
+    text: String,
+    options: &CompilerOptions,
+) -> Result<Entry, String> {
+    let (name, past) = parse_entry(&text, options)?;
+    entry(&mut env.to_vec(), name, text.trim().to_string(), past)
+}
+
+// reads a polymorphic definition entered at the REPL (in the environment it
+// was entered in) again as the copy of what it defines at the types a later
+// entry uses it at: 'let f<int> : int -> int = (let f = ... in f) in f<int>'
+pub fn read_copy(
+    env: &[(String, TypeExpr)],
+    text: &str,
+    copy: &Copied,
+    options: &CompilerOptions,
+) -> Result<Entry, String> {
+    let (_, past) = parse_entry(text, options)?;
+    let (names, type_expr) = match types::scheme(env, &mut vec![], &past) {
+        Some(TypeExpr::Forall(names, type_expr)) => (names, type_expr),
+        // it was polymorphic when it was entered
+        _ => unreachable!(),
+    };
+    let type_expr = type_expr.replace(&mut |type_expr| match *type_expr {
+        TypeExpr::Variable(ref name) => names
+            .iter()
+            .position(|other| other == name)
+            .map(|k| copy.types[k].clone()),
+        _ => None,
+    });
+    let location = past.location().clone();
+    let name = (location.clone(), past::Expr::Var(copy.name.clone())).into();
+    let past = past::Expr::Let(copy.name.clone(), type_expr, Box::new(past), Box::new(name));
+    entry(
+        &mut env.to_vec(),
+        None,
+        text.to_string(),
+        (location, past).into(),
+    )
+}
+
+// reads a saved session back as the definitions that wrap the program (in
+// order), followed by whatever they wrap unless that's just '()'
+pub fn read_session(
+    env: &[(String, TypeExpr)],
+    filename: &str,
+    text: String,
+    options: &CompilerOptions,
+) -> Result<Vec<Entry>, String> {
+    let mut env = env.to_vec();
+    let mut past = parse(filename, text, options)?;
+    let mut entries = vec![];
+    loop {
+        let location = past.location().clone();
+        let (v, text, definition, body) = match past.into_raw() {
+            past::Expr::Let(v, type_expr, sub, body) => {
+                let text = format!(
+                    "let {}{} = {}",
+                    v,
+                    past::Annotation(&type_expr),
+                    sub.borrow_raw()
+                );
+                let name = (location.clone(), past::Expr::Var(v.clone())).into();
+                let definition = past::Expr::Let(v.clone(), type_expr, sub, Box::new(name));
+                (v, text, definition, body)
+            }
+            past::Expr::LetFun(f, (v, type_expr_v, sub), type_expr, body) => {
+                let text = format!(
+                    "let {} ({}{}){} = {}",
+                    f,
+                    v,
+                    past::Annotation(&type_expr_v),
+                    past::Annotation(&type_expr),
+                    sub.borrow_raw()
+                );
+                let name = (location.clone(), past::Expr::Var(f.clone())).into();
+                let definition =
+                    past::Expr::LetFun(f.clone(), (v, type_expr_v, sub), type_expr, Box::new(name));
+                (f, text, definition, body)
+            }
+            past::Expr::Unit => break,
+            expr => {
+                let text = format!("{}", expr);
+                entries.push(entry(&mut env, None, text, (location, expr).into())?);
+                break;
+            }
+        };
+        entries.push(entry(
+            &mut env,
+            Some(v),
+            text,
+            (location, definition).into(),
+        )?);
+        past = *body;
+    }
+    Ok(entries)
+}
+
+// writes definitions out as a program that binds each of them in turn (and
+// which can be read back with 'read_session')
+pub fn write_session(definitions: &[&str]) -> String {
+    let mut text = String::new();
+    for definition in definitions.iter() {
+        text.push_str(&format!("{} in\n", definition));
+    }
+    text.push_str("()\n");
+    for _ in definitions.iter() {
+        text.push_str("end\n");
+    }
+    text
 }
@@ -0,0 +1,995 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use super::datatypes;
+use super::past::{self, Datatype, Expr, Item, Pattern, Spec, Var};
+use super::types::TypeExpr;
+use super::{log, Locatable, Location};
+
+// what can be referred to in a module from outside it
+struct Exports {
+    name: Var,
+    // each type as it's known outside the module (where an abstract type is
+    // only known by its name)
+    types: Vec<(Var, TypeExpr)>,
+    vals: Vec<Var>,
+}
+
+// the type variables a 'let' or a signature's 'val' binds, each as the unknown
+// that takes its place, which the type checker has to make sure can be any type
+pub struct Quantified {
+    pub v: Var,
+    pub variables: Vec<(Var, usize)>,
+    pub location: Location,
+    pub expr: Expr,
+}
+
+struct Resolver {
+    // the modules in scope, innermost last
+    modules: Vec<Exports>,
+    // the types defined by the structures being resolved so far, innermost
+    // last
+    types: Vec<(Var, TypeExpr)>,
+    // the name of every module in the program
+    names: HashSet<Var>,
+    // the type that represents each abstract type (and each data type)
+    representations: HashMap<Var, TypeExpr>,
+    // each data type in the program, by its name
+    datatypes: HashMap<Var, Datatype>,
+    // the constructors in scope, innermost last, each with the data type it
+    // makes values of and whether it's given anything
+    constructors: Vec<(Var, Var, bool)>,
+    // the type variables in scope, innermost last
+    type_variables: Vec<(Var, TypeExpr)>,
+    quantified: Vec<Quantified>,
+    // the next unknown that isn't used in the program
+    next: usize,
+    errors: Vec<String>,
+}
+
+// what 'resolve' finds out about a program, for the type checker
+pub struct Resolved {
+    pub representations: HashMap<Var, TypeExpr>,
+    pub quantified: Vec<Quantified>,
+    pub datatypes: HashMap<Var, Datatype>,
+}
+
+// the type variables a 'forall' at the outside of an annotation binds
+fn quantified(type_expr: &TypeExpr) -> Vec<Var> {
+    match *type_expr {
+        TypeExpr::Forall(ref variables, _) => variables.clone(),
+        _ => vec![],
+    }
+}
+
+// an annotation without the 'forall' at the outside of it
+fn unquantify(type_expr: &mut TypeExpr) {
+    if let TypeExpr::Forall(_, ref mut sub) = *type_expr {
+        *type_expr = std::mem::replace(&mut **sub, TypeExpr::Error);
+    }
+}
+
+impl Resolver {
+    // what a type refers to by name, giving why it can't be resolved if it
+    // can't
+    fn type_expr(&self, type_expr: &TypeExpr) -> Result<TypeExpr, String> {
+        let annotation = type_expr;
+        let mut failure = None;
+        let resolved = type_expr.replace(&mut |type_expr| {
+            let name = match *type_expr {
+                TypeExpr::Named(ref name) => name,
+                TypeExpr::Variable(ref a) => {
+                    return Some(
+                        match self.type_variables.iter().rev().find(|(b, _)| b == a) {
+                            Some((_, type_expr)) => type_expr.clone(),
+                            None => {
+                                failure.get_or_insert(format!(
+                                    "type variable '{}' isn't bound here (a 'let' binds type variables with a 'forall' at the outside of its annotation, and a function binds the ones in its annotations)",
+                                    type_expr
+                                ));
+                                TypeExpr::Error
+                            }
+                        },
+                    )
+                }
+                TypeExpr::Forall(_, _) => {
+                    failure.get_or_insert(format!(
+                        "a 'forall' can only be at the outside of the type of something a 'let' or a 'val' gives a name to (anywhere else, as in '{}', it would make a higher-rank type, which can't be checked)",
+                        annotation
+                    ));
+                    return Some(TypeExpr::Error);
+                }
+                _ => return None,
+            };
+            let resolved = match name.split_once('.') {
+                Some((module, t)) => match self.modules.iter().rev().find(|m| m.name == module) {
+                    Some(exports) => match exports.types.iter().rev().find(|(v, _)| v == t) {
+                        Some((_, type_expr)) => Ok(type_expr.clone()),
+                        None => Err(format!(
+                            "module '{}' doesn't export a type called '{}'",
+                            module, t
+                        )),
+                    },
+                    None => Err(format!("there's no module called '{}' here", module)),
+                },
+                None => match self.types.iter().rev().find(|(v, _)| v == name) {
+                    Some((_, type_expr)) => Ok(type_expr.clone()),
+                    None => Err(format!("there's no type called '{}' here", name)),
+                },
+            };
+            Some(resolved.unwrap_or_else(|message| {
+                failure.get_or_insert(message);
+                TypeExpr::Error
+            }))
+        });
+        match failure {
+            Some(message) => Err(message),
+            None => Ok(resolved),
+        }
+    }
+
+    // resolves an annotation in place, recording why if it can't be
+    fn annotation(&self, type_expr: &mut TypeExpr, failures: &mut Vec<String>) {
+        match self.type_expr(type_expr) {
+            Ok(resolved) => *type_expr = resolved,
+            Err(message) => failures.push(message),
+        }
+    }
+
+    // whether a name that refers to something in a module ('M.x') refers to
+    // something the module exports
+    fn qualified(&self, name: &str) -> Result<(), String> {
+        let (module, v) = match name.split_once('.') {
+            Some(qualified) => qualified,
+            None => return Ok(()),
+        };
+        match self.modules.iter().rev().find(|m| m.name == module) {
+            Some(exports) if exports.vals.iter().any(|val| val == v) => Ok(()),
+            Some(_) => Err(format!("module '{}' doesn't export '{}'", module, v)),
+            None => Err(format!("there's no module called '{}' here", module)),
+        }
+    }
+
+    // the data type a constructor in scope makes values of, and whether it's
+    // given anything
+    fn constructor(&self, c: &str) -> Result<(Var, bool), String> {
+        match self.constructors.iter().rev().find(|(d, _, _)| d == c) {
+            Some((_, t, payload)) => Ok((t.clone(), *payload)),
+            None => Err(format!(
+                "there's no constructor called '{}' here{}",
+                c,
+                self.suggest(c)
+            )),
+        }
+    }
+
+    // the constructor in scope most like one that isn't, with the others of
+    // its data type, or else every constructor in scope
+    fn suggest(&self, c: &str) -> String {
+        let mut visible: Vec<(&str, &str)> = vec![];
+        for (d, t, _) in self.constructors.iter().rev() {
+            if !visible.iter().any(|(e, _)| e == d) {
+                visible.push((d, t));
+            }
+        }
+        visible.reverse();
+        let names = visible.iter().map(|(d, _)| *d).collect::<Vec<_>>();
+        match log::closest(c, names.iter().copied()) {
+            Some(d) => {
+                let t = visible.iter().find(|(e, _)| *e == d).unwrap().1;
+                let siblings = visible
+                    .iter()
+                    .filter(|(_, u)| *u == t)
+                    .map(|(e, _)| *e)
+                    .collect::<Vec<_>>();
+                format!(
+                    " (did you mean '{}'? '{}' has {})",
+                    d,
+                    t,
+                    log::listed(&siblings)
+                )
+            }
+            None if names.is_empty() => String::new(),
+            None => format!(" (the ones here are {})", log::listed(&names)),
+        }
+    }
+
+    // names the data type of each constructor in a pattern
+    fn pattern(&self, pattern: &mut Pattern, failures: &mut Vec<String>) {
+        match *pattern {
+            Pattern::Pair(ref mut left, ref mut right)
+            | Pattern::Cons(ref mut left, ref mut right) => {
+                self.pattern(left, failures);
+                self.pattern(right, failures);
+            }
+            Pattern::Tuple(ref mut patterns) => {
+                for sub in patterns.iter_mut() {
+                    self.pattern(sub, failures);
+                }
+            }
+            Pattern::Inl(ref mut sub) | Pattern::Inr(ref mut sub) => self.pattern(sub, failures),
+            Pattern::Construct(ref mut t, ref c, ref mut sub) => {
+                match self.constructor(c) {
+                    Ok((datatype, _)) => *t = datatype,
+                    Err(message) => failures.push(message),
+                }
+                if let Some(ref mut sub) = *sub {
+                    self.pattern(sub, failures);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // puts type variables in scope, each standing for a fresh unknown, giving
+    // what they stand for
+    fn bind(&mut self, variables: Vec<Var>) -> Vec<(Var, usize)> {
+        let mut bound = vec![];
+        for a in variables {
+            self.type_variables
+                .push((a.clone(), TypeExpr::Unknown(self.next)));
+            bound.push((a, self.next));
+            self.next += 1;
+        }
+        bound
+    }
+
+    // the type variables in a function's annotations that aren't bound outside
+    // it, which the function binds itself
+    fn unbound(&self, annotations: &[&TypeExpr]) -> Vec<Var> {
+        let mut unbound = vec![];
+        for type_expr in annotations {
+            type_expr.replace(&mut |type_expr| {
+                if let TypeExpr::Variable(ref a) = *type_expr {
+                    if self.type_variables.iter().all(|(b, _)| b != a) && !unbound.contains(a) {
+                        unbound.push(a.clone());
+                    }
+                }
+                None
+            });
+        }
+        unbound
+    }
+
+    // records what a 'let' or a 'val' binds for the type checker, leaving out
+    // any type variable its type doesn't mention (which can't stand for
+    // anything in particular anyway)
+    fn quantify(
+        &mut self,
+        v: &Var,
+        bound: Vec<(Var, usize)>,
+        type_expr: &TypeExpr,
+        location: &Location,
+        expr: &Option<Expr>,
+    ) {
+        let unknowns = type_expr.unknowns();
+        let variables = bound
+            .into_iter()
+            .filter(|(_, n)| unknowns.contains(n))
+            .collect::<Vec<_>>();
+        match *expr {
+            Some(ref expr) if !variables.is_empty() => self.quantified.push(Quantified {
+                v: v.clone(),
+                variables,
+                location: location.clone(),
+                expr: expr.clone(),
+            }),
+            _ => (),
+        }
+    }
+
+    // resolves a 'let' with the type variables it binds in scope for its
+    // annotations and what it defines (but not for the rest of the program),
+    // giving what's in its scope
+    fn binding<'a>(&mut self, expr: &'a mut Locatable<Expr>) -> &'a mut Locatable<Expr> {
+        if let Expr::LetRec(..) = expr.t {
+            return self.group(expr);
+        }
+        let depth = self.type_variables.len();
+        let (v, bound) = match expr.t {
+            Expr::Let(ref v, ref type_expr, _, _) => (v.clone(), self.bind(quantified(type_expr))),
+            Expr::LetFun(ref f, (_, ref type_expr_v, _), ref type_expr, _) => {
+                let unbound = self.unbound(&[type_expr_v, type_expr]);
+                (f.clone(), self.bind(unbound))
+            }
+            _ => unreachable!(),
+        };
+        // as it was written, for reporting what it binds
+        let raw = if bound.is_empty() {
+            None
+        } else {
+            Some(expr.borrow_raw().clone())
+        };
+        if let Expr::Let(_, ref mut type_expr, _, _) = expr.t {
+            unquantify(type_expr);
+        }
+        let mut failures = vec![];
+        for type_expr in expr.t.annotations_mut() {
+            self.annotation(type_expr, &mut failures);
+        }
+        let type_expr = match expr.t {
+            Expr::Let(_, ref type_expr, _, _) => type_expr.clone(),
+            Expr::LetFun(_, (_, ref type_expr_v, _), ref type_expr, _) => {
+                TypeExpr::Arrow(Box::new(type_expr_v.clone()), Box::new(type_expr.clone()))
+            }
+            _ => unreachable!(),
+        };
+        self.quantify(&v, bound, &type_expr, expr.location(), &raw);
+        for message in failures {
+            self.errors
+                .push(log::type_error(expr.location(), message, expr.borrow_raw()));
+        }
+        let (sub, body) = match expr.t {
+            Expr::Let(_, _, ref mut sub, ref mut body)
+            | Expr::LetFun(_, (_, _, ref mut sub), _, ref mut body) => (sub, body),
+            _ => unreachable!(),
+        };
+        self.expr(sub);
+        self.type_variables.truncate(depth);
+        body
+    }
+
+    // resolves a 'let rec' in the same way, with the type variables any of its
+    // functions' annotations bind in scope for all of them
+    fn group<'a>(&mut self, expr: &'a mut Locatable<Expr>) -> &'a mut Locatable<Expr> {
+        let depth = self.type_variables.len();
+        let bound = {
+            let annotations = expr.t.annotations();
+            let unbound = self.unbound(&annotations);
+            self.bind(unbound)
+        };
+        let raw = if bound.is_empty() {
+            None
+        } else {
+            Some(expr.borrow_raw().clone())
+        };
+        let mut failures = vec![];
+        for type_expr in expr.t.annotations_mut() {
+            self.annotation(type_expr, &mut failures);
+        }
+        let types = match expr.t {
+            Expr::LetRec(ref functions, _) => functions
+                .iter()
+                .map(|(f, (_, ref type_expr_v, _), ref type_expr)| {
+                    let type_expr =
+                        TypeExpr::Arrow(Box::new(type_expr_v.clone()), Box::new(type_expr.clone()));
+                    (f.clone(), type_expr)
+                })
+                .collect::<Vec<_>>(),
+            _ => unreachable!(),
+        };
+        for (f, type_expr) in types.iter() {
+            self.quantify(f, bound.clone(), type_expr, expr.location(), &raw);
+        }
+        for message in failures {
+            self.errors
+                .push(log::type_error(expr.location(), message, expr.borrow_raw()));
+        }
+        let (functions, body) = match expr.t {
+            Expr::LetRec(ref mut functions, ref mut body) => (functions, body),
+            _ => unreachable!(),
+        };
+        for (_, (_, _, ref mut sub), _) in functions.iter_mut() {
+            self.expr(sub);
+        }
+        self.type_variables.truncate(depth);
+        body
+    }
+
+    // 'r.x' takes a field from a record, unless 'r' is a module (a name that
+    // starts with a capital letter is always taken to be one)
+    fn field(&mut self, expr: &mut Locatable<Expr>) {
+        if let Expr::Var(ref v) = expr.t {
+            if let Some((r, x)) = v.split_once('.') {
+                if !r.starts_with(|c: char| c.is_ascii_uppercase())
+                    && self.modules.iter().all(|m| m.name != r)
+                {
+                    let record = (expr.location().clone(), Expr::Var(r.to_string())).into();
+                    expr.t = Expr::Field(
+                        Box::new(record),
+                        x.to_string(),
+                        TypeExpr::Unknown(self.next),
+                    );
+                    self.next += 1;
+                }
+            }
+        }
+    }
+
+    // every field taken from a record in an expression, as 'field' finds them
+    fn fields(&mut self, expr: &mut Locatable<Expr>) {
+        self.field(expr);
+        let children = match expr.t {
+            Expr::BinOp(..) => past::operands_mut(expr),
+            _ => expr.t.children_mut(),
+        };
+        for sub in children {
+            self.fields(sub);
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Locatable<Expr>) {
+        match expr.t {
+            Expr::BinOp(..) => {
+                for sub in past::operands_mut(expr) {
+                    self.expr(sub);
+                }
+                return;
+            }
+            Expr::Module(_, _) => return self.module(expr),
+            Expr::Open(_, _) => return self.open(expr),
+            // a chain of definitions ('let x = e in let y = e in ...') is
+            // resolved in a loop, as a program of top-level definitions is one
+            Expr::Let(..) | Expr::LetFun(..) | Expr::LetRec(..) => {
+                let mut expr = expr;
+                while let Expr::Let(..) | Expr::LetFun(..) | Expr::LetRec(..) = expr.t {
+                    expr = self.binding(expr);
+                }
+                return self.expr(expr);
+            }
+            Expr::Data(_, _) => return self.datatype(expr),
+            _ => (),
+        }
+        self.field(expr);
+        // a constructor applied to what it's given is made from it directly
+        // (rather than by a function that makes it)
+        if let Expr::App(ref mut left, ref mut right) = expr.t {
+            if let Expr::Construct(_, ref c, None) = left.t {
+                if let Ok((_, true)) = self.constructor(c) {
+                    let right = std::mem::replace(
+                        &mut **right,
+                        (left.location().clone(), Expr::Unit).into(),
+                    );
+                    expr.t = Expr::Construct(String::new(), c.clone(), Some(Box::new(right)));
+                }
+            }
+        }
+        let mut failures = vec![];
+        match expr.t {
+            Expr::Var(ref v) => failures.extend(self.qualified(v).err()),
+            Expr::Construct(ref mut t, ref c, _) => match self.constructor(c) {
+                Ok((datatype, _)) => *t = datatype,
+                Err(message) => failures.push(message),
+            },
+            Expr::Match(_, _, ref mut arms, _) => {
+                for (pattern, _) in arms.iter_mut() {
+                    self.pattern(pattern, &mut failures);
+                }
+            }
+            _ => (),
+        }
+        for type_expr in expr.t.annotations_mut() {
+            self.annotation(type_expr, &mut failures);
+        }
+        for message in failures {
+            self.errors
+                .push(log::type_error(expr.location(), message, expr.borrow_raw()));
+        }
+        for sub in expr.t.children_mut() {
+            self.expr(sub);
+        }
+    }
+
+    // resolves an 'open' by giving everything the module exports its name in
+    // the module wherever it's referred to without it (and isn't shadowed),
+    // leaving what the module is opened in with its types in scope too
+    fn open(&mut self, expr: &mut Locatable<Expr>) {
+        let name = match expr.t {
+            Expr::Open(ref name, _) => name.clone(),
+            _ => unreachable!(),
+        };
+        let (types, vals) = match self.modules.iter().rev().find(|m| m.name == name) {
+            Some(exports) => (exports.types.clone(), exports.vals.clone()),
+            None => {
+                let message = format!("there's no module called '{}' here", name);
+                self.errors
+                    .push(log::type_error(expr.location(), message, expr.borrow_raw()));
+                (vec![], vec![])
+            }
+        };
+        let location = expr.location().clone();
+        let body = match expr.t {
+            Expr::Open(_, ref mut body) => body,
+            _ => unreachable!(),
+        };
+        // a field taken from something the module exports ('r.x') is found
+        // before 'r' is renamed, which would hide it
+        self.fields(body);
+        let names = vals
+            .iter()
+            .map(|v| (v.clone(), format!("{}.{}", name, v)))
+            .collect();
+        rename(body, &names);
+        let depth = self.types.len();
+        self.types.extend(types);
+        self.expr(body);
+        self.types.truncate(depth);
+        let body = std::mem::replace(&mut **body, (location, Expr::Unit).into());
+        *expr = body;
+    }
+
+    // resolves what a data type's constructors are given, then the rest of the
+    // program with the data type and its constructors in scope
+    fn datatype(&mut self, expr: &mut Locatable<Expr>) {
+        let (datatype, body) = match expr.t {
+            Expr::Data(ref mut datatype, ref mut body) => (datatype, body),
+            _ => unreachable!(),
+        };
+        let mut failures = vec![];
+        // data types are only known by their names until the program has been
+        // checked, so two with the same name would be mistaken for each other
+        if self.datatypes.contains_key(&datatype.name) {
+            failures.push(format!(
+                "there's already a type called '{}' (each data type needs a name of its own)",
+                datatype.name
+            ));
+        }
+        for (i, (c, _)) in datatype.constructors.iter().enumerate() {
+            if datatype.constructors[..i].iter().any(|(d, _)| d == c) {
+                failures.push(format!(
+                    "'{}' has more than one constructor called '{}'",
+                    datatype.name, c
+                ));
+            }
+        }
+        let name = datatype.name.clone();
+        for (c, type_expr) in datatype.constructors.iter_mut() {
+            let type_expr = match *type_expr {
+                Some(ref mut type_expr) => type_expr,
+                None => continue,
+            };
+            let mut recursive = false;
+            let mut unknown = false;
+            type_expr.replace(&mut |type_expr| {
+                match *type_expr {
+                    TypeExpr::Named(ref t) if *t == name => recursive = true,
+                    TypeExpr::Variable(_) | TypeExpr::Hole => unknown = true,
+                    _ => (),
+                }
+                None
+            });
+            if recursive {
+                failures.push(format!(
+                    "constructor '{}' can't be given a '{}', as a data type can't refer to itself",
+                    c, name
+                ));
+            } else if unknown {
+                failures.push(format!(
+                    "constructor '{}' has to be given something of a type with no '_' or type variable in it ('{}' has one), as a data type can't be polymorphic",
+                    c, type_expr
+                ));
+            } else {
+                self.annotation(type_expr, &mut failures);
+            }
+        }
+        let representation = datatypes::representation(datatype).expand(&self.representations);
+        self.representations
+            .insert(datatype.name.clone(), representation);
+        self.datatypes
+            .insert(datatype.name.clone(), datatype.clone());
+        let depth = self.types.len();
+        let depth_constructors = self.constructors.len();
+        self.types.push((
+            datatype.name.clone(),
+            TypeExpr::Named(datatype.name.clone()),
+        ));
+        for (c, type_expr) in datatype.constructors.iter() {
+            self.constructors
+                .push((c.clone(), datatype.name.clone(), type_expr.is_some()));
+        }
+        self.expr(body);
+        self.types.truncate(depth);
+        self.constructors.truncate(depth_constructors);
+        for message in failures {
+            self.errors
+                .push(log::type_error(expr.location(), message, expr.borrow_raw()));
+        }
+    }
+
+    // resolves what a module's structure defines, each in the scope of the
+    // types defined before it, then its signature, which is what's in scope
+    // for the rest of the program
+    fn module(&mut self, expr: &mut Locatable<Expr>) {
+        let location = expr.location().clone();
+        let raw = Some(expr.borrow_raw().clone());
+        let (module, body) = match expr.t {
+            Expr::Module(ref mut module, ref mut body) => (module, body),
+            _ => unreachable!(),
+        };
+        let mut failures = vec![];
+        // two modules with the same name would have abstract types with the
+        // same name too, so one could be mistaken for the other
+        if !self.names.insert(module.name.clone()) {
+            failures.push(format!(
+                "there's already a module called '{}' (each module needs a name of its own)",
+                module.name
+            ));
+        }
+        let depth = self.types.len();
+        let depth_variables = self.type_variables.len();
+        let mut vals = vec![];
+        for item in module.items.iter_mut() {
+            match *item {
+                Item::Type(ref t, ref mut type_expr) => {
+                    *type_expr = self.type_expr(type_expr).unwrap_or_else(|message| {
+                        failures.push(message);
+                        TypeExpr::Error
+                    });
+                    self.types.push((t.clone(), type_expr.clone()));
+                }
+                Item::Let(ref v, ref mut type_expr, ref mut sub) => {
+                    let bound = self.bind(quantified(type_expr));
+                    unquantify(type_expr);
+                    self.annotation(type_expr, &mut failures);
+                    self.quantify(v, bound, type_expr, &location, &raw);
+                    self.expr(sub);
+                    self.type_variables.truncate(depth_variables);
+                    vals.push(v.clone());
+                }
+                Item::LetFun(ref f, (_, ref mut type_expr_v, ref mut sub), ref mut type_expr) => {
+                    let bound = self.bind(self.unbound(&[type_expr_v, type_expr]));
+                    self.annotation(type_expr_v, &mut failures);
+                    self.annotation(type_expr, &mut failures);
+                    let fun_type_expr =
+                        TypeExpr::Arrow(Box::new(type_expr_v.clone()), Box::new(type_expr.clone()));
+                    self.quantify(f, bound, &fun_type_expr, &location, &raw);
+                    self.expr(sub);
+                    self.type_variables.truncate(depth_variables);
+                    vals.push(f.clone());
+                }
+            }
+        }
+        let types = self.types.split_off(depth);
+        let exports = match module.signature {
+            None => Exports {
+                name: module.name.clone(),
+                types,
+                vals,
+            },
+            Some(ref mut signature) => {
+                let mut exports = Exports {
+                    name: module.name.clone(),
+                    types: vec![],
+                    vals: vec![],
+                };
+                for spec in signature.iter_mut() {
+                    match *spec {
+                        Spec::Type(ref t) => {
+                            let abstract_type = format!("{}.{}", module.name, t);
+                            match types.iter().rev().find(|(v, _)| v == t) {
+                                Some((_, type_expr)) => {
+                                    self.representations
+                                        .insert(abstract_type.clone(), type_expr.clone());
+                                }
+                                None => failures.push(format!(
+                                    "the signature of '{}' has a type '{}' that its structure doesn't define",
+                                    module.name, t
+                                )),
+                            }
+                            // the signature's own types refer to the
+                            // abstract ones
+                            self.types
+                                .push((t.clone(), TypeExpr::Named(abstract_type.clone())));
+                            exports
+                                .types
+                                .push((t.clone(), TypeExpr::Named(abstract_type)));
+                        }
+                        Spec::Val(ref v, ref mut type_expr) => {
+                            let bound = self.bind(quantified(type_expr));
+                            unquantify(type_expr);
+                            self.annotation(type_expr, &mut failures);
+                            self.quantify(v, bound, type_expr, &location, &raw);
+                            self.type_variables.truncate(depth_variables);
+                            if !vals.contains(v) {
+                                failures.push(format!(
+                                    "the signature of '{}' has a value '{}' that its structure doesn't define",
+                                    module.name, v
+                                ));
+                            }
+                            exports.vals.push(v.clone());
+                        }
+                    }
+                }
+                self.types.truncate(depth);
+                exports
+            }
+        };
+        self.modules.push(exports);
+        self.expr(body);
+        self.modules.pop();
+        for message in failures {
+            self.errors
+                .push(log::type_error(expr.location(), message, expr.borrow_raw()));
+        }
+    }
+}
+
+// resolves the names of the types in a program's annotations (replacing each
+// with what it names, or leaving it as it is if it names an abstract type) and
+// of the things it refers to in modules, checking that each module exports
+// what's referred to in it. This gives the type that represents each abstract
+// type, which it can be replaced by once the program has been checked. Each type
+// variable is replaced by an unknown too, which is recorded with what binds it
+pub fn resolve(expr: &mut Locatable<Expr>) -> Result<Resolved, String> {
+    let mut next = 0;
+    let mut work = vec![&*expr];
+    while let Some(expr) = work.pop() {
+        for type_expr in expr.borrow_raw().annotations() {
+            next = next.max(type_expr.unknowns().into_iter().max().map_or(0, |n| n + 1));
+        }
+        work.extend(expr.borrow_raw().children());
+    }
+    let mut resolver = Resolver {
+        modules: vec![],
+        types: vec![],
+        names: HashSet::new(),
+        representations: HashMap::new(),
+        datatypes: HashMap::new(),
+        constructors: vec![],
+        type_variables: vec![],
+        quantified: vec![],
+        next,
+        errors: vec![],
+    };
+    resolver.expr(expr);
+    if resolver.errors.is_empty() {
+        Ok(Resolved {
+            representations: resolver.representations,
+            quantified: resolver.quantified,
+            datatypes: resolver.datatypes,
+        })
+    } else {
+        Err(resolver.errors.join("\n"))
+    }
+}
+
+// renames every free occurrence of each variable in 'names' to what it's
+// mapped to (the new names are always ones that a program can't bind itself,
+// so they can't be captured)
+fn rename(expr: &mut Locatable<Expr>, names: &HashMap<Var, Var>) {
+    if names.is_empty() {
+        return;
+    }
+    match expr.t {
+        Expr::Var(ref mut v) => {
+            if let Some(to) = names.get(v) {
+                *v = to.clone();
+            }
+        }
+        Expr::Try(ref mut sub, (ref v, _, ref mut handler)) => {
+            rename(sub, names);
+            rename(handler, &hide(names, &[v]));
+        }
+        Expr::For(ref v, ref mut start, ref mut end, ref mut body) => {
+            rename(start, names);
+            rename(end, names);
+            rename(body, &hide(names, &[v]));
+        }
+        Expr::Match(ref mut sub, _, ref mut arms, _) => {
+            rename(sub, names);
+            for (pattern, body) in arms.iter_mut() {
+                rename(body, &hide(names, &pattern.vars()));
+            }
+        }
+        Expr::Lambda((ref v, _, ref mut body)) => rename(body, &hide(names, &[v])),
+        Expr::Case(ref mut sub, (ref v_left, _, ref mut left), (ref v_right, _, ref mut right)) => {
+            rename(sub, names);
+            rename(left, &hide(names, &[v_left]));
+            rename(right, &hide(names, &[v_right]));
+        }
+        Expr::Let(ref v, _, ref mut sub, ref mut body) => {
+            rename(sub, names);
+            rename(body, &hide(names, &[v]));
+        }
+        Expr::LetFun(ref f, (ref v, _, ref mut sub), _, ref mut body) => {
+            rename(sub, &hide(names, &[f, v]));
+            rename(body, &hide(names, &[f]));
+        }
+        Expr::LetRec(ref mut functions, ref mut body) => {
+            let names = hide(
+                names,
+                &functions.iter().map(|(f, _, _)| f).collect::<Vec<_>>(),
+            );
+            for (_, (ref v, _, ref mut sub), _) in functions.iter_mut() {
+                rename(sub, &hide(&names, &[v]));
+            }
+            rename(body, &names);
+        }
+        // what a module defines is only in scope without its name in front of
+        // it for what the module defines after it
+        Expr::Module(ref mut module, ref mut body) => {
+            let mut hidden = Cow::Borrowed(names);
+            for item in module.items.iter_mut() {
+                match *item {
+                    Item::Type(_, _) => (),
+                    Item::Let(ref v, _, ref mut sub) => {
+                        rename(sub, &hidden);
+                        hidden = Cow::Owned(hide(&hidden, &[v]).into_owned());
+                    }
+                    Item::LetFun(ref f, (ref v, _, ref mut sub), _) => {
+                        hidden = Cow::Owned(hide(&hidden, &[f]).into_owned());
+                        rename(sub, &hide(&hidden, &[v]));
+                    }
+                }
+            }
+            rename(body, names);
+        }
+        Expr::BinOp(..) => {
+            for sub in past::operands_mut(expr) {
+                rename(sub, names);
+            }
+        }
+        _ => {
+            for sub in expr.t.children_mut() {
+                rename(sub, names);
+            }
+        }
+    }
+}
+
+// the variables to rename in the scope of binders for 'vars', which hide them
+fn hide<'a>(names: &'a HashMap<Var, Var>, vars: &[&Var]) -> Cow<'a, HashMap<Var, Var>> {
+    if vars.iter().any(|v| names.contains_key(*v)) {
+        let mut names = names.clone();
+        for v in vars {
+            names.remove(*v);
+        }
+        Cow::Owned(names)
+    } else {
+        Cow::Borrowed(names)
+    }
+}
+
+// adds every variable that occurs in an expression to 'vars' (which is only
+// used for the ones a module defines, as a name like 'M.x' can't be bound
+// anywhere else, so can't be shadowed)
+fn mentioned(expr: &Locatable<Expr>, vars: &mut HashSet<Var>) {
+    let mut work = vec![expr];
+    while let Some(expr) = work.pop() {
+        if let Expr::Var(ref v) = expr.t {
+            vars.insert(v.clone());
+        }
+        work.extend(expr.t.children());
+    }
+}
+
+// whether evaluating an expression can only give its value (without any effect
+// that leaving it out would change)
+fn value(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Unit | Expr::Int(_) | Expr::Bool(_) | Expr::Var(_) => true,
+        Expr::Lambda(_) | Expr::Lazy(_) => true,
+        Expr::Inl(ref sub, _) | Expr::Inr(ref sub, _) => value(sub.borrow_raw()),
+        Expr::Pair(ref left, ref right) => value(left.borrow_raw()) && value(right.borrow_raw()),
+        Expr::Tuple(ref subs) => subs.iter().all(|sub| value(sub.borrow_raw())),
+        _ => false,
+    }
+}
+
+// turns each module into the 'let's its structure is made of, once the program
+// has been checked, so that the rest of the compiler never sees one. Each
+// value a module defines is bound to its name in the module ('M.x'), and every
+// abstract type is replaced by the type that represents it. Anything a module
+// defines that nothing the rest of the program runs refers to (directly, or
+// through something else the module defines) is reported, and left out if
+// 'strip' is set and leaving it out can't change what the program does
+pub fn erase(
+    expr: &mut Locatable<Expr>,
+    representations: &HashMap<Var, TypeExpr>,
+    strip: bool,
+    warnings: &mut Vec<String>,
+) {
+    // a chain of definitions ('let x = e in let y = e in ...') is walked
+    // down in a loop, as a program of top-level definitions is one (and
+    // there's nothing to erase from a 'let' itself)
+    let mut expr = expr;
+    while let Expr::Let(..) | Expr::LetFun(..) | Expr::LetRec(..) = expr.t {
+        for type_expr in expr.t.annotations_mut() {
+            *type_expr = type_expr.expand(representations);
+        }
+        let mut children = expr.t.children_mut();
+        let body = children.pop().unwrap();
+        for sub in children {
+            erase(sub, representations, strip, warnings);
+        }
+        expr = body;
+    }
+    if let Expr::BinOp(..) = expr.t {
+        for sub in past::operands_mut(expr) {
+            erase(sub, representations, strip, warnings);
+        }
+        return;
+    }
+    for type_expr in expr.t.annotations_mut() {
+        *type_expr = type_expr.expand(representations);
+    }
+    for sub in expr.t.children_mut() {
+        erase(sub, representations, strip, warnings);
+    }
+    let location = expr.location().clone();
+    let (module, body) = match expr.t {
+        Expr::Module(ref mut module, ref mut body) => (module, body),
+        _ => return,
+    };
+    let mut bindings = vec![];
+    let mut defined = HashMap::new();
+    for item in module.items.iter_mut() {
+        match *item {
+            Item::Type(_, _) => (),
+            Item::Let(ref v, ref type_expr, ref mut sub) => {
+                rename(sub, &defined);
+                bindings.push((v.clone(), type_expr.clone(), sub.clone(), None));
+                defined.insert(v.clone(), format!("{}.{}", module.name, v));
+            }
+            Item::LetFun(ref f, (ref v, ref type_expr_v, ref mut sub), ref type_expr) => {
+                defined.insert(f.clone(), format!("{}.{}", module.name, f));
+                rename(sub, &hide(&defined, &[v]));
+                bindings.push((
+                    f.clone(),
+                    type_expr.clone(),
+                    sub.clone(),
+                    Some((v.clone(), type_expr_v.clone())),
+                ));
+            }
+        }
+    }
+    // what's used is found from the last definition back, as each can only
+    // be referred to by what comes after it
+    let mut live = HashSet::new();
+    mentioned(body, &mut live);
+    let mut used = vec![false; bindings.len()];
+    for (i, (v, _, sub, _)) in bindings.iter().enumerate().rev() {
+        if live.contains(&format!("{}.{}", module.name, v)) {
+            used[i] = true;
+            mentioned(sub, &mut live);
+        }
+    }
+    if !bindings.is_empty() && !used.contains(&true) {
+        warnings.push(log::warning(
+            &location,
+            format!(
+                "module '{}' is never used (nothing the rest of the program runs refers to anything it defines)",
+                module.name
+            ),
+            &Expr::Var(module.name.clone()),
+        ));
+    } else {
+        for (i, (v, _, sub, param)) in bindings.iter().enumerate() {
+            if used[i] {
+                continue;
+            }
+            let definition = match *param {
+                None => sub.borrow_raw().clone(),
+                Some((ref param, ref type_expr_param)) => {
+                    Expr::Lambda((param.clone(), type_expr_param.clone(), sub.clone()))
+                }
+            };
+            warnings.push(log::warning(
+                sub.location(),
+                format!(
+                    "'{}.{}' is never used (neither the rest of the program nor anything used in '{}' refers to it)",
+                    module.name, v, module.name
+                ),
+                &definition,
+            ));
+        }
+    }
+    let mut erased = (**body).clone();
+    for (i, (v, type_expr, sub, param)) in bindings.into_iter().enumerate().rev() {
+        if strip && !used[i] && (param.is_some() || value(sub.borrow_raw())) {
+            continue;
+        }
+        let v = format!("{}.{}", module.name, v);
+        let binding = match param {
+            None => Expr::Let(v, type_expr, sub, Box::new(erased)),
+            Some((param, type_expr_param)) => Expr::LetFun(
+                v,
+                (param, type_expr_param, sub),
+                type_expr,
+                Box::new(erased),
+            ),
+        };
+        erased = (location.clone(), binding).into();
+    }
+    *expr = erased;
+}
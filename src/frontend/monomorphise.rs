@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+
+use super::past::{self, Expr, Var};
+use super::types::TypeExpr;
+use super::Locatable;
+
+// the types each polymorphic value is used at, as what each of its type
+// variables (the unknowns it was generalised over) stands for
+pub struct Instances {
+    // by where each use is (its line and column)
+    pub uses: HashMap<(usize, usize), Vec<(usize, TypeExpr)>>,
+    // by name, for a value a module exports at the type its signature gives,
+    // which is what every use of it outside the module uses it at (along with
+    // what the signature's own type variables stand for there)
+    pub exports: HashMap<Var, Vec<(usize, TypeExpr)>>,
+}
+
+// a copy a program needs of a polymorphic value defined before it (at the
+// REPL), which has to be made outside the program, as it's only referred to in
+// it
+pub struct Copied {
+    pub v: Var,
+    // the name the program refers to the copy by
+    pub name: Var,
+    // what the value's type variables stand for in the copy, in the order its
+    // 'forall' gives them
+    pub types: Vec<TypeExpr>,
+}
+
+// what a name in scope refers to
+enum Binding {
+    // a value with a single type, by the name it has now
+    Monomorphic(Var),
+    // a polymorphic value, which is copied for each type it's used at
+    Polymorphic {
+        type_variables: Vec<usize>,
+        // what its type variables stand for in each copy, in the order the
+        // copies were first needed
+        copies: Vec<Vec<TypeExpr>>,
+    },
+    // a function defined in a 'let rec' after the first, which is copied along
+    // with the rest of them (the binding of the first, by where it is in
+    // scope, says what they're all copied at)
+    Sibling(usize),
+}
+
+struct Monomorphiser<'a> {
+    instances: &'a Instances,
+    // what the type variables of the copies being made stand for
+    types: HashMap<usize, TypeExpr>,
+    scope: Vec<(Var, Binding)>,
+}
+
+impl<'a> Monomorphiser<'a> {
+    // a type as it is in the copy being made, where anything still unknown
+    // (which nothing in the program says anything about) is taken to be 'unit'
+    fn specialise(&self, type_expr: &TypeExpr) -> TypeExpr {
+        type_expr.replace(&mut |type_expr| match *type_expr {
+            TypeExpr::Unknown(n) => Some(self.types.get(&n).cloned().unwrap_or(TypeExpr::Unit)),
+            _ => None,
+        })
+    }
+
+    // the type variables in a type, other than those of the copies being made
+    fn type_variables(&self, type_expr: &TypeExpr) -> Vec<usize> {
+        let mut type_variables = vec![];
+        for n in type_expr.unknowns() {
+            if !self.types.contains_key(&n) && !type_variables.contains(&n) {
+                type_variables.push(n);
+            }
+        }
+        type_variables
+    }
+
+    // the name a variable has in the copy being made, which for a polymorphic
+    // value is the name of its copy at the types it's used at here
+    fn name(&mut self, expr: &Locatable<Expr>, v: &str) -> Var {
+        let i = match self.scope.iter().rposition(|(name, _)| name == v) {
+            Some(i) => i,
+            // anything else has a single type (what's defined before the
+            // program, at the REPL, is in scope from the start)
+            None => return v.to_string(),
+        };
+        let i = match self.scope[i].1 {
+            Binding::Sibling(first) => first,
+            _ => i,
+        };
+        let type_variables = match self.scope[i].1 {
+            Binding::Monomorphic(ref name) => return name.clone(),
+            Binding::Polymorphic {
+                ref type_variables, ..
+            } => type_variables.clone(),
+            Binding::Sibling(_) => unreachable!(),
+        };
+        let location = expr.location();
+        let instance = self
+            .instances
+            .uses
+            .get(&(location.line(), location.column()));
+        let export = self.instances.exports.get(v);
+        let find = |instance: Option<&Vec<(usize, TypeExpr)>>, n: usize| {
+            instance
+                .and_then(|instance| instance.iter().find(|(m, _)| *m == n))
+                .map(|(_, type_expr)| type_expr.clone())
+        };
+        let copy = type_variables
+            .iter()
+            .map(|n| match (find(instance, *n), find(export, *n)) {
+                (Some(type_expr), _) => self.specialise(&type_expr),
+                // the type a module exports a value at can itself be
+                // polymorphic (when its signature has a 'forall'), in which
+                // case its type variables stand for what they do at this use
+                (None, Some(type_expr)) => {
+                    self.specialise(&type_expr.replace(&mut |type_expr| match *type_expr {
+                        TypeExpr::Unknown(m) => find(instance, m),
+                        _ => None,
+                    }))
+                }
+                (None, None) => TypeExpr::Unit,
+            })
+            .collect::<Vec<_>>();
+        match self.scope[i].1 {
+            Binding::Polymorphic { ref mut copies, .. } => {
+                let name = copy_name(v, &copy);
+                if !copies.contains(&copy) {
+                    copies.push(copy);
+                }
+                name
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // makes copies of what's in scope of binders that have a single type
+    fn scoped(&mut self, bound: Vec<(Var, Var)>, expr: &mut Locatable<Expr>) {
+        let depth = self.scope.len();
+        for (v, name) in bound {
+            self.scope.push((v, Binding::Monomorphic(name)));
+        }
+        self.expr(expr);
+        self.scope.truncate(depth);
+    }
+
+    fn expr(&mut self, expr: &mut Locatable<Expr>) {
+        match expr.t {
+            Expr::Let(..) | Expr::LetFun(..) | Expr::LetRec(..) => return self.definitions(expr),
+            Expr::BinOp(..) => {
+                for sub in past::operands_mut(expr) {
+                    self.expr(sub);
+                }
+                return;
+            }
+            Expr::Var(ref v) => {
+                let name = self.name(expr, &v.clone());
+                expr.t = Expr::Var(name);
+                return;
+            }
+            _ => (),
+        }
+        for type_expr in expr.t.annotations_mut() {
+            *type_expr = self.specialise(type_expr);
+        }
+        match expr.t {
+            Expr::Lambda((ref v, _, ref mut sub)) => self.scoped(vec![(v.clone(), v.clone())], sub),
+            Expr::Case(
+                ref mut sub,
+                (ref v_left, _, ref mut left),
+                (ref v_right, _, ref mut right),
+            ) => {
+                self.expr(sub);
+                self.scoped(vec![(v_left.clone(), v_left.clone())], left);
+                self.scoped(vec![(v_right.clone(), v_right.clone())], right);
+            }
+            Expr::Try(ref mut sub, (ref v, _, ref mut handler)) => {
+                self.expr(sub);
+                self.scoped(vec![(v.clone(), v.clone())], handler);
+            }
+            Expr::For(ref v, ref mut from, ref mut to, ref mut body) => {
+                self.expr(from);
+                self.expr(to);
+                self.scoped(vec![(v.clone(), v.clone())], body);
+            }
+            _ => {
+                for sub in expr.t.children_mut() {
+                    self.expr(sub);
+                }
+            }
+        }
+    }
+
+    // a copy of what a 'let' defines, where its type variables stand for
+    // particular types (and, for a recursive function, where it refers to
+    // itself by the name of the copy). What's in its scope isn't copied, so
+    // it's taken out of the 'let' first (see 'take_body')
+    fn copy(
+        &mut self,
+        expr: &Locatable<Expr>,
+        type_variables: &[usize],
+        copy: &[TypeExpr],
+        name: Var,
+    ) -> Expr {
+        for (n, type_expr) in type_variables.iter().zip(copy) {
+            self.types.insert(*n, type_expr.clone());
+        }
+        let mut expr = expr.clone();
+        let copied = match expr.t {
+            Expr::Let(_, ref type_expr, ref mut sub, ref body) => {
+                self.expr(sub);
+                Expr::Let(name, self.specialise(type_expr), sub.clone(), body.clone())
+            }
+            Expr::LetFun(ref f, (ref v, ref type_expr_v, ref mut sub), ref type_expr, ref body) => {
+                self.scoped(vec![(v.clone(), v.clone()), (f.clone(), name.clone())], sub);
+                Expr::LetFun(
+                    name,
+                    (v.clone(), self.specialise(type_expr_v), sub.clone()),
+                    self.specialise(type_expr),
+                    body.clone(),
+                )
+            }
+            _ => unreachable!(),
+        };
+        for n in type_variables.iter() {
+            self.types.remove(n);
+        }
+        copied
+    }
+
+    // a copy of the functions a 'let rec' defines, where their type variables
+    // stand for particular types (each refers to the others, and to itself, by
+    // the names of their copies, if they're renamed)
+    fn copy_group(
+        &mut self,
+        expr: &Locatable<Expr>,
+        type_variables: &[usize],
+        copy: &[TypeExpr],
+        renamed: bool,
+    ) -> Expr {
+        for (n, type_expr) in type_variables.iter().zip(copy) {
+            self.types.insert(*n, type_expr.clone());
+        }
+        let mut expr = expr.clone();
+        let copied = match expr.t {
+            Expr::LetRec(ref mut functions, ref body) => {
+                let names = functions
+                    .iter()
+                    .map(|(f, _, _)| {
+                        let name = if renamed {
+                            copy_name(f, copy)
+                        } else {
+                            f.clone()
+                        };
+                        (f.clone(), name)
+                    })
+                    .collect::<Vec<_>>();
+                let functions = functions
+                    .iter_mut()
+                    .zip(names.iter())
+                    .map(
+                        |((_, (ref v, ref type_expr_v, ref mut sub), ref type_expr), (_, name))| {
+                            let mut bound = vec![(v.clone(), v.clone())];
+                            bound.extend(names.iter().cloned());
+                            self.scoped(bound, sub);
+                            (
+                                name.clone(),
+                                (v.clone(), self.specialise(type_expr_v), sub.clone()),
+                                self.specialise(type_expr),
+                            )
+                        },
+                    )
+                    .collect();
+                Expr::LetRec(functions, body.clone())
+            }
+            _ => unreachable!(),
+        };
+        for n in type_variables.iter() {
+            self.types.remove(n);
+        }
+        copied
+    }
+
+    // a chain of definitions ('let x = e in let y = e in ...') is taken apart
+    // in a loop, as a program of top-level definitions is one: what each
+    // defines is put in scope on the way down, and copied on the way back up
+    // (once what's in its scope has said what it's used at)
+    fn definitions(&mut self, expr: &mut Locatable<Expr>) {
+        let location = expr.location().clone();
+        let mut body = std::mem::replace(expr, (location, Expr::Unit).into());
+        let mut definitions = vec![];
+        while let Expr::Let(..) | Expr::LetFun(..) | Expr::LetRec(..) = body.t {
+            let rest = take_body(&mut body);
+            let depth = self.scope.len();
+            let type_variables = self.enter(&body);
+            definitions.push((body, type_variables, depth));
+            body = *rest;
+        }
+        self.expr(&mut body);
+        for (definition, type_variables, depth) in definitions.into_iter().rev() {
+            body = self.leave(&definition, &type_variables, depth, body);
+        }
+        *expr = body;
+    }
+
+    // puts what a 'let' (or 'let rec') defines in scope, giving the type
+    // variables it's polymorphic in (none, if the types of what it defines are
+    // all known)
+    fn enter(&mut self, expr: &Locatable<Expr>) -> Vec<usize> {
+        let mut type_variables = vec![];
+        let names = match expr.t {
+            Expr::Let(ref v, ref type_expr, _, _) => {
+                type_variables = self.type_variables(type_expr);
+                vec![v.clone()]
+            }
+            Expr::LetFun(ref f, (_, ref type_expr_v, _), ref type_expr, _) => {
+                type_variables = self.type_variables(&TypeExpr::Arrow(
+                    Box::new(type_expr_v.clone()),
+                    Box::new(type_expr.clone()),
+                ));
+                vec![f.clone()]
+            }
+            Expr::LetRec(ref functions, _) => {
+                for (_, (_, ref type_expr_v, _), ref type_expr) in functions.iter() {
+                    let type_expr =
+                        TypeExpr::Arrow(Box::new(type_expr_v.clone()), Box::new(type_expr.clone()));
+                    for n in self.type_variables(&type_expr) {
+                        if !type_variables.contains(&n) {
+                            type_variables.push(n);
+                        }
+                    }
+                }
+                functions
+                    .iter()
+                    .map(|(f, _, _)| f.clone())
+                    .collect::<Vec<_>>()
+            }
+            _ => unreachable!(),
+        };
+        let first = self.scope.len();
+        for (i, f) in names.into_iter().enumerate() {
+            let binding = if type_variables.is_empty() {
+                Binding::Monomorphic(f.clone())
+            } else if i == 0 {
+                Binding::Polymorphic {
+                    type_variables: type_variables.clone(),
+                    copies: vec![],
+                }
+            } else {
+                Binding::Sibling(first)
+            };
+            self.scope.push((f, binding));
+        }
+        type_variables
+    }
+
+    // takes what a 'let' (or 'let rec') defines back out of scope, giving it
+    // with what's in its scope: as it is, if the types of what it defines are
+    // all known, or with a copy of it for each type it's used at (one at
+    // 'unit' for each type variable if it isn't used at all)
+    fn leave(
+        &mut self,
+        expr: &Locatable<Expr>,
+        type_variables: &[usize],
+        depth: usize,
+        body: Locatable<Expr>,
+    ) -> Locatable<Expr> {
+        let copies = match self.scope.drain(depth..).next() {
+            Some((_, Binding::Polymorphic { copies, .. })) => copies,
+            _ => vec![],
+        };
+        let location = expr.location().clone();
+        if copies.is_empty() {
+            let unused = vec![TypeExpr::Unit; type_variables.len()];
+            let binding = self.copy_binding(expr, type_variables, &unused, false);
+            return (location, with_body(binding, Box::new(body))).into();
+        }
+        let mut copied = body;
+        for copy in copies.iter().rev() {
+            let binding = self.copy_binding(expr, type_variables, copy, true);
+            copied = (location.clone(), with_body(binding, Box::new(copied))).into();
+        }
+        copied
+    }
+
+    // a copy of what a 'let' (or 'let rec') defines (see 'copy' and
+    // 'copy_group'), renamed after the types it's copied at if 'renamed' is set
+    fn copy_binding(
+        &mut self,
+        expr: &Locatable<Expr>,
+        type_variables: &[usize],
+        copy: &[TypeExpr],
+        renamed: bool,
+    ) -> Expr {
+        match expr.t {
+            Expr::Let(ref v, _, _, _) | Expr::LetFun(ref v, _, _, _) => {
+                let name = if renamed {
+                    copy_name(v, copy)
+                } else {
+                    v.clone()
+                };
+                self.copy(expr, type_variables, copy, name)
+            }
+            _ => self.copy_group(expr, type_variables, copy, renamed),
+        }
+    }
+}
+
+// takes what's in the scope of a 'let' out of it (leaving '()' in its place),
+// so that copying what it defines doesn't copy everything after it as well
+fn take_body(expr: &mut Locatable<Expr>) -> Box<Locatable<Expr>> {
+    let location = expr.location().clone();
+    match expr.t {
+        Expr::Let(_, _, _, ref mut body)
+        | Expr::LetFun(_, _, _, ref mut body)
+        | Expr::LetRec(_, ref mut body) => {
+            std::mem::replace(body, Box::new((location, Expr::Unit).into()))
+        }
+        _ => unreachable!(),
+    }
+}
+
+// puts what's in the scope of a 'let' back into it
+fn with_body(mut binding: Expr, body: Box<Locatable<Expr>>) -> Expr {
+    match binding {
+        Expr::Let(_, _, _, ref mut old)
+        | Expr::LetFun(_, _, _, ref mut old)
+        | Expr::LetRec(_, ref mut old) => *old = body,
+        _ => unreachable!(),
+    }
+    binding
+}
+
+// the name of the copy of a polymorphic value at some types (what each of its
+// type variables stands for, in order), which no program can name itself
+fn copy_name(v: &str, copy: &[TypeExpr]) -> Var {
+    let types = copy.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+    format!("{}<{}>", v, types.join(", "))
+}
+
+// makes a copy of each polymorphic value in a (checked) program for each type
+// it's used at, so that the rest of the compiler only sees values with a single
+// type. The copies are named after the value and the types it's copied at
+// ('f<int>' and 'f<bool list>', say, for 'f'), so that's what they're reported
+// as when the program runs. What's defined before the program is given (in
+// order) by name, with the type variables of each that's polymorphic, and the
+// copies the program needs of those are given back
+pub fn monomorphise(
+    expr: &mut Locatable<Expr>,
+    instances: &Instances,
+    defined: &[(Var, Option<Vec<usize>>)],
+) -> Vec<Copied> {
+    let scope = defined
+        .iter()
+        .map(|(v, type_variables)| {
+            let binding = match *type_variables {
+                Some(ref type_variables) => Binding::Polymorphic {
+                    type_variables: type_variables.clone(),
+                    copies: vec![],
+                },
+                None => Binding::Monomorphic(v.clone()),
+            };
+            (v.clone(), binding)
+        })
+        .collect();
+    let mut monomorphiser = Monomorphiser {
+        instances,
+        types: HashMap::new(),
+        scope,
+    };
+    monomorphiser.expr(expr);
+    let mut copied = vec![];
+    for (v, binding) in monomorphiser.scope {
+        if let Binding::Polymorphic { copies, .. } = binding {
+            for types in copies {
+                copied.push(Copied {
+                    name: copy_name(&v, &types),
+                    v: v.clone(),
+                    types,
+                });
+            }
+        }
+    }
+    copied
+}
@@ -1,40 +1,204 @@
 use std::iter::Peekable;
 
-use super::ast::{BinOp, UnOp};
-use super::lex::{Kind, Token};
-use super::past::Expr;
+use super::ast::{Attributes, BinOp, UnOp};
+use super::lex::{quote, Kind, Token};
+use super::past::{Datatype, Expr, Function, Item, Lambda, Module, Pattern, Spec, SubExpr};
 use super::types::TypeExpr;
-use super::{log, Locatable, Location};
+use super::{log, Diagnostic, Locatable, Location};
+
+// a function of several parameters is a function of the first that gives back
+// a function of the rest, so 'fun x y -> e end' is 'fun x -> fun y -> e end end'
+// (where the inner function is where 'y' is written)
+fn curry(params: Vec<(Location, String, TypeExpr)>, body: Locatable<Expr>) -> Lambda {
+    let mut params = params.into_iter();
+    let (_, ident, type_expr) = params.next().unwrap();
+    let body = params
+        .rev()
+        .fold(body, |body, (location, ident, type_expr)| {
+            (location, Expr::Lambda((ident, type_expr, Box::new(body)))).into()
+        });
+    (ident, type_expr, Box::new(body))
+}
+
+// puts a function's attributes on its body beneath all of its parameters
+fn attribute(body: Locatable<Expr>, attributes: Attributes) -> Locatable<Expr> {
+    let location = body.location().clone();
+    let body = match body.into_raw() {
+        Expr::Lambda((v, type_expr, sub)) => {
+            Expr::Lambda((v, type_expr, Box::new(attribute(*sub, attributes))))
+        }
+        body => Expr::Attributed(attributes, Box::new((location.clone(), body).into())),
+    };
+    (location, body).into()
+}
+
+// nested blocks are spliced into the enclosing sequence, so 'begin a; begin b; c end end'
+// becomes the same three element sequence as 'begin a; b; c end'
+fn flatten_into(exprs: &mut Vec<SubExpr>, expr: Locatable<Expr>) {
+    let location = expr.location().clone();
+    match expr.into_raw() {
+        Expr::Seq(seq) => exprs.extend(seq),
+        expr => exprs.push(Box::new((location, expr).into())),
+    }
+}
+
+// a name that starts with a capital letter (and doesn't refer to something in a
+// module) is a constructor of a data type
+fn is_constructor(name: &str) -> bool {
+    !name.contains('.') && name.starts_with(|c: char| c.is_ascii_uppercase())
+}
+
+// everything in a 'let' before 'in', which is all there is to a definition at the REPL
+enum Binding {
+    Value(String, TypeExpr, SubExpr),
+    Function(String, (String, TypeExpr, SubExpr), TypeExpr),
+    // several functions defined together with 'and'
+    Functions(Vec<Function>),
+}
+
+impl Binding {
+    fn name(&self) -> &str {
+        match *self {
+            Binding::Value(ref v, _, _) | Binding::Function(ref v, _, _) => v,
+            Binding::Functions(ref functions) => &functions[0].0,
+        }
+    }
+
+    fn into_item(self) -> Item {
+        match self {
+            Binding::Value(v, type_expr, sub) => Item::Let(v, type_expr, sub),
+            Binding::Function(f, lambda, type_expr) => Item::LetFun(f, lambda, type_expr),
+            // a structure can't define these (which 'next_item' reports)
+            Binding::Functions(_) => unreachable!(),
+        }
+    }
+
+    fn with_body(self, body: Locatable<Expr>) -> Expr {
+        match self {
+            Binding::Value(v, type_expr, sub) => Expr::Let(v, type_expr, sub, Box::new(body)),
+            Binding::Function(f, lambda, type_expr) => {
+                Expr::LetFun(f, lambda, type_expr, Box::new(body))
+            }
+            Binding::Functions(functions) => Expr::LetRec(functions, Box::new(body)),
+        }
+    }
+}
+
+// adapts a stream of tokens that comes to an end to what the parser takes (the
+// lexer never ends, but reports the end of the file as an error for as long as
+// it's asked for more)
+pub struct Tokens<T>
+where
+    T: Iterator<Item = Token>,
+{
+    tokens: T,
+    // where the end of the stream is reported, which is where its last token
+    // was
+    last: Location,
+}
+
+impl<T> Tokens<T>
+where
+    T: Iterator<Item = Token>,
+{
+    pub fn over(tokens: T) -> Tokens<T> {
+        Tokens {
+            tokens,
+            last: Location::new("<tokens>".to_string(), 1, 1),
+        }
+    }
+}
+
+impl<T> Iterator for Tokens<T>
+where
+    T: Iterator<Item = Token>,
+{
+    type Item = Result<Token, Diagnostic>;
+
+    fn next(&mut self) -> Option<Result<Token, Diagnostic>> {
+        match self.tokens.next() {
+            Some(token) => {
+                self.last = token.location().clone();
+                Some(Ok(token))
+            }
+            None => Some(Err(log::parse_error(
+                &self.last,
+                "unexpected end of input".to_string(),
+            ))),
+        }
+    }
+}
 
 pub struct Parser<T>
 where
-    T: Iterator<Item = Result<Token, String>>,
+    T: Iterator<Item = Result<Token, Diagnostic>>,
 {
     tokens: Peekable<T>,
+    depth: usize,
+    max_depth: usize,
+    // how many annotations have been left out so far (each of which is a type
+    // for the type checker to work out, numbered in the order they were found)
+    unknowns: usize,
 }
 
 impl<T> Parser<T>
 where
-    T: Iterator<Item = Result<Token, String>>,
+    T: Iterator<Item = Result<Token, Diagnostic>>,
 {
-    pub fn new(t: T) -> Parser<T> {
+    pub fn new(t: T, max_depth: usize) -> Parser<T> {
         Parser {
             tokens: t.peekable(),
+            depth: 0,
+            max_depth,
+            unknowns: 0,
+        }
+    }
+
+    // a parser for part of a program that's read separately from the rest of
+    // it, whose unknowns have to be numbered after the ones the rest uses
+    pub fn after(mut self, unknowns: usize) -> Parser<T> {
+        self.unknowns = unknowns;
+        self
+    }
+
+    // how many unknowns have been numbered so far
+    pub fn unknowns(&self) -> usize {
+        self.unknowns
+    }
+
+    fn unknown(&mut self) -> TypeExpr {
+        self.unknowns += 1;
+        TypeExpr::Unknown(self.unknowns - 1)
+    }
+
+    fn descend(&mut self) -> Result<(), Diagnostic> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            let location = self.location()?;
+            Err(log::parse_error(
+                &location,
+                format!(
+                    "expression too deeply nested (the limit is {})",
+                    self.max_depth
+                ),
+            ))
+        } else {
+            Ok(())
         }
     }
 
-    fn location(&mut self) -> Result<Location, String> {
+    fn location(&mut self) -> Result<Location, Diagnostic> {
         if let Some(token) = self.tokens.peek() {
             match *token {
                 Ok(ref token) => Ok(token.location().clone()),
-                Err(ref err) => Err(err.to_string()),
+                Err(ref err) => Err(err.clone()),
             }
         } else {
             unreachable!()
         }
     }
 
-    fn eat(&mut self, kind: Kind) -> Result<Token, String> {
+    fn eat(&mut self, kind: Kind) -> Result<Token, Diagnostic> {
         let token = self.next()?;
         if !token.borrow_raw().eq(&kind) {
             Err(log::parse_error(
@@ -46,7 +210,7 @@ where
         }
     }
 
-    fn next(&mut self) -> Result<Token, String> {
+    fn next(&mut self) -> Result<Token, Diagnostic> {
         if let Some(token) = self.tokens.next() {
             token
         } else {
@@ -65,7 +229,7 @@ where
         }
     }
 
-    fn next_type_factor(&mut self) -> Result<TypeExpr, String> {
+    fn next_type_factor(&mut self) -> Result<TypeExpr, Diagnostic> {
         let mut type_expr = if self.next_is(Kind::UnitType) {
             self.eat(Kind::UnitType)?;
             TypeExpr::Unit
@@ -75,11 +239,31 @@ where
         } else if self.next_is(Kind::BoolType) {
             self.eat(Kind::BoolType)?;
             TypeExpr::Bool
+        } else if self.next_is(Kind::StringType) {
+            self.eat(Kind::StringType)?;
+            TypeExpr::String
+        } else if self.next_is(Kind::FloatType) {
+            self.eat(Kind::FloatType)?;
+            TypeExpr::Float
+        } else if self.next_is(Kind::Underscore) {
+            self.eat(Kind::Underscore)?;
+            TypeExpr::Hole
+        } else if self.next_is(Kind::Ident(String::new())) {
+            TypeExpr::Named(self.next_name()?)
+        } else if self.next_is(Kind::TypeVariable(String::new())) {
+            TypeExpr::Variable(self.next_type_variable()?)
         } else if self.next_is(Kind::LParen) {
             self.eat(Kind::LParen)?;
             let type_expr = self.next_type_expression()?;
             self.eat(Kind::RParen)?;
             type_expr
+        } else if self.next_is(Kind::LBrace) {
+            let mut fields = self.next_fields(|parser| {
+                parser.eat(Kind::Colon)?;
+                parser.next_type_expression()
+            })?;
+            fields.sort_by(|(x, _), (y, _)| x.cmp(y));
+            TypeExpr::Record(fields)
         } else {
             let token = self.next()?;
             return Err(log::parse_error(
@@ -87,23 +271,48 @@ where
                 format!("expected a type expression, but got {}", token.borrow_raw()),
             ));
         };
-        while self.next_is(Kind::Ref) {
-            self.eat(Kind::Ref)?;
-            type_expr = TypeExpr::Ref(Box::new(type_expr));
+        loop {
+            if self.next_is(Kind::Ref) {
+                self.eat(Kind::Ref)?;
+                type_expr = TypeExpr::Ref(Box::new(type_expr));
+            } else if self.next_is(Kind::Lazy) {
+                self.eat(Kind::Lazy)?;
+                type_expr = TypeExpr::Lazy(Box::new(type_expr));
+            } else if self.next_is(Kind::Chan) {
+                self.eat(Kind::Chan)?;
+                type_expr = TypeExpr::Chan(Box::new(type_expr));
+            } else if self.next_is(Kind::ArrayType) {
+                self.eat(Kind::ArrayType)?;
+                type_expr = TypeExpr::Array(Box::new(type_expr));
+            } else if self.next_is(Kind::ListType) {
+                self.eat(Kind::ListType)?;
+                type_expr = TypeExpr::List(Box::new(type_expr));
+            } else {
+                break;
+            }
         }
         Ok(type_expr)
     }
 
-    fn next_type_term(&mut self) -> Result<TypeExpr, String> {
-        let mut type_expr = self.next_type_factor()?;
+    // 'T1 * T2' is a product, but 'T1 * T2 * T3' (and so on) is a tuple, as in
+    // OCaml, rather than a product with a product inside it
+    fn next_type_term(&mut self) -> Result<TypeExpr, Diagnostic> {
+        let mut types = vec![self.next_type_factor()?];
         while self.next_is(Kind::Mul) {
             self.eat(Kind::Mul)?;
-            type_expr = TypeExpr::Product(Box::new(type_expr), Box::new(self.next_type_factor()?));
+            types.push(self.next_type_factor()?);
         }
-        Ok(type_expr)
+        Ok(match types.len() {
+            1 => types.pop().unwrap(),
+            2 => {
+                let right = types.pop().unwrap();
+                TypeExpr::Product(Box::new(types.pop().unwrap()), Box::new(right))
+            }
+            _ => TypeExpr::Tuple(types),
+        })
     }
 
-    fn next_type_union(&mut self) -> Result<TypeExpr, String> {
+    fn next_type_union(&mut self) -> Result<TypeExpr, Diagnostic> {
         let mut type_expr = self.next_type_term()?;
         while self.next_is(Kind::Add) {
             self.eat(Kind::Add)?;
@@ -112,65 +321,158 @@ where
         Ok(type_expr)
     }
 
-    fn next_type_expression(&mut self) -> Result<TypeExpr, String> {
+    fn next_type_expression(&mut self) -> Result<TypeExpr, Diagnostic> {
+        self.descend()?;
+        if self.next_is(Kind::Forall) {
+            self.eat(Kind::Forall)?;
+            let mut variables = vec![self.next_type_variable()?];
+            while self.next_is(Kind::TypeVariable(String::new())) {
+                variables.push(self.next_type_variable()?);
+            }
+            self.eat(Kind::Dot)?;
+            let type_expr = TypeExpr::Forall(variables, Box::new(self.next_type_expression()?));
+            self.depth -= 1;
+            return Ok(type_expr);
+        }
         let mut type_expr = self.next_type_union()?;
         if self.next_is(Kind::Arrow) {
             self.eat(Kind::Arrow)?;
             type_expr =
                 TypeExpr::Arrow(Box::new(type_expr), Box::new(self.next_type_expression()?));
         }
+        self.depth -= 1;
         Ok(type_expr)
     }
 
-    fn next_factor(&mut self) -> Result<Locatable<Expr>, String> {
-        let location = self.location()?;
-        let factor = if self.next_is(Kind::Unit) {
+    // a factor that isn't in brackets or braces
+    fn next_atom(&mut self) -> Result<Expr, Diagnostic> {
+        let atom = if self.next_is(Kind::Unit) {
             self.eat(Kind::Unit)?;
             Expr::Unit
         } else if self.next_is(Kind::What) {
             self.eat(Kind::What)?;
             Expr::What
+        } else if self.next_is(Kind::Hole(String::new())) {
+            if let Kind::Hole(name) = self.eat(Kind::Hole(String::new()))?.into_raw() {
+                Expr::Hole(name)
+            } else {
+                unreachable!()
+            }
         } else if self.next_is(Kind::Int(0)) {
             if let Kind::Int(i) = self.eat(Kind::Int(0))?.into_raw() {
                 Expr::Int(i)
             } else {
                 unreachable!()
             }
-        } else if self.next_is(Kind::Ident(String::new())) {
-            if let Kind::Ident(ident) = self.eat(Kind::Ident(String::new()))?.into_raw() {
-                Expr::Var(ident)
+        } else if self.next_is(Kind::Float(0.0)) {
+            Expr::Float(self.next_float()?)
+        } else if self.next_is(Kind::Str(String::new())) {
+            if let Kind::Str(string) = self.eat(Kind::Str(String::new()))?.into_raw() {
+                Expr::Str(string)
             } else {
                 unreachable!()
             }
+        } else if self.next_is(Kind::Ident(String::new())) {
+            let name = self.next_name()?;
+            // there's no module called 'Array' (or 'List'): 'Array.make' is
+            // built in, and takes its arguments as 'ref' takes its one (as do
+            // 'List.hd', 'List.tl' and 'List.is_empty')
+            if name == "Array.make" {
+                let length = self.next_inner_factor()?;
+                Expr::MakeArray(Box::new(length), Box::new(self.next_inner_factor()?))
+            } else if name == "List.hd" {
+                Expr::Head(Box::new(self.next_inner_factor()?))
+            } else if name == "List.tl" {
+                Expr::Tail(Box::new(self.next_inner_factor()?))
+            } else if name == "List.is_empty" {
+                Expr::IsEmpty(Box::new(self.next_inner_factor()?))
+            } else if is_constructor(&name) {
+                // which data type it's a constructor of is found once names
+                // have been resolved
+                Expr::Construct(String::new(), name, None)
+            } else {
+                Expr::Var(name)
+            }
+        } else if self.next_is(Kind::Nil) {
+            self.eat(Kind::Nil)?;
+            Expr::Nil
         } else if self.next_is(Kind::True) {
             self.eat(Kind::True)?;
             Expr::Bool(true)
         } else if self.next_is(Kind::False) {
             self.eat(Kind::False)?;
             Expr::Bool(false)
-        } else if self.next_is(Kind::LParen) {
-            self.eat(Kind::LParen)?;
-            let expr = self.next_expression()?;
-            let expr = if self.next_is(Kind::Comma) {
-                self.eat(Kind::Comma)?;
-                Expr::Pair(Box::new(expr), Box::new(self.next_expression()?))
-            } else {
-                expr.into_raw()
-            };
-            self.eat(Kind::RParen)?;
-            expr
         } else if self.next_is(Kind::Ref) {
             self.eat(Kind::Ref)?;
-            Expr::Ref(Box::new(self.next_factor()?))
+            Expr::Ref(Box::new(self.next_inner_factor()?))
         } else if self.next_is(Kind::Bang) {
             self.eat(Kind::Bang)?;
-            Expr::Deref(Box::new(self.next_factor()?))
+            Expr::Deref(Box::new(self.next_inner_factor()?))
+        } else if self.next_is(Kind::Proj(0)) {
+            let k = match self.eat(Kind::Proj(0))?.into_raw() {
+                Kind::Proj(k) => k,
+                _ => unreachable!(),
+            };
+            Expr::Proj(Box::new(self.next_inner_factor()?), k, self.unknown())
+        } else if self.next_is(Kind::Lazy) {
+            self.eat(Kind::Lazy)?;
+            Expr::Lazy(Box::new(self.next_inner_factor()?))
+        } else if self.next_is(Kind::Force) {
+            self.eat(Kind::Force)?;
+            Expr::Force(Box::new(self.next_inner_factor()?))
+        } else if self.next_is(Kind::Chan) {
+            self.eat(Kind::Chan)?;
+            Expr::Chan(self.next_type_factor()?)
+        } else if self.next_is(Kind::Spawn) {
+            self.eat(Kind::Spawn)?;
+            Expr::Spawn(Box::new(self.next_inner_factor()?))
+        } else if self.next_is(Kind::Send) {
+            self.eat(Kind::Send)?;
+            let channel = self.next_inner_factor()?;
+            Expr::Send(Box::new(channel), Box::new(self.next_inner_factor()?))
+        } else if self.next_is(Kind::Recv) {
+            self.eat(Kind::Recv)?;
+            Expr::Recv(Box::new(self.next_inner_factor()?))
+        } else if self.next_is(Kind::Raise) {
+            self.eat(Kind::Raise)?;
+            Expr::Raise(Box::new(self.next_inner_factor()?))
+        } else if self.next_is(Kind::Expect) {
+            self.eat(Kind::Expect)?;
+            let condition = self.next_inner_factor()?;
+            // which way the condition is expected to go has to be known when
+            // the program is compiled, so it's always written as a literal
+            let likely = if self.next_is(Kind::True) {
+                self.eat(Kind::True)?;
+                true
+            } else if self.next_is(Kind::False) {
+                self.eat(Kind::False)?;
+                false
+            } else {
+                let token = self.next()?;
+                return Err(log::parse_error(
+                    token.location(),
+                    format!(
+                        "expected 'true' or 'false' for what the condition of 'expect' is likely to be, but got {}",
+                        token.borrow_raw()
+                    ),
+                ));
+            };
+            Expr::Expect(Box::new(condition), likely)
+        } else if self.next_is(Kind::PrintString) {
+            self.eat(Kind::PrintString)?;
+            Expr::Print(Box::new(self.next_inner_factor()?))
         } else if self.next_is(Kind::Not) {
             self.eat(Kind::Not)?;
-            Expr::UnOp(UnOp::Not, Box::new(self.next_factor()?))
+            Expr::UnOp(UnOp::Not, Box::new(self.next_inner_factor()?))
         } else if self.next_is(Kind::Sub) {
             self.eat(Kind::Sub)?;
-            Expr::UnOp(UnOp::Neg, Box::new(self.next_factor()?))
+            // there's no operator that negates a float, but a float literal
+            // can still be written with a '-'
+            if self.next_is(Kind::Float(0.0)) {
+                Expr::Float(-self.next_float()?)
+            } else {
+                Expr::UnOp(UnOp::Neg, Box::new(self.next_inner_factor()?))
+            }
         } else {
             let token = self.next()?;
             return Err(log::parse_error(
@@ -178,21 +480,138 @@ where
                 format!("expected an expression, but got {}", token.borrow_raw()),
             ));
         };
-        Ok((location, factor).into())
+        Ok(atom)
+    }
+
+    // a factor is split up into what's in brackets and everything else, as
+    // each level of brackets around an expression takes a call to this, and
+    // a function with as many cases as 'next_atom' has takes a lot of stack
+    // (particularly in unoptimised builds)
+    fn next_factor(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
+        let location = self.location()?;
+        let factor = if self.next_is(Kind::LParen) {
+            self.next_bracketed()?
+        } else if self.next_is(Kind::LBrace) {
+            let fields = self.next_fields(|parser| {
+                parser.eat(Kind::Eq)?;
+                Ok(Box::new(parser.next_expression()?))
+            })?;
+            Expr::Record(fields, self.unknown())
+        } else {
+            self.next_atom()?
+        };
+        // fields are taken from whatever comes before them ('r.x.y' is
+        // '(r.x).y'), as are the elements of arrays ('a[i][j]' is '(a[i])[j]')
+        let mut factor: Locatable<Expr> = (location.clone(), factor).into();
+        loop {
+            if self.next_is(Kind::Dot) {
+                self.eat(Kind::Dot)?;
+                let x = self.next_ident()?;
+                factor = (
+                    location.clone(),
+                    Expr::Field(Box::new(factor), x, self.unknown()),
+                )
+                    .into();
+            } else if self.next_is(Kind::LBracket) {
+                self.eat(Kind::LBracket)?;
+                let index = self.next_expression()?;
+                self.eat(Kind::RBracket)?;
+                factor = (
+                    location.clone(),
+                    Expr::Index(Box::new(factor), Box::new(index)),
+                )
+                    .into();
+            } else {
+                break;
+            }
+        }
+        Ok(factor)
+    }
+
+    // '(e)', or a pair or tuple, '(e, e)' or '(e, e, e)' and so on
+    fn next_bracketed(&mut self) -> Result<Expr, Diagnostic> {
+        self.eat(Kind::LParen)?;
+        let mut subs = vec![Box::new(self.next_expression()?)];
+        while self.next_is(Kind::Comma) {
+            self.eat(Kind::Comma)?;
+            subs.push(Box::new(self.next_expression()?));
+        }
+        self.eat(Kind::RParen)?;
+        Ok(match subs.len() {
+            1 => subs.pop().unwrap().into_raw(),
+            2 => {
+                let right = subs.pop().unwrap();
+                Expr::Pair(subs.pop().unwrap(), right)
+            }
+            _ => Expr::Tuple(subs),
+        })
+    }
+
+    // a factor inside another (as in 'ref e' or '-e'), which is a level deeper
+    // than it. Any other factor counts as part of the expression it's in, so
+    // that each pair of brackets is one level, as it is in 'check_depth'
+    fn next_inner_factor(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
+        self.descend()?;
+        let factor = self.next_factor();
+        self.depth -= 1;
+        factor
+    }
+
+    // '{x ..., y ...}', for a record ('{x = e}') or its type ('{x: T}'), where
+    // 'next' parses what comes after each field's name. A record has to have
+    // at least one field, and can't have the same one twice
+    fn next_fields<U>(
+        &mut self,
+        next: fn(&mut Self) -> Result<U, Diagnostic>,
+    ) -> Result<Vec<(String, U)>, Diagnostic> {
+        self.eat(Kind::LBrace)?;
+        let mut fields: Vec<(String, U)> = vec![];
+        loop {
+            let location = self.location()?;
+            let x = self.next_ident()?;
+            if fields.iter().any(|(y, _)| *y == x) {
+                return Err(log::parse_error(
+                    &location,
+                    format!("field '{}' is already in this record", x),
+                ));
+            }
+            fields.push((x, next(self)?));
+            if !self.next_is(Kind::Comma) {
+                break;
+            }
+            self.eat(Kind::Comma)?;
+        }
+        self.eat(Kind::RBrace)?;
+        Ok(fields)
     }
 
-    fn next_application(&mut self) -> Result<Locatable<Expr>, String> {
+    fn next_application(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
         let location = self.location()?;
         let mut application = self.next_factor()?;
         while self.next_is(Kind::LParen)
+            || self.next_is(Kind::LBrace)
             || self.next_is(Kind::True)
             || self.next_is(Kind::False)
             || self.next_is(Kind::What)
+            || self.next_is(Kind::Hole(String::new()))
             || self.next_is(Kind::Unit)
+            || self.next_is(Kind::Nil)
             || self.next_is(Kind::Ref)
+            || self.next_is(Kind::Lazy)
+            || self.next_is(Kind::Force)
+            || self.next_is(Kind::Chan)
+            || self.next_is(Kind::Spawn)
+            || self.next_is(Kind::Send)
+            || self.next_is(Kind::Recv)
+            || self.next_is(Kind::Raise)
+            || self.next_is(Kind::Expect)
+            || self.next_is(Kind::PrintString)
             || self.next_is(Kind::Bang)
+            || self.next_is(Kind::Proj(0))
             || self.next_is(Kind::Not)
             || self.next_is(Kind::Int(0))
+            || self.next_is(Kind::Float(0.0))
+            || self.next_is(Kind::Str(String::new()))
             || self.next_is(Kind::Ident(String::new()))
         {
             application = (
@@ -204,16 +623,30 @@ where
         Ok(application)
     }
 
-    fn next_term(&mut self) -> Result<Locatable<Expr>, String> {
+    fn next_term(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
         let location = self.location()?;
         let mut term = self.next_application()?;
-        while self.next_is(Kind::Mul) || self.next_is(Kind::Div) {
+        while self.next_is(Kind::Mul)
+            || self.next_is(Kind::Div)
+            || self.next_is(Kind::Mod)
+            || self.next_is(Kind::FloatMul)
+            || self.next_is(Kind::FloatDiv)
+        {
             let op = if self.next_is(Kind::Mul) {
                 self.eat(Kind::Mul)?;
                 BinOp::Mul
-            } else {
+            } else if self.next_is(Kind::Div) {
                 self.eat(Kind::Div)?;
                 BinOp::Div
+            } else if self.next_is(Kind::FloatMul) {
+                self.eat(Kind::FloatMul)?;
+                BinOp::FloatMul
+            } else if self.next_is(Kind::FloatDiv) {
+                self.eat(Kind::FloatDiv)?;
+                BinOp::FloatDiv
+            } else {
+                self.eat(Kind::Mod)?;
+                BinOp::Mod
             };
             term = (
                 location.clone(),
@@ -224,16 +657,30 @@ where
         Ok(term)
     }
 
-    fn next_sum(&mut self) -> Result<Locatable<Expr>, String> {
+    fn next_sum(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
         let location = self.location()?;
         let mut sum = self.next_term()?;
-        while self.next_is(Kind::Add) || self.next_is(Kind::Sub) {
+        while self.next_is(Kind::Add)
+            || self.next_is(Kind::Sub)
+            || self.next_is(Kind::FloatAdd)
+            || self.next_is(Kind::FloatSub)
+            || self.next_is(Kind::Concat)
+        {
             let op = if self.next_is(Kind::Add) {
                 self.eat(Kind::Add)?;
                 BinOp::Add
-            } else {
+            } else if self.next_is(Kind::Sub) {
                 self.eat(Kind::Sub)?;
                 BinOp::Sub
+            } else if self.next_is(Kind::FloatAdd) {
+                self.eat(Kind::FloatAdd)?;
+                BinOp::FloatAdd
+            } else if self.next_is(Kind::FloatSub) {
+                self.eat(Kind::FloatSub)?;
+                BinOp::FloatSub
+            } else {
+                self.eat(Kind::Concat)?;
+                BinOp::Concat
             };
             sum = (
                 location.clone(),
@@ -244,22 +691,48 @@ where
         Ok(sum)
     }
 
-    fn next_comparison(&mut self) -> Result<Locatable<Expr>, String> {
+    // '::' associates to the right, and binds less tightly than arithmetic but
+    // more tightly than comparisons (so '1 + 2 :: xs = ys' is
+    // '((1 + 2) :: xs) = ys')
+    fn next_cons(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
+        let location = self.location()?;
+        let head = self.next_sum()?;
+        if !self.next_is(Kind::Cons) {
+            return Ok(head);
+        }
+        self.eat(Kind::Cons)?;
+        // a long list nests as deeply as it's long
+        self.descend()?;
+        let tail = self.next_cons()?;
+        self.depth -= 1;
+        Ok((location, Expr::Cons(Box::new(head), Box::new(tail))).into())
+    }
+
+    fn next_comparison(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
         let location = self.location()?;
-        let comparison = self.next_sum()?;
-        let comparison = if self.next_is(Kind::Lt) {
+        let comparison = self.next_cons()?;
+        let op = if self.next_is(Kind::Lt) {
             self.eat(Kind::Lt)?;
-            Expr::BinOp(BinOp::Lt, Box::new(comparison), Box::new(self.next_sum()?))
+            BinOp::Lt
         } else if self.next_is(Kind::Eq) {
             self.eat(Kind::Eq)?;
-            Expr::BinOp(BinOp::Eq, Box::new(comparison), Box::new(self.next_sum()?))
+            BinOp::Eq
+        } else if self.next_is(Kind::PhysEq) {
+            self.eat(Kind::PhysEq)?;
+            BinOp::PhysEq
         } else {
-            comparison.into_raw()
+            return Ok((location, comparison.into_raw()).into());
         };
+        let comparison = Expr::Comparison(
+            op,
+            Box::new(comparison),
+            Box::new(self.next_cons()?),
+            self.unknown(),
+        );
         Ok((location, comparison).into())
     }
 
-    fn next_conjunction(&mut self) -> Result<Locatable<Expr>, String> {
+    fn next_conjunction(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
         let location = self.location()?;
         let mut conjunction = self.next_comparison()?;
         while self.next_is(Kind::AndOp) {
@@ -277,7 +750,7 @@ where
         Ok(conjunction)
     }
 
-    fn next_disjunction(&mut self) -> Result<Locatable<Expr>, String> {
+    fn next_disjunction(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
         let location = self.location()?;
         let mut disjunction = self.next_conjunction()?;
         while self.next_is(Kind::OrOp) {
@@ -295,20 +768,54 @@ where
         Ok(disjunction)
     }
 
-    fn next_expression(&mut self) -> Result<Locatable<Expr>, String> {
+    fn next_expression(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
+        self.descend()?;
         let location = self.location()?;
+        let expr = if let Some(expr) = self.next_keyword_expression()? {
+            expr
+        } else {
+            let assign = self.next_disjunction()?;
+            if self.next_is(Kind::Assign) {
+                self.eat(Kind::Assign)?;
+                let right = Box::new(self.next_expression()?);
+                // an element of an array is written to in place
+                match assign.into_raw() {
+                    Expr::Index(array, index) => Expr::Update(array, index, right),
+                    assign => Expr::Assign(Box::new((location.clone(), assign).into()), right),
+                }
+            } else {
+                assign.into_raw()
+            }
+        };
+        self.depth -= 1;
+        Ok((location, expr).into())
+    }
+
+    // an expression that starts with a keyword (or a minus sign), if the next one
+    // does. This is kept apart from 'next_expression' as it needs a lot of stack
+    // in unoptimised builds, which otherwise every level of brackets would use
+    fn next_keyword_expression(&mut self) -> Result<Option<Expr>, Diagnostic> {
         let expr = if self.next_is(Kind::Begin) {
             self.eat(Kind::Begin)?;
-            let mut exprs = vec![Box::new(self.next_expression()?)];
+            let mut exprs = vec![];
+            flatten_into(&mut exprs, self.next_expression()?);
             while self.next_is(Kind::Semi) {
                 self.eat(Kind::Semi)?;
-                exprs.push(Box::new(self.next_expression()?));
+                if self.next_is(Kind::End) {
+                    break;
+                }
+                flatten_into(&mut exprs, self.next_expression()?);
             }
             self.eat(Kind::End)?;
             Expr::Seq(exprs)
         } else if self.next_is(Kind::Sub) {
             self.eat(Kind::Sub)?;
-            Expr::UnOp(UnOp::Neg, Box::new(self.next_expression()?))
+            let sub = self.next_expression()?;
+            // (as in a factor)
+            match *sub.borrow_raw() {
+                Expr::Float(x) => Expr::Float(-x),
+                _ => Expr::UnOp(UnOp::Neg, Box::new(sub)),
+            }
         } else if self.next_is(Kind::If) {
             self.eat(Kind::If)?;
             let condition = self.next_expression()?;
@@ -325,12 +832,26 @@ where
             let body = self.next_expression()?;
             self.eat(Kind::End)?;
             Expr::While(Box::new(condition), Box::new(body))
+        } else if self.next_is(Kind::For) {
+            self.eat(Kind::For)?;
+            let ident = self.next_ident()?;
+            self.eat(Kind::Eq)?;
+            let from = self.next_expression()?;
+            self.eat(Kind::To)?;
+            let to = self.next_expression()?;
+            self.eat(Kind::Do)?;
+            let body = self.next_expression()?;
+            self.eat(Kind::End)?;
+            Expr::For(ident, Box::new(from), Box::new(to), Box::new(body))
         } else if self.next_is(Kind::Fst) {
             self.eat(Kind::Fst)?;
             Expr::Fst(Box::new(self.next_expression()?))
         } else if self.next_is(Kind::Snd) {
             self.eat(Kind::Snd)?;
             Expr::Snd(Box::new(self.next_expression()?))
+        } else if self.next_is(Kind::Ignore) {
+            self.eat(Kind::Ignore)?;
+            Expr::Ignore(Box::new(self.next_expression()?))
         } else if self.next_is(Kind::Inl) {
             self.eat(Kind::Inl)?;
             let type_expr = self.next_type_expression()?;
@@ -341,47 +862,22 @@ where
             Expr::Inr(Box::new(self.next_expression()?), type_expr)
         } else if self.next_is(Kind::Fun) {
             self.eat(Kind::Fun)?;
-            self.eat(Kind::LParen)?;
-            if let Kind::Ident(ident) = self.eat(Kind::Ident(String::new()))?.into_raw() {
-                self.eat(Kind::Colon)?;
-                let type_expr = self.next_type_expression()?;
-                self.eat(Kind::RParen)?;
-                self.eat(Kind::Arrow)?;
-                let body = self.next_expression()?;
-                self.eat(Kind::End)?;
-                Expr::Lambda((ident, type_expr, Box::new(body)))
-            } else {
-                unreachable!()
-            }
+            let params = self.next_params()?;
+            self.eat(Kind::Arrow)?;
+            let body = self.next_expression()?;
+            self.eat(Kind::End)?;
+            Expr::Lambda(curry(params, body))
         } else if self.next_is(Kind::Case) {
             self.eat(Kind::Case)?;
             let to_match = self.next_expression()?;
             self.eat(Kind::Of)?;
             self.eat(Kind::Inl)?;
-            self.eat(Kind::LParen)?;
-            let left_ident =
-                if let Kind::Ident(ident) = self.eat(Kind::Ident(String::new()))?.into_raw() {
-                    ident
-                } else {
-                    unreachable!()
-                };
-            self.eat(Kind::Colon)?;
-            let left_type_expr = self.next_type_expression()?;
-            self.eat(Kind::RParen)?;
+            let (left_ident, left_type_expr) = self.next_param()?;
             self.eat(Kind::Arrow)?;
             let left_expr = self.next_expression()?;
             self.eat(Kind::Bar)?;
             self.eat(Kind::Inr)?;
-            self.eat(Kind::LParen)?;
-            let right_ident =
-                if let Kind::Ident(ident) = self.eat(Kind::Ident(String::new()))?.into_raw() {
-                    ident
-                } else {
-                    unreachable!()
-                };
-            self.eat(Kind::Colon)?;
-            let right_type_expr = self.next_type_expression()?;
-            self.eat(Kind::RParen)?;
+            let (right_ident, right_type_expr) = self.next_param()?;
             self.eat(Kind::Arrow)?;
             let right_expr = self.next_expression()?;
             self.eat(Kind::End)?;
@@ -390,64 +886,654 @@ where
                 (left_ident, left_type_expr, Box::new(left_expr)),
                 (right_ident, right_type_expr, Box::new(right_expr)),
             )
-        } else if self.next_is(Kind::Let) {
-            self.eat(Kind::Let)?;
-            if let Kind::Ident(ident) = self.eat(Kind::Ident(String::new()))?.into_raw() {
-                if self.next_is(Kind::Colon) {
-                    self.eat(Kind::Colon)?;
-                    let type_expr = self.next_type_expression()?;
-                    self.eat(Kind::Eq)?;
-                    let sub = self.next_expression()?;
-                    self.eat(Kind::In)?;
-                    let body = self.next_expression()?;
-                    self.eat(Kind::End)?;
-                    Expr::Let(ident, type_expr, Box::new(sub), Box::new(body))
-                } else if self.next_is(Kind::LParen) {
-                    self.eat(Kind::LParen)?;
-                    if let Kind::Ident(arg) = self.eat(Kind::Ident(String::new()))?.into_raw() {
-                        self.eat(Kind::Colon)?;
-                        let arg_type_expr = self.next_type_expression()?;
-                        self.eat(Kind::RParen)?;
-                        self.eat(Kind::Colon)?;
-                        let type_expr = self.next_type_expression()?;
-                        self.eat(Kind::Eq)?;
-                        let sub = self.next_expression()?;
-                        self.eat(Kind::In)?;
-                        let body = self.next_expression()?;
-                        self.eat(Kind::End)?;
-                        Expr::LetFun(
-                            ident,
-                            (arg, arg_type_expr, Box::new(sub)),
-                            type_expr,
-                            Box::new(body),
-                        )
-                    } else {
-                        unreachable!();
-                    }
+        } else if self.next_is(Kind::Try) {
+            self.eat(Kind::Try)?;
+            let body = self.next_expression()?;
+            self.eat(Kind::With)?;
+            let (ident, type_expr) = self.next_param()?;
+            self.eat(Kind::Arrow)?;
+            let handler = self.next_expression()?;
+            self.eat(Kind::End)?;
+            Expr::Try(Box::new(body), (ident, type_expr, Box::new(handler)))
+        } else if self.next_is(Kind::Match) {
+            self.eat(Kind::Match)?;
+            let to_match = self.next_expression()?;
+            self.eat(Kind::With)?;
+            // the bar before the first branch can be left out
+            if self.next_is(Kind::Bar) {
+                self.eat(Kind::Bar)?;
+            }
+            let mut arms = vec![];
+            loop {
+                let pattern = self.next_pattern()?;
+                self.eat(Kind::Arrow)?;
+                arms.push((pattern, Box::new(self.next_expression()?)));
+                if !self.next_is(Kind::Bar) {
+                    break;
+                }
+                self.eat(Kind::Bar)?;
+            }
+            self.eat(Kind::End)?;
+            Expr::Match(Box::new(to_match), self.unknown(), arms, self.unknown())
+        } else if self.next_is(Kind::Let) || self.next_is(Kind::LAttribute) {
+            let binding = self.next_binding()?;
+            self.eat(Kind::In)?;
+            let body = self.next_expression()?;
+            self.eat(Kind::End)?;
+            binding.with_body(body)
+        } else if self.next_is(Kind::Type) {
+            self.eat(Kind::Type)?;
+            let name = self.next_ident()?;
+            self.eat(Kind::Eq)?;
+            // the bar before the first constructor can be left out
+            if self.next_is(Kind::Bar) {
+                self.eat(Kind::Bar)?;
+            }
+            let mut constructors = vec![];
+            loop {
+                let c = self.next_constructor()?;
+                let type_expr = if self.next_is(Kind::Of) {
+                    self.eat(Kind::Of)?;
+                    Some(self.next_type_expression()?)
                 } else {
-                    let token = self.next()?;
-                    return Err(log::parse_error(
-                        token.location(),
-                        format!("expected a type annotation, but got {}", token.borrow_raw()),
-                    ));
+                    None
+                };
+                constructors.push((c, type_expr));
+                if !self.next_is(Kind::Bar) {
+                    break;
                 }
-            } else {
-                unreachable!()
+                self.eat(Kind::Bar)?;
             }
+            self.eat(Kind::In)?;
+            let body = self.next_expression()?;
+            self.eat(Kind::End)?;
+            Expr::Data(Datatype { name, constructors }, Box::new(body))
+        } else if self.next_is(Kind::Module) {
+            self.eat(Kind::Module)?;
+            let name = self.next_ident()?;
+            let signature = if self.next_is(Kind::Colon) {
+                self.eat(Kind::Colon)?;
+                Some(self.next_signature()?)
+            } else {
+                None
+            };
+            self.eat(Kind::Eq)?;
+            // a structure imported from another file is read in once the
+            // whole program has been parsed
+            let (items, source) = if self.next_is(Kind::Import) {
+                self.eat(Kind::Import)?;
+                match self.eat(Kind::Str(String::new()))?.into_raw() {
+                    Kind::Str(path) => (vec![], Some(path)),
+                    _ => unreachable!(),
+                }
+            } else {
+                self.eat(Kind::Struct)?;
+                let mut items = vec![];
+                while !self.next_is(Kind::End) {
+                    items.push(self.next_item()?);
+                }
+                self.eat(Kind::End)?;
+                (items, None)
+            };
+            self.eat(Kind::In)?;
+            let body = self.next_expression()?;
+            self.eat(Kind::End)?;
+            Expr::Module(
+                Module {
+                    name,
+                    signature,
+                    items,
+                    source,
+                },
+                Box::new(body),
+            )
+        } else if self.next_is(Kind::Open) {
+            self.eat(Kind::Open)?;
+            let name = self.next_ident()?;
+            self.eat(Kind::In)?;
+            let body = self.next_expression()?;
+            self.eat(Kind::End)?;
+            Expr::Open(name, Box::new(body))
         } else {
-            let assign = self.next_disjunction()?;
-            let assign = if self.next_is(Kind::Assign) {
-                self.eat(Kind::Assign)?;
-                Expr::Assign(Box::new(assign), Box::new(self.next_expression()?))
+            return Ok(None);
+        };
+        Ok(Some(expr))
+    }
+
+    // a type annotation (': T'), or a type for the type checker to work out if
+    // there isn't one
+    fn next_annotation(&mut self) -> Result<TypeExpr, Diagnostic> {
+        if self.next_is(Kind::Colon) {
+            self.eat(Kind::Colon)?;
+            self.next_type_expression()
+        } else {
+            Ok(self.unknown())
+        }
+    }
+
+    fn next_ident(&mut self) -> Result<String, Diagnostic> {
+        if let Kind::Ident(ident) = self.eat(Kind::Ident(String::new()))?.into_raw() {
+            Ok(ident)
+        } else {
+            unreachable!()
+        }
+    }
+
+    fn next_constructor(&mut self) -> Result<String, Diagnostic> {
+        let location = self.location()?;
+        let c = self.next_ident()?;
+        if is_constructor(&c) {
+            Ok(c)
+        } else {
+            Err(log::parse_error(
+                &location,
+                format!(
+                    "expected a constructor (a name that starts with a capital letter), but got '{}'",
+                    c
+                ),
+            ))
+        }
+    }
+
+    fn next_type_variable(&mut self) -> Result<String, Diagnostic> {
+        if let Kind::TypeVariable(name) = self.eat(Kind::TypeVariable(String::new()))?.into_raw() {
+            Ok(name)
+        } else {
+            unreachable!()
+        }
+    }
+
+    fn next_float(&mut self) -> Result<f64, Diagnostic> {
+        if let Kind::Float(f) = self.eat(Kind::Float(0.0))?.into_raw() {
+            Ok(f)
+        } else {
+            unreachable!()
+        }
+    }
+
+    // a name, which can refer to something in a module ('M.x')
+    fn next_name(&mut self) -> Result<String, Diagnostic> {
+        let ident = self.next_ident()?;
+        if self.next_is(Kind::Dot) {
+            self.eat(Kind::Dot)?;
+            Ok(format!("{}.{}", ident, self.next_ident()?))
+        } else {
+            Ok(ident)
+        }
+    }
+
+    // what a function or a branch of a 'case' binds: 'x', '(x)' or '(x : T)'
+    fn next_param(&mut self) -> Result<(String, TypeExpr), Diagnostic> {
+        if self.next_is(Kind::LParen) {
+            self.eat(Kind::LParen)?;
+            let ident = self.next_ident()?;
+            let type_expr = self.next_annotation()?;
+            self.eat(Kind::RParen)?;
+            Ok((ident, type_expr))
+        } else {
+            let ident = self.next_ident()?;
+            Ok((ident, self.unknown()))
+        }
+    }
+
+    // the parameters of a function, of which there's at least one, along with
+    // where each is written
+    fn next_params(&mut self) -> Result<Vec<(Location, String, TypeExpr)>, Diagnostic> {
+        let mut params = vec![];
+        loop {
+            let location = self.location()?;
+            let (ident, type_expr) = self.next_param()?;
+            params.push((location, ident, type_expr));
+            if !self.next_is(Kind::LParen) && !self.next_is(Kind::Ident(String::new())) {
+                return Ok(params);
+            }
+        }
+    }
+
+    // what a branch of a 'match' matches: a pattern that isn't a list, or
+    // 'p :: q' (which, as in expressions, associates to the right)
+    fn next_pattern(&mut self) -> Result<Pattern, Diagnostic> {
+        let head = self.next_simple_pattern()?;
+        if !self.next_is(Kind::Cons) {
+            return Ok(head);
+        }
+        self.eat(Kind::Cons)?;
+        self.descend()?;
+        let tail = self.next_pattern()?;
+        self.depth -= 1;
+        Ok(Pattern::Cons(Box::new(head), Box::new(tail)))
+    }
+
+    // '_', a name, a literal, '[]', '(p, p)' (or '(p, p, p)' and so on), 'inl p', 'inr p' or a
+    // constructor ('C', or 'C p' for one that's given something)
+    fn next_simple_pattern(&mut self) -> Result<Pattern, Diagnostic> {
+        self.descend()?;
+        let pattern = if self.next_is(Kind::Underscore) {
+            self.eat(Kind::Underscore)?;
+            Pattern::Wildcard
+        } else if self.next_is(Kind::Ident(String::new())) {
+            let ident = self.next_ident()?;
+            if !is_constructor(&ident) {
+                Pattern::Var(ident)
+            } else if self.next_is(Kind::Underscore)
+                || self.next_is(Kind::Ident(String::new()))
+                || self.next_is(Kind::Unit)
+                || self.next_is(Kind::Int(0))
+                || self.next_is(Kind::True)
+                || self.next_is(Kind::False)
+                || self.next_is(Kind::LParen)
+                || self.next_is(Kind::Nil)
+            {
+                let sub = self.next_simple_pattern()?;
+                Pattern::Construct(String::new(), ident, Some(Box::new(sub)))
             } else {
-                assign.into_raw()
+                Pattern::Construct(String::new(), ident, None)
+            }
+        } else if self.next_is(Kind::Unit) {
+            self.eat(Kind::Unit)?;
+            Pattern::Unit
+        } else if self.next_is(Kind::Int(0)) || self.next_is(Kind::Sub) {
+            let negative = self.next_is(Kind::Sub);
+            if negative {
+                self.eat(Kind::Sub)?;
+            }
+            match self.eat(Kind::Int(0))?.into_raw() {
+                Kind::Int(i) if negative => Pattern::Int(-i),
+                Kind::Int(i) => Pattern::Int(i),
+                _ => unreachable!(),
+            }
+        } else if self.next_is(Kind::Nil) {
+            self.eat(Kind::Nil)?;
+            Pattern::Nil
+        } else if self.next_is(Kind::True) {
+            self.eat(Kind::True)?;
+            Pattern::Bool(true)
+        } else if self.next_is(Kind::False) {
+            self.eat(Kind::False)?;
+            Pattern::Bool(false)
+        } else if self.next_is(Kind::LParen) {
+            self.eat(Kind::LParen)?;
+            let mut patterns = vec![self.next_pattern()?];
+            while self.next_is(Kind::Comma) {
+                self.eat(Kind::Comma)?;
+                patterns.push(self.next_pattern()?);
+            }
+            self.eat(Kind::RParen)?;
+            match patterns.len() {
+                1 => patterns.pop().unwrap(),
+                2 => {
+                    let right = Box::new(patterns.pop().unwrap());
+                    Pattern::Pair(Box::new(patterns.pop().unwrap()), right)
+                }
+                _ => Pattern::Tuple(patterns),
+            }
+        } else if self.next_is(Kind::Inl) {
+            self.eat(Kind::Inl)?;
+            Pattern::Inl(Box::new(self.next_simple_pattern()?))
+        } else if self.next_is(Kind::Inr) {
+            self.eat(Kind::Inr)?;
+            Pattern::Inr(Box::new(self.next_simple_pattern()?))
+        } else {
+            let token = self.next()?;
+            return Err(log::parse_error(
+                token.location(),
+                format!("expected a pattern, but got {}", token.borrow_raw()),
+            ));
+        };
+        self.depth -= 1;
+        Ok(pattern)
+    }
+
+    // the attributes before a 'let' that defines a function, each of which is
+    // '[@inline]', '[@noinline]' or '[@optimize "size"]'
+    fn next_attributes(&mut self) -> Result<Attributes, Diagnostic> {
+        let mut attributes = Attributes::default();
+        while self.next_is(Kind::LAttribute) {
+            self.eat(Kind::LAttribute)?;
+            let location = self.location()?;
+            match self.next_ident()?.as_str() {
+                "inline" | "noinline" if attributes.inline.is_some() => {
+                    return Err(log::parse_error(
+                        &location,
+                        "a function can only say once whether it's inlined".to_string(),
+                    ))
+                }
+                "inline" => attributes.inline = Some(true),
+                "noinline" => attributes.inline = Some(false),
+                "optimize" => {
+                    let location = self.location()?;
+                    match self.next()?.into_raw() {
+                        Kind::Str(ref goal) if goal == "size" => attributes.size = true,
+                        Kind::Str(goal) => {
+                            return Err(log::parse_error(
+                                &location,
+                                format!(
+                                    "a function can only be optimised for \"size\", not {}",
+                                    quote(&goal)
+                                ),
+                            ))
+                        }
+                        kind => {
+                            return Err(log::parse_error(
+                                &location,
+                                format!(
+                                    "expected what to optimise the function for (\"size\"), but got {}",
+                                    kind
+                                ),
+                            ))
+                        }
+                    }
+                }
+                attribute => {
+                    return Err(log::parse_error(
+                        &location,
+                        format!(
+                            "there's no '[@{}]' attribute (only '[@inline]', '[@noinline]' and '[@optimize \"size\"]')",
+                            attribute
+                        ),
+                    ))
+                }
+            }
+            self.eat(Kind::RBracket)?;
+        }
+        Ok(attributes)
+    }
+
+    // 'let', then what it defines: a value, a function, or (with 'and') several
+    // functions that can each call the others. Every function is in scope in
+    // its own body anyway, so the 'rec' in 'let rec' can be left out. Attributes
+    // can come before a 'let' that defines a single function
+    fn next_binding(&mut self) -> Result<Binding, Diagnostic> {
+        let location = self.location()?;
+        let attributed = self.next_is(Kind::LAttribute);
+        let attributes = self.next_attributes()?;
+        let binding = self.next_unattributed_binding()?;
+        match binding {
+            binding if !attributed => Ok(binding),
+            Binding::Function(f, (v, type_expr, body), t) => Ok(Binding::Function(
+                f,
+                (v, type_expr, Box::new(attribute(*body, attributes))),
+                t,
+            )),
+            _ => Err(log::parse_error(
+                &location,
+                "attributes can only be given to a single function (not a value, or functions defined together with 'and')".to_string(),
+            )),
+        }
+    }
+
+    fn next_unattributed_binding(&mut self) -> Result<Binding, Diagnostic> {
+        self.eat(Kind::Let)?;
+        let rec = self.next_is(Kind::Rec);
+        if rec {
+            self.eat(Kind::Rec)?;
+        }
+        let location = self.location()?;
+        let first = self.next_definition()?;
+        if !self.next_is(Kind::And) {
+            return match first {
+                Binding::Value(..) if rec => Err(log::parse_error(
+                    &location,
+                    "'let rec' can only define functions".to_string(),
+                )),
+                first => Ok(first),
+            };
+        }
+        let mut functions: Vec<Function> = vec![];
+        let mut next = (location, first);
+        loop {
+            match next {
+                (_, Binding::Function(f, lambda, type_expr))
+                    if functions.iter().all(|(g, _, _)| *g != f) =>
+                {
+                    functions.push((f, lambda, type_expr))
+                }
+                (location, Binding::Function(f, _, _)) => {
+                    return Err(log::parse_error(
+                        &location,
+                        format!("'{}' is defined more than once in the same 'let rec'", f),
+                    ))
+                }
+                (location, _) => {
+                    return Err(log::parse_error(
+                        &location,
+                        "only functions can be defined together with 'and'".to_string(),
+                    ))
+                }
+            }
+            if !self.next_is(Kind::And) {
+                return Ok(Binding::Functions(functions));
+            }
+            self.eat(Kind::And)?;
+            next = (self.location()?, self.next_definition()?);
+        }
+    }
+
+    // a single definition, after the 'let' (or 'and')
+    fn next_definition(&mut self) -> Result<Binding, Diagnostic> {
+        let ident = self.next_ident()?;
+        if self.next_is(Kind::LParen) || self.next_is(Kind::Ident(String::new())) {
+            let params = self.next_params()?;
+            // what's annotated is the result of applying the function to all
+            // of its parameters, so the first gives back a function of the rest
+            let type_expr = match self.next_annotation()? {
+                TypeExpr::Unknown(n) => TypeExpr::Unknown(n),
+                result => params[1..].iter().rev().fold(result, |result, (_, _, t)| {
+                    TypeExpr::Arrow(Box::new(t.clone()), Box::new(result))
+                }),
             };
-            assign
+            self.eat(Kind::Eq)?;
+            let sub = self.next_expression()?;
+            Ok(Binding::Function(ident, curry(params, sub), type_expr))
+        } else {
+            let type_expr = self.next_annotation()?;
+            self.eat(Kind::Eq)?;
+            let sub = self.next_expression()?;
+            Ok(Binding::Value(ident, type_expr, Box::new(sub)))
+        }
+    }
+
+    // something a module's structure defines: a type ('type t = T') or
+    // anything a 'let' can
+    fn next_item(&mut self) -> Result<Item, Diagnostic> {
+        if self.next_is(Kind::Type) {
+            self.eat(Kind::Type)?;
+            let t = self.next_ident()?;
+            self.eat(Kind::Eq)?;
+            Ok(Item::Type(t, self.next_type_expression()?))
+        } else {
+            let location = self.location()?;
+            match self.next_binding()? {
+                Binding::Functions(_) => Err(log::parse_error(
+                    &location,
+                    "a module's structure can't define functions together with 'and' (each thing it defines is only in scope after it)".to_string(),
+                )),
+                binding => Ok(binding.into_item()),
+            }
+        }
+    }
+
+    // 'sig ... end', where each thing the module exports is either an abstract
+    // type ('type t') or a value and its type ('val x : T')
+    fn next_signature(&mut self) -> Result<Vec<Spec>, Diagnostic> {
+        self.eat(Kind::Sig)?;
+        let mut signature = vec![];
+        while !self.next_is(Kind::End) {
+            if self.next_is(Kind::Type) {
+                self.eat(Kind::Type)?;
+                signature.push(Spec::Type(self.next_ident()?));
+            } else {
+                self.eat(Kind::Val)?;
+                let v = self.next_ident()?;
+                self.eat(Kind::Colon)?;
+                signature.push(Spec::Val(v, self.next_type_expression()?));
+            }
+        }
+        self.eat(Kind::End)?;
+        Ok(signature)
+    }
+
+    fn check_depth(&self, expr: &Locatable<Expr>) -> Result<(), Diagnostic> {
+        let mut work = vec![(expr, 1)];
+        while let Some((expr, depth)) = work.pop() {
+            if depth > self.max_depth {
+                return Err(log::parse_error(
+                    expr.location(),
+                    format!(
+                        "expression too deeply nested (the limit is {})",
+                        self.max_depth
+                    ),
+                ));
+            }
+            // a chain of left-associative operators ('a + b + c') is as deep as
+            // its operands, as nothing walks down one by recursing
+            if let Expr::BinOp(_, ref left, ref right) = *expr.borrow_raw() {
+                work.push((left, depth));
+                work.push((right, depth + 1));
+                continue;
+            }
+            // so is what's in the scope of a 'let', as a program of top-level
+            // definitions is one long chain of them (but what a 'let' defines
+            // is a level further in)
+            let mut children = expr.borrow_raw().children();
+            if let Expr::Let(..) | Expr::LetFun(..) | Expr::LetRec(..) = *expr.borrow_raw() {
+                work.push((children.pop().unwrap(), depth));
+            }
+            for sub in children {
+                work.push((sub, depth + 1));
+            }
+        }
+        Ok(())
+    }
+
+    // a program that starts with a 'let' is either an expression, if the 'let'
+    // has an 'in', or a sequence of top-level definitions (each a 'let' without
+    // one) that ends with 'let main = e', where 'e' is what the program runs.
+    // The definitions are nested as if each had an 'in', with 'main' as the
+    // body of the last
+    fn next_program(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
+        let mut definitions = vec![];
+        loop {
+            let location = self.location()?;
+            self.descend()?;
+            let binding = self.next_binding()?;
+            if definitions.is_empty() && self.next_is(Kind::In) {
+                self.eat(Kind::In)?;
+                let body = self.next_expression()?;
+                self.eat(Kind::End)?;
+                self.depth -= 1;
+                return Ok((location, binding.with_body(body)).into());
+            }
+            self.depth -= 1;
+            match binding {
+                Binding::Value(ref v, _, _) if v == "main" => {
+                    definitions.push((location, binding));
+                    break;
+                }
+                Binding::Function(ref f, _, _) if f == "main" => {
+                    return Err(log::parse_error(
+                        &location,
+                        "'main' is the expression the program runs, so it can't have parameters"
+                            .to_string(),
+                    ))
+                }
+                Binding::Functions(ref functions) if functions.iter().any(|(f, _, _)| f == "main") => {
+                    return Err(log::parse_error(
+                        &location,
+                        "'main' is the expression the program runs, so it can't be defined with 'and'"
+                            .to_string(),
+                    ))
+                }
+                binding => definitions.push((location.clone(), binding)),
+            }
+            if !self.next_is(Kind::Let) && !self.next_is(Kind::LAttribute) {
+                let (location, _) = definitions.last().unwrap();
+                return Err(log::parse_error(
+                    location,
+                    "a program made of top-level definitions has to end with 'let main = ...' (the expression it runs), or this definition needs an 'in'".to_string(),
+                ));
+            }
+        }
+        let (location, main) = definitions.pop().unwrap();
+        let body = (location.clone(), Expr::Var("main".to_string())).into();
+        let mut expr: Locatable<Expr> = (location, main.with_body(body)).into();
+        while let Some((location, binding)) = definitions.pop() {
+            expr = (location, binding.with_body(expr)).into();
+        }
+        Ok(expr)
+    }
+
+    pub fn parse(&mut self) -> Result<Locatable<Expr>, Diagnostic> {
+        let expr = if self.next_is(Kind::Let) || self.next_is(Kind::LAttribute) {
+            self.next_program()?
+        } else {
+            self.next_expression()?
         };
-        Ok((location, expr).into())
+        self.check_depth(&expr)?;
+        Ok(expr)
+    }
+
+    // parses a type on its own, which has to be all there is
+    pub fn parse_type(&mut self) -> Result<TypeExpr, Diagnostic> {
+        let type_expr = self.next_type_expression()?;
+        match self.tokens.peek() {
+            Some(Ok(token)) => Err(log::parse_error(
+                token.location(),
+                format!(
+                    "expected the end of the type, but got {}",
+                    token.borrow_raw()
+                ),
+            )),
+            _ => Ok(type_expr),
+        }
+    }
+
+    // parses what a structure defines, on its own (as a file that's imported
+    // is), which has to be all there is
+    pub fn parse_structure(&mut self) -> Result<Vec<Item>, Diagnostic> {
+        let mut items = vec![];
+        while let Some(Ok(_)) = self.tokens.peek() {
+            let item = self.next_item()?;
+            match item {
+                Item::Type(_, _) => (),
+                Item::Let(_, _, ref sub) | Item::LetFun(_, (_, _, ref sub), _) => {
+                    self.check_depth(sub)?
+                }
+            }
+            items.push(item);
+        }
+        Ok(items)
     }
 
-    pub fn parse(&mut self) -> Result<Locatable<Expr>, String> {
-        Ok(self.next_expression()?)
+    // parses either an expression or a definition (a 'let' without a body), in
+    // which case the result is the 'let' with the defined name as its body
+    pub fn parse_entry(&mut self) -> Result<(Option<String>, Locatable<Expr>), Diagnostic> {
+        if !self.next_is(Kind::Let) && !self.next_is(Kind::LAttribute) {
+            return Ok((None, self.parse()?));
+        }
+        let location = self.location()?;
+        self.descend()?;
+        let binding = self.next_binding()?;
+        let (name, body) = if self.next_is(Kind::In) {
+            self.eat(Kind::In)?;
+            let body = self.next_expression()?;
+            self.eat(Kind::End)?;
+            (None, body)
+        } else if let Binding::Functions(_) = binding {
+            return Err(log::parse_error(
+                &location,
+                "functions defined together with 'and' need an 'in' here, as a definition at the REPL only names one thing".to_string(),
+            ));
+        } else {
+            let name = binding.name().to_string();
+            (
+                Some(name.clone()),
+                (location.clone(), Expr::Var(name)).into(),
+            )
+        };
+        self.depth -= 1;
+        let expr = (location, binding.with_body(body)).into();
+        self.check_depth(&expr)?;
+        Ok((name, expr))
     }
 }
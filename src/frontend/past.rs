@@ -1,4 +1,5 @@
-use super::ast::{BinOp, UnOp};
+use super::ast::{Attributes, BinOp, UnOp};
+use super::lex::{float_literal, quote};
 use super::types::TypeExpr;
 use super::Locatable;
 
@@ -8,6 +9,10 @@ pub type Var = String;
 
 pub type Lambda = (Var, TypeExpr, SubExpr);
 
+// a function a 'let' defines: its name, its parameter and body, and the type of
+// what it gives back
+pub type Function = (Var, Lambda, TypeExpr);
+
 pub type SubExpr = Box<Locatable<Expr>>;
 
 impl fmt::Display for SubExpr {
@@ -15,36 +20,609 @@ impl fmt::Display for SubExpr {
         use self::Expr::*;
         let sub = (*self).borrow_raw();
         match *sub {
-            Unit | What | Var(_) | Int(_) | Bool(_) | App(_, _) => write!(f, "{}", sub),
+            Unit
+            | What
+            | Hole(_)
+            | Var(_)
+            | Int(_)
+            | Float(_)
+            | Bool(_)
+            | Str(_)
+            | Nil
+            | Construct(_, _, None)
+            | Record(_, _)
+            | Field(_, _, _)
+            | Tuple(_)
+            | Index(_, _) => write!(f, "{}", sub),
             _ => write!(f, "({})", sub),
         }
     }
 }
 
+// a type annotation as it's written after a name (': T'), which is left out
+// altogether if it was left out of the program
+pub struct Annotation<'a>(pub &'a TypeExpr);
+
+impl<'a> fmt::Display for Annotation<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.0 {
+            TypeExpr::Unknown(_) => Ok(()),
+            ref type_expr => write!(f, ": {}", type_expr),
+        }
+    }
+}
+
+// a type a program declares ('type t = A | B of T'), each value of which is made
+// by one of its constructors (from a value of the type the constructor is
+// given, if it's given anything)
+#[derive(Clone)]
+pub struct Datatype {
+    pub name: Var,
+    pub constructors: Vec<(Var, Option<TypeExpr>)>,
+}
+
+impl fmt::Display for Datatype {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "type {} =", self.name)?;
+        for (i, (c, type_expr)) in self.constructors.iter().enumerate() {
+            if i > 0 {
+                write!(f, " |")?;
+            }
+            write!(f, " {}", c)?;
+            if let Some(ref type_expr) = *type_expr {
+                write!(f, " of {}", type_expr)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// something a module's structure defines, which is in scope for everything it
+// defines after it
+#[derive(Clone)]
+pub enum Item {
+    Type(Var, TypeExpr),
+    Let(Var, TypeExpr, SubExpr),
+    LetFun(Var, Lambda, TypeExpr),
+}
+
+// something a module's signature says it exports
+#[derive(Clone)]
+pub enum Spec {
+    // a type whose representation is hidden outside the module
+    Type(Var),
+    Val(Var, TypeExpr),
+}
+
+#[derive(Clone)]
+pub struct Module {
+    pub name: Var,
+    // a module without a signature exports everything it defines, as it's
+    // defined
+    pub signature: Option<Vec<Spec>>,
+    pub items: Vec<Item>,
+    // the file its structure is read from, if it's imported ('import
+    // "file.slang"') rather than written out
+    pub source: Option<String>,
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Item::Type(ref t, ref type_expr) => write!(f, "type {} = {}", t, type_expr),
+            Item::Let(ref v, ref type_expr, ref sub) => {
+                write!(f, "let {}{} = {}", v, Annotation(type_expr), sub)
+            }
+            Item::LetFun(
+                ref v,
+                (ref v_lambda, ref type_expr_lambda, ref sub_lambda),
+                ref type_expr,
+            ) => {
+                write!(
+                    f,
+                    "let {} ({}{}){} = {}",
+                    v,
+                    v_lambda,
+                    Annotation(type_expr_lambda),
+                    Annotation(type_expr),
+                    sub_lambda
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Spec::Type(ref t) => write!(f, "type {}", t),
+            Spec::Val(ref v, ref type_expr) => write!(f, "val {} : {}", v, type_expr),
+        }
+    }
+}
+
+// what a branch of a 'match' takes apart the value it's given with
+#[derive(Clone)]
+pub enum Pattern {
+    // '_', which matches anything without binding it
+    Wildcard,
+    Var(Var),
+    Unit,
+    Int(i64),
+    Bool(bool),
+    Pair(Box<Pattern>, Box<Pattern>),
+    // '(p, q, r)' (and so on), for a tuple of three or more
+    Tuple(Vec<Pattern>),
+    Inl(Box<Pattern>),
+    Inr(Box<Pattern>),
+    // a constructor of a data type (named along with it, once names have been
+    // resolved), with a pattern for what it was given if it's given anything
+    Construct(Var, Var, Option<Box<Pattern>>),
+    // '[]'
+    Nil,
+    // 'p :: q'
+    Cons(Box<Pattern>, Box<Pattern>),
+}
+
+impl Pattern {
+    // the variables this pattern binds, in the order they appear in it
+    pub fn vars(&self) -> Vec<&Var> {
+        use self::Pattern::*;
+        match *self {
+            Wildcard | Unit | Int(_) | Bool(_) | Nil => vec![],
+            Var(ref v) => vec![v],
+            Pair(ref left, ref right) | Cons(ref left, ref right) => {
+                let mut vars = left.vars();
+                vars.extend(right.vars());
+                vars
+            }
+            Tuple(ref patterns) => patterns.iter().flat_map(Pattern::vars).collect(),
+            Inl(ref sub) | Inr(ref sub) | Construct(_, _, Some(ref sub)) => sub.vars(),
+            Construct(_, _, None) => vec![],
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Pattern::*;
+        match *self {
+            Wildcard => write!(f, "_"),
+            Var(ref v) => write!(f, "{}", v),
+            Unit => write!(f, "()"),
+            Int(ref i) => write!(f, "{}", i),
+            Bool(ref b) => write!(f, "{}", b),
+            Pair(ref left, ref right) => write!(f, "({}, {})", left, right),
+            Tuple(ref patterns) => {
+                let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                write!(f, "({})", patterns.join(", "))
+            }
+            Inl(ref sub) => write!(f, "inl {}", sub),
+            Inr(ref sub) => write!(f, "inr {}", sub),
+            Construct(_, ref c, None) => write!(f, "{}", c),
+            Construct(_, ref c, Some(ref sub)) => match **sub {
+                Inl(_) | Inr(_) | Construct(_, _, Some(_)) => write!(f, "{} ({})", c, sub),
+                _ => write!(f, "{} {}", c, sub),
+            },
+            Nil => write!(f, "[]"),
+            // '::' associates to the right, so only a list on its left needs
+            // parentheses
+            Cons(ref left, ref right) => match **left {
+                Cons(_, _) => write!(f, "({}) :: {}", left, right),
+                _ => write!(f, "{} :: {}", left, right),
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum Expr {
     Unit,
     What,
+    // a hole in a program, which the type checker reports the type of
+    Hole(Var),
     Var(Var),
     Int(i64),
+    Float(f64),
     Bool(bool),
+    Str(String),
     UnOp(UnOp, SubExpr),
     BinOp(BinOp, SubExpr, SubExpr),
+    // 'e < e', 'e = e' or 'e == e', with the type of what's compared, which is
+    // worked out by the type checker
+    Comparison(BinOp, SubExpr, SubExpr, TypeExpr),
     If(SubExpr, SubExpr, SubExpr),
     Pair(SubExpr, SubExpr),
     Fst(SubExpr),
     Snd(SubExpr),
+    Ignore(SubExpr),
     Inl(SubExpr, TypeExpr),
     Inr(SubExpr, TypeExpr),
     Case(SubExpr, Lambda, Lambda),
+    // the types of what's matched and of what the match gives are never
+    // written, so are always worked out by the type checker
+    Match(SubExpr, TypeExpr, Vec<(Pattern, SubExpr)>, TypeExpr),
     Lambda(Lambda),
     While(SubExpr, SubExpr),
+    // 'for i = a to b do e end', which runs 'e' with 'i' bound to each int
+    // from 'a' up to 'b' in turn
+    For(Var, SubExpr, SubExpr, SubExpr),
     Seq(Vec<SubExpr>),
     Ref(SubExpr),
     Deref(SubExpr),
+    Lazy(SubExpr),
+    Force(SubExpr),
+    Chan(TypeExpr),
+    Spawn(SubExpr),
+    // 'raise e', which raises the int 'e' as an exception
+    Raise(SubExpr),
+    // 'try e with x -> e end', where the handler is run with the exception
+    // as 'x' if 'e' raises one
+    Try(SubExpr, Lambda),
+    Send(SubExpr, SubExpr),
+    Recv(SubExpr),
+    // writes a string to the output as it is (with no newline after it)
+    Print(SubExpr),
+    // 'expect e true' (or 'false'), which is 'e', but tells the compiler which
+    // way a condition is likely to go
+    Expect(SubExpr, bool),
+    // the body of a function with attributes, beneath all of its parameters
+    Attributed(Attributes, SubExpr),
     Assign(SubExpr, SubExpr),
+    // 'Array.make n e', an array of 'n' copies of what 'e' gives
+    MakeArray(SubExpr, SubExpr),
+    // 'a[i]'
+    Index(SubExpr, SubExpr),
+    // 'a[i] := e'
+    Update(SubExpr, SubExpr, SubExpr),
+    // '[]'
+    Nil,
+    // 'e :: e'
+    Cons(SubExpr, SubExpr),
+    // 'List.hd e' and 'List.tl e', which fail on an empty list
+    Head(SubExpr),
+    Tail(SubExpr),
+    // 'List.is_empty e'
+    IsEmpty(SubExpr),
     App(SubExpr, SubExpr),
     Let(Var, TypeExpr, SubExpr, SubExpr),
     LetFun(Var, Lambda, TypeExpr, SubExpr),
+    // 'let rec f ... and g ... in e end', where each function is in scope in
+    // all of their bodies (as well as in 'e')
+    LetRec(Vec<Function>, SubExpr),
+    Module(Module, SubExpr),
+    // 'open M in e end', where what 'M' exports is in scope in 'e' without
+    // its name in front of it (until names are resolved, after which there
+    // are none left)
+    Open(Var, SubExpr),
+    // 'type t = A | B of T in e end', where the type and its constructors are
+    // in scope in 'e'
+    Data(Datatype, SubExpr),
+    // a constructor of a data type (named along with it, once names have been
+    // resolved) and what it's given. One that's given something but isn't
+    // applied to anything is a function
+    Construct(Var, Var, Option<SubExpr>),
+    // '{x = e, y = e}', with its fields in the order they're written (which
+    // is the order they're run in) and its type, which is worked out by the
+    // type checker
+    Record(Vec<(Var, SubExpr)>, TypeExpr),
+    // 'e.x', with the type of the record it's taken from, which is worked out
+    // by the type checker
+    Field(SubExpr, Var, TypeExpr),
+    // '(e, e, e)' (and so on), a tuple of three or more values, which are run
+    // in the order they're written
+    Tuple(Vec<SubExpr>),
+    // '#k e', the 'k'th component (counting from 1) of a tuple or a pair, with
+    // the type it's taken from, which is worked out by the type checker
+    Proj(SubExpr, usize, TypeExpr),
+}
+
+impl Expr {
+    pub fn children(&self) -> Vec<&Locatable<Expr>> {
+        use self::Expr::*;
+        match *self {
+            Unit
+            | What
+            | Hole(_)
+            | Var(_)
+            | Int(_)
+            | Float(_)
+            | Bool(_)
+            | Str(_)
+            | Nil
+            | Chan(_)
+            | Construct(_, _, None) => {
+                vec![]
+            }
+            UnOp(_, ref sub)
+            | Fst(ref sub)
+            | Snd(ref sub)
+            | Ignore(ref sub)
+            | Inl(ref sub, _)
+            | Inr(ref sub, _)
+            | Lambda((_, _, ref sub))
+            | Ref(ref sub)
+            | Deref(ref sub)
+            | Lazy(ref sub)
+            | Force(ref sub)
+            | Spawn(ref sub)
+            | Raise(ref sub)
+            | Recv(ref sub)
+            | Print(ref sub)
+            | Expect(ref sub, _)
+            | Attributed(_, ref sub)
+            | Head(ref sub)
+            | Tail(ref sub)
+            | IsEmpty(ref sub)
+            | Data(_, ref sub)
+            | Open(_, ref sub)
+            | Construct(_, _, Some(ref sub))
+            | Field(ref sub, _, _)
+            | Proj(ref sub, _, _) => vec![sub],
+            BinOp(_, ref left, ref right)
+            | Comparison(_, ref left, ref right, _)
+            | Send(ref left, ref right)
+            | Pair(ref left, ref right)
+            | While(ref left, ref right)
+            | Assign(ref left, ref right)
+            | MakeArray(ref left, ref right)
+            | Index(ref left, ref right)
+            | Cons(ref left, ref right)
+            | App(ref left, ref right)
+            | Let(_, _, ref left, ref right)
+            | LetFun(_, (_, _, ref left), _, ref right)
+            | Try(ref left, (_, _, ref right)) => vec![left, right],
+            If(ref condition, ref left, ref right)
+            | Update(ref condition, ref left, ref right)
+            | For(_, ref condition, ref left, ref right)
+            | Case(ref condition, (_, _, ref left), (_, _, ref right)) => {
+                vec![condition, left, right]
+            }
+            Seq(ref seq) | Tuple(ref seq) => seq.iter().map(|sub| &**sub).collect(),
+            LetRec(ref functions, ref body) => {
+                let mut children = functions
+                    .iter()
+                    .map(|(_, (_, _, sub), _)| &**sub)
+                    .collect::<Vec<_>>();
+                children.push(&**body);
+                children
+            }
+            Record(ref fields, _) => fields.iter().map(|(_, sub)| &**sub).collect(),
+            Match(ref sub, _, ref arms, _) => {
+                let mut children = vec![&**sub];
+                children.extend(arms.iter().map(|(_, body)| &**body));
+                children
+            }
+            Module(ref module, ref body) => {
+                let mut children = vec![];
+                for item in module.items.iter() {
+                    match *item {
+                        Item::Type(_, _) => (),
+                        Item::Let(_, _, ref sub) | Item::LetFun(_, (_, _, ref sub), _) => {
+                            children.push(&**sub)
+                        }
+                    }
+                }
+                children.push(&**body);
+                children
+            }
+        }
+    }
+
+    pub fn children_mut(&mut self) -> Vec<&mut Locatable<Expr>> {
+        use self::Expr::*;
+        match *self {
+            Unit
+            | What
+            | Hole(_)
+            | Var(_)
+            | Int(_)
+            | Float(_)
+            | Bool(_)
+            | Str(_)
+            | Nil
+            | Chan(_)
+            | Construct(_, _, None) => {
+                vec![]
+            }
+            UnOp(_, ref mut sub)
+            | Fst(ref mut sub)
+            | Snd(ref mut sub)
+            | Ignore(ref mut sub)
+            | Inl(ref mut sub, _)
+            | Inr(ref mut sub, _)
+            | Lambda((_, _, ref mut sub))
+            | Ref(ref mut sub)
+            | Deref(ref mut sub)
+            | Lazy(ref mut sub)
+            | Force(ref mut sub)
+            | Spawn(ref mut sub)
+            | Raise(ref mut sub)
+            | Recv(ref mut sub)
+            | Print(ref mut sub)
+            | Expect(ref mut sub, _)
+            | Attributed(_, ref mut sub)
+            | Head(ref mut sub)
+            | Tail(ref mut sub)
+            | IsEmpty(ref mut sub)
+            | Data(_, ref mut sub)
+            | Open(_, ref mut sub)
+            | Construct(_, _, Some(ref mut sub))
+            | Field(ref mut sub, _, _)
+            | Proj(ref mut sub, _, _) => vec![sub],
+            BinOp(_, ref mut left, ref mut right)
+            | Comparison(_, ref mut left, ref mut right, _)
+            | Send(ref mut left, ref mut right)
+            | Pair(ref mut left, ref mut right)
+            | While(ref mut left, ref mut right)
+            | Assign(ref mut left, ref mut right)
+            | MakeArray(ref mut left, ref mut right)
+            | Index(ref mut left, ref mut right)
+            | Cons(ref mut left, ref mut right)
+            | App(ref mut left, ref mut right)
+            | Let(_, _, ref mut left, ref mut right)
+            | LetFun(_, (_, _, ref mut left), _, ref mut right)
+            | Try(ref mut left, (_, _, ref mut right)) => vec![left, right],
+            If(ref mut condition, ref mut left, ref mut right)
+            | Update(ref mut condition, ref mut left, ref mut right)
+            | For(_, ref mut condition, ref mut left, ref mut right)
+            | Case(ref mut condition, (_, _, ref mut left), (_, _, ref mut right)) => {
+                vec![condition, left, right]
+            }
+            Seq(ref mut seq) | Tuple(ref mut seq) => seq.iter_mut().map(|sub| &mut **sub).collect(),
+            LetRec(ref mut functions, ref mut body) => {
+                let mut children = functions
+                    .iter_mut()
+                    .map(|(_, (_, _, sub), _)| &mut **sub)
+                    .collect::<Vec<_>>();
+                children.push(&mut **body);
+                children
+            }
+            Record(ref mut fields, _) => fields.iter_mut().map(|(_, sub)| &mut **sub).collect(),
+            Match(ref mut sub, _, ref mut arms, _) => {
+                let mut children = vec![&mut **sub];
+                children.extend(arms.iter_mut().map(|(_, body)| &mut **body));
+                children
+            }
+            Module(ref mut module, ref mut body) => {
+                let mut children = vec![];
+                for item in module.items.iter_mut() {
+                    match *item {
+                        Item::Type(_, _) => (),
+                        Item::Let(_, _, ref mut sub) | Item::LetFun(_, (_, _, ref mut sub), _) => {
+                            children.push(&mut **sub)
+                        }
+                    }
+                }
+                children.push(&mut **body);
+                children
+            }
+        }
+    }
+
+    // the type annotations on this expression itself (not its subexpressions)
+    pub fn annotations(&self) -> Vec<&TypeExpr> {
+        use self::Expr::*;
+        match *self {
+            Inl(_, ref type_expr)
+            | Inr(_, ref type_expr)
+            | Chan(ref type_expr)
+            | Record(_, ref type_expr)
+            | Comparison(_, _, _, ref type_expr)
+            | Field(_, _, ref type_expr)
+            | Proj(_, _, ref type_expr)
+            | Lambda((_, ref type_expr, _))
+            | Try(_, (_, ref type_expr, _))
+            | Let(_, ref type_expr, _, _) => vec![type_expr],
+            Case(_, (_, ref left, _), (_, ref right, _))
+            | Match(_, ref left, _, ref right)
+            | LetFun(_, (_, ref left, _), ref right, _) => vec![left, right],
+            LetRec(ref functions, _) => functions
+                .iter()
+                .flat_map(|(_, (_, left, _), right)| vec![left, right])
+                .collect(),
+            Module(ref module, _) => {
+                let mut annotations = vec![];
+                for item in module.items.iter() {
+                    match *item {
+                        Item::Type(_, _) => (),
+                        Item::Let(_, ref type_expr, _) => annotations.push(type_expr),
+                        Item::LetFun(_, (_, ref left, _), ref right) => {
+                            annotations.push(left);
+                            annotations.push(right);
+                        }
+                    }
+                }
+                for spec in module.signature.iter().flatten() {
+                    if let Spec::Val(_, ref type_expr) = *spec {
+                        annotations.push(type_expr);
+                    }
+                }
+                annotations
+            }
+            Data(ref datatype, _) => datatype
+                .constructors
+                .iter()
+                .filter_map(|(_, type_expr)| type_expr.as_ref())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn annotations_mut(&mut self) -> Vec<&mut TypeExpr> {
+        use self::Expr::*;
+        match *self {
+            Inl(_, ref mut type_expr)
+            | Inr(_, ref mut type_expr)
+            | Chan(ref mut type_expr)
+            | Record(_, ref mut type_expr)
+            | Comparison(_, _, _, ref mut type_expr)
+            | Field(_, _, ref mut type_expr)
+            | Proj(_, _, ref mut type_expr)
+            | Lambda((_, ref mut type_expr, _))
+            | Try(_, (_, ref mut type_expr, _))
+            | Let(_, ref mut type_expr, _, _) => vec![type_expr],
+            Case(_, (_, ref mut left, _), (_, ref mut right, _))
+            | Match(_, ref mut left, _, ref mut right)
+            | LetFun(_, (_, ref mut left, _), ref mut right, _) => vec![left, right],
+            LetRec(ref mut functions, _) => functions
+                .iter_mut()
+                .flat_map(|(_, (_, left, _), right)| vec![left, right])
+                .collect(),
+            Module(ref mut module, _) => {
+                let mut annotations = vec![];
+                for item in module.items.iter_mut() {
+                    match *item {
+                        Item::Type(_, _) => (),
+                        Item::Let(_, ref mut type_expr, _) => annotations.push(type_expr),
+                        Item::LetFun(_, (_, ref mut left, _), ref mut right) => {
+                            annotations.push(left);
+                            annotations.push(right);
+                        }
+                    }
+                }
+                for spec in module.signature.iter_mut().flatten() {
+                    if let Spec::Val(_, ref mut type_expr) = *spec {
+                        annotations.push(type_expr);
+                    }
+                }
+                annotations
+            }
+            Data(ref mut datatype, _) => datatype
+                .constructors
+                .iter_mut()
+                .filter_map(|(_, type_expr)| type_expr.as_mut())
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+// the operands of a chain of left-associative operators ('a + b + c'), the
+// leftmost first. A chain can be as long as a program is, so passes walk down
+// one in a loop (with this) rather than by recursing
+pub fn operands(expr: &Locatable<Expr>) -> Vec<&Locatable<Expr>> {
+    let mut operands = vec![];
+    let mut expr = expr;
+    while let Expr::BinOp(_, ref left, ref right) = expr.t {
+        operands.push(&**right);
+        expr = left;
+    }
+    operands.push(expr);
+    operands.reverse();
+    operands
+}
+
+pub fn operands_mut(expr: &mut Locatable<Expr>) -> Vec<&mut Locatable<Expr>> {
+    let mut operands = vec![];
+    let mut expr = expr;
+    while let Expr::BinOp(_, ref mut left, ref mut right) = expr.t {
+        operands.push(&mut **right);
+        expr = left;
+    }
+    operands.push(expr);
+    operands.reverse();
+    operands
 }
 
 impl fmt::Display for Expr {
@@ -53,17 +631,38 @@ impl fmt::Display for Expr {
         match *self {
             Unit => write!(f, "()"),
             What => write!(f, "?"),
+            Hole(ref name) => write!(f, "?{}", name),
             Var(ref v) => write!(f, "{}", v),
             Int(ref i) => write!(f, "{}", i),
+            Float(x) => write!(f, "{}", float_literal(x)),
             Bool(ref b) => write!(f, "{}", b),
+            Str(ref string) => write!(f, "{}", quote(string)),
             UnOp(ref op, ref sub) => write!(f, "{}{}", op, sub),
-            BinOp(ref op, ref left, ref right) => write!(f, "{} {} {}", left, op, right),
+            BinOp(ref op, ref left, ref right) => {
+                // a chain ('(a + b) + c') is written out in a loop, as it can
+                // be too long to recurse down
+                let mut links = vec![(op, right)];
+                let mut left = left;
+                while let BinOp(ref op, ref sub, ref right) = *left.borrow_raw() {
+                    links.push((op, right));
+                    left = sub;
+                }
+                write!(f, "{}{}", "(".repeat(links.len() - 1), left)?;
+                for (i, (op, right)) in links.into_iter().rev().enumerate() {
+                    write!(f, "{} {} {}", if i == 0 { "" } else { ")" }, op, right)?;
+                }
+                Ok(())
+            }
+            Comparison(ref op, ref left, ref right, _) => {
+                write!(f, "{} {} {}", left, op, right)
+            }
             If(ref condition, ref left, ref right) => {
                 write!(f, "if {} then {} else {} end", condition, left, right)
             }
             Pair(ref left, ref right) => write!(f, "({}, {})", left, right),
             Fst(ref sub) => write!(f, "fst {}", sub),
             Snd(ref sub) => write!(f, "snd {}", sub),
+            Ignore(ref sub) => write!(f, "ignore {}", sub),
             Inl(ref sub, ref type_expr) => write!(f, "inl {} {}", type_expr, sub),
             Inr(ref sub, ref type_expr) => write!(f, "inr {} {}", type_expr, sub),
             Case(
@@ -72,13 +671,29 @@ impl fmt::Display for Expr {
                 (ref v_right, ref type_expr_right, ref sub_right),
             ) => write!(
                 f,
-                "case {} of inl({}: {}) -> {} | inr({}: {}) -> {}",
-                sub, v_left, type_expr_left, sub_left, v_right, type_expr_right, sub_right
+                "case {} of inl({}{}) -> {} | inr({}{}) -> {} end",
+                sub,
+                v_left,
+                Annotation(type_expr_left),
+                sub_left,
+                v_right,
+                Annotation(type_expr_right),
+                sub_right
             ),
+            Match(ref sub, _, ref arms, _) => {
+                write!(f, "match {} with", sub)?;
+                for (pattern, body) in arms.iter() {
+                    write!(f, " | {} -> {}", pattern, body)?;
+                }
+                write!(f, " end")
+            }
             Lambda((ref v, ref type_expr, ref sub)) => {
-                write!(f, "fun {}: {} -> {} end", v, type_expr, sub)
+                write!(f, "fun ({}{}) -> {} end", v, Annotation(type_expr), sub)
             }
             While(ref condition, ref sub) => write!(f, "while {} do {} end", condition, sub),
+            For(ref v, ref from, ref to, ref sub) => {
+                write!(f, "for {} = {} to {} do {} end", v, from, to, sub)
+            }
             Seq(ref seq) => {
                 write!(f, "begin ")?;
                 let mut first = true;
@@ -94,10 +709,52 @@ impl fmt::Display for Expr {
             }
             Ref(ref sub) => write!(f, "ref {}", sub),
             Deref(ref sub) => write!(f, "!{}", sub),
+            Lazy(ref sub) => write!(f, "lazy {}", sub),
+            Force(ref sub) => write!(f, "force {}", sub),
+            Chan(ref type_expr) => {
+                write!(f, "chan ")?;
+                type_expr.fmt_operand(f, 3)
+            }
+            Spawn(ref sub) => write!(f, "spawn {}", sub),
+            Raise(ref sub) => write!(f, "raise {}", sub),
+            Try(ref sub, (ref v, ref type_expr, ref handler)) => write!(
+                f,
+                "try {} with {}{} -> {} end",
+                sub,
+                v,
+                Annotation(type_expr),
+                handler
+            ),
+            Send(ref left, ref right) => write!(f, "send {} {}", left, right),
+            Recv(ref sub) => write!(f, "recv {}", sub),
+            Print(ref sub) => write!(f, "print_string {}", sub),
+            Expect(ref sub, likely) => write!(f, "expect {} {}", sub, likely),
+            Attributed(attributes, ref sub) => write!(f, "{} {}", attributes, sub),
             Assign(ref left, ref right) => write!(f, "{} := {}", left, right),
-            App(ref left, ref right) => write!(f, "{} {}", left, right),
+            MakeArray(ref left, ref right) => write!(f, "Array.make {} {}", left, right),
+            Index(ref left, ref right) => write!(f, "{}[{}]", left, right.borrow_raw()),
+            Update(ref array, ref index, ref right) => {
+                write!(f, "{}[{}] := {}", array, index.borrow_raw(), right)
+            }
+            Nil => write!(f, "[]"),
+            Cons(ref left, ref right) => write!(f, "{} :: {}", left, right),
+            Head(ref sub) => write!(f, "List.hd {}", sub),
+            Tail(ref sub) => write!(f, "List.tl {}", sub),
+            IsEmpty(ref sub) => write!(f, "List.is_empty {}", sub),
+            // application associates to the left, so only the argument needs parentheses
+            App(ref left, ref right) => match *left.borrow_raw() {
+                App(_, _) => write!(f, "{} {}", left.borrow_raw(), right),
+                _ => write!(f, "{} {}", left, right),
+            },
             Let(ref v, ref type_expr, ref sub, ref body) => {
-                write!(f, "let {}: {} = {} in {} end", v, type_expr, sub, body)
+                write!(
+                    f,
+                    "let {}{} = {} in {} end",
+                    v,
+                    Annotation(type_expr),
+                    sub,
+                    body
+                )
             }
             LetFun(
                 ref v,
@@ -106,9 +763,72 @@ impl fmt::Display for Expr {
                 ref body,
             ) => write!(
                 f,
-                "let {} ({}: {}): {} = {} in {} end",
-                v, v_lambda, type_expr_lambda, type_expr, sub_lambda, body
+                "let {} ({}{}){} = {} in {} end",
+                v,
+                v_lambda,
+                Annotation(type_expr_lambda),
+                Annotation(type_expr),
+                sub_lambda,
+                body
             ),
+            LetRec(ref functions, ref body) => {
+                for (i, (v, (v_lambda, type_expr_lambda, sub_lambda), type_expr)) in
+                    functions.iter().enumerate()
+                {
+                    write!(
+                        f,
+                        "{} {} ({}{}){} = {} ",
+                        if i == 0 { "let rec" } else { "and" },
+                        v,
+                        v_lambda,
+                        Annotation(type_expr_lambda),
+                        Annotation(type_expr),
+                        sub_lambda
+                    )?;
+                }
+                write!(f, "in {} end", body)
+            }
+            Module(ref module, ref body) => {
+                write!(f, "module {}", module.name)?;
+                if let Some(ref signature) = module.signature {
+                    write!(f, " : sig")?;
+                    for spec in signature.iter() {
+                        write!(f, " {}", spec)?;
+                    }
+                    write!(f, " end")?;
+                }
+                if let Some(ref source) = module.source {
+                    return write!(f, " = import {} in {} end", quote(source), body);
+                }
+                write!(f, " = struct")?;
+                for item in module.items.iter() {
+                    write!(f, " {}", item)?;
+                }
+                write!(f, " end in {} end", body)
+            }
+            Open(ref name, ref body) => write!(f, "open {} in {} end", name, body),
+            Data(ref datatype, ref body) => write!(f, "{} in {} end", datatype, body),
+            Construct(_, ref c, None) => write!(f, "{}", c),
+            Construct(_, ref c, Some(ref sub)) => write!(f, "{} {}", c, sub),
+            Record(ref fields, _) => {
+                write!(f, "{{")?;
+                for (i, (x, sub)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{} = {}", x, sub.borrow_raw())?;
+                }
+                write!(f, "}}")
+            }
+            Field(ref sub, ref x, _) => write!(f, "{}.{}", sub, x),
+            Tuple(ref subs) => {
+                let subs = subs
+                    .iter()
+                    .map(|sub| sub.borrow_raw().to_string())
+                    .collect::<Vec<_>>();
+                write!(f, "({})", subs.join(", "))
+            }
+            Proj(ref sub, k, _) => write!(f, "#{} {}", k, sub),
         }
     }
 }
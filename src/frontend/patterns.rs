@@ -0,0 +1,917 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::mem;
+
+use super::ast::BinOp;
+use super::past::{self, Datatype, Expr, Pattern, Var};
+use super::types::TypeExpr;
+use super::{Locatable, Location};
+
+// the patterns inside a pattern made by the same constructor as 'constructor'
+// ('_' for each of them if the pattern matches anything), or nothing if it's
+// made by a different one. '_' as a constructor stands for a value made by a
+// constructor that no pattern names
+fn inside(pattern: &Pattern, constructor: &Pattern) -> Option<Vec<Pattern>> {
+    use self::Pattern::*;
+    match (pattern, constructor) {
+        (Wildcard, _) | (Var(_), _) => Some(match *constructor {
+            Pair(_, _) | Cons(_, _) => vec![Wildcard, Wildcard],
+            Tuple(ref patterns) => vec![Wildcard; patterns.len()],
+            Inl(_) | Inr(_) | Construct(_, _, Some(_)) => vec![Wildcard],
+            _ => vec![],
+        }),
+        (Unit, Unit) | (Nil, Nil) => Some(vec![]),
+        (Int(i), Int(j)) if i == j => Some(vec![]),
+        (Bool(a), Bool(b)) if a == b => Some(vec![]),
+        (Pair(left, right), Pair(_, _)) | (Cons(left, right), Cons(_, _)) => {
+            Some(vec![(**left).clone(), (**right).clone()])
+        }
+        (Tuple(patterns), Tuple(_)) => Some(patterns.clone()),
+        (Inl(sub), Inl(_)) | (Inr(sub), Inr(_)) => Some(vec![(**sub).clone()]),
+        (Construct(_, c, sub), Construct(_, d, _)) if c == d => {
+            Some(sub.iter().map(|sub| (**sub).clone()).collect())
+        }
+        _ => None,
+    }
+}
+
+// every constructor that makes values of a type (along with the types of what's
+// inside each), if there are few enough of them to list
+fn constructors(
+    type_expr: &TypeExpr,
+    datatypes: &HashMap<Var, Datatype>,
+) -> Option<Vec<(Pattern, Vec<TypeExpr>)>> {
+    use self::Pattern::*;
+    match *type_expr {
+        TypeExpr::Unit => Some(vec![(Unit, vec![])]),
+        TypeExpr::Bool => Some(vec![(Bool(true), vec![]), (Bool(false), vec![])]),
+        TypeExpr::Product(ref left, ref right) => Some(vec![(
+            Pair(Box::new(Wildcard), Box::new(Wildcard)),
+            vec![(**left).clone(), (**right).clone()],
+        )]),
+        TypeExpr::Tuple(ref types) => {
+            Some(vec![(Tuple(vec![Wildcard; types.len()]), types.clone())])
+        }
+        TypeExpr::Union(ref left, ref right) => Some(vec![
+            (Inl(Box::new(Wildcard)), vec![(**left).clone()]),
+            (Inr(Box::new(Wildcard)), vec![(**right).clone()]),
+        ]),
+        TypeExpr::List(ref t) => Some(vec![
+            (Nil, vec![]),
+            (
+                Cons(Box::new(Wildcard), Box::new(Wildcard)),
+                vec![(**t).clone(), type_expr.clone()],
+            ),
+        ]),
+        TypeExpr::Named(ref t) => datatypes.get(t).map(|datatype| {
+            datatype
+                .constructors
+                .iter()
+                .map(|(c, type_expr)| {
+                    let sub = type_expr.as_ref().map(|_| Box::new(Wildcard));
+                    (
+                        Construct(t.clone(), c.clone(), sub),
+                        type_expr.iter().cloned().collect(),
+                    )
+                })
+                .collect()
+        }),
+        _ => None,
+    }
+}
+
+// puts the patterns that were inside a constructor back inside it
+fn rebuild(constructor: &Pattern, inside: Vec<Pattern>) -> Pattern {
+    let mut inside = inside.into_iter().map(Box::new);
+    match *constructor {
+        Pattern::Pair(_, _) => Pattern::Pair(inside.next().unwrap(), inside.next().unwrap()),
+        Pattern::Cons(_, _) => Pattern::Cons(inside.next().unwrap(), inside.next().unwrap()),
+        Pattern::Tuple(_) => Pattern::Tuple(inside.map(|pattern| *pattern).collect()),
+        Pattern::Inl(_) => Pattern::Inl(inside.next().unwrap()),
+        Pattern::Inr(_) => Pattern::Inr(inside.next().unwrap()),
+        Pattern::Construct(ref t, ref c, _) => {
+            Pattern::Construct(t.clone(), c.clone(), inside.next())
+        }
+        ref constructor => constructor.clone(),
+    }
+}
+
+// a value (written as a pattern, with '_' for any value at all) that none of
+// the rows match, if there is one, where each row is a list of patterns that
+// are matched against values of the types given, one each
+fn unmatched(
+    rows: &[Vec<Pattern>],
+    types: &[TypeExpr],
+    datatypes: &HashMap<Var, Datatype>,
+) -> Option<Vec<Pattern>> {
+    let (type_expr, rest) = match types.split_first() {
+        Some(split) => split,
+        None if rows.is_empty() => return Some(vec![]),
+        None => return None,
+    };
+    // a list's tail is a list too, so a column of lists that every row matches
+    // whatever it is would be taken apart forever
+    let anything = rows
+        .iter()
+        .all(|row| matches!(row[0], Pattern::Wildcard | Pattern::Var(_)));
+    let listed = match *type_expr {
+        TypeExpr::List(_) if anything => None,
+        _ => constructors(type_expr, datatypes),
+    };
+    let (constructors, first) = match listed {
+        Some(constructors) => (constructors, None),
+        // any other value can only be matched by a literal (if it's an
+        // integer) or by a pattern that matches anything, so the value that
+        // isn't matched is one that no literal is
+        None => {
+            let literals = rows
+                .iter()
+                .filter_map(|row| match row[0] {
+                    Pattern::Int(i) => Some(i),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let first = match *type_expr {
+                TypeExpr::Int => Pattern::Int((0..).find(|i| !literals.contains(i)).unwrap()),
+                _ => Pattern::Wildcard,
+            };
+            (vec![(Pattern::Wildcard, vec![])], Some(first))
+        }
+    };
+    for (constructor, inside_types) in constructors {
+        let rows = rows
+            .iter()
+            .filter_map(|row| {
+                let mut inside = inside(&row[0], &constructor)?;
+                inside.extend(row[1..].iter().cloned());
+                Some(inside)
+            })
+            .collect::<Vec<_>>();
+        let arity = inside_types.len();
+        let mut types = inside_types;
+        types.extend(rest.iter().cloned());
+        if let Some(mut unmatched) = unmatched(&rows, &types, datatypes) {
+            let rest = unmatched.split_off(arity);
+            let constructor = rebuild(&constructor, unmatched);
+            let mut unmatched = vec![first.unwrap_or(constructor)];
+            unmatched.extend(rest);
+            return Some(unmatched);
+        }
+    }
+    None
+}
+
+// a value of a type that none of the patterns match (written as a pattern), if
+// there is one
+pub fn missing(
+    patterns: &[&Pattern],
+    type_expr: &TypeExpr,
+    datatypes: &HashMap<Var, Datatype>,
+) -> Option<Pattern> {
+    let rows = patterns
+        .iter()
+        .map(|pattern| vec![(*pattern).clone()])
+        .collect::<Vec<_>>();
+    unmatched(&rows, std::slice::from_ref(type_expr), datatypes)
+        .map(|mut unmatched| unmatched.remove(0))
+}
+
+// a branch of a 'match' as it's compiled: the patterns it has left to match
+// (against the values being taken apart, one each), the variables it's bound
+// so far (each to the name of a value), and which arm it is
+struct Row {
+    patterns: Vec<Pattern>,
+    bound: Vec<(Var, Var)>,
+    arm: usize,
+}
+
+// the rows that match a value made by a constructor in a column (which is
+// 'value'), with what's inside the constructor in its place
+fn specialise(rows: &[Row], column: usize, value: &Var, constructor: &Pattern) -> Vec<Row> {
+    let mut specialised = vec![];
+    for row in rows.iter() {
+        let inside = match inside(&row.patterns[column], constructor) {
+            Some(inside) => inside,
+            None => continue,
+        };
+        let mut patterns = row.patterns[..column].to_vec();
+        patterns.extend(inside);
+        patterns.extend(row.patterns[column + 1..].iter().cloned());
+        let mut bound = row.bound.clone();
+        if let Pattern::Var(ref v) = row.patterns[column] {
+            bound.push((v.clone(), value.clone()));
+        }
+        specialised.push(Row {
+            patterns,
+            bound,
+            arm: row.arm,
+        });
+    }
+    specialised
+}
+
+// the values being taken apart, with what's inside the value in a column in
+// its place
+fn replace(values: &[Var], column: usize, inside: Vec<Var>) -> Vec<Var> {
+    let mut replaced = values[..column].to_vec();
+    replaced.extend(inside);
+    replaced.extend(values[column + 1..].iter().cloned());
+    replaced
+}
+
+// how many expressions there are in an expression
+fn size(expr: &Locatable<Expr>) -> usize {
+    let mut size = 0;
+    let mut work = vec![expr];
+    while let Some(expr) = work.pop() {
+        size += 1;
+        work.extend(expr.t.children());
+    }
+    size
+}
+
+// whether an expression calls a function as the last thing it does
+fn calls_last(expr: &Expr) -> bool {
+    match *expr {
+        Expr::App(_, _) => true,
+        Expr::If(_, ref left, ref right) | Expr::Case(_, (_, _, ref left), (_, _, ref right)) => {
+            calls_last(&left.t) || calls_last(&right.t)
+        }
+        Expr::Let(_, _, _, ref body)
+        | Expr::LetFun(_, _, _, ref body)
+        | Expr::LetRec(_, ref body)
+        | Expr::Module(_, ref body)
+        | Expr::Open(_, ref body)
+        | Expr::Expect(ref body, _) => calls_last(&body.t),
+        Expr::Seq(ref seq) => seq.last().is_some_and(|last| calls_last(&last.t)),
+        _ => false,
+    }
+}
+
+// subtrees that cost less than this (counting each test in them and each
+// expression in the arms they run) are copied to everywhere they're reached
+// rather than shared, as jumping to a join point means building a closure and
+// calling it, which costs about as much as running a handful of expressions
+const SMALL: usize = 16;
+
+// a part of a value that's taken out of it to be matched on its own
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Part {
+    Fst,
+    Snd,
+    Proj(usize),
+    Head,
+    Tail,
+}
+
+impl fmt::Display for Part {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Part::Fst => write!(f, "fst"),
+            Part::Snd => write!(f, "snd"),
+            Part::Proj(k) => write!(f, "#{}", k),
+            Part::Head => write!(f, "List.hd"),
+            Part::Tail => write!(f, "List.tl"),
+        }
+    }
+}
+
+// what a 'match' is compiled into: the tests it makes on the values it takes
+// apart, and the arm that each path through them ends at. A value is named
+// after where it is in what's matched, so two paths that leave the same
+// rows to match lead to equal subtrees, which is how they're found to share
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Tree {
+    // runs an arm (counting from 0), with each variable its pattern binds
+    // bound to the value it matched
+    Arm(usize, Vec<(Var, Var)>),
+    // takes a part out of a value
+    Let(Var, Part, Var, Box<Tree>),
+    If(Var, Box<Tree>, Box<Tree>),
+    // whether an int is a literal
+    Equals(Var, i64, Box<Tree>, Box<Tree>),
+    // which side of a union a value is on, naming what's inside it on each
+    Case(Var, (Var, Box<Tree>), (Var, Box<Tree>)),
+    // whether a list is empty
+    IsEmpty(Var, Box<Tree>, Box<Tree>),
+    // a subtree that more than one path leads to, defined once (as a function
+    // of '()') above all of them, and jumped to from each
+    Join(Var, Box<Tree>, Box<Tree>),
+    Jump(Var),
+}
+
+impl Tree {
+    fn children(&self) -> Vec<&Tree> {
+        match *self {
+            Tree::Arm(_, _) | Tree::Jump(_) => vec![],
+            Tree::Let(_, _, _, ref body) => vec![body],
+            Tree::If(_, ref left, ref right)
+            | Tree::Equals(_, _, ref left, ref right)
+            | Tree::Case(_, (_, ref left), (_, ref right))
+            | Tree::IsEmpty(_, ref left, ref right)
+            | Tree::Join(_, ref left, ref right) => vec![left, right],
+        }
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut Tree> {
+        match *self {
+            Tree::Arm(_, _) | Tree::Jump(_) => vec![],
+            Tree::Let(_, _, _, ref mut body) => vec![body],
+            Tree::If(_, ref mut left, ref mut right)
+            | Tree::Equals(_, _, ref mut left, ref mut right)
+            | Tree::Case(_, (_, ref mut left), (_, ref mut right))
+            | Tree::IsEmpty(_, ref mut left, ref mut right)
+            | Tree::Join(_, ref mut left, ref mut right) => vec![left, right],
+        }
+    }
+
+    // the values a tree uses that it doesn't take out of others itself
+    fn free(&self) -> HashSet<&Var> {
+        match *self {
+            Tree::Arm(_, ref bound) => bound.iter().map(|(_, value)| value).collect(),
+            Tree::Let(ref v, _, ref value, ref body) => {
+                let mut free = body.free();
+                free.remove(v);
+                free.insert(value);
+                free
+            }
+            Tree::If(ref value, ref left, ref right)
+            | Tree::Equals(ref value, _, ref left, ref right)
+            | Tree::IsEmpty(ref value, ref left, ref right) => {
+                let mut free = left.free();
+                free.extend(right.free());
+                free.insert(value);
+                free
+            }
+            Tree::Case(ref value, (ref v_left, ref left), (ref v_right, ref right)) => {
+                let mut free = left.free();
+                free.remove(v_left);
+                let mut free_right = right.free();
+                free_right.remove(v_right);
+                free.extend(free_right);
+                free.insert(value);
+                free
+            }
+            Tree::Join(_, ref def, ref body) => {
+                let mut free = def.free();
+                free.extend(body.free());
+                free
+            }
+            Tree::Jump(_) => HashSet::new(),
+        }
+    }
+
+    // replaces each copy of a subtree with a jump to a join point
+    fn replace(&mut self, shared: &Tree, join: &Var) {
+        if *self == *shared {
+            *self = Tree::Jump(join.clone());
+        } else {
+            for sub in self.children_mut() {
+                sub.replace(shared, join);
+            }
+        }
+    }
+}
+
+// a 'match' as it's compiled: the types of the values it takes apart, each
+// named after the value it's a part of (starting from what's matched itself,
+// which is 'name'), and its arms
+struct Match {
+    name: Var,
+    types: HashMap<Var, TypeExpr>,
+    result: TypeExpr,
+    patterns: Vec<Pattern>,
+    bodies: Vec<Locatable<Expr>>,
+    joins: usize,
+}
+
+impl Match {
+    // the name of a part of a value (for a tuple, its position)
+    fn part(&mut self, value: &Var, part: &str, type_expr: TypeExpr) -> Var {
+        let v = format!("{}.{}", value, part);
+        self.types.insert(v.clone(), type_expr);
+        v
+    }
+
+    // which column to test next: one that the first row needs tested (as
+    // it can't be picked until they all are), choosing the one that the most
+    // rows from the top need tested, then the one that splits into the fewest
+    // branches, then the one whose constructors have the fewest parts, then
+    // the leftmost (Maranget's heuristics 'p', 'b' and 'a'). There's none if
+    // the first row matches whatever's left
+    fn column(&self, values: &[Var], rows: &[Row]) -> Option<usize> {
+        let tested = |pattern: &Pattern| !matches!(*pattern, Pattern::Wildcard | Pattern::Var(_));
+        (0..values.len())
+            .filter(|&column| tested(&rows[0].patterns[column]))
+            .min_by_key(|&column| {
+                let prefix = rows
+                    .iter()
+                    .take_while(|row| tested(&row.patterns[column]))
+                    .count();
+                let (branches, parts) = match self.types[&values[column]] {
+                    TypeExpr::Bool => (2, 0),
+                    TypeExpr::Product(_, _) => (1, 2),
+                    TypeExpr::Tuple(ref types) => (1, types.len()),
+                    TypeExpr::Union(_, _) | TypeExpr::List(_) => (2, 2),
+                    // one for each literal, and one for anything else
+                    TypeExpr::Int => {
+                        let literals = rows
+                            .iter()
+                            .filter_map(|row| match row.patterns[column] {
+                                Pattern::Int(i) => Some(i),
+                                _ => None,
+                            })
+                            .collect::<HashSet<_>>();
+                        (literals.len() + 1, 0)
+                    }
+                    _ => (1, 0),
+                };
+                (Reverse(prefix), branches, parts)
+            })
+    }
+
+    // the tree that finds the first row to match the values, testing one
+    // column at a time. Matches have been checked to cover every value, so
+    // some row always does
+    fn build(&mut self, values: Vec<Var>, rows: Vec<Row>) -> Tree {
+        let column = match self.column(&values, &rows) {
+            Some(column) => column,
+            None => {
+                let row = rows.into_iter().next().unwrap();
+                let mut bound = row.bound;
+                for (pattern, value) in row.patterns.iter().zip(values) {
+                    if let Pattern::Var(ref v) = *pattern {
+                        bound.push((v.clone(), value));
+                    }
+                }
+                return Tree::Arm(row.arm, bound);
+            }
+        };
+        let value = values[column].clone();
+        let type_expr = self.types[&value].clone();
+        let wildcard = || Box::new(Pattern::Wildcard);
+        match type_expr {
+            TypeExpr::Unit => {
+                let rows = specialise(&rows, column, &value, &Pattern::Unit);
+                self.build(replace(&values, column, vec![]), rows)
+            }
+            TypeExpr::Bool => {
+                let values = replace(&values, column, vec![]);
+                let left = specialise(&rows, column, &value, &Pattern::Bool(true));
+                let right = specialise(&rows, column, &value, &Pattern::Bool(false));
+                let left = self.build(values.clone(), left);
+                let right = self.build(values, right);
+                Tree::If(value, Box::new(left), Box::new(right))
+            }
+            TypeExpr::Product(t1, t2) => {
+                let left = self.part(&value, "1", *t1);
+                let right = self.part(&value, "2", *t2);
+                let rows = specialise(
+                    &rows,
+                    column,
+                    &value,
+                    &Pattern::Pair(wildcard(), wildcard()),
+                );
+                let values = replace(&values, column, vec![left.clone(), right.clone()]);
+                let body = self.build(values, rows);
+                let body = Tree::Let(right, Part::Snd, value.clone(), Box::new(body));
+                Tree::Let(left, Part::Fst, value, Box::new(body))
+            }
+            // each component is projected from the tuple, which the tuple is
+            // erased along with later
+            TypeExpr::Tuple(types) => {
+                let components = types
+                    .into_iter()
+                    .enumerate()
+                    .map(|(k, t)| self.part(&value, &(k + 1).to_string(), t))
+                    .collect::<Vec<_>>();
+                let rows = specialise(
+                    &rows,
+                    column,
+                    &value,
+                    &Pattern::Tuple(vec![Pattern::Wildcard; components.len()]),
+                );
+                let mut body = self.build(replace(&values, column, components.clone()), rows);
+                for (k, component) in components.into_iter().enumerate().rev() {
+                    body = Tree::Let(component, Part::Proj(k + 1), value.clone(), Box::new(body));
+                }
+                body
+            }
+            TypeExpr::Union(t1, t2) => {
+                let left = self.part(&value, "l", *t1);
+                let right = self.part(&value, "r", *t2);
+                let inl = specialise(&rows, column, &value, &Pattern::Inl(wildcard()));
+                let inr = specialise(&rows, column, &value, &Pattern::Inr(wildcard()));
+                let inl = self.build(replace(&values, column, vec![left.clone()]), inl);
+                let inr = self.build(replace(&values, column, vec![right.clone()]), inr);
+                Tree::Case(value, (left, Box::new(inl)), (right, Box::new(inr)))
+            }
+            // an integer is compared with each literal in turn
+            TypeExpr::Int => {
+                let values = replace(&values, column, vec![]);
+                let mut literals: Vec<i64> = vec![];
+                for row in rows.iter() {
+                    if let Pattern::Int(i) = row.patterns[column] {
+                        if !literals.contains(&i) {
+                            literals.push(i);
+                        }
+                    }
+                }
+                let otherwise = specialise(&rows, column, &value, &Pattern::Wildcard);
+                let mut tree = self.build(values.clone(), otherwise);
+                for i in literals.into_iter().rev() {
+                    let matched = specialise(&rows, column, &value, &Pattern::Int(i));
+                    let matched = self.build(values.clone(), matched);
+                    tree = Tree::Equals(value.clone(), i, Box::new(matched), Box::new(tree));
+                }
+                tree
+            }
+            TypeExpr::List(t) => {
+                let head = self.part(&value, "h", *t);
+                let tail = self.part(&value, "t", self.types[&value].clone());
+                let nil = specialise(&rows, column, &value, &Pattern::Nil);
+                let cons = specialise(
+                    &rows,
+                    column,
+                    &value,
+                    &Pattern::Cons(wildcard(), wildcard()),
+                );
+                let nil = self.build(replace(&values, column, vec![]), nil);
+                let values = replace(&values, column, vec![head.clone(), tail.clone()]);
+                let cons = self.build(values, cons);
+                let cons = Tree::Let(tail, Part::Tail, value.clone(), Box::new(cons));
+                let cons = Tree::Let(head, Part::Head, value.clone(), Box::new(cons));
+                Tree::IsEmpty(value, Box::new(nil), Box::new(cons))
+            }
+            // only patterns that match anything can match anything else
+            _ => unreachable!(),
+        }
+    }
+
+    // whether a subtree costs enough that jumping to it is cheaper than
+    // copying it, and only runs arms that don't call a function as the last
+    // thing they do (as in a join point, the call wouldn't be the last thing
+    // the function around the 'match' does any more, so recursion through it
+    // would use up stack)
+    fn worth_sharing(&self, tree: &Tree) -> bool {
+        let mut cost = 0;
+        let mut work = vec![tree];
+        while let Some(tree) = work.pop() {
+            cost += 1;
+            if let Tree::Arm(arm, _) = *tree {
+                if calls_last(&self.bodies[arm].t) {
+                    return false;
+                }
+                cost += size(&self.bodies[arm]);
+            }
+            work.extend(tree.children());
+        }
+        cost >= SMALL
+    }
+
+    // the biggest subtree worth sharing that more than one of the branches
+    // of a test (or of the join points above it) lead to, and that only uses
+    // values in scope there
+    fn shared(&self, branches: &[&Tree], scope: &[Var]) -> Option<Tree> {
+        let mut reached: HashMap<&Tree, usize> = HashMap::new();
+        let mut order = vec![];
+        for branch in branches.iter() {
+            let mut seen = HashSet::new();
+            let mut work = vec![*branch];
+            while let Some(tree) = work.pop() {
+                if !seen.insert(tree) {
+                    continue;
+                }
+                let count = reached.entry(tree).or_insert(0);
+                if *count == 0 {
+                    order.push(tree);
+                }
+                *count += 1;
+                work.extend(tree.children());
+            }
+        }
+        let mut shared: Option<(&Tree, usize)> = None;
+        for tree in order {
+            if reached[tree] < 2
+                || !tree.free().iter().all(|value| scope.contains(value))
+                || !self.worth_sharing(tree)
+            {
+                continue;
+            }
+            let cost = size_of(tree);
+            if shared.is_none_or(|(_, biggest)| cost > biggest) {
+                shared = Some((tree, cost));
+            }
+        }
+        shared.map(|(tree, _)| tree.clone())
+    }
+
+    // turns each subtree worth sharing that's reached from more than one
+    // branch of a test into a join point just above the test (if the values
+    // it uses are in scope there, and otherwise leaves it copied). 'scope' is
+    // what's been taken apart above the tree
+    fn share(&mut self, tree: Tree, scope: &mut Vec<Var>) -> Tree {
+        let mut tree = match tree {
+            Tree::Let(v, part, value, body) => {
+                scope.push(v.clone());
+                let body = self.share(*body, scope);
+                scope.pop();
+                return Tree::Let(v, part, value, Box::new(body));
+            }
+            Tree::Arm(_, _) | Tree::Jump(_) | Tree::Join(_, _, _) => return tree,
+            tree => tree,
+        };
+        let mut joins: Vec<(Var, Tree)> = vec![];
+        loop {
+            let shared = {
+                let mut branches = tree.children();
+                branches.extend(joins.iter().map(|(_, def)| def));
+                match self.shared(&branches, scope) {
+                    Some(shared) => shared,
+                    None => break,
+                }
+            };
+            self.joins += 1;
+            let join = format!("{}.k{}", self.name, self.joins);
+            for branch in tree.children_mut() {
+                branch.replace(&shared, &join);
+            }
+            for (_, def) in joins.iter_mut() {
+                def.replace(&shared, &join);
+            }
+            joins.push((join, shared));
+        }
+        tree = match tree {
+            Tree::If(value, left, right) => {
+                let left = self.share(*left, scope);
+                let right = self.share(*right, scope);
+                Tree::If(value, Box::new(left), Box::new(right))
+            }
+            Tree::Equals(value, i, left, right) => {
+                let left = self.share(*left, scope);
+                let right = self.share(*right, scope);
+                Tree::Equals(value, i, Box::new(left), Box::new(right))
+            }
+            Tree::IsEmpty(value, left, right) => {
+                let left = self.share(*left, scope);
+                let right = self.share(*right, scope);
+                Tree::IsEmpty(value, Box::new(left), Box::new(right))
+            }
+            Tree::Case(value, (v_left, left), (v_right, right)) => {
+                scope.push(v_left.clone());
+                let left = self.share(*left, scope);
+                scope.pop();
+                scope.push(v_right.clone());
+                let right = self.share(*right, scope);
+                scope.pop();
+                Tree::Case(value, (v_left, Box::new(left)), (v_right, Box::new(right)))
+            }
+            _ => unreachable!(),
+        };
+        // a join point that's shared later is smaller, so it can be jumped to
+        // from ones shared before it but not the other way round, and goes
+        // outside them
+        for (join, def) in joins {
+            let def = self.share(def, scope);
+            tree = Tree::Join(join, Box::new(def), Box::new(tree));
+        }
+        tree
+    }
+
+    // the expression a tree is compiled into
+    fn expr(&self, location: &Location, tree: &Tree) -> Locatable<Expr> {
+        let at = |expr: Expr| -> Locatable<Expr> { (location.clone(), expr).into() };
+        let var = |v: &Var| Box::new(at(Expr::Var(v.clone())));
+        let sub = |tree: &Tree| Box::new(self.expr(location, tree));
+        match *tree {
+            Tree::Arm(arm, ref bound) => {
+                let mut body = self.bodies[arm].clone();
+                for (v, value) in bound.iter().rev() {
+                    body = at(Expr::Let(
+                        v.clone(),
+                        self.types[value].clone(),
+                        var(value),
+                        Box::new(body),
+                    ));
+                }
+                body
+            }
+            Tree::Let(ref v, part, ref value, ref body) => {
+                let part = match part {
+                    Part::Fst => Expr::Fst(var(value)),
+                    Part::Snd => Expr::Snd(var(value)),
+                    Part::Proj(k) => Expr::Proj(var(value), k, self.types[value].clone()),
+                    Part::Head => Expr::Head(var(value)),
+                    Part::Tail => Expr::Tail(var(value)),
+                };
+                at(Expr::Let(
+                    v.clone(),
+                    self.types[v].clone(),
+                    Box::new(at(part)),
+                    sub(body),
+                ))
+            }
+            Tree::If(ref value, ref left, ref right) => {
+                at(Expr::If(var(value), sub(left), sub(right)))
+            }
+            Tree::Equals(ref value, i, ref left, ref right) => {
+                let condition = at(Expr::Comparison(
+                    BinOp::Eq,
+                    var(value),
+                    Box::new(at(Expr::Int(i))),
+                    TypeExpr::Int,
+                ));
+                at(Expr::If(Box::new(condition), sub(left), sub(right)))
+            }
+            Tree::Case(ref value, (ref v_left, ref left), (ref v_right, ref right)) => {
+                at(Expr::Case(
+                    var(value),
+                    (v_left.clone(), self.types[v_left].clone(), sub(left)),
+                    (v_right.clone(), self.types[v_right].clone(), sub(right)),
+                ))
+            }
+            Tree::IsEmpty(ref value, ref left, ref right) => at(Expr::If(
+                Box::new(at(Expr::IsEmpty(var(value)))),
+                sub(left),
+                sub(right),
+            )),
+            Tree::Join(ref join, ref def, ref body) => {
+                let lambda = (format!("{}.()", join), TypeExpr::Unit, sub(def));
+                at(Expr::Let(
+                    join.clone(),
+                    TypeExpr::Arrow(Box::new(TypeExpr::Unit), Box::new(self.result.clone())),
+                    Box::new(at(Expr::Lambda(lambda))),
+                    sub(body),
+                ))
+            }
+            Tree::Jump(ref join) => at(Expr::App(var(join), Box::new(at(Expr::Unit)))),
+        }
+    }
+
+    // writes a tree out, a test (or an arm) to a line, with what each test
+    // leads to indented beneath it
+    fn render(&self, tree: &Tree, depth: usize, out: &mut String) {
+        let indent = "  ".repeat(depth);
+        match *tree {
+            Tree::Arm(arm, ref bound) => {
+                write!(out, "{}arm {}: {}", indent, arm + 1, self.patterns[arm]).unwrap();
+                let bound = bound
+                    .iter()
+                    .map(|(v, value)| format!("{} = {}", v, value))
+                    .collect::<Vec<_>>();
+                if !bound.is_empty() {
+                    write!(out, " (with {})", bound.join(", ")).unwrap();
+                }
+                writeln!(out).unwrap();
+            }
+            Tree::Let(ref v, part, ref value, ref body) => {
+                writeln!(out, "{}let {} = {} {}", indent, v, part, value).unwrap();
+                self.render(body, depth, out);
+            }
+            Tree::If(ref value, ref left, ref right) => {
+                writeln!(out, "{}if {} then", indent, value).unwrap();
+                self.render(left, depth + 1, out);
+                writeln!(out, "{}else", indent).unwrap();
+                self.render(right, depth + 1, out);
+            }
+            Tree::Equals(ref value, i, ref left, ref right) => {
+                writeln!(out, "{}if {} = {} then", indent, value, i).unwrap();
+                self.render(left, depth + 1, out);
+                writeln!(out, "{}else", indent).unwrap();
+                self.render(right, depth + 1, out);
+            }
+            Tree::IsEmpty(ref value, ref left, ref right) => {
+                writeln!(out, "{}if List.is_empty {} then", indent, value).unwrap();
+                self.render(left, depth + 1, out);
+                writeln!(out, "{}else", indent).unwrap();
+                self.render(right, depth + 1, out);
+            }
+            Tree::Case(ref value, (ref v_left, ref left), (ref v_right, ref right)) => {
+                writeln!(out, "{}case {} of", indent, value).unwrap();
+                writeln!(out, "{}inl {} ->", indent, v_left).unwrap();
+                self.render(left, depth + 1, out);
+                writeln!(out, "{}inr {} ->", indent, v_right).unwrap();
+                self.render(right, depth + 1, out);
+            }
+            Tree::Join(ref join, ref def, ref body) => {
+                writeln!(out, "{}join {} =", indent, join).unwrap();
+                self.render(def, depth + 1, out);
+                self.render(body, depth, out);
+            }
+            Tree::Jump(ref join) => writeln!(out, "{}jump {}", indent, join).unwrap(),
+        }
+    }
+}
+
+// how many tests and arms there are in a tree
+fn size_of(tree: &Tree) -> usize {
+    1 + tree.children().into_iter().map(size_of).sum::<usize>()
+}
+
+struct Compiler<'a> {
+    fresh: usize,
+    // each tree written out, with where its 'match' is, if they're wanted
+    trees: Option<&'a mut Vec<(Location, String)>>,
+}
+
+impl<'a> Compiler<'a> {
+    // a '.' can't appear in a name a program binds (and 'match' can't be the
+    // name of a module), so these never capture the program's own variables
+    fn fresh(&mut self) -> Var {
+        self.fresh += 1;
+        format!("match.{}", self.fresh)
+    }
+
+    fn erase(&mut self, expr: &mut Locatable<Expr>) {
+        // a chain of definitions ('let x = e in let y = e in ...') is walked
+        // down in a loop, as a program of top-level definitions is one (and
+        // a 'let' itself has no 'match' to compile)
+        let mut expr = expr;
+        while let Expr::Let(..) | Expr::LetFun(..) | Expr::LetRec(..) = expr.t {
+            let mut children = expr.t.children_mut();
+            let body = children.pop().unwrap();
+            for sub in children {
+                self.erase(sub);
+            }
+            expr = body;
+        }
+        if let Expr::BinOp(..) = expr.t {
+            for sub in past::operands_mut(expr) {
+                self.erase(sub);
+            }
+            return;
+        }
+        for sub in expr.t.children_mut() {
+            self.erase(sub);
+        }
+        if let Expr::Match(_, _, _, _) = expr.t {
+            let (sub, type_expr, arms, result) = match mem::replace(&mut expr.t, Expr::Unit) {
+                Expr::Match(sub, type_expr, arms, result) => (sub, type_expr, arms, result),
+                _ => unreachable!(),
+            };
+            let value = self.fresh();
+            let (patterns, bodies): (Vec<_>, Vec<_>) = arms
+                .into_iter()
+                .map(|(pattern, body)| (pattern, *body))
+                .unzip();
+            let rows = patterns
+                .iter()
+                .enumerate()
+                .map(|(arm, pattern)| Row {
+                    patterns: vec![pattern.clone()],
+                    bound: vec![],
+                    arm,
+                })
+                .collect();
+            let mut types = HashMap::new();
+            types.insert(value.clone(), type_expr.clone());
+            let mut matching = Match {
+                name: value.clone(),
+                types,
+                result,
+                patterns,
+                bodies,
+                joins: 0,
+            };
+            let tree = matching.build(vec![value.clone()], rows);
+            let tree = matching.share(tree, &mut vec![value.clone()]);
+            let location = expr.location().clone();
+            if let Some(ref mut trees) = self.trees {
+                let mut out = format!("{} : {}\n", value, type_expr);
+                matching.render(&tree, 1, &mut out);
+                trees.push((location.clone(), out));
+            }
+            let body = matching.expr(&location, &tree);
+            expr.t = Expr::Let(value, type_expr, sub, Box::new(body));
+        }
+    }
+}
+
+// compiles each 'match' in a (checked) program into the 'let's, 'case's and
+// 'if's that take apart what it matches, so that the rest of the compiler
+// never sees one. The tree each is compiled into is written into 'trees' (in
+// the order the matches are written in), if it's given
+pub fn erase(expr: &mut Locatable<Expr>, trees: Option<&mut Vec<String>>) {
+    let mut located = vec![];
+    let mut compiler = Compiler {
+        fresh: 0,
+        trees: if trees.is_some() {
+            Some(&mut located)
+        } else {
+            None
+        },
+    };
+    compiler.erase(expr);
+    if let Some(trees) = trees {
+        located.sort_by_key(|(location, _)| (location.line(), location.column()));
+        for (location, tree) in located {
+            trees.push(format!(
+                "line {}: column {}: match on {}",
+                location.line(),
+                location.column(),
+                tree
+            ));
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use super::{log, Diagnostic, Location};
+
+// an '#if' that hasn't been closed yet
+struct Open {
+    location: Location,
+    // whether the name it tests is defined (or isn't, for '#if !NAME')
+    condition: bool,
+    // whether the code around it is kept, as nothing inside it is otherwise
+    enclosing: bool,
+    kept: bool,
+    otherwise: bool,
+}
+
+// resolves the '#if NAME', '#else' and '#end' lines in a program's source
+// against the names defined for it, keeping the code under each '#if' only if
+// its name is defined (and the code under its '#else' only if it isn't). Any
+// line it drops (the directives included) is left blank, so that everything it
+// keeps is still where it was in the source
+pub fn resolve(filename: &str, text: &str, defines: &[String]) -> Result<String, Diagnostic> {
+    let mut open: Vec<Open> = vec![];
+    let mut resolved = String::with_capacity(text.len());
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        let trimmed = line.trim_start();
+        let kept = open.last().is_none_or(|open| open.kept);
+        // a line can also start with a projection ('#1 p')
+        let directive = match trimmed.strip_prefix('#') {
+            Some(directive) if !directive.starts_with(|c: char| c.is_ascii_digit()) => directive,
+            _ => {
+                if kept {
+                    resolved.push_str(line);
+                } else if line.ends_with('\n') {
+                    resolved.push('\n');
+                }
+                continue;
+            }
+        };
+        let location = Location::new(
+            filename.to_string(),
+            i + 1,
+            line.chars().count() - trimmed.chars().count() + 1,
+        );
+        let mut words = directive.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("if"), Some(name), None) => {
+                let (negated, name) = match name.strip_prefix('!') {
+                    Some(name) => (true, name),
+                    None => (false, name),
+                };
+                let condition = defines.iter().any(|define| define == name) != negated;
+                open.push(Open {
+                    location,
+                    condition,
+                    enclosing: kept,
+                    kept: kept && condition,
+                    otherwise: false,
+                });
+            }
+            (Some("else"), None, None) => match open.last_mut() {
+                Some(open) if !open.otherwise => {
+                    open.otherwise = true;
+                    open.kept = open.enclosing && !open.condition;
+                }
+                Some(_) => {
+                    return Err(log::parse_error(
+                        &location,
+                        "'#if' already has an '#else'".to_string(),
+                    ))
+                }
+                None => {
+                    return Err(log::parse_error(
+                        &location,
+                        "'#else' without an '#if'".to_string(),
+                    ))
+                }
+            },
+            (Some("end"), None, None) => {
+                if open.pop().is_none() {
+                    return Err(log::parse_error(
+                        &location,
+                        "'#end' without an '#if'".to_string(),
+                    ));
+                }
+            }
+            _ => {
+                return Err(log::parse_error(
+                    &location,
+                    "expected '#if NAME', '#else' or '#end'".to_string(),
+                ))
+            }
+        }
+        if line.ends_with('\n') {
+            resolved.push('\n');
+        }
+    }
+    match open.pop() {
+        Some(open) => Err(log::parse_error(
+            &open.location,
+            "'#if' without an '#end'".to_string(),
+        )),
+        None => Ok(resolved),
+    }
+}
@@ -0,0 +1,191 @@
+use std::mem;
+
+use super::past::{self, Expr, SubExpr, Var};
+use super::types::TypeExpr;
+use super::{Locatable, Location};
+
+// the type that represents a record type or a tuple type (with the record and
+// tuple types in the types inside it already replaced): the first half of its
+// types paired with the second (unless there's only one). Splitting them in
+// half rather than pairing the first with the rest keeps the pairs as shallow
+// as they can be, so that a wide tuple isn't one deeply nested value
+fn product(types: &[TypeExpr]) -> TypeExpr {
+    match types.len() {
+        // every record type has at least one field
+        0 => unreachable!(),
+        1 => types[0].clone(),
+        n => {
+            let (left, right) = types.split_at(n / 2);
+            TypeExpr::Product(Box::new(product(left)), Box::new(product(right)))
+        }
+    }
+}
+
+// a type with each record type and tuple type in it replaced by what
+// represents it
+pub fn represent(type_expr: &TypeExpr) -> TypeExpr {
+    type_expr.replace(&mut |type_expr| match *type_expr {
+        TypeExpr::Record(ref fields) => Some(product(
+            &fields
+                .iter()
+                .map(|(_, type_expr)| represent(type_expr))
+                .collect::<Vec<_>>(),
+        )),
+        TypeExpr::Tuple(ref types) => {
+            Some(product(&types.iter().map(represent).collect::<Vec<_>>()))
+        }
+        _ => None,
+    })
+}
+
+// the fields of a record type, in order
+fn fields(type_expr: &TypeExpr) -> &[(Var, TypeExpr)] {
+    match *type_expr {
+        TypeExpr::Record(ref fields) => fields,
+        // the type checker has worked out the type of every record
+        _ => unreachable!(),
+    }
+}
+
+// the 'k'th of 'n' values (counting from 0) taken from the pairs that represent
+// them, by taking the first or second of each pair (whichever half it's in)
+// until it's reached
+fn project(location: &Location, sub: Locatable<Expr>, mut k: usize, mut n: usize) -> Expr {
+    let mut projected = sub;
+    while n > 1 {
+        let half = n / 2;
+        let sub = Box::new(projected);
+        projected = if k < half {
+            n = half;
+            (location.clone(), Expr::Fst(sub)).into()
+        } else {
+            k -= half;
+            n -= half;
+            (location.clone(), Expr::Snd(sub)).into()
+        };
+    }
+    projected.into_raw()
+}
+
+// the values of a record's fields (or a tuple's components), in order, as the
+// pairs that represent it (see 'product')
+fn pairs(location: &Location, mut values: Vec<Locatable<Expr>>) -> Expr {
+    if values.len() == 1 {
+        values.pop().unwrap().into_raw()
+    } else {
+        let right = values.split_off(values.len() / 2);
+        let left = (location.clone(), pairs(location, values)).into();
+        let right = (location.clone(), pairs(location, right)).into();
+        Expr::Pair(Box::new(left), Box::new(right))
+    }
+}
+
+struct Eraser {
+    fresh: usize,
+}
+
+impl Eraser {
+    // a '.' can't appear in a name a program binds, so these never capture the
+    // program's own variables
+    fn fresh(&mut self) -> Var {
+        self.fresh += 1;
+        format!("record.{}", self.fresh)
+    }
+
+    // a record's fields are run in the order they're written, so any that
+    // aren't written in order are bound to names first, and paired up after
+    fn record(
+        &mut self,
+        location: &Location,
+        fields: Vec<(Var, SubExpr)>,
+        type_expr: &TypeExpr,
+    ) -> Expr {
+        let types = self::fields(type_expr);
+        if fields
+            .iter()
+            .map(|(x, _)| x)
+            .eq(types.iter().map(|(x, _)| x))
+        {
+            let values = fields.into_iter().map(|(_, sub)| *sub).collect();
+            return pairs(location, values);
+        }
+        let names = fields
+            .iter()
+            .map(|(x, _)| (x.clone(), self.fresh()))
+            .collect::<Vec<_>>();
+        let values = types
+            .iter()
+            .map(|(x, _)| {
+                let (_, v) = names.iter().find(|(y, _)| y == x).unwrap();
+                (location.clone(), Expr::Var(v.clone())).into()
+            })
+            .collect();
+        let mut record = pairs(location, values);
+        for ((x, sub), (_, v)) in fields.into_iter().zip(names).rev() {
+            let (_, field_type_expr) = types.iter().find(|(y, _)| *y == x).unwrap();
+            let body = Box::new((location.clone(), record).into());
+            record = Expr::Let(v, represent(field_type_expr), sub, body);
+        }
+        record
+    }
+
+    fn erase(&mut self, expr: &mut Locatable<Expr>) {
+        // a chain of definitions ('let x = e in let y = e in ...') is walked
+        // down in a loop, as a program of top-level definitions is one (and
+        // all there is to erase from a 'let' itself is in its annotations)
+        let mut expr = expr;
+        while let Expr::Let(..) | Expr::LetFun(..) | Expr::LetRec(..) = expr.t {
+            for type_expr in expr.t.annotations_mut() {
+                *type_expr = represent(type_expr);
+            }
+            let mut children = expr.t.children_mut();
+            let body = children.pop().unwrap();
+            for sub in children {
+                self.erase(sub);
+            }
+            expr = body;
+        }
+        if let Expr::BinOp(..) = expr.t {
+            for sub in past::operands_mut(expr) {
+                self.erase(sub);
+            }
+            return;
+        }
+        for sub in expr.t.children_mut() {
+            self.erase(sub);
+        }
+        let location = expr.location().clone();
+        expr.t = match mem::replace(&mut expr.t, Expr::Unit) {
+            Expr::Record(fields, type_expr) => self.record(&location, fields, &type_expr),
+            Expr::Field(sub, x, type_expr) => {
+                let types = fields(&type_expr);
+                let k = types.iter().position(|(y, _)| *y == x).unwrap();
+                project(&location, *sub, k, types.len())
+            }
+            // a tuple's components are already in the order they're run in
+            Expr::Tuple(subs) => pairs(&location, subs.into_iter().map(|sub| *sub).collect()),
+            Expr::Proj(sub, k, type_expr) => {
+                let n = match type_expr {
+                    TypeExpr::Tuple(ref types) => types.len(),
+                    // the type checker has worked out that it's a pair otherwise
+                    _ => 2,
+                };
+                project(&location, *sub, k - 1, n)
+            }
+            erased => erased,
+        };
+        for type_expr in expr.t.annotations_mut() {
+            *type_expr = represent(type_expr);
+        }
+    }
+}
+
+// replaces each record type with nested products of its fields' types (in
+// order of their names), each record with the pairs of its fields' values and
+// each field taken from one with the 'fst's and 'snd's that take it from them,
+// once the program has been checked. Tuples are replaced in the same way, as
+// records whose fields are in the order they're written. Like data types,
+// records and tuples are never seen by the rest of the compiler
+pub fn erase(expr: &mut Locatable<Expr>) {
+    Eraser { fresh: 0 }.erase(expr);
+}
@@ -1,18 +1,348 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 
 use super::ast::{BinOp, UnOp};
-use super::past::{Expr, Var};
-use super::{log, Locatable};
+use super::modules::{self, Quantified};
+use super::monomorphise::{self, Copied, Instances};
+use super::past::{Datatype, Expr, Function, Item, Lambda, Module, Pattern, Spec, SubExpr, Var};
+use super::{datatypes, log, patterns, records, Locatable, Location};
 
 #[derive(Clone, PartialEq, Eq)]
 pub enum TypeExpr {
     Unit,
     Bool,
     Int,
+    Float,
+    String,
     Ref(Box<TypeExpr>),
+    Lazy(Box<TypeExpr>),
+    Chan(Box<TypeExpr>),
+    Array(Box<TypeExpr>),
+    List(Box<TypeExpr>),
     Arrow(Box<TypeExpr>, Box<TypeExpr>),
     Product(Box<TypeExpr>, Box<TypeExpr>),
     Union(Box<TypeExpr>, Box<TypeExpr>),
+    // 'T1 * T2 * T3' (and so on), a tuple of three or more types (a tuple of
+    // two is a product), which is replaced by nested products once the program
+    // has been checked
+    Tuple(Vec<TypeExpr>),
+    // '{x: int, y: bool}', with its fields in order of their names (so the
+    // order they're written in doesn't matter), which is replaced by nested
+    // products once the program has been checked
+    Record(Vec<(String, TypeExpr)>),
+    // a type referred to by name ('t', or 't' in module 'M' as 'M.t'), which
+    // is replaced by what it names before a program is checked unless it's a
+    // module's abstract type, which only matches itself until the program has
+    // been checked (when it's replaced by the type that represents it)
+    Named(String),
+    // a type variable ('a), which a 'forall' or a function's annotations bind
+    // (these are replaced by unknowns when names are resolved)
+    Variable(String),
+    // 'forall 'a 'b. T', which is only allowed at the outside of the type of
+    // something a 'let' or a signature's 'val' gives a name to, and taken off
+    // it when names are resolved
+    Forall(Vec<String>, Box<TypeExpr>),
+    // the type of something that failed to type check, which never gets past
+    // the type checker
+    Error,
+    // '_' in an annotation, which the type checker reports the type of
+    Hole,
+    // a type the type checker has to work out for itself, where an annotation
+    // was left out or while it's still working out part of a type (these are
+    // all worked out before the type checker is done with a program)
+    Unknown(usize),
+}
+
+impl TypeExpr {
+    // whether two types could be the same, treating 'Error' as matching anything
+    // (so that one mistake isn't reported again everywhere it's used), as well
+    // as any type that hasn't been worked out yet
+    fn agrees(&self, other: &TypeExpr) -> bool {
+        use self::TypeExpr::*;
+        match (self, other) {
+            (Error, _) | (_, Error) | (Hole, _) | (_, Hole) => true,
+            (Unknown(_), _) | (_, Unknown(_)) => true,
+            (Ref(left), Ref(right))
+            | (Lazy(left), Lazy(right))
+            | (Chan(left), Chan(right))
+            | (Array(left), Array(right))
+            | (List(left), List(right)) => left.agrees(right),
+            (Arrow(left1, right1), Arrow(left2, right2))
+            | (Product(left1, right1), Product(left2, right2))
+            | (Union(left1, right1), Union(left2, right2)) => {
+                left1.agrees(left2) && right1.agrees(right2)
+            }
+            (Tuple(types1), Tuple(types2)) => {
+                types1.len() == types2.len()
+                    && types1.iter().zip(types2).all(|(t1, t2)| t1.agrees(t2))
+            }
+            (Record(fields1), Record(fields2)) => {
+                fields1.len() == fields2.len()
+                    && fields1
+                        .iter()
+                        .zip(fields2)
+                        .all(|((x1, t1), (x2, t2))| x1 == x2 && t1.agrees(t2))
+            }
+            (left, right) => left == right,
+        }
+    }
+
+    // whether this type is completely known (so has no holes or errors in it)
+    fn known(&self) -> bool {
+        use self::TypeExpr::*;
+        match *self {
+            Unit | Bool | Int | Float | String | Named(_) | Variable(_) => true,
+            Error | Hole | Unknown(_) => false,
+            Ref(ref sub)
+            | Lazy(ref sub)
+            | Chan(ref sub)
+            | Array(ref sub)
+            | List(ref sub)
+            | Forall(_, ref sub) => sub.known(),
+            Arrow(ref left, ref right)
+            | Product(ref left, ref right)
+            | Union(ref left, ref right) => left.known() && right.known(),
+            Tuple(ref types) => types.iter().all(TypeExpr::known),
+            Record(ref fields) => fields.iter().all(|(_, type_expr)| type_expr.known()),
+        }
+    }
+
+    fn has_holes(&self) -> bool {
+        use self::TypeExpr::*;
+        match *self {
+            Unit | Bool | Int | Float | String | Named(_) | Variable(_) | Error | Unknown(_) => {
+                false
+            }
+            Hole => true,
+            Ref(ref sub)
+            | Lazy(ref sub)
+            | Chan(ref sub)
+            | Array(ref sub)
+            | List(ref sub)
+            | Forall(_, ref sub) => sub.has_holes(),
+            Arrow(ref left, ref right)
+            | Product(ref left, ref right)
+            | Union(ref left, ref right) => left.has_holes() || right.has_holes(),
+            Tuple(ref types) => types.iter().any(TypeExpr::has_holes),
+            Record(ref fields) => fields.iter().any(|(_, type_expr)| type_expr.has_holes()),
+        }
+    }
+
+    // the unknowns in this type
+    pub(super) fn unknowns(&self) -> Vec<usize> {
+        use self::TypeExpr::*;
+        match *self {
+            Unit | Bool | Int | Float | String | Named(_) | Variable(_) | Error | Hole => vec![],
+            Unknown(n) => vec![n],
+            Ref(ref sub)
+            | Lazy(ref sub)
+            | Chan(ref sub)
+            | Array(ref sub)
+            | List(ref sub)
+            | Forall(_, ref sub) => sub.unknowns(),
+            Arrow(ref left, ref right)
+            | Product(ref left, ref right)
+            | Union(ref left, ref right) => {
+                let mut unknowns = left.unknowns();
+                unknowns.extend(right.unknowns());
+                unknowns
+            }
+            Tuple(ref types) => types.iter().flat_map(TypeExpr::unknowns).collect(),
+            Record(ref fields) => fields
+                .iter()
+                .flat_map(|(_, type_expr)| type_expr.unknowns())
+                .collect(),
+        }
+    }
+
+    // fills the holes in an annotation with whatever is in the same place in
+    // the type that was found, collecting what each hole stood for (as 'Error'
+    // where there was nothing to go on)
+    fn fill(&self, found: &TypeExpr, holes: &mut Vec<TypeExpr>) -> TypeExpr {
+        use self::TypeExpr::*;
+        let (left, right) = match (self, found) {
+            (Ref(_), Ref(sub))
+            | (Lazy(_), Lazy(sub))
+            | (Chan(_), Chan(sub))
+            | (Array(_), Array(sub))
+            | (List(_), List(sub)) => (&**sub, &Error),
+            (Arrow(_, _), Arrow(left, right))
+            | (Product(_, _), Product(left, right))
+            | (Union(_, _), Union(left, right)) => (&**left, &**right),
+            _ => (&Error, &Error),
+        };
+        match *self {
+            Hole => {
+                holes.push(found.clone());
+                found.clone()
+            }
+            Unit | Bool | Int | Float | String | Named(_) | Variable(_) | Error | Unknown(_) => {
+                self.clone()
+            }
+            Forall(ref variables, ref sub) => {
+                Forall(variables.clone(), Box::new(sub.fill(found, holes)))
+            }
+            Ref(ref sub) => Ref(Box::new(sub.fill(left, holes))),
+            Lazy(ref sub) => Lazy(Box::new(sub.fill(left, holes))),
+            Chan(ref sub) => Chan(Box::new(sub.fill(left, holes))),
+            Array(ref sub) => Array(Box::new(sub.fill(left, holes))),
+            List(ref sub) => List(Box::new(sub.fill(left, holes))),
+            Arrow(ref t1, ref t2) => Arrow(
+                Box::new(t1.fill(left, holes)),
+                Box::new(t2.fill(right, holes)),
+            ),
+            Product(ref t1, ref t2) => Product(
+                Box::new(t1.fill(left, holes)),
+                Box::new(t2.fill(right, holes)),
+            ),
+            Union(ref t1, ref t2) => Union(
+                Box::new(t1.fill(left, holes)),
+                Box::new(t2.fill(right, holes)),
+            ),
+            Tuple(ref types) => Tuple(
+                types
+                    .iter()
+                    .enumerate()
+                    .map(|(i, type_expr)| {
+                        let found = match *found {
+                            Tuple(ref found) if found.len() == types.len() => found[i].clone(),
+                            _ => Error,
+                        };
+                        type_expr.fill(&found, holes)
+                    })
+                    .collect(),
+            ),
+            Record(ref fields) => Record(
+                fields
+                    .iter()
+                    .map(|(x, type_expr)| {
+                        let found = match *found {
+                            Record(ref found) => found
+                                .iter()
+                                .find(|(y, _)| y == x)
+                                .map_or(Error, |(_, found)| found.clone()),
+                            _ => Error,
+                        };
+                        (x.clone(), type_expr.fill(&found, holes))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    // this type with each part of it that 'replace' gives something for
+    // replaced by what it gives
+    pub(super) fn replace(
+        &self,
+        replace: &mut dyn FnMut(&TypeExpr) -> Option<TypeExpr>,
+    ) -> TypeExpr {
+        use self::TypeExpr::*;
+        if let Some(replaced) = replace(self) {
+            return replaced;
+        }
+        match *self {
+            Unit | Bool | Int | Float | String | Named(_) | Variable(_) | Error | Hole
+            | Unknown(_) => self.clone(),
+            Forall(ref variables, ref sub) => {
+                Forall(variables.clone(), Box::new(sub.replace(replace)))
+            }
+            Ref(ref sub) => Ref(Box::new(sub.replace(replace))),
+            Lazy(ref sub) => Lazy(Box::new(sub.replace(replace))),
+            Chan(ref sub) => Chan(Box::new(sub.replace(replace))),
+            Array(ref sub) => Array(Box::new(sub.replace(replace))),
+            List(ref sub) => List(Box::new(sub.replace(replace))),
+            Arrow(ref left, ref right) => Arrow(
+                Box::new(left.replace(replace)),
+                Box::new(right.replace(replace)),
+            ),
+            Product(ref left, ref right) => Product(
+                Box::new(left.replace(replace)),
+                Box::new(right.replace(replace)),
+            ),
+            Union(ref left, ref right) => Union(
+                Box::new(left.replace(replace)),
+                Box::new(right.replace(replace)),
+            ),
+            Tuple(ref types) => Tuple(
+                types
+                    .iter()
+                    .map(|type_expr| type_expr.replace(replace))
+                    .collect(),
+            ),
+            Record(ref fields) => Record(
+                fields
+                    .iter()
+                    .map(|(x, type_expr)| (x.clone(), type_expr.replace(replace)))
+                    .collect(),
+            ),
+        }
+    }
+
+    // this type with the abstract types (and data types) in it replaced by what
+    // represents them
+    pub(super) fn expand(&self, representations: &HashMap<Var, TypeExpr>) -> TypeExpr {
+        self.replace(&mut |type_expr| match *type_expr {
+            TypeExpr::Named(ref name) => representations
+                .get(name)
+                .map(|type_expr| type_expr.expand(representations)),
+            _ => None,
+        })
+    }
+
+    // the first part of this type that keeps it out of a class (for '=', a
+    // function, as these can't be compared structurally), collecting the
+    // unknowns in it that have to be in the class for it to be in it
+    fn outside(&self, class: Class, unknowns: &mut Vec<usize>) -> Option<&TypeExpr> {
+        use self::TypeExpr::*;
+        match (self, class) {
+            (Unknown(n), _) => {
+                unknowns.push(*n);
+                None
+            }
+            // an abstract type is checked as what represents it once modules
+            // have been erased
+            (Unit | Bool | Int | Float | String | Named(_) | Variable(_) | Error | Hole, _) => None,
+            (Forall(_, sub), _) => sub.outside(class, unknowns),
+            (Ref(sub) | Lazy(sub), Class::Eq) => sub.outside(class, unknowns),
+            // channels (and arrays) are compared by which one they are, not
+            // what's in them
+            (Chan(_) | Array(_), Class::Eq) => None,
+            // lists are compared (and ordered) element by element
+            (List(sub), _) => sub.outside(class, unknowns),
+            (Product(left, right) | Union(left, right), _) => match left.outside(class, unknowns) {
+                Some(outside) => Some(outside),
+                None => right.outside(class, unknowns),
+            },
+            (Tuple(types), _) => types
+                .iter()
+                .find_map(|type_expr| type_expr.outside(class, unknowns)),
+            (Record(fields), _) => fields
+                .iter()
+                .find_map(|(_, type_expr)| type_expr.outside(class, unknowns)),
+            _ => Some(self),
+        }
+    }
+
+    // binding strength of each type constructor, mirroring the parser
+    fn precedence(&self) -> usize {
+        use self::TypeExpr::*;
+        match *self {
+            Arrow(_, _) | Forall(_, _) => 0,
+            Union(_, _) => 1,
+            Product(_, _) | Tuple(_) => 2,
+            Ref(_) | Lazy(_) | Chan(_) | Array(_) | List(_) => 3,
+            Unit | Bool | Int | Float | String | Named(_) | Variable(_) | Record(_) | Error
+            | Hole | Unknown(_) => 4,
+        }
+    }
+
+    pub(super) fn fmt_operand(&self, f: &mut fmt::Formatter, precedence: usize) -> fmt::Result {
+        if self.precedence() < precedence {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
 }
 
 impl fmt::Display for TypeExpr {
@@ -20,354 +350,2465 @@ impl fmt::Display for TypeExpr {
         use self::TypeExpr::*;
         match *self {
             Unit => write!(f, "unit"),
+            String => write!(f, "string"),
             Bool => write!(f, "bool"),
             Int => write!(f, "int"),
-            Ref(ref sub) => write!(f, "{} ref", sub),
-            Arrow(ref left, ref right) => match **left {
-                Arrow(_, _) => write!(f, "({}) -> {}", left, right),
-                _ => write!(f, "{} -> {}", left, right),
+            Float => write!(f, "float"),
+            Error => write!(f, "<error>"),
+            Hole => write!(f, "_"),
+            Named(ref name) => write!(f, "{}", name),
+            Variable(ref name) => write!(f, "'{}", name),
+            Forall(ref variables, ref sub) => {
+                write!(f, "forall")?;
+                for variable in variables.iter() {
+                    write!(f, " '{}", variable)?;
+                }
+                write!(f, ". {}", sub)
+            }
+            // named as OCaml names type variables: 'a to 'z, then 'a1 and so on
+            Unknown(n) => {
+                write!(f, "'{}", (b'a' + (n % 26) as u8) as char)?;
+                if n >= 26 {
+                    write!(f, "{}", n / 26)?;
+                }
+                Ok(())
+            }
+            Ref(ref sub) => {
+                sub.fmt_operand(f, 3)?;
+                write!(f, " ref")
+            }
+            Lazy(ref sub) => {
+                sub.fmt_operand(f, 3)?;
+                write!(f, " lazy")
+            }
+            Chan(ref sub) => {
+                sub.fmt_operand(f, 3)?;
+                write!(f, " chan")
+            }
+            Array(ref sub) => {
+                sub.fmt_operand(f, 3)?;
+                write!(f, " array")
+            }
+            List(ref sub) => {
+                sub.fmt_operand(f, 3)?;
+                write!(f, " list")
+            }
+            Arrow(ref left, ref right) => {
+                left.fmt_operand(f, 1)?;
+                write!(f, " -> {}", right)
+            }
+            // a product inside a product is always in parentheses, as 'T1 *
+            // T2 * T3' is a tuple
+            Product(ref left, ref right) => {
+                left.fmt_operand(f, 3)?;
+                write!(f, " * ")?;
+                right.fmt_operand(f, 3)
+            }
+            Tuple(ref types) => {
+                for (i, type_expr) in types.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " * ")?;
+                    }
+                    type_expr.fmt_operand(f, 3)?;
+                }
+                Ok(())
+            }
+            Union(ref left, ref right) => {
+                left.fmt_operand(f, 1)?;
+                write!(f, " + ")?;
+                right.fmt_operand(f, 2)
+            }
+            Record(ref fields) => {
+                write!(f, "{{")?;
+                for (i, (x, type_expr)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", x, type_expr)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn find(env: &[(Var, TypeExpr)], v: &Var) -> Option<TypeExpr> {
+    for (env_v, type_expr) in env.iter().rev() {
+        if env_v.eq(v) {
+            return Some(type_expr.clone());
+        }
+    }
+    None
+}
+
+// the types whose values an operator can be given, which the type variables of
+// a polymorphic value that uses it can only stand for
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Class {
+    // what '=' can compare
+    Eq,
+    // what '<' can compare
+    Ord,
+}
+
+impl Class {
+    fn op(self) -> BinOp {
+        match self {
+            Class::Eq => BinOp::Eq,
+            Class::Ord => BinOp::Lt,
+        }
+    }
+
+    // why a type isn't in this class, given the part of it that keeps it out
+    // (and the polymorphic value whose use put it in, if it wasn't an operator)
+    fn unsatisfied(self, type_expr: &TypeExpr, outside: &TypeExpr, via: Option<&Var>) -> String {
+        let message = match self {
+            Class::Eq => {
+                let found = if *outside == *type_expr {
+                    format!("found functions of type '{}'", type_expr)
+                } else {
+                    format!(
+                        "found '{}', which contains functions of type '{}'",
+                        type_expr, outside
+                    )
+                };
+                format!(
+                    "'=' cannot compare functions, {} (two functions are only equal if they agree on every argument, which can't be checked; use '==' to test whether both sides are the same closure)",
+                    found
+                )
+            }
+            // (every type 'outside' lets into the class, so this has to be kept
+            // in step with it)
+            Class::Ord => {
+                let found = if *outside == *type_expr {
+                    format!("found '{}'", type_expr)
+                } else {
+                    format!("found '{}', which contains '{}'", type_expr, outside)
+                };
+                format!(
+                    "'<' can only compare ints, floats, bools, strings, '()', and pairs, tuples, records, lists and injections of them, {}",
+                    found
+                )
+            }
+        };
+        match via {
+            Some(v) => format!(
+                "'{}' can't be used here, as it compares values with '{}' and {}",
+                v,
+                self.op(),
+                message
+            ),
+            None => message,
+        }
+    }
+}
+
+// a type that has to be in a class, which couldn't be checked where it was
+// made as the type wasn't known yet
+struct Constraint {
+    class: Class,
+    type_expr: TypeExpr,
+    location: Location,
+    expr: Expr,
+    via: Option<Var>,
+}
+
+// where a polymorphic value is used, and what each of its type variables
+// stands for there
+struct Use {
+    v: Var,
+    location: Location,
+    expr: Expr,
+    instance: Vec<(usize, TypeExpr)>,
+}
+
+// what the type checker has worked out so far about the types it has to work
+// out for itself
+struct Unknowns {
+    solved: HashMap<usize, TypeExpr>,
+    // the next unknown that isn't used in the program
+    next: usize,
+    // the unknowns that have been generalised, as the type variables of the
+    // polymorphic values they're in the types of, which each use of the value
+    // gets fresh unknowns in place of
+    generic: HashSet<usize>,
+    // how many 'let's in each unknown was made (or first written), which is
+    // as far out as it's been unified with anything, and how many 'let's in
+    // the checker is. What a 'let' defines can only be polymorphic in the
+    // unknowns made further in than the 'let' itself, as nothing in scope
+    // there can have them in its type
+    levels: Vec<usize>,
+    level: usize,
+    instances: Instances,
+    // the constraints that are waiting for the types they're on to be known
+    constraints: Vec<Constraint>,
+    uses: Vec<Use>,
+    // the data types the program declares, by name (no two have the same one)
+    datatypes: HashMap<Var, Datatype>,
+}
+
+impl Unknowns {
+    fn new(expr: &Locatable<Expr>) -> Unknowns {
+        let mut levels = vec![];
+        let mut work = vec![(expr, 0)];
+        while let Some((expr, level)) = work.pop() {
+            let mut children = expr.borrow_raw().children();
+            // what a 'let' defines (with its annotations) is a level further in
+            // than the 'let', and what's in its scope isn't (see 'enter')
+            let inner = match *expr.borrow_raw() {
+                Expr::Let(..) | Expr::LetFun(..) | Expr::LetRec(..) | Expr::Module(..) => {
+                    work.push((children.pop().unwrap(), level));
+                    level + 1
+                }
+                _ => level,
+            };
+            for type_expr in expr.borrow_raw().annotations() {
+                for n in type_expr.unknowns() {
+                    if n >= levels.len() {
+                        levels.resize(n + 1, usize::MAX);
+                    }
+                    levels[n] = levels[n].min(inner);
+                }
+            }
+            work.extend(children.into_iter().map(|sub| (sub, inner)));
+        }
+        Unknowns {
+            solved: HashMap::new(),
+            next: levels.len(),
+            generic: HashSet::new(),
+            levels,
+            level: 0,
+            instances: Instances {
+                uses: HashMap::new(),
+                exports: HashMap::new(),
             },
-            Product(ref left, ref right) => write!(f, "{} * {}", left, right),
-            Union(ref left, ref right) => write!(f, "{} + {}", left, right),
+            constraints: vec![],
+            uses: vec![],
+            datatypes: HashMap::new(),
+        }
+    }
+
+    // the type a constructor of a data type is given, if it's given anything
+    fn payload(&self, t: &str, c: &str) -> Option<TypeExpr> {
+        self.datatypes
+            .get(t)
+            .and_then(|datatype| datatype.constructors.iter().find(|(d, _)| d == c))
+            .and_then(|(_, type_expr)| type_expr.clone())
+    }
+
+    fn fresh(&mut self) -> TypeExpr {
+        self.next += 1;
+        self.levels.resize(self.next, usize::MAX);
+        self.levels[self.next - 1] = self.level;
+        TypeExpr::Unknown(self.next - 1)
+    }
+
+    // the unknowns in the types of what's in scope before the program can't be
+    // generalised within it
+    fn scope(&mut self, env: &[(Var, TypeExpr)]) {
+        for (_, type_expr) in env.iter() {
+            for n in type_expr.unknowns() {
+                self.lower(n, 0, &mut vec![]);
+            }
+        }
+    }
+
+    // checks what a 'let' defines one level further in than the 'let', so that
+    // the unknowns made while checking it can be told apart from those already
+    // in scope
+    fn enter(&mut self) {
+        self.level += 1;
+    }
+
+    fn leave(&mut self) {
+        self.level -= 1;
+    }
+
+    fn level(&self, n: usize) -> usize {
+        self.levels.get(n).copied().unwrap_or(usize::MAX)
+    }
+
+    // makes an unknown at most 'level' levels in, remembering what it was
+    // before (in case a unification that does this is undone)
+    fn lower(&mut self, n: usize, level: usize, lowered: &mut Vec<(usize, usize)>) {
+        if n >= self.levels.len() {
+            self.levels.resize(n + 1, usize::MAX);
+        }
+        if self.levels[n] > level {
+            lowered.push((n, self.levels[n]));
+            self.levels[n] = level;
+        }
+    }
+
+    // a type with whatever's known about it on the outside filled in
+    fn resolve(&self, type_expr: &TypeExpr) -> TypeExpr {
+        self.resolved(type_expr).clone()
+    }
+
+    // as 'resolve', but without copying it (as copying it at every level of a
+    // type that's walked down makes walking it quadratic in its depth)
+    fn resolved<'b>(&'b self, type_expr: &'b TypeExpr) -> &'b TypeExpr {
+        let mut type_expr = type_expr;
+        while let TypeExpr::Unknown(n) = *type_expr {
+            match self.solved.get(&n) {
+                Some(solved) => type_expr = solved,
+                None => break,
+            }
+        }
+        type_expr
+    }
+
+    // a type with everything that's known about it filled in
+    fn apply(&self, type_expr: &TypeExpr) -> TypeExpr {
+        use self::TypeExpr::*;
+        match *self.resolved(type_expr) {
+            Ref(ref sub) => Ref(Box::new(self.apply(sub))),
+            Lazy(ref sub) => Lazy(Box::new(self.apply(sub))),
+            Chan(ref sub) => Chan(Box::new(self.apply(sub))),
+            Array(ref sub) => Array(Box::new(self.apply(sub))),
+            List(ref sub) => List(Box::new(self.apply(sub))),
+            Arrow(ref left, ref right) => {
+                Arrow(Box::new(self.apply(left)), Box::new(self.apply(right)))
+            }
+            Product(ref left, ref right) => {
+                Product(Box::new(self.apply(left)), Box::new(self.apply(right)))
+            }
+            Union(ref left, ref right) => {
+                Union(Box::new(self.apply(left)), Box::new(self.apply(right)))
+            }
+            Tuple(ref types) => Tuple(
+                types
+                    .iter()
+                    .map(|type_expr| self.apply(type_expr))
+                    .collect(),
+            ),
+            Record(ref fields) => Record(
+                fields
+                    .iter()
+                    .map(|(x, type_expr)| (x.clone(), self.apply(type_expr)))
+                    .collect(),
+            ),
+            ref type_expr => type_expr.clone(),
+        }
+    }
+
+    // makes two types the same if they can be, by working out what the unknowns
+    // in them have to be (and works out nothing if they can't). Like 'agrees',
+    // this treats 'Error' as matching anything
+    fn unify(&mut self, left: &TypeExpr, right: &TypeExpr) -> bool {
+        let mut solved = vec![];
+        let mut lowered = vec![];
+        if self.unify_into(left, right, &mut solved, &mut lowered) {
+            true
+        } else {
+            for n in solved {
+                self.solved.remove(&n);
+            }
+            for (n, level) in lowered.into_iter().rev() {
+                self.levels[n] = level;
+            }
+            false
+        }
+    }
+
+    fn unify_into(
+        &mut self,
+        left: &TypeExpr,
+        right: &TypeExpr,
+        solved: &mut Vec<usize>,
+        lowered: &mut Vec<(usize, usize)>,
+    ) -> bool {
+        use self::TypeExpr::*;
+        // only what's filled in for an unknown is copied, as the rest of each
+        // type is only looked at
+        if let Unknown(n) = *left {
+            if let Some(known) = self.solved.get(&n) {
+                let known = known.clone();
+                return self.unify_into(&known, right, solved, lowered);
+            }
+        }
+        if let Unknown(n) = *right {
+            if let Some(known) = self.solved.get(&n) {
+                let known = known.clone();
+                return self.unify_into(left, &known, solved, lowered);
+            }
+        }
+        match (left, right) {
+            (Error, _) | (_, Error) | (Hole, _) | (_, Hole) => true,
+            (Unknown(left), Unknown(right)) if left == right => true,
+            // an unknown can't be something with itself inside it (as 'a' and
+            // 'a ref' can't be the same type)
+            (&Unknown(n), type_expr) | (type_expr, &Unknown(n)) => {
+                let unknowns = self.apply(type_expr).unknowns();
+                if unknowns.contains(&n) {
+                    return false;
+                }
+                // whatever's in scope wherever the unknown was is now in scope
+                // wherever what it stands for is
+                let level = self.level(n);
+                for m in unknowns {
+                    self.lower(m, level, lowered);
+                }
+                self.solved.insert(n, type_expr.clone());
+                solved.push(n);
+                true
+            }
+            (Ref(left), Ref(right))
+            | (Lazy(left), Lazy(right))
+            | (Chan(left), Chan(right))
+            | (Array(left), Array(right))
+            | (List(left), List(right)) => self.unify_into(left, right, solved, lowered),
+            (Arrow(left1, right1), Arrow(left2, right2))
+            | (Product(left1, right1), Product(left2, right2))
+            | (Union(left1, right1), Union(left2, right2)) => {
+                self.unify_into(left1, left2, solved, lowered)
+                    && self.unify_into(right1, right2, solved, lowered)
+            }
+            (Tuple(types1), Tuple(types2)) if types1.len() == types2.len() => types1
+                .iter()
+                .zip(types2.iter())
+                .all(|(t1, t2)| self.unify_into(t1, t2, solved, lowered)),
+            (Record(fields1), Record(fields2)) if fields1.len() == fields2.len() => fields1
+                .iter()
+                .zip(fields2.iter())
+                .all(|((x1, t1), (x2, t2))| x1 == x2 && self.unify_into(t1, t2, solved, lowered)),
+            (left, right) => left == right,
+        }
+    }
+
+    // makes the unknowns in the type of something a 'let' defines its type
+    // variables, unless they're also in the type of something in scope (which
+    // could still be worked out to be a particular type). Those are the ones
+    // that aren't further in than the 'let', so this is called once what it
+    // defines has been checked and the checker has left it again
+    fn generalise(&mut self, type_expr: &TypeExpr) {
+        for n in self.apply(type_expr).unknowns() {
+            if self.level(n) > self.level {
+                self.generic.insert(n);
+            }
+        }
+    }
+
+    // as 'generalise', but for the type of a recursive function in its own
+    // body (which is checked a level further in), where what it gives back can
+    // be at other types but its parameter can't
+    fn generalise_within(&mut self, type_expr: &TypeExpr, param: &TypeExpr) {
+        let param = self.apply(param).unknowns();
+        for n in self.apply(type_expr).unknowns() {
+            if self.level(n) >= self.level && !param.contains(&n) {
+                self.generic.insert(n);
+            }
+        }
+    }
+
+    // a type with fresh unknowns in place of its type variables, along with
+    // what each type variable was replaced by
+    fn instantiate(&mut self, type_expr: &TypeExpr) -> (TypeExpr, Vec<(usize, TypeExpr)>) {
+        let type_expr = self.apply(type_expr);
+        let mut fresh = vec![];
+        for n in type_expr.unknowns() {
+            if self.generic.contains(&n) && fresh.iter().all(|(m, _)| *m != n) {
+                let unknown = self.fresh();
+                fresh.push((n, unknown));
+            }
+        }
+        let type_expr = type_expr.replace(&mut |type_expr| match *type_expr {
+            TypeExpr::Unknown(n) => fresh
+                .iter()
+                .find(|(m, _)| *m == n)
+                .map(|(_, unknown)| unknown.clone()),
+            _ => None,
+        });
+        (type_expr, fresh)
+    }
+
+    // puts a type in a class, reporting why it can't be in it if it can't,
+    // which waits until the end if it depends on what isn't known yet
+    fn constrain(
+        &mut self,
+        errors: &mut Vec<String>,
+        class: Class,
+        type_expr: &TypeExpr,
+        loc: &Location,
+        expr: &Expr,
+    ) {
+        let type_expr = self.apply(type_expr);
+        let mut unknowns = vec![];
+        match type_expr.outside(class, &mut unknowns) {
+            Some(outside) => errors.push(log::type_error(
+                loc,
+                class.unsatisfied(&type_expr, outside, None),
+                expr,
+            )),
+            None if !unknowns.is_empty() => self.constraints.push(Constraint {
+                class,
+                type_expr: type_expr.clone(),
+                location: loc.clone(),
+                expr: expr.clone(),
+                via: None,
+            }),
+            None => (),
+        }
+    }
+
+    // checks the constraints that had to wait, once everything that can be
+    // worked out about their types has been. A type variable that has to be in
+    // a class puts whatever it stands for wherever its value is used in the
+    // class too (which is reported there, rather than inside the value)
+    fn check_constraints(&mut self, errors: &mut Vec<String>) {
+        let mut constrained = HashSet::new();
+        let mut work = std::mem::take(&mut self.constraints);
+        while let Some(constraint) = work.pop() {
+            let type_expr = self.apply(&constraint.type_expr);
+            let mut unknowns = vec![];
+            if let Some(outside) = type_expr.outside(constraint.class, &mut unknowns) {
+                let error = log::type_error(
+                    &constraint.location,
+                    constraint
+                        .class
+                        .unsatisfied(&type_expr, outside, constraint.via.as_ref()),
+                    &constraint.expr,
+                );
+                if !errors.contains(&error) {
+                    errors.push(error);
+                }
+                continue;
+            }
+            // anything else that isn't known is never used at a particular
+            // type, so can be any type in the class
+            for n in unknowns {
+                if !self.generic.contains(&n) || !constrained.insert((n, constraint.class)) {
+                    continue;
+                }
+                for u in self.uses.iter() {
+                    for (m, type_expr) in u.instance.iter() {
+                        if *m == n {
+                            work.push(Constraint {
+                                class: constraint.class,
+                                type_expr: type_expr.clone(),
+                                location: u.location.clone(),
+                                expr: u.expr.clone(),
+                                via: Some(u.v.clone()),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // checks that each type variable a 'let' or a 'val' binds can be any type,
+    // which it can only be if nothing in what's defined says what it is and
+    // it's a type variable of what's defined (rather than, say, an unknown in
+    // the type of something else in scope)
+    fn check_quantified(&self, quantified: &[Quantified], errors: &mut Vec<String>) {
+        for q in quantified.iter() {
+            let mut seen: Vec<(usize, &Var)> = vec![];
+            for (a, n) in q.variables.iter() {
+                let message = match self.resolve(&TypeExpr::Unknown(*n)) {
+                    TypeExpr::Error => continue,
+                    TypeExpr::Unknown(m) if !self.generic.contains(&m) => format!(
+                        "'{}' has to stand for any type in the type of '{}', but '{}' can't be polymorphic (only a value can be, and only in types that nothing else in scope has)",
+                        TypeExpr::Variable(a.clone()),
+                        q.v,
+                        q.v
+                    ),
+                    TypeExpr::Unknown(m) => match seen.iter().find(|(k, _)| *k == m) {
+                        Some((_, b)) => format!(
+                            "'{}' and '{}' have to stand for any types in the type of '{}', but its definition needs them to be the same type",
+                            TypeExpr::Variable((*b).clone()),
+                            TypeExpr::Variable(a.clone()),
+                            q.v
+                        ),
+                        None => {
+                            seen.push((m, a));
+                            continue;
+                        }
+                    },
+                    type_expr => format!(
+                        "'{}' has to stand for any type in the type of '{}', but its definition needs it to be '{}'",
+                        TypeExpr::Variable(a.clone()),
+                        q.v,
+                        self.apply(&type_expr)
+                    ),
+                };
+                errors.push(log::type_error(&q.location, message, &q.expr));
+            }
+        }
+    }
+
+    // everything that's been worked out about the types polymorphic values
+    // are used at, in terms of what represents any abstract type or data type
+    // they're used at (as that's all that's left once those are erased)
+    fn instances(&self, representations: &HashMap<Var, TypeExpr>) -> Instances {
+        let apply = |instance: &Vec<(usize, TypeExpr)>| {
+            instance
+                .iter()
+                .map(|(n, type_expr)| (*n, self.apply(type_expr).expand(representations)))
+                .collect::<Vec<_>>()
+        };
+        Instances {
+            uses: self
+                .instances
+                .uses
+                .iter()
+                .map(|(at, instance)| (*at, apply(instance)))
+                .collect(),
+            exports: self
+                .instances
+                .exports
+                .iter()
+                .map(|(v, instance)| (v.clone(), apply(instance)))
+                .collect(),
+        }
+    }
+
+    // the type of something that has to be made by a particular type
+    // constructor to be used where it is (a reference, say), which is worked
+    // out to be one if it isn't known yet
+    fn shaped(&mut self, type_expr: &TypeExpr, make: fn(Box<TypeExpr>) -> TypeExpr) -> TypeExpr {
+        if let TypeExpr::Unknown(_) = self.resolve(type_expr) {
+            let shaped = make(Box::new(self.fresh()));
+            self.unify(type_expr, &shaped);
+        }
+        self.resolve(type_expr)
+    }
+
+    // as 'shaped', for a type constructor with two types inside it (like a
+    // function)
+    fn shaped_pair(
+        &mut self,
+        type_expr: &TypeExpr,
+        make: fn(Box<TypeExpr>, Box<TypeExpr>) -> TypeExpr,
+    ) -> TypeExpr {
+        if let TypeExpr::Unknown(_) = self.resolve(type_expr) {
+            let shaped = make(Box::new(self.fresh()), Box::new(self.fresh()));
+            self.unify(type_expr, &shaped);
+        }
+        self.resolve(type_expr)
+    }
+}
+
+// infers the type of a program, reporting every type error in it rather than
+// just the first
+pub fn infer(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    expr: &Locatable<Expr>,
+) -> Result<TypeExpr, String> {
+    let mut errors = vec![];
+    let mut unknowns = Unknowns::new(expr);
+    unknowns.scope(env);
+    let type_expr = infer_expr(env, warnings, &mut errors, &mut unknowns, expr);
+    unknowns.check_constraints(&mut errors);
+    if errors.is_empty() {
+        Ok(unknowns.apply(&type_expr))
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+// infers the type of a program with some of its annotations left out, which are
+// worked out from how what they annotate is used and filled in, so that the
+// rest of the compiler only ever sees programs with every annotation in place
+// (and without any modules, data types or records, which are erased once
+// they've been checked, or polymorphic values, which are copied for each type
+// they're used at). The
+// program is checked again once they have been, as some mistakes (like
+// comparing functions with '=') only show up once every type is known. What
+// modules define but the program never uses is reported, and left out if
+// 'strip_unused' is set (see 'modules::erase'), and the decision tree each
+// 'match' is compiled into is written into 'trees', if it's given. What's
+// defined before the program (at the REPL) can be polymorphic, in which case
+// the program refers to a copy of it at each type it uses it at, which are
+// given back along with its type
+pub fn elaborate(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    expr: &mut Locatable<Expr>,
+    strip_unused: bool,
+    trees: Option<&mut Vec<String>>,
+) -> Result<(TypeExpr, Vec<Copied>), String> {
+    let resolved = modules::resolve(expr)?;
+    let mut errors = vec![];
+    let mut unknowns = Unknowns::new(expr);
+    unknowns.scope(env);
+    unknowns.datatypes = resolved.datatypes;
+    let (mut defined, type_variables) = defined(&mut unknowns, env);
+    let type_expr = infer_expr(&mut defined, &mut vec![], &mut errors, &mut unknowns, expr);
+    unknowns.check_constraints(&mut errors);
+    unknowns.check_quantified(&resolved.quantified, &mut errors);
+    let type_expr = unknowns.apply(&type_expr);
+    // a polymorphic value is only ever copied at the types it's used at, so
+    // the program itself has to have a single type
+    if errors.is_empty() && !type_expr.unknowns().is_empty() {
+        errors.push(log::type_error(
+            expr.location(),
+            format!(
+                "can't work out the type '{}' here, as nothing it's used for says what it is (it needs an annotation)",
+                type_expr
+            ),
+            expr.borrow_raw(),
+        ));
+    }
+    if errors.is_empty() {
+        // each unknown is only reported where it's first left out, as it's
+        // usually the same mistake everywhere else it turns up
+        let mut reported = HashSet::new();
+        let mut work = vec![&mut *expr];
+        while let Some(expr) = work.pop() {
+            let mut unknown = None;
+            for type_expr in expr.t.annotations_mut() {
+                *type_expr = unknowns.apply(type_expr);
+                // the type variables of polymorphic values can be any type
+                let mut fresh = false;
+                for n in type_expr.unknowns() {
+                    fresh |= !unknowns.generic.contains(&n) && reported.insert(n);
+                }
+                if unknown.is_none() && fresh {
+                    unknown = Some(type_expr.clone());
+                }
+            }
+            if let Some(unknown) = unknown {
+                errors.push(log::type_error(
+                    expr.location(),
+                    format!(
+                        "can't work out the type '{}' here, as nothing it's used for says what it is (it needs an annotation)",
+                        unknown
+                    ),
+                    expr.borrow_raw(),
+                ));
+            }
+            work.extend(expr.t.children_mut());
+        }
+    }
+    if errors.is_empty() {
+        datatypes::erase(expr, &unknowns.datatypes, &resolved.representations);
+        patterns::erase(expr, trees);
+        modules::erase(expr, &resolved.representations, strip_unused, warnings);
+        let names = env
+            .iter()
+            .zip(type_variables.iter())
+            .map(|((v, _), type_variables)| (v.clone(), type_variables.clone()))
+            .collect::<Vec<_>>();
+        let copied = monomorphise::monomorphise(
+            expr,
+            &unknowns.instances(&resolved.representations),
+            &names,
+        );
+        records::erase(expr);
+        // what's left refers to the copies instead, at the types they're made
+        // at (with the records and tuples in those erased as well)
+        let depth = env.len();
+        for copy in copied.iter() {
+            let i = env.iter().rposition(|(v, _)| *v == copy.v).unwrap();
+            let type_variables = type_variables[i].as_ref().unwrap();
+            let type_expr = defined[i].1.replace(&mut |type_expr| match *type_expr {
+                TypeExpr::Unknown(n) => type_variables
+                    .iter()
+                    .position(|m| *m == n)
+                    .map(|k| copy.types[k].clone()),
+                _ => None,
+            });
+            env.push((copy.name.clone(), records::represent(&type_expr)));
+        }
+        let type_expr = infer(env, warnings, expr);
+        env.truncate(depth);
+        type_expr.map(|type_expr| (type_expr, copied))
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+// the type variables of each value defined before a program (if it's
+// polymorphic)
+type Polymorphic = Vec<Option<Vec<usize>>>;
+
+// the values defined before a program (at the REPL), with the type variables
+// of each that's polymorphic replaced by unknowns that can be any type (as if
+// it had been defined in the program), along with those unknowns (in the order
+// its 'forall' gives them) for each that's polymorphic
+fn defined(
+    unknowns: &mut Unknowns,
+    env: &[(Var, TypeExpr)],
+) -> (Vec<(Var, TypeExpr)>, Polymorphic) {
+    let mut defined = vec![];
+    let mut type_variables = vec![];
+    for (v, type_expr) in env.iter() {
+        let (names, type_expr) = match *type_expr {
+            TypeExpr::Forall(ref names, ref type_expr) => (names, type_expr),
+            _ => {
+                defined.push((v.clone(), type_expr.clone()));
+                type_variables.push(None);
+                continue;
+            }
+        };
+        let mut fresh = vec![];
+        for _ in names.iter() {
+            let n = match unknowns.fresh() {
+                TypeExpr::Unknown(n) => n,
+                _ => unreachable!(),
+            };
+            unknowns.generic.insert(n);
+            fresh.push(n);
+        }
+        let type_expr = type_expr.replace(&mut |type_expr| match *type_expr {
+            TypeExpr::Variable(ref name) => names
+                .iter()
+                .position(|other| other == name)
+                .map(|k| TypeExpr::Unknown(fresh[k])),
+            _ => None,
+        });
+        defined.push((v.clone(), type_expr));
+        type_variables.push(Some(fresh));
+    }
+    (defined, type_variables)
+}
+
+// the type of a definition entered at the REPL ('let f = ... in f') if what it
+// defines is polymorphic, with its type variables named in the order they
+// turn up in it. It isn't run then, but copied at the types it's used at
+// later on, as it would be if it were defined in the program that uses it. If
+// what it defines has a single type (or it doesn't type check, which checking
+// it as a program reports) there's no such type
+pub fn scheme(
+    env: &[(Var, TypeExpr)],
+    warnings: &mut Vec<String>,
+    expr: &Locatable<Expr>,
+) -> Option<TypeExpr> {
+    let mut expr = expr.clone();
+    let resolved = modules::resolve(&mut expr).ok()?;
+    let mut errors = vec![];
+    let mut unknowns = Unknowns::new(&expr);
+    unknowns.scope(env);
+    unknowns.datatypes = resolved.datatypes;
+    let (mut defined, _) = defined(&mut unknowns, env);
+    definitions(&mut defined, warnings, &mut errors, &mut unknowns, &expr);
+    unknowns.check_constraints(&mut errors);
+    unknowns.check_quantified(&resolved.quantified, &mut errors);
+    let type_expr = unknowns.apply(&defined.last()?.1);
+    let mut variables = vec![];
+    for n in type_expr.unknowns() {
+        if !unknowns.generic.contains(&n) {
+            return None;
+        }
+        if !variables.contains(&n) {
+            variables.push(n);
+        }
+    }
+    if !errors.is_empty() || variables.is_empty() {
+        return None;
+    }
+    let names = (0..variables.len())
+        .map(|k| {
+            let letter = (b'a' + (k % 26) as u8) as char;
+            match k / 26 {
+                0 => letter.to_string(),
+                i => format!("{}{}", letter, i),
+            }
+        })
+        .collect::<Vec<_>>();
+    let type_expr = type_expr.replace(&mut |type_expr| match *type_expr {
+        TypeExpr::Unknown(n) => variables
+            .iter()
+            .position(|m| *m == n)
+            .map(|k| TypeExpr::Variable(names[k].clone())),
+        _ => None,
+    });
+    Some(TypeExpr::Forall(
+        names,
+        Box::new(type_expr.expand(&resolved.representations)),
+    ))
+}
+
+// records a type error, giving the type of whatever caused it
+fn error(errors: &mut Vec<String>, error: String) -> TypeExpr {
+    errors.push(error);
+    TypeExpr::Error
+}
+
+fn mismatch(
+    errors: &mut Vec<String>,
+    unknowns: &Unknowns,
+    expr: &Locatable<Expr>,
+    expected: &TypeExpr,
+    found: &TypeExpr,
+) {
+    let (expected, found) = (unknowns.apply(expected), unknowns.apply(found));
+    // the types only fail to match because one would have to be inside itself
+    let circular = match (&expected, &found) {
+        (&TypeExpr::Unknown(n), other) | (other, &TypeExpr::Unknown(n)) => {
+            other.unknowns().contains(&n)
+        }
+        _ => false,
+    };
+    errors.push(log::type_error(
+        expr.location(),
+        format!(
+            "expected expression of type '{}', found '{}'{}",
+            expected,
+            found,
+            if circular {
+                " (a type can't have itself inside it)"
+            } else {
+                ""
+            }
+        ),
+        expr.borrow_raw(),
+    ));
+}
+
+// what's in scope at a hole, leaving out anything that's been shadowed
+fn scope(env: &[(Var, TypeExpr)], unknowns: &Unknowns) -> String {
+    let mut lines = String::new();
+    for (i, (v, type_expr)) in env.iter().enumerate() {
+        if env[i + 1..].iter().all(|(other, _)| other != v) {
+            lines.push_str(&format!("\n       {} : {}", v, unknowns.apply(type_expr)));
+        }
+    }
+    if lines.is_empty() {
+        "\n     nothing is in scope".to_string()
+    } else {
+        format!("\n     in scope:{}", lines)
+    }
+}
+
+// the type an annotation stands for, where any holes in it are filled in from
+// the type that was found in its place (and reported, as a program with holes
+// in it never type checks)
+fn annotation(
+    errors: &mut Vec<String>,
+    unknowns: &Unknowns,
+    loc: &Location,
+    expr: &Expr,
+    annotation: &TypeExpr,
+    found: &TypeExpr,
+) -> TypeExpr {
+    if !annotation.has_holes() {
+        return annotation.clone();
+    }
+    let mut holes = vec![];
+    let filled = annotation.fill(&unknowns.apply(found), &mut holes);
+    for hole in holes.iter() {
+        let message = if hole.known() {
+            format!("type hole '{}' stands for '{}'", TypeExpr::Hole, hole)
+        } else {
+            format!(
+                "can't work out what type hole '{}' stands for here",
+                TypeExpr::Hole
+            )
+        };
+        errors.push(log::type_error(loc, message, expr));
+    }
+    filled
+}
+
+// checks the annotation on what a 'try' binds an exception to, which is always
+// an int
+fn handles(
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    loc: &Location,
+    expr: &Expr,
+    type_expr: &TypeExpr,
+) {
+    let type_expr = annotation(errors, unknowns, loc, expr, type_expr, &TypeExpr::Int);
+    if !unknowns.unify(&type_expr, &TypeExpr::Int) {
+        errors.push(log::type_error(
+            loc,
+            format!(
+                "an exception is an 'int', so what handles it can't take a '{}'",
+                unknowns.apply(&type_expr)
+            ),
+            expr,
+        ));
+    }
+}
+
+// whether what a 'let' defines can be polymorphic, which it can only be if it's
+// a value (as otherwise, say, a reference it makes could be given something of
+// one type and read back at another)
+fn generalisable(expr: &Expr) -> bool {
+    use self::Expr::*;
+    match *expr {
+        Unit | Var(_) | Int(_) | Bool(_) | Lambda(_) => true,
+        Pair(ref left, ref right) => {
+            generalisable(left.borrow_raw()) && generalisable(right.borrow_raw())
+        }
+        Inl(ref sub, _) | Inr(ref sub, _) | Construct(_, _, Some(ref sub)) => {
+            generalisable(sub.borrow_raw())
+        }
+        Construct(_, _, None) | Nil => true,
+        Cons(ref left, ref right) => {
+            generalisable(left.borrow_raw()) && generalisable(right.borrow_raw())
+        }
+        Tuple(ref subs) => subs.iter().all(|sub| generalisable(sub.borrow_raw())),
+        Record(ref fields, _) => fields
+            .iter()
+            .all(|(_, sub)| generalisable(sub.borrow_raw())),
+        _ => false,
+    }
+}
+
+// checks what a 'let' defines against its annotation, giving the type the name
+// is bound to
+fn define(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    sub: &Locatable<Expr>,
+    type_expr: &TypeExpr,
+) -> TypeExpr {
+    if type_expr.has_holes() {
+        let t = infer_expr(env, warnings, errors, unknowns, sub);
+        if !unknowns.unify(&t, type_expr) {
+            mismatch(errors, unknowns, sub, type_expr, &t);
+        }
+        annotation(
+            errors,
+            unknowns,
+            sub.location(),
+            sub.borrow_raw(),
+            type_expr,
+            &t,
+        )
+    } else {
+        check_expr(env, warnings, errors, unknowns, sub, type_expr);
+        type_expr.clone()
+    }
+}
+
+// the field of a record most like one it doesn't have, with all of its fields
+fn fields_like(x: &str, fields: &[(Var, TypeExpr)]) -> String {
+    let names = fields.iter().map(|(y, _)| y.as_str()).collect::<Vec<_>>();
+    match log::closest(x, names.iter().copied()) {
+        _ if names.is_empty() => String::new(),
+        Some(y) => format!(" (did you mean '{}'? it has {})", y, log::listed(&names)),
+        None => format!(" (it has {})", log::listed(&names)),
+    }
+}
+
+// the type of something with two branches, which must agree
+fn branches(
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    loc: &Location,
+    expr: &Expr,
+    left: TypeExpr,
+    right: TypeExpr,
+) -> TypeExpr {
+    if !unknowns.unify(&left, &right) {
+        error(
+            errors,
+            log::type_error(
+                loc,
+                format!(
+                    "branches must have the same type, found '{}' and '{}'",
+                    unknowns.apply(&left),
+                    unknowns.apply(&right)
+                ),
+                expr,
+            ),
+        )
+    } else if left == TypeExpr::Error {
+        right
+    } else {
+        left
+    }
+}
+
+// checks what a 'case' takes apart, which has to be a union of the types its
+// branches were given (the branches are checked with those types either way),
+// giving the types of the branches
+#[allow(clippy::too_many_arguments)]
+fn scrutinise(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    loc: &Location,
+    expr: &Expr,
+    sub: &Locatable<Expr>,
+    type_expr_left: &TypeExpr,
+    type_expr_right: &TypeExpr,
+) -> (TypeExpr, TypeExpr) {
+    let t = infer_expr(env, warnings, errors, unknowns, sub);
+    let t = unknowns.shaped_pair(&t, TypeExpr::Union);
+    let expected = TypeExpr::Union(
+        Box::new(type_expr_left.clone()),
+        Box::new(type_expr_right.clone()),
+    );
+    match t {
+        TypeExpr::Union(_, _) | TypeExpr::Error if unknowns.unify(&t, &expected) => (),
+        TypeExpr::Union(_, _) => errors.push(format!(
+            "expected union of type '{}', found '{}'",
+            unknowns.apply(&t),
+            unknowns.apply(&expected)
+        )),
+        ref t => errors.push(log::type_error(
+            loc,
+            format!("case expected a union type, found '{}'", unknowns.apply(t)),
+            sub.borrow_raw(),
+        )),
+    }
+    let (left, right) = match t {
+        TypeExpr::Union(left, right) => (*left, *right),
+        _ => (TypeExpr::Error, TypeExpr::Error),
+    };
+    (
+        annotation(errors, unknowns, loc, expr, type_expr_left, &left),
+        annotation(errors, unknowns, loc, expr, type_expr_right, &right),
+    )
+}
+
+// checks a pattern against the type of what it's matched against, collecting
+// the variables it binds along with their types
+fn bind(
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    loc: &Location,
+    expr: &Expr,
+    pattern: &Pattern,
+    type_expr: &TypeExpr,
+    bound: &mut Vec<(Var, TypeExpr)>,
+) {
+    let literal = match *pattern {
+        Pattern::Wildcard => return,
+        Pattern::Var(ref v) => return bound.push((v.clone(), type_expr.clone())),
+        Pattern::Unit => TypeExpr::Unit,
+        Pattern::Int(_) => TypeExpr::Int,
+        Pattern::Bool(_) => TypeExpr::Bool,
+        Pattern::Pair(ref left, ref right) => {
+            let (t1, t2) = match unknowns.shaped_pair(type_expr, TypeExpr::Product) {
+                TypeExpr::Product(t1, t2) => (*t1, *t2),
+                TypeExpr::Error => (TypeExpr::Error, TypeExpr::Error),
+                t => {
+                    errors.push(log::type_error(
+                        loc,
+                        format!(
+                            "pattern '{}' can't match a value of type '{}'",
+                            pattern,
+                            unknowns.apply(&t)
+                        ),
+                        expr,
+                    ));
+                    (TypeExpr::Error, TypeExpr::Error)
+                }
+            };
+            bind(errors, unknowns, loc, expr, left, &t1, bound);
+            bind(errors, unknowns, loc, expr, right, &t2, bound);
+            return;
+        }
+        Pattern::Tuple(ref patterns) => {
+            if let TypeExpr::Unknown(_) = unknowns.resolve(type_expr) {
+                let shaped = TypeExpr::Tuple(patterns.iter().map(|_| unknowns.fresh()).collect());
+                unknowns.unify(type_expr, &shaped);
+            }
+            let types = match unknowns.resolve(type_expr) {
+                TypeExpr::Tuple(types) if types.len() == patterns.len() => types,
+                TypeExpr::Error => vec![TypeExpr::Error; patterns.len()],
+                t => {
+                    errors.push(log::type_error(
+                        loc,
+                        format!(
+                            "pattern '{}' can't match a value of type '{}'",
+                            pattern,
+                            unknowns.apply(&t)
+                        ),
+                        expr,
+                    ));
+                    vec![TypeExpr::Error; patterns.len()]
+                }
+            };
+            for (sub, t) in patterns.iter().zip(types) {
+                bind(errors, unknowns, loc, expr, sub, &t, bound);
+            }
+            return;
+        }
+        Pattern::Inl(ref sub) | Pattern::Inr(ref sub) => {
+            let t = match unknowns.shaped_pair(type_expr, TypeExpr::Union) {
+                TypeExpr::Union(t1, _) if matches!(*pattern, Pattern::Inl(_)) => *t1,
+                TypeExpr::Union(_, t2) => *t2,
+                TypeExpr::Error => TypeExpr::Error,
+                t => {
+                    errors.push(log::type_error(
+                        loc,
+                        format!(
+                            "pattern '{}' can't match a value of type '{}'",
+                            pattern,
+                            unknowns.apply(&t)
+                        ),
+                        expr,
+                    ));
+                    TypeExpr::Error
+                }
+            };
+            return bind(errors, unknowns, loc, expr, sub, &t, bound);
+        }
+        Pattern::Construct(ref t, ref c, ref sub) => {
+            match (sub, unknowns.payload(t, c)) {
+                (Some(sub), Some(payload)) => bind(errors, unknowns, loc, expr, sub, &payload, bound),
+                (None, None) => (),
+                (Some(_), None) => errors.push(log::type_error(
+                    loc,
+                    format!(
+                        "constructor '{}' isn't given anything, so there can't be a pattern after it in '{}'",
+                        c, pattern
+                    ),
+                    expr,
+                )),
+                (None, Some(payload)) => errors.push(log::type_error(
+                    loc,
+                    format!(
+                        "constructor '{}' is given a value of type '{}', so it needs a pattern for it (like '{} _')",
+                        c, payload, c
+                    ),
+                    expr,
+                )),
+            }
+            TypeExpr::Named(t.clone())
+        }
+        Pattern::Nil => TypeExpr::List(Box::new(unknowns.fresh())),
+        Pattern::Cons(ref left, ref right) => {
+            let t = match unknowns.shaped(type_expr, TypeExpr::List) {
+                TypeExpr::List(t) => *t,
+                TypeExpr::Error => TypeExpr::Error,
+                t => {
+                    errors.push(log::type_error(
+                        loc,
+                        format!(
+                            "pattern '{}' can't match a value of type '{}'",
+                            pattern,
+                            unknowns.apply(&t)
+                        ),
+                        expr,
+                    ));
+                    TypeExpr::Error
+                }
+            };
+            bind(errors, unknowns, loc, expr, left, &t, bound);
+            let list = TypeExpr::List(Box::new(t));
+            return bind(errors, unknowns, loc, expr, right, &list, bound);
+        }
+    };
+    if !unknowns.unify(type_expr, &literal) {
+        errors.push(log::type_error(
+            loc,
+            format!(
+                "pattern '{}' can't match a value of type '{}'",
+                pattern,
+                unknowns.apply(type_expr)
+            ),
+            expr,
+        ));
+    }
+}
+
+// checks what a 'match' takes apart against each of its patterns, giving the
+// variables each branch binds. Between them, the patterns have to match every
+// value they could be given
+#[allow(clippy::too_many_arguments)]
+fn scrutinise_match(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    loc: &Location,
+    expr: &Expr,
+    sub: &Locatable<Expr>,
+    type_expr: &TypeExpr,
+    arms: &[(Pattern, SubExpr)],
+) -> Vec<Vec<(Var, TypeExpr)>> {
+    let t = infer_expr(env, warnings, errors, unknowns, sub);
+    if !unknowns.unify(&t, type_expr) {
+        mismatch(errors, unknowns, sub, type_expr, &t);
+    }
+    let failures = errors.len();
+    let mut bindings = vec![];
+    for (pattern, _) in arms.iter() {
+        let mut bound = vec![];
+        bind(errors, unknowns, loc, expr, pattern, type_expr, &mut bound);
+        let vars = pattern.vars();
+        for (i, v) in vars.iter().enumerate() {
+            if vars[..i].contains(v) {
+                errors.push(log::type_error(
+                    loc,
+                    format!("'{}' is bound more than once in pattern '{}'", v, pattern),
+                    expr,
+                ));
+            }
+        }
+        bindings.push(bound);
+    }
+    // what the patterns match is only known once they've all been checked
+    if errors.len() == failures {
+        let t = unknowns.apply(type_expr);
+        let patterns = arms.iter().map(|(pattern, _)| pattern).collect::<Vec<_>>();
+        if let Some(missing) = patterns::missing(&patterns, &t, &unknowns.datatypes) {
+            errors.push(log::type_error(
+                loc,
+                format!(
+                    "this 'match' doesn't match every value of type '{}' (it doesn't match '{}', for one)",
+                    t, missing
+                ),
+                expr,
+            ));
+        }
+    }
+    bindings
+}
+
+// checks the definition of a recursive function against the result type it was
+// given, returning the type of the function
+#[allow(clippy::too_many_arguments)]
+fn check_fun(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    loc: &Location,
+    expr: &Expr,
+    fun: &Var,
+    (v_lambda, type_expr_lambda, sub_lambda): &Lambda,
+    type_expr: &TypeExpr,
+) -> TypeExpr {
+    // nothing says what the parameter should be other than its annotation
+    let type_expr_lambda = annotation(
+        errors,
+        unknowns,
+        loc,
+        expr,
+        type_expr_lambda,
+        &TypeExpr::Error,
+    );
+    let fun_type_expr = TypeExpr::Arrow(
+        Box::new(type_expr_lambda.clone()),
+        Box::new(type_expr.clone()),
+    );
+    env.push((v_lambda.to_string(), type_expr_lambda.clone()));
+    unknowns.generalise_within(&fun_type_expr, &type_expr_lambda);
+    env.push((fun.to_string(), fun_type_expr));
+    let type_expr = check_body(env, warnings, errors, unknowns, sub_lambda, type_expr);
+    env.pop();
+    env.pop();
+    TypeExpr::Arrow(Box::new(type_expr_lambda), Box::new(type_expr))
+}
+
+// checks a function's body against the annotation on what it gives back,
+// giving the type it gives back
+fn check_body(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    sub_lambda: &SubExpr,
+    type_expr: &TypeExpr,
+) -> TypeExpr {
+    if type_expr.has_holes() {
+        let t = infer_expr(env, warnings, errors, unknowns, sub_lambda);
+        if !unknowns.unify(&t, type_expr) {
+            mismatch(errors, unknowns, sub_lambda, type_expr, &t);
+        }
+        annotation(
+            errors,
+            unknowns,
+            sub_lambda.location(),
+            sub_lambda.borrow_raw(),
+            type_expr,
+            &t,
+        )
+    } else {
+        check_expr(env, warnings, errors, unknowns, sub_lambda, type_expr);
+        type_expr.clone()
+    }
+}
+
+// checks functions defined together, each of which is in scope in all of their
+// bodies (at a single type, so they can't use each other at different types),
+// giving their types, which are generalised once they've all been checked
+fn check_funs(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    loc: &Location,
+    expr: &Expr,
+    functions: &[Function],
+) -> Vec<(Var, TypeExpr)> {
+    unknowns.enter();
+    let params = functions
+        .iter()
+        .map(|(_, (_, type_expr_lambda, _), _)| {
+            annotation(
+                errors,
+                unknowns,
+                loc,
+                expr,
+                type_expr_lambda,
+                &TypeExpr::Error,
+            )
+        })
+        .collect::<Vec<_>>();
+    let depth = env.len();
+    for ((fun, _, type_expr), param) in functions.iter().zip(params.iter()) {
+        let fun_type_expr = TypeExpr::Arrow(Box::new(param.clone()), Box::new(type_expr.clone()));
+        env.push((fun.to_string(), fun_type_expr));
+    }
+    let mut types = vec![];
+    for ((fun, (v_lambda, _, sub_lambda), type_expr), param) in functions.iter().zip(params) {
+        env.push((v_lambda.to_string(), param.clone()));
+        let type_expr = check_body(env, warnings, errors, unknowns, sub_lambda, type_expr);
+        env.pop();
+        types.push((
+            fun.to_string(),
+            TypeExpr::Arrow(Box::new(param), Box::new(type_expr)),
+        ));
+    }
+    env.truncate(depth);
+    unknowns.leave();
+    for (_, fun_type_expr) in types.iter() {
+        unknowns.generalise(fun_type_expr);
+    }
+    types
+}
+
+// checks what a module's structure defines (each in scope for everything it
+// defines after it) against its signature, giving what it exports by their
+// names outside it ('M.x'). Inside the module, its abstract types are the same
+// as the types that represent them, so a value the signature gives an abstract
+// type is checked as if it had the representation's type
+fn check_module(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    loc: &Location,
+    expr: &Expr,
+    module: &Module,
+) -> Vec<(Var, TypeExpr)> {
+    let depth = env.len();
+    let mut representations = HashMap::new();
+    for item in module.items.iter() {
+        match *item {
+            Item::Type(ref t, ref type_expr) => {
+                representations.insert(format!("{}.{}", module.name, t), type_expr.clone());
+            }
+            Item::Let(ref v, ref type_expr, ref sub) => {
+                unknowns.enter();
+                let type_expr = define(env, warnings, errors, unknowns, sub, type_expr);
+                unknowns.leave();
+                if generalisable(sub.borrow_raw()) {
+                    unknowns.generalise(&type_expr);
+                }
+                env.push((v.to_string(), type_expr));
+            }
+            Item::LetFun(ref fun, ref lambda, ref type_expr) => {
+                unknowns.enter();
+                let fun_type_expr = check_fun(
+                    env, warnings, errors, unknowns, loc, expr, fun, lambda, type_expr,
+                );
+                unknowns.leave();
+                unknowns.generalise(&fun_type_expr);
+                env.push((fun.to_string(), fun_type_expr));
+            }
+        }
+    }
+    let defined = env.split_off(depth);
+    let signature = match module.signature {
+        Some(ref signature) => signature,
+        None => {
+            return defined
+                .into_iter()
+                .map(|(v, type_expr)| (format!("{}.{}", module.name, v), type_expr))
+                .collect()
+        }
+    };
+    let mut exports = vec![];
+    for spec in signature.iter() {
+        if let Spec::Val(ref v, ref type_expr) = *spec {
+            // names are resolved before anything is checked, so everything in
+            // the signature has been defined
+            let defined_type = find(&defined, v).unwrap_or(TypeExpr::Error);
+            // a polymorphic value is exported at the type its signature gives
+            let (found, instance) = unknowns.instantiate(&defined_type);
+            unknowns
+                .instances
+                .exports
+                .insert(format!("{}.{}", module.name, v), instance.clone());
+            unknowns.uses.push(Use {
+                v: v.clone(),
+                location: loc.clone(),
+                expr: expr.clone(),
+                instance,
+            });
+            if !unknowns.unify(&found, &type_expr.expand(&representations)) {
+                errors.push(log::type_error(
+                    loc,
+                    format!(
+                        "the signature of '{}' says '{}' has type '{}', but it's defined with type '{}'",
+                        module.name,
+                        v,
+                        type_expr,
+                        unknowns.apply(&found)
+                    ),
+                    expr,
+                ));
+            }
+            // the type variables the signature binds are type variables of what
+            // it exports too, unless they've turned out to be in the type of
+            // something that can't be polymorphic (which is reported once
+            // everything has been checked)
+            let monomorphic = unknowns.apply(&defined_type).unknowns();
+            for n in type_expr.unknowns() {
+                if let TypeExpr::Unknown(m) = unknowns.resolve(&TypeExpr::Unknown(n)) {
+                    if !monomorphic.contains(&m) || unknowns.generic.contains(&m) {
+                        unknowns.generic.insert(m);
+                    }
+                }
+            }
+            exports.push((format!("{}.{}", module.name, v), type_expr.clone()));
         }
     }
+    exports
 }
 
-fn find(env: &Vec<(Var, TypeExpr)>, v: &Var) -> Result<TypeExpr, String> {
-    for (env_v, type_expr) in env.iter().rev() {
-        if env_v.eq(v) {
-            return Ok(type_expr.clone());
+// the condition of a branch or a loop, which has to be a boolean
+fn check_condition(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    loc: &Location,
+    condition: &Locatable<Expr>,
+    what: &str,
+) {
+    let t = infer_expr(env, warnings, errors, unknowns, condition);
+    if !unknowns.unify(&t, &TypeExpr::Bool) {
+        errors.push(log::type_error(
+            loc,
+            format!(
+                "a {} condition must have type '{}', found '{}'",
+                what,
+                TypeExpr::Bool,
+                unknowns.apply(&t)
+            ),
+            condition.borrow_raw(),
+        ));
+    }
+}
+
+// the expressions before the last in a sequence, whose values are thrown away
+fn discard(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    discarded: &[SubExpr],
+) {
+    for sub in discarded.iter() {
+        let t = infer_expr(env, warnings, errors, unknowns, sub);
+        let t = unknowns.apply(&t);
+        if !t.agrees(&TypeExpr::Unit) {
+            warnings.push(log::warning(
+                sub.location(),
+                format!(
+                    "value of type '{}' is discarded (use 'ignore' to discard it explicitly)",
+                    t
+                ),
+                sub.borrow_raw(),
+            ));
+        }
+    }
+}
+
+// checks an expression against the type it's expected to have (from an
+// annotation, or from the function it's passed to), which is passed down into
+// its subexpressions where possible, so that a mismatch is reported at the
+// smallest part of the expression that's wrong rather than around all of it
+fn check_expr(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    expr: &Locatable<Expr>,
+    expected: &TypeExpr,
+) {
+    use Expr::*;
+    let loc = expr.location();
+    let expected = &unknowns.resolve(expected);
+    match (expr.borrow_raw(), expected) {
+        (If(condition, left, right), _) => {
+            check_condition(env, warnings, errors, unknowns, loc, condition, "branch");
+            check_expr(env, warnings, errors, unknowns, left, expected);
+            check_expr(env, warnings, errors, unknowns, right, expected);
+        }
+        (Pair(left, right), TypeExpr::Product(t1, t2)) => {
+            check_expr(env, warnings, errors, unknowns, left, t1);
+            check_expr(env, warnings, errors, unknowns, right, t2);
+        }
+        (Tuple(subs), TypeExpr::Tuple(types)) if subs.len() == types.len() => {
+            for (sub, t) in subs.iter().zip(types) {
+                check_expr(env, warnings, errors, unknowns, sub, t);
+            }
+        }
+        (Inl(sub, type_expr), TypeExpr::Union(t1, t2)) if unknowns.unify(type_expr, t2) => {
+            annotation(errors, unknowns, loc, expr.borrow_raw(), type_expr, t2);
+            check_expr(env, warnings, errors, unknowns, sub, t1)
+        }
+        (Inr(sub, type_expr), TypeExpr::Union(t1, t2)) if unknowns.unify(type_expr, t1) => {
+            annotation(errors, unknowns, loc, expr.borrow_raw(), type_expr, t1);
+            check_expr(env, warnings, errors, unknowns, sub, t2)
+        }
+        (
+            Case(sub, (v_left, type_expr_left, sub_left), (v_right, type_expr_right, sub_right)),
+            _,
+        ) => {
+            let (type_expr_left, type_expr_right) = scrutinise(
+                env,
+                warnings,
+                errors,
+                unknowns,
+                loc,
+                expr.borrow_raw(),
+                sub,
+                type_expr_left,
+                type_expr_right,
+            );
+            env.push((v_left.to_string(), type_expr_left));
+            check_expr(env, warnings, errors, unknowns, sub_left, expected);
+            env.pop();
+            env.push((v_right.to_string(), type_expr_right));
+            check_expr(env, warnings, errors, unknowns, sub_right, expected);
+            env.pop();
+        }
+        (Try(sub, (v, type_expr, handler)), _) => {
+            handles(errors, unknowns, loc, expr.borrow_raw(), type_expr);
+            check_expr(env, warnings, errors, unknowns, sub, expected);
+            env.push((v.to_string(), TypeExpr::Int));
+            check_expr(env, warnings, errors, unknowns, handler, expected);
+            env.pop();
+        }
+        (Match(sub, type_expr, arms, result), _) => {
+            unknowns.unify(result, expected);
+            let bindings = scrutinise_match(
+                env,
+                warnings,
+                errors,
+                unknowns,
+                loc,
+                expr.borrow_raw(),
+                sub,
+                type_expr,
+                arms,
+            );
+            for ((_, body), bound) in arms.iter().zip(bindings) {
+                let depth = env.len();
+                env.extend(bound);
+                check_expr(env, warnings, errors, unknowns, body, expected);
+                env.truncate(depth);
+            }
+        }
+        (Lambda((v, type_expr, sub)), TypeExpr::Arrow(from, to))
+            if unknowns.unify(type_expr, from) =>
+        {
+            let type_expr = annotation(errors, unknowns, loc, expr.borrow_raw(), type_expr, from);
+            env.push((v.to_string(), type_expr));
+            check_expr(env, warnings, errors, unknowns, sub, to);
+            env.pop();
+        }
+        (Seq(seq), _) if !seq.is_empty() => {
+            let (last, discarded) = seq.split_last().unwrap();
+            discard(env, warnings, errors, unknowns, discarded);
+            check_expr(env, warnings, errors, unknowns, last, expected);
+        }
+        (Ref(sub), TypeExpr::Ref(t)) | (Lazy(sub), TypeExpr::Lazy(t)) => {
+            check_expr(env, warnings, errors, unknowns, sub, t)
+        }
+        (MakeArray(length, sub), TypeExpr::Array(t)) => {
+            check_expr(env, warnings, errors, unknowns, length, &TypeExpr::Int);
+            check_expr(env, warnings, errors, unknowns, sub, t)
+        }
+        (Cons(head, tail), TypeExpr::List(t)) => {
+            check_expr(env, warnings, errors, unknowns, head, t);
+            check_expr(env, warnings, errors, unknowns, tail, expected)
+        }
+        (Let(..), _) | (LetFun(..), _) | (LetRec(..), _) => {
+            let depth = env.len();
+            let body = definitions(env, warnings, errors, unknowns, expr);
+            check_expr(env, warnings, errors, unknowns, body, expected);
+            env.truncate(depth);
+        }
+        (Hole(name), _) => errors.push(format!(
+            "{}{}",
+            log::type_error(
+                loc,
+                format!("hole '?{}' has type '{}'", name, unknowns.apply(expected)),
+                expr.borrow_raw(),
+            ),
+            scope(env, unknowns)
+        )),
+        _ => {
+            let t = infer_expr(env, warnings, errors, unknowns, expr);
+            if !unknowns.unify(&t, expected) {
+                mismatch(errors, unknowns, expr, expected, &t);
+            }
+        }
+    }
+}
+
+// checks a chain of definitions ('let x = e in let y = e in ...', along with
+// modules and data types), bringing what each defines into scope for the rest,
+// and gives what's inside the last of them. A program of top-level definitions
+// is one chain, so this walks down it in a loop rather than by recursing, and
+// leaves taking the definitions back out of 'env' to the caller
+fn definitions<'a>(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    expr: &'a Locatable<Expr>,
+) -> &'a Locatable<Expr> {
+    use Expr::*;
+    let mut expr = expr;
+    loop {
+        let loc = expr.location();
+        expr = match expr.borrow_raw() {
+            Let(v, type_expr, sub, body) => {
+                unknowns.enter();
+                let type_expr = define(env, warnings, errors, unknowns, sub, type_expr);
+                unknowns.leave();
+                if generalisable(sub.borrow_raw()) {
+                    unknowns.generalise(&type_expr);
+                }
+                env.push((v.to_string(), type_expr));
+                body
+            }
+            LetFun(fun, lambda, type_expr, body) => {
+                unknowns.enter();
+                let fun_type_expr = check_fun(
+                    env,
+                    warnings,
+                    errors,
+                    unknowns,
+                    loc,
+                    expr.borrow_raw(),
+                    fun,
+                    lambda,
+                    type_expr,
+                );
+                unknowns.leave();
+                unknowns.generalise(&fun_type_expr);
+                env.push((fun.to_string(), fun_type_expr));
+                body
+            }
+            LetRec(functions, body) => {
+                let types = check_funs(
+                    env,
+                    warnings,
+                    errors,
+                    unknowns,
+                    loc,
+                    expr.borrow_raw(),
+                    functions,
+                );
+                env.extend(types);
+                body
+            }
+            Module(module, body) => {
+                let exports = check_module(
+                    env,
+                    warnings,
+                    errors,
+                    unknowns,
+                    loc,
+                    expr.borrow_raw(),
+                    module,
+                );
+                env.extend(exports);
+                body
+            }
+            // a data type's name and constructors have been resolved, so
+            // nothing else about it needs checking
+            Data(_, body) => body,
+            _ => return expr,
+        };
+    }
+}
+
+// the type of what an operator gives, given the types of its operands
+fn binop(
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    loc: &Location,
+    expr: &Expr,
+    op: &BinOp,
+    t1: TypeExpr,
+    t2: TypeExpr,
+) -> TypeExpr {
+    use self::BinOp::*;
+    match op {
+        Add | Sub | Mul | Div | Mod | FloatAdd | FloatSub | FloatMul | FloatDiv => {
+            let expected = match op {
+                FloatAdd | FloatSub | FloatMul | FloatDiv => TypeExpr::Float,
+                _ => TypeExpr::Int,
+            };
+            if !unknowns.unify(&t1, &expected) || !unknowns.unify(&t2, &expected) {
+                errors.push(log::type_error(
+                    loc,
+                    format!(
+                        "'{}' expects operands of type '{}', found '{}' and '{}'",
+                        op,
+                        expected,
+                        unknowns.apply(&t1),
+                        unknowns.apply(&t2)
+                    ),
+                    expr,
+                ));
+            }
+            expected
         }
+        Concat => {
+            if !unknowns.unify(&t1, &TypeExpr::String) || !unknowns.unify(&t2, &TypeExpr::String) {
+                errors.push(log::type_error(
+                    loc,
+                    format!(
+                        "'{}' expects operands of type '{}', found '{}' and '{}'",
+                        op,
+                        TypeExpr::String,
+                        unknowns.apply(&t1),
+                        unknowns.apply(&t2)
+                    ),
+                    expr,
+                ));
+            }
+            TypeExpr::String
+        }
+        Or | And => {
+            if !unknowns.unify(&t1, &TypeExpr::Bool) || !unknowns.unify(&t2, &TypeExpr::Bool) {
+                errors.push(format!("'{}' expects boolean operands", op));
+            }
+            TypeExpr::Bool
+        }
+        Lt | Eq | PhysEq => {
+            if !unknowns.unify(&t1, &t2) {
+                errors.push(log::type_error(
+                    loc,
+                    format!(
+                        "'{}' expects operands of the same type, found '{}' and '{}'",
+                        op,
+                        unknowns.apply(&t1),
+                        unknowns.apply(&t2)
+                    ),
+                    expr,
+                ));
+            } else if let Lt = op {
+                unknowns.constrain(errors, Class::Ord, &t1, loc, expr);
+            } else if let Eq = op {
+                unknowns.constrain(errors, Class::Eq, &t1, loc, expr);
+            }
+            TypeExpr::Bool
+        }
+        // only made once the program has been checked
+        FloatLt | FloatEq | Compare => unreachable!(),
     }
-    Err(format!("'{}' is not defined", v))
 }
 
-pub fn infer(env: &mut Vec<(Var, TypeExpr)>, expr: &Locatable<Expr>) -> Result<TypeExpr, String> {
+// after a type error, this carries on with 'TypeExpr::Error' as the type of
+// whatever was wrong, so that the rest of the program is still checked
+fn infer_expr(
+    env: &mut Vec<(Var, TypeExpr)>,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+    unknowns: &mut Unknowns,
+    expr: &Locatable<Expr>,
+) -> TypeExpr {
     use Expr::*;
+    let located = expr;
     let loc = expr.location();
     let expr = expr.borrow_raw();
     match expr {
-        Unit => Ok(TypeExpr::Unit),
-        What => Ok(TypeExpr::Int),
-        Var(ref v) => Ok(find(&env, v)?),
-        Int(_) => Ok(TypeExpr::Int),
-        Bool(_) => Ok(TypeExpr::Bool),
-        UnOp(op, sub) => {
-            use self::UnOp::*;
-            match (op, infer(env, sub)?) {
-                (Neg, TypeExpr::Int) => Ok(TypeExpr::Int),
-                (Not, TypeExpr::Bool) => Ok(TypeExpr::Bool),
-                (Neg, t) => Err(log::type_error(
+        Unit => TypeExpr::Unit,
+        What => TypeExpr::Int,
+        Hole(ref name) => {
+            errors.push(format!(
+                "{}{}",
+                log::type_error(
                     loc,
                     format!(
-                        "'{}' expects an operand of type '{}', found '{}'",
-                        Neg,
-                        TypeExpr::Int,
-                        t
+                        "can't work out the type of hole '?{}' here (it needs to be somewhere its type is known, such as an argument or the right hand side of a 'let')",
+                        name
+                    ),
+                    expr,
+                ),
+                scope(env, unknowns)
+            ));
+            TypeExpr::Error
+        }
+        Var(ref v) => match find(env, v) {
+            Some(type_expr) => {
+                let (type_expr, instance) = unknowns.instantiate(&type_expr);
+                if !instance.is_empty() {
+                    unknowns
+                        .instances
+                        .uses
+                        .insert((loc.line(), loc.column()), instance.clone());
+                    unknowns.uses.push(Use {
+                        v: v.clone(),
+                        location: loc.clone(),
+                        expr: expr.clone(),
+                        instance,
+                    });
+                }
+                type_expr
+            }
+            None => error(
+                errors,
+                log::type_error(loc, format!("'{}' is not defined", v), expr),
+            ),
+        },
+        Int(_) => TypeExpr::Int,
+        Float(_) => TypeExpr::Float,
+        Bool(_) => TypeExpr::Bool,
+        Str(_) => TypeExpr::String,
+        Attributed(_, sub) => infer_expr(env, warnings, errors, unknowns, sub),
+        Expect(sub, _) => {
+            let t = infer_expr(env, warnings, errors, unknowns, sub);
+            if !unknowns.unify(&t, &TypeExpr::Bool) {
+                errors.push(log::type_error(
+                    loc,
+                    format!(
+                        "'expect' expects a condition of type '{}', found '{}'",
+                        TypeExpr::Bool,
+                        unknowns.apply(&t)
                     ),
                     sub.borrow_raw(),
-                )),
-                (Not, t) => Err(log::type_error(
+                ));
+            }
+            TypeExpr::Bool
+        }
+        UnOp(op, sub) => {
+            use self::UnOp::*;
+            let t = infer_expr(env, warnings, errors, unknowns, sub);
+            let expected = match op {
+                Neg => TypeExpr::Int,
+                Not => TypeExpr::Bool,
+            };
+            if !unknowns.unify(&t, &expected) {
+                errors.push(log::type_error(
                     loc,
                     format!(
                         "'{}' expects an operand of type '{}', found '{}'",
-                        Not,
-                        TypeExpr::Bool,
-                        t,
+                        op,
+                        expected,
+                        unknowns.apply(&t)
                     ),
                     sub.borrow_raw(),
-                )),
+                ));
             }
+            expected
         }
-        BinOp(op, left, right) => {
-            use self::BinOp::*;
-            match (op, infer(env, left)?, infer(env, right)?) {
-                (Lt, TypeExpr::Int, TypeExpr::Int) => Ok(TypeExpr::Bool),
-                (Add, TypeExpr::Int, TypeExpr::Int) => Ok(TypeExpr::Int),
-                (Sub, TypeExpr::Int, TypeExpr::Int) => Ok(TypeExpr::Int),
-                (Mul, TypeExpr::Int, TypeExpr::Int) => Ok(TypeExpr::Int),
-                (Div, TypeExpr::Int, TypeExpr::Int) => Ok(TypeExpr::Int),
-                (Lt, t1, t2) | (Add, t1, t2) | (Sub, t1, t2) | (Mul, t1, t2) | (Div, t1, t2) => {
-                    Err(log::type_error(
-                        loc,
-                        format!(
-                            "'{}' expects operands of type '{}', found '{}' and '{}'",
-                            op,
-                            TypeExpr::Int,
-                            t1,
-                            t2
-                        ),
-                        expr,
-                    ))
-                }
-                (Or, TypeExpr::Bool, TypeExpr::Bool) => Ok(TypeExpr::Bool),
-                (And, TypeExpr::Bool, TypeExpr::Bool) => Ok(TypeExpr::Bool),
-                (Or, _, _) | (And, _, _) => Err(format!("'{}' expects boolean operands", op)),
-                (Eq, t1, t2) => {
-                    if t1 == t2 {
-                        Ok(TypeExpr::Bool)
-                    } else {
-                        Err(log::type_error(
-                            loc,
-                            format!(
-                                "'=' expects operands of the same type, found '{}' and '{}'",
-                                t1, t2
-                            ),
-                            expr,
-                        ))
-                    }
+        BinOp(..) => {
+            // a chain ('a + b + c') is checked from its leftmost operand on,
+            // in a loop, as it can be too long to recurse down
+            let mut chain = vec![];
+            let mut sub = located;
+            while let BinOp(_, ref left, _) = sub.t {
+                chain.push(sub);
+                sub = left;
+            }
+            let mut t = infer_expr(env, warnings, errors, unknowns, sub);
+            for link in chain.into_iter().rev() {
+                if let BinOp(ref op, _, ref right) = link.t {
+                    let t2 = infer_expr(env, warnings, errors, unknowns, right);
+                    t = binop(errors, unknowns, link.location(), &link.t, op, t, t2);
                 }
             }
+            t
+        }
+        Comparison(op, left, right, type_expr) => {
+            let t1 = infer_expr(env, warnings, errors, unknowns, left);
+            unknowns.unify(type_expr, &t1);
+            let t2 = infer_expr(env, warnings, errors, unknowns, right);
+            binop(errors, unknowns, loc, expr, op, t1, t2)
         }
         If(condition, left, right) => {
-            let t1 = infer(env, condition)?;
-            if let TypeExpr::Bool = t1 {
-                let t2 = infer(env, left)?;
-                let t3 = infer(env, right)?;
-                if t2 == t3 {
-                    Ok(t2)
-                } else {
-                    Err(log::type_error(
+            check_condition(env, warnings, errors, unknowns, loc, condition, "branch");
+            let t2 = infer_expr(env, warnings, errors, unknowns, left);
+            let t3 = infer_expr(env, warnings, errors, unknowns, right);
+            branches(errors, unknowns, loc, expr, t2, t3)
+        }
+        Pair(left, right) => TypeExpr::Product(
+            Box::new(infer_expr(env, warnings, errors, unknowns, left)),
+            Box::new(infer_expr(env, warnings, errors, unknowns, right)),
+        ),
+        Fst(sub) | Snd(sub) => {
+            let t = infer_expr(env, warnings, errors, unknowns, sub);
+            match unknowns.shaped_pair(&t, TypeExpr::Product) {
+                TypeExpr::Product(left, _) if matches!(expr, Fst(_)) => *left,
+                TypeExpr::Product(_, right) => *right,
+                TypeExpr::Error => TypeExpr::Error,
+                t => error(
+                    errors,
+                    log::type_error(
                         loc,
                         format!(
-                            "branches must have the same type, found '{}' and '{}'",
-                            t2, t3
+                            "can only project from a product type, found '{}'",
+                            unknowns.apply(&t)
                         ),
-                        expr,
-                    ))
-                }
-            } else {
-                Err(log::type_error(
-                    loc,
-                    format!(
-                        "a branch condition must have type '{}', found '{}'",
-                        TypeExpr::Bool,
-                        t1
+                        sub.borrow_raw(),
                     ),
-                    condition.borrow_raw(),
-                ))
+                ),
             }
         }
-        Pair(left, right) => Ok(TypeExpr::Product(
-            Box::new(infer(env, left)?),
-            Box::new(infer(env, right)?),
-        )),
-        Fst(sub) => {
-            let t = infer(env, sub)?;
-            if let TypeExpr::Product(left, _) = t {
-                Ok(*left)
-            } else {
-                Err(log::type_error(
-                    loc,
-                    format!("can only project from a product type, found '{}'", t),
-                    sub.borrow_raw(),
-                ))
+        Inl(sub, type_expr) => TypeExpr::Union(
+            Box::new(infer_expr(env, warnings, errors, unknowns, sub)),
+            Box::new(annotation(
+                errors,
+                unknowns,
+                loc,
+                expr,
+                type_expr,
+                &TypeExpr::Error,
+            )),
+        ),
+        Inr(sub, type_expr) => TypeExpr::Union(
+            Box::new(annotation(
+                errors,
+                unknowns,
+                loc,
+                expr,
+                type_expr,
+                &TypeExpr::Error,
+            )),
+            Box::new(infer_expr(env, warnings, errors, unknowns, sub)),
+        ),
+        Case(sub, (v_left, type_expr_left, sub_left), (v_right, type_expr_right, sub_right)) => {
+            let (type_expr_left, type_expr_right) = scrutinise(
+                env,
+                warnings,
+                errors,
+                unknowns,
+                loc,
+                expr,
+                sub,
+                type_expr_left,
+                type_expr_right,
+            );
+            env.push((v_left.to_string(), type_expr_left));
+            let left = infer_expr(env, warnings, errors, unknowns, sub_left);
+            env.pop();
+            env.push((v_right.to_string(), type_expr_right));
+            let right = infer_expr(env, warnings, errors, unknowns, sub_right);
+            env.pop();
+            branches(errors, unknowns, loc, expr, left, right)
+        }
+        Match(sub, type_expr, arms, result) => {
+            let bindings = scrutinise_match(
+                env, warnings, errors, unknowns, loc, expr, sub, type_expr, arms,
+            );
+            let mut found: Option<TypeExpr> = None;
+            for ((_, body), bound) in arms.iter().zip(bindings) {
+                let depth = env.len();
+                env.extend(bound);
+                let t = infer_expr(env, warnings, errors, unknowns, body);
+                env.truncate(depth);
+                found = Some(match found {
+                    Some(found) => branches(errors, unknowns, loc, expr, found, t),
+                    None => t,
+                });
             }
+            let found = found.unwrap_or(TypeExpr::Error);
+            unknowns.unify(result, &found);
+            found
         }
-        Snd(sub) => {
-            let t = infer(env, sub)?;
-            if let TypeExpr::Product(_, right) = t {
-                Ok(*right)
-            } else {
-                Err(log::type_error(
-                    loc,
-                    format!("can only project from a product type, found '{}'", t),
-                    sub.borrow_raw(),
-                ))
+        Record(fields, type_expr) => {
+            let mut types = fields
+                .iter()
+                .map(|(x, sub)| (x.clone(), infer_expr(env, warnings, errors, unknowns, sub)))
+                .collect::<Vec<_>>();
+            types.sort_by(|(x, _), (y, _)| x.cmp(y));
+            let t = TypeExpr::Record(types);
+            unknowns.unify(type_expr, &t);
+            t
+        }
+        Field(sub, x, type_expr) => {
+            let t = infer_expr(env, warnings, errors, unknowns, sub);
+            unknowns.unify(type_expr, &t);
+            match unknowns.resolve(&t) {
+                TypeExpr::Record(fields) => match fields.iter().find(|(y, _)| y == x) {
+                    Some((_, t)) => t.clone(),
+                    None => error(
+                        errors,
+                        log::type_error(
+                            loc,
+                            format!(
+                                "'{}' doesn't have a field called '{}'{}",
+                                unknowns.apply(&TypeExpr::Record(fields.clone())),
+                                x,
+                                fields_like(x, &fields)
+                            ),
+                            expr,
+                        ),
+                    ),
+                },
+                TypeExpr::Error => TypeExpr::Error,
+                // a field could be in any number of record types, so which
+                // one it's taken from has to be known by the time it's taken
+                TypeExpr::Unknown(_) => error(
+                    errors,
+                    log::type_error(
+                        loc,
+                        format!(
+                            "can't work out what type of record '.{}' is taken from here (it needs an annotation)",
+                            x
+                        ),
+                        expr,
+                    ),
+                ),
+                t => error(
+                    errors,
+                    log::type_error(
+                        loc,
+                        format!(
+                            "can only take a field from a record, found '{}'",
+                            unknowns.apply(&t)
+                        ),
+                        sub.borrow_raw(),
+                    ),
+                ),
             }
         }
-        Inl(sub, type_expr) => Ok(TypeExpr::Union(
-            Box::new(infer(env, sub)?),
-            Box::new(type_expr.clone()),
-        )),
-        Inr(sub, type_expr) => Ok(TypeExpr::Union(
-            Box::new(type_expr.clone()),
-            Box::new(infer(env, sub)?),
-        )),
-        Case(sub, (v_left, type_expr_left, sub_left), (v_right, type_expr_right, sub_right)) => {
-            let t = infer(env, sub)?;
-            if let TypeExpr::Union(t1, t2) = t {
-                if *t1 == *type_expr_left && *t2 == *type_expr_right {
-                    env.push((v_left.to_string(), type_expr_left.clone()));
-                    let left = infer(env, sub_left)?;
-                    env.pop();
-                    env.push((v_right.to_string(), type_expr_right.clone()));
-                    let right = infer(env, sub_right)?;
-                    env.pop();
-                    if left == right {
-                        Ok(left)
-                    } else {
-                        Err(log::type_error(
+        Tuple(subs) => TypeExpr::Tuple(
+            subs.iter()
+                .map(|sub| infer_expr(env, warnings, errors, unknowns, sub))
+                .collect(),
+        ),
+        Proj(sub, k, type_expr) => {
+            let t = infer_expr(env, warnings, errors, unknowns, sub);
+            unknowns.unify(type_expr, &t);
+            let types = match unknowns.resolve(&t) {
+                TypeExpr::Product(left, right) => vec![*left, *right],
+                TypeExpr::Tuple(types) => types,
+                TypeExpr::Error => return TypeExpr::Error,
+                // how many components the tuple has decides which pairs the
+                // component is taken from, so it has to be known by then
+                TypeExpr::Unknown(_) => {
+                    return error(
+                        errors,
+                        log::type_error(
                             loc,
                             format!(
-                                "branches must have the same type, found '{}' and '{}'",
-                                left, right
+                                "can't work out how many components the tuple '#{}' is taken from here has (it needs an annotation)",
+                                k
                             ),
                             expr,
-                        ))
-                    }
-                } else {
-                    Err(format!(
-                        "expected union of type '{}', found '{}'",
-                        TypeExpr::Union(t1, t2),
-                        TypeExpr::Union(
-                            Box::new(type_expr_left.clone()),
-                            Box::new(type_expr_right.clone())
-                        )
-                    ))
+                        ),
+                    )
                 }
-            } else {
-                Err(log::type_error(
-                    loc,
-                    format!("case expected a union type, found '{}'", t),
-                    sub.borrow_raw(),
-                ))
+                t => {
+                    return error(
+                        errors,
+                        log::type_error(
+                            loc,
+                            format!(
+                                "can only project from a product type, found '{}'",
+                                unknowns.apply(&t)
+                            ),
+                            sub.borrow_raw(),
+                        ),
+                    )
+                }
+            };
+            match types.get(k - 1) {
+                Some(t) => t.clone(),
+                None => error(
+                    errors,
+                    log::type_error(
+                        loc,
+                        format!(
+                            "'{}' only has {} components, so there's no '#{}'",
+                            unknowns.apply(&t),
+                            types.len(),
+                            k
+                        ),
+                        expr,
+                    ),
+                ),
             }
         }
         Lambda((v, type_expr, sub)) => {
+            let type_expr = annotation(errors, unknowns, loc, expr, type_expr, &TypeExpr::Error);
             env.push((v.to_string(), type_expr.clone()));
-            let other_type_expr = infer(env, sub)?;
+            let other_type_expr = infer_expr(env, warnings, errors, unknowns, sub);
             env.pop();
-            Ok(TypeExpr::Arrow(
-                Box::new(type_expr.clone()),
-                Box::new(other_type_expr),
-            ))
+            TypeExpr::Arrow(Box::new(type_expr), Box::new(other_type_expr))
         }
         While(condition, sub) => {
-            let t = infer(env, condition)?;
-            if let TypeExpr::Bool = t {
-                infer(env, sub)?;
-                Ok(TypeExpr::Unit)
-            } else {
-                Err(log::type_error(
-                    loc,
-                    format!(
-                        "a loop condition must have type '{}', found '{}'",
-                        TypeExpr::Bool,
-                        t
+            check_condition(env, warnings, errors, unknowns, loc, condition, "loop");
+            infer_expr(env, warnings, errors, unknowns, sub);
+            TypeExpr::Unit
+        }
+        For(v, from, to, sub) => {
+            check_expr(env, warnings, errors, unknowns, from, &TypeExpr::Int);
+            check_expr(env, warnings, errors, unknowns, to, &TypeExpr::Int);
+            env.push((v.to_string(), TypeExpr::Int));
+            infer_expr(env, warnings, errors, unknowns, sub);
+            env.pop();
+            TypeExpr::Unit
+        }
+        Seq(seq) => match seq.split_last() {
+            None => error(
+                errors,
+                log::type_error(loc, "found empty sequence".to_string(), expr),
+            ),
+            Some((last, discarded)) => {
+                discard(env, warnings, errors, unknowns, discarded);
+                infer_expr(env, warnings, errors, unknowns, last)
+            }
+        },
+        Ignore(sub) => {
+            infer_expr(env, warnings, errors, unknowns, sub);
+            TypeExpr::Unit
+        }
+        Ref(sub) => TypeExpr::Ref(Box::new(infer_expr(env, warnings, errors, unknowns, sub))),
+        Deref(sub) => {
+            let t = infer_expr(env, warnings, errors, unknowns, sub);
+            match unknowns.shaped(&t, TypeExpr::Ref) {
+                TypeExpr::Ref(t) => *t,
+                TypeExpr::Error => TypeExpr::Error,
+                t => error(
+                    errors,
+                    log::type_error(
+                        loc,
+                        format!(
+                            "cannot dereference something of type '{}'",
+                            unknowns.apply(&t)
+                        ),
+                        sub.borrow_raw(),
                     ),
-                    condition.borrow_raw(),
-                ))
+                ),
             }
         }
-        Seq(seq) => {
-            if seq.is_empty() {
-                Err(log::type_error(
-                    loc,
-                    "found empty sequence".to_string(),
-                    expr,
-                ))
-            } else {
-                for sub in seq.iter() {
-                    infer(env, sub)?;
+        Lazy(sub) => TypeExpr::Lazy(Box::new(infer_expr(env, warnings, errors, unknowns, sub))),
+        Force(sub) => {
+            let t = infer_expr(env, warnings, errors, unknowns, sub);
+            match unknowns.shaped(&t, TypeExpr::Lazy) {
+                TypeExpr::Lazy(t) => *t,
+                TypeExpr::Error => TypeExpr::Error,
+                t => error(
+                    errors,
+                    log::type_error(
+                        loc,
+                        format!("cannot force something of type '{}'", unknowns.apply(&t)),
+                        sub.borrow_raw(),
+                    ),
+                ),
+            }
+        }
+        Chan(type_expr) => TypeExpr::Chan(Box::new(annotation(
+            errors,
+            unknowns,
+            loc,
+            expr,
+            type_expr,
+            &TypeExpr::Error,
+        ))),
+        Spawn(sub) => {
+            check_expr(env, warnings, errors, unknowns, sub, &TypeExpr::Unit);
+            TypeExpr::Unit
+        }
+        // raising never gives a value, so it can be used as anything
+        Raise(sub) => {
+            check_expr(env, warnings, errors, unknowns, sub, &TypeExpr::Int);
+            unknowns.fresh()
+        }
+        Try(sub, (v, type_expr, handler)) => {
+            handles(errors, unknowns, loc, expr, type_expr);
+            let t = infer_expr(env, warnings, errors, unknowns, sub);
+            env.push((v.to_string(), TypeExpr::Int));
+            check_expr(env, warnings, errors, unknowns, handler, &t);
+            env.pop();
+            t
+        }
+        Print(sub) => {
+            check_expr(env, warnings, errors, unknowns, sub, &TypeExpr::String);
+            TypeExpr::Unit
+        }
+        Send(left, right) => {
+            let t = infer_expr(env, warnings, errors, unknowns, left);
+            match unknowns.shaped(&t, TypeExpr::Chan) {
+                TypeExpr::Chan(t) => check_expr(env, warnings, errors, unknowns, right, &t),
+                TypeExpr::Error => {
+                    infer_expr(env, warnings, errors, unknowns, right);
+                }
+                t => {
+                    errors.push(log::type_error(
+                        loc,
+                        format!("cannot send on something of type '{}'", unknowns.apply(&t)),
+                        left.borrow_raw(),
+                    ));
+                    infer_expr(env, warnings, errors, unknowns, right);
                 }
-                infer(env, &seq[seq.len() - 1])
             }
+            TypeExpr::Unit
         }
-        Ref(sub) => Ok(TypeExpr::Ref(Box::new(infer(env, sub)?))),
-        Deref(sub) => {
-            let t = infer(env, sub)?;
-            if let TypeExpr::Ref(t) = t {
-                Ok(*t)
-            } else {
-                Err(log::type_error(
-                    loc,
-                    format!("cannot dereference something of type '{}'", t),
-                    sub.borrow_raw(),
-                ))
+        Nil => TypeExpr::List(Box::new(unknowns.fresh())),
+        Cons(head, tail) => {
+            let t = TypeExpr::List(Box::new(infer_expr(env, warnings, errors, unknowns, head)));
+            check_expr(env, warnings, errors, unknowns, tail, &t);
+            t
+        }
+        Head(sub) | Tail(sub) | IsEmpty(sub) => {
+            let t = infer_expr(env, warnings, errors, unknowns, sub);
+            let element = match unknowns.shaped(&t, TypeExpr::List) {
+                TypeExpr::List(t) => *t,
+                TypeExpr::Error => TypeExpr::Error,
+                t => {
+                    let message = match *expr {
+                        Head(_) => format!(
+                            "cannot take the head of something of type '{}'",
+                            unknowns.apply(&t)
+                        ),
+                        Tail(_) => format!(
+                            "cannot take the tail of something of type '{}'",
+                            unknowns.apply(&t)
+                        ),
+                        _ => format!(
+                            "cannot check whether something of type '{}' is empty",
+                            unknowns.apply(&t)
+                        ),
+                    };
+                    error(errors, log::type_error(loc, message, sub.borrow_raw()))
+                }
+            };
+            match *expr {
+                Head(_) => element,
+                Tail(_) => TypeExpr::List(Box::new(element)),
+                _ => TypeExpr::Bool,
             }
         }
-        Assign(left, right) => {
-            let t1 = infer(env, left)?;
-            if let TypeExpr::Ref(t1) = t1 {
-                let t2 = infer(env, right)?;
-                if *t1 == t2 {
-                    Ok(TypeExpr::Unit)
-                } else {
-                    Err(log::type_error(
+        Recv(sub) => {
+            let t = infer_expr(env, warnings, errors, unknowns, sub);
+            match unknowns.shaped(&t, TypeExpr::Chan) {
+                TypeExpr::Chan(t) => *t,
+                TypeExpr::Error => TypeExpr::Error,
+                t => error(
+                    errors,
+                    log::type_error(
                         loc,
                         format!(
-                        "right hand side of assignment was expected to be of type '{}', found '{}'",
-                        t1, t2
-                    ),
-                        right.borrow_raw(),
-                    ))
-                }
-            } else {
-                Err(log::type_error(
-                    loc,
-                    format!(
-                        "left hand side of assignment must be a reference type, found '{}'",
-                        t1
+                            "cannot receive from something of type '{}'",
+                            unknowns.apply(&t)
+                        ),
+                        sub.borrow_raw(),
                     ),
-                    left.borrow_raw(),
-                ))
+                ),
             }
         }
-        App(left, right) => {
-            let t = infer(env, left)?;
-            if let TypeExpr::Arrow(from, to) = t {
-                let t = infer(env, right)?;
-                if *from == t {
-                    Ok(*to)
-                } else {
-                    Err(log::type_error(
+        Assign(left, right) => {
+            let t1 = infer_expr(env, warnings, errors, unknowns, left);
+            match unknowns.shaped(&t1, TypeExpr::Ref) {
+                TypeExpr::Ref(t1) => check_expr(env, warnings, errors, unknowns, right, &t1),
+                TypeExpr::Error => {
+                    infer_expr(env, warnings, errors, unknowns, right);
+                }
+                t1 => {
+                    errors.push(log::type_error(
                         loc,
                         format!(
-                            "function was expecting argument of type '{}', found '{}'",
-                            from, t
+                            "left hand side of assignment must be a reference type, found '{}'",
+                            unknowns.apply(&t1)
                         ),
-                        right.borrow_raw(),
-                    ))
+                        left.borrow_raw(),
+                    ));
+                    infer_expr(env, warnings, errors, unknowns, right);
                 }
-            } else {
-                Err(log::type_error(
-                    loc,
-                    format!("expected a function type, found '{}'", t),
-                    left.borrow_raw(),
-                ))
-            }
-        }
-        Let(v, type_expr, sub, body) => {
-            let t = infer(env, sub)?;
-            if t == *type_expr {
-                env.push((v.to_string(), t));
-                let body = infer(env, body)?;
-                env.pop();
-                Ok(body)
-            } else {
-                Err(log::type_error(
-                    loc,
-                    format!("expected expression of type '{}', found '{}'", type_expr, t),
-                    sub.borrow_raw(),
-                ))
             }
+            TypeExpr::Unit
         }
-        LetFun(fun, (v_lambda, type_expr_lambda, sub_lambda), type_expr, body) => {
-            let fun_type_expr = TypeExpr::Arrow(
-                Box::new(type_expr_lambda.clone()),
-                Box::new(type_expr.clone()),
-            );
-            env.push((v_lambda.to_string(), type_expr_lambda.clone()));
-            env.push((fun.to_string(), fun_type_expr.clone()));
-            let lambda = infer(env, sub_lambda)?;
-            env.pop();
-            env.pop();
-            if lambda == *type_expr {
-                env.push((fun.to_string(), fun_type_expr));
-                let body = infer(env, body)?;
-                env.pop();
-                Ok(body)
-            } else {
-                Err(log::type_error(
-                    loc,
-                    format!(
-                        "expected expression of type '{}', found '{}'",
-                        type_expr, lambda
+        MakeArray(length, sub) => {
+            check_expr(env, warnings, errors, unknowns, length, &TypeExpr::Int);
+            TypeExpr::Array(Box::new(infer_expr(env, warnings, errors, unknowns, sub)))
+        }
+        Index(array, index) => {
+            let t = infer_expr(env, warnings, errors, unknowns, array);
+            check_expr(env, warnings, errors, unknowns, index, &TypeExpr::Int);
+            match unknowns.shaped(&t, TypeExpr::Array) {
+                TypeExpr::Array(t) => *t,
+                TypeExpr::Error => TypeExpr::Error,
+                t => error(
+                    errors,
+                    log::type_error(
+                        loc,
+                        format!("cannot index something of type '{}'", unknowns.apply(&t)),
+                        array.borrow_raw(),
+                    ),
+                ),
+            }
+        }
+        Update(array, index, right) => {
+            let t = infer_expr(env, warnings, errors, unknowns, array);
+            check_expr(env, warnings, errors, unknowns, index, &TypeExpr::Int);
+            match unknowns.shaped(&t, TypeExpr::Array) {
+                TypeExpr::Array(t) => check_expr(env, warnings, errors, unknowns, right, &t),
+                TypeExpr::Error => {
+                    infer_expr(env, warnings, errors, unknowns, right);
+                }
+                t => {
+                    errors.push(log::type_error(
+                        loc,
+                        format!("cannot index something of type '{}'", unknowns.apply(&t)),
+                        array.borrow_raw(),
+                    ));
+                    infer_expr(env, warnings, errors, unknowns, right);
+                }
+            }
+            TypeExpr::Unit
+        }
+        App(left, right) => {
+            let t1 = infer_expr(env, warnings, errors, unknowns, left);
+            match unknowns.shaped_pair(&t1, TypeExpr::Arrow) {
+                TypeExpr::Arrow(from, to) => {
+                    check_expr(env, warnings, errors, unknowns, right, &from);
+                    *to
+                }
+                TypeExpr::Error => {
+                    infer_expr(env, warnings, errors, unknowns, right);
+                    TypeExpr::Error
+                }
+                t1 => error(
+                    errors,
+                    log::type_error(
+                        loc,
+                        format!("expected a function type, found '{}'", unknowns.apply(&t1)),
+                        left.borrow_raw(),
                     ),
-                    sub_lambda.borrow_raw(),
-                ))
+                ),
             }
         }
+        Let(..) | LetFun(..) | LetRec(..) | Module(..) | Data(..) => {
+            let depth = env.len();
+            let body = definitions(env, warnings, errors, unknowns, located);
+            let body = infer_expr(env, warnings, errors, unknowns, body);
+            env.truncate(depth);
+            body
+        }
+        // resolving names replaces each 'open' with what it opens the module
+        // in (with the names it brings into scope qualified)
+        Open(_, _) => unreachable!(),
+        // names are resolved by applying each constructor that's given
+        // something to what it's applied to, so one that isn't is a function
+        Construct(t, c, sub) => match (sub, unknowns.payload(t, c)) {
+            (Some(sub), Some(payload)) => {
+                check_expr(env, warnings, errors, unknowns, sub, &payload);
+                TypeExpr::Named(t.clone())
+            }
+            (None, Some(payload)) => {
+                TypeExpr::Arrow(Box::new(payload), Box::new(TypeExpr::Named(t.clone())))
+            }
+            (None, None) => TypeExpr::Named(t.clone()),
+            (Some(_), None) => unreachable!(),
+        },
     }
 }
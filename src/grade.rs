@@ -0,0 +1,90 @@
+use super::frontend;
+use super::interp::{Input, Interpreter, Limits};
+use super::{opt, CompilerOptions, Value, STACK_SIZE};
+
+use std::thread;
+
+pub struct TestCase {
+    // the values to give each '?', in order
+    pub input: Vec<i64>,
+    // the value the program should produce
+    pub expected: Value,
+    // the seed to pick which thread runs next with, if the program's threads
+    // shouldn't run in the order they became ready
+    pub schedule: Option<u64>,
+}
+
+pub enum Outcome {
+    Passed,
+    // the program finished, but produced the wrong value
+    Failed { expected: Value, actual: Value },
+    // the program hit a runtime error, ran out of input or exceeded a limit
+    Crashed(String),
+}
+
+pub struct Report {
+    pub warnings: Vec<String>,
+    // one outcome per test case, in order
+    pub outcomes: Vec<Outcome>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome, Outcome::Passed))
+            .count()
+    }
+
+    pub fn all_passed(&self) -> bool {
+        self.passed() == self.outcomes.len()
+    }
+}
+
+fn run(source: String, cases: Vec<TestCase>, limits: Limits) -> Result<Report, String> {
+    let options = CompilerOptions::default();
+    let (ast, warnings) = frontend::frontend("<source>", source, &options)?;
+    let ast = opt::optimise(ast, &options);
+    let mut outcomes = vec![];
+    for case in cases {
+        let mut interpreter =
+            Interpreter::new(Input::Script(case.input.into()), limits, options.int_width);
+        if let Some(seed) = case.schedule {
+            interpreter.schedule(seed);
+        }
+        // only a case's value is graded, so what the program prints is kept
+        // out of the report
+        interpreter.capture_output();
+        let outcome = match interpreter.run(&None, &ast) {
+            Ok(value) => {
+                let actual = Value::from(&value);
+                if actual == case.expected {
+                    Outcome::Passed
+                } else {
+                    Outcome::Failed {
+                        expected: case.expected,
+                        actual,
+                    }
+                }
+            }
+            Err(err) => Outcome::Crashed(err),
+        };
+        outcomes.push(outcome);
+    }
+    Ok(Report { warnings, outcomes })
+}
+
+// checks a program against some test cases, running it in the interpreter once
+// per case (so that a program that loops or recurses forever fails its case
+// rather than taking down the caller), or fails if the program doesn't compile
+pub fn grade(source: &str, cases: Vec<TestCase>, limits: Limits) -> Result<Report, String> {
+    let source = source.to_string();
+    // the interpreter recurses on the host stack, so it gets a thread with as
+    // much room as the compiler's own main thread
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || run(source, cases, limits))
+        .unwrap()
+        .join()
+        .unwrap()
+}
@@ -0,0 +1,1002 @@
+use super::frontend::ast::{self, BinOp, Expr, UnOp};
+use super::frontend::{log, Location};
+use super::{reflect, IntWidth, STACK_SIZE};
+
+mod reference;
+mod snapshot;
+mod threads;
+mod value;
+
+pub use self::reference::{Debugger, Reference};
+pub use self::snapshot::{restore, save, Snapshot};
+use self::threads::{Interrupt, Scheduler};
+pub use self::threads::{Schedule, MAIN};
+pub use self::value::Value;
+use self::value::{Channel, Closure, Elements, Thunk};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::os::raw::{c_int, c_void};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// environments are shared between closures, so they're persistent lists
+pub type Env<'a> = Option<Rc<Binding<'a>>>;
+
+pub struct Binding<'a> {
+    name: &'a str,
+    value: Value<'a>,
+    next: Env<'a>,
+}
+
+pub fn bind<'a>(env: &Env<'a>, name: &'a str, value: Value<'a>) -> Env<'a> {
+    Some(Rc::new(Binding {
+        name,
+        value,
+        next: env.clone(),
+    }))
+}
+
+// binds each of the functions defined together in a 'let rec' to a closure
+// over the environment the group was defined in
+fn bind_group<'a>(env: &Env<'a>, captured: &Env<'a>, group: &'a Expr) -> Env<'a> {
+    let functions = match *group {
+        Expr::LetRec(ref functions, _) => functions,
+        _ => unreachable!(),
+    };
+    let mut env = env.clone();
+    for (f, (v, lambda)) in functions.iter() {
+        let function = Value::Fun(Rc::new(Closure {
+            name: Some(f),
+            param: v,
+            body: lambda,
+            env: captured.clone(),
+            group: Some(group),
+        }));
+        env = bind(&env, f, function);
+    }
+    env
+}
+
+fn lookup<'a>(env: &Env<'a>, name: &str) -> Value<'a> {
+    let mut env = env;
+    while let Some(binding) = env {
+        if binding.name == name {
+            return binding.value.clone();
+        }
+        env = &binding.next;
+    }
+    // the program has already been checked, so every variable is bound
+    unreachable!()
+}
+
+// where the values for '?' come from
+#[derive(Clone)]
+pub enum Input {
+    Stdin,
+    Script(VecDeque<i64>),
+    // numbers from 0 to 99 drawn from a generator with this state
+    Random(u64),
+}
+
+// the next number from a splitmix64 generator
+fn splitmix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// the next number for a '?', which has to match 'random' in the runtime so that
+// compiled programs see the same numbers for a seed
+fn random(state: &mut u64) -> i64 {
+    (splitmix(state) % 100) as i64
+}
+
+fn peek(input: &mut impl BufRead) -> Option<u8> {
+    input.fill_buf().ok().and_then(|buf| buf.first().copied())
+}
+
+// reads an integer as the runtime's 'scanf("%ld")' does: skipping whitespace
+// (blank lines included), then taking an optional sign and the digits after it
+// (leaving anything else on the line for the next '?'), or if there's no number
+// there, giving 0 and skipping the rest of the line
+fn scan(input: &mut impl BufRead) -> Result<i64, String> {
+    while matches!(peek(input), Some(c) if c.is_ascii_whitespace()) {
+        input.consume(1);
+    }
+    let negative = match peek(input) {
+        None => return Err("stdin died :(".to_string()),
+        Some(c @ (b'-' | b'+')) => {
+            input.consume(1);
+            c == b'-'
+        }
+        Some(_) => false,
+    };
+    let mut got = None;
+    while let Some(c) = peek(input).filter(u8::is_ascii_digit) {
+        input.consume(1);
+        let digit = (c - b'0') as i64;
+        // out of range numbers saturate, as they do in 'strtol'
+        let n: i64 = got.unwrap_or(0);
+        got = Some(if negative {
+            n.saturating_mul(10).saturating_sub(digit)
+        } else {
+            n.saturating_mul(10).saturating_add(digit)
+        });
+    }
+    match got {
+        Some(i) => Ok(i),
+        None => {
+            input.read_until(b'\n', &mut vec![]).ok();
+            Ok(0)
+        }
+    }
+}
+
+impl Input {
+    // reads an integer for a '?' (in the same way as the runtime's 'what')
+    pub fn read(&mut self, width: IntWidth) -> Result<i64, String> {
+        match *self {
+            Input::Stdin => {
+                print!("> ");
+                io::stdout().flush().unwrap();
+                scan(&mut io::stdin().lock()).map(|i| width.wrap(i))
+            }
+            Input::Script(ref mut script) => match script.pop_front() {
+                Some(i) => Ok(width.wrap(i)),
+                None => Err("ran out of input".to_string()),
+            },
+            Input::Random(ref mut state) => Ok(random(state)),
+        }
+    }
+}
+
+// where what a program prints goes
+#[derive(Clone)]
+pub enum Output {
+    Stdout,
+    // kept to be compared with what something else printed, rather than
+    // printed as it's written
+    Captured(String),
+}
+
+impl Output {
+    pub fn write(&mut self, string: &str) {
+        match *self {
+            Output::Stdout => {
+                print!("{}", string);
+                io::stdout().flush().unwrap();
+            }
+            Output::Captured(ref mut captured) => captured.push_str(string),
+        }
+    }
+
+    // what's been captured so far, which is then forgotten
+    pub fn take(&mut self) -> String {
+        match *self {
+            Output::Stdout => String::new(),
+            Output::Captured(ref mut captured) => std::mem::take(captured),
+        }
+    }
+}
+
+// the order strings are compared in (by their bytes), as -1, 0 or 1
+pub fn compare(left: &str, right: &str) -> i64 {
+    left.cmp(right) as i64
+}
+
+// bounds on how long a program can run for, and how deeply the interpreter
+// can recurse while running it ('None' meaning no bound, although the
+// interpreter never recurses deeper than the stack it runs on has room for)
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    pub steps: Option<usize>,
+    pub depth: Option<usize>,
+}
+
+// what a call made from the top level of a program cost (counting the steps
+// the interpreter took and the values it allocated that compiled code would put
+// on the heap)
+#[derive(Clone, Debug)]
+pub struct Call {
+    pub function: String,
+    pub arg: reflect::Value,
+    pub steps: usize,
+    pub allocations: usize,
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {} steps, {} allocations",
+            self.function, self.arg, self.steps, self.allocations
+        )
+    }
+}
+
+fn int(value: Value) -> i64 {
+    match value {
+        Value::Int(i) => i,
+        _ => unreachable!(),
+    }
+}
+
+fn float(value: Value) -> f64 {
+    match value {
+        Value::Float(f) => f,
+        _ => unreachable!(),
+    }
+}
+
+fn string(value: Value) -> Rc<str> {
+    match value {
+        Value::Str(string) => string,
+        _ => unreachable!(),
+    }
+}
+
+fn array(value: Value) -> Elements {
+    match value {
+        Value::Array(elements) => elements,
+        _ => unreachable!(),
+    }
+}
+
+fn boolean(value: Value) -> bool {
+    match value {
+        Value::Bool(b) => b,
+        _ => unreachable!(),
+    }
+}
+
+// how much of the stack the interpreter runs on is kept back from recursion,
+// for whatever it does at the deepest level that isn't a level of its own
+// (comparing or printing values, say), so that a program that recurses too
+// deeply fails rather than overflowing it
+const STACK_RESERVE: usize = 1024 * 1024;
+
+extern "C" {
+    fn pthread_self() -> usize;
+    fn pthread_getattr_np(thread: usize, attributes: *mut ThreadAttributes) -> c_int;
+    fn pthread_attr_getstack(
+        attributes: *const ThreadAttributes,
+        low: *mut *mut c_void,
+        size: *mut usize,
+    ) -> c_int;
+    fn pthread_attr_destroy(attributes: *mut ThreadAttributes) -> c_int;
+}
+
+// room for a 'pthread_attr_t', which is only ever passed to pthreads
+#[repr(C, align(8))]
+struct ThreadAttributes([u8; 64]);
+
+// the lowest address the current thread's stack can grow down to (as the
+// runtime finds it for its crash reporter), if it can be found
+fn stack_limit() -> Option<usize> {
+    let mut attributes = ThreadAttributes([0; 64]);
+    let mut low = std::ptr::null_mut();
+    let mut size = 0;
+    unsafe {
+        if pthread_getattr_np(pthread_self(), &mut attributes) != 0 {
+            return None;
+        }
+        let found = pthread_attr_getstack(&attributes, &mut low, &mut size) == 0;
+        pthread_attr_destroy(&mut attributes);
+        found.then_some(low as usize)
+    }
+}
+
+// roughly how far down the current thread's stack has grown
+fn stack_pointer() -> usize {
+    let here = 0u8;
+    std::hint::black_box(&here) as *const u8 as usize
+}
+
+// what's left of an expression once the interpreter has taken a step of it:
+// its value, or what it's in tail position, which gives its value in its
+// place (and is carried on with in a loop, rather than by recursing, so that
+// a function that calls itself in tail position loops in constant stack)
+enum Next<'a> {
+    Value(Value<'a>),
+    Eval(Env<'a>, &'a Expr),
+    // the body of a function that's been applied, in the environment it runs
+    // in
+    Call(Env<'a>, &'a Expr),
+}
+
+// the body of a function and the environment it runs in when it's applied to
+// an argument
+fn call<'a>(function: Value<'a>, arg: Value<'a>) -> (Env<'a>, &'a Expr) {
+    let closure = match function {
+        Value::Fun(closure) => closure,
+        _ => unreachable!(),
+    };
+    let mut env = closure.env.clone();
+    if let Some(group) = closure.group {
+        env = bind_group(&env, &closure.env, group);
+    }
+    if let Some(name) = closure.name {
+        env = bind(&env, name, Value::Fun(closure.clone()));
+    }
+    (bind(&env, closure.param, arg), closure.body)
+}
+
+// slang values can only be passed to another host thread because only one
+// thread ever runs at a time, and 'run' waits for every thread to finish before
+// anything they borrow can go away
+struct Unshared<T>(T);
+
+unsafe impl<T> Send for Unshared<T> {}
+
+// each thread a program spawns gets an interpreter of its own (sharing the
+// input and the scheduler), so step and recursion limits apply to each thread
+// separately
+pub struct Interpreter {
+    input: Arc<Mutex<Input>>,
+    output: Arc<Mutex<Output>>,
+    limits: Limits,
+    // arithmetic wraps around at this many bits, as it does in compiled code
+    width: IntWidth,
+    steps: usize,
+    depth: usize,
+    // the lowest the stack of the thread it's running on may grow to (see
+    // 'STACK_RESERVE'), which is found when it starts running
+    lowest: usize,
+    allocations: usize,
+    // how many calls deep the interpreter is
+    nested: usize,
+    // the calls made from the top level so far, if they're being recorded
+    calls: Option<Vec<Call>>,
+    thread: usize,
+    scheduler: Arc<Scheduler>,
+    // the exception being raised, while the error it's been turned into is
+    // passed back to whatever handles it
+    raised: Option<i64>,
+}
+
+impl Interpreter {
+    pub fn new(input: Input, limits: Limits, width: IntWidth) -> Interpreter {
+        Interpreter {
+            input: Arc::new(Mutex::new(input)),
+            output: Arc::new(Mutex::new(Output::Stdout)),
+            limits,
+            width,
+            steps: 0,
+            depth: 0,
+            lowest: 0,
+            allocations: 0,
+            nested: 0,
+            calls: None,
+            thread: MAIN,
+            scheduler: Arc::new(Scheduler::new()),
+            raised: None,
+        }
+    }
+
+    // runs a program (or anything else in the scope of 'env') to completion,
+    // after which any threads it spawned are stopped
+    pub fn run<'a>(&mut self, env: &Env<'a>, expr: &'a Expr) -> Result<Value<'a>, String> {
+        self.find_stack();
+        let value = self.eval(env, expr);
+        self.scheduler.stop();
+        value
+    }
+
+    // starts interpreting 'expr' in a new thread, which waits for its turn
+    fn spawn<'a>(&mut self, env: &Env<'a>, expr: &'a Expr) {
+        let thread = self.scheduler.number();
+        let interpreter = Interpreter {
+            input: self.input.clone(),
+            output: self.output.clone(),
+            limits: self.limits,
+            width: self.width,
+            steps: 0,
+            depth: 0,
+            lowest: 0,
+            allocations: 0,
+            // so that its calls aren't mistaken for calls from the top level
+            nested: 1,
+            calls: None,
+            thread,
+            scheduler: self.scheduler.clone(),
+            raised: None,
+        };
+        let task = Unshared((interpreter, env.clone(), expr));
+        let body: Box<dyn FnOnce() + Send + 'a> = Box::new(move || {
+            let Unshared((mut interpreter, env, expr)) = task;
+            if interpreter.scheduler.wait_turn(thread).is_err() {
+                return;
+            }
+            interpreter.find_stack();
+            let error = interpreter.eval(&env, expr).err();
+            drop(env);
+            interpreter.scheduler.finish(error);
+        });
+        // the thread is joined by 'run' before anything it borrows goes away
+        let body: Box<dyn FnOnce() + Send + 'static> = unsafe { std::mem::transmute(body) };
+        let handle = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(body)
+            .unwrap();
+        self.scheduler.add(thread, handle);
+    }
+
+    // waits for another thread to take what this one is sending, or to send
+    // what it's waiting to receive
+    fn wait(&mut self, location: &Location) -> Result<(), String> {
+        match self.scheduler.wait(self.thread) {
+            Ok(()) => Ok(()),
+            Err(Interrupt::Deadlock) => Err(log::runtime_error(
+                location,
+                "deadlock: every thread is waiting on a channel",
+            )),
+            Err(Interrupt::Failed(error)) => Err(error),
+            // nobody sees this, as the program is already over
+            Err(Interrupt::Stopped) => Err(String::new()),
+        }
+    }
+
+    // picks the thread that takes over from one that finishes or has to wait
+    // with a generator seeded with 'seed', rather than taking the one that's
+    // been ready the longest, so that programs can be tried in other orders
+    pub fn schedule(&mut self, seed: u64) {
+        self.scheduler.set_schedule(Schedule::Seeded(seed));
+    }
+
+    // keeps what the program prints instead of printing it, so that it can be
+    // compared with what it should have printed
+    pub fn capture_output(&mut self) {
+        *self.output.lock().unwrap() = Output::Captured(String::new());
+    }
+
+    pub fn output(&mut self) -> String {
+        self.output.lock().unwrap().take()
+    }
+
+    // starts recording what each call made from the top level costs
+    pub fn record_calls(&mut self) {
+        self.calls = Some(vec![]);
+    }
+
+    pub fn calls(&mut self) -> Vec<Call> {
+        self.calls.take().unwrap_or_default()
+    }
+
+    fn allocate(&mut self) {
+        self.allocations += 1;
+    }
+
+    // bounds recursion by the stack of the thread the interpreter is about to
+    // run on
+    fn find_stack(&mut self) {
+        self.lowest = stack_limit().map_or(0, |limit| limit + STACK_RESERVE);
+    }
+
+    fn apply<'a>(&mut self, function: Value<'a>, arg: Value<'a>) -> Result<Value<'a>, String> {
+        let (env, body) = call(function, arg);
+        self.nested += 1;
+        let value = self.eval(&env, body);
+        self.nested -= 1;
+        value
+    }
+
+    // applies a function called from the top level, recording what it cost
+    // (calls are named after the variable they're made through, if any)
+    fn record<'a>(
+        &mut self,
+        expr: &Expr,
+        function: Value<'a>,
+        arg: Value<'a>,
+    ) -> Result<Value<'a>, String> {
+        let name = match (expr, &function) {
+            (Expr::Var(v), _) => v.to_string(),
+            (_, Value::Fun(closure)) => closure.name.unwrap_or("fun").to_string(),
+            _ => unreachable!(),
+        };
+        let call_arg = (&arg).into();
+        let (steps, allocations) = (self.steps, self.allocations);
+        let value = self.apply(function, arg)?;
+        if let Some(ref mut calls) = self.calls {
+            calls.push(Call {
+                function: name,
+                arg: call_arg,
+                steps: self.steps - steps,
+                allocations: self.allocations - allocations,
+            });
+        }
+        Ok(value)
+    }
+
+    // counts a step, failing once there have been more than the limit allows
+    fn tick(&mut self) -> Result<(), String> {
+        self.steps += 1;
+        if matches!(self.limits.steps, Some(steps) if self.steps > steps) {
+            return Err(format!(
+                "step limit exceeded (the limit is {})",
+                self.steps - 1
+            ));
+        }
+        Ok(())
+    }
+
+    fn eval<'a>(&mut self, env: &Env<'a>, expr: &'a Expr) -> Result<Value<'a>, String> {
+        self.tick()?;
+        self.depth += 1;
+        if matches!(self.limits.depth, Some(depth) if self.depth > depth) {
+            return Err(format!(
+                "recursion limit exceeded (the limit is {})",
+                self.depth - 1
+            ));
+        }
+        if stack_pointer() < self.lowest {
+            return Err(format!(
+                "recursion limit exceeded (out of stack {} levels deep)",
+                self.depth - 1
+            ));
+        }
+        let nested = self.nested;
+        let mut next = self.step(env, expr);
+        let value = loop {
+            let (env, expr) = match next {
+                Ok(Next::Value(value)) => break Ok(value),
+                Ok(Next::Eval(env, expr)) => (env, expr),
+                Ok(Next::Call(env, body)) => {
+                    self.nested = nested + 1;
+                    (env, body)
+                }
+                Err(error) => break Err(error),
+            };
+            next = self.tick().and_then(|()| self.step(&env, expr));
+        };
+        self.nested = nested;
+        self.depth -= 1;
+        value
+    }
+
+    // applies an operator, given what its left operand gave
+    fn operate<'a>(
+        &mut self,
+        env: &Env<'a>,
+        expr: &'a Expr,
+        left: Value<'a>,
+    ) -> Result<Value<'a>, String> {
+        use self::Expr::*;
+        let right = match *expr {
+            // the right operand of '&&' and '||' is only run if the left one
+            // doesn't already say what they give
+            BinOp(self::BinOp::And, _, _) if matches!(left, Value::Bool(false)) => return Ok(left),
+            BinOp(self::BinOp::Or, _, _) if matches!(left, Value::Bool(true)) => return Ok(left),
+            BinOp(_, _, ref right) | Div(_, _, ref right) | Mod(_, _, ref right) => right,
+            _ => unreachable!(),
+        };
+        let right = self.eval(env, right)?;
+        self.binop(expr, left, right)
+    }
+
+    // what an operator gives, given what its operands gave (which is worked out
+    // in a function of its own, so that its frame isn't on the stack while the
+    // right operand is run)
+    #[inline(never)]
+    fn binop<'a>(
+        &mut self,
+        expr: &'a Expr,
+        left: Value<'a>,
+        right: Value<'a>,
+    ) -> Result<Value<'a>, String> {
+        use self::Expr::*;
+        Ok(match *expr {
+            BinOp(self::BinOp::And, _, _) | BinOp(self::BinOp::Or, _, _) => right,
+            BinOp(self::BinOp::Eq, _, _) | BinOp(self::BinOp::PhysEq, _, _) => {
+                Value::Bool(left.same(&right))
+            }
+            BinOp(self::BinOp::Concat, _, _) => {
+                let (left, right) = (string(left), string(right));
+                self.allocate();
+                Value::Str(Rc::from(format!("{}{}", left, right)))
+            }
+            BinOp(self::BinOp::Compare, _, _) => Value::Int(compare(&string(left), &string(right))),
+            BinOp(
+                op @ (self::BinOp::FloatAdd
+                | self::BinOp::FloatSub
+                | self::BinOp::FloatMul
+                | self::BinOp::FloatDiv
+                | self::BinOp::FloatLt
+                | self::BinOp::FloatEq),
+                _,
+                _,
+            ) => {
+                let (left, right) = (float(left), float(right));
+                match op {
+                    self::BinOp::FloatAdd => Value::Float(left + right),
+                    self::BinOp::FloatSub => Value::Float(left - right),
+                    self::BinOp::FloatMul => Value::Float(left * right),
+                    self::BinOp::FloatDiv => Value::Float(left / right),
+                    self::BinOp::FloatLt => Value::Bool(left < right),
+                    _ => Value::Bool(left == right),
+                }
+            }
+            BinOp(op, _, _) => {
+                let (left, right) = (int(left), int(right));
+                match op {
+                    self::BinOp::Add => Value::Int(self.width.wrap(left.wrapping_add(right))),
+                    self::BinOp::Sub => Value::Int(self.width.wrap(left.wrapping_sub(right))),
+                    self::BinOp::Mul => Value::Int(self.width.wrap(left.wrapping_mul(right))),
+                    self::BinOp::Lt => Value::Bool(left < right),
+                    _ => unreachable!(),
+                }
+            }
+            Div(ref location, _, _) => match int(right) {
+                0 => return Err(log::runtime_error(location, "division by zero")),
+                right => match int(left).checked_div(right) {
+                    Some(i) if self.width.wrap(i) == i => Value::Int(i),
+                    _ => return Err(log::runtime_error(location, "overflow in division")),
+                },
+            },
+            Mod(ref location, _, _) => match int(right) {
+                0 => return Err(log::runtime_error(location, "division by zero")),
+                right => Value::Int(int(left).wrapping_rem(right)),
+            },
+            _ => unreachable!(),
+        })
+    }
+
+    // brings what a 'let' defines into scope
+    fn define<'a>(&mut self, env: &Env<'a>, expr: &'a Expr) -> Result<Env<'a>, String> {
+        use self::Expr::*;
+        Ok(match *expr {
+            Let(ref v, ref sub, _) => {
+                let value = self.eval(env, sub)?;
+                bind(env, v, value)
+            }
+            LetFun(ref f, (ref v, ref lambda), _) => {
+                self.allocate();
+                let function = Value::Fun(Rc::new(Closure {
+                    name: Some(f),
+                    param: v,
+                    body: lambda,
+                    env: env.clone(),
+                    group: None,
+                }));
+                bind(env, f, function)
+            }
+            LetRec(ref functions, _) => {
+                for _ in functions.iter() {
+                    self.allocate();
+                }
+                bind_group(env, env, expr)
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    #[inline(never)]
+    fn effect<'a>(&mut self, env: &Env<'a>, expr: &'a Expr) -> Result<Value<'a>, String> {
+        use self::Expr::*;
+        Ok(match *expr {
+            What => Value::Int(self.input.lock().unwrap().read(self.width)?),
+            Str(ref string) => {
+                self.allocate();
+                Value::Str(Rc::from(string.as_str()))
+            }
+            While(ref condition, ref body) => {
+                while boolean(self.eval(env, condition)?) {
+                    self.eval(env, body)?;
+                }
+                Value::Unit
+            }
+            // the bounds are only worked out once, before the first iteration
+            For(ref from, ref to, (ref v, ref body)) => {
+                let from = int(self.eval(env, from)?);
+                let to = int(self.eval(env, to)?);
+                for i in from..=to {
+                    self.eval(&bind(env, v, Value::Int(i)), body)?;
+                }
+                Value::Unit
+            }
+            Force(ref location, ref sub) => match self.eval(env, sub)? {
+                Value::Lazy(thunk) => {
+                    let state = std::mem::replace(&mut *thunk.borrow_mut(), Thunk::Forcing);
+                    let value = match state {
+                        Thunk::Delayed(env, sub) => self.eval(&env, sub)?,
+                        Thunk::Forcing => {
+                            return Err(log::runtime_error(
+                                location,
+                                "lazy value forced while it was being computed",
+                            ))
+                        }
+                        Thunk::Forced(value) => value,
+                    };
+                    *thunk.borrow_mut() = Thunk::Forced(value.clone());
+                    value
+                }
+                _ => unreachable!(),
+            },
+            Chan => {
+                self.allocate();
+                Value::Chan(Rc::new(Channel::default()))
+            }
+            Spawn(ref sub) => {
+                self.allocate();
+                self.spawn(env, sub);
+                Value::Unit
+            }
+            Raise(ref location, ref sub) => {
+                let exception = int(self.eval(env, sub)?);
+                self.raised = Some(exception);
+                return Err(log::runtime_error(
+                    location,
+                    &format!("uncaught exception {}", exception),
+                ));
+            }
+            Try(ref sub, (ref v, ref handler)) => match self.eval(env, sub) {
+                Ok(value) => value,
+                // any other error (like running out of steps) can't be handled
+                Err(error) => match self.raised.take() {
+                    Some(exception) => self.eval(&bind(env, v, Value::Int(exception)), handler)?,
+                    None => return Err(error),
+                },
+            },
+            Send(ref location, ref left, ref right) => match self.eval(env, left)? {
+                Value::Chan(channel) => {
+                    let value = self.eval(env, right)?;
+                    let receiver = channel.receivers.borrow_mut().pop_front();
+                    match receiver {
+                        Some((thread, slot)) => {
+                            *slot.borrow_mut() = Some(value);
+                            self.scheduler.wake(thread);
+                        }
+                        None => {
+                            channel.senders.borrow_mut().push_back((self.thread, value));
+                            if let Err(error) = self.wait(location) {
+                                let thread = self.thread;
+                                channel.senders.borrow_mut().retain(|(t, _)| *t != thread);
+                                return Err(error);
+                            }
+                        }
+                    }
+                    Value::Unit
+                }
+                _ => unreachable!(),
+            },
+            Recv(ref location, ref sub) => match self.eval(env, sub)? {
+                Value::Chan(channel) => {
+                    let sender = channel.senders.borrow_mut().pop_front();
+                    match sender {
+                        Some((thread, value)) => {
+                            self.scheduler.wake(thread);
+                            value
+                        }
+                        None => {
+                            let slot = Rc::new(RefCell::new(None));
+                            channel
+                                .receivers
+                                .borrow_mut()
+                                .push_back((self.thread, slot.clone()));
+                            if let Err(error) = self.wait(location) {
+                                let thread = self.thread;
+                                channel.receivers.borrow_mut().retain(|(t, _)| *t != thread);
+                                return Err(error);
+                            }
+                            let value = slot.borrow_mut().take().unwrap();
+                            value
+                        }
+                    }
+                }
+                _ => unreachable!(),
+            },
+            Print(ref sub) => {
+                let printed = string(self.eval(env, sub)?);
+                self.output.lock().unwrap().write(&printed);
+                Value::Unit
+            }
+            Assign(ref left, ref right) => match self.eval(env, left)? {
+                Value::Ref(value) => {
+                    *value.borrow_mut() = self.eval(env, right)?;
+                    Value::Unit
+                }
+                _ => unreachable!(),
+            },
+            MakeArray(ref location, ref length, ref sub) => {
+                let length = int(self.eval(env, length)?);
+                let value = self.eval(env, sub)?;
+                if length < 0 {
+                    return Err(log::runtime_error(location, "negative array length"));
+                }
+                self.allocate();
+                Value::Array(Rc::new(RefCell::new(vec![value; length as usize])))
+            }
+            Index(ref location, ref left, ref right) => {
+                let elements = array(self.eval(env, left)?);
+                let i = int(self.eval(env, right)?);
+                let element = usize::try_from(i)
+                    .ok()
+                    .and_then(|i| elements.borrow().get(i).cloned());
+                match element {
+                    Some(value) => value,
+                    None => return Err(log::runtime_error(location, "array index out of bounds")),
+                }
+            }
+            Update(ref location, ref left, ref index, ref right) => {
+                let elements = array(self.eval(env, left)?);
+                let i = int(self.eval(env, index)?);
+                let value = self.eval(env, right)?;
+                match usize::try_from(i)
+                    .ok()
+                    .and_then(|i| elements.borrow_mut().get_mut(i).map(|slot| *slot = value))
+                {
+                    Some(()) => Value::Unit,
+                    None => return Err(log::runtime_error(location, "array index out of bounds")),
+                }
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    #[inline(never)]
+    fn value<'a>(&mut self, env: &Env<'a>, expr: &'a Expr) -> Result<Value<'a>, String> {
+        use self::Expr::*;
+        Ok(match *expr {
+            UnOp(self::UnOp::Neg, ref sub) => {
+                Value::Int(self.width.wrap(int(self.eval(env, sub)?).wrapping_neg()))
+            }
+            UnOp(self::UnOp::Not, ref sub) => Value::Bool(!boolean(self.eval(env, sub)?)),
+            Pair(ref left, ref right) => {
+                let left = self.eval(env, left)?;
+                let right = self.eval(env, right)?;
+                self.allocate();
+                Value::Pair(Rc::new((left, right)))
+            }
+            Fst(ref sub) => match self.eval(env, sub)? {
+                Value::Pair(pair) => pair.0.clone(),
+                _ => unreachable!(),
+            },
+            Snd(ref sub) => match self.eval(env, sub)? {
+                Value::Pair(pair) => pair.1.clone(),
+                _ => unreachable!(),
+            },
+            Inl(ref sub, ref type_expr) => {
+                let value = self.eval(env, sub)?;
+                self.allocate();
+                Value::Inl(Rc::new(value), type_expr)
+            }
+            Inr(ref sub, ref type_expr) => {
+                let value = self.eval(env, sub)?;
+                self.allocate();
+                Value::Inr(Rc::new(value), type_expr)
+            }
+            Ref(ref sub) => {
+                let value = self.eval(env, sub)?;
+                self.allocate();
+                Value::Ref(Rc::new(RefCell::new(value)))
+            }
+            Deref(ref sub) => match self.eval(env, sub)? {
+                Value::Ref(value) => value.borrow().clone(),
+                _ => unreachable!(),
+            },
+            Lazy(ref sub) => {
+                self.allocate();
+                Value::Lazy(Rc::new(RefCell::new(Thunk::Delayed(env.clone(), sub))))
+            }
+            Nil => Value::Nil,
+            Cons(ref left, ref right) => {
+                let left = self.eval(env, left)?;
+                let right = self.eval(env, right)?;
+                self.allocate();
+                Value::Cons(Rc::new((left, right)))
+            }
+            Head(ref location, ref sub) => match self.eval(env, sub)? {
+                Value::Cons(cell) => cell.0.clone(),
+                _ => return Err(log::runtime_error(location, "head of empty list")),
+            },
+            Tail(ref location, ref sub) => match self.eval(env, sub)? {
+                Value::Cons(cell) => cell.1.clone(),
+                _ => return Err(log::runtime_error(location, "tail of empty list")),
+            },
+            IsEmpty(ref sub) => Value::Bool(matches!(self.eval(env, sub)?, Value::Nil)),
+            Lambda((ref v, ref body)) => {
+                self.allocate();
+                Value::Fun(Rc::new(Closure {
+                    name: None,
+                    param: v,
+                    body,
+                    env: env.clone(),
+                    group: None,
+                }))
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    fn step<'a>(&mut self, env: &Env<'a>, expr: &'a Expr) -> Result<Next<'a>, String> {
+        use self::Expr::*;
+        Ok(Next::Value(match *expr {
+            Unit => Value::Unit,
+            Var(ref v) => lookup(env, v),
+            Int(i) => Value::Int(i),
+            Float(f) => Value::Float(f),
+            Bool(b) => Value::Bool(b),
+            BinOp(..) | Div(..) | Mod(..) => {
+                // a chain ('a + b + c') is run from its leftmost operand on, in
+                // a loop, as it can be too long to recurse down (though each
+                // operator in it is still a step of its own)
+                let (first, operators) = ast::chain(expr);
+                for _ in 1..operators.len() {
+                    self.tick()?;
+                }
+                let mut value = self.eval(env, first)?;
+                for operator in operators {
+                    value = self.operate(env, operator, value)?;
+                }
+                value
+            }
+            If(ref condition, ref left, ref right) => {
+                let branch = if boolean(self.eval(env, condition)?) {
+                    left
+                } else {
+                    right
+                };
+                return Ok(Next::Eval(env.clone(), branch));
+            }
+            Case(ref sub, (ref x, ref left), (ref y, ref right)) => {
+                return Ok(match self.eval(env, sub)? {
+                    Value::Inl(value, _) => Next::Eval(bind(env, x, (*value).clone()), left),
+                    Value::Inr(value, _) => Next::Eval(bind(env, y, (*value).clone()), right),
+                    _ => unreachable!(),
+                })
+            }
+            // what makes a value out of others is taken a step in a function
+            // of its own, as is anything with effects (other than allocating)
+            // or loops, so that neither of their frames is on the stack while
+            // the interpreter recurses through anything else
+            UnOp(..) | Pair(..) | Fst(_) | Snd(_) | Inl(..) | Inr(..) | Ref(_) | Deref(_)
+            | Lazy(_) | Nil | Cons(..) | Head(..) | Tail(..) | IsEmpty(_) | Lambda(_) => {
+                self.value(env, expr)?
+            }
+            What | Str(_) | While(..) | For(..) | Force(..) | Chan | Spawn(_) | Raise(..)
+            | Try(..) | Send(..) | Recv(..) | Print(_) | Assign(..) | MakeArray(..) | Index(..)
+            | Update(..) => self.effect(env, expr)?,
+            Seq(ref seq) => match seq.split_last() {
+                Some((last, seq)) => {
+                    for sub in seq.iter() {
+                        self.eval(env, sub)?;
+                    }
+                    return Ok(Next::Eval(env.clone(), last));
+                }
+                None => Value::Unit,
+            },
+            // only the compiler takes any notice of which way a condition is
+            // expected to go, so this isn't a step of its own
+            Expect(ref sub, _) | Attributed(_, ref sub) => return self.step(env, sub),
+            App(ref left, ref right) => {
+                let function = self.eval(env, left)?;
+                let arg = self.eval(env, right)?;
+                if self.nested == 0 && self.calls.is_some() {
+                    self.record(left, function, arg)?
+                } else {
+                    let (env, body) = call(function, arg);
+                    return Ok(Next::Call(env, body));
+                }
+            }
+            Let(..) | LetFun(..) | LetRec(..) => {
+                // so is a chain of definitions ('let x = e in let y = e in
+                // ...'), as a program of top-level definitions is one
+                let (definitions, body) = ast::definitions(expr);
+                for _ in 1..definitions.len() {
+                    self.tick()?;
+                }
+                let mut env = env.clone();
+                for definition in definitions {
+                    env = self.define(&env, definition)?;
+                }
+                return Ok(Next::Eval(env, body));
+            }
+        }))
+    }
+}
@@ -0,0 +1,1404 @@
+use super::super::frontend::ast::{self, BinOp, Expr, UnOp};
+use super::super::frontend::{float_literal, log, quote, Location, TypeExpr};
+use super::super::{reflect, IntWidth};
+use super::threads::{Schedule, MAIN};
+use super::{compare, Input, Output};
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+
+// programs as the reference evaluator sees them: expressions are rewritten one
+// step at a time (substituting values for variables), so running programs also
+// contain values that can't be written in the source
+#[derive(Clone)]
+enum Term<'a> {
+    Unit,
+    What,
+    Var(&'a str),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(&'a str),
+    UnOp(UnOp, Box<Term<'a>>),
+    BinOp(BinOp, Box<Term<'a>>, Box<Term<'a>>),
+    Div(&'a Location, Box<Term<'a>>, Box<Term<'a>>),
+    Mod(&'a Location, Box<Term<'a>>, Box<Term<'a>>),
+    If(Box<Term<'a>>, Box<Term<'a>>, Box<Term<'a>>),
+    Pair(Box<Term<'a>>, Box<Term<'a>>),
+    Fst(Box<Term<'a>>),
+    Snd(Box<Term<'a>>),
+    Inl(Box<Term<'a>>, &'a TypeExpr),
+    Inr(Box<Term<'a>>, &'a TypeExpr),
+    Case(
+        Box<Term<'a>>,
+        (&'a str, Box<Term<'a>>),
+        (&'a str, Box<Term<'a>>),
+    ),
+    While(Box<Term<'a>>, Box<Term<'a>>),
+    For(Box<Term<'a>>, Box<Term<'a>>, (&'a str, Box<Term<'a>>)),
+    Seq(Vec<Term<'a>>),
+    Ref(Box<Term<'a>>),
+    Deref(Box<Term<'a>>),
+    Lazy(Box<Term<'a>>),
+    Force(&'a Location, Box<Term<'a>>),
+    Chan,
+    Spawn(Box<Term<'a>>),
+    Raise(&'a Location, Box<Term<'a>>),
+    Try(Box<Term<'a>>, (&'a str, Box<Term<'a>>)),
+    Send(&'a Location, Box<Term<'a>>, Box<Term<'a>>),
+    Recv(&'a Location, Box<Term<'a>>),
+    Assign(Box<Term<'a>>, Box<Term<'a>>),
+    MakeArray(&'a Location, Box<Term<'a>>, Box<Term<'a>>),
+    Index(&'a Location, Box<Term<'a>>, Box<Term<'a>>),
+    Update(&'a Location, Box<Term<'a>>, Box<Term<'a>>, Box<Term<'a>>),
+    Nil,
+    Cons(Box<Term<'a>>, Box<Term<'a>>),
+    Head(&'a Location, Box<Term<'a>>),
+    Tail(&'a Location, Box<Term<'a>>),
+    IsEmpty(Box<Term<'a>>),
+    Lambda(&'a str, Box<Term<'a>>),
+    App(Box<Term<'a>>, Box<Term<'a>>),
+    Let(&'a str, Box<Term<'a>>, Box<Term<'a>>),
+    LetFun(&'a str, &'a str, Box<Term<'a>>, Box<Term<'a>>),
+    LetRec(Vec<(&'a str, &'a str, Box<Term<'a>>)>, Box<Term<'a>>),
+    Print(Box<Term<'a>>),
+    // a location in the store
+    Loc(usize),
+    // an array, by the location in the store of its elements
+    Array(usize),
+    // what's in the store for an array
+    Elements(Vec<Term<'a>>),
+    // a lazy value, which is kept in the store until it's been forced (and
+    // then replaced by its value)
+    Thunk(usize),
+    // computes the value of the lazy value in a location, which is forced
+    Memo(usize, Box<Term<'a>>),
+    // what's in the store for a lazy value while it's being computed
+    Forcing,
+    // a channel, by its number
+    Channel(usize),
+    // an exception on its way to the nearest 'try' (which a term that's
+    // waiting on one of its subterms becomes in a single step)
+    Raised(&'a Location, i64),
+    // a thread waiting on a channel at a location, which can't take a step
+    // until another thread has sent or received on it
+    Waiting(&'a Location),
+    // pairs, injections and functions live on the heap in compiled code, and
+    // '==' can tell them apart by their addresses, so as values they carry an
+    // address of their own
+    Tuple(usize, Box<Term<'a>>, Box<Term<'a>>),
+    Left(usize, Box<Term<'a>>, &'a TypeExpr),
+    Right(usize, Box<Term<'a>>, &'a TypeExpr),
+    // a list that isn't empty, which lives on the heap in the same way
+    Cell(usize, Box<Term<'a>>, Box<Term<'a>>),
+    // a function, along with the name it can use to refer to itself (nothing is
+    // captured, as its free variables have already been substituted away)
+    Closure(usize, Option<&'a str>, &'a str, Box<Term<'a>>),
+    // a string, which is on the heap too
+    Text(usize, String),
+}
+
+impl<'a> From<&'a Expr> for Term<'a> {
+    fn from(expr: &'a Expr) -> Term<'a> {
+        let term = |sub: &'a Expr| Box::new(Term::from(sub));
+        match *expr {
+            Expr::Unit => Term::Unit,
+            Expr::What => Term::What,
+            Expr::Var(ref v) => Term::Var(v),
+            Expr::Int(i) => Term::Int(i),
+            Expr::Float(f) => Term::Float(f),
+            Expr::Bool(b) => Term::Bool(b),
+            Expr::Str(ref string) => Term::Str(string),
+            Expr::UnOp(op, ref sub) => Term::UnOp(op, term(sub)),
+            // a chain ('a + b + c') is built up in a loop, as it can be too
+            // long to recurse down
+            Expr::BinOp(..) | Expr::Div(..) | Expr::Mod(..) => {
+                let (first, operators) = ast::chain(expr);
+                let mut chain = Term::from(first);
+                for operator in operators {
+                    let left = Box::new(chain);
+                    chain = match *operator {
+                        Expr::BinOp(op, _, ref right) => Term::BinOp(op, left, term(right)),
+                        Expr::Div(ref location, _, ref right) => {
+                            Term::Div(location, left, term(right))
+                        }
+                        Expr::Mod(ref location, _, ref right) => {
+                            Term::Mod(location, left, term(right))
+                        }
+                        _ => unreachable!(),
+                    };
+                }
+                chain
+            }
+            Expr::If(ref condition, ref left, ref right) => {
+                Term::If(term(condition), term(left), term(right))
+            }
+            Expr::Pair(ref left, ref right) => Term::Pair(term(left), term(right)),
+            Expr::Fst(ref sub) => Term::Fst(term(sub)),
+            Expr::Snd(ref sub) => Term::Snd(term(sub)),
+            Expr::Inl(ref sub, ref type_expr) => Term::Inl(term(sub), type_expr),
+            Expr::Inr(ref sub, ref type_expr) => Term::Inr(term(sub), type_expr),
+            Expr::Case(ref sub, (ref x, ref left), (ref y, ref right)) => {
+                Term::Case(term(sub), (x, term(left)), (y, term(right)))
+            }
+            Expr::While(ref condition, ref body) => Term::While(term(condition), term(body)),
+            Expr::For(ref from, ref to, (ref v, ref body)) => {
+                Term::For(term(from), term(to), (v, term(body)))
+            }
+            Expr::Seq(ref seq) => Term::Seq(seq.iter().map(Term::from).collect()),
+            Expr::Ref(ref sub) => Term::Ref(term(sub)),
+            Expr::Deref(ref sub) => Term::Deref(term(sub)),
+            Expr::Lazy(ref sub) => Term::Lazy(term(sub)),
+            Expr::Force(ref location, ref sub) => Term::Force(location, term(sub)),
+            Expr::Chan => Term::Chan,
+            Expr::Spawn(ref sub) => Term::Spawn(term(sub)),
+            Expr::Raise(ref location, ref sub) => Term::Raise(location, term(sub)),
+            Expr::Try(ref sub, (ref v, ref handler)) => Term::Try(term(sub), (v, term(handler))),
+            Expr::Send(ref location, ref left, ref right) => {
+                Term::Send(location, term(left), term(right))
+            }
+            Expr::Recv(ref location, ref sub) => Term::Recv(location, term(sub)),
+            Expr::Assign(ref left, ref right) => Term::Assign(term(left), term(right)),
+            Expr::MakeArray(ref location, ref length, ref sub) => {
+                Term::MakeArray(location, term(length), term(sub))
+            }
+            Expr::Index(ref location, ref left, ref right) => {
+                Term::Index(location, term(left), term(right))
+            }
+            Expr::Update(ref location, ref left, ref index, ref right) => {
+                Term::Update(location, term(left), term(index), term(right))
+            }
+            Expr::Nil => Term::Nil,
+            Expr::Cons(ref left, ref right) => Term::Cons(term(left), term(right)),
+            Expr::Head(ref location, ref sub) => Term::Head(location, term(sub)),
+            Expr::Tail(ref location, ref sub) => Term::Tail(location, term(sub)),
+            Expr::IsEmpty(ref sub) => Term::IsEmpty(term(sub)),
+            Expr::Lambda((ref v, ref body)) => Term::Lambda(v, term(body)),
+            Expr::App(ref left, ref right) => Term::App(term(left), term(right)),
+            Expr::Let(ref v, ref sub, ref body) => Term::Let(v, term(sub), term(body)),
+            Expr::LetFun(ref f, (ref v, ref lambda), ref body) => {
+                Term::LetFun(f, v, term(lambda), term(body))
+            }
+            Expr::LetRec(ref functions, ref body) => Term::LetRec(
+                functions
+                    .iter()
+                    .map(|(f, (v, lambda))| (&**f, &**v, term(lambda)))
+                    .collect(),
+                term(body),
+            ),
+            Expr::Print(ref sub) => Term::Print(term(sub)),
+            // a hint means nothing to the semantics
+            Expr::Expect(ref sub, _) | Expr::Attributed(_, ref sub) => Term::from(&**sub),
+        }
+    }
+}
+
+impl<'a> Term<'a> {
+    fn is_value(&self) -> bool {
+        use self::Term::*;
+        matches!(
+            *self,
+            Unit | Int(_)
+                | Float(_)
+                | Bool(_)
+                | Loc(_)
+                | Array(_)
+                | Thunk(_)
+                | Channel(_)
+                | Tuple(_, _, _)
+                | Left(_, _, _)
+                | Right(_, _, _)
+                | Nil
+                | Cell(_, _, _)
+                | Closure(_, _, _, _)
+                | Text(_, _)
+        )
+    }
+
+    // the subterms that are evaluated before this term can take a step, in the
+    // order they're evaluated in (everything else waits for a rule to use it)
+    fn strict(&mut self) -> Vec<&mut Term<'a>> {
+        use self::Term::*;
+        match *self {
+            UnOp(_, ref mut sub)
+            | Fst(ref mut sub)
+            | Snd(ref mut sub)
+            | Inl(ref mut sub, _)
+            | Inr(ref mut sub, _)
+            | Ref(ref mut sub)
+            | Deref(ref mut sub)
+            | Force(_, ref mut sub)
+            | Memo(_, ref mut sub)
+            | Recv(_, ref mut sub)
+            | Raise(_, ref mut sub)
+            | Try(ref mut sub, _)
+            | Print(ref mut sub)
+            | Head(_, ref mut sub)
+            | Tail(_, ref mut sub)
+            | IsEmpty(ref mut sub)
+            | If(ref mut sub, _, _)
+            | Case(ref mut sub, _, _)
+            | Let(_, ref mut sub, _)
+            | BinOp(ast::BinOp::And, ref mut sub, _)
+            | BinOp(ast::BinOp::Or, ref mut sub, _) => vec![sub],
+            BinOp(_, ref mut left, ref mut right)
+            | Div(_, ref mut left, ref mut right)
+            | Mod(_, ref mut left, ref mut right)
+            | Pair(ref mut left, ref mut right)
+            | Assign(ref mut left, ref mut right)
+            | Send(_, ref mut left, ref mut right)
+            | MakeArray(_, ref mut left, ref mut right)
+            | Index(_, ref mut left, ref mut right)
+            | Cons(ref mut left, ref mut right)
+            | For(ref mut left, ref mut right, _)
+            | App(ref mut left, ref mut right) => vec![left, right],
+            Update(_, ref mut left, ref mut index, ref mut right) => vec![left, index, right],
+            Seq(ref mut seq) => seq.iter_mut().take(1).collect(),
+            _ => vec![],
+        }
+    }
+
+    // the leftmost operand of a chain of operators, and each operator in it
+    // after that, the innermost first (see 'ast::chain')
+    fn chain(&self) -> (&Term<'a>, Vec<&Term<'a>>) {
+        use self::Term::*;
+        let mut operators = vec![];
+        let mut term = self;
+        while let BinOp(_, ref left, _) | Div(_, ref left, _) | Mod(_, ref left, _) = *term {
+            operators.push(term);
+            term = left;
+        }
+        operators.reverse();
+        (term, operators)
+    }
+
+    // substitutes a (closed) value for a variable
+    fn subst(&self, x: &str, value: &Term<'a>) -> Term<'a> {
+        use self::Term::*;
+        let subst = |sub: &Term<'a>| Box::new(sub.subst(x, value));
+        match *self {
+            Var(v) if v == x => value.clone(),
+            UnOp(op, ref sub) => UnOp(op, subst(sub)),
+            BinOp(..) | Div(..) | Mod(..) => {
+                let (first, operators) = self.chain();
+                let mut chain = first.subst(x, value);
+                for operator in operators {
+                    let left = Box::new(chain);
+                    chain = match *operator {
+                        BinOp(op, _, ref right) => BinOp(op, left, subst(right)),
+                        Div(location, _, ref right) => Div(location, left, subst(right)),
+                        Mod(location, _, ref right) => Mod(location, left, subst(right)),
+                        _ => unreachable!(),
+                    };
+                }
+                chain
+            }
+            If(ref condition, ref left, ref right) => {
+                If(subst(condition), subst(left), subst(right))
+            }
+            Pair(ref left, ref right) => Pair(subst(left), subst(right)),
+            Fst(ref sub) => Fst(subst(sub)),
+            Snd(ref sub) => Snd(subst(sub)),
+            Inl(ref sub, type_expr) => Inl(subst(sub), type_expr),
+            Inr(ref sub, type_expr) => Inr(subst(sub), type_expr),
+            Case(ref sub, (y, ref left), (z, ref right)) => Case(
+                subst(sub),
+                (y, if y == x { left.clone() } else { subst(left) }),
+                (z, if z == x { right.clone() } else { subst(right) }),
+            ),
+            While(ref condition, ref body) => While(subst(condition), subst(body)),
+            For(ref from, ref to, (v, ref body)) => For(
+                subst(from),
+                subst(to),
+                (v, if v == x { body.clone() } else { subst(body) }),
+            ),
+            Seq(ref seq) => Seq(seq.iter().map(|sub| sub.subst(x, value)).collect()),
+            Ref(ref sub) => Ref(subst(sub)),
+            Deref(ref sub) => Deref(subst(sub)),
+            Lazy(ref sub) => Lazy(subst(sub)),
+            Force(location, ref sub) => Force(location, subst(sub)),
+            Spawn(ref sub) => Spawn(subst(sub)),
+            Raise(location, ref sub) => Raise(location, subst(sub)),
+            Try(ref sub, (v, ref handler)) => Try(
+                subst(sub),
+                (
+                    v,
+                    if v == x {
+                        handler.clone()
+                    } else {
+                        subst(handler)
+                    },
+                ),
+            ),
+            Send(location, ref left, ref right) => Send(location, subst(left), subst(right)),
+            Recv(location, ref sub) => Recv(location, subst(sub)),
+            Print(ref sub) => Print(subst(sub)),
+            Assign(ref left, ref right) => Assign(subst(left), subst(right)),
+            MakeArray(location, ref length, ref sub) => {
+                MakeArray(location, subst(length), subst(sub))
+            }
+            Index(location, ref left, ref right) => Index(location, subst(left), subst(right)),
+            Update(location, ref left, ref index, ref right) => {
+                Update(location, subst(left), subst(index), subst(right))
+            }
+            Cons(ref left, ref right) => Cons(subst(left), subst(right)),
+            Head(location, ref sub) => Head(location, subst(sub)),
+            Tail(location, ref sub) => Tail(location, subst(sub)),
+            IsEmpty(ref sub) => IsEmpty(subst(sub)),
+            Lambda(v, ref body) if v != x => Lambda(v, subst(body)),
+            App(ref left, ref right) => App(subst(left), subst(right)),
+            Let(v, ref sub, ref body) => Let(
+                v,
+                subst(sub),
+                if v == x { body.clone() } else { subst(body) },
+            ),
+            LetFun(f, v, ref lambda, ref body) if f != x => LetFun(
+                f,
+                v,
+                if v == x {
+                    lambda.clone()
+                } else {
+                    subst(lambda)
+                },
+                subst(body),
+            ),
+            LetRec(ref functions, ref body) if functions.iter().all(|&(f, _, _)| f != x) => LetRec(
+                functions
+                    .iter()
+                    .map(|&(f, v, ref lambda)| {
+                        (
+                            f,
+                            v,
+                            if v == x {
+                                lambda.clone()
+                            } else {
+                                subst(lambda)
+                            },
+                        )
+                    })
+                    .collect(),
+                subst(body),
+            ),
+            // values are closed, and anything else binds 'x' itself
+            _ => self.clone(),
+        }
+    }
+}
+
+impl<'a> Term<'a> {
+    // anything that isn't printed as a single token (or in brackets of its own)
+    // gets parentheses as an operand
+    fn fmt_operand(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Term::*;
+        match *self {
+            Unit
+            | What
+            | Var(_)
+            | Bool(_)
+            | Str(_)
+            | Text(_, _)
+            | Loc(_)
+            | Array(_)
+            | Elements(_)
+            | Nil
+            | Index(_, _, _)
+            | Thunk(_)
+            | Channel(_)
+            | Forcing
+            | Raised(_, _)
+            | Waiting(_)
+            | Pair(_, _)
+            | Tuple(_, _, _)
+            | Closure(_, Some(_), _, _) => {
+                write!(f, "{}", self)
+            }
+            Int(i) if i >= 0 => write!(f, "{}", self),
+            // a float no literal is written is printed in brackets already
+            Float(x) if x.is_nan() || x.is_infinite() || x >= 0.0 => write!(f, "{}", self),
+            _ => write!(f, "({})", self),
+        }
+    }
+}
+
+struct Operand<'t, 'a>(&'t Term<'a>);
+
+impl<'t, 'a> fmt::Display for Operand<'t, 'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_operand(f)
+    }
+}
+
+// terms are printed much as they'd be written, without the types (which they
+// don't keep), and with what's in the store referred to by location
+impl<'a> fmt::Display for Term<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Term::*;
+        let op = Operand;
+        match *self {
+            Unit => write!(f, "()"),
+            What => write!(f, "?"),
+            Var(v) => write!(f, "{}", v),
+            Int(i) => write!(f, "{}", i),
+            Float(x) => write!(f, "{}", float_literal(x)),
+            Bool(b) => write!(f, "{}", b),
+            Str(string) => write!(f, "{}", quote(string)),
+            Text(_, ref string) => write!(f, "{}", quote(string)),
+            UnOp(o, ref sub) => write!(f, "{}{}", o, op(sub)),
+            // a chain ('(a + b) + c') is printed in a loop, as it can be too
+            // long to recurse down
+            BinOp(..) | Div(..) | Mod(..) => {
+                let (first, operators) = self.chain();
+                write!(f, "{}{}", "(".repeat(operators.len() - 1), op(first))?;
+                for (i, operator) in operators.into_iter().enumerate() {
+                    let close = if i == 0 { "" } else { ")" };
+                    match *operator {
+                        BinOp(o, _, ref right) => write!(f, "{} {} {}", close, o, op(right))?,
+                        Div(_, _, ref right) => write!(f, "{} / {}", close, op(right))?,
+                        Mod(_, _, ref right) => write!(f, "{} % {}", close, op(right))?,
+                        _ => unreachable!(),
+                    }
+                }
+                Ok(())
+            }
+            If(ref condition, ref left, ref right) => {
+                write!(f, "if {} then {} else {} end", condition, left, right)
+            }
+            Pair(ref left, ref right) | Tuple(_, ref left, ref right) => {
+                write!(f, "({}, {})", left, right)
+            }
+            Fst(ref sub) => write!(f, "fst {}", op(sub)),
+            Snd(ref sub) => write!(f, "snd {}", op(sub)),
+            Inl(ref sub, type_expr) | Left(_, ref sub, type_expr) => {
+                write!(f, "inl {} {}", type_expr, op(sub))
+            }
+            Inr(ref sub, type_expr) | Right(_, ref sub, type_expr) => {
+                write!(f, "inr {} {}", type_expr, op(sub))
+            }
+            Case(ref sub, (x, ref left), (y, ref right)) => write!(
+                f,
+                "case {} of inl {} -> {} | inr {} -> {} end",
+                sub, x, left, y, right
+            ),
+            While(ref condition, ref body) => write!(f, "while {} do {} end", condition, body),
+            For(ref from, ref to, (v, ref body)) => {
+                write!(f, "for {} = {} to {} do {} end", v, from, to, body)
+            }
+            Seq(ref seq) => {
+                write!(f, "begin ")?;
+                for (i, sub) in seq.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", sub)?;
+                }
+                write!(f, " end")
+            }
+            Ref(ref sub) => write!(f, "ref {}", op(sub)),
+            Deref(ref sub) => write!(f, "!{}", op(sub)),
+            Lazy(ref sub) => write!(f, "lazy {}", op(sub)),
+            Force(_, ref sub) => write!(f, "force {}", op(sub)),
+            Chan => write!(f, "chan"),
+            Spawn(ref sub) => write!(f, "spawn {}", op(sub)),
+            Raise(_, ref sub) => write!(f, "raise {}", op(sub)),
+            Try(ref sub, (v, ref handler)) => {
+                write!(f, "try {} with {} -> {} end", sub, v, handler)
+            }
+            Raised(_, i) => write!(f, "<raised {}>", i),
+            Send(_, ref left, ref right) => write!(f, "send {} {}", op(left), op(right)),
+            Recv(_, ref sub) => write!(f, "recv {}", op(sub)),
+            Print(ref sub) => write!(f, "print_string {}", op(sub)),
+            Assign(ref left, ref right) => write!(f, "{} := {}", op(left), right),
+            MakeArray(_, ref length, ref sub) => {
+                write!(f, "Array.make {} {}", op(length), op(sub))
+            }
+            Index(_, ref left, ref right) => write!(f, "{}[{}]", op(left), right),
+            Update(_, ref left, ref index, ref right) => {
+                write!(f, "{}[{}] := {}", op(left), index, right)
+            }
+            Nil => write!(f, "[]"),
+            Cons(ref left, ref right) | Cell(_, ref left, ref right) => {
+                write!(f, "{} :: {}", op(left), right)
+            }
+            Head(_, ref sub) => write!(f, "List.hd {}", op(sub)),
+            Tail(_, ref sub) => write!(f, "List.tl {}", op(sub)),
+            IsEmpty(ref sub) => write!(f, "List.is_empty {}", op(sub)),
+            Lambda(v, ref body) | Closure(_, None, v, ref body) => {
+                write!(f, "fun {} -> {} end", v, body)
+            }
+            // a recursive function's body refers to it by name, so that's
+            // enough to tell which it is
+            Closure(_, Some(name), _, _) => write!(f, "<fun {}>", name),
+            App(ref left, ref right) => match **left {
+                App(_, _) => write!(f, "{} {}", left, op(right)),
+                _ => write!(f, "{} {}", op(left), op(right)),
+            },
+            Let(v, ref sub, ref body) => write!(f, "let {} = {} in {} end", v, sub, body),
+            LetFun(name, v, ref lambda, ref body) => {
+                write!(f, "let {} {} = {} in {} end", name, v, lambda, body)
+            }
+            LetRec(ref functions, ref body) => {
+                write!(f, "let rec ")?;
+                for (i, &(name, v, ref lambda)) in functions.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " and ")?;
+                    }
+                    write!(f, "{} {} = {}", name, v, lambda)?;
+                }
+                write!(f, " in {} end", body)
+            }
+            Loc(l) => write!(f, "<ref {}>", l),
+            Array(l) => write!(f, "<array {}>", l),
+            Elements(ref elements) => {
+                write!(f, "[|")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "|]")
+            }
+            Thunk(l) => write!(f, "<lazy {}>", l),
+            Memo(l, ref sub) => write!(f, "memo <lazy {}> {}", l, op(sub)),
+            Forcing => write!(f, "<forcing>"),
+            Channel(c) => write!(f, "<chan {}>", c),
+            Waiting(_) => write!(f, "<waiting>"),
+        }
+    }
+}
+
+// the threads waiting to send on a channel (along with what they're sending),
+// or to receive from it
+#[derive(Clone, Default)]
+struct Channel<'a> {
+    senders: VecDeque<(usize, Term<'a>)>,
+    receivers: VecDeque<usize>,
+}
+
+// what's left over from running a program: the store, along with what's needed
+// to run '?' and to give new values addresses, and the channels between its
+// threads
+#[derive(Clone)]
+struct Machine<'a> {
+    store: Vec<Term<'a>>,
+    addresses: usize,
+    input: Input,
+    output: Output,
+    width: IntWidth,
+    channels: Vec<Channel<'a>>,
+    // the thread taking a step, and how many threads there have been
+    thread: usize,
+    threads: usize,
+    // threads spawned by the last step, which haven't started yet
+    spawned: Vec<Term<'a>>,
+    // threads that are ready to run, in the order they became ready
+    ready: VecDeque<usize>,
+    schedule: Schedule,
+    // what each waiting thread that's been woken goes on with
+    woken: HashMap<usize, Term<'a>>,
+}
+
+impl<'a> Machine<'a> {
+    fn address(&mut self) -> usize {
+        self.addresses += 1;
+        self.addresses
+    }
+
+    fn reflect(&self, value: &Term<'a>) -> reflect::Value {
+        use self::Term::*;
+        match *value {
+            Unit => reflect::Value::Unit,
+            Int(i) => reflect::Value::Int(i),
+            Float(f) => reflect::Value::Float(f),
+            Bool(b) => reflect::Value::Bool(b),
+            Tuple(_, ref left, ref right) => {
+                reflect::Value::Pair(Box::new(self.reflect(left)), Box::new(self.reflect(right)))
+            }
+            Left(_, ref sub, type_expr) => {
+                reflect::Value::Inl(Box::new(self.reflect(sub)), type_expr.into())
+            }
+            Right(_, ref sub, type_expr) => {
+                reflect::Value::Inr(Box::new(self.reflect(sub)), type_expr.into())
+            }
+            Nil | Cell(_, _, _) => {
+                let mut elements = vec![];
+                let mut list = value;
+                while let Cell(_, ref head, ref tail) = *list {
+                    elements.push(self.reflect(head));
+                    list = tail;
+                }
+                reflect::Value::List(elements)
+            }
+            Loc(l) => reflect::Value::Ref(Box::new(self.reflect(&self.store[l]))),
+            Array(l) => match self.store[l] {
+                Elements(ref elements) => reflect::Value::Array(
+                    elements
+                        .iter()
+                        .map(|element| self.reflect(element))
+                        .collect(),
+                ),
+                _ => unreachable!(),
+            },
+            Thunk(l) if self.store[l].is_value() => {
+                reflect::Value::Lazy(Some(Box::new(self.reflect(&self.store[l]))))
+            }
+            Thunk(_) => reflect::Value::Lazy(None),
+            Channel(_) => reflect::Value::Chan,
+            Closure(_, _, _, _) => reflect::Value::Fun,
+            Text(_, ref string) => reflect::Value::Str(string.clone()),
+            _ => unreachable!(),
+        }
+    }
+}
+
+// the same value (so the same number or boolean, or the same thing on the heap)
+fn same(left: &Term, right: &Term) -> bool {
+    use self::Term::*;
+    match (left, right) {
+        (Unit, Unit) | (Nil, Nil) => true,
+        (Int(a), Int(b)) => a == b,
+        (Float(a), Float(b)) => a == b,
+        (Bool(a), Bool(b)) => a == b,
+        (Loc(a), Loc(b))
+        | (Array(a), Array(b))
+        | (Thunk(a), Thunk(b))
+        | (Channel(a), Channel(b))
+        | (Tuple(a, _, _), Tuple(b, _, _))
+        | (Left(a, _, _), Left(b, _, _))
+        | (Right(a, _, _), Right(b, _, _))
+        | (Cell(a, _, _), Cell(b, _, _))
+        | (Closure(a, _, _, _), Closure(b, _, _, _))
+        | (Text(a, _), Text(b, _)) => a == b,
+        _ => false,
+    }
+}
+
+// a rule rewrites a term whose strict subterms are all values, or doesn't apply
+// to it
+type Rule = for<'a> fn(&mut Machine<'a>, &Term<'a>) -> Option<Result<Term<'a>, String>>;
+
+// the small-step rules of the language, by name (a term with no rule that
+// applies to it is stuck, which a well-typed program never is)
+const RULES: [(&str, Rule); 49] = [
+    ("neg", |m, term| match *term {
+        Term::UnOp(UnOp::Neg, ref sub) => match **sub {
+            Term::Int(i) => Some(Ok(Term::Int(m.width.wrap(i.wrapping_neg())))),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("not", |_, term| match *term {
+        Term::UnOp(UnOp::Not, ref sub) => match **sub {
+            Term::Bool(b) => Some(Ok(Term::Bool(!b))),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("and", |_, term| match *term {
+        Term::BinOp(BinOp::And, ref left, ref right) => match **left {
+            Term::Bool(true) => Some(Ok((**right).clone())),
+            Term::Bool(false) => Some(Ok(Term::Bool(false))),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("or", |_, term| match *term {
+        Term::BinOp(BinOp::Or, ref left, ref right) => match **left {
+            Term::Bool(true) => Some(Ok(Term::Bool(true))),
+            Term::Bool(false) => Some(Ok((**right).clone())),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("eq", |_, term| match *term {
+        Term::BinOp(BinOp::Eq, ref left, ref right)
+        | Term::BinOp(BinOp::PhysEq, ref left, ref right) => {
+            Some(Ok(Term::Bool(same(left, right))))
+        }
+        _ => None,
+    }),
+    ("arith", |m, term| match *term {
+        Term::BinOp(op, ref left, ref right) => match (&**left, &**right) {
+            (&Term::Int(left), &Term::Int(right)) => Some(Ok(match op {
+                BinOp::Add => Term::Int(m.width.wrap(left.wrapping_add(right))),
+                BinOp::Sub => Term::Int(m.width.wrap(left.wrapping_sub(right))),
+                BinOp::Mul => Term::Int(m.width.wrap(left.wrapping_mul(right))),
+                BinOp::Lt => Term::Bool(left < right),
+                _ => return None,
+            })),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("float", |_, term| match *term {
+        Term::BinOp(op, ref left, ref right) => match (&**left, &**right) {
+            (&Term::Float(left), &Term::Float(right)) => Some(Ok(match op {
+                BinOp::FloatAdd => Term::Float(left + right),
+                BinOp::FloatSub => Term::Float(left - right),
+                BinOp::FloatMul => Term::Float(left * right),
+                BinOp::FloatDiv => Term::Float(left / right),
+                BinOp::FloatLt => Term::Bool(left < right),
+                BinOp::FloatEq => Term::Bool(left == right),
+                _ => return None,
+            })),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("string", |m, term| match *term {
+        Term::Str(string) => Some(Ok(Term::Text(m.address(), string.to_string()))),
+        _ => None,
+    }),
+    ("concat", |m, term| match *term {
+        Term::BinOp(BinOp::Concat, ref left, ref right) => match (&**left, &**right) {
+            (Term::Text(_, left), Term::Text(_, right)) => {
+                Some(Ok(Term::Text(m.address(), format!("{}{}", left, right))))
+            }
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("compare", |_, term| match *term {
+        Term::BinOp(BinOp::Compare, ref left, ref right) => match (&**left, &**right) {
+            (Term::Text(_, left), Term::Text(_, right)) => {
+                Some(Ok(Term::Int(compare(left, right))))
+            }
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("div", |m, term| match *term {
+        Term::Div(location, ref left, ref right) => match (&**left, &**right) {
+            (_, &Term::Int(0)) => Some(Err(log::runtime_error(location, "division by zero"))),
+            (&Term::Int(left), &Term::Int(right)) => Some(match left.checked_div(right) {
+                Some(i) if m.width.wrap(i) == i => Ok(Term::Int(i)),
+                _ => Err(log::runtime_error(location, "overflow in division")),
+            }),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("mod", |_, term| match *term {
+        Term::Mod(location, ref left, ref right) => match (&**left, &**right) {
+            (_, &Term::Int(0)) => Some(Err(log::runtime_error(location, "division by zero"))),
+            (&Term::Int(left), &Term::Int(right)) => Some(Ok(Term::Int(left.wrapping_rem(right)))),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("if", |_, term| match *term {
+        Term::If(ref condition, ref left, ref right) => match **condition {
+            Term::Bool(true) => Some(Ok((**left).clone())),
+            Term::Bool(false) => Some(Ok((**right).clone())),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("pair", |m, term| match *term {
+        Term::Pair(ref left, ref right) => {
+            Some(Ok(Term::Tuple(m.address(), left.clone(), right.clone())))
+        }
+        _ => None,
+    }),
+    ("fst", |_, term| match *term {
+        Term::Fst(ref sub) => match **sub {
+            Term::Tuple(_, ref left, _) => Some(Ok((**left).clone())),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("snd", |_, term| match *term {
+        Term::Snd(ref sub) => match **sub {
+            Term::Tuple(_, _, ref right) => Some(Ok((**right).clone())),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("inl", |m, term| match *term {
+        Term::Inl(ref sub, type_expr) => Some(Ok(Term::Left(m.address(), sub.clone(), type_expr))),
+        _ => None,
+    }),
+    ("inr", |m, term| match *term {
+        Term::Inr(ref sub, type_expr) => Some(Ok(Term::Right(m.address(), sub.clone(), type_expr))),
+        _ => None,
+    }),
+    ("case", |_, term| match *term {
+        Term::Case(ref sub, (x, ref left), (y, ref right)) => match **sub {
+            Term::Left(_, ref value, _) => Some(Ok(left.subst(x, value))),
+            Term::Right(_, ref value, _) => Some(Ok(right.subst(y, value))),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("while", |_, term| match *term {
+        Term::While(ref condition, ref body) => Some(Ok(Term::If(
+            condition.clone(),
+            Box::new(Term::Seq(vec![(**body).clone(), term.clone()])),
+            Box::new(Term::Unit),
+        ))),
+        _ => None,
+    }),
+    // the last iteration doesn't go on to the next int, which might not exist
+    ("for", |_, term| match *term {
+        Term::For(ref from, ref to, (v, ref body)) => match (&**from, &**to) {
+            (&Term::Int(i), &Term::Int(j)) if i > j => Some(Ok(Term::Unit)),
+            (&Term::Int(i), &Term::Int(j)) => {
+                let next = if i == j {
+                    Term::Unit
+                } else {
+                    Term::For(Box::new(Term::Int(i + 1)), to.clone(), (v, body.clone()))
+                };
+                Some(Ok(Term::Seq(vec![body.subst(v, &Term::Int(i)), next])))
+            }
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("seq", |_, term| match *term {
+        Term::Seq(ref seq) => Some(Ok(match seq.len() {
+            0 => Term::Unit,
+            1 => seq[0].clone(),
+            _ => Term::Seq(seq[1..].to_vec()),
+        })),
+        _ => None,
+    }),
+    ("ref", |m, term| match *term {
+        Term::Ref(ref sub) => {
+            m.store.push((**sub).clone());
+            Some(Ok(Term::Loc(m.store.len() - 1)))
+        }
+        _ => None,
+    }),
+    ("deref", |m, term| match *term {
+        Term::Deref(ref sub) => match **sub {
+            Term::Loc(l) => Some(Ok(m.store[l].clone())),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("assign", |m, term| match *term {
+        Term::Assign(ref left, ref right) => match **left {
+            Term::Loc(l) => {
+                m.store[l] = (**right).clone();
+                Some(Ok(Term::Unit))
+            }
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("array", |m, term| match *term {
+        Term::MakeArray(location, ref length, ref sub) => match **length {
+            Term::Int(length) if length < 0 => {
+                Some(Err(log::runtime_error(location, "negative array length")))
+            }
+            Term::Int(length) => {
+                m.store
+                    .push(Term::Elements(vec![(**sub).clone(); length as usize]));
+                Some(Ok(Term::Array(m.store.len() - 1)))
+            }
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("index", |m, term| match *term {
+        Term::Index(location, ref left, ref right) => match (&**left, &**right) {
+            (&Term::Array(l), &Term::Int(i)) => match m.store[l] {
+                Term::Elements(ref elements) => Some(
+                    usize::try_from(i)
+                        .ok()
+                        .and_then(|i| elements.get(i).cloned())
+                        .ok_or_else(|| log::runtime_error(location, "array index out of bounds")),
+                ),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("update", |m, term| match *term {
+        Term::Update(location, ref left, ref index, ref right) => match (&**left, &**index) {
+            (&Term::Array(l), &Term::Int(i)) => match m.store[l] {
+                Term::Elements(ref mut elements) => Some(
+                    usize::try_from(i)
+                        .ok()
+                        .and_then(|i| elements.get_mut(i))
+                        .map(|slot| {
+                            *slot = (**right).clone();
+                            Term::Unit
+                        })
+                        .ok_or_else(|| log::runtime_error(location, "array index out of bounds")),
+                ),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("cons", |m, term| match *term {
+        Term::Cons(ref left, ref right) => {
+            Some(Ok(Term::Cell(m.address(), left.clone(), right.clone())))
+        }
+        _ => None,
+    }),
+    ("hd", |_, term| match *term {
+        Term::Head(location, ref sub) => match **sub {
+            Term::Cell(_, ref left, _) => Some(Ok((**left).clone())),
+            Term::Nil => Some(Err(log::runtime_error(location, "head of empty list"))),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("tl", |_, term| match *term {
+        Term::Tail(location, ref sub) => match **sub {
+            Term::Cell(_, _, ref right) => Some(Ok((**right).clone())),
+            Term::Nil => Some(Err(log::runtime_error(location, "tail of empty list"))),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("is_empty", |_, term| match *term {
+        Term::IsEmpty(ref sub) => match **sub {
+            Term::Nil => Some(Ok(Term::Bool(true))),
+            Term::Cell(_, _, _) => Some(Ok(Term::Bool(false))),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("lazy", |m, term| match *term {
+        Term::Lazy(_) => {
+            m.store.push(term.clone());
+            Some(Ok(Term::Thunk(m.store.len() - 1)))
+        }
+        _ => None,
+    }),
+    ("force", |m, term| match *term {
+        Term::Force(location, ref sub) => match **sub {
+            Term::Thunk(l) => Some(Ok(
+                match std::mem::replace(&mut m.store[l], Term::Forcing) {
+                    Term::Lazy(sub) => Term::Memo(l, sub),
+                    Term::Forcing => {
+                        return Some(Err(log::runtime_error(
+                            location,
+                            "lazy value forced while it was being computed",
+                        )))
+                    }
+                    value => {
+                        m.store[l] = value.clone();
+                        value
+                    }
+                },
+            )),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("memo", |m, term| match *term {
+        Term::Memo(l, ref sub) => {
+            m.store[l] = (**sub).clone();
+            Some(Ok((**sub).clone()))
+        }
+        _ => None,
+    }),
+    ("chan", |m, term| match *term {
+        Term::Chan => {
+            m.channels.push(Channel::default());
+            Some(Ok(Term::Channel(m.channels.len() - 1)))
+        }
+        _ => None,
+    }),
+    ("spawn", |m, term| match *term {
+        Term::Spawn(ref sub) => {
+            m.spawned.push((**sub).clone());
+            m.ready.push_back(m.threads);
+            m.threads += 1;
+            Some(Ok(Term::Unit))
+        }
+        _ => None,
+    }),
+    ("raise", |_, term| match *term {
+        Term::Raise(location, ref sub) => match **sub {
+            Term::Int(i) => Some(Ok(Term::Raised(location, i))),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("try", |_, term| match *term {
+        Term::Try(ref sub, (v, ref handler)) => match **sub {
+            Term::Raised(_, i) => Some(Ok(handler.subst(v, &Term::Int(i)))),
+            ref value => Some(Ok(value.clone())),
+        },
+        _ => None,
+    }),
+    ("send", |m, term| match *term {
+        Term::Send(location, ref left, ref right) => match **left {
+            Term::Channel(c) => Some(Ok(match m.channels[c].receivers.pop_front() {
+                Some(receiver) => {
+                    m.woken.insert(receiver, (**right).clone());
+                    m.ready.push_back(receiver);
+                    Term::Unit
+                }
+                None => {
+                    m.channels[c]
+                        .senders
+                        .push_back((m.thread, (**right).clone()));
+                    Term::Waiting(location)
+                }
+            })),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("recv", |m, term| match *term {
+        Term::Recv(location, ref sub) => match **sub {
+            Term::Channel(c) => Some(Ok(match m.channels[c].senders.pop_front() {
+                Some((sender, value)) => {
+                    m.woken.insert(sender, Term::Unit);
+                    m.ready.push_back(sender);
+                    value
+                }
+                None => {
+                    m.channels[c].receivers.push_back(m.thread);
+                    Term::Waiting(location)
+                }
+            })),
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("wake", |m, term| match *term {
+        Term::Waiting(_) => m.woken.remove(&m.thread).map(Ok),
+        _ => None,
+    }),
+    ("fun", |m, term| match *term {
+        Term::Lambda(v, ref body) => Some(Ok(Term::Closure(m.address(), None, v, body.clone()))),
+        _ => None,
+    }),
+    ("app", |_, term| match *term {
+        Term::App(ref left, ref arg) => match **left {
+            Term::Closure(_, name, v, ref body) => {
+                let body = match name {
+                    Some(f) if f != v => body.subst(f, left),
+                    _ => (**body).clone(),
+                };
+                Some(Ok(body.subst(v, arg)))
+            }
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("let", |_, term| match *term {
+        Term::Let(v, ref sub, ref body) => Some(Ok(body.subst(v, sub))),
+        _ => None,
+    }),
+    ("letfun", |m, term| match *term {
+        Term::LetFun(f, v, ref lambda, ref body) => {
+            let closure = Term::Closure(m.address(), Some(f), v, lambda.clone());
+            Some(Ok(body.subst(f, &closure)))
+        }
+        _ => None,
+    }),
+    // each function's siblings are whatever the group gives them, which is
+    // worked out again wherever they're used
+    ("letrec", |m, term| match *term {
+        Term::LetRec(ref functions, ref body) => {
+            let mut body = (**body).clone();
+            for &(f, v, ref lambda) in functions.iter() {
+                let mut lambda = (**lambda).clone();
+                for &(g, _, _) in functions.iter() {
+                    if g != f && g != v {
+                        let sibling = Term::LetRec(functions.clone(), Box::new(Term::Var(g)));
+                        lambda = lambda.subst(g, &sibling);
+                    }
+                }
+                let closure = Term::Closure(m.address(), Some(f), v, Box::new(lambda));
+                body = body.subst(f, &closure);
+            }
+            Some(Ok(body))
+        }
+        _ => None,
+    }),
+    ("print", |m, term| match *term {
+        Term::Print(ref sub) => match **sub {
+            Term::Text(_, ref string) => {
+                m.output.write(string);
+                Some(Ok(Term::Unit))
+            }
+            _ => None,
+        },
+        _ => None,
+    }),
+    ("what", |m, term| match *term {
+        Term::What => Some(m.input.read(m.width).map(Term::Int)),
+        _ => None,
+    }),
+];
+
+// where each of a program's threads has got to ('None' once it's finished),
+// and where its main thread is waiting, if it is
+#[derive(Clone)]
+struct Run<'a> {
+    threads: Vec<Option<Term<'a>>>,
+    waiting: Option<&'a Location>,
+}
+
+impl<'a> Run<'a> {
+    fn new(expr: &'a Expr) -> Run<'a> {
+        Run {
+            threads: vec![Some(Term::from(expr))],
+            waiting: None,
+        }
+    }
+}
+
+// what happened when the thread whose turn it was ran
+enum Tick {
+    Rule(&'static str),
+    Waiting,
+    // a thread other than the main thread finished
+    Exited,
+    // the main thread finished, and so did the program
+    Finished(reflect::Value),
+}
+
+// a reference evaluator that runs programs by following the small-step rules
+// above, one step at a time (it's far slower than the interpreter, but small
+// enough to check against the semantics by eye, so the interpreter can be
+// checked against it)
+pub struct Reference<'a> {
+    machine: Machine<'a>,
+}
+
+impl<'a> Reference<'a> {
+    pub fn new(input: Input, width: IntWidth) -> Reference<'a> {
+        Reference {
+            machine: Machine {
+                store: vec![],
+                addresses: 0,
+                input,
+                output: Output::Stdout,
+                width,
+                channels: vec![],
+                thread: MAIN,
+                threads: MAIN + 1,
+                spawned: vec![],
+                ready: VecDeque::new(),
+                schedule: Schedule::InOrder,
+                woken: HashMap::new(),
+            },
+        }
+    }
+
+    // picks the thread that takes over from one that finishes or has to wait
+    // with a generator seeded with 'seed', as the interpreter does
+    pub fn schedule(&mut self, seed: u64) {
+        self.machine.schedule = Schedule::Seeded(seed);
+    }
+
+    // keeps what the program prints instead of printing it, as the interpreter
+    // does
+    pub fn capture_output(&mut self) {
+        self.machine.output = Output::Captured(String::new());
+    }
+
+    pub fn output(&mut self) -> String {
+        self.machine.output.take()
+    }
+
+    // takes a step somewhere inside a term (which isn't a value), unless the
+    // thread is waiting on a channel, in which case this is where (along with
+    // the rule that was used otherwise)
+    fn step(&mut self, term: &mut Term<'a>) -> Result<Result<&'static str, &'a Location>, String> {
+        // the subterm to step is found in a loop, as terms can be too deep to
+        // recurse into
+        let mut term = term;
+        loop {
+            let next = term.strict().iter().position(|sub| !sub.is_value());
+            match next {
+                Some(i) if !matches!(*term.strict()[i], Term::Raised(_, _)) => {
+                    term = term.strict().swap_remove(i);
+                }
+                _ => break,
+            }
+        }
+        let raised = match term.strict().into_iter().find(|sub| !sub.is_value()) {
+            Some(&mut Term::Raised(location, i)) => Some(Term::Raised(location, i)),
+            _ => None,
+        };
+        // only a 'try' has a rule for an exception, and anything else is left
+        // for the exception to go on past
+        if let Some(raised) = raised {
+            if !matches!(*term, Term::Try(_, _)) {
+                *term = raised;
+                return Ok(Ok("unwind"));
+            }
+        }
+        for (name, rule) in RULES.iter() {
+            if let Some(next) = rule(&mut self.machine, term) {
+                *term = next?;
+                return Ok(Ok(name));
+            }
+        }
+        match *term {
+            Term::Waiting(location) => Ok(Err(location)),
+            Term::Raised(location, i) => Err(log::runtime_error(
+                location,
+                &format!("uncaught exception {}", i),
+            )),
+            // the program has already been checked, so it never gets stuck
+            _ => unreachable!(),
+        }
+    }
+
+    // a thread runs until it finishes or has to wait, and then the first of the
+    // threads that are ready to run takes over (unless there's a seed) (a
+    // program ends when its main thread does, and deadlocks where its main
+    // thread is waiting if every thread is waiting)
+    fn tick(&mut self, run: &mut Run<'a>) -> Result<Tick, String> {
+        let thread = self.machine.thread;
+        let term = run.threads[thread].as_mut().unwrap();
+        let (blocked, tick) = if term.is_value() {
+            if thread == MAIN {
+                return Ok(Tick::Finished(self.machine.reflect(term)));
+            }
+            run.threads[thread] = None;
+            (true, Tick::Exited)
+        } else {
+            match self.step(term)? {
+                Ok(rule) => (false, Tick::Rule(rule)),
+                Err(location) => {
+                    if thread == MAIN {
+                        run.waiting = Some(location);
+                    }
+                    (true, Tick::Waiting)
+                }
+            }
+        };
+        run.threads.extend(self.machine.spawned.drain(..).map(Some));
+        if blocked {
+            let machine = &mut self.machine;
+            match machine.schedule.next(&mut machine.ready) {
+                Some(next) => self.machine.thread = next,
+                None => {
+                    return Err(log::runtime_error(
+                        run.waiting.unwrap(),
+                        "deadlock: every thread is waiting on a channel",
+                    ))
+                }
+            }
+        }
+        Ok(tick)
+    }
+
+    pub fn eval(&mut self, expr: &'a Expr) -> Result<reflect::Value, String> {
+        let mut run = Run::new(expr);
+        loop {
+            if let Tick::Finished(value) = self.tick(&mut run)? {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+// steps through a program one tick at a time for a debugger, remembering a
+// bounded number of the states it's been through so that it can go back over
+// them (going back also puts back the numbers '?' read, so they're read again,
+// which only asks for them again if they come from stdin)
+pub struct Debugger<'a> {
+    reference: Reference<'a>,
+    run: Run<'a>,
+    history: VecDeque<(Machine<'a>, Run<'a>)>,
+    limit: usize,
+    steps: usize,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(reference: Reference<'a>, expr: &'a Expr, limit: usize) -> Debugger<'a> {
+        Debugger {
+            reference,
+            run: Run::new(expr),
+            history: VecDeque::new(),
+            limit,
+            steps: 0,
+        }
+    }
+
+    // how many steps it took to get here
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    // the program's value, once its main thread has finished
+    pub fn value(&self) -> Option<reflect::Value> {
+        match self.run.threads[MAIN] {
+            Some(ref term) if term.is_value() => Some(self.reference.machine.reflect(term)),
+            _ => None,
+        }
+    }
+
+    // takes a step, giving the rule that was used (unless a thread finished or
+    // had to wait instead), and leaves everything as it was if the program
+    // fails (so going back from there works as it would have)
+    pub fn step(&mut self) -> Result<Option<&'static str>, String> {
+        if self.value().is_some() {
+            return Ok(None);
+        }
+        let saved = (self.reference.machine.clone(), self.run.clone());
+        match self.reference.tick(&mut self.run) {
+            Ok(tick) => {
+                if self.history.len() == self.limit {
+                    self.history.pop_front();
+                }
+                if self.limit > 0 {
+                    self.history.push_back(saved);
+                }
+                self.steps += 1;
+                Ok(match tick {
+                    Tick::Rule(rule) => Some(rule),
+                    _ => None,
+                })
+            }
+            Err(err) => {
+                let (machine, run) = saved;
+                self.reference.machine = machine;
+                self.run = run;
+                Err(err)
+            }
+        }
+    }
+
+    // undoes the last step, unless it's been forgotten
+    pub fn back(&mut self) -> bool {
+        match self.history.pop_back() {
+            Some((machine, run)) => {
+                self.reference.machine = machine;
+                self.run = run;
+                self.steps -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// every thread that hasn't finished (if there's more than one, with the one
+// that takes the next step marked), then everything in the store
+impl<'a> fmt::Display for Debugger<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let machine = &self.reference.machine;
+        let live = self.run.threads.iter().filter(|t| t.is_some()).count();
+        for (thread, term) in self.run.threads.iter().enumerate() {
+            if let Some(ref term) = *term {
+                if live > 1 {
+                    let marker = if thread == machine.thread { "*" } else { " " };
+                    write!(f, "{}thread {}: ", marker, thread)?;
+                }
+                writeln!(f, "{}", term)?;
+            }
+        }
+        for (l, term) in machine.store.iter().enumerate() {
+            writeln!(f, "  {} = {}", l, term)?;
+        }
+        Ok(())
+    }
+}
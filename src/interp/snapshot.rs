@@ -0,0 +1,991 @@
+use super::super::frontend::ast::{self, Attributes, BinOp, Expr, UnOp};
+use super::super::frontend::{Location, TypeExpr};
+use super::value::{Channel, Closure, Thunk};
+use super::{Binding, Env, Value};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// what every snapshot starts with, so that other files aren't mistaken for one
+const MAGIC: &[u8] = b"slang snapshot 2\n";
+
+const BINOPS: [BinOp; 18] = [
+    BinOp::Add,
+    BinOp::Mul,
+    BinOp::Div,
+    BinOp::Mod,
+    BinOp::Sub,
+    BinOp::Lt,
+    BinOp::And,
+    BinOp::Or,
+    BinOp::Eq,
+    BinOp::PhysEq,
+    BinOp::Concat,
+    BinOp::Compare,
+    BinOp::FloatAdd,
+    BinOp::FloatSub,
+    BinOp::FloatMul,
+    BinOp::FloatDiv,
+    BinOp::FloatLt,
+    BinOp::FloatEq,
+];
+
+// everything a REPL session has built up: the types and text of its
+// definitions, the values they're bound to, and what was in scope when each
+// was made
+pub struct Snapshot<'a> {
+    pub types: Vec<(String, TypeExpr)>,
+    pub definitions: Vec<String>,
+    pub values: Env<'a>,
+    pub scopes: Vec<Env<'a>>,
+}
+
+// anything on the heap is written out the first time it's reached and referred
+// to by number after that, so that sharing survives a snapshot (and '=='
+// still tells the same things apart)
+#[derive(Default)]
+struct Writer<'a> {
+    bytes: Vec<u8>,
+    values: HashMap<usize, usize>,
+    bindings: HashMap<usize, usize>,
+    exprs: HashMap<usize, usize>,
+    // references, lazy values and arrays can be part of a cycle, so they're numbered
+    // as soon as they're reached, and what they hold is written afterwards
+    cells: Vec<(usize, Value<'a>)>,
+}
+
+fn address<T: ?Sized>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const u8 as usize
+}
+
+impl<'a> Writer<'a> {
+    fn byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn number(&mut self, n: u64) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.number(s.len() as u64);
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn type_expr(&mut self, type_expr: &TypeExpr) {
+        use self::TypeExpr::*;
+        match *type_expr {
+            Unit => self.byte(0),
+            Bool => self.byte(1),
+            Int => self.byte(2),
+            String => self.byte(9),
+            Float => self.byte(10),
+            Ref(ref sub) | Lazy(ref sub) | Chan(ref sub) | Array(ref sub) | List(ref sub) => {
+                self.byte(match *type_expr {
+                    Ref(_) => 3,
+                    Lazy(_) => 4,
+                    Chan(_) => 5,
+                    Array(_) => 11,
+                    _ => 12,
+                });
+                self.type_expr(sub);
+            }
+            Arrow(ref left, ref right)
+            | Product(ref left, ref right)
+            | Union(ref left, ref right) => {
+                self.byte(match *type_expr {
+                    Arrow(_, _) => 6,
+                    Product(_, _) => 7,
+                    _ => 8,
+                });
+                self.type_expr(left);
+                self.type_expr(right);
+            }
+            // the type of a polymorphic definition
+            Forall(ref variables, ref sub) => {
+                self.byte(13);
+                self.number(variables.len() as u64);
+                for variable in variables.iter() {
+                    self.string(variable);
+                }
+                self.type_expr(sub);
+            }
+            Variable(ref variable) => {
+                self.byte(14);
+                self.string(variable);
+            }
+            // only programs that type check are ever run
+            Error | Hole | Unknown(_) | Named(_) | Record(_) | Tuple(_) => unreachable!(),
+        }
+    }
+
+    fn location(&mut self, location: &Location) {
+        self.string(location.filename());
+        self.number(location.line() as u64);
+        self.number(location.column() as u64);
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        use self::Expr::*;
+        match *expr {
+            Unit => self.byte(0),
+            What => self.byte(1),
+            Var(ref v) => {
+                self.byte(2);
+                self.string(v);
+            }
+            Int(i) => {
+                self.byte(3);
+                self.number(i as u64);
+            }
+            Bool(b) => {
+                self.byte(4);
+                self.byte(b as u8);
+            }
+            UnOp(op, ref sub) => {
+                self.byte(5);
+                self.byte(match op {
+                    self::UnOp::Neg => 0,
+                    self::UnOp::Not => 1,
+                });
+                self.expr(sub);
+            }
+            // a chain ('a + b + c') is written in a loop, as it can be too
+            // long to recurse down: each operator (the outermost first), then
+            // the leftmost operand, then the right operands in turn
+            BinOp(..) | Div(..) | Mod(..) => {
+                let (first, operators) = ast::chain(expr);
+                for operator in operators.iter().rev() {
+                    match **operator {
+                        BinOp(op, _, _) => {
+                            self.byte(6);
+                            self.byte(BINOPS.iter().position(|&o| o == op).unwrap() as u8);
+                        }
+                        Div(ref location, _, _) | Mod(ref location, _, _) => {
+                            self.byte(if let Div(_, _, _) = **operator { 7 } else { 8 });
+                            self.location(location);
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                self.expr(first);
+                for operator in operators {
+                    if let BinOp(_, _, ref right) | Div(_, _, ref right) | Mod(_, _, ref right) =
+                        *operator
+                    {
+                        self.expr(right);
+                    }
+                }
+            }
+            If(ref condition, ref left, ref right) => {
+                self.byte(9);
+                self.expr(condition);
+                self.expr(left);
+                self.expr(right);
+            }
+            Pair(ref left, ref right) => {
+                self.byte(10);
+                self.expr(left);
+                self.expr(right);
+            }
+            Fst(ref sub) => {
+                self.byte(11);
+                self.expr(sub);
+            }
+            Snd(ref sub) => {
+                self.byte(12);
+                self.expr(sub);
+            }
+            Inl(ref sub, ref type_expr) | Inr(ref sub, ref type_expr) => {
+                self.byte(if let Inl(_, _) = *expr { 13 } else { 14 });
+                self.expr(sub);
+                self.type_expr(type_expr);
+            }
+            Case(ref sub, (ref x, ref left), (ref y, ref right)) => {
+                self.byte(15);
+                self.expr(sub);
+                self.string(x);
+                self.expr(left);
+                self.string(y);
+                self.expr(right);
+            }
+            While(ref condition, ref body) => {
+                self.byte(16);
+                self.expr(condition);
+                self.expr(body);
+            }
+            Seq(ref seq) => {
+                self.byte(17);
+                self.number(seq.len() as u64);
+                for sub in seq.iter() {
+                    self.expr(sub);
+                }
+            }
+            Ref(ref sub) => {
+                self.byte(18);
+                self.expr(sub);
+            }
+            Deref(ref sub) => {
+                self.byte(19);
+                self.expr(sub);
+            }
+            Lazy(ref sub) => {
+                self.byte(20);
+                self.expr(sub);
+            }
+            Force(ref location, ref sub) => {
+                self.byte(21);
+                self.location(location);
+                self.expr(sub);
+            }
+            Chan => self.byte(22),
+            Spawn(ref sub) => {
+                self.byte(23);
+                self.expr(sub);
+            }
+            Send(ref location, ref left, ref right) => {
+                self.byte(24);
+                self.location(location);
+                self.expr(left);
+                self.expr(right);
+            }
+            Recv(ref location, ref sub) => {
+                self.byte(25);
+                self.location(location);
+                self.expr(sub);
+            }
+            Assign(ref left, ref right) => {
+                self.byte(26);
+                self.expr(left);
+                self.expr(right);
+            }
+            Lambda((ref v, ref body)) => {
+                self.byte(27);
+                self.string(v);
+                self.expr(body);
+            }
+            App(ref left, ref right) => {
+                self.byte(28);
+                self.expr(left);
+                self.expr(right);
+            }
+            Let(ref v, ref sub, ref body) => {
+                self.byte(29);
+                self.string(v);
+                self.expr(sub);
+                self.expr(body);
+            }
+            LetFun(ref f, (ref v, ref lambda), ref body) => {
+                self.byte(30);
+                self.string(f);
+                self.string(v);
+                self.expr(lambda);
+                self.expr(body);
+            }
+            Str(ref string) => {
+                self.byte(31);
+                self.string(string);
+            }
+            Print(ref sub) => {
+                self.byte(32);
+                self.expr(sub);
+            }
+            Float(f) => {
+                self.byte(33);
+                self.number(f.to_bits());
+            }
+            MakeArray(ref location, ref left, ref right)
+            | Index(ref location, ref left, ref right) => {
+                self.byte(if let MakeArray(_, _, _) = *expr {
+                    34
+                } else {
+                    35
+                });
+                self.location(location);
+                self.expr(left);
+                self.expr(right);
+            }
+            Update(ref location, ref left, ref index, ref right) => {
+                self.byte(36);
+                self.location(location);
+                self.expr(left);
+                self.expr(index);
+                self.expr(right);
+            }
+            Nil => self.byte(37),
+            Cons(ref left, ref right) => {
+                self.byte(38);
+                self.expr(left);
+                self.expr(right);
+            }
+            Head(ref location, ref sub) | Tail(ref location, ref sub) => {
+                self.byte(if let Head(_, _) = *expr { 39 } else { 40 });
+                self.location(location);
+                self.expr(sub);
+            }
+            IsEmpty(ref sub) => {
+                self.byte(41);
+                self.expr(sub);
+            }
+            Expect(ref sub, likely) => {
+                self.byte(42);
+                self.expr(sub);
+                self.byte(likely as u8);
+            }
+            LetRec(ref functions, ref body) => {
+                self.byte(43);
+                self.number(functions.len() as u64);
+                for (f, (v, lambda)) in functions.iter() {
+                    self.string(f);
+                    self.string(v);
+                    self.expr(lambda);
+                }
+                self.expr(body);
+            }
+            // '[@inline]' and '[@noinline]' are written as 1 and 2
+            Attributed(attributes, ref sub) => {
+                self.byte(44);
+                self.byte(match attributes.inline {
+                    None => 0,
+                    Some(true) => 1,
+                    Some(false) => 2,
+                });
+                self.byte(attributes.size as u8);
+                self.expr(sub);
+            }
+            Raise(ref location, ref sub) => {
+                self.byte(45);
+                self.location(location);
+                self.expr(sub);
+            }
+            Try(ref sub, (ref v, ref handler)) => {
+                self.byte(46);
+                self.expr(sub);
+                self.string(v);
+                self.expr(handler);
+            }
+            For(ref from, ref to, (ref v, ref body)) => {
+                self.byte(47);
+                self.expr(from);
+                self.expr(to);
+                self.string(v);
+                self.expr(body);
+            }
+        }
+    }
+
+    // the code a closure or a lazy value runs, which is only written out once
+    // however many of them share it
+    fn code(&mut self, expr: &Expr) {
+        let key = expr as *const Expr as usize;
+        match self.exprs.get(&key) {
+            Some(&id) => {
+                self.byte(0);
+                self.number(id as u64);
+            }
+            None => {
+                self.byte(1);
+                self.expr(expr);
+                let id = self.exprs.len();
+                self.exprs.insert(key, id);
+            }
+        }
+    }
+
+    fn env(&mut self, env: &Env<'a>) {
+        let binding = match *env {
+            Some(ref binding) => binding,
+            None => return self.byte(0),
+        };
+        if let Some(&id) = self.bindings.get(&address(binding)) {
+            self.byte(1);
+            return self.number(id as u64);
+        }
+        self.byte(2);
+        self.string(binding.name);
+        self.value(&binding.value);
+        self.env(&binding.next);
+        let id = self.bindings.len();
+        self.bindings.insert(address(binding), id);
+    }
+
+    // a value that was already written, or a reference or lazy value (whose
+    // number is taken before anything else is written)
+    fn seen(&mut self, key: usize, cell: Option<&Value<'a>>) -> bool {
+        if let Some(&id) = self.values.get(&key) {
+            self.byte(10);
+            self.number(id as u64);
+            return true;
+        }
+        if let Some(cell) = cell {
+            let id = self.values.len();
+            self.values.insert(key, id);
+            self.cells.push((id, cell.clone()));
+        }
+        false
+    }
+
+    // numbers a value once everything inside it has been written (which is
+    // when it's rebuilt)
+    fn number_value(&mut self, key: usize) {
+        let id = self.values.len();
+        self.values.insert(key, id);
+    }
+
+    fn value(&mut self, value: &Value<'a>) {
+        use self::Value::*;
+        match *value {
+            Unit => self.byte(0),
+            Int(i) => {
+                self.byte(1);
+                self.number(i as u64);
+            }
+            Bool(b) => {
+                self.byte(2);
+                self.byte(b as u8);
+            }
+            Pair(ref pair) => {
+                if !self.seen(address(pair), None) {
+                    self.byte(3);
+                    self.value(&pair.0);
+                    self.value(&pair.1);
+                    self.number_value(address(pair));
+                }
+            }
+            Inl(ref sub, type_expr) | Inr(ref sub, type_expr) => {
+                if !self.seen(address(sub), None) {
+                    self.byte(if let Inl(_, _) = *value { 4 } else { 5 });
+                    self.value(sub);
+                    self.type_expr(type_expr);
+                    self.number_value(address(sub));
+                }
+            }
+            Ref(ref cell) => {
+                if !self.seen(address(cell), Some(value)) {
+                    self.byte(6);
+                }
+            }
+            Lazy(ref cell) => {
+                if !self.seen(address(cell), Some(value)) {
+                    self.byte(7);
+                }
+            }
+            // threads never outlive the entry that spawned them, so nothing
+            // can be waiting on a channel
+            Chan(ref channel) => {
+                if !self.seen(address(channel), None) {
+                    self.byte(8);
+                    self.number_value(address(channel));
+                }
+            }
+            // a function defined in a 'let rec' is written as the group and
+            // which of its functions it is
+            Fun(ref closure) if closure.group.is_some() => {
+                if !self.seen(address(closure), None) {
+                    let group = closure.group.unwrap();
+                    let index = match *group {
+                        Expr::LetRec(ref functions, _) => functions
+                            .iter()
+                            .position(|(_, (_, lambda))| std::ptr::eq(&**lambda, closure.body))
+                            .unwrap(),
+                        _ => unreachable!(),
+                    };
+                    self.byte(16);
+                    self.code(group);
+                    self.number(index as u64);
+                    self.env(&closure.env);
+                    self.number_value(address(closure));
+                }
+            }
+            Fun(ref closure) => {
+                if !self.seen(address(closure), None) {
+                    self.byte(9);
+                    match closure.name {
+                        Some(name) => {
+                            self.byte(1);
+                            self.string(name);
+                        }
+                        None => self.byte(0),
+                    }
+                    self.string(closure.param);
+                    self.code(closure.body);
+                    self.env(&closure.env);
+                    self.number_value(address(closure));
+                }
+            }
+            Str(ref string) => {
+                if !self.seen(address(string), None) {
+                    self.byte(11);
+                    self.string(string);
+                    self.number_value(address(string));
+                }
+            }
+            Float(f) => {
+                self.byte(12);
+                self.number(f.to_bits());
+            }
+            Array(ref cell) => {
+                if !self.seen(address(cell), Some(value)) {
+                    self.byte(13);
+                }
+            }
+            Nil => self.byte(14),
+            Cons(ref cell) => {
+                if !self.seen(address(cell), None) {
+                    self.byte(15);
+                    self.value(&cell.0);
+                    self.value(&cell.1);
+                    self.number_value(address(cell));
+                }
+            }
+        }
+    }
+
+    // what references, lazy values and arrays hold, which may reach more of
+    // them
+    fn cells(&mut self) {
+        while let Some((id, cell)) = self.cells.pop() {
+            self.byte(1);
+            self.number(id as u64);
+            match cell {
+                Value::Ref(ref cell) => {
+                    let value = cell.borrow().clone();
+                    self.value(&value);
+                }
+                Value::Lazy(ref cell) => match *cell.borrow() {
+                    Thunk::Delayed(ref env, expr) => {
+                        self.byte(0);
+                        self.env(env);
+                        self.code(expr);
+                    }
+                    // left behind by a computation that failed
+                    Thunk::Forcing => self.byte(1),
+                    Thunk::Forced(ref value) => {
+                        self.byte(2);
+                        self.value(value);
+                    }
+                },
+                Value::Array(ref cell) => {
+                    let elements = cell.borrow().clone();
+                    self.number(elements.len() as u64);
+                    for element in elements.iter() {
+                        self.value(element);
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        self.byte(0);
+    }
+}
+
+pub fn save(snapshot: &Snapshot) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.number(snapshot.types.len() as u64);
+    for (name, type_expr) in snapshot.types.iter() {
+        writer.string(name);
+        writer.type_expr(type_expr);
+    }
+    writer.number(snapshot.definitions.len() as u64);
+    for definition in snapshot.definitions.iter() {
+        writer.string(definition);
+    }
+    writer.env(&snapshot.values);
+    for scope in snapshot.scopes.iter() {
+        writer.env(scope);
+    }
+    writer.cells();
+    writer.bytes
+}
+
+// everything read back is leaked, as values refer to the code that built them
+// (and the REPL's values live as long as it does anyway)
+fn leak<T>(t: T) -> &'static T {
+    Box::leak(Box::new(t))
+}
+
+fn leak_str(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+// reads a snapshot back in the order it was written, numbering things as they
+// were numbered then (anything that doesn't make sense gives 'None')
+struct Reader<'b> {
+    bytes: &'b [u8],
+    values: Vec<Value<'static>>,
+    bindings: Vec<Rc<Binding<'static>>>,
+    exprs: Vec<&'static Expr>,
+}
+
+impl<'b> Reader<'b> {
+    fn byte(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.bytes.split_first()?;
+        self.bytes = rest;
+        Some(byte)
+    }
+
+    fn number(&mut self) -> Option<u64> {
+        if self.bytes.len() < 8 {
+            return None;
+        }
+        let (number, rest) = self.bytes.split_at(8);
+        self.bytes = rest;
+        let mut le = [0; 8];
+        le.copy_from_slice(number);
+        Some(u64::from_le_bytes(le))
+    }
+
+    fn index(&mut self) -> Option<usize> {
+        self.number().map(|n| n as usize)
+    }
+
+    fn boolean(&mut self) -> Option<bool> {
+        match self.byte()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.index()?;
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (s, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        String::from_utf8(s.to_vec()).ok()
+    }
+
+    fn boxed<T>(&mut self, read: fn(&mut Self) -> Option<T>) -> Option<Box<T>> {
+        read(self).map(Box::new)
+    }
+
+    fn type_expr(&mut self) -> Option<TypeExpr> {
+        use self::TypeExpr::*;
+        Some(match self.byte()? {
+            0 => Unit,
+            1 => Bool,
+            2 => Int,
+            3 => Ref(self.boxed(Self::type_expr)?),
+            4 => Lazy(self.boxed(Self::type_expr)?),
+            5 => Chan(self.boxed(Self::type_expr)?),
+            6 => Arrow(self.boxed(Self::type_expr)?, self.boxed(Self::type_expr)?),
+            7 => Product(self.boxed(Self::type_expr)?, self.boxed(Self::type_expr)?),
+            8 => Union(self.boxed(Self::type_expr)?, self.boxed(Self::type_expr)?),
+            9 => String,
+            10 => Float,
+            11 => Array(self.boxed(Self::type_expr)?),
+            12 => List(self.boxed(Self::type_expr)?),
+            13 => {
+                let mut variables = vec![];
+                for _ in 0..self.index()? {
+                    variables.push(self.string()?);
+                }
+                Forall(variables, self.boxed(Self::type_expr)?)
+            }
+            14 => Variable(self.string()?),
+            _ => return None,
+        })
+    }
+
+    fn location(&mut self) -> Option<Location> {
+        let filename = self.string()?;
+        let line = self.index()?;
+        Some(Location::new(filename, line, self.index()?))
+    }
+
+    // a chain is read back in a loop too (see 'Writer::expr')
+    fn expr(&mut self) -> Option<Expr> {
+        let mut links: Vec<ast::Link> = vec![];
+        loop {
+            let link = match self.byte()? {
+                6 => ast::Link::Op(*BINOPS.get(self.byte()? as usize)?),
+                7 => ast::Link::Div(self.location()?),
+                8 => ast::Link::Mod(self.location()?),
+                tag => {
+                    let mut expr = self.node(tag)?;
+                    for link in links.into_iter().rev() {
+                        expr = link.join(expr, self.expr()?);
+                    }
+                    return Some(expr);
+                }
+            };
+            links.push(link);
+        }
+    }
+
+    fn node(&mut self, tag: u8) -> Option<Expr> {
+        use self::Expr::*;
+        let expr = |reader: &mut Self| reader.boxed(Self::expr);
+        Some(match tag {
+            0 => Unit,
+            1 => What,
+            2 => Var(self.string()?),
+            3 => Int(self.number()? as i64),
+            4 => Bool(self.boolean()?),
+            5 => {
+                let op = match self.byte()? {
+                    0 => self::UnOp::Neg,
+                    1 => self::UnOp::Not,
+                    _ => return None,
+                };
+                UnOp(op, expr(self)?)
+            }
+            9 => If(expr(self)?, expr(self)?, expr(self)?),
+            10 => Pair(expr(self)?, expr(self)?),
+            11 => Fst(expr(self)?),
+            12 => Snd(expr(self)?),
+            13 => Inl(expr(self)?, self.type_expr()?),
+            14 => Inr(expr(self)?, self.type_expr()?),
+            15 => {
+                let sub = expr(self)?;
+                let left = (self.string()?, expr(self)?);
+                Case(sub, left, (self.string()?, expr(self)?))
+            }
+            16 => While(expr(self)?, expr(self)?),
+            17 => {
+                let len = self.index()?;
+                let mut seq = vec![];
+                for _ in 0..len {
+                    seq.push(self.expr()?);
+                }
+                Seq(seq)
+            }
+            18 => Ref(expr(self)?),
+            19 => Deref(expr(self)?),
+            20 => Lazy(expr(self)?),
+            21 => Force(self.location()?, expr(self)?),
+            22 => Chan,
+            23 => Spawn(expr(self)?),
+            24 => Send(self.location()?, expr(self)?, expr(self)?),
+            25 => Recv(self.location()?, expr(self)?),
+            26 => Assign(expr(self)?, expr(self)?),
+            27 => Lambda((self.string()?, expr(self)?)),
+            28 => App(expr(self)?, expr(self)?),
+            29 => Let(self.string()?, expr(self)?, expr(self)?),
+            30 => {
+                let f = self.string()?;
+                let lambda = (self.string()?, expr(self)?);
+                LetFun(f, lambda, expr(self)?)
+            }
+            31 => Str(self.string()?),
+            32 => Print(expr(self)?),
+            33 => Float(f64::from_bits(self.number()?)),
+            34 => MakeArray(self.location()?, expr(self)?, expr(self)?),
+            35 => Index(self.location()?, expr(self)?, expr(self)?),
+            36 => Update(self.location()?, expr(self)?, expr(self)?, expr(self)?),
+            37 => Nil,
+            38 => Cons(expr(self)?, expr(self)?),
+            39 => Head(self.location()?, expr(self)?),
+            40 => Tail(self.location()?, expr(self)?),
+            41 => IsEmpty(expr(self)?),
+            42 => Expect(expr(self)?, self.boolean()?),
+            43 => {
+                let len = self.index()?;
+                let mut functions = vec![];
+                for _ in 0..len {
+                    let f = self.string()?;
+                    functions.push((f, (self.string()?, expr(self)?)));
+                }
+                LetRec(functions, expr(self)?)
+            }
+            44 => {
+                let inline = match self.byte()? {
+                    0 => None,
+                    1 => Some(true),
+                    2 => Some(false),
+                    _ => return None,
+                };
+                let size = self.boolean()?;
+                Attributed(Attributes { inline, size }, expr(self)?)
+            }
+            45 => Raise(self.location()?, expr(self)?),
+            46 => Try(expr(self)?, (self.string()?, expr(self)?)),
+            47 => For(expr(self)?, expr(self)?, (self.string()?, expr(self)?)),
+            _ => return None,
+        })
+    }
+
+    fn code(&mut self) -> Option<&'static Expr> {
+        match self.byte()? {
+            0 => {
+                let id = self.index()?;
+                self.exprs.get(id).copied()
+            }
+            1 => {
+                let expr = leak(self.expr()?);
+                self.exprs.push(expr);
+                Some(expr)
+            }
+            _ => None,
+        }
+    }
+
+    fn env(&mut self) -> Option<Env<'static>> {
+        match self.byte()? {
+            0 => Some(None),
+            1 => {
+                let id = self.index()?;
+                self.bindings.get(id).cloned().map(Some)
+            }
+            2 => {
+                let name = leak_str(self.string()?);
+                let value = self.value()?;
+                let binding = Rc::new(Binding {
+                    name,
+                    value,
+                    next: self.env()?,
+                });
+                self.bindings.push(binding.clone());
+                Some(Some(binding))
+            }
+            _ => None,
+        }
+    }
+
+    fn numbered(&mut self, value: Value<'static>) -> Value<'static> {
+        self.values.push(value.clone());
+        value
+    }
+
+    fn value(&mut self) -> Option<Value<'static>> {
+        use self::Value::*;
+        let tag = self.byte()?;
+        Some(match tag {
+            0 => Unit,
+            1 => Int(self.number()? as i64),
+            2 => Bool(self.boolean()?),
+            3 => {
+                let left = self.value()?;
+                let pair = Pair(Rc::new((left, self.value()?)));
+                self.numbered(pair)
+            }
+            4 | 5 => {
+                let sub = Rc::new(self.value()?);
+                let type_expr = leak(self.type_expr()?);
+                self.numbered(if tag == 4 {
+                    Inl(sub, type_expr)
+                } else {
+                    Inr(sub, type_expr)
+                })
+            }
+            6 => self.numbered(Ref(Rc::new(RefCell::new(Unit)))),
+            7 => self.numbered(Lazy(Rc::new(RefCell::new(Thunk::Forcing)))),
+            8 => self.numbered(Chan(Rc::new(Channel::default()))),
+            9 => {
+                let name = if self.boolean()? {
+                    Some(leak_str(self.string()?))
+                } else {
+                    None
+                };
+                let param = leak_str(self.string()?);
+                let body = self.code()?;
+                let closure = Fun(Rc::new(Closure {
+                    name,
+                    param,
+                    body,
+                    env: self.env()?,
+                    group: None,
+                }));
+                self.numbered(closure)
+            }
+            10 => {
+                let id = self.index()?;
+                self.values.get(id)?.clone()
+            }
+            11 => {
+                let string = Str(Rc::from(self.string()?));
+                self.numbered(string)
+            }
+            12 => Float(f64::from_bits(self.number()?)),
+            13 => self.numbered(Array(Rc::new(RefCell::new(vec![])))),
+            14 => Nil,
+            15 => {
+                let left = self.value()?;
+                let cell = Cons(Rc::new((left, self.value()?)));
+                self.numbered(cell)
+            }
+            16 => {
+                let group = self.code()?;
+                let (f, (v, lambda)) = match *group {
+                    Expr::LetRec(ref functions, _) => functions.get(self.index()?)?,
+                    _ => return None,
+                };
+                let closure = Fun(Rc::new(Closure {
+                    name: Some(f),
+                    param: v,
+                    body: lambda,
+                    env: self.env()?,
+                    group: Some(group),
+                }));
+                self.numbered(closure)
+            }
+            _ => return None,
+        })
+    }
+
+    fn cells(&mut self) -> Option<()> {
+        while self.boolean()? {
+            let id = self.index()?;
+            match self.values.get(id)?.clone() {
+                Value::Ref(cell) => *cell.borrow_mut() = self.value()?,
+                Value::Lazy(cell) => {
+                    *cell.borrow_mut() = match self.byte()? {
+                        0 => {
+                            let env = self.env()?;
+                            Thunk::Delayed(env, self.code()?)
+                        }
+                        1 => Thunk::Forcing,
+                        2 => Thunk::Forced(self.value()?),
+                        _ => return None,
+                    }
+                }
+                Value::Array(cell) => {
+                    let mut elements = vec![];
+                    for _ in 0..self.index()? {
+                        elements.push(self.value()?);
+                    }
+                    *cell.borrow_mut() = elements;
+                }
+                _ => return None,
+            }
+        }
+        Some(())
+    }
+}
+
+pub fn restore(bytes: &[u8]) -> Option<Snapshot<'static>> {
+    let mut reader = Reader {
+        bytes: bytes.strip_prefix(MAGIC)?,
+        values: vec![],
+        bindings: vec![],
+        exprs: vec![],
+    };
+    let mut types = vec![];
+    for _ in 0..reader.index()? {
+        let name = reader.string()?;
+        types.push((name, reader.type_expr()?));
+    }
+    let mut definitions = vec![];
+    for _ in 0..reader.index()? {
+        definitions.push(reader.string()?);
+    }
+    let values = reader.env()?;
+    let mut scopes = vec![];
+    for _ in 0..definitions.len() {
+        scopes.push(reader.env()?);
+    }
+    reader.cells()?;
+    if !reader.bytes.is_empty() {
+        return None;
+    }
+    Some(Snapshot {
+        types,
+        definitions,
+        values,
+        scopes,
+    })
+}
@@ -0,0 +1,182 @@
+use super::splitmix;
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+
+// the interpreter evaluates expressions on the host stack, which a thread has
+// to keep while it's waiting on a channel, so each thread a program spawns is
+// interpreted on a host thread of its own. They take turns, though: a thread
+// runs until it finishes or has to wait, and then the first of the threads
+// that are ready to run takes over (or, with a seed, one of them picked at
+// random). Only one of them ever runs at a time, so they can share values
+// without locking them, and a program always runs in the same order for the
+// same schedule (which is the order the reference evaluator runs it in)
+
+// the thread that runs the program itself
+pub const MAIN: usize = 0;
+
+// which of the threads that are ready to run takes over
+#[derive(Clone, Copy)]
+pub enum Schedule {
+    // the one that's been ready the longest
+    InOrder,
+    // one drawn from a splitmix64 generator with this state, so that a program
+    // can be tried in other orders that are still the same every time
+    Seeded(u64),
+}
+
+impl Schedule {
+    pub fn next(&mut self, ready: &mut VecDeque<usize>) -> Option<usize> {
+        match *self {
+            Schedule::InOrder => ready.pop_front(),
+            Schedule::Seeded(_) if ready.is_empty() => None,
+            Schedule::Seeded(ref mut state) => {
+                let n = splitmix(state) % ready.len() as u64;
+                ready.remove(n as usize)
+            }
+        }
+    }
+}
+
+// why a thread stopped waiting without getting its turn
+pub enum Interrupt {
+    // every thread is waiting (which the main thread reports)
+    Deadlock,
+    // another thread failed with this error (which the main thread reports)
+    Failed(String),
+    // the main thread has finished, so this one never gets to go on
+    Stopped,
+}
+
+struct State {
+    running: usize,
+    ready: VecDeque<usize>,
+    schedule: Schedule,
+    // set once the main thread has finished, so that the rest give up
+    stopping: bool,
+    // why the main thread was woken, if it wasn't by a channel
+    interrupt: Option<Interrupt>,
+    threads: Vec<(usize, JoinHandle<()>)>,
+    // the number the next thread gets (threads are never renumbered, so a
+    // thread left waiting by an earlier program can't be mistaken for a new one)
+    next: usize,
+}
+
+pub struct Scheduler {
+    state: Mutex<State>,
+    turn: Condvar,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            state: Mutex::new(State {
+                running: MAIN,
+                ready: VecDeque::new(),
+                schedule: Schedule::InOrder,
+                stopping: false,
+                interrupt: None,
+                threads: vec![],
+                next: MAIN + 1,
+            }),
+            turn: Condvar::new(),
+        }
+    }
+
+    pub fn set_schedule(&self, schedule: Schedule) {
+        self.state.lock().unwrap().schedule = schedule;
+    }
+
+    pub fn number(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.next += 1;
+        state.next - 1
+    }
+
+    // adds a newly spawned thread, which is ready to run
+    pub fn add(&self, thread: usize, handle: JoinHandle<()>) {
+        let mut state = self.state.lock().unwrap();
+        state.ready.push_back(thread);
+        state.threads.push((thread, handle));
+    }
+
+    // makes a waiting thread ready to run again
+    pub fn wake(&self, thread: usize) {
+        self.state.lock().unwrap().ready.push_back(thread);
+    }
+
+    fn hand_over(&self, state: &mut MutexGuard<State>, interrupt: Option<Interrupt>) {
+        let state = &mut **state;
+        match (interrupt, state.schedule.next(&mut state.ready)) {
+            (None, Some(next)) => state.running = next,
+            (None, None) => {
+                state.interrupt = Some(Interrupt::Deadlock);
+                state.running = MAIN;
+            }
+            (interrupt, _) => {
+                state.interrupt = interrupt;
+                state.running = MAIN;
+            }
+        }
+        self.turn.notify_all();
+    }
+
+    // waits until it's this thread's turn to run
+    pub fn wait_turn(&self, thread: usize) -> Result<(), Interrupt> {
+        let mut state = self.state.lock().unwrap();
+        while state.running != thread {
+            state = self.turn.wait(state).unwrap();
+        }
+        if state.stopping {
+            return Err(Interrupt::Stopped);
+        }
+        match state.interrupt.take() {
+            Some(interrupt) => Err(interrupt),
+            None => Ok(()),
+        }
+    }
+
+    // hands over to the next thread that's ready to run, then waits for this
+    // one to be woken and get its turn again
+    pub fn wait(&self, thread: usize) -> Result<(), Interrupt> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if thread == MAIN && state.ready.is_empty() {
+                return Err(Interrupt::Deadlock);
+            }
+            self.hand_over(&mut state, None);
+        }
+        self.wait_turn(thread)
+    }
+
+    // hands over once a spawned thread has finished (everything it was using
+    // has to have been dropped by now), ending the program if it failed
+    pub fn finish(&self, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        if !state.stopping {
+            self.hand_over(&mut state, error.map(Interrupt::Failed));
+        }
+    }
+
+    // once the main thread has finished, every other thread is given its turn
+    // in order to give up (so that they still run one at a time), and the
+    // scheduler is left ready for the next program
+    pub fn stop(&self) {
+        let threads = {
+            let mut state = self.state.lock().unwrap();
+            state.stopping = true;
+            std::mem::take(&mut state.threads)
+        };
+        for (thread, handle) in threads {
+            self.state.lock().unwrap().running = thread;
+            self.turn.notify_all();
+            let _ = handle.join();
+        }
+        let mut state = self.state.lock().unwrap();
+        state.running = MAIN;
+        state.ready.clear();
+        state.stopping = false;
+        state.interrupt = None;
+    }
+}
@@ -0,0 +1,130 @@
+use super::super::frontend::ast::Expr;
+use super::super::frontend::TypeExpr;
+use super::super::reflect;
+use super::Env;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+
+pub struct Closure<'a> {
+    // the name a recursive function can use to refer to itself
+    pub name: Option<&'a str>,
+    pub param: &'a str,
+    pub body: &'a Expr,
+    pub env: Env<'a>,
+    // the 'let rec' a function was defined in along with others, which are
+    // all in scope in its body
+    pub group: Option<&'a Expr>,
+}
+
+pub enum Thunk<'a> {
+    Delayed(Env<'a>, &'a Expr),
+    // being computed, so forcing it again would never finish
+    Forcing,
+    Forced(Value<'a>),
+}
+
+// where a thread waiting to receive from a channel is left what it receives
+pub type Slot<'a> = Rc<RefCell<Option<Value<'a>>>>;
+
+// the threads waiting to send on a channel (along with what they're sending),
+// or to receive from it
+#[derive(Default)]
+pub struct Channel<'a> {
+    pub senders: RefCell<VecDeque<(usize, Value<'a>)>>,
+    pub receivers: RefCell<VecDeque<(usize, Slot<'a>)>>,
+}
+
+// the elements of an array, which are written to in place
+pub type Elements<'a> = Rc<RefCell<Vec<Value<'a>>>>;
+
+// anything that lives on the heap in compiled code is behind an 'Rc' here, so
+// that '==' can compare it by address in the same way
+#[derive(Clone)]
+pub enum Value<'a> {
+    Unit,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(Rc<str>),
+    Pair(Rc<(Value<'a>, Value<'a>)>),
+    Inl(Rc<Value<'a>>, &'a TypeExpr),
+    Inr(Rc<Value<'a>>, &'a TypeExpr),
+    Ref(Rc<RefCell<Value<'a>>>),
+    Lazy(Rc<RefCell<Thunk<'a>>>),
+    Chan(Rc<Channel<'a>>),
+    Array(Elements<'a>),
+    Nil,
+    Cons(Rc<(Value<'a>, Value<'a>)>),
+    Fun(Rc<Closure<'a>>),
+}
+
+impl<'a> Value<'a> {
+    pub fn same(&self, other: &Value<'a>) -> bool {
+        use self::Value::*;
+        match (self, other) {
+            (Unit, Unit) | (Nil, Nil) => true,
+            (Int(a), Int(b)) => a == b,
+            (Float(a), Float(b)) => a == b,
+            (Bool(a), Bool(b)) => a == b,
+            (Str(a), Str(b)) => Rc::ptr_eq(a, b),
+            (Pair(a), Pair(b)) | (Cons(a), Cons(b)) => Rc::ptr_eq(a, b),
+            (Inl(a, _), Inl(b, _)) | (Inr(a, _), Inr(b, _)) => Rc::ptr_eq(a, b),
+            (Ref(a), Ref(b)) => Rc::ptr_eq(a, b),
+            (Lazy(a), Lazy(b)) => Rc::ptr_eq(a, b),
+            (Chan(a), Chan(b)) => Rc::ptr_eq(a, b),
+            (Array(a), Array(b)) => Rc::ptr_eq(a, b),
+            (Fun(a), Fun(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl<'a> From<&Value<'a>> for reflect::Value {
+    fn from(value: &Value<'a>) -> reflect::Value {
+        use self::Value::*;
+        match *value {
+            Unit => reflect::Value::Unit,
+            Int(i) => reflect::Value::Int(i),
+            Float(f) => reflect::Value::Float(f),
+            Bool(b) => reflect::Value::Bool(b),
+            Str(ref string) => reflect::Value::Str(string.to_string()),
+            Pair(ref pair) => {
+                reflect::Value::Pair(Box::new((&pair.0).into()), Box::new((&pair.1).into()))
+            }
+            Inl(ref value, type_expr) => {
+                reflect::Value::Inl(Box::new((&**value).into()), type_expr.into())
+            }
+            Inr(ref value, type_expr) => {
+                reflect::Value::Inr(Box::new((&**value).into()), type_expr.into())
+            }
+            Ref(ref value) => reflect::Value::Ref(Box::new((&*value.borrow()).into())),
+            Lazy(ref thunk) => match *thunk.borrow() {
+                Thunk::Forced(ref value) => reflect::Value::Lazy(Some(Box::new(value.into()))),
+                _ => reflect::Value::Lazy(None),
+            },
+            Chan(_) => reflect::Value::Chan,
+            Array(ref elements) => {
+                reflect::Value::Array(elements.borrow().iter().map(|value| value.into()).collect())
+            }
+            Nil | Cons(_) => {
+                let mut elements = vec![];
+                let mut list = value;
+                while let Cons(ref cell) = *list {
+                    elements.push((&cell.0).into());
+                    list = &cell.1;
+                }
+                reflect::Value::List(elements)
+            }
+            Fun(_) => reflect::Value::Fun,
+        }
+    }
+}
+
+impl<'a> fmt::Display for Value<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", reflect::Value::from(self))
+    }
+}
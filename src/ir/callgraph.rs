@@ -0,0 +1,407 @@
+use super::super::frontend::quote;
+use super::{source_name, Atom, Binding, Block, Op};
+
+use std::collections::HashMap;
+
+// how a function calls another
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Call {
+    // by name, from outside the callee
+    Direct,
+    // from inside the callee's own body
+    Recursive,
+    // through a value that isn't known to be any particular function (or, from
+    // the unknown node, a function that might be called that way)
+    HigherOrder,
+}
+
+impl Call {
+    fn name(self) -> &'static str {
+        match self {
+            Call::Direct => "direct",
+            Call::Recursive => "recursive",
+            Call::HigherOrder => "higher-order",
+        }
+    }
+}
+
+// the code outside every function
+const TOP_LEVEL: usize = 0;
+// whatever is called through a value that isn't known to be a function
+const UNKNOWN: usize = 1;
+
+// which function calls which in a program. A function of several arguments
+// (written as a function that returns a function) is one node, and a call
+// through a value that could be any function goes to the unknown node, which
+// in turn calls every function that's passed around as a value
+pub struct CallGraph {
+    names: Vec<String>,
+    edges: Vec<(usize, usize, Call)>,
+    // the node each function the program is compiled into belongs to, in the
+    // order they're reached (each argument of a function of several arguments
+    // gets a function of its own, as does each lazy value and thread)
+    functions: Vec<usize>,
+}
+
+// the most stack a call can take
+#[derive(Clone, Copy)]
+enum Usage {
+    Bounded(usize),
+    // as the call can go on calling the function at this node
+    Unbounded(usize),
+}
+
+impl CallGraph {
+    fn used(&self, node: usize) -> bool {
+        node != UNKNOWN
+            || self
+                .edges
+                .iter()
+                .any(|&(from, to, _)| from == UNKNOWN || to == UNKNOWN)
+    }
+
+    pub fn dot(&self) -> String {
+        let mut dot = "digraph callgraph {\n".to_string();
+        for (node, name) in self.names.iter().enumerate() {
+            if !self.used(node) {
+                continue;
+            }
+            let style = match node {
+                TOP_LEVEL => ", shape=box",
+                UNKNOWN => ", style=dashed",
+                _ => "",
+            };
+            dot.push_str(&format!("  n{} [label={}{}];\n", node, quote(name), style));
+        }
+        for &(from, to, call) in self.edges.iter() {
+            let style = match call {
+                Call::Direct => "",
+                Call::Recursive => " [label=\"recursive\"]",
+                Call::HigherOrder => " [style=dashed]",
+            };
+            dot.push_str(&format!("  n{} -> n{}{};\n", from, to, style));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn json(&self) -> String {
+        let nodes = self
+            .names
+            .iter()
+            .enumerate()
+            .filter(|&(node, _)| self.used(node))
+            .map(|(node, name)| format!("    {{\"id\": {}, \"name\": {}}}", node, quote(name)))
+            .collect::<Vec<_>>();
+        let edges = self
+            .edges
+            .iter()
+            .map(|&(from, to, call)| {
+                format!(
+                    "    {{\"from\": {}, \"to\": {}, \"kind\": \"{}\"}}",
+                    from,
+                    to,
+                    call.name()
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\n  \"nodes\": [\n{}\n  ],\n  \"edges\": [\n{}\n  ]\n}}\n",
+            nodes.join(",\n"),
+            edges.join(",\n")
+        )
+    }
+
+    fn usage(&self, node: usize, own: &[usize], usages: &mut [Option<Option<Usage>>]) -> Usage {
+        match usages[node] {
+            Some(Some(usage)) => return usage,
+            // the node is still being worked out, so it calls itself
+            Some(None) => return Usage::Unbounded(node),
+            None => usages[node] = Some(None),
+        }
+        let mut deepest = 0;
+        let mut usage = None;
+        for &(from, to, _) in self.edges.iter() {
+            if from != node {
+                continue;
+            }
+            match self.usage(to, own, usages) {
+                Usage::Bounded(depth) => deepest = deepest.max(depth),
+                unbounded => {
+                    usage = Some(unbounded);
+                    break;
+                }
+            }
+        }
+        let usage = usage.unwrap_or(Usage::Bounded(own[node] + deepest));
+        usages[node] = Some(Some(usage));
+        usage
+    }
+
+    // the most stack a call to each function (or the top level) can take, along
+    // with everything it calls, given how much each function the program is
+    // compiled into takes on its own (in the order they're reached). Calls into
+    // the runtime aren't counted
+    pub fn stack_usage(&self, frames: &[usize]) -> String {
+        assert_eq!(frames.len(), self.functions.len());
+        let mut own = vec![0; self.names.len()];
+        for (&node, &frame) in self.functions.iter().zip(frames) {
+            own[node] = own[node].max(frame);
+        }
+        let mut usages = vec![None; self.names.len()];
+        let mut report = String::new();
+        for (node, name) in self.names.iter().enumerate() {
+            if node == UNKNOWN {
+                continue;
+            }
+            let usage = match self.usage(node, &own, &mut usages) {
+                Usage::Bounded(depth) => format!("{} bytes", depth),
+                Usage::Unbounded(at) => {
+                    format!("unbounded due to recursion at '{}'", self.names[at])
+                }
+            };
+            report.push_str(&format!("{}: {}\n", name, usage));
+        }
+        report
+    }
+}
+
+// how many arguments a function takes before its body runs past the
+// functions it returns
+fn arity(mut body: &Block) -> usize {
+    let mut arity = 1;
+    while let Op::Lambda(_, ref inner) = body.result {
+        arity += 1;
+        body = inner;
+    }
+    arity
+}
+
+struct Builder {
+    graph: CallGraph,
+    arities: Vec<usize>,
+    // the function each variable is known to hold, and how many of its
+    // arguments it's already been given
+    known: HashMap<String, (usize, usize)>,
+    // the functions whose bodies are being walked, innermost last
+    enclosing: Vec<usize>,
+}
+
+impl Builder {
+    fn edge(&mut self, from: usize, to: usize, call: Call) {
+        if !self.graph.edges.contains(&(from, to, call)) {
+            self.graph.edges.push((from, to, call));
+        }
+    }
+
+    fn node(&mut self, v: Option<&str>, body: &Block) -> usize {
+        // temporaries are only ever named by lowering
+        let name = match v {
+            Some(v) if !v.starts_with('%') => source_name(v),
+            _ => "fun",
+        };
+        self.graph.names.push(name.to_string());
+        self.arities.push(arity(body));
+        self.graph.names.len() - 1
+    }
+
+    // a function value that's used as anything but the function of a call can
+    // be called from anywhere
+    fn escape(&mut self, atom: &Atom) {
+        if let Atom::Var(ref v) = *atom {
+            if let Some(&(node, _)) = self.known.get(v) {
+                self.edge(UNKNOWN, node, Call::HigherOrder);
+            }
+        }
+    }
+
+    fn function(&mut self, node: usize, mut body: &Block) {
+        self.enclosing.push(node);
+        self.graph.functions.push(node);
+        while let Op::Lambda(_, ref inner) = body.result {
+            self.bindings(&body.bindings, node);
+            self.graph.functions.push(node);
+            body = inner;
+        }
+        self.block(body, node);
+        self.enclosing.pop();
+    }
+
+    fn call(&mut self, callee: &Atom, current: usize) -> Option<(usize, usize)> {
+        let known = match *callee {
+            Atom::Var(ref v) => self.known.get(v).cloned(),
+            _ => None,
+        };
+        match known {
+            Some((node, given)) => {
+                let call = if self.enclosing.contains(&node) {
+                    Call::Recursive
+                } else {
+                    Call::Direct
+                };
+                self.edge(current, node, call);
+                if given + 1 < self.arities[node] {
+                    Some((node, given + 1))
+                } else {
+                    None
+                }
+            }
+            None => {
+                self.edge(current, UNKNOWN, Call::HigherOrder);
+                None
+            }
+        }
+    }
+
+    // the function an operation's value is known to be (if it is one)
+    fn op(&mut self, op: &Op, current: usize) -> Option<(usize, usize)> {
+        use self::Op::*;
+        match *op {
+            Atom(super::Atom::Var(ref v)) => self.known.get(v).cloned(),
+            Atom(_) | What | Chan | Str(_) | Nil => None,
+            App(ref callee, ref argument) => {
+                self.escape(argument);
+                self.call(callee, current)
+            }
+            // both are called on behalf of the code running the 'try'
+            Try(ref body, ref handler) => {
+                self.call(body, current);
+                self.call(handler, current);
+                None
+            }
+            If(ref condition, ref left, ref right, _) => {
+                self.escape(condition);
+                self.block(left, current);
+                self.block(right, current);
+                None
+            }
+            Case(ref sub, (_, ref left), (_, ref right)) => {
+                self.escape(sub);
+                self.block(left, current);
+                self.block(right, current);
+                None
+            }
+            While(ref condition, ref body) => {
+                self.block(condition, current);
+                self.block(body, current);
+                None
+            }
+            For(ref from, ref to, (_, ref body)) => {
+                self.escape(from);
+                self.escape(to);
+                self.block(body, current);
+                None
+            }
+            // these run later (or on another thread), but on behalf of the code
+            // that made them
+            Lazy(ref block) | Spawn(ref block) => {
+                self.graph.functions.push(current);
+                self.block(block, current);
+                None
+            }
+            Lambda(_, ref body) => {
+                let node = self.node(None, body);
+                self.function(node, body);
+                Some((node, 0))
+            }
+            Neg(ref atom)
+            | Not(ref atom)
+            | Fst(ref atom)
+            | Snd(ref atom)
+            | Inl(ref atom)
+            | Inr(ref atom)
+            | Ref(ref atom)
+            | Deref(ref atom)
+            | Force(_, ref atom)
+            | Recv(_, ref atom)
+            | Raise(_, ref atom)
+            | Head(_, ref atom)
+            | Tail(_, ref atom)
+            | IsEmpty(ref atom)
+            | Print(ref atom) => {
+                self.escape(atom);
+                None
+            }
+            Binary(_, ref left, ref right)
+            | Div(_, ref left, ref right)
+            | Mod(_, ref left, ref right)
+            | Pair(ref left, ref right)
+            | Assign(ref left, ref right)
+            | MakeArray(_, ref left, ref right)
+            | Index(_, ref left, ref right)
+            | Cons(ref left, ref right)
+            | Send(_, ref left, ref right) => {
+                self.escape(left);
+                self.escape(right);
+                None
+            }
+            Update(_, ref left, ref index, ref right) => {
+                self.escape(left);
+                self.escape(index);
+                self.escape(right);
+                None
+            }
+        }
+    }
+
+    fn bindings(&mut self, bindings: &[Binding], current: usize) {
+        for binding in bindings.iter() {
+            match *binding {
+                Binding::Let(ref v, Op::Lambda(_, ref body))
+                | Binding::LetFun(ref v, _, ref body) => {
+                    let node = self.node(Some(v), body);
+                    // a function is in scope in its own body
+                    self.known.insert(v.clone(), (node, 0));
+                    self.function(node, body);
+                }
+                // every function in the group is in scope in all of their
+                // bodies
+                Binding::LetRec(ref functions) => {
+                    let nodes = functions
+                        .iter()
+                        .map(|(f, _, body)| {
+                            let node = self.node(Some(f), body);
+                            self.known.insert(f.clone(), (node, 0));
+                            node
+                        })
+                        .collect::<Vec<_>>();
+                    for (node, (_, _, body)) in nodes.into_iter().zip(functions) {
+                        self.function(node, body);
+                    }
+                }
+                Binding::Let(ref v, ref op) => {
+                    if let Some(known) = self.op(op, current) {
+                        self.known.insert(v.clone(), known);
+                    }
+                }
+                Binding::Do(ref op) => {
+                    self.op(op, current);
+                }
+            }
+        }
+    }
+
+    fn block(&mut self, block: &Block, current: usize) {
+        self.bindings(&block.bindings, current);
+        // a function a block gives as its value is passed on to whatever
+        // runs it
+        if let Some((node, _)) = self.op(&block.result, current) {
+            self.edge(UNKNOWN, node, Call::HigherOrder);
+        }
+    }
+}
+
+pub fn callgraph(program: &Block) -> CallGraph {
+    let mut builder = Builder {
+        graph: CallGraph {
+            names: vec!["(top level)".to_string(), "(unknown)".to_string()],
+            edges: vec![],
+            functions: vec![TOP_LEVEL],
+        },
+        arities: vec![0, 0],
+        known: HashMap::new(),
+        enclosing: vec![],
+    };
+    builder.block(program, TOP_LEVEL);
+    builder.graph
+}
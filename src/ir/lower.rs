@@ -0,0 +1,459 @@
+use super::super::frontend::ast::{self, Expr};
+use super::{Atom, Binding, Block, Op};
+
+use std::collections::HashMap;
+
+type Step = Box<dyn FnOnce(&mut Lowering)>;
+
+enum Work {
+    Lower(Expr),
+    Step(Step),
+}
+
+// an expression is lowered as a sequence of sub-expressions that still need to
+// be lowered, interleaved with steps that put together what they give (this
+// lets 'Lowering::block' use an explicit stack rather than recursing, so a
+// long chain like 'a + b + c + ...' can be as long as it likes)
+struct Sequence(Vec<Work>);
+
+impl Sequence {
+    fn new() -> Sequence {
+        Sequence(vec![])
+    }
+
+    // the operation that gives an expression its value, after the bindings it
+    // needs first (which are added to the innermost block in the order they're
+    // run), is left on 'ops'
+    fn op(mut self, expr: Expr) -> Sequence {
+        self.0.push(Work::Lower(expr));
+        self
+    }
+
+    // as 'op', but naming the operation if it isn't already an atom
+    fn atom(self, expr: Expr) -> Sequence {
+        self.op(expr).then(|lowering| {
+            let op = lowering.ops.pop().unwrap();
+            let atom = match op {
+                Op::Atom(atom) => atom,
+                op => {
+                    let t = lowering.fresh();
+                    lowering.push(Binding::Let(t.clone(), op));
+                    Atom::Var(t)
+                }
+            };
+            lowering.ops.push(Op::Atom(atom));
+        })
+    }
+
+    // as 'op', but with the bindings in a block of their own, which is left on
+    // 'blocks'
+    fn block(self, expr: Expr) -> Sequence {
+        self.then(|lowering| lowering.bindings.push(vec![]))
+            .op(expr)
+            .then(|lowering| {
+                let result = lowering.ops.pop().unwrap();
+                let bindings = lowering.bindings.pop().unwrap();
+                lowering.blocks.push(Block { bindings, result });
+            })
+    }
+
+    // as 'block', but with a variable in scope, whose new name is left on
+    // 'names'
+    fn scoped(self, v: String, expr: Expr) -> Sequence {
+        self.then(move |lowering| {
+            let v = lowering.bind(v);
+            lowering.names.push(v);
+        })
+        .block(expr)
+        .then(|lowering| lowering.unbind(1))
+    }
+
+    fn then<F>(mut self, step: F) -> Sequence
+    where
+        F: FnOnce(&mut Lowering) + 'static,
+    {
+        self.0.push(Work::Step(Box::new(step)));
+        self
+    }
+}
+
+// names everything a program computes along the way, and renames variables so
+// that each is only bound once (a variable keeps its name the first time it's
+// bound, and '%' can't appear in an identifier, so renamed variables never
+// capture anything)
+struct Lowering {
+    // what each variable in scope has been renamed to
+    env: Vec<(String, String)>,
+    // how many times each name has been bound so far
+    bound: HashMap<String, usize>,
+    fresh: usize,
+    // what the sequences lowered so far have given, for the steps after them
+    // to put together
+    ops: Vec<Op>,
+    blocks: Vec<Block>,
+    names: Vec<String>,
+    // the bindings of each block that's still being lowered (the innermost
+    // last)
+    bindings: Vec<Vec<Binding>>,
+}
+
+impl Lowering {
+    fn fresh(&mut self) -> String {
+        self.fresh += 1;
+        format!("%t{}", self.fresh)
+    }
+
+    fn bind(&mut self, v: String) -> String {
+        let count = self.bound.entry(v.clone()).or_insert(0);
+        *count += 1;
+        let renamed = if *count == 1 {
+            v.clone()
+        } else {
+            format!("{}%{}", v, count)
+        };
+        self.env.push((v, renamed.clone()));
+        renamed
+    }
+
+    // takes the last 'n' variables bound back out of scope
+    fn unbind(&mut self, n: usize) {
+        self.env.truncate(self.env.len() - n);
+    }
+
+    fn lookup(&self, v: &str) -> String {
+        // the program has already been checked, so every variable is in scope
+        self.env
+            .iter()
+            .rev()
+            .find(|(envv, _)| envv == v)
+            .map(|(_, renamed)| renamed.clone())
+            .unwrap()
+    }
+
+    fn push(&mut self, binding: Binding) {
+        self.bindings.last_mut().unwrap().push(binding);
+    }
+
+    // the atom left by 'Sequence::atom'
+    fn atom(&mut self) -> Atom {
+        match self.ops.pop() {
+            Some(Op::Atom(atom)) => atom,
+            _ => unreachable!(),
+        }
+    }
+
+    fn block(&mut self) -> Box<Block> {
+        Box::new(self.blocks.pop().unwrap())
+    }
+
+    fn scoped(&mut self) -> (String, Box<Block>) {
+        let block = self.block();
+        (self.names.pop().unwrap(), block)
+    }
+
+    fn lower(&mut self, expr: Expr) -> Block {
+        let mut work = Sequence::new().block(expr).0;
+        work.reverse();
+        while let Some(next) = work.pop() {
+            match next {
+                Work::Lower(expr) => work.extend(self.op(expr).0.into_iter().rev()),
+                Work::Step(step) => step(self),
+            }
+        }
+        *self.block()
+    }
+
+    // the sequence that leaves the operation giving an expression its value on
+    // 'ops'
+    fn op(&mut self, expr: Expr) -> Sequence {
+        use self::Expr::*;
+        match expr {
+            Unit => value(Op::Atom(Atom::Unit)),
+            Int(i) => value(Op::Atom(Atom::Int(i))),
+            Float(f) => value(Op::Atom(Atom::Float(f))),
+            Bool(b) => value(Op::Atom(Atom::Bool(b))),
+            Var(v) => value(Op::Atom(Atom::Var(self.lookup(&v)))),
+            What => value(Op::What),
+            Str(string) => value(Op::Str(string)),
+            Print(sub) => unary(*sub, Op::Print),
+            UnOp(ast::UnOp::Neg, sub) => unary(*sub, Op::Neg),
+            UnOp(ast::UnOp::Not, sub) => unary(*sub, Op::Not),
+            BinOp(ast::BinOp::And, left, right) => {
+                let (left, likely) = condition(*left);
+                Sequence::new()
+                    .atom(left)
+                    .block(*right)
+                    .then(move |lowering| {
+                        let right = lowering.block();
+                        let left = lowering.atom();
+                        lowering.ops.push(Op::If(
+                            left,
+                            right,
+                            Box::new(constant(Atom::Bool(false))),
+                            likely,
+                        ));
+                    })
+            }
+            BinOp(ast::BinOp::Or, left, right) => {
+                let (left, likely) = condition(*left);
+                Sequence::new()
+                    .atom(left)
+                    .block(*right)
+                    .then(move |lowering| {
+                        let right = lowering.block();
+                        let left = lowering.atom();
+                        lowering.ops.push(Op::If(
+                            left,
+                            Box::new(constant(Atom::Bool(true))),
+                            right,
+                            likely,
+                        ));
+                    })
+            }
+            BinOp(op, left, right) => binary(*left, *right, move |left, right| {
+                Op::Binary(op, left, right)
+            }),
+            Div(location, left, right) => binary(*left, *right, move |left, right| {
+                Op::Div(location, left, right)
+            }),
+            Mod(location, left, right) => binary(*left, *right, move |left, right| {
+                Op::Mod(location, left, right)
+            }),
+            If(condition_, left, right) => {
+                let (condition_, likely) = condition(*condition_);
+                Sequence::new()
+                    .atom(condition_)
+                    .block(*left)
+                    .block(*right)
+                    .then(move |lowering| {
+                        let right = lowering.block();
+                        let left = lowering.block();
+                        let condition = lowering.atom();
+                        lowering.ops.push(Op::If(condition, left, right, likely));
+                    })
+            }
+            // only a hint for an 'if' (or '&&' or '||') that branches on it
+            Expect(sub, _) => Sequence::new().op(*sub),
+            // only a hint for the optimiser, which is done by now
+            Attributed(_, sub) => Sequence::new().op(*sub),
+            Pair(left, right) => binary(*left, *right, Op::Pair),
+            Fst(sub) => unary(*sub, Op::Fst),
+            Snd(sub) => unary(*sub, Op::Snd),
+            Inl(sub, _) => unary(*sub, Op::Inl),
+            Inr(sub, _) => unary(*sub, Op::Inr),
+            Case(sub, (x, left), (y, right)) => Sequence::new()
+                .atom(*sub)
+                .scoped(x, *left)
+                .scoped(y, *right)
+                .then(|lowering| {
+                    let right = lowering.scoped();
+                    let left = lowering.scoped();
+                    let sub = lowering.atom();
+                    lowering.ops.push(Op::Case(sub, left, right));
+                }),
+            While(condition, body) => {
+                Sequence::new()
+                    .block(*condition)
+                    .block(*body)
+                    .then(|lowering| {
+                        let body = lowering.block();
+                        let condition = lowering.block();
+                        lowering.ops.push(Op::While(condition, body));
+                    })
+            }
+            For(from, to, (v, body)) => Sequence::new()
+                .atom(*from)
+                .atom(*to)
+                .scoped(v, *body)
+                .then(|lowering| {
+                    let body = lowering.scoped();
+                    let to = lowering.atom();
+                    let from = lowering.atom();
+                    lowering.ops.push(Op::For(from, to, body));
+                }),
+            Seq(seq) => {
+                if seq.is_empty() {
+                    return value(Op::Atom(Atom::Unit));
+                }
+                let last = seq.len() - 1;
+                let mut sequence = Sequence::new();
+                for (i, sub) in seq.into_iter().enumerate() {
+                    sequence = sequence.op(sub);
+                    if i < last {
+                        sequence = sequence.then(|lowering| {
+                            match lowering.ops.pop().unwrap() {
+                                // an atom has no effects to run
+                                Op::Atom(_) => (),
+                                op => lowering.push(Binding::Do(op)),
+                            }
+                        });
+                    }
+                }
+                sequence
+            }
+            Ref(sub) => unary(*sub, Op::Ref),
+            Deref(sub) => unary(*sub, Op::Deref),
+            Assign(left, right) => binary(*left, *right, Op::Assign),
+            MakeArray(location, length, sub) => binary(*length, *sub, move |length, sub| {
+                Op::MakeArray(location, length, sub)
+            }),
+            Index(location, left, right) => binary(*left, *right, move |left, right| {
+                Op::Index(location, left, right)
+            }),
+            Update(location, left, index, right) => Sequence::new()
+                .atom(*left)
+                .atom(*index)
+                .atom(*right)
+                .then(move |lowering| {
+                    let right = lowering.atom();
+                    let index = lowering.atom();
+                    let left = lowering.atom();
+                    lowering.ops.push(Op::Update(location, left, index, right));
+                }),
+            Nil => value(Op::Nil),
+            Cons(left, right) => binary(*left, *right, Op::Cons),
+            Head(location, sub) => unary(*sub, move |sub| Op::Head(location, sub)),
+            Tail(location, sub) => unary(*sub, move |sub| Op::Tail(location, sub)),
+            IsEmpty(sub) => unary(*sub, Op::IsEmpty),
+            Lazy(sub) => Sequence::new().block(*sub).then(|lowering| {
+                let block = lowering.block();
+                lowering.ops.push(Op::Lazy(block));
+            }),
+            Force(location, sub) => unary(*sub, move |sub| Op::Force(location, sub)),
+            Chan => value(Op::Chan),
+            Spawn(sub) => Sequence::new().block(*sub).then(|lowering| {
+                let block = lowering.block();
+                lowering.ops.push(Op::Spawn(block));
+            }),
+            Send(location, left, right) => binary(*left, *right, move |left, right| {
+                Op::Send(location, left, right)
+            }),
+            Recv(location, sub) => unary(*sub, move |sub| Op::Recv(location, sub)),
+            Raise(location, sub) => unary(*sub, move |sub| Op::Raise(location, sub)),
+            // the body is made into a function (of '()') as well as the
+            // handler, so that whatever runs the 'try' can call either
+            Try(sub, handler) => {
+                let unit = self.fresh();
+                binary(Lambda((unit, sub)), Lambda(handler), Op::Try)
+            }
+            Lambda((v, body)) => Sequence::new().scoped(v, *body).then(|lowering| {
+                let (v, body) = lowering.scoped();
+                lowering.ops.push(Op::Lambda(v, body));
+            }),
+            App(left, right) => binary(*left, *right, Op::App),
+            // the variable is only in scope in the body, but as nothing is
+            // shadowed, its binding can go in the same block
+            Let(v, sub, body) => Sequence::new()
+                .op(*sub)
+                .then(move |lowering| {
+                    let op = lowering.ops.pop().unwrap();
+                    let v = lowering.bind(v);
+                    lowering.push(Binding::Let(v, op));
+                })
+                .op(*body)
+                .then(|lowering| lowering.unbind(1)),
+            LetFun(f, (v, lambda), body) => Sequence::new()
+                .then(move |lowering| {
+                    let f = lowering.bind(f);
+                    lowering.names.push(f);
+                })
+                .scoped(v, *lambda)
+                .then(|lowering| {
+                    let (v, lambda) = lowering.scoped();
+                    let f = lowering.names.pop().unwrap();
+                    lowering.push(Binding::LetFun(f, v, lambda));
+                })
+                .op(*body)
+                .then(|lowering| lowering.unbind(1)),
+            LetRec(functions, body) => {
+                let n = functions.len();
+                let names = functions.iter().map(|(f, _)| f.clone()).collect::<Vec<_>>();
+                let mut sequence = Sequence::new().then(move |lowering| {
+                    for f in names {
+                        let f = lowering.bind(f);
+                        lowering.names.push(f);
+                    }
+                });
+                for (_, (v, lambda)) in functions {
+                    sequence = sequence.scoped(v, *lambda);
+                }
+                sequence
+                    .then(move |lowering| {
+                        let mut lambdas = (0..n).map(|_| lowering.scoped()).collect::<Vec<_>>();
+                        lambdas.reverse();
+                        let names = lowering.names.split_off(lowering.names.len() - n);
+                        let functions = names
+                            .into_iter()
+                            .zip(lambdas)
+                            .map(|(f, (v, lambda))| (f, v, lambda))
+                            .collect();
+                        lowering.push(Binding::LetRec(functions));
+                    })
+                    .op(*body)
+                    .then(move |lowering| lowering.unbind(n))
+            }
+        }
+    }
+}
+
+fn value(op: Op) -> Sequence {
+    Sequence::new().then(|lowering| lowering.ops.push(op))
+}
+
+fn unary<F>(sub: Expr, op: F) -> Sequence
+where
+    F: FnOnce(Atom) -> Op + 'static,
+{
+    Sequence::new().atom(sub).then(|lowering| {
+        let sub = lowering.atom();
+        lowering.ops.push(op(sub));
+    })
+}
+
+fn binary<F>(left: Expr, right: Expr, op: F) -> Sequence
+where
+    F: FnOnce(Atom, Atom) -> Op + 'static,
+{
+    Sequence::new().atom(left).atom(right).then(|lowering| {
+        let right = lowering.atom();
+        let left = lowering.atom();
+        lowering.ops.push(op(left, right));
+    })
+}
+
+// what an 'if' branches on, and which way the program expects it to go (if it
+// says, with 'expect')
+fn condition(expr: Expr) -> (Expr, Option<bool>) {
+    match expr {
+        Expr::Expect(sub, likely) => (*sub, Some(likely)),
+        expr => (expr, None),
+    }
+}
+
+fn constant(atom: Atom) -> Block {
+    Block {
+        bindings: vec![],
+        result: Op::Atom(atom),
+    }
+}
+
+// the name a variable was given in the source, before it was renamed
+pub fn source_name(v: &str) -> &str {
+    match v.find('%') {
+        Some(i) if i > 0 => &v[..i],
+        _ => v,
+    }
+}
+
+pub fn lower(expr: Expr) -> Block {
+    Lowering {
+        env: vec![],
+        bound: HashMap::new(),
+        fresh: 0,
+        ops: vec![],
+        blocks: vec![],
+        names: vec![],
+        bindings: vec![],
+    }
+    .lower(expr)
+}
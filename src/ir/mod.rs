@@ -0,0 +1,431 @@
+use super::frontend::ast::{BinOp, Free};
+use super::frontend::{float_literal, quote, Location};
+
+use std::collections::HashSet;
+use std::fmt;
+
+mod callgraph;
+mod lower;
+
+pub use self::callgraph::callgraph;
+pub use self::lower::{lower, source_name};
+
+type Var = String;
+
+// something that's already been computed (or never needs to be), which an
+// operation can use directly
+#[derive(Clone)]
+pub enum Atom {
+    Unit,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Var(Var),
+}
+
+// a single step of a program, whose operands are all atoms (anything that runs
+// code of its own, like a function body or the branches of an 'if', is a block)
+#[derive(Clone)]
+pub enum Op {
+    Atom(Atom),
+    What,
+    Neg(Atom),
+    Not(Atom),
+    // never '&&' or '||', which only run their right operand sometimes (so
+    // they're lowered to 'if')
+    Binary(BinOp, Atom, Atom),
+    Div(Location, Atom, Atom),
+    Mod(Location, Atom, Atom),
+    // with whether the condition is expected to be true, if the program says
+    // (with 'expect')
+    If(Atom, Box<Block>, Box<Block>, Option<bool>),
+    Pair(Atom, Atom),
+    Fst(Atom),
+    Snd(Atom),
+    Inl(Atom),
+    Inr(Atom),
+    Case(Atom, (Var, Box<Block>), (Var, Box<Block>)),
+    // the condition is a block, as it's run again before each iteration
+    While(Box<Block>, Box<Block>),
+    // runs the block with the variable bound to each int from the first atom
+    // up to the second in turn
+    For(Atom, Atom, (Var, Box<Block>)),
+    Ref(Atom),
+    Deref(Atom),
+    Assign(Atom, Atom),
+    // arrays trap if they'd have a negative length, or if an index is out of
+    // bounds
+    MakeArray(Location, Atom, Atom),
+    Index(Location, Atom, Atom),
+    Update(Location, Atom, Atom, Atom),
+    // taking the head or tail of an empty list traps
+    Nil,
+    Cons(Atom, Atom),
+    Head(Location, Atom),
+    Tail(Location, Atom),
+    IsEmpty(Atom),
+    Lazy(Box<Block>),
+    Force(Location, Atom),
+    Chan,
+    Spawn(Box<Block>),
+    Send(Location, Atom, Atom),
+    Recv(Location, Atom),
+    // an exception goes to the handler of the nearest 'try' that's running,
+    // and stops the program where it was raised if there isn't one
+    Raise(Location, Atom),
+    // calls the body (a function of '()'), and if that raises an exception,
+    // calls the handler with it instead
+    Try(Atom, Atom),
+    Lambda(Var, Box<Block>),
+    App(Atom, Atom),
+    // a new string, made each time it's run
+    Str(String),
+    Print(Atom),
+}
+
+#[derive(Clone)]
+pub enum Binding {
+    Let(Var, Op),
+    // the function is in scope in its own body
+    LetFun(Var, Var, Box<Block>),
+    // every function is in scope in all of their bodies
+    LetRec(Vec<(Var, Var, Box<Block>)>),
+    // only run for its effects
+    Do(Op),
+}
+
+// a sequence of bindings, each in scope for the rest of the block, then the
+// operation that gives the block its value (every variable is bound exactly
+// once in a program, so nothing is ever shadowed)
+#[derive(Clone)]
+pub struct Block {
+    pub bindings: Vec<Binding>,
+    pub result: Op,
+}
+
+impl Free for Atom {
+    fn fv(&self) -> HashSet<&Var> {
+        let mut fv = HashSet::new();
+        if let Atom::Var(ref v) = *self {
+            fv.insert(v);
+        }
+        fv
+    }
+}
+
+fn atoms<'a>(atoms: &[&'a Atom]) -> HashSet<&'a Var> {
+    atoms.iter().flat_map(|atom| atom.fv()).collect()
+}
+
+fn scoped<'a>(v: &Var, block: &'a Block) -> HashSet<&'a Var> {
+    let mut fv = block.fv();
+    fv.remove(v);
+    fv
+}
+
+impl Free for Op {
+    fn fv(&self) -> HashSet<&Var> {
+        use self::Op::*;
+        match *self {
+            What | Chan | Str(_) | Nil => HashSet::new(),
+            Atom(ref atom)
+            | Neg(ref atom)
+            | Not(ref atom)
+            | Fst(ref atom)
+            | Snd(ref atom)
+            | Inl(ref atom)
+            | Inr(ref atom)
+            | Ref(ref atom)
+            | Deref(ref atom)
+            | Force(_, ref atom)
+            | Recv(_, ref atom)
+            | Raise(_, ref atom)
+            | Head(_, ref atom)
+            | Tail(_, ref atom)
+            | IsEmpty(ref atom)
+            | Print(ref atom) => atom.fv(),
+            Binary(_, ref left, ref right)
+            | Div(_, ref left, ref right)
+            | Mod(_, ref left, ref right)
+            | Pair(ref left, ref right)
+            | Assign(ref left, ref right)
+            | Send(_, ref left, ref right)
+            | MakeArray(_, ref left, ref right)
+            | Index(_, ref left, ref right)
+            | Cons(ref left, ref right)
+            | Try(ref left, ref right)
+            | App(ref left, ref right) => atoms(&[left, right]),
+            Update(_, ref left, ref index, ref right) => atoms(&[left, index, right]),
+            If(ref condition, ref left, ref right, _) => {
+                let mut fv = condition.fv();
+                fv.extend(left.fv());
+                fv.extend(right.fv());
+                fv
+            }
+            Case(ref sub, (ref x, ref left), (ref y, ref right)) => {
+                let mut fv = sub.fv();
+                fv.extend(scoped(x, left));
+                fv.extend(scoped(y, right));
+                fv
+            }
+            While(ref condition, ref body) => {
+                let mut fv = condition.fv();
+                fv.extend(body.fv());
+                fv
+            }
+            For(ref from, ref to, (ref v, ref body)) => {
+                let mut fv = atoms(&[from, to]);
+                fv.extend(scoped(v, body));
+                fv
+            }
+            Lazy(ref block) | Spawn(ref block) => block.fv(),
+            Lambda(ref v, ref body) => scoped(v, body),
+        }
+    }
+}
+
+impl Binding {
+    // the variables a binding brings into scope
+    fn bound(&self) -> Vec<&Var> {
+        match *self {
+            Binding::Let(ref v, _) | Binding::LetFun(ref v, _, _) => vec![v],
+            Binding::LetRec(ref functions) => functions.iter().map(|(f, _, _)| f).collect(),
+            Binding::Do(_) => vec![],
+        }
+    }
+}
+
+impl Free for Binding {
+    fn fv(&self) -> HashSet<&Var> {
+        let mut fv = match *self {
+            Binding::Let(_, ref op) | Binding::Do(ref op) => op.fv(),
+            Binding::LetFun(_, ref v, ref body) => scoped(v, body),
+            Binding::LetRec(ref functions) => functions
+                .iter()
+                .flat_map(|(_, v, body)| scoped(v, body))
+                .collect(),
+        };
+        // functions are in scope in their own bodies
+        for v in self.bound() {
+            fv.remove(v);
+        }
+        fv
+    }
+}
+
+impl Free for Block {
+    fn fv(&self) -> HashSet<&Var> {
+        // working backwards, each binding removes its variable from what's free
+        // after it
+        let mut fv = self.result.fv();
+        for binding in self.bindings.iter().rev() {
+            for v in binding.bound() {
+                fv.remove(v);
+            }
+            fv.extend(binding.fv());
+        }
+        fv
+    }
+}
+
+impl Block {
+    // for each binding and then the result, the variables the block binds that
+    // nothing after it uses (those it uses for the last time, and its own if
+    // nothing uses them), so that wherever they're kept can be reused from there
+    // on (in order of their names, so that's always done in the same order)
+    pub fn last_uses(&self) -> Vec<Vec<&Var>> {
+        let bound = self
+            .bindings
+            .iter()
+            .flat_map(Binding::bound)
+            .collect::<HashSet<_>>();
+        let mut used = HashSet::new();
+        let mut last_uses = vec![];
+        for (fv, defined) in std::iter::once((self.result.fv(), vec![])).chain(
+            self.bindings
+                .iter()
+                .rev()
+                .map(|binding| (binding.fv(), binding.bound())),
+        ) {
+            let mut dead = defined
+                .into_iter()
+                .filter(|v| !used.contains(v))
+                .collect::<Vec<_>>();
+            for v in fv {
+                if bound.contains(v) && used.insert(v) {
+                    dead.push(v);
+                }
+            }
+            dead.sort();
+            last_uses.push(dead);
+        }
+        last_uses.reverse();
+        last_uses
+    }
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Atom::Unit => write!(f, "()"),
+            Atom::Int(i) => write!(f, "{}", i),
+            Atom::Float(x) => write!(f, "{}", float_literal(x)),
+            Atom::Bool(b) => write!(f, "{}", b),
+            Atom::Var(ref v) => write!(f, "{}", v),
+        }
+    }
+}
+
+fn indent(f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+    write!(f, "{:1$}", "", depth * 2)
+}
+
+impl Op {
+    // anything with a block in it is written over several lines, with the
+    // blocks indented one level past 'depth' (and nothing after the last line)
+    fn fmt_at(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        use self::Op::*;
+        match *self {
+            Atom(ref atom) => write!(f, "{}", atom),
+            What => write!(f, "?"),
+            Neg(ref atom) => write!(f, "-{}", atom),
+            Not(ref atom) => write!(f, "~{}", atom),
+            Binary(op, ref left, ref right) => write!(f, "{} {} {}", left, op, right),
+            Div(_, ref left, ref right) => write!(f, "{} / {}", left, right),
+            Mod(_, ref left, ref right) => write!(f, "{} % {}", left, right),
+            If(ref condition, ref left, ref right, likely) => {
+                match likely {
+                    Some(likely) => writeln!(f, "if expect {} {} then", condition, likely)?,
+                    None => writeln!(f, "if {} then", condition)?,
+                }
+                left.fmt_at(f, depth + 1)?;
+                indent(f, depth)?;
+                writeln!(f, "else")?;
+                right.fmt_at(f, depth + 1)?;
+                indent(f, depth)?;
+                write!(f, "end")
+            }
+            Pair(ref left, ref right) => write!(f, "({}, {})", left, right),
+            Fst(ref atom) => write!(f, "fst {}", atom),
+            Snd(ref atom) => write!(f, "snd {}", atom),
+            Inl(ref atom) => write!(f, "inl {}", atom),
+            Inr(ref atom) => write!(f, "inr {}", atom),
+            Case(ref sub, (ref x, ref left), (ref y, ref right)) => {
+                writeln!(f, "case {} of", sub)?;
+                indent(f, depth)?;
+                writeln!(f, "inl {} ->", x)?;
+                left.fmt_at(f, depth + 1)?;
+                indent(f, depth)?;
+                writeln!(f, "inr {} ->", y)?;
+                right.fmt_at(f, depth + 1)?;
+                indent(f, depth)?;
+                write!(f, "end")
+            }
+            While(ref condition, ref body) => {
+                writeln!(f, "while")?;
+                condition.fmt_at(f, depth + 1)?;
+                indent(f, depth)?;
+                writeln!(f, "do")?;
+                body.fmt_at(f, depth + 1)?;
+                indent(f, depth)?;
+                write!(f, "end")
+            }
+            For(ref from, ref to, (ref v, ref body)) => {
+                writeln!(f, "for {} = {} to {} do", v, from, to)?;
+                body.fmt_at(f, depth + 1)?;
+                indent(f, depth)?;
+                write!(f, "end")
+            }
+            Ref(ref atom) => write!(f, "ref {}", atom),
+            Deref(ref atom) => write!(f, "!{}", atom),
+            Assign(ref left, ref right) => write!(f, "{} := {}", left, right),
+            MakeArray(_, ref length, ref value) => write!(f, "Array.make {} {}", length, value),
+            Index(_, ref array, ref index) => write!(f, "{}[{}]", array, index),
+            Update(_, ref array, ref index, ref value) => {
+                write!(f, "{}[{}] <- {}", array, index, value)
+            }
+            Nil => write!(f, "[]"),
+            Cons(ref left, ref right) => write!(f, "{} :: {}", left, right),
+            Head(_, ref atom) => write!(f, "List.hd {}", atom),
+            Tail(_, ref atom) => write!(f, "List.tl {}", atom),
+            IsEmpty(ref atom) => write!(f, "List.is_empty {}", atom),
+            Lazy(ref block) => {
+                writeln!(f, "lazy")?;
+                block.fmt_at(f, depth + 1)?;
+                indent(f, depth)?;
+                write!(f, "end")
+            }
+            Force(_, ref atom) => write!(f, "force {}", atom),
+            Chan => write!(f, "chan"),
+            Spawn(ref block) => {
+                writeln!(f, "spawn")?;
+                block.fmt_at(f, depth + 1)?;
+                indent(f, depth)?;
+                write!(f, "end")
+            }
+            Send(_, ref left, ref right) => write!(f, "send {} {}", left, right),
+            Recv(_, ref atom) => write!(f, "recv {}", atom),
+            Raise(_, ref atom) => write!(f, "raise {}", atom),
+            Try(ref body, ref handler) => write!(f, "try {} with {}", body, handler),
+            Lambda(ref v, ref body) => {
+                writeln!(f, "fun {} ->", v)?;
+                body.fmt_at(f, depth + 1)?;
+                indent(f, depth)?;
+                write!(f, "end")
+            }
+            App(ref left, ref right) => write!(f, "{} {}", left, right),
+            Str(ref string) => write!(f, "{}", quote(string)),
+            Print(ref atom) => write!(f, "print {}", atom),
+        }
+    }
+}
+
+impl Block {
+    // each binding (and then the result) on a line of its own, indented to
+    // 'depth'
+    fn fmt_at(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        for binding in self.bindings.iter() {
+            indent(f, depth)?;
+            match *binding {
+                Binding::Let(ref v, ref op) => {
+                    write!(f, "let {} = ", v)?;
+                    op.fmt_at(f, depth)?;
+                }
+                Binding::LetFun(ref v, ref x, ref body) => {
+                    writeln!(f, "let rec {} {} =", v, x)?;
+                    body.fmt_at(f, depth + 1)?;
+                    indent(f, depth)?;
+                    write!(f, "end")?;
+                }
+                Binding::LetRec(ref functions) => {
+                    for (i, (v, x, body)) in functions.iter().enumerate() {
+                        if i > 0 {
+                            indent(f, depth)?;
+                        }
+                        let keyword = if i == 0 { "let rec" } else { "and" };
+                        writeln!(f, "{} {} {} =", keyword, v, x)?;
+                        body.fmt_at(f, depth + 1)?;
+                    }
+                    indent(f, depth)?;
+                    write!(f, "end")?;
+                }
+                Binding::Do(ref op) => {
+                    write!(f, "do ")?;
+                    op.fmt_at(f, depth)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        indent(f, depth)?;
+        self.result.fmt_at(f, depth)?;
+        writeln!(f)
+    }
+}
+
+// the program as it's given to the backends, with every intermediate value
+// named (as lowering names them)
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_at(f, 0)
+    }
+}
@@ -1,14 +1,213 @@
 extern crate termion;
 
-use std::fs::OpenOptions;
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
 use std::path::Path;
 use termion::{color, style};
 
+mod artifacts;
 mod backend;
+mod cover;
+mod daemon;
+mod debug;
 mod frontend;
+mod grade;
+mod interp;
+mod ir;
+mod metrics;
+mod minimise;
+mod native;
+mod opt;
+mod reflect;
+mod repl;
+pub mod syntax;
+mod vm;
 
-pub fn compile(input: &Path, output: &Path, comments: bool) -> Result<(), String> {
+pub use artifacts::{compile_to, Artifacts, Target};
+pub use backend::mangle::{demangle, demangle_text, mangle, Symbol};
+pub use backend::{
+    parse_assembly, Function, GeneratedCode, Instruction, Label, Location, Mutant, Register,
+};
+pub use cover::{coverage, Coverage};
+pub use daemon::daemon;
+pub use debug::debug;
+pub use grade::{grade, Outcome, Report, TestCase};
+pub use interp::{Call, Input, Limits};
+pub use minimise::minimise;
+pub use native::{load, load_with, Corpus, Mutation, Resource};
+pub use reflect::{Reflect, Type, Value};
+pub use repl::repl;
+pub use vm::Bytecode;
+
+// how much stack the compiler runs on from the command line (the frontend, the
+// interpreter and the daemon all recurse over programs, so they get more room
+// than a thread gets by default)
+pub const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+// how many bits an 'int' has
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IntWidth {
+    I32,
+    I64,
+}
+
+impl IntWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            IntWidth::I32 => 32,
+            IntWidth::I64 => 64,
+        }
+    }
+
+    // wraps an integer around to this width (narrower integers are always kept
+    // sign-extended to 64 bits)
+    pub fn wrap(self, i: i64) -> i64 {
+        match self {
+            IntWidth::I32 => i as i32 as i64,
+            IntWidth::I64 => i,
+        }
+    }
+}
+
+// how compiled code represents ints and bools: as they are, tagged by setting
+// their lowest bit (with the value shifted left to make room), or boxed on the
+// heap (everything else is always a pointer, apart from '()')
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    Untagged,
+    Tagged,
+    Boxed,
+}
+
+// how compiled functions get the symbols that tools like debuggers and
+// profilers see them by: by their mangled names (which say which modules they
+// come from, and tell apart functions with the same name), or not at all
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mangling {
+    Slang,
+    None,
+}
+
+// what a program may do besides working out its result, so that untrusted code
+// can be compiled with no more than it needs. A program that uses something it
+// isn't given doesn't type check, and the runtime leaves out whatever it would
+// otherwise do on a program's behalf that isn't given
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    // printing with 'print_string', and reading the numbers for '?'
+    pub io: bool,
+    // reading the numbers for '?' from the file 'SLANG_INPUT' names
+    pub filesystem: bool,
+    // reading the runtime's settings from environment variables
+    pub env: bool,
+    // drawing the numbers for '?' from a generator seeded by 'SLANG_SEED'
+    pub random: bool,
+    // reading the clock (which nothing does yet)
+    pub time: bool,
+}
+
+impl Capabilities {
+    pub fn all() -> Capabilities {
+        Capabilities {
+            io: true,
+            filesystem: true,
+            env: true,
+            random: true,
+            time: true,
+        }
+    }
+
+    pub fn none() -> Capabilities {
+        Capabilities {
+            io: false,
+            filesystem: false,
+            env: false,
+            random: false,
+            time: false,
+        }
+    }
+
+    // reads a list of capabilities separated by commas (like 'io,random'),
+    // which can be empty
+    pub fn parse(list: &str) -> Option<Capabilities> {
+        let mut capabilities = Capabilities::none();
+        for name in list.split(',').filter(|name| !name.is_empty()) {
+            match name {
+                "io" => capabilities.io = true,
+                "filesystem" => capabilities.filesystem = true,
+                "env" => capabilities.env = true,
+                "random" => capabilities.random = true,
+                "time" => capabilities.time = true,
+                _ => return None,
+            }
+        }
+        Some(capabilities)
+    }
+
+    // how the runtime is told which capabilities a program has (which has to
+    // match the 'CAN_' constants in the runtime)
+    pub fn bits(self) -> i64 {
+        [self.io, self.filesystem, self.env, self.random, self.time]
+            .iter()
+            .enumerate()
+            .filter(|(_, given)| **given)
+            .map(|(i, _)| 1 << i)
+            .sum()
+    }
+}
+
+pub struct CompilerOptions {
+    pub comments: bool,
+    pub max_depth: usize,
+    pub fold: bool,
+    pub propagate: bool,
+    pub specialise: usize,
+    pub unroll: usize,
+    pub sanitise: bool,
+    pub int_width: IntWidth,
+    pub representation: Representation,
+    pub harden: bool,
+    pub omit_frame_pointer: bool,
+    pub stack_maps: bool,
+    pub shadow_stack: bool,
+    // the seed the interpreter picks which thread runs next with, if it doesn't
+    // run them in the order they became ready
+    pub schedule: Option<u64>,
+    // the names that '#if' in a program's source tests for
+    pub defines: Vec<String>,
+    // whether what modules define but the program never uses is left out
+    pub strip_unused: bool,
+    pub mangling: Mangling,
+    pub capabilities: Capabilities,
+}
+
+impl Default for CompilerOptions {
+    fn default() -> CompilerOptions {
+        CompilerOptions {
+            comments: false,
+            max_depth: 512,
+            fold: false,
+            propagate: false,
+            specialise: 0,
+            unroll: 0,
+            sanitise: false,
+            int_width: IntWidth::I64,
+            representation: Representation::Untagged,
+            harden: false,
+            omit_frame_pointer: false,
+            stack_maps: false,
+            shadow_stack: false,
+            schedule: None,
+            defines: vec![],
+            strip_unused: false,
+            mangling: Mangling::Slang,
+            capabilities: Capabilities::all(),
+        }
+    }
+}
+
+fn read(input: &Path) -> Result<String, String> {
     let mut input_file = match OpenOptions::new().read(true).open(input) {
         Ok(file) => file,
         Err(_) => {
@@ -25,7 +224,7 @@ pub fn compile(input: &Path, output: &Path, comments: bool) -> Result<(), String
         }
     };
     let mut text = String::new();
-    if let Err(_) = input_file.read_to_string(&mut text) {
+    if input_file.read_to_string(&mut text).is_err() {
         return Err(format!(
             "{}{}error{}{}: failed to read '{}{}{}'",
             style::Bold,
@@ -37,7 +236,267 @@ pub fn compile(input: &Path, output: &Path, comments: bool) -> Result<(), String
             style::Reset
         ));
     }
-    let ast = frontend::frontend(&format!("{}", input.display()), text)?;
+    Ok(text)
+}
+
+pub fn check(input: &Path, options: &CompilerOptions) -> Result<Vec<String>, String> {
+    let text = read(input)?;
+    frontend::check(&format!("{}", input.display()), text, options)
+}
+
+// reads the numbers in a file (separated by whitespace) as the input for '?'
+pub fn script(path: &Path) -> Result<Input, String> {
+    let text = read(path)?;
+    let mut script = VecDeque::new();
+    for word in text.split_whitespace() {
+        match word.parse::<i64>() {
+            Ok(i) => script.push_back(i),
+            Err(_) => {
+                return Err(format!(
+                    "{}{}error{}{}: invalid input '{}' in '{}{}{}'",
+                    style::Bold,
+                    color::Fg(color::Red),
+                    color::Fg(color::Reset),
+                    style::Reset,
+                    word,
+                    style::Bold,
+                    path.display(),
+                    style::Reset
+                ))
+            }
+        }
+    }
+    Ok(Input::Script(script))
+}
+
+fn run(
+    input: &Path,
+    what: Input,
+    options: &CompilerOptions,
+    record_calls: bool,
+) -> Result<(Value, Vec<Call>, Vec<String>), String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    let ast = opt::optimise(ast, options);
+    let mut interpreter =
+        interp::Interpreter::new(what, interp::Limits::default(), options.int_width);
+    if let Some(seed) = options.schedule {
+        interpreter.schedule(seed);
+    }
+    if record_calls {
+        interpreter.record_calls();
+    }
+    let value = interpreter.run(&None, &ast)?;
+    Ok(((&value).into(), interpreter.calls(), warnings))
+}
+
+// runs a program without compiling it, returning its value along with any
+// warnings
+pub fn interpret(
+    input: &Path,
+    what: Input,
+    options: &CompilerOptions,
+) -> Result<(Value, Vec<String>), String> {
+    let (value, _, warnings) = run(input, what, options, false)?;
+    Ok((value, warnings))
+}
+
+// runs a program without compiling it, as 'interpret' does, but also returns
+// what each call made from the top level of the program cost (so that the
+// costs of different algorithms can be compared)
+pub fn profile(
+    input: &Path,
+    what: Input,
+    options: &CompilerOptions,
+) -> Result<(Value, Vec<Call>, Vec<String>), String> {
+    run(input, what, options, true)
+}
+
+fn outcome(result: &Result<Value, String>) -> String {
+    match *result {
+        Ok(ref value) => format!("'{}{}{}'", style::Bold, value, style::Reset),
+        Err(ref err) => format!("an error ({})", err),
+    }
+}
+
+// compiles a program to bytecode for the stack machine without running it, so
+// that it can be inspected, returning it along with any warnings
+pub fn bytecode(
+    input: &Path,
+    options: &CompilerOptions,
+) -> Result<(Bytecode, Vec<String>), String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    let ast = opt::optimise(ast, options);
+    Ok((vm::compile(&ast), warnings))
+}
+
+// runs a program on the stack machine, which gives the same results as
+// 'interpret' (running threads in the same order) in around half the time
+pub fn execute(
+    input: &Path,
+    what: Input,
+    options: &CompilerOptions,
+) -> Result<(Value, Vec<String>), String> {
+    let (bytecode, warnings) = self::bytecode(input, options)?;
+    let mut machine = vm::Machine::new(&bytecode, what, options.int_width);
+    if let Some(seed) = options.schedule {
+        machine.schedule(seed);
+    }
+    let value = machine.run()?;
+    Ok((machine.reflect(&value), warnings))
+}
+
+// runs a program in both the interpreter and the reference evaluator (which
+// follows the small-step rules of the language directly), returning its value
+// along with any warnings if they agree on it (and on what it prints, which is
+// only printed once they've both finished)
+pub fn cross_check(
+    input: &Path,
+    what: Input,
+    options: &CompilerOptions,
+) -> Result<(Value, Vec<String>), String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    // the semantics apply to the program as it was written, so the reference
+    // evaluator runs it before it's optimised
+    let mut reference = interp::Reference::new(what.clone(), options.int_width);
+    let mut interpreter =
+        interp::Interpreter::new(what, interp::Limits::default(), options.int_width);
+    if let Some(seed) = options.schedule {
+        reference.schedule(seed);
+        interpreter.schedule(seed);
+    }
+    reference.capture_output();
+    interpreter.capture_output();
+    let expected = reference.eval(&ast);
+    let printed = reference.output();
+    let ast = opt::optimise(ast, options);
+    let found = interpreter
+        .run(&None, &ast)
+        .map(|value| Value::from(&value));
+    // the semantics don't bound how deeply a program recurses, so a program
+    // that recurses too deeply for the interpreter can't be checked against them
+    if let Err(ref err) = found {
+        if err.contains("limit exceeded") {
+            return Err(err.clone());
+        }
+    }
+    if printed != interpreter.output() {
+        return Err(format!(
+            "{}{}error{}{}: the interpreter disagrees with the semantics on what the program prints",
+            style::Bold,
+            color::Fg(color::Red),
+            color::Fg(color::Reset),
+            style::Reset
+        ));
+    }
+    interp::Output::Stdout.write(&printed);
+    match (expected, found) {
+        (Ok(expected), Ok(found)) if expected == found => Ok((expected, warnings)),
+        (Err(expected), Err(found)) if expected == found => Err(expected),
+        (expected, found) => Err(format!(
+            "{}{}error{}{}: the interpreter disagrees with the semantics: expected {}, found {}",
+            style::Bold,
+            color::Fg(color::Red),
+            color::Fg(color::Reset),
+            style::Reset,
+            outcome(&expected),
+            outcome(&found)
+        )),
+    }
+}
+
+// checks the generated code for a program without loops against the
+// interpreter, running both on the numbers drawn from each seed below 'trials',
+// and returns any warnings if they always agree
+pub fn validate(
+    input: &Path,
+    options: &CompilerOptions,
+    trials: u64,
+) -> Result<Vec<String>, String> {
+    let text = read(input)?;
+    native::validate(&format!("{}", input.display()), text, options, trials)
+}
+
+// validates every program in a directory (see 'validate'), skipping the ones
+// that can't be validated rather than failing on them
+pub fn validate_corpus(
+    directory: &Path,
+    options: &CompilerOptions,
+    trials: u64,
+) -> Result<Corpus, String> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => {
+            return Err(format!(
+                "{}{}error{}{}: failed to read the directory '{}{}{}'",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                directory.display(),
+                style::Reset
+            ))
+        }
+    };
+    let mut paths = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "slang")
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+    let mut programs = vec![];
+    for path in paths {
+        let text = read(&path)?;
+        programs.push((path, text));
+    }
+    Ok(native::validate_corpus(programs, options, trials))
+}
+
+// measures how strong '--validate' is for a program, by counting how many of
+// the mutants of its generated code (with an instruction dropped or its
+// operands swapped) validating it with 'trials' seeds catches
+pub fn mutate(
+    input: &Path,
+    options: &CompilerOptions,
+    trials: u64,
+) -> Result<(Mutation, Vec<String>), String> {
+    let text = read(input)?;
+    native::mutate(&format!("{}", input.display()), text, options, trials)
+}
+
+// compiles a program without writing it out, so that the generated code can be
+// inspected, returning it along with any warnings
+pub fn generate(
+    input: &Path,
+    options: &CompilerOptions,
+) -> Result<(GeneratedCode, Vec<String>), String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    let ast = opt::optimise(ast, options);
+    Ok((backend::generate(ir::lower(ast), options), warnings))
+}
+
+// compiles two programs (or one program two ways) and compares the generated
+// code function by function, with labels renamed so that only real differences
+// show up, returning the diff along with any warnings from either
+pub fn diff_asm(
+    left: &Path,
+    left_options: &CompilerOptions,
+    right: &Path,
+    right_options: &CompilerOptions,
+) -> Result<(String, Vec<String>), String> {
+    let (left, mut warnings) = generate(left, left_options)?;
+    let (right, right_warnings) = generate(right, right_options)?;
+    warnings.extend(right_warnings);
+    Ok((backend::diff::diff(&left, &right), warnings))
+}
+
+fn write(output: &Path, contents: &[u8]) -> Result<(), String> {
     let mut output_file = match OpenOptions::new()
         .create(true)
         .write(true)
@@ -58,12 +517,7 @@ pub fn compile(input: &Path, output: &Path, comments: bool) -> Result<(), String
             ))
         }
     };
-    let code = if comments {
-        backend::generate_with_comments(ast.into())
-    } else {
-        backend::generate(ast.into())
-    };
-    if let Err(_) = write!(output_file, "{}", code) {
+    if output_file.write_all(contents).is_err() {
         return Err(format!(
             "{}{}error{}{}: failed to write to '{}{}{}'",
             style::Bold,
@@ -77,3 +531,126 @@ pub fn compile(input: &Path, output: &Path, comments: bool) -> Result<(), String
     }
     Ok(())
 }
+
+pub fn compile(
+    input: &Path,
+    output: &Path,
+    options: &CompilerOptions,
+) -> Result<Vec<String>, String> {
+    let (code, warnings) = generate(input, options)?;
+    write(output, code.to_string().as_bytes())?;
+    Ok(warnings)
+}
+
+// compiles a program to a WebAssembly module instead of assembly
+pub fn compile_wasm(
+    input: &Path,
+    output: &Path,
+    options: &CompilerOptions,
+) -> Result<Vec<String>, String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    let ast = opt::optimise(ast, options);
+    write(output, &backend::wasm::generate(ir::lower(ast), options)?)?;
+    Ok(warnings)
+}
+
+// compiles a program to C instead of assembly
+pub fn compile_c(
+    input: &Path,
+    output: &Path,
+    options: &CompilerOptions,
+) -> Result<Vec<String>, String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    let ast = opt::optimise(ast, options);
+    write(
+        output,
+        backend::c::generate(ir::lower(ast), options)?.as_bytes(),
+    )?;
+    Ok(warnings)
+}
+
+// writes out which functions in a program call which (as it would be compiled,
+// so after any optimisations) as a DOT graph, or as JSON
+pub fn compile_callgraph(
+    input: &Path,
+    output: &Path,
+    options: &CompilerOptions,
+    json: bool,
+) -> Result<Vec<String>, String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    let ast = opt::optimise(ast, options);
+    let graph = ir::callgraph(&ir::lower(ast));
+    let contents = if json { graph.json() } else { graph.dot() };
+    write(output, contents.as_bytes())?;
+    Ok(warnings)
+}
+
+// writes out an estimate of the most stack each function in a program (and
+// the top level) can take, along with everything it calls, as it would be
+// compiled with these options (or that it can take any amount, because it can
+// recurse)
+pub fn compile_stack_usage(
+    input: &Path,
+    output: &Path,
+    options: &CompilerOptions,
+) -> Result<Vec<String>, String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    let ast = opt::optimise(ast, options);
+    let block = ir::lower(ast);
+    let graph = ir::callgraph(&block);
+    let code = backend::generate(block, options);
+    write(output, graph.stack_usage(&code.frames()).as_bytes())?;
+    Ok(warnings)
+}
+
+// writes out statistics about a program as JSON: how it's written, where what
+// it compiles to allocates and which instructions it's compiled into (with
+// these options)
+pub fn compile_metrics(
+    input: &Path,
+    output: &Path,
+    options: &CompilerOptions,
+) -> Result<Vec<String>, String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    let block = ir::lower(opt::optimise(ast.clone(), options));
+    let code = backend::generate(block.clone(), options);
+    write(output, metrics::metrics(&ast, &block, &code).as_bytes())?;
+    Ok(warnings)
+}
+
+// writes out the decision tree each 'match' in a program is compiled into,
+// showing which tests it makes in which order and which parts of it are shared
+pub fn compile_match_trees(
+    input: &Path,
+    output: &Path,
+    options: &CompilerOptions,
+) -> Result<Vec<String>, String> {
+    let text = read(input)?;
+    let (trees, warnings) = frontend::match_trees(&format!("{}", input.display()), text, options)?;
+    write(output, trees.concat().as_bytes())?;
+    Ok(warnings)
+}
+
+// compiles a program straight into memory and runs it there, without an
+// assembler or linker, returning what the executable would print (the numbers
+// for '?' are read as an executable reads them)
+pub fn jit_run(source: &str) -> Result<i64, String> {
+    let options = CompilerOptions::default();
+    let (ast, _) = frontend::frontend("<source>", source.to_string(), &options)?;
+    let ast = opt::optimise(ast, &options);
+    backend::jit::run(&backend::generate(ir::lower(ast), &options))
+}
+
+// as 'jit_run', but for a file, returning any warnings too
+pub fn jit(input: &Path, options: &CompilerOptions) -> Result<(i64, Vec<String>), String> {
+    let text = read(input)?;
+    let (ast, warnings) = frontend::frontend(&format!("{}", input.display()), text, options)?;
+    let ast = opt::optimise(ast, options);
+    let value = backend::jit::run(&backend::generate(ir::lower(ast), options))?;
+    Ok((value, warnings))
+}
@@ -1,28 +1,136 @@
 extern crate slang;
 extern crate termion;
 
+use slang::{
+    Capabilities, CompilerOptions, Input, IntWidth, Mangling, Representation, Target, STACK_SIZE,
+};
 use std::path::Path;
 use std::process::Command;
 use std::time::Instant;
 use termion::{color, style};
 
 use std::env;
+use std::io::{self, Read};
+use std::thread;
+
+// a (generous, as unoptimised builds use a lot) bound on the stack each level
+// of nesting takes in the frontend, which bounds what '--max-depth' may allow
+const LEVEL_SIZE: usize = 80 * 1024;
+const MAX_DEPTH: usize = STACK_SIZE / LEVEL_SIZE;
 
 struct Options {
     comments: bool,
     autolink: bool,
+    check: bool,
+    interpret: bool,
+    jit: bool,
+    vm: bool,
+    cross_check: bool,
+    coverage: bool,
+    debug: bool,
+    validate: Option<u64>,
+    mutate: Option<u64>,
+    minimise: Option<String>,
+    stats: bool,
+    repl: bool,
+    daemon: bool,
+    demangle: bool,
+    // what 'demangle' was given to demangle
+    symbols: Vec<String>,
+    diff_asm: bool,
+    // the file 'diff-asm' compares the input with (which is the input itself if
+    // there isn't one), and the options it's compiled with on top of the rest
+    other: Option<String>,
+    with: Option<String>,
+    socket: Option<String>,
+    script: Option<String>,
+    seed: Option<u64>,
+    schedule: Option<u64>,
+    defines: Vec<String>,
+    max_depth: Option<usize>,
+    fold: bool,
+    propagate: bool,
+    specialise: usize,
+    unroll: usize,
+    sanitise: bool,
+    int_width: IntWidth,
+    representation: Representation,
+    mangling: Mangling,
+    capabilities: Capabilities,
+    harden: bool,
+    omit_frame_pointer: bool,
+    stack_maps: bool,
+    shadow_stack: bool,
+    strip_unused: bool,
+    wasm: bool,
+    emit_c: bool,
+    callgraph: bool,
+    callgraph_json: bool,
+    stack_usage: bool,
+    metrics: bool,
+    match_tree: bool,
+    out_dir: Option<String>,
     help: bool,
     input: Option<String>,
 }
 
 impl Options {
     fn init() -> Options {
+        Options::parse(env::args().skip(1).collect())
+    }
+
+    fn parse(args: Vec<String>) -> Options {
         let mut comments = false;
         let mut autolink = false;
+        let mut check = false;
+        let mut interpret = false;
+        let mut jit = false;
+        let mut vm = false;
+        let mut cross_check = false;
+        let mut coverage = false;
+        let mut debug = false;
+        let mut validate = None;
+        let mut mutate = None;
+        let mut minimise = None;
+        let mut stats = false;
+        let mut repl = false;
+        let mut daemon = false;
+        let mut demangle = false;
+        let mut symbols = vec![];
+        let mut diff_asm = false;
+        let mut other = None;
+        let mut with = None;
+        let mut socket = None;
+        let mut script = None;
+        let mut seed = None;
+        let mut schedule = None;
+        let mut defines = vec![];
+        let mut max_depth = None;
+        let mut fold = false;
+        let mut propagate = false;
+        let mut specialise = 0;
+        let mut unroll = 0;
+        let mut sanitise = false;
+        let mut int_width = IntWidth::I64;
+        let mut representation = Representation::Untagged;
+        let mut mangling = Mangling::Slang;
+        let mut capabilities = Capabilities::all();
+        let mut harden = false;
+        let mut omit_frame_pointer = false;
+        let mut stack_maps = false;
+        let mut shadow_stack = false;
+        let mut strip_unused = false;
+        let mut wasm = false;
+        let mut emit_c = false;
+        let mut callgraph = false;
+        let mut callgraph_json = false;
+        let mut stack_usage = false;
+        let mut metrics = false;
+        let mut match_tree = false;
+        let mut out_dir = None;
         let mut help = false;
         let mut input = None;
-        let args = env::args().collect::<Vec<String>>();
-        for arg in args.into_iter().skip(1) {
+        for arg in args.into_iter() {
             if arg.starts_with("-") {
                 if arg == "-C" {
                     comments = true;
@@ -30,6 +138,269 @@ impl Options {
                     help = true;
                 } else if arg == "-L" || arg == "--link" {
                     autolink = true;
+                } else if arg == "--check" {
+                    check = true;
+                } else if arg == "-i" || arg == "--interpret" {
+                    interpret = true;
+                } else if arg == "--jit" {
+                    jit = true;
+                } else if arg == "--vm" {
+                    vm = true;
+                } else if arg == "--stats" {
+                    stats = true;
+                } else if arg == "--cross-check" {
+                    cross_check = true;
+                } else if arg == "--coverage" {
+                    coverage = true;
+                } else if arg == "--debug" {
+                    debug = true;
+                } else if arg == "--repl" {
+                    repl = true;
+                } else if arg == "-O" {
+                    fold = true;
+                } else if arg == "--propagate" {
+                    propagate = true;
+                } else if arg == "--sanitise" {
+                    sanitise = true;
+                } else if arg == "--harden" {
+                    harden = true;
+                } else if arg == "--omit-frame-pointer" {
+                    omit_frame_pointer = true;
+                } else if arg == "--stack-maps" {
+                    stack_maps = true;
+                } else if arg == "--shadow-stack" {
+                    shadow_stack = true;
+                } else if arg == "--strip-unused" {
+                    strip_unused = true;
+                } else if arg == "--wasm" {
+                    wasm = true;
+                } else if arg == "--c" {
+                    emit_c = true;
+                } else if let Some(what) = arg.strip_prefix("--emit=") {
+                    match what {
+                        "callgraph" => callgraph = true,
+                        "callgraph-json" => callgraph_json = true,
+                        "stack-usage" => stack_usage = true,
+                        "metrics" => metrics = true,
+                        "match-tree" => match_tree = true,
+                        _ => {
+                            println!(
+                                "{}{}error{}{}: invalid output in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                } else if let Some(options) = arg.strip_prefix("--with=") {
+                    with = Some(options.to_string());
+                } else if let Some(path) = arg.strip_prefix("--out-dir=") {
+                    out_dir = Some(path.to_string());
+                } else if let Some(path) = arg.strip_prefix("--socket=") {
+                    socket = Some(path.to_string());
+                } else if let Some(path) = arg.strip_prefix("--input=") {
+                    script = Some(path.to_string());
+                } else if let Some(n) = arg.strip_prefix("--seed=") {
+                    match n.parse::<u64>() {
+                        Ok(n) => seed = Some(n),
+                        Err(_) => {
+                            println!(
+                                "{}{}error{}{}: invalid seed in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                } else if let Some(n) = arg.strip_prefix("--validate=") {
+                    match n.parse::<u64>() {
+                        Ok(n) => validate = Some(n),
+                        Err(_) => {
+                            println!(
+                                "{}{}error{}{}: invalid number of trials in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                } else if let Some(n) = arg.strip_prefix("--mutate=") {
+                    match n.parse::<u64>() {
+                        Ok(n) => mutate = Some(n),
+                        Err(_) => {
+                            println!(
+                                "{}{}error{}{}: invalid number of trials in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                } else if let Some(command) = arg.strip_prefix("--minimise=") {
+                    minimise = Some(command.to_string());
+                } else if let Some(n) = arg.strip_prefix("--schedule=") {
+                    match n.parse::<u64>() {
+                        Ok(n) => schedule = Some(n),
+                        Err(_) => {
+                            println!(
+                                "{}{}error{}{}: invalid seed in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                } else if let Some(name) = arg.strip_prefix("--define=") {
+                    if name.is_empty()
+                        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    {
+                        println!(
+                            "{}{}error{}{}: invalid name in '{}' (see '--help' for usage)",
+                            style::Bold,
+                            color::Fg(color::Red),
+                            color::Fg(color::Reset),
+                            style::Reset,
+                            arg
+                        );
+                        std::process::exit(1);
+                    }
+                    defines.push(name.to_string());
+                } else if let Some(depth) = arg.strip_prefix("--max-depth=") {
+                    match depth.parse::<usize>() {
+                        Ok(depth) if depth <= MAX_DEPTH => max_depth = Some(depth),
+                        Ok(_) => {
+                            println!(
+                                "{}{}error{}{}: depth in '{}' is more than the stack allows (at most {})",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg,
+                                MAX_DEPTH
+                            );
+                            std::process::exit(1);
+                        }
+                        Err(_) => {
+                            println!(
+                                "{}{}error{}{}: invalid depth in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                } else if let Some(aggressiveness) = arg.strip_prefix("--specialise=") {
+                    match aggressiveness.parse::<usize>() {
+                        Ok(aggressiveness) => specialise = aggressiveness,
+                        Err(_) => {
+                            println!(
+                                "{}{}error{}{}: invalid aggressiveness in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                } else if let Some(bits) = arg.strip_prefix("--int-width=") {
+                    match bits {
+                        "32" => int_width = IntWidth::I32,
+                        "64" => int_width = IntWidth::I64,
+                        _ => {
+                            println!(
+                                "{}{}error{}{}: invalid width in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                } else if let Some(name) = arg.strip_prefix("--representation=") {
+                    match name {
+                        "untagged" => representation = Representation::Untagged,
+                        "tagged" => representation = Representation::Tagged,
+                        "boxed" => representation = Representation::Boxed,
+                        _ => {
+                            println!(
+                                "{}{}error{}{}: invalid representation in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                } else if let Some(name) = arg.strip_prefix("--mangling=") {
+                    match name {
+                        "slang" => mangling = Mangling::Slang,
+                        "none" => mangling = Mangling::None,
+                        _ => {
+                            println!(
+                                "{}{}error{}{}: invalid mangling scheme in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                } else if let Some(list) = arg.strip_prefix("--capabilities=") {
+                    match Capabilities::parse(list) {
+                        Some(given) => capabilities = given,
+                        None => {
+                            println!(
+                                "{}{}error{}{}: invalid capability in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                } else if let Some(budget) = arg.strip_prefix("--unroll=") {
+                    match budget.parse::<usize>() {
+                        Ok(budget) => unroll = budget,
+                        Err(_) => {
+                            println!(
+                                "{}{}error{}{}: invalid budget in '{}' (see '--help' for usage)",
+                                style::Bold,
+                                color::Fg(color::Red),
+                                color::Fg(color::Reset),
+                                style::Reset,
+                                arg
+                            );
+                            std::process::exit(1);
+                        }
+                    }
                 } else {
                     println!(
                         "{}{}error{}{}: unrecognised option '{}' (see '--help' for usage)",
@@ -41,8 +412,18 @@ impl Options {
                     );
                     std::process::exit(1);
                 }
-            } else if let None = input {
+            } else if arg == "daemon" && input.is_none() && !daemon && !demangle && !diff_asm {
+                daemon = true;
+            } else if arg == "demangle" && input.is_none() && !daemon && !demangle && !diff_asm {
+                demangle = true;
+            } else if arg == "diff-asm" && input.is_none() && !daemon && !demangle && !diff_asm {
+                diff_asm = true;
+            } else if demangle {
+                symbols.push(arg);
+            } else if input.is_none() {
                 input = Some(arg)
+            } else if diff_asm && other.is_none() {
+                other = Some(arg)
             } else {
                 println!(
                     "{}{}error{}{}: too many input files '{}' (see '--help' for usage)",
@@ -58,69 +439,365 @@ impl Options {
         Options {
             comments,
             autolink,
+            check,
+            interpret,
+            jit,
+            vm,
+            cross_check,
+            coverage,
+            debug,
+            validate,
+            mutate,
+            minimise,
+            stats,
+            repl,
+            daemon,
+            demangle,
+            symbols,
+            diff_asm,
+            other,
+            with,
+            socket,
+            script,
+            seed,
+            schedule,
+            defines,
+            max_depth,
+            fold,
+            propagate,
+            specialise,
+            unroll,
+            sanitise,
+            int_width,
+            representation,
+            mangling,
+            capabilities,
+            harden,
+            omit_frame_pointer,
+            stack_maps,
+            shadow_stack,
+            strip_unused,
+            wasm,
+            emit_c,
+            callgraph,
+            callgraph_json,
+            stack_usage,
+            metrics,
+            match_tree,
+            out_dir,
             help,
             input,
         }
     }
 }
 
+impl Options {
+    // where the interpreter gets the values for '?' from
+    fn input(&self) -> Input {
+        if let Some(ref path) = self.script {
+            match slang::script(Path::new(path)) {
+                Ok(input) => input,
+                Err(err) => {
+                    println!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        } else if let Some(seed) = self.seed {
+            Input::Random(seed)
+        } else {
+            Input::Stdin
+        }
+    }
+
+    fn compiler_options(&self) -> CompilerOptions {
+        let mut options = CompilerOptions {
+            comments: self.comments,
+            fold: self.fold,
+            propagate: self.propagate,
+            specialise: self.specialise,
+            unroll: self.unroll,
+            sanitise: self.sanitise,
+            int_width: self.int_width,
+            representation: self.representation,
+            mangling: self.mangling,
+            capabilities: self.capabilities,
+            harden: self.harden,
+            // optimising drops frame pointers too (unless they're needed, for
+            // '-C' or '--sanitise' say, which the backend checks)
+            omit_frame_pointer: self.omit_frame_pointer || self.fold,
+            stack_maps: self.stack_maps,
+            shadow_stack: self.shadow_stack,
+            strip_unused: self.strip_unused,
+            schedule: self.schedule,
+            defines: self.defines.clone(),
+            ..CompilerOptions::default()
+        };
+        if let Some(max_depth) = self.max_depth {
+            options.max_depth = max_depth;
+        }
+        options
+    }
+}
+
 fn usage() {
     println!("usage: slang [options] file");
+    println!("       slang [options] daemon");
+    println!("       slang demangle [symbol...]");
+    println!("       slang [options] diff-asm file [file] [--with=OPTIONS]");
     println!("options:");
     println!("  --help        display this information");
     println!("  -C            add comments to generated code");
     println!("  -L, --link    assemble and link generated code");
+    println!("  --check       type check without generating code");
+    println!("  --sanitise    generate code that makes memory errors easier to find");
+    println!("  --harden      generate code that supports control-flow enforcement (CET)");
+    println!(
+        "  --omit-frame-pointer address locals relative to '%rsp', as -O does (ignored with -C or --sanitise)"
+    );
+    println!("  --stack-maps  record which stack slots hold values at every call");
+    println!(
+        "  --shadow-stack link every frame's local variables into a list the runtime can walk"
+    );
+    println!(
+        "  --strip-unused leave out what modules define but the program never uses (if that can't change what it does)"
+    );
+    println!("  --wasm        generate a WebAssembly module (a WASI command) instead of assembly");
+    println!("  --c           generate C99 instead of assembly (with -L, build it with 'cc')");
+    println!(
+        "  --emit=callgraph write which functions call which to a DOT graph instead of compiling"
+    );
+    println!("  --emit=callgraph-json as --emit=callgraph, but as JSON");
+    println!(
+        "  --emit=stack-usage write how much stack each function can take (with what it calls) instead of compiling"
+    );
+    println!(
+        "  --emit=metrics write statistics about the program (its constructs, nesting, instructions and allocations) as JSON instead of compiling"
+    );
+    println!(
+        "  --emit=match-tree write the decision tree each 'match' is compiled into instead of compiling"
+    );
+    println!(
+        "  --out-dir=DIR write the generated code, the lowered program, a source map and (with -L) the object and executable under DIR, listed in DIR/manifest.json"
+    );
+    println!(
+        "  --with=OPTIONS with 'diff-asm', compile the second side with OPTIONS as well (in quotes, separated by spaces)"
+    );
+    println!("  -i, --interpret run the program without compiling it");
+    println!(
+        "  --jit         compile the program into memory and run it there (no assembler or linker)"
+    );
+    println!(
+        "  --vm          run the program on a bytecode stack machine (as -i does, but faster)"
+    );
+    println!("  --stats       with -i, report the steps and allocations of each top-level call");
+    println!("  --cross-check check the interpreter against the reference semantics");
+    println!("  --coverage    run the program and show how many times each line of it ran");
+    println!("  --debug       step through the program (and back) by the reference semantics");
+    println!("  --validate=N  check the generated code against the interpreter for seeds below N");
+    println!("                (for every program in it, if the input is a directory)");
+    println!("  --mutate=N    count how many mutants of the generated code --validate=N catches");
+    println!(
+        "  --minimise=CMD shrink the program to the smallest one CMD still fails on ('{{}}' in CMD is its path)"
+    );
+    println!("  --repl        start an interactive session (no file needed)");
+    println!(
+        "  --socket=PATH with 'daemon', take requests on a Unix socket at PATH rather than on stdin"
+    );
+    println!(
+        "  --input=FILE  with -i, --vm, --coverage, --cross-check, --debug or --repl, read the numbers for '?' from FILE"
+    );
+    println!(
+        "  --seed=N      with -i, --vm, --coverage, --cross-check, --debug or --repl, use random numbers from seed N for '?'"
+    );
+    println!(
+        "  --schedule=N  with -i, --vm, --coverage, --cross-check, --debug or --repl, run threads in an order drawn from seed N"
+    );
+    println!(
+        "  --define=NAME keep the code under '#if NAME' (and drop the code under its '#else')"
+    );
+    println!(
+        "  --capabilities=LIST only let the program use the capabilities in LIST (from 'io', 'filesystem', 'env', 'random' and 'time', separated by commas)"
+    );
+    println!(
+        "  --max-depth=N limit expression nesting to N levels (at most {})",
+        MAX_DEPTH
+    );
+    println!("  -O            fold constant expressions before generating code (and omit frame pointers)");
+    println!("  --propagate   propagate literal arguments into the top-level functions they're always passed to");
+    println!("  --specialise=N unfold calls with literal arguments up to N levels deep");
+    println!("  --unroll=N    unroll loops with known trip counts into at most N nodes");
+    println!("  --int-width=N make 'int' N bits wide (32 or 64, the default)");
+    println!(
+        "  --mangling=M  give compiled functions symbols mangled by the 'slang' scheme (the default), or 'none'"
+    );
+    println!("  --representation=R represent ints and bools in compiled code as 'untagged' (the default), 'tagged' or 'boxed'");
 }
 
-fn main() {
-    println!("( {}slang{} ) ", style::Bold, style::Reset);
-    let options = Options::init();
-    if options.help {
-        usage();
-        return;
-    }
-    let input = match options.input {
-        Some(input) => input,
-        None => {
+fn check(input: &Path, options: &CompilerOptions) {
+    println!(
+        "{}{}checking{}{}: '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    match slang::check(input, options) {
+        Ok(warnings) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
             println!(
-                "{}{}error{}{}: no input file given! (see '--help' for usage)",
+                "{}{}success{}{}: checking completed in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: checking terminated after {}{}ms{}",
                 style::Bold,
                 color::Fg(color::Red),
                 color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
                 style::Reset
             );
             std::process::exit(1);
         }
-    };
-    let input = Path::new(&input);
-    let output = &input.with_extension("s");
+    }
+}
+
+fn interpret(input: &Path, what: Input, options: &CompilerOptions, stats: bool) {
     println!(
-        "{}{}compiling{}{}: '{}{}{}' to output file '{}{}{}'...",
+        "{}{}interpreting{}{}: '{}{}{}'...",
         style::Bold,
         color::Fg(color::Blue),
         color::Fg(color::Reset),
         style::Reset,
         style::Bold,
         input.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    let result = if stats {
+        slang::profile(input, what, options)
+    } else {
+        slang::interpret(input, what, options).map(|(value, warnings)| (value, vec![], warnings))
+    };
+    match result {
+        Ok((value, calls, warnings)) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            println!("{}", value);
+            for call in calls.iter() {
+                println!("{}", call);
+            }
+            println!(
+                "{}{}success{}{}: interpretation completed in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: interpretation terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn cross_check(input: &Path, what: Input, options: &CompilerOptions) {
+    println!(
+        "{}{}cross-checking{}{}: '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
         style::Reset,
         style::Bold,
-        output.display(),
+        input.display(),
         style::Reset
     );
-    if options.comments {
-        println!(
-            "{}{}note{}{}: including comments in generated assembly...",
-            style::Bold,
-            color::Fg(color::Magenta),
-            color::Fg(color::Reset),
-            style::Reset,
-        );
+    let now = Instant::now();
+    match slang::cross_check(input, what, options) {
+        Ok((value, warnings)) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            println!("{}", value);
+            println!(
+                "{}{}success{}{}: cross-checking completed in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: cross-checking terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
     }
+}
+
+fn vm(input: &Path, what: Input, options: &CompilerOptions) {
+    println!(
+        "{}{}executing{}{}: '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset
+    );
     let now = Instant::now();
-    match slang::compile(input, output, options.comments) {
-        Ok(_) => {
+    match slang::execute(input, what, options) {
+        Ok((value, warnings)) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            println!("{}", value);
             println!(
-                "{}{}success{}{}: compilation completed in {}{}ms{}",
+                "{}{}success{}{}: execution completed in {}{}ms{}",
                 style::Bold,
                 color::Fg(color::Green),
                 color::Fg(color::Reset),
@@ -128,35 +805,58 @@ fn main() {
                 style::Bold,
                 now.elapsed().as_millis(),
                 style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: execution terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
             );
-            if options.autolink {
-                let executable = &input.with_extension("");
-                println!(
-                    "{}{}note{}{}: linking into executable '{}{}{}'...",
-                    style::Bold,
-                    color::Fg(color::Magenta),
-                    color::Fg(color::Reset),
-                    style::Reset,
-                    style::Bold,
-                    executable.display(),
-                    style::Reset,
-                );
-                Command::new("gcc")
-                    .args(&[
-                        "-o",
-                        &format!("{}", executable.display()),
-                        &format!("{}", output.display()),
-                        concat!("-L", env!("OUT_DIR")),
-                        "-lslangrt",
-                    ])
-                    .status()
-                    .unwrap();
+            std::process::exit(1);
+        }
+    }
+}
+
+fn jit(input: &Path, options: &CompilerOptions) {
+    println!(
+        "{}{}running{}{}: '{}{}{}' in memory...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    match slang::jit(input, options) {
+        Ok((value, warnings)) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
             }
+            println!("{}", value);
+            println!(
+                "{}{}success{}{}: run completed in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
         }
         Err(err) => {
             println!("{}", err);
             println!(
-                "{}{}failure{}{}: compilation terminated after {}{}ms{}",
+                "{}{}failure{}{}: run terminated after {}{}ms{}",
                 style::Bold,
                 color::Fg(color::Red),
                 color::Fg(color::Reset),
@@ -169,3 +869,940 @@ fn main() {
         }
     }
 }
+
+fn coverage(input: &Path, what: Input, options: &CompilerOptions) {
+    println!(
+        "{}{}covering{}{}: '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    match slang::coverage(input, vec![what], options) {
+        Ok(coverage) => {
+            for warning in coverage.warnings.iter() {
+                println!("{}", warning);
+            }
+            // the counts are still worth seeing when the program fails
+            for result in coverage.results.iter() {
+                match result {
+                    Ok(value) => println!("{}", value),
+                    Err(err) => println!("{}", err),
+                }
+            }
+            print!("{}", coverage);
+            let (run, total) = coverage.covered();
+            println!(
+                "{}{}success{}{}: {} of {} expressions run, in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                run,
+                total,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: covering terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn validate(input: &Path, options: &CompilerOptions, trials: u64) {
+    println!(
+        "{}{}validating{}{}: '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    match slang::validate(input, options, trials) {
+        Ok(warnings) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            println!(
+                "{}{}success{}{}: validation of {} runs completed in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                trials,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: validation terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn validate_corpus(directory: &Path, options: &CompilerOptions, trials: u64) {
+    println!(
+        "{}{}validating{}{}: every program in '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        directory.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    let corpus = match slang::validate_corpus(directory, options, trials) {
+        Ok(corpus) => corpus,
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: validation terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    };
+    for path in corpus.skipped.iter() {
+        println!(
+            "skipped '{}{}{}' (it doesn't check, or can't be validated)",
+            style::Bold,
+            path.display(),
+            style::Reset
+        );
+    }
+    for (path, err) in corpus.failed.iter() {
+        println!(
+            "{}{}failed{}{} '{}{}{}':",
+            style::Bold,
+            color::Fg(color::Red),
+            color::Fg(color::Reset),
+            style::Reset,
+            style::Bold,
+            path.display(),
+            style::Reset
+        );
+        println!("{}", err);
+    }
+    if corpus.failed.is_empty() {
+        println!(
+            "{}{}success{}{}: validation of {} programs ({} skipped) with {} runs each completed in {}{}ms{}",
+            style::Bold,
+            color::Fg(color::Green),
+            color::Fg(color::Reset),
+            style::Reset,
+            corpus.validated.len(),
+            corpus.skipped.len(),
+            trials,
+            style::Bold,
+            now.elapsed().as_millis(),
+            style::Reset
+        )
+    } else {
+        println!(
+            "{}{}failure{}{}: {} of {} programs failed validation after {}{}ms{}",
+            style::Bold,
+            color::Fg(color::Red),
+            color::Fg(color::Reset),
+            style::Reset,
+            corpus.failed.len(),
+            corpus.failed.len() + corpus.validated.len(),
+            style::Bold,
+            now.elapsed().as_millis(),
+            style::Reset
+        );
+        std::process::exit(1);
+    }
+}
+
+fn mutate(input: &Path, options: &CompilerOptions, trials: u64) {
+    println!(
+        "{}{}mutating{}{}: '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    match slang::mutate(input, options, trials) {
+        Ok((mutation, warnings)) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            for survivor in mutation.survived.iter() {
+                println!(
+                    "{}{}survived{}{}: {}",
+                    style::Bold,
+                    color::Fg(color::Yellow),
+                    color::Fg(color::Reset),
+                    style::Reset,
+                    survivor
+                );
+            }
+            println!(
+                "{}{}success{}{}: {} of {} mutants caught in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                mutation.caught,
+                mutation.caught + mutation.survived.len(),
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: mutation terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn minimise(input: &Path, command: &str, options: &CompilerOptions) {
+    println!(
+        "{}{}minimising{}{}: '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    match slang::minimise(input, command, options) {
+        Ok((output, before, after)) => println!(
+            "{}{}success{}{}: minimised to '{}{}{}' ({} characters, from {}) in {}{}ms{}",
+            style::Bold,
+            color::Fg(color::Green),
+            color::Fg(color::Reset),
+            style::Reset,
+            style::Bold,
+            output.display(),
+            style::Reset,
+            after,
+            before,
+            style::Bold,
+            now.elapsed().as_millis(),
+            style::Reset
+        ),
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: minimisation terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn callgraph(input: &Path, json: bool, options: &CompilerOptions) {
+    let output = &input.with_extension(if json { "json" } else { "dot" });
+    println!(
+        "{}{}graphing{}{}: '{}{}{}' to output file '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset,
+        style::Bold,
+        output.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    match slang::compile_callgraph(input, output, options, json) {
+        Ok(warnings) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            println!(
+                "{}{}success{}{}: graphing completed in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: graphing terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn diff_asm(input: &Path, options: &Options) {
+    if options.other.is_none() && options.with.is_none() {
+        println!(
+            "{}{}error{}{}: nothing to compare '{}{}{}' with (give 'diff-asm' a second file or '--with=OPTIONS')",
+            style::Bold,
+            color::Fg(color::Red),
+            color::Fg(color::Reset),
+            style::Reset,
+            style::Bold,
+            input.display(),
+            style::Reset
+        );
+        std::process::exit(1);
+    }
+    let other = options.other.as_ref().map(Path::new).unwrap_or(input);
+    // the second side is compiled with every option the first is, and then
+    // those in '--with'
+    let other_options = match options.with {
+        Some(ref with) => {
+            let mut args = env::args()
+                .skip(1)
+                .filter(|arg| !arg.starts_with("--with="))
+                .collect::<Vec<_>>();
+            args.extend(with.split_whitespace().map(str::to_string));
+            Options::parse(args).compiler_options()
+        }
+        None => options.compiler_options(),
+    };
+    println!(
+        "{}{}diffing{}{}: '{}{}{}' against '{}{}{}'{}...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset,
+        style::Bold,
+        other.display(),
+        style::Reset,
+        match options.with {
+            Some(ref with) => format!(" with '{}'", with),
+            None => String::new(),
+        }
+    );
+    let now = Instant::now();
+    match slang::diff_asm(input, &options.compiler_options(), other, &other_options) {
+        Ok((diff, warnings)) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            for line in diff.lines() {
+                if line.starts_with("@@") {
+                    println!("{}{}{}", style::Bold, line, style::Reset);
+                } else if line.starts_with('-') {
+                    println!(
+                        "{}{}{}",
+                        color::Fg(color::Red),
+                        line,
+                        color::Fg(color::Reset)
+                    );
+                } else if line.starts_with('+') {
+                    println!(
+                        "{}{}{}",
+                        color::Fg(color::Green),
+                        line,
+                        color::Fg(color::Reset)
+                    );
+                } else {
+                    println!("{}", line);
+                }
+            }
+            println!(
+                "{}{}success{}{}: diffing completed in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: diffing terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn stack_usage(input: &Path, options: &CompilerOptions) {
+    let output = &input.with_extension("stack");
+    println!(
+        "{}{}estimating{}{}: '{}{}{}' to output file '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset,
+        style::Bold,
+        output.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    match slang::compile_stack_usage(input, output, options) {
+        Ok(warnings) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            println!(
+                "{}{}success{}{}: estimating completed in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: estimating terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn metrics(input: &Path, options: &CompilerOptions) {
+    let output = &input.with_extension("metrics");
+    println!(
+        "{}{}measuring{}{}: '{}{}{}' to output file '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset,
+        style::Bold,
+        output.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    match slang::compile_metrics(input, output, options) {
+        Ok(warnings) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            println!(
+                "{}{}success{}{}: measuring completed in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: measuring terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn match_trees(input: &Path, options: &CompilerOptions) {
+    let output = &input.with_extension("match-tree");
+    println!(
+        "{}{}explaining{}{}: '{}{}{}' to output file '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset,
+        style::Bold,
+        output.display(),
+        style::Reset
+    );
+    let now = Instant::now();
+    match slang::compile_match_trees(input, output, options) {
+        Ok(warnings) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            println!(
+                "{}{}success{}{}: explaining completed in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            )
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: explaining terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn compilation_failed(err: String, now: Instant) -> ! {
+    println!("{}", err);
+    println!(
+        "{}{}failure{}{}: compilation terminated after {}{}ms{}",
+        style::Bold,
+        color::Fg(color::Red),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        now.elapsed().as_millis(),
+        style::Reset
+    );
+    std::process::exit(1);
+}
+
+fn compile_to(input: &Path, directory: &Path, options: &Options, compiler: &CompilerOptions) {
+    let target = if options.wasm {
+        Target::Wasm
+    } else if options.emit_c {
+        Target::C
+    } else {
+        Target::Assembly
+    };
+    println!(
+        "{}{}compiling{}{}: '{}{}{}' to output directory '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset,
+        style::Bold,
+        directory.display(),
+        style::Reset
+    );
+    if options.comments && target == Target::Assembly {
+        println!(
+            "{}{}note{}{}: including comments in generated assembly...",
+            style::Bold,
+            color::Fg(color::Magenta),
+            color::Fg(color::Reset),
+            style::Reset,
+        );
+    }
+    let now = Instant::now();
+    let (mut artifacts, warnings) = match slang::compile_to(input, directory, compiler, target) {
+        Ok(compiled) => compiled,
+        Err(err) => compilation_failed(err, now),
+    };
+    for warning in warnings.iter() {
+        println!("{}", warning);
+    }
+    // the manifest lists whatever was written, even if linking fails
+    let linked = if options.autolink {
+        artifacts.link()
+    } else {
+        Ok(())
+    };
+    let manifest = match artifacts.write_manifest() {
+        Ok(manifest) => manifest,
+        Err(err) => compilation_failed(err, now),
+    };
+    if let Err(err) = linked {
+        compilation_failed(err, now);
+    }
+    if let Some(executable) = artifacts.executable() {
+        println!(
+            "{}{}note{}{}: linked into executable '{}{}{}'...",
+            style::Bold,
+            color::Fg(color::Magenta),
+            color::Fg(color::Reset),
+            style::Reset,
+            style::Bold,
+            executable.display(),
+            style::Reset,
+        );
+    }
+    println!(
+        "{}{}note{}{}: listed what was written in '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Magenta),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        manifest.display(),
+        style::Reset,
+    );
+    println!(
+        "{}{}success{}{}: compilation completed in {}{}ms{}",
+        style::Bold,
+        color::Fg(color::Green),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        now.elapsed().as_millis(),
+        style::Reset
+    );
+}
+
+fn run() {
+    let options = Options::init();
+    // the daemon's stdout is where it answers requests, so it's left for them
+    if options.daemon && !options.help {
+        let socket = options.socket.as_ref().map(Path::new);
+        if let Err(err) = slang::daemon(options.compiler_options(), socket) {
+            println!(
+                "{}{}error{}{}: {}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                err
+            );
+            std::process::exit(1);
+        }
+        return;
+    }
+    // like 'c++filt', this is for pipelines, so it only prints what it's asked
+    if options.demangle && !options.help {
+        if options.symbols.is_empty() {
+            let mut text = String::new();
+            if io::stdin().read_to_string(&mut text).is_err() {
+                std::process::exit(1);
+            }
+            print!("{}", slang::demangle_text(&text));
+        }
+        for symbol in options.symbols.iter() {
+            match slang::demangle(symbol) {
+                Some(demangled) => println!("{}", demangled),
+                None => println!("{}", symbol),
+            }
+        }
+        return;
+    }
+    println!("( {}slang{} ) ", style::Bold, style::Reset);
+    if options.help {
+        usage();
+        return;
+    }
+    if options.repl {
+        slang::repl(options.input(), &options.compiler_options());
+        return;
+    }
+    let input = match options.input {
+        Some(ref input) => input,
+        None => {
+            println!(
+                "{}{}error{}{}: no input file given! (see '--help' for usage)",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    };
+    let input = Path::new(input);
+    let compiler_options = options.compiler_options();
+    if options.diff_asm {
+        diff_asm(input, &options);
+        return;
+    }
+    if options.check {
+        check(input, &compiler_options);
+        return;
+    }
+    if options.interpret {
+        interpret(input, options.input(), &compiler_options, options.stats);
+        return;
+    }
+    if options.jit {
+        jit(input, &compiler_options);
+        return;
+    }
+    if options.vm {
+        vm(input, options.input(), &compiler_options);
+        return;
+    }
+    if options.coverage {
+        coverage(input, options.input(), &compiler_options);
+        return;
+    }
+    if options.debug {
+        if let Err(err) = slang::debug(input, options.input(), &compiler_options) {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if options.cross_check {
+        // both evaluators have to see the same numbers, so neither reads stdin
+        let what = match options.input() {
+            Input::Stdin => Input::Random(0),
+            what => what,
+        };
+        cross_check(input, what, &compiler_options);
+        return;
+    }
+    if let Some(trials) = options.validate {
+        if input.is_dir() {
+            validate_corpus(input, &compiler_options, trials);
+        } else {
+            validate(input, &compiler_options, trials);
+        }
+        return;
+    }
+    if let Some(trials) = options.mutate {
+        mutate(input, &compiler_options, trials);
+        return;
+    }
+    if let Some(ref command) = options.minimise {
+        minimise(input, command, &compiler_options);
+        return;
+    }
+    if options.callgraph || options.callgraph_json {
+        callgraph(input, options.callgraph_json, &compiler_options);
+        return;
+    }
+    if options.stack_usage {
+        stack_usage(input, &compiler_options);
+        return;
+    }
+    if options.metrics {
+        metrics(input, &compiler_options);
+        return;
+    }
+    if options.match_tree {
+        match_trees(input, &compiler_options);
+        return;
+    }
+    if let Some(ref directory) = options.out_dir {
+        compile_to(input, Path::new(directory), &options, &compiler_options);
+        return;
+    }
+    let output = &input.with_extension(if options.wasm {
+        "wasm"
+    } else if options.emit_c {
+        "c"
+    } else {
+        "s"
+    });
+    println!(
+        "{}{}compiling{}{}: '{}{}{}' to output file '{}{}{}'...",
+        style::Bold,
+        color::Fg(color::Blue),
+        color::Fg(color::Reset),
+        style::Reset,
+        style::Bold,
+        input.display(),
+        style::Reset,
+        style::Bold,
+        output.display(),
+        style::Reset
+    );
+    if options.comments && !options.wasm && !options.emit_c {
+        println!(
+            "{}{}note{}{}: including comments in generated assembly...",
+            style::Bold,
+            color::Fg(color::Magenta),
+            color::Fg(color::Reset),
+            style::Reset,
+        );
+    }
+    let now = Instant::now();
+    let compiled = if options.wasm {
+        slang::compile_wasm(input, output, &compiler_options)
+    } else if options.emit_c {
+        slang::compile_c(input, output, &compiler_options)
+    } else {
+        slang::compile(input, output, &compiler_options)
+    };
+    match compiled {
+        Ok(warnings) => {
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            println!(
+                "{}{}success{}{}: compilation completed in {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Green),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            // a module doesn't need linking
+            if options.autolink && !options.wasm {
+                let executable = &input.with_extension("");
+                println!(
+                    "{}{}note{}{}: linking into executable '{}{}{}'...",
+                    style::Bold,
+                    color::Fg(color::Magenta),
+                    color::Fg(color::Reset),
+                    style::Reset,
+                    style::Bold,
+                    executable.display(),
+                    style::Reset,
+                );
+                // C carries its own runtime, and optimising turns most calls
+                // in tail position into jumps
+                if options.emit_c {
+                    Command::new("cc")
+                        .args([
+                            "-std=c99",
+                            "-O2",
+                            "-o",
+                            &format!("{}", executable.display()),
+                            &format!("{}", output.display()),
+                        ])
+                        .status()
+                        .unwrap();
+                } else {
+                    Command::new("gcc")
+                        .args([
+                            "-o",
+                            &format!("{}", executable.display()),
+                            &format!("{}", output.display()),
+                            concat!("-L", env!("OUT_DIR")),
+                            "-lslangrt",
+                            "-pthread",
+                        ])
+                        .status()
+                        .unwrap();
+                }
+            }
+        }
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "{}{}failure{}{}: compilation terminated after {}{}ms{}",
+                style::Bold,
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                style::Reset,
+                style::Bold,
+                now.elapsed().as_millis(),
+                style::Reset
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    // the frontend recurses over the syntax tree (up to '--max-depth' levels),
+    // so we give it more room than the main thread gets by default
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run)
+        .unwrap()
+        .join()
+        .unwrap();
+}
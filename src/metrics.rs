@@ -0,0 +1,189 @@
+use super::backend::GeneratedCode;
+use super::frontend::ast::Expr;
+use super::ir::{Binding, Block, Op};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// the name each construct is counted under
+fn construct(expr: &Expr) -> &'static str {
+    use self::Expr::*;
+    match *expr {
+        Unit => "unit",
+        What => "what",
+        Var(_) => "var",
+        Int(_) => "int",
+        Float(_) => "float",
+        Bool(_) => "bool",
+        Str(_) => "string",
+        UnOp(_, _) => "unary",
+        BinOp(_, _, _) => "binary",
+        Div(_, _, _) => "div",
+        Mod(_, _, _) => "mod",
+        If(_, _, _) => "if",
+        Pair(_, _) => "pair",
+        Fst(_) => "fst",
+        Snd(_) => "snd",
+        Inl(_, _) => "inl",
+        Inr(_, _) => "inr",
+        Case(_, _, _) => "case",
+        While(_, _) => "while",
+        For(_, _, _) => "for",
+        Seq(_) => "seq",
+        Ref(_) => "ref",
+        Deref(_) => "deref",
+        Lazy(_) => "lazy",
+        Force(_, _) => "force",
+        Chan => "chan",
+        Spawn(_) => "spawn",
+        Raise(_, _) => "raise",
+        Try(_, _) => "try",
+        Send(_, _, _) => "send",
+        Recv(_, _) => "recv",
+        Print(_) => "print",
+        Expect(_, _) => "expect",
+        Attributed(_, _) => "attributed",
+        Assign(_, _) => "assign",
+        MakeArray(_, _, _) => "make_array",
+        Index(_, _, _) => "index",
+        Update(_, _, _, _) => "update",
+        Nil => "nil",
+        Cons(_, _) => "cons",
+        Head(_, _) => "head",
+        Tail(_, _) => "tail",
+        IsEmpty(_) => "is_empty",
+        Lambda(_) => "lambda",
+        App(_, _) => "app",
+        Let(_, _, _) => "let",
+        LetFun(_, _, _) => "let_fun",
+        LetRec(_, _) => "let_rec",
+    }
+}
+
+// what an operation allocates on the heap, if anything (a function is counted
+// as a closure even where the backend doesn't need to make one)
+fn allocation(op: &Op) -> Option<&'static str> {
+    match *op {
+        Op::Pair(_, _) => Some("pair"),
+        Op::Inl(_) | Op::Inr(_) => Some("sum"),
+        Op::Ref(_) => Some("ref"),
+        Op::MakeArray(_, _, _) => Some("array"),
+        Op::Cons(_, _) => Some("cons"),
+        Op::Lazy(_) => Some("lazy"),
+        Op::Chan => Some("channel"),
+        Op::Spawn(_) => Some("thread"),
+        Op::Lambda(_, _) => Some("closure"),
+        Op::Str(_) => Some("string"),
+        _ => None,
+    }
+}
+
+// counts the places in a block (and the blocks in it) that allocate, by what
+// they allocate
+fn allocations(block: &Block, counts: &mut BTreeMap<&'static str, usize>) {
+    let mut ops = vec![];
+    for binding in block.bindings.iter() {
+        match *binding {
+            Binding::Let(_, ref op) | Binding::Do(ref op) => ops.push(op),
+            Binding::LetFun(_, _, ref body) => {
+                *counts.entry("closure").or_insert(0) += 1;
+                allocations(body, counts);
+            }
+            Binding::LetRec(ref functions) => {
+                for (_, _, body) in functions.iter() {
+                    *counts.entry("closure").or_insert(0) += 1;
+                    allocations(body, counts);
+                }
+            }
+        }
+    }
+    ops.push(&block.result);
+    for op in ops {
+        if let Some(allocation) = allocation(op) {
+            *counts.entry(allocation).or_insert(0) += 1;
+        }
+        match *op {
+            Op::If(_, ref left, ref right, _)
+            | Op::Case(_, (_, ref left), (_, ref right))
+            | Op::While(ref left, ref right) => {
+                allocations(left, counts);
+                allocations(right, counts);
+            }
+            Op::Lazy(ref body)
+            | Op::Spawn(ref body)
+            | Op::Lambda(_, ref body)
+            | Op::For(_, _, (_, ref body)) => allocations(body, counts),
+            _ => (),
+        }
+    }
+}
+
+// a JSON object of counts, with its keys in order
+fn object(counts: &BTreeMap<&str, usize>) -> String {
+    let fields = counts
+        .iter()
+        .map(|(name, count)| format!("\"{}\": {}", name, count))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(", "))
+}
+
+// statistics about a program, as JSON: how many of each construct it's written
+// with and how deeply its expressions are nested ('source', as the frontend
+// gives it), where what it compiles to allocates ('block', once it's been
+// optimised) and which instructions it's compiled into ('code')
+pub fn metrics(source: &Expr, block: &Block, code: &GeneratedCode) -> String {
+    let mut constructs = BTreeMap::new();
+    let (mut expressions, mut depths, mut deepest) = (0, 0, 0);
+    let mut work = vec![(source, 1)];
+    while let Some((expr, depth)) = work.pop() {
+        *constructs.entry(construct(expr)).or_insert(0) += 1;
+        expressions += 1;
+        depths += depth;
+        deepest = deepest.max(depth);
+        work.extend(expr.children().into_iter().map(|sub| (sub, depth + 1)));
+    }
+    let mut allocated = BTreeMap::new();
+    allocations(block, &mut allocated);
+    let mut mix = BTreeMap::new();
+    let mut text = String::new();
+    for instruction in code.instructions() {
+        write!(text, "{}", instruction).unwrap();
+    }
+    // only the lines that are indented are instructions (the rest are labels
+    // and directives)
+    for line in text.lines().filter(|line| line.starts_with('\t')) {
+        if let Some(mnemonic) = line.split_whitespace().next() {
+            *mix.entry(mnemonic).or_insert(0) += 1;
+        }
+    }
+    let mut json = String::new();
+    writeln!(json, "{{").unwrap();
+    writeln!(json, "  \"expressions\": {},", expressions).unwrap();
+    writeln!(json, "  \"constructs\": {},", object(&constructs)).unwrap();
+    writeln!(
+        json,
+        "  \"average_depth\": {:.2},",
+        depths as f64 / expressions as f64
+    )
+    .unwrap();
+    writeln!(json, "  \"max_depth\": {},", deepest).unwrap();
+    writeln!(json, "  \"functions\": {},", code.functions().len()).unwrap();
+    writeln!(json, "  \"instructions\": {},", mix.values().sum::<usize>()).unwrap();
+    writeln!(json, "  \"instruction_mix\": {},", object(&mix)).unwrap();
+    let (saved, skipped) = code
+        .functions()
+        .iter()
+        .map(|function| function.saved_registers())
+        .fold((0, 0), |(a, b), (c, d)| (a + c, b + d));
+    writeln!(json, "  \"saved_registers\": {},", saved).unwrap();
+    writeln!(json, "  \"saved_registers_skipped\": {},", skipped).unwrap();
+    writeln!(
+        json,
+        "  \"allocation_sites\": {},",
+        allocated.values().sum::<usize>()
+    )
+    .unwrap();
+    writeln!(json, "  \"allocations\": {}", object(&allocated)).unwrap();
+    writeln!(json, "}}").unwrap();
+    json
+}
@@ -0,0 +1,95 @@
+use super::{frontend, read, write, CompilerOptions};
+
+use termion::{color, style};
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how long a command gets to run on each program, as making a program smaller
+// can turn it into one that never finishes (by dropping a loop's condition, say)
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn error(message: String) -> String {
+    format!(
+        "{}{}error{}{}: {}",
+        style::Bold,
+        color::Fg(color::Red),
+        color::Fg(color::Reset),
+        style::Reset,
+        message
+    )
+}
+
+// runs a command through the shell on the program at 'path' (which takes the
+// place of '{}' in it, or goes on the end if there isn't one), returning how it
+// exited, if it did in time
+fn run(command: &str, path: &Path) -> Result<Option<ExitStatus>, String> {
+    let path = format!("{}", path.display());
+    let command = if command.contains("{}") {
+        command.replace("{}", &path)
+    } else {
+        format!("{} {}", command, path)
+    };
+    let mut child = match Command::new("sh")
+        .args(["-c", &command])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(_) => return Err(error(format!("failed to run '{}'", command))),
+    };
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(Some(status)),
+            Ok(None) if started.elapsed() > TIMEOUT => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Ok(None);
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(1)),
+            Err(_) => return Err(error(format!("failed to run '{}'", command))),
+        }
+    }
+}
+
+// shrinks a program that a command fails on to the smallest well-typed program
+// it still fails on in the same way (exiting with the same status), which is
+// left in a file next to it ('p.min.slang' for 'p.slang'), returning where that
+// is along with how long the program was before and after
+pub fn minimise(
+    input: &Path,
+    command: &str,
+    options: &CompilerOptions,
+) -> Result<(PathBuf, usize, usize), String> {
+    let text = read(input)?;
+    let output = input.with_extension("min.slang");
+    write(&output, text.as_bytes())?;
+    let failure = match run(command, &output)? {
+        Some(status) if !status.success() => status,
+        _ => {
+            return Err(error(format!(
+                "'{}' doesn't fail on '{}{}{}', so there's nothing to minimise",
+                command,
+                style::Bold,
+                input.display(),
+                style::Reset
+            )))
+        }
+    };
+    let minimised = frontend::minimise(
+        &format!("{}", input.display()),
+        text.clone(),
+        options,
+        &mut |candidate| {
+            write(&output, candidate.as_bytes())?;
+            Ok(run(command, &output)? == Some(failure))
+        },
+    )?;
+    write(&output, minimised.as_bytes())?;
+    Ok((output, text.len(), minimised.len()))
+}
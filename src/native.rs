@@ -0,0 +1,504 @@
+use super::backend::{self, GeneratedCode};
+use super::frontend::ast::{Expr, Free};
+use super::frontend::{self, TypeExpr};
+use super::interp::{Input, Interpreter, Limits};
+use super::reflect::Value;
+use super::{ir, opt, CompilerOptions};
+
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use termion::{color, style};
+
+extern "C" {
+    fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlclose(handle: *mut c_void) -> c_int;
+    fn dlerror() -> *const c_char;
+}
+
+const RTLD_NOW: c_int = 2;
+
+// so that every library loaded by this process gets its own file
+static LOADED: AtomicUsize = AtomicUsize::new(0);
+
+type Entry = unsafe extern "C" fn() -> i64;
+type Apply = unsafe extern "C" fn(i64, i64) -> i64;
+
+// a program compiled into a shared library (along with the runtime), which is
+// unloaded and deleted when dropped
+struct Library {
+    handle: *mut c_void,
+    path: PathBuf,
+}
+
+impl Library {
+    fn open(path: PathBuf) -> Result<Library, String> {
+        let filename = CString::new(format!("{}", path.display())).unwrap();
+        let handle = unsafe { dlopen(filename.as_ptr(), RTLD_NOW) };
+        if handle.is_null() {
+            return Err(error(format!("failed to load '{}'", last_error())));
+        }
+        Ok(Library { handle, path })
+    }
+
+    fn symbol(&self, name: &str) -> Result<*mut c_void, String> {
+        let symbol = CString::new(name).unwrap();
+        let address = unsafe { dlsym(self.handle, symbol.as_ptr()) };
+        if address.is_null() {
+            return Err(error(format!("failed to find '{}'", name)));
+        }
+        Ok(address)
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe {
+            dlclose(self.handle);
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn last_error() -> String {
+    unsafe {
+        let message = dlerror();
+        if message.is_null() {
+            "unknown error".to_string()
+        } else {
+            CStr::from_ptr(message).to_string_lossy().into_owned()
+        }
+    }
+}
+
+fn error(message: String) -> String {
+    format!(
+        "{}{}error{}{}: {}",
+        style::Bold,
+        color::Fg(color::Red),
+        color::Fg(color::Reset),
+        style::Reset,
+        message
+    )
+}
+
+// links generated code (along with the runtime) into a shared library, or into
+// an executable if it's not to be loaded
+fn link(code: &GeneratedCode, shared: bool) -> Result<PathBuf, String> {
+    let name = format!(
+        "slang-{}-{}",
+        process::id(),
+        LOADED.fetch_add(1, Ordering::SeqCst)
+    );
+    let assembly = env::temp_dir().join(format!("{}.s", name));
+    let library = env::temp_dir().join(if shared { format!("{}.so", name) } else { name });
+    if code.write_to(&assembly).is_err() {
+        return Err(error(format!(
+            "failed to write to '{}{}{}'",
+            style::Bold,
+            assembly.display(),
+            style::Reset
+        )));
+    }
+    let status = if shared {
+        // generated code refers to the runtime's globals relative to '%rip',
+        // which only links into a shared library if they can't be overridden
+        Command::new("gcc")
+            .args([
+                "-shared",
+                "-Wl,-Bsymbolic",
+                "-o",
+                &format!("{}", library.display()),
+                &format!("{}", assembly.display()),
+                concat!("-L", env!("OUT_DIR")),
+                "-Wl,--whole-archive",
+                "-lslangrt",
+                "-Wl,--no-whole-archive",
+                "-pthread",
+            ])
+            .status()
+    } else {
+        Command::new("gcc")
+            .args([
+                "-o",
+                &format!("{}", library.display()),
+                &format!("{}", assembly.display()),
+                concat!("-L", env!("OUT_DIR")),
+                "-lslangrt",
+                "-pthread",
+            ])
+            .status()
+    };
+    let _ = fs::remove_file(&assembly);
+    match status {
+        Ok(status) if status.success() => Ok(library),
+        _ => Err(error("failed to link generated code".to_string())),
+    }
+}
+
+// something the host owns on behalf of a loaded program (like a file handle or
+// a socket), which the program only sees as an integer, and which is cleaned up
+// when the program is dropped (the runtime never frees anything, so there's
+// nothing sooner to tie it to)
+pub struct Resource {
+    handle: i64,
+    cleanup: Option<Box<dyn FnOnce(i64)>>,
+}
+
+impl Resource {
+    pub fn new(handle: i64, cleanup: impl FnOnce(i64) + 'static) -> Resource {
+        Resource {
+            handle,
+            cleanup: Some(Box::new(cleanup)),
+        }
+    }
+
+    // what to pass to the program in place of the resource
+    pub fn handle(&self) -> i64 {
+        self.handle
+    }
+}
+
+impl Drop for Resource {
+    fn drop(&mut self) {
+        if let Some(cleanup) = self.cleanup.take() {
+            cleanup(self.handle);
+        }
+    }
+}
+
+// compiles a program of type 'int -> int' and loads it into this process, so
+// that the generated code can be called directly (for testing the backend)
+pub fn load(source: &str, options: &CompilerOptions) -> Result<impl Fn(i64) -> i64, String> {
+    load_with(source, options, vec![])
+}
+
+// as 'load', but the program holds on to some resources until it's dropped
+// (after which its code is unloaded, so nothing can use them any more)
+pub fn load_with(
+    source: &str,
+    options: &CompilerOptions,
+    resources: Vec<Resource>,
+) -> Result<impl Fn(i64) -> i64, String> {
+    let (ast, type_expr, _) = frontend::typed_frontend("<source>", source.to_string(), options)?;
+    let expected = TypeExpr::Arrow(Box::new(TypeExpr::Int), Box::new(TypeExpr::Int));
+    if type_expr != expected {
+        return Err(error(format!(
+            "expected a program of type '{}', found one of type '{}'",
+            expected, type_expr
+        )));
+    }
+    let ast = opt::optimise(ast, options);
+    let library = Library::open(link(&backend::generate(ir::lower(ast), options), true)?)?;
+    let (entry, apply) = unsafe {
+        (
+            std::mem::transmute::<*mut c_void, Entry>(library.symbol("entry")?),
+            std::mem::transmute::<*mut c_void, Apply>(library.symbol("slang_apply")?),
+        )
+    };
+    // running the program builds the closure, which is then kept for every call
+    let closure = unsafe { entry() };
+    // a tuple's fields are dropped in order, so the library is unloaded before
+    // any resources are cleaned up
+    let held = (library, resources);
+    Ok(move |arg| {
+        let _ = &held;
+        unsafe { apply(closure, arg) }
+    })
+}
+
+// an executable linked from generated code, which is deleted when dropped
+struct Executable {
+    path: PathBuf,
+}
+
+impl Drop for Executable {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// whether every part of a program runs at most once, so that it always
+// finishes and runs the same way in compiled code as in the interpreter (this
+// rules out loops, recursive functions and threads)
+fn straight_line(expr: &Expr) -> bool {
+    let mut work = vec![expr];
+    while let Some(expr) = work.pop() {
+        match *expr {
+            Expr::While(_, _) | Expr::Spawn(_) | Expr::LetRec(_, _) => return false,
+            Expr::LetFun(ref f, ref lambda, _) if lambda.fv().contains(f) => return false,
+            _ => work.extend(expr.children()),
+        }
+    }
+    true
+}
+
+// what a compiled program prints for a value, if it prints the value itself
+// rather than a pointer to it
+fn printed(value: &Value) -> Option<String> {
+    match *value {
+        Value::Unit => Some("0".to_string()),
+        Value::Int(i) => Some(format!("{}", i)),
+        Value::Bool(b) => Some(format!("{}", b as i64)),
+        _ => None,
+    }
+}
+
+// a message with its styling taken out (as the runtime doesn't style its own)
+fn plain(message: &str) -> String {
+    let mut plain = String::new();
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+fn describe(result: &Result<Value, String>) -> String {
+    match *result {
+        Ok(ref value) => format!("'{}{}{}'", style::Bold, value, style::Reset),
+        Err(ref err) => format!("an error ({})", err),
+    }
+}
+
+// how long a run of generated code gets before it's taken to never finish
+// (which a program without loops can only do if its code is broken)
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// what a program prints, then its value or the error it fails with
+type Outcome = (String, Result<Value, String>);
+
+// what the interpreter makes of a program (and what it prints along the way)
+// with the numbers for '?' drawn from every seed below 'trials' (references to
+// functions can still be used to recurse, so a program that runs for too long,
+// or recurses too deeply for the stack it's interpreted on, is given up on
+// rather than run natively)
+fn expected(ast: &Expr, options: &CompilerOptions, trials: u64) -> Result<Vec<Outcome>, String> {
+    let mut results = vec![];
+    for seed in 0..trials {
+        let limits = Limits {
+            steps: Some(1 << 24),
+            depth: Some(2048),
+        };
+        let mut interpreter = Interpreter::new(Input::Random(seed), limits, options.int_width);
+        interpreter.capture_output();
+        let expected = interpreter.run(&None, ast).map(|value| Value::from(&value));
+        if matches!(expected, Err(ref err) if err.contains("limit exceeded")) {
+            return Err(error(
+                "the program runs for too long to be validated".to_string(),
+            ));
+        }
+        results.push((interpreter.output(), expected));
+    }
+    Ok(results)
+}
+
+// runs an executable with 'SLANG_SEED' set, giving what it printed to stdout and
+// stderr if it finished in time, along with whether it succeeded
+fn run_seeded(path: &Path, seed: u64) -> Result<Option<(bool, String, String)>, String> {
+    let mut child = match Command::new(path)
+        .env("SLANG_SEED", format!("{}", seed))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(_) => return Err(error("failed to run generated code".to_string())),
+    };
+    let started = Instant::now();
+    while let Ok(None) = child.try_wait() {
+        if started.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    let output = match child.wait_with_output() {
+        Ok(output) => output,
+        Err(_) => return Err(error("failed to run generated code".to_string())),
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    Ok(Some((output.status.success(), stdout, stderr)))
+}
+
+// runs an executable with each seed in turn, failing on the first where it
+// disagrees with what the interpreter expects
+fn compare(path: &Path, expected: &[Outcome]) -> Result<(), String> {
+    for (seed, (output, expected)) in expected.iter().enumerate() {
+        let seed = seed as u64;
+        let (success, stdout, stderr) = match run_seeded(path, seed)? {
+            Some(output) => output,
+            None => {
+                return Err(error(format!(
+                    "the generated code never finished for seed {}: expected {}",
+                    seed,
+                    describe(expected)
+                )))
+            }
+        };
+        let agree = match *expected {
+            // anything the program printed comes before its value
+            Ok(ref value) if success => match printed(value) {
+                Some(printed) => format!("{}{}", output, printed).trim() == stdout,
+                None => stdout.starts_with(output.trim_start()),
+            },
+            // the runtime reports an error as the interpreter does
+            Err(ref err) if !success => plain(err) == stderr,
+            _ => false,
+        };
+        if !agree {
+            let found = if success {
+                format!("'{}{}{}'", style::Bold, stdout, style::Reset)
+            } else {
+                format!("an error ({})", stderr)
+            };
+            return Err(error(format!(
+                "the generated code disagrees with the interpreter for seed {}: expected {}, found {}",
+                seed,
+                describe(expected),
+                found
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn straight_line_program(
+    name: &str,
+    source: String,
+    options: &CompilerOptions,
+) -> Result<(Expr, Vec<String>), String> {
+    let (ast, warnings) = frontend::frontend(name, source, options)?;
+    let ast = opt::optimise(ast, options);
+    if !straight_line(&ast) {
+        return Err(error(
+            "only programs without loops, recursive functions or threads can be validated"
+                .to_string(),
+        ));
+    }
+    Ok((ast, warnings))
+}
+
+// checks the backend against the interpreter: the optimised program is both
+// compiled and interpreted, and each is run with the numbers for '?' drawn from
+// every seed below 'trials', failing on the first seed where they disagree on
+// its value or on whether (and where) it fails at runtime
+pub fn validate(
+    name: &str,
+    source: String,
+    options: &CompilerOptions,
+    trials: u64,
+) -> Result<Vec<String>, String> {
+    let (ast, warnings) = straight_line_program(name, source, options)?;
+    let executable = Executable {
+        path: link(&backend::generate(ir::lower(ast.clone()), options), false)?,
+    };
+    compare(&executable.path, &expected(&ast, options, trials)?)?;
+    Ok(warnings)
+}
+
+// what validating every program in a corpus came to: the programs whose
+// generated code agreed with the interpreter, the ones that couldn't be
+// validated (as they don't check, have loops, recursive functions or threads,
+// or run for too long in the interpreter), and the ones it didn't agree on,
+// along with how
+pub struct Corpus {
+    pub validated: Vec<PathBuf>,
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+// validates each program in a corpus (given by its path and source) with
+// 'trials' seeds, carrying on past any that fail
+pub fn validate_corpus(
+    programs: Vec<(PathBuf, String)>,
+    options: &CompilerOptions,
+    trials: u64,
+) -> Corpus {
+    let mut corpus = Corpus {
+        validated: vec![],
+        skipped: vec![],
+        failed: vec![],
+    };
+    for (path, source) in programs {
+        let skipped = straight_line_program(&format!("{}", path.display()), source, options)
+            .and_then(|(ast, _)| Ok((expected(&ast, options, trials)?, ast)));
+        let (expected, ast) = match skipped {
+            Ok(program) => program,
+            Err(_) => {
+                corpus.skipped.push(path);
+                continue;
+            }
+        };
+        let validated = link(&backend::generate(ir::lower(ast), options), false).and_then(|path| {
+            let executable = Executable { path };
+            compare(&executable.path, &expected)
+        });
+        match validated {
+            Ok(()) => corpus.validated.push(path),
+            Err(err) => corpus.failed.push((path, err)),
+        }
+    }
+    corpus
+}
+
+// how well validation catches broken code: how many of the mutants of a
+// program's generated code (see 'GeneratedCode::mutants') it caught, and what
+// was changed in each of the ones it didn't
+pub struct Mutation {
+    pub caught: usize,
+    pub survived: Vec<String>,
+}
+
+// measures how strong validation with 'trials' seeds is for a program, by
+// validating every mutant of its generated code in place of the real thing (the
+// program itself has to validate first)
+pub fn mutate(
+    name: &str,
+    source: String,
+    options: &CompilerOptions,
+    trials: u64,
+) -> Result<(Mutation, Vec<String>), String> {
+    let (ast, warnings) = straight_line_program(name, source, options)?;
+    let expected = expected(&ast, options, trials)?;
+    let code = backend::generate(ir::lower(ast), options);
+    let executable = Executable {
+        path: link(&code, false)?,
+    };
+    compare(&executable.path, &expected)?;
+    let mut mutation = Mutation {
+        caught: 0,
+        survived: vec![],
+    };
+    for mutant in code.mutants() {
+        // code that doesn't even link (like a jump to a dropped label) counts
+        // as caught
+        let caught = match link(&mutant.code, false) {
+            Ok(path) => {
+                let executable = Executable { path };
+                compare(&executable.path, &expected).is_err()
+            }
+            Err(_) => true,
+        };
+        if caught {
+            mutation.caught += 1;
+        } else {
+            mutation.survived.push(mutant.description);
+        }
+    }
+    Ok((mutation, warnings))
+}
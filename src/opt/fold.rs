@@ -0,0 +1,76 @@
+use super::super::frontend::ast::{self, Expr};
+use super::super::IntWidth;
+
+pub fn is_literal(expr: &Expr) -> bool {
+    matches!(
+        *expr,
+        Expr::Unit | Expr::Int(_) | Expr::Float(_) | Expr::Bool(_)
+    )
+}
+
+// simplifies a single node whose children have already been simplified (with
+// arithmetic wrapping around at 'width' bits, as it does at runtime)
+pub fn fold(expr: Expr, width: IntWidth) -> Expr {
+    use self::ast::BinOp::*;
+    use self::ast::UnOp::*;
+    match expr {
+        Expr::UnOp(op, sub) => match (op, *sub) {
+            (Neg, Expr::Int(i)) => Expr::Int(width.wrap(i.wrapping_neg())),
+            (Not, Expr::Bool(b)) => Expr::Bool(!b),
+            (op, sub) => Expr::UnOp(op, Box::new(sub)),
+        },
+        Expr::BinOp(op, left, right) => match (op, *left, *right) {
+            (Add, Expr::Int(a), Expr::Int(b)) => Expr::Int(width.wrap(a.wrapping_add(b))),
+            (Sub, Expr::Int(a), Expr::Int(b)) => Expr::Int(width.wrap(a.wrapping_sub(b))),
+            (Mul, Expr::Int(a), Expr::Int(b)) => Expr::Int(width.wrap(a.wrapping_mul(b))),
+            (Lt, Expr::Int(a), Expr::Int(b)) => Expr::Bool(a < b),
+            (Eq, Expr::Int(a), Expr::Int(b)) | (PhysEq, Expr::Int(a), Expr::Int(b)) => {
+                Expr::Bool(a == b)
+            }
+            (Eq, Expr::Bool(a), Expr::Bool(b)) | (PhysEq, Expr::Bool(a), Expr::Bool(b)) => {
+                Expr::Bool(a == b)
+            }
+            (Eq, Expr::Unit, Expr::Unit) | (PhysEq, Expr::Unit, Expr::Unit) => Expr::Bool(true),
+            // float arithmetic never traps, and rounds the same way everywhere
+            (FloatAdd, Expr::Float(a), Expr::Float(b)) => Expr::Float(a + b),
+            (FloatSub, Expr::Float(a), Expr::Float(b)) => Expr::Float(a - b),
+            (FloatMul, Expr::Float(a), Expr::Float(b)) => Expr::Float(a * b),
+            (FloatDiv, Expr::Float(a), Expr::Float(b)) => Expr::Float(a / b),
+            (FloatLt, Expr::Float(a), Expr::Float(b)) => Expr::Bool(a < b),
+            (FloatEq, Expr::Float(a), Expr::Float(b)) => Expr::Bool(a == b),
+            (And, Expr::Bool(true), right) | (Or, Expr::Bool(false), right) => right,
+            (And, Expr::Bool(false), _) => Expr::Bool(false),
+            (Or, Expr::Bool(true), _) => Expr::Bool(true),
+            (Add, Expr::Int(0), sub)
+            | (Add, sub, Expr::Int(0))
+            | (Sub, sub, Expr::Int(0))
+            | (Mul, Expr::Int(1), sub)
+            | (Mul, sub, Expr::Int(1)) => sub,
+            (op, left, right) => Expr::BinOp(op, Box::new(left), Box::new(right)),
+        },
+        Expr::Div(location, left, right) => match (*left, *right) {
+            // division by zero (or overflow) has to trap at runtime, so leave those alone
+            (Expr::Int(a), Expr::Int(b)) if matches!(a.checked_div(b), Some(i) if width.wrap(i) == i) => {
+                Expr::Int(a / b)
+            }
+            (sub, Expr::Int(1)) => sub,
+            (left, right) => Expr::Div(location, Box::new(left), Box::new(right)),
+        },
+        // the remainder always fits, even when the division overflows
+        Expr::Mod(location, left, right) => match (*left, *right) {
+            (Expr::Int(a), Expr::Int(b)) if b != 0 => Expr::Int(a.wrapping_rem(b)),
+            (left, right) => Expr::Mod(location, Box::new(left), Box::new(right)),
+        },
+        // a condition that's already known needs no hint
+        Expr::Expect(sub, likely) => match *sub {
+            Expr::Bool(b) => Expr::Bool(b),
+            sub => Expr::Expect(Box::new(sub), likely),
+        },
+        Expr::If(condition, left, right) => match *condition {
+            Expr::Bool(true) => *left,
+            Expr::Bool(false) => *right,
+            condition => Expr::If(Box::new(condition), left, right),
+        },
+        expr => expr,
+    }
+}
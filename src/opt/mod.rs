@@ -0,0 +1,45 @@
+use super::frontend::ast::Expr;
+use super::CompilerOptions;
+
+mod fold;
+mod propagate;
+mod specialise;
+mod unroll;
+mod verify;
+
+// in debug builds, the program is checked after each pass, so that a pass that
+// breaks it is caught there rather than by whatever runs it later
+fn verified(pass: &str, expr: Expr) -> Expr {
+    if cfg!(debug_assertions) {
+        if let Err(err) = verify::verify(&expr) {
+            panic!("{} left an invalid program: {}", pass, err);
+        }
+    }
+    expr
+}
+
+pub fn optimise(expr: Expr, options: &CompilerOptions) -> Expr {
+    let mut expr = verified("lowering", expr);
+    if options.propagate {
+        expr = verified("propagation", propagate::propagate(expr, options.int_width));
+    }
+    if options.fold {
+        expr = verified("folding", specialise::simplify(expr, options.int_width));
+    }
+    if options.specialise > 0 {
+        expr = verified(
+            "specialisation",
+            specialise::specialise(expr, options.specialise, options.int_width),
+        );
+    } else if specialise::asks_to_inline(&expr) {
+        // '[@inline]' is respected without '--specialise'
+        expr = verified("inlining", specialise::inline(expr, options.int_width));
+    }
+    if options.unroll > 0 {
+        expr = verified(
+            "unrolling",
+            unroll::unroll(expr, options.unroll, options.int_width),
+        );
+    }
+    expr
+}
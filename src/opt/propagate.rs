@@ -0,0 +1,161 @@
+use super::super::frontend::ast::{self, Definition, Expr};
+use super::super::IntWidth;
+use super::fold::is_literal;
+use super::specialise::{simplify, substitute};
+
+fn same(a: &Expr, b: &Expr) -> bool {
+    match (a, b) {
+        (Expr::Unit, Expr::Unit) => true,
+        (Expr::Int(a), Expr::Int(b)) => a == b,
+        (Expr::Float(a), Expr::Float(b)) => a.to_bits() == b.to_bits(),
+        (Expr::Bool(a), Expr::Bool(b)) => a == b,
+        _ => false,
+    }
+}
+
+// whether 'f' is only ever called in 'expr', and always with the same literal
+// argument, which is recorded in 'literal' (inside 'f' itself, a call may also
+// pass 'param' straight back, as that's the same literal again)
+fn called_with(f: &str, param: Option<&str>, expr: &Expr, literal: &mut Option<Expr>) -> bool {
+    use self::Expr::*;
+    let mut called_with = |param: Option<&str>, expr: &Expr| called_with(f, param, expr, literal);
+    match *expr {
+        Var(ref g) => g != f,
+        App(ref left, ref right) if matches!(**left, Var(ref g) if g == f) => match **right {
+            Var(ref w) => Some(w.as_str()) == param,
+            ref arg if is_literal(arg) => match *literal {
+                Some(ref literal) => same(literal, arg),
+                None => {
+                    *literal = Some(arg.clone());
+                    true
+                }
+            },
+            _ => false,
+        },
+        // a binder for 'f' hides it, and a binder for 'param' means something else
+        Lambda((ref w, ref body)) => w == f || called_with(param.filter(|v| v != w), body),
+        // a chain of definitions is walked in a loop, as a program of
+        // top-level definitions is one
+        Let(..) | LetFun(..) | LetRec(..) => {
+            let (definitions, body) = ast::definitions(expr);
+            let mut param = param;
+            for definition in definitions {
+                match *definition {
+                    Let(ref w, ref sub, _) => {
+                        if !called_with(param, sub) {
+                            return false;
+                        }
+                        if w == f {
+                            return true;
+                        }
+                        param = param.filter(|v| v != w);
+                    }
+                    LetFun(ref g, (ref w, ref lambda), _) => {
+                        param = param.filter(|v| v != g);
+                        if g == f {
+                            return true;
+                        }
+                        if !(w == f || called_with(param.filter(|v| v != w), lambda)) {
+                            return false;
+                        }
+                    }
+                    LetRec(ref functions, _) => {
+                        param = param.filter(|v| functions.iter().all(|(g, _)| g != v));
+                        if functions.iter().any(|(g, _)| g == f) {
+                            return true;
+                        }
+                        if !functions.iter().all(|(_, (ref w, ref lambda))| {
+                            w == f || called_with(param.filter(|v| v != w), lambda)
+                        }) {
+                            return false;
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            called_with(param, body)
+        }
+        Case(ref sub, (ref x, ref left), (ref y, ref right)) => {
+            called_with(param, sub)
+                && (x == f || called_with(param.filter(|v| v != x), left))
+                && (y == f || called_with(param.filter(|v| v != y), right))
+        }
+        Try(ref sub, (ref x, ref handler)) => {
+            called_with(param, sub) && (x == f || called_with(param.filter(|v| v != x), handler))
+        }
+        For(ref from, ref to, (ref x, ref body)) => {
+            called_with(param, from)
+                && called_with(param, to)
+                && (x == f || called_with(param.filter(|v| v != x), body))
+        }
+        BinOp(..) | Div(..) | Mod(..) => ast::operands(expr)
+            .into_iter()
+            .all(|sub| called_with(param, sub)),
+        _ => expr
+            .children()
+            .into_iter()
+            .all(|sub| called_with(param, sub)),
+    }
+}
+
+// binds the parameter of a function's body to the literal it's always called
+// with, if it's called at all
+fn bind(body: Expr, v: &str, literal: Option<Expr>, width: IntWidth) -> Expr {
+    match literal {
+        Some(literal) => simplify(substitute(body, v, &literal), width),
+        None => body,
+    }
+}
+
+// propagates the argument of a top-level function into its body when every call
+// to it (anywhere in the program after its definition) passes the same literal,
+// so that the body can be simplified for it: the calls still pass the argument,
+// but nothing reads it any more
+pub fn propagate(expr: Expr, width: IntWidth) -> Expr {
+    use self::Expr::*;
+    // the top-level definitions are one chain, which is walked in a loop
+    let mut definitions = vec![];
+    let mut expr = expr;
+    let body = loop {
+        expr = match expr {
+            LetFun(f, (v, lambda), body) => {
+                let mut literal = None;
+                let lambda = if called_with(&f, Some(&v), &lambda, &mut literal)
+                    && called_with(&f, None, &body, &mut literal)
+                {
+                    bind(*lambda, &v, literal, width)
+                } else {
+                    *lambda
+                };
+                definitions.push(Definition::LetFun(f, (v, Box::new(lambda))));
+                *body
+            }
+            // functions defined together are left as they are
+            LetRec(functions, body) => {
+                definitions.push(Definition::LetRec(functions));
+                *body
+            }
+            Let(f, sub, body) => {
+                let sub = match *sub {
+                    Lambda((v, lambda)) => {
+                        let mut literal = None;
+                        let lambda = if called_with(&f, None, &body, &mut literal) {
+                            bind(*lambda, &v, literal, width)
+                        } else {
+                            *lambda
+                        };
+                        Lambda((v, Box::new(lambda)))
+                    }
+                    sub => sub,
+                };
+                definitions.push(Definition::Let(f, Box::new(sub)));
+                *body
+            }
+            expr => break expr,
+        };
+    };
+    definitions
+        .into_iter()
+        .rev()
+        .fold(body, |body, definition| definition.scope(body))
+}
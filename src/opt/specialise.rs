@@ -0,0 +1,454 @@
+use super::super::frontend::ast::{self, Definition, Expr, Free, Lambda};
+use super::super::IntWidth;
+use super::fold::{fold, is_literal};
+
+type Var = String;
+
+fn is_atom(expr: &Expr) -> bool {
+    matches!(*expr, Expr::Var(_)) || is_literal(expr)
+}
+
+// replaces free occurrences of 'v' with a literal (literals have no free
+// variables, so there's no risk of capture)
+pub fn substitute(expr: Expr, v: &str, literal: &Expr) -> Expr {
+    use self::Expr::*;
+    let sub = |expr: Box<Expr>| Box::new(substitute(*expr, v, literal));
+    match expr {
+        Var(ref w) if w == v => literal.clone(),
+        Unit | What | Var(_) | Int(_) | Float(_) | Bool(_) | Str(_) | Chan | Nil => expr,
+        UnOp(op, expr) => UnOp(op, sub(expr)),
+        BinOp(..) | Div(..) | Mod(..) => {
+            let (first, links) = ast::unchain(expr);
+            let first = substitute(first, v, literal);
+            links.into_iter().fold(first, |left, (link, right)| {
+                link.join(left, substitute(right, v, literal))
+            })
+        }
+        If(condition, left, right) => If(sub(condition), sub(left), sub(right)),
+        Pair(left, right) => Pair(sub(left), sub(right)),
+        Fst(expr) => Fst(sub(expr)),
+        Snd(expr) => Snd(sub(expr)),
+        Inl(expr, type_expr) => Inl(sub(expr), type_expr),
+        Inr(expr, type_expr) => Inr(sub(expr), type_expr),
+        Case(expr, (x, left), (y, right)) => {
+            let left = if x == v { left } else { sub(left) };
+            let right = if y == v { right } else { sub(right) };
+            Case(sub(expr), (x, left), (y, right))
+        }
+        While(condition, body) => While(sub(condition), sub(body)),
+        For(from, to, (w, body)) => {
+            let body = if w == v { body } else { sub(body) };
+            For(sub(from), sub(to), (w, body))
+        }
+        Seq(seq) => Seq(seq
+            .into_iter()
+            .map(|expr| substitute(expr, v, literal))
+            .collect()),
+        Ref(expr) => Ref(sub(expr)),
+        Deref(expr) => Deref(sub(expr)),
+        Lazy(expr) => Lazy(sub(expr)),
+        Force(location, expr) => Force(location, sub(expr)),
+        Spawn(expr) => Spawn(sub(expr)),
+        Raise(location, expr) => Raise(location, sub(expr)),
+        Try(expr, (w, handler)) => {
+            let handler = if w == v { handler } else { sub(handler) };
+            Try(sub(expr), (w, handler))
+        }
+        Send(location, left, right) => Send(location, sub(left), sub(right)),
+        Recv(location, expr) => Recv(location, sub(expr)),
+        Print(expr) => Print(sub(expr)),
+        Expect(expr, likely) => Expect(sub(expr), likely),
+        Attributed(attributes, expr) => Attributed(attributes, sub(expr)),
+        Assign(left, right) => Assign(sub(left), sub(right)),
+        MakeArray(location, length, expr) => MakeArray(location, sub(length), sub(expr)),
+        Index(location, left, right) => Index(location, sub(left), sub(right)),
+        Update(location, left, index, right) => Update(location, sub(left), sub(index), sub(right)),
+        Cons(left, right) => Cons(sub(left), sub(right)),
+        Head(location, expr) => Head(location, sub(expr)),
+        Tail(location, expr) => Tail(location, sub(expr)),
+        IsEmpty(expr) => IsEmpty(sub(expr)),
+        Lambda((w, body)) => {
+            let body = if w == v { body } else { sub(body) };
+            Lambda((w, body))
+        }
+        App(left, right) => App(sub(left), sub(right)),
+        // a chain of definitions is walked in a loop (as a program of
+        // top-level definitions is one), up to the first that hides 'v'
+        Let(..) | LetFun(..) | LetRec(..) => {
+            let (definitions, body) = ast::undefine(expr);
+            let mut hidden = false;
+            let mut substituted = vec![];
+            for definition in definitions {
+                if hidden {
+                    substituted.push(definition);
+                    continue;
+                }
+                substituted.push(match definition {
+                    Definition::Let(w, expr) => {
+                        hidden = w == v;
+                        Definition::Let(w, sub(expr))
+                    }
+                    Definition::LetFun(f, (w, lambda)) => {
+                        let lambda = if f == v || w == v {
+                            lambda
+                        } else {
+                            sub(lambda)
+                        };
+                        hidden = f == v;
+                        Definition::LetFun(f, (w, lambda))
+                    }
+                    Definition::LetRec(functions) => {
+                        if functions.iter().any(|(f, _)| f == v) {
+                            hidden = true;
+                            Definition::LetRec(functions)
+                        } else {
+                            Definition::LetRec(
+                                functions
+                                    .into_iter()
+                                    .map(|(f, (w, lambda))| {
+                                        let lambda = if w == v { lambda } else { sub(lambda) };
+                                        (f, (w, lambda))
+                                    })
+                                    .collect(),
+                            )
+                        }
+                    }
+                });
+            }
+            let body = if hidden {
+                body
+            } else {
+                substitute(body, v, literal)
+            };
+            substituted
+                .into_iter()
+                .rev()
+                .fold(body, |body, definition| definition.scope(body))
+        }
+    }
+}
+
+// binds the formal parameter of a lambda to an argument (a literal is
+// substituted, and anything else is bound by a 'let')
+fn apply((v, body): Lambda, arg: Expr) -> Expr {
+    match arg {
+        Expr::Var(ref w) if *w == v => *body,
+        _ if is_literal(&arg) => substitute(*body, &v, &arg),
+        _ => Expr::Let(v, Box::new(arg), body),
+    }
+}
+
+// applies the result of an unfolding to a further argument, looking through
+// any 'let's that were introduced along the way (unless they would capture
+// something the argument refers to)
+fn apply_to(expr: Expr, arg: Expr) -> Expr {
+    match expr {
+        Expr::Lambda(lambda) => apply(lambda, arg),
+        Expr::Let(w, sub, body) => {
+            if arg.fv().contains(&w) {
+                Expr::App(Box::new(Expr::Let(w, sub, body)), Box::new(arg))
+            } else {
+                Expr::Let(w, sub, Box::new(apply_to(*body, arg)))
+            }
+        }
+        expr => Expr::App(Box::new(expr), Box::new(arg)),
+    }
+}
+
+struct Specialiser {
+    // every binder in scope, along with its definition if it is a known function
+    scope: Vec<(Var, Option<Lambda>)>,
+    budget: usize,
+    // whether only the functions that ask for it ('[@inline]') are unfolded
+    inline_only: bool,
+    width: IntWidth,
+}
+
+impl Specialiser {
+    fn known(&self, f: &str) -> Option<Lambda> {
+        let i = self.scope.iter().rposition(|(v, _)| v == f)?;
+        let lambda = self.scope[i].1.as_ref()?;
+        let fv = lambda.fv();
+        // the body can only be unfolded here if its free variables still mean
+        // what they did where the function was defined
+        if self.scope[i + 1..].iter().any(|(v, _)| fv.contains(&v)) {
+            None
+        } else {
+            Some(lambda.clone())
+        }
+    }
+
+    fn scoped(&mut self, binders: Vec<(Var, Option<Lambda>)>, expr: Expr, depth: usize) -> Expr {
+        let len = self.scope.len();
+        self.scope.extend(binders);
+        let expr = self.specialise(expr, depth);
+        self.scope.truncate(len);
+        expr
+    }
+
+    fn specialise_sub(&mut self, expr: Expr, depth: usize) -> Box<Expr> {
+        Box::new(self.specialise(expr, depth))
+    }
+
+    fn specialise_app(&mut self, expr: Expr, depth: usize) -> Expr {
+        let mut head = expr;
+        let mut args = vec![];
+        while let Expr::App(left, right) = head {
+            args.push(self.specialise(*right, depth));
+            head = *left;
+        }
+        args.reverse();
+        let head = self.specialise(head, depth);
+        let lambda = match head {
+            Expr::Var(ref f) => self.known(f),
+            Expr::Lambda(ref lambda) => Some(lambda.clone()),
+            _ => None,
+        };
+        // a function's attributes can ask for calls to it to be unfolded
+        // whatever they pass, or never to be
+        let unfold = |lambda: &Lambda| match lambda.1.attributes().inline {
+            Some(inline) => inline,
+            None => !self.inline_only && args.iter().all(is_atom) && args.iter().any(is_literal),
+        };
+        match lambda {
+            Some(lambda) if depth > 0 && self.budget > 0 && unfold(&lambda) => {
+                self.budget -= 1;
+                let mut args = args.into_iter();
+                let unfolded = apply(lambda, args.next().unwrap());
+                let unfolded = args.fold(unfolded, apply_to);
+                self.specialise(unfolded, depth - 1)
+            }
+            _ => args
+                .into_iter()
+                .fold(head, |head, arg| Expr::App(Box::new(head), Box::new(arg))),
+        }
+    }
+
+    // a chain of definitions ('let x = e in let y = e in ...') is specialised
+    // from the outermost on, in a loop, as a program of top-level definitions
+    // is one
+    fn specialise_definitions(&mut self, expr: Expr, depth: usize) -> Expr {
+        use self::Expr::*;
+        let len = self.scope.len();
+        let mut definitions = vec![];
+        let mut expr = expr;
+        let body = loop {
+            expr = match expr {
+                Let(v, sub, body) => {
+                    let sub = self.specialise(*sub, depth);
+                    match sub {
+                        // propagate literals into the body, where they might
+                        // enable unfolding
+                        _ if is_literal(&sub) => substitute(*body, &v, &sub),
+                        Var(ref w) if *w == v => *body,
+                        Lambda(ref lambda) => {
+                            self.scope.push((v.clone(), Some(lambda.clone())));
+                            definitions.push(Definition::Let(v, Box::new(sub)));
+                            *body
+                        }
+                        _ => {
+                            self.scope.push((v.clone(), None));
+                            definitions.push(Definition::Let(v, Box::new(sub)));
+                            *body
+                        }
+                    }
+                }
+                LetFun(f, (v, lambda), body) => {
+                    let known = Some((v.clone(), lambda.clone()));
+                    let lambda =
+                        self.scoped(vec![(f.clone(), known), (v.clone(), None)], *lambda, depth);
+                    let known = Some((v.clone(), Box::new(lambda.clone())));
+                    self.scope.push((f.clone(), known));
+                    definitions.push(Definition::LetFun(f, (v, Box::new(lambda))));
+                    *body
+                }
+                // functions defined together are never unfolded, as each one's
+                // body refers to the others
+                LetRec(functions, body) => {
+                    let group = functions
+                        .iter()
+                        .map(|(f, _)| (f.clone(), None))
+                        .collect::<Vec<_>>();
+                    let functions = functions
+                        .into_iter()
+                        .map(|(f, (v, lambda))| {
+                            let mut binders = group.clone();
+                            binders.push((v.clone(), None));
+                            let lambda = self.scoped(binders, *lambda, depth);
+                            (f, (v, Box::new(lambda)))
+                        })
+                        .collect();
+                    self.scope.extend(group);
+                    definitions.push(Definition::LetRec(functions));
+                    *body
+                }
+                expr => break self.specialise(expr, depth),
+            };
+        };
+        self.scope.truncate(len);
+        definitions
+            .into_iter()
+            .rev()
+            .fold(body, |body, definition| definition.scope(body))
+    }
+
+    fn specialise(&mut self, expr: Expr, depth: usize) -> Expr {
+        use self::Expr::*;
+        let expr = match expr {
+            Unit | What | Var(_) | Int(_) | Float(_) | Bool(_) | Str(_) | Chan | Nil => expr,
+            UnOp(op, sub) => UnOp(op, self.specialise_sub(*sub, depth)),
+            // each operator in a chain is folded as soon as its operands have
+            // been
+            BinOp(..) | Div(..) | Mod(..) => {
+                let (first, links) = ast::unchain(expr);
+                let mut expr = self.specialise(first, depth);
+                for (link, right) in links {
+                    let right = self.specialise(right, depth);
+                    expr = fold(link.join(expr, right), self.width);
+                }
+                return expr;
+            }
+            // don't unfold anything in a branch that will never be taken
+            If(condition, left, right) => match self.specialise(*condition, depth) {
+                Bool(true) => self.specialise(*left, depth),
+                Bool(false) => self.specialise(*right, depth),
+                condition => If(
+                    Box::new(condition),
+                    self.specialise_sub(*left, depth),
+                    self.specialise_sub(*right, depth),
+                ),
+            },
+            Pair(left, right) => Pair(
+                self.specialise_sub(*left, depth),
+                self.specialise_sub(*right, depth),
+            ),
+            Fst(sub) => Fst(self.specialise_sub(*sub, depth)),
+            Snd(sub) => Snd(self.specialise_sub(*sub, depth)),
+            Inl(sub, type_expr) => Inl(self.specialise_sub(*sub, depth), type_expr),
+            Inr(sub, type_expr) => Inr(self.specialise_sub(*sub, depth), type_expr),
+            Case(sub, (x, left), (y, right)) => {
+                let sub = self.specialise_sub(*sub, depth);
+                let left = self.scoped(vec![(x.clone(), None)], *left, depth);
+                let right = self.scoped(vec![(y.clone(), None)], *right, depth);
+                Case(sub, (x, Box::new(left)), (y, Box::new(right)))
+            }
+            While(condition, body) => While(
+                self.specialise_sub(*condition, depth),
+                self.specialise_sub(*body, depth),
+            ),
+            For(from, to, (v, body)) => {
+                let from = self.specialise_sub(*from, depth);
+                let to = self.specialise_sub(*to, depth);
+                let body = self.scoped(vec![(v.clone(), None)], *body, depth);
+                For(from, to, (v, Box::new(body)))
+            }
+            Seq(seq) => Seq(seq
+                .into_iter()
+                .map(|sub| self.specialise(sub, depth))
+                .collect()),
+            Ref(sub) => Ref(self.specialise_sub(*sub, depth)),
+            Deref(sub) => Deref(self.specialise_sub(*sub, depth)),
+            Lazy(sub) => Lazy(self.specialise_sub(*sub, depth)),
+            Force(location, sub) => Force(location, self.specialise_sub(*sub, depth)),
+            Spawn(sub) => Spawn(self.specialise_sub(*sub, depth)),
+            Raise(location, sub) => Raise(location, self.specialise_sub(*sub, depth)),
+            Try(sub, (v, handler)) => {
+                let sub = self.specialise_sub(*sub, depth);
+                let handler = self.scoped(vec![(v.clone(), None)], *handler, depth);
+                Try(sub, (v, Box::new(handler)))
+            }
+            Send(location, left, right) => Send(
+                location,
+                self.specialise_sub(*left, depth),
+                self.specialise_sub(*right, depth),
+            ),
+            Recv(location, sub) => Recv(location, self.specialise_sub(*sub, depth)),
+            Print(sub) => Print(self.specialise_sub(*sub, depth)),
+            Expect(sub, likely) => Expect(self.specialise_sub(*sub, depth), likely),
+            // nothing is unfolded in the body of a function that's to be kept small
+            Attributed(attributes, sub) if attributes.size => {
+                Attributed(attributes, self.specialise_sub(*sub, 0))
+            }
+            Attributed(attributes, sub) => Attributed(attributes, self.specialise_sub(*sub, depth)),
+            Assign(left, right) => Assign(
+                self.specialise_sub(*left, depth),
+                self.specialise_sub(*right, depth),
+            ),
+            MakeArray(location, length, sub) => MakeArray(
+                location,
+                self.specialise_sub(*length, depth),
+                self.specialise_sub(*sub, depth),
+            ),
+            Index(location, left, right) => Index(
+                location,
+                self.specialise_sub(*left, depth),
+                self.specialise_sub(*right, depth),
+            ),
+            Update(location, left, index, right) => Update(
+                location,
+                self.specialise_sub(*left, depth),
+                self.specialise_sub(*index, depth),
+                self.specialise_sub(*right, depth),
+            ),
+            Cons(left, right) => Cons(
+                self.specialise_sub(*left, depth),
+                self.specialise_sub(*right, depth),
+            ),
+            Head(location, sub) => Head(location, self.specialise_sub(*sub, depth)),
+            Tail(location, sub) => Tail(location, self.specialise_sub(*sub, depth)),
+            IsEmpty(sub) => IsEmpty(self.specialise_sub(*sub, depth)),
+            Lambda((v, body)) => {
+                let body = self.scoped(vec![(v.clone(), None)], *body, depth);
+                Lambda((v, Box::new(body)))
+            }
+            App(_, _) => self.specialise_app(expr, depth),
+            Let(..) | LetFun(..) | LetRec(..) => return self.specialise_definitions(expr, depth),
+        };
+        fold(expr, self.width)
+    }
+}
+
+// unfolds calls to known functions when (some of) their arguments are literals,
+// so that the body can be simplified for that particular argument: 'aggressiveness'
+// bounds how deeply unfoldings may nest, and the total number of unfoldings
+pub fn specialise(expr: Expr, aggressiveness: usize, width: IntWidth) -> Expr {
+    let mut specialiser = Specialiser {
+        scope: vec![],
+        budget: 64 * aggressiveness,
+        inline_only: false,
+        width,
+    };
+    specialiser.specialise(expr, aggressiveness)
+}
+
+// whether any function in 'expr' asks for calls to it to always be unfolded
+pub fn asks_to_inline(expr: &Expr) -> bool {
+    let mut work = vec![expr];
+    while let Some(expr) = work.pop() {
+        if let Expr::Attributed(attributes, _) = *expr {
+            if attributes.inline == Some(true) {
+                return true;
+            }
+        }
+        work.extend(expr.children());
+    }
+    false
+}
+
+// unfolds calls to the functions that ask for it ('[@inline]'), and nothing
+// else, which is done even when nothing else is specialised
+pub fn inline(expr: Expr, width: IntWidth) -> Expr {
+    let mut specialiser = Specialiser {
+        scope: vec![],
+        budget: 64,
+        inline_only: true,
+        width,
+    };
+    specialiser.specialise(expr, 1)
+}
+
+// folds constants and propagates literals bound by 'let's, without unfolding
+// any calls
+pub fn simplify(expr: Expr, width: IntWidth) -> Expr {
+    specialise(expr, 0, width)
+}
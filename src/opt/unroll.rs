@@ -0,0 +1,560 @@
+use super::super::frontend::ast::{self, BinOp, Definition, Expr, UnOp};
+use super::super::IntWidth;
+
+type Var = String;
+
+// whether 'v' is used as anything other than the target of '!' or ':=', or is
+// assigned inside a function, a lazy value or another thread (where we can't
+// tell when the assignment happens)
+fn escapes(v: &str, expr: &Expr, in_lambda: bool) -> bool {
+    use self::Expr::*;
+    let escapes = |expr: &Expr| escapes(v, expr, in_lambda);
+    match *expr {
+        Var(ref w) => w == v,
+        Deref(ref sub) => match **sub {
+            Var(_) => false,
+            _ => escapes(sub),
+        },
+        Assign(ref left, ref right) => match **left {
+            Var(ref w) if w == v => in_lambda || escapes(right),
+            _ => escapes(left) || escapes(right),
+        },
+        Lambda((ref w, ref body)) => w != v && self::escapes(v, body, true),
+        Lazy(ref sub) | Spawn(ref sub) => self::escapes(v, sub, true),
+        // a chain of definitions is walked in a loop, as a program of
+        // top-level definitions is one
+        Let(..) | LetFun(..) | LetRec(..) => {
+            let (definitions, body) = ast::definitions(expr);
+            for definition in definitions {
+                match *definition {
+                    Let(ref w, ref sub, _) => {
+                        if escapes(sub) {
+                            return true;
+                        }
+                        if w == v {
+                            return false;
+                        }
+                    }
+                    LetFun(ref f, (ref w, ref lambda), _) => {
+                        if f == v {
+                            return false;
+                        }
+                        if w != v && self::escapes(v, lambda, true) {
+                            return true;
+                        }
+                    }
+                    LetRec(ref functions, _) => {
+                        if functions.iter().any(|(f, _)| f == v) {
+                            return false;
+                        }
+                        if functions.iter().any(|(_, (ref w, ref lambda))| {
+                            w != v && self::escapes(v, lambda, true)
+                        }) {
+                            return true;
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            escapes(body)
+        }
+        Case(ref sub, (ref x, ref left), (ref y, ref right)) => {
+            escapes(sub) || (x != v && escapes(left)) || (y != v && escapes(right))
+        }
+        Try(ref sub, (ref w, ref handler)) => escapes(sub) || (w != v && escapes(handler)),
+        For(ref from, ref to, (ref w, ref body)) => {
+            escapes(from) || escapes(to) || (w != v && escapes(body))
+        }
+        BinOp(..) | Div(..) | Mod(..) => ast::operands(expr).into_iter().any(escapes),
+        _ => expr.children().into_iter().any(escapes),
+    }
+}
+
+// whether 'v' might be assigned to anywhere in 'expr'
+fn assigns(v: &str, expr: &Expr) -> bool {
+    use self::Expr::*;
+    let assigns = |expr: &Expr| assigns(v, expr);
+    match *expr {
+        Assign(ref left, ref right) => {
+            matches!(**left, Var(ref w) if w == v) || assigns(left) || assigns(right)
+        }
+        Lambda((ref w, ref body)) => w != v && assigns(body),
+        Let(..) | LetFun(..) | LetRec(..) => {
+            let (definitions, body) = ast::definitions(expr);
+            for definition in definitions {
+                match *definition {
+                    Let(ref w, ref sub, _) => {
+                        if assigns(sub) {
+                            return true;
+                        }
+                        if w == v {
+                            return false;
+                        }
+                    }
+                    LetFun(ref f, (ref w, ref lambda), _) => {
+                        if f == v {
+                            return false;
+                        }
+                        if w != v && assigns(lambda) {
+                            return true;
+                        }
+                    }
+                    LetRec(ref functions, _) => {
+                        if functions.iter().any(|(f, _)| f == v) {
+                            return false;
+                        }
+                        if functions
+                            .iter()
+                            .any(|(_, (ref w, ref lambda))| w != v && assigns(lambda))
+                        {
+                            return true;
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            assigns(body)
+        }
+        Case(ref sub, (ref x, ref left), (ref y, ref right)) => {
+            assigns(sub) || (x != v && assigns(left)) || (y != v && assigns(right))
+        }
+        Try(ref sub, (ref w, ref handler)) => assigns(sub) || (w != v && assigns(handler)),
+        For(ref from, ref to, (ref w, ref body)) => {
+            assigns(from) || assigns(to) || (w != v && assigns(body))
+        }
+        BinOp(..) | Div(..) | Mod(..) => ast::operands(expr).into_iter().any(assigns),
+        _ => expr.children().into_iter().any(assigns),
+    }
+}
+
+fn reads(v: &str, expr: &Expr) -> bool {
+    match *expr {
+        Expr::Deref(ref sub) => matches!(**sub, Expr::Var(ref w) if w == v),
+        _ => false,
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum Binding {
+    // an 'int' bound by 'let' whose value is known
+    Constant(i64),
+    // an 'int ref' that doesn't escape, along with its contents (if they are known)
+    Counter(Option<i64>),
+    Other,
+}
+
+struct Unroller {
+    scope: Vec<(Var, Binding)>,
+    budget: usize,
+    width: IntWidth,
+}
+
+impl Unroller {
+    fn lookup(&self, v: &str) -> Option<&Binding> {
+        self.scope
+            .iter()
+            .rev()
+            .find(|(w, _)| w == v)
+            .map(|(_, binding)| binding)
+    }
+
+    fn lookup_mut(&mut self, v: &str) -> Option<&mut Binding> {
+        self.scope
+            .iter_mut()
+            .rev()
+            .find(|(w, _)| w == v)
+            .map(|(_, binding)| binding)
+    }
+
+    // the value of a pure integer expression, if it's known here (reading
+    // counters is only allowed when 'counters' is set)
+    fn value(&self, expr: &Expr, counters: bool) -> Option<i64> {
+        match *expr {
+            Expr::Int(i) => Some(i),
+            Expr::Var(ref v) => match self.lookup(v) {
+                Some(Binding::Constant(i)) => Some(*i),
+                _ => None,
+            },
+            Expr::Deref(ref sub) if counters => match **sub {
+                Expr::Var(ref v) => match self.lookup(v) {
+                    Some(Binding::Counter(i)) => *i,
+                    _ => None,
+                },
+                _ => None,
+            },
+            Expr::UnOp(UnOp::Neg, ref sub) => {
+                Some(self.width.wrap(self.value(sub, counters)?.wrapping_neg()))
+            }
+            // a chain is worked out from its leftmost operand on, in a loop
+            Expr::BinOp(..) => {
+                let mut links = vec![];
+                let mut sub = expr;
+                while let Expr::BinOp(op, ref left, ref right) = *sub {
+                    links.push((op, right));
+                    sub = left;
+                }
+                let mut value = self.value(sub, counters)?;
+                for (op, right) in links.into_iter().rev() {
+                    let right = self.value(right, counters)?;
+                    value = match op {
+                        BinOp::Add => self.width.wrap(value.wrapping_add(right)),
+                        BinOp::Sub => self.width.wrap(value.wrapping_sub(right)),
+                        BinOp::Mul => self.width.wrap(value.wrapping_mul(right)),
+                        _ => return None,
+                    };
+                }
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+
+    fn forget(&mut self, expr: &Expr) {
+        for (v, binding) in self.scope.iter_mut() {
+            if let Binding::Counter(_) = binding {
+                if assigns(v, expr) {
+                    *binding = Binding::Counter(None);
+                }
+            }
+        }
+    }
+
+    fn scoped(&mut self, v: Var, binding: Binding, expr: Expr) -> Expr {
+        self.scope.push((v, binding));
+        let expr = self.unroll(expr);
+        self.scope.pop();
+        expr
+    }
+
+    // unrolls code that might not run (or that runs at some unknown point), so
+    // afterwards we only know the counters that it couldn't have changed
+    fn branch<F>(&mut self, f: F) -> Expr
+    where
+        F: FnOnce(&mut Unroller) -> Expr,
+    {
+        let before = self.counters();
+        let expr = f(self);
+        for (i, before) in before {
+            if self.scope[i].1 != before {
+                self.scope[i].1 = Binding::Counter(None);
+            }
+        }
+        expr
+    }
+
+    // matches 'while !i < n do ...; i := !i + c end' where the trip count is known
+    fn try_unroll(&mut self, condition: &Expr, body: &Expr) -> Option<Expr> {
+        let (i, bound) = match *condition {
+            Expr::BinOp(BinOp::Lt, ref left, ref bound) => match **left {
+                Expr::Deref(ref sub) => match **sub {
+                    Expr::Var(ref i) => (i, bound),
+                    _ => return None,
+                },
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let start = match self.lookup(i) {
+            Some(Binding::Counter(Some(start))) => *start,
+            _ => return None,
+        };
+        let bound = self.value(bound, false)?;
+        let (step, stmts) = match *body {
+            Expr::Seq(ref seq) => seq.split_last()?,
+            ref step => (step, &[][..]),
+        };
+        let increment = match *step {
+            Expr::Assign(ref left, ref right) => match (&**left, &**right) {
+                (Expr::Var(ref v), Expr::BinOp(BinOp::Add, ref left, ref increment))
+                    if v == i && reads(i, left) =>
+                {
+                    self.value(increment, false)?
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+        if increment <= 0 || stmts.iter().any(|stmt| assigns(i, stmt)) {
+            return None;
+        }
+        let trips = if start < bound {
+            (bound as i128 - start as i128 + increment as i128 - 1) / increment as i128
+        } else {
+            0
+        };
+        // the loop only stops if the counter doesn't wrap around on its way past the bound
+        let last = start as i128 + trips * increment as i128;
+        if self.width.wrap(last as i64) as i128 != last {
+            return None;
+        }
+        if trips * body.size() as i128 > self.budget as i128 {
+            return None;
+        }
+        if trips == 0 {
+            return Some(Expr::Unit);
+        }
+        let mut unrolled = vec![];
+        for _ in 0..trips {
+            for stmt in stmts.iter().chain(Some(step)) {
+                unrolled.push(self.unroll(stmt.clone()));
+            }
+        }
+        Some(Expr::Seq(unrolled))
+    }
+
+    // a 'for' loop whose bounds are known becomes a copy of its body for each
+    // int, with the int bound by 'let'
+    fn try_unroll_for(&mut self, from: &Expr, to: &Expr, v: &Var, body: &Expr) -> Option<Expr> {
+        let (from, to) = (self.value(from, true)?, self.value(to, true)?);
+        let trips = (to as i128 - from as i128 + 1).max(0);
+        if trips * body.size() as i128 > self.budget as i128 {
+            return None;
+        }
+        let mut unrolled = vec![];
+        for i in from..=to {
+            let copy = Expr::Let(v.clone(), Box::new(Expr::Int(i)), Box::new(body.clone()));
+            unrolled.push(self.unroll(copy));
+        }
+        unrolled.push(Expr::Unit);
+        Some(Expr::Seq(unrolled))
+    }
+
+    fn unroll_sub(&mut self, expr: Expr) -> Box<Expr> {
+        Box::new(self.unroll(expr))
+    }
+
+    fn unroll(&mut self, expr: Expr) -> Expr {
+        use self::Expr::*;
+        match expr {
+            Unit | What | Var(_) | Int(_) | Float(_) | Bool(_) | Str(_) | Chan | Nil => expr,
+            UnOp(op, sub) => UnOp(op, self.unroll_sub(*sub)),
+            BinOp(..) | Div(..) | Mod(..) => {
+                let (first, links) = ast::unchain(expr);
+                let mut expr = self.unroll(first);
+                for (link, right) in links {
+                    let right = match link {
+                        // the right operand of '&&' or '||' might not run
+                        ast::Link::Op(self::BinOp::And) | ast::Link::Op(self::BinOp::Or) => {
+                            self.branch(|unroller| unroller.unroll(right))
+                        }
+                        _ => self.unroll(right),
+                    };
+                    expr = link.join(expr, right);
+                }
+                expr
+            }
+            If(condition, left, right) => {
+                let condition = self.unroll_sub(*condition);
+                let left = self.branch(|unroller| unroller.unroll(*left));
+                let right = self.branch(|unroller| unroller.unroll(*right));
+                If(condition, Box::new(left), Box::new(right))
+            }
+            Pair(left, right) => {
+                let left = self.unroll_sub(*left);
+                Pair(left, self.unroll_sub(*right))
+            }
+            Fst(sub) => Fst(self.unroll_sub(*sub)),
+            Snd(sub) => Snd(self.unroll_sub(*sub)),
+            Inl(sub, type_expr) => Inl(self.unroll_sub(*sub), type_expr),
+            Inr(sub, type_expr) => Inr(self.unroll_sub(*sub), type_expr),
+            Case(sub, (x, left), (y, right)) => {
+                let sub = self.unroll_sub(*sub);
+                let left =
+                    self.branch(|unroller| unroller.scoped(x.clone(), Binding::Other, *left));
+                let right =
+                    self.branch(|unroller| unroller.scoped(y.clone(), Binding::Other, *right));
+                Case(sub, (x, Box::new(left)), (y, Box::new(right)))
+            }
+            While(condition, body) => {
+                if let Some(unrolled) = self.try_unroll(&condition, &body) {
+                    return unrolled;
+                }
+                self.forget(&condition);
+                self.forget(&body);
+                let condition = self.unroll_sub(*condition);
+                let body = self.unroll_sub(*body);
+                self.forget(&body);
+                While(condition, body)
+            }
+            For(from, to, (v, body)) => {
+                let from = self.unroll_sub(*from);
+                let to = self.unroll_sub(*to);
+                if let Some(unrolled) = self.try_unroll_for(&from, &to, &v, &body) {
+                    return unrolled;
+                }
+                self.forget(&body);
+                let body =
+                    self.branch(|unroller| unroller.scoped(v.clone(), Binding::Other, *body));
+                self.forget(&body);
+                For(from, to, (v, Box::new(body)))
+            }
+            Seq(seq) => Seq(seq.into_iter().map(|sub| self.unroll(sub)).collect()),
+            Ref(sub) => Ref(self.unroll_sub(*sub)),
+            Deref(sub) => Deref(self.unroll_sub(*sub)),
+            Lazy(sub) => Lazy(Box::new(self.later(|unroller| unroller.unroll(*sub)))),
+            Force(location, sub) => Force(location, self.unroll_sub(*sub)),
+            Spawn(sub) => Spawn(Box::new(self.later(|unroller| unroller.unroll(*sub)))),
+            Raise(location, sub) => Raise(location, self.unroll_sub(*sub)),
+            // the body can stop anywhere, so nothing it assigns is known in the
+            // handler (or afterwards)
+            Try(sub, (v, handler)) => {
+                let sub = self.unroll_sub(*sub);
+                self.forget(&sub);
+                let handler =
+                    self.branch(|unroller| unroller.scoped(v.clone(), Binding::Other, *handler));
+                Try(sub, (v, Box::new(handler)))
+            }
+            Send(location, left, right) => {
+                let left = self.unroll_sub(*left);
+                Send(location, left, self.unroll_sub(*right))
+            }
+            Recv(location, sub) => Recv(location, self.unroll_sub(*sub)),
+            Print(sub) => Print(self.unroll_sub(*sub)),
+            Expect(sub, likely) => Expect(self.unroll_sub(*sub), likely),
+            // no loop is unrolled in the body of a function that's to be kept small
+            Attributed(attributes, sub) if attributes.size => {
+                let budget = self.budget;
+                self.budget = 0;
+                let sub = self.unroll_sub(*sub);
+                self.budget = budget;
+                Attributed(attributes, sub)
+            }
+            Attributed(attributes, sub) => Attributed(attributes, self.unroll_sub(*sub)),
+            MakeArray(location, length, sub) => {
+                let length = self.unroll_sub(*length);
+                MakeArray(location, length, self.unroll_sub(*sub))
+            }
+            Index(location, left, right) => {
+                let left = self.unroll_sub(*left);
+                Index(location, left, self.unroll_sub(*right))
+            }
+            Update(location, left, index, right) => {
+                let left = self.unroll_sub(*left);
+                let index = self.unroll_sub(*index);
+                Update(location, left, index, self.unroll_sub(*right))
+            }
+            Cons(left, right) => {
+                let left = self.unroll_sub(*left);
+                Cons(left, self.unroll_sub(*right))
+            }
+            Head(location, sub) => Head(location, self.unroll_sub(*sub)),
+            Tail(location, sub) => Tail(location, self.unroll_sub(*sub)),
+            IsEmpty(sub) => IsEmpty(self.unroll_sub(*sub)),
+            Assign(left, right) => {
+                let left = self.unroll_sub(*left);
+                let right = self.unroll_sub(*right);
+                if let Var(ref v) = *left {
+                    let value = self.value(&right, true);
+                    if let Some(binding @ Binding::Counter(_)) = self.lookup_mut(v) {
+                        *binding = Binding::Counter(value);
+                    }
+                }
+                Assign(left, right)
+            }
+            Lambda((v, body)) => {
+                let body = self.in_function(v.clone(), *body);
+                Lambda((v, Box::new(body)))
+            }
+            App(left, right) => {
+                let left = self.unroll_sub(*left);
+                App(left, self.unroll_sub(*right))
+            }
+            // a chain of definitions is unrolled from the outermost on, in a
+            // loop, as a program of top-level definitions is one
+            Let(..) | LetFun(..) | LetRec(..) => {
+                let len = self.scope.len();
+                let mut definitions = vec![];
+                let mut expr = expr;
+                let body = loop {
+                    expr = match expr {
+                        Let(v, sub, body) => {
+                            let sub = self.unroll(*sub);
+                            let binding = match sub {
+                                Ref(ref sub) if !escapes(&v, &body, false) => {
+                                    Binding::Counter(self.value(sub, true))
+                                }
+                                ref sub => match self.value(sub, true) {
+                                    Some(i) => Binding::Constant(i),
+                                    None => Binding::Other,
+                                },
+                            };
+                            self.scope.push((v.clone(), binding));
+                            definitions.push(Definition::Let(v, Box::new(sub)));
+                            *body
+                        }
+                        LetFun(f, (v, lambda), body) => {
+                            self.scope.push((f.clone(), Binding::Other));
+                            let lambda = self.in_function(v.clone(), *lambda);
+                            definitions.push(Definition::LetFun(f, (v, Box::new(lambda))));
+                            *body
+                        }
+                        LetRec(functions, body) => {
+                            for (f, _) in functions.iter() {
+                                self.scope.push((f.clone(), Binding::Other));
+                            }
+                            let functions = functions
+                                .into_iter()
+                                .map(|(f, (v, lambda))| {
+                                    let lambda = self.in_function(v.clone(), *lambda);
+                                    (f, (v, Box::new(lambda)))
+                                })
+                                .collect();
+                            definitions.push(Definition::LetRec(functions));
+                            *body
+                        }
+                        expr => break self.unroll(expr),
+                    };
+                };
+                self.scope.truncate(len);
+                definitions
+                    .into_iter()
+                    .rev()
+                    .fold(body, |body, definition| definition.scope(body))
+            }
+        }
+    }
+
+    fn in_function(&mut self, v: Var, body: Expr) -> Expr {
+        self.later(|unroller| unroller.scoped(v, Binding::Other, body))
+    }
+
+    // the body of a function, a lazy value or a thread runs at some unknown
+    // point, so the contents of every counter are unknown inside it
+    fn later<F>(&mut self, f: F) -> Expr
+    where
+        F: FnOnce(&mut Unroller) -> Expr,
+    {
+        let before = self.counters();
+        for &(i, _) in before.iter() {
+            self.scope[i].1 = Binding::Counter(None);
+        }
+        let expr = f(self);
+        for (i, before) in before {
+            self.scope[i].1 = before;
+        }
+        expr
+    }
+
+    // where the counters in scope are and what's known of them (nothing else in
+    // scope changes, so that's all that needs saving around code that might not
+    // run, rather than a copy of the whole scope)
+    fn counters(&self) -> Vec<(usize, Binding)> {
+        self.scope
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, binding))| matches!(binding, Binding::Counter(_)))
+            .map(|(i, (_, binding))| (i, binding.clone()))
+            .collect()
+    }
+}
+
+// unrolls 'while' loops with a statically known number of iterations, as long
+// as the unrolled loop is no bigger than 'budget' nodes
+pub fn unroll(expr: Expr, budget: usize, width: IntWidth) -> Expr {
+    let mut unroller = Unroller {
+        scope: vec![],
+        budget,
+        width,
+    };
+    unroller.unroll(expr)
+}
@@ -0,0 +1,110 @@
+use super::super::frontend::ast::{self, Expr, Free};
+
+// what a value is, when the expression makes that plain
+fn kind(expr: &Expr) -> Option<&'static str> {
+    use self::Expr::*;
+    let kind = match *expr {
+        Unit => "unit",
+        Int(_) => "int",
+        Float(_) => "float",
+        Bool(_) => "bool",
+        Str(_) => "string",
+        Pair(_, _) => "pair",
+        Inl(_, _) | Inr(_, _) => "union",
+        Ref(_) => "reference",
+        Lazy(_) => "lazy value",
+        Chan => "channel",
+        MakeArray(_, _, _) => "array",
+        Nil | Cons(_, _) => "list",
+        Lambda(_) => "function",
+        _ => return None,
+    };
+    Some(kind)
+}
+
+// the tree is untyped by now, so only values whose kind is plain from the
+// expression itself can be seen to be in the wrong place (which is where a
+// mistake in folding or substitution usually shows up)
+fn check_kinds(expr: &Expr) -> Result<(), String> {
+    use self::Expr::*;
+    let expected: Vec<(&Expr, &str)> = match *expr {
+        UnOp(ast::UnOp::Neg, ref sub) => vec![(sub, "int")],
+        UnOp(ast::UnOp::Not, ref sub) | Expect(ref sub, _) => vec![(sub, "bool")],
+        BinOp(ast::BinOp::And, ref left, ref right)
+        | BinOp(ast::BinOp::Or, ref left, ref right) => {
+            vec![(left, "bool"), (right, "bool")]
+        }
+        BinOp(ast::BinOp::Eq, ref left, ref right)
+        | BinOp(ast::BinOp::PhysEq, ref left, ref right) => match (kind(left), kind(right)) {
+            (Some(left), Some(right)) if left != right => {
+                return Err(format!("a {} is compared with a {}", left, right))
+            }
+            _ => vec![],
+        },
+        BinOp(ast::BinOp::Concat, ref left, ref right)
+        | BinOp(ast::BinOp::Compare, ref left, ref right) => {
+            vec![(left, "string"), (right, "string")]
+        }
+        BinOp(
+            ast::BinOp::FloatAdd
+            | ast::BinOp::FloatSub
+            | ast::BinOp::FloatMul
+            | ast::BinOp::FloatDiv
+            | ast::BinOp::FloatLt
+            | ast::BinOp::FloatEq,
+            ref left,
+            ref right,
+        ) => vec![(left, "float"), (right, "float")],
+        BinOp(_, ref left, ref right)
+        | Div(_, ref left, ref right)
+        | Mod(_, ref left, ref right) => {
+            vec![(left, "int"), (right, "int")]
+        }
+        If(ref condition, _, _) | While(ref condition, _) => vec![(condition, "bool")],
+        Fst(ref sub) | Snd(ref sub) => vec![(sub, "pair")],
+        Case(ref sub, _, _) => vec![(sub, "union")],
+        Deref(ref sub) | Assign(ref sub, _) => vec![(sub, "reference")],
+        MakeArray(_, ref length, _) | Raise(_, ref length) => vec![(length, "int")],
+        For(ref from, ref to, _) => vec![(from, "int"), (to, "int")],
+        Index(_, ref sub, ref index) | Update(_, ref sub, ref index, _) => {
+            vec![(sub, "array"), (index, "int")]
+        }
+        Cons(_, ref sub) | Head(_, ref sub) | Tail(_, ref sub) | IsEmpty(ref sub) => {
+            vec![(sub, "list")]
+        }
+        Force(_, ref sub) => vec![(sub, "lazy value")],
+        Send(_, ref sub, _) | Recv(_, ref sub) => vec![(sub, "channel")],
+        App(ref sub, _) => vec![(sub, "function")],
+        Print(ref sub) => vec![(sub, "string")],
+        _ => vec![],
+    };
+    for (sub, expected) in expected {
+        match kind(sub) {
+            Some(found) if found != expected => {
+                return Err(format!(
+                    "a {} is used where a {} is needed",
+                    found, expected
+                ))
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// checks what can still be checked of a program once it's been lowered: that it
+// doesn't refer to any variable outside its scope, and that no value is
+// obviously of the wrong kind for where it's used
+pub fn verify(expr: &Expr) -> Result<(), String> {
+    let mut fv = expr.fv().into_iter().collect::<Vec<_>>();
+    if !fv.is_empty() {
+        fv.sort();
+        return Err(format!("'{}' is used outside its scope", fv[0]));
+    }
+    let mut work = vec![expr];
+    while let Some(expr) = work.pop() {
+        check_kinds(expr)?;
+        work.extend(expr.children());
+    }
+    Ok(())
+}
@@ -0,0 +1,365 @@
+use super::frontend::{float_literal, quote, TypeExpr};
+
+use std::convert::TryFrom;
+use std::fmt;
+
+// the types of slang values, for host code
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Unit,
+    Bool,
+    Int,
+    Float,
+    String,
+    Ref(Box<Type>),
+    Lazy(Box<Type>),
+    Chan(Box<Type>),
+    Array(Box<Type>),
+    List(Box<Type>),
+    Arrow(Box<Type>, Box<Type>),
+    Product(Box<Type>, Box<Type>),
+    Union(Box<Type>, Box<Type>),
+}
+
+impl From<&TypeExpr> for Type {
+    fn from(type_expr: &TypeExpr) -> Type {
+        match *type_expr {
+            TypeExpr::Unit => Type::Unit,
+            TypeExpr::Bool => Type::Bool,
+            TypeExpr::Int => Type::Int,
+            TypeExpr::Float => Type::Float,
+            TypeExpr::String => Type::String,
+            TypeExpr::Ref(ref sub) => Type::Ref(Box::new((&**sub).into())),
+            TypeExpr::Lazy(ref sub) => Type::Lazy(Box::new((&**sub).into())),
+            TypeExpr::Chan(ref sub) => Type::Chan(Box::new((&**sub).into())),
+            TypeExpr::Array(ref sub) => Type::Array(Box::new((&**sub).into())),
+            TypeExpr::List(ref sub) => Type::List(Box::new((&**sub).into())),
+            TypeExpr::Arrow(ref left, ref right) => {
+                Type::Arrow(Box::new((&**left).into()), Box::new((&**right).into()))
+            }
+            TypeExpr::Product(ref left, ref right) => {
+                Type::Product(Box::new((&**left).into()), Box::new((&**right).into()))
+            }
+            TypeExpr::Union(ref left, ref right) => {
+                Type::Union(Box::new((&**left).into()), Box::new((&**right).into()))
+            }
+            TypeExpr::Error
+            | TypeExpr::Hole
+            | TypeExpr::Unknown(_)
+            | TypeExpr::Named(_)
+            | TypeExpr::Variable(_)
+            | TypeExpr::Forall(_, _)
+            | TypeExpr::Record(_)
+            | TypeExpr::Tuple(_) => unreachable!(),
+        }
+    }
+}
+
+impl From<&Type> for TypeExpr {
+    fn from(t: &Type) -> TypeExpr {
+        match *t {
+            Type::Unit => TypeExpr::Unit,
+            Type::Bool => TypeExpr::Bool,
+            Type::Int => TypeExpr::Int,
+            Type::Float => TypeExpr::Float,
+            Type::String => TypeExpr::String,
+            Type::Ref(ref sub) => TypeExpr::Ref(Box::new((&**sub).into())),
+            Type::Lazy(ref sub) => TypeExpr::Lazy(Box::new((&**sub).into())),
+            Type::Chan(ref sub) => TypeExpr::Chan(Box::new((&**sub).into())),
+            Type::Array(ref sub) => TypeExpr::Array(Box::new((&**sub).into())),
+            Type::List(ref sub) => TypeExpr::List(Box::new((&**sub).into())),
+            Type::Arrow(ref left, ref right) => {
+                TypeExpr::Arrow(Box::new((&**left).into()), Box::new((&**right).into()))
+            }
+            Type::Product(ref left, ref right) => {
+                TypeExpr::Product(Box::new((&**left).into()), Box::new((&**right).into()))
+            }
+            Type::Union(ref left, ref right) => {
+                TypeExpr::Union(Box::new((&**left).into()), Box::new((&**right).into()))
+            }
+        }
+    }
+}
+
+// types are printed as they would be written in a program
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", TypeExpr::from(self))
+    }
+}
+
+// slang values, for host code (references are copied out, so they no longer
+// alias anything, and functions can't be looked inside)
+#[derive(Clone, Debug)]
+pub enum Value {
+    Unit,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Pair(Box<Value>, Box<Value>),
+    // an injection carries the type of the other side, as it would be written
+    Inl(Box<Value>, Type),
+    Inr(Box<Value>, Type),
+    Ref(Box<Value>),
+    // a lazy value, along with what it computed if it's been forced
+    Lazy(Option<Box<Value>>),
+    Chan,
+    // an array's elements are copied out too
+    Array(Vec<Value>),
+    List(Vec<Value>),
+    Fun,
+}
+
+// two values are the same if they were built the same way, so floats are
+// compared by their bits (a 'nan' is the same as itself, even though '=' says
+// otherwise)
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        use self::Value::*;
+        match (self, other) {
+            (Unit, Unit) | (Chan, Chan) | (Fun, Fun) => true,
+            (Int(i), Int(j)) => i == j,
+            (Float(x), Float(y)) => x.to_bits() == y.to_bits(),
+            (Bool(a), Bool(b)) => a == b,
+            (Str(s), Str(t)) => s == t,
+            (Pair(l1, r1), Pair(l2, r2)) => l1 == l2 && r1 == r2,
+            (Inl(v1, t1), Inl(v2, t2)) | (Inr(v1, t1), Inr(v2, t2)) => v1 == v2 && t1 == t2,
+            (Ref(v1), Ref(v2)) => v1 == v2,
+            (Lazy(v1), Lazy(v2)) => v1 == v2,
+            (Array(v1), Array(v2)) | (List(v1), List(v2)) => v1 == v2,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value {}
+
+impl Value {
+    // 'ref' and 'lazy' only take a factor, so injections need parentheses
+    // underneath them
+    fn fmt_operand(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Inl(_, _) | Value::Inr(_, _) => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+
+    // 'ref' and 'lazy' are also type operators, so either straight after the
+    // type of an injection would be read as part of it
+    fn fmt_injected(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Ref(_) | Value::Lazy(Some(_)) => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+// values are printed as the expression that would build them, so that they
+// can be pasted back into a program (apart from functions, channels, lazy
+// values that haven't been forced and arrays, which are printed as OCaml
+// prints them, as there's nothing that builds one from its elements)
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Value::*;
+        match *self {
+            Unit => write!(f, "()"),
+            Int(i) => write!(f, "{}", i),
+            Float(x) => write!(f, "{}", float_literal(x)),
+            Bool(b) => write!(f, "{}", b),
+            Str(ref string) => write!(f, "{}", quote(string)),
+            Pair(ref left, ref right) => write!(f, "({}, {})", left, right),
+            Inl(ref value, ref t) => {
+                write!(f, "inl {} ", t)?;
+                value.fmt_injected(f)
+            }
+            Inr(ref value, ref t) => {
+                write!(f, "inr {} ", t)?;
+                value.fmt_injected(f)
+            }
+            Ref(ref value) => {
+                write!(f, "ref ")?;
+                value.fmt_operand(f)
+            }
+            Lazy(Some(ref value)) => {
+                write!(f, "lazy ")?;
+                value.fmt_operand(f)
+            }
+            Lazy(None) => write!(f, "<lazy>"),
+            Chan => write!(f, "<chan>"),
+            Array(ref elements) => {
+                write!(f, "[|")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "|]")
+            }
+            // '::' groups to the right, so only a list that isn't empty needs
+            // parentheses before one
+            List(ref elements) => {
+                for element in elements.iter() {
+                    match *element {
+                        List(ref inner) if !inner.is_empty() => write!(f, "({}) :: ", element)?,
+                        _ => write!(f, "{} :: ", element)?,
+                    }
+                }
+                write!(f, "[]")
+            }
+            Fun => write!(f, "<fun>"),
+        }
+    }
+}
+
+// Rust types that have a slang counterpart
+pub trait Reflect: Into<Value> + TryFrom<Value, Error = String> {
+    fn slang_type() -> Type;
+}
+
+fn mismatch(expected: &str, found: &Value) -> String {
+    format!("expected {}, found '{}'", expected, found)
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Value {
+        Value::Unit
+    }
+}
+
+impl TryFrom<Value> for () {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<(), String> {
+        match value {
+            Value::Unit => Ok(()),
+            value => Err(mismatch("'()'", &value)),
+        }
+    }
+}
+
+impl Reflect for () {
+    fn slang_type() -> Type {
+        Type::Unit
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::Int(i)
+    }
+}
+
+impl TryFrom<Value> for i64 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<i64, String> {
+        match value {
+            Value::Int(i) => Ok(i),
+            value => Err(mismatch("an integer", &value)),
+        }
+    }
+}
+
+impl Reflect for i64 {
+    fn slang_type() -> Type {
+        Type::Int
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Value {
+        Value::Float(f)
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<f64, String> {
+        match value {
+            Value::Float(f) => Ok(f),
+            value => Err(mismatch("a float", &value)),
+        }
+    }
+}
+
+impl Reflect for f64 {
+    fn slang_type() -> Type {
+        Type::Float
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<bool, String> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            value => Err(mismatch("a boolean", &value)),
+        }
+    }
+}
+
+impl Reflect for bool {
+    fn slang_type() -> Type {
+        Type::Bool
+    }
+}
+
+impl From<String> for Value {
+    fn from(string: String) -> Value {
+        Value::Str(string)
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<String, String> {
+        match value {
+            Value::Str(string) => Ok(string),
+            value => Err(mismatch("a string", &value)),
+        }
+    }
+}
+
+impl Reflect for String {
+    fn slang_type() -> Type {
+        Type::String
+    }
+}
+
+impl<A: Into<Value>, B: Into<Value>> From<(A, B)> for Value {
+    fn from((left, right): (A, B)) -> Value {
+        Value::Pair(Box::new(left.into()), Box::new(right.into()))
+    }
+}
+
+impl<A, B> TryFrom<Value> for (A, B)
+where
+    A: TryFrom<Value, Error = String>,
+    B: TryFrom<Value, Error = String>,
+{
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<(A, B), String> {
+        match value {
+            Value::Pair(left, right) => Ok((A::try_from(*left)?, B::try_from(*right)?)),
+            value => Err(mismatch("a pair", &value)),
+        }
+    }
+}
+
+impl<A: Reflect, B: Reflect> Reflect for (A, B) {
+    fn slang_type() -> Type {
+        Type::Product(Box::new(A::slang_type()), Box::new(B::slang_type()))
+    }
+}
@@ -0,0 +1,350 @@
+use super::frontend::{self, Copied, Entry, TypeExpr};
+use super::interp::{self, Env, Input, Interpreter, Limits, Snapshot, Value};
+use super::{read, CompilerOptions};
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use termion::{color, style};
+
+struct Session {
+    interpreter: Interpreter,
+    types: Vec<(String, TypeExpr)>,
+    values: Env<'static>,
+    // the text of each definition so far, which is what ':save' writes out
+    definitions: Vec<String>,
+    // what was in scope when each definition was made, which is what a copy
+    // of a polymorphic one is run in
+    scopes: Vec<Env<'static>>,
+    // how many expressions have been evaluated, which numbers the 'it's
+    results: usize,
+}
+
+fn error(message: String) -> String {
+    format!(
+        "{}{}error{}{}: {}",
+        style::Bold,
+        color::Fg(color::Red),
+        color::Fg(color::Reset),
+        style::Reset,
+        message
+    )
+}
+
+fn note(message: String) -> String {
+    format!(
+        "{}{}note{}{}: {}",
+        style::Bold,
+        color::Fg(color::Magenta),
+        color::Fg(color::Reset),
+        style::Reset,
+        message
+    )
+}
+
+// the number of a name like 'it3', which a session opened or restored from a
+// file carries on counting from
+fn numbered(name: &str) -> Option<usize> {
+    name.strip_prefix("it").and_then(|n| n.parse().ok())
+}
+
+impl Session {
+    fn new(input: Input, options: &CompilerOptions) -> Session {
+        let mut interpreter = Interpreter::new(input, Limits::default(), options.int_width);
+        if let Some(seed) = options.schedule {
+            interpreter.schedule(seed);
+        }
+        Session {
+            interpreter,
+            types: vec![],
+            values: None,
+            definitions: vec![],
+            scopes: vec![],
+            results: 0,
+        }
+    }
+
+    // evaluates an entry that has already been checked, adding it to the session
+    // if it's a definition
+    fn run(&mut self, entry: Entry, options: &CompilerOptions) -> Result<(), String> {
+        for warning in entry.warnings.iter() {
+            println!("{}", warning);
+        }
+        let expr = match entry.expr {
+            Some(expr) => expr,
+            // a polymorphic definition is only run when it's copied
+            None => {
+                let name = entry.name.unwrap();
+                println!("{} : {}", name, entry.type_expr);
+                self.define(name, entry.type_expr, None, entry.text);
+                return Ok(());
+            }
+        };
+        let values = self.values.clone();
+        let values = self.copy(self.definitions.len(), &values, &entry.copies, options)?;
+        // values can refer to the code that built them, which has to outlive the
+        // session, and sessions only end when the REPL does
+        let expr = Box::leak(Box::new(expr));
+        let value = self.interpreter.run(&values, expr)?;
+        match entry.name {
+            Some(name) => {
+                println!("{} : {} = {}", name, entry.type_expr, value);
+                self.define(name, entry.type_expr, Some(value), entry.text);
+            }
+            // an expression's value is kept as 'it', and as 'it1', 'it2' and so
+            // on, so that later entries can use it
+            None => {
+                self.results += 1;
+                let name = format!("it{}", self.results);
+                println!("{} : {} = {}", name, entry.type_expr, value);
+                let text = format!("let {} = {}", name, entry.text);
+                self.define(
+                    name.clone(),
+                    entry.type_expr.clone(),
+                    Some(value.clone()),
+                    text,
+                );
+                let text = format!("let it = {}", name);
+                self.define("it".to_string(), entry.type_expr, Some(value), text);
+            }
+        }
+        Ok(())
+    }
+
+    // 'values' along with the copies of the polymorphic definitions among the
+    // first 'defined' that something run in them needs, each made by running
+    // its definition again at the types it's copied at (in what was in scope
+    // when it was made, with the copies it needs in turn)
+    fn copy(
+        &mut self,
+        defined: usize,
+        values: &Env<'static>,
+        copies: &[Copied],
+        options: &CompilerOptions,
+    ) -> Result<Env<'static>, String> {
+        let mut values = values.clone();
+        for copy in copies.iter() {
+            let i = (self.types[..defined].iter())
+                .rposition(|(name, _)| *name == copy.v)
+                .unwrap();
+            let entry = frontend::read_copy(&self.types[..i], &self.definitions[i], copy, options)?;
+            let scope = self.scopes[i].clone();
+            let scope = self.copy(i, &scope, &entry.copies, options)?;
+            let expr = Box::leak(Box::new(entry.expr.unwrap()));
+            let value = self.interpreter.run(&scope, expr)?;
+            values = interp::bind(
+                &values,
+                Box::leak(copy.name.clone().into_boxed_str()),
+                value,
+            );
+        }
+        Ok(values)
+    }
+
+    // adds a definition to the session, which has no value of its own if it's
+    // polymorphic
+    fn define(
+        &mut self,
+        name: String,
+        type_expr: TypeExpr,
+        value: Option<Value<'static>>,
+        text: String,
+    ) {
+        self.scopes.push(self.values.clone());
+        if let Some(value) = value {
+            self.values = interp::bind(
+                &self.values,
+                Box::leak(name.clone().into_boxed_str()),
+                value,
+            );
+        }
+        if let Some(n) = numbered(&name) {
+            self.results = self.results.max(n);
+        }
+        self.types.push((name, type_expr));
+        self.definitions.push(text);
+    }
+
+    fn save(&self, path: &Path) -> Result<(), String> {
+        let definitions = self
+            .definitions
+            .iter()
+            .map(|d| d.as_str())
+            .collect::<Vec<_>>();
+        let text = frontend::write_session(&definitions);
+        let written = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .and_then(|mut file| write!(file, "{}", text));
+        match written {
+            Ok(()) => {
+                println!(
+                    "{}",
+                    note(format!(
+                        "saved {} definitions to '{}{}{}'",
+                        self.definitions.len(),
+                        style::Bold,
+                        path.display(),
+                        style::Reset
+                    ))
+                );
+                Ok(())
+            }
+            Err(_) => Err(error(format!(
+                "failed to write to '{}{}{}'",
+                style::Bold,
+                path.display(),
+                style::Reset
+            ))),
+        }
+    }
+
+    fn open(&mut self, path: &Path, options: &CompilerOptions) -> Result<(), String> {
+        let text = read(path)?;
+        let filename = format!("{}", path.display());
+        for entry in frontend::read_session(&self.types, &filename, text, options)? {
+            self.run(entry, options)?;
+        }
+        Ok(())
+    }
+
+    // writes the session's values out as they are now, rather than the
+    // definitions that built them (so nothing is run again when it's restored,
+    // and references keep whatever was last assigned to them)
+    fn snapshot(&self, path: &Path) -> Result<(), String> {
+        let bytes = interp::save(&Snapshot {
+            types: self.types.clone(),
+            definitions: self.definitions.clone(),
+            values: self.values.clone(),
+            scopes: self.scopes.clone(),
+        });
+        match fs::write(path, bytes) {
+            Ok(()) => {
+                println!(
+                    "{}",
+                    note(format!(
+                        "saved a snapshot of {} definitions to '{}{}{}'",
+                        self.definitions.len(),
+                        style::Bold,
+                        path.display(),
+                        style::Reset
+                    ))
+                );
+                Ok(())
+            }
+            Err(_) => Err(error(format!(
+                "failed to write to '{}{}{}'",
+                style::Bold,
+                path.display(),
+                style::Reset
+            ))),
+        }
+    }
+
+    // replaces the session with one from a snapshot
+    fn restore(&mut self, path: &Path) -> Result<(), String> {
+        let bytes = fs::read(path).map_err(|_| {
+            error(format!(
+                "failed to read '{}{}{}'",
+                style::Bold,
+                path.display(),
+                style::Reset
+            ))
+        })?;
+        let snapshot = interp::restore(&bytes).ok_or_else(|| {
+            error(format!(
+                "'{}{}{}' isn't a snapshot",
+                style::Bold,
+                path.display(),
+                style::Reset
+            ))
+        })?;
+        self.types = snapshot.types;
+        self.definitions = snapshot.definitions;
+        self.values = snapshot.values;
+        self.scopes = snapshot.scopes;
+        self.results = (self.types.iter())
+            .filter_map(|(name, _)| numbered(name))
+            .max()
+            .unwrap_or(0);
+        println!(
+            "{}",
+            note(format!(
+                "restored {} definitions from '{}{}{}'",
+                self.definitions.len(),
+                style::Bold,
+                path.display(),
+                style::Reset
+            ))
+        );
+        Ok(())
+    }
+
+    fn command(&mut self, command: &str, options: &CompilerOptions) -> Result<bool, String> {
+        let (command, arg) = match command.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (command, ""),
+        };
+        match (command, arg) {
+            (":quit", "") => return Ok(false),
+            (":save", "") | (":open", "") | (":snapshot", "") | (":restore", "") => {
+                return Err(error(format!("'{}' expects a file name", command)))
+            }
+            (":save", path) => self.save(Path::new(path))?,
+            (":open", path) => self.open(Path::new(path), options)?,
+            (":snapshot", path) => self.snapshot(Path::new(path))?,
+            (":restore", path) => self.restore(Path::new(path))?,
+            (":help", "") => {
+                println!("enter an expression to evaluate it, or a definition without 'in'");
+                println!("(like 'let x : int = 1') to add it to the session; the value of");
+                println!("each expression is kept as 'it' (and 'it1', 'it2' and so on)");
+                println!("commands:");
+                println!("  :save FILE    write the session's definitions to FILE");
+                println!("  :open FILE    run the definitions in FILE (e.g. a saved session)");
+                println!("  :snapshot FILE  write the session's values to FILE");
+                println!("  :restore FILE   replace the session with a snapshot from FILE");
+                println!("  :help         display this information");
+                println!("  :quit         leave the REPL");
+            }
+            _ => {
+                return Err(error(format!(
+                    "unrecognised command '{}' (see ':help' for usage)",
+                    command
+                )))
+            }
+        }
+        Ok(true)
+    }
+}
+
+pub fn repl(input: Input, options: &CompilerOptions) {
+    let mut session = Session::new(input, options);
+    let stdin = io::stdin();
+    loop {
+        print!("# ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+        let input = line.trim();
+        let result = if input.is_empty() {
+            continue;
+        } else if input.starts_with(':') {
+            match session.command(input, options) {
+                Ok(true) => Ok(()),
+                Ok(false) => break,
+                Err(err) => Err(err),
+            }
+        } else {
+            frontend::read_entry(&session.types, input.to_string(), options)
+                .and_then(|entry| session.run(entry, options))
+        };
+        if let Err(err) = result {
+            println!("{}", err);
+        }
+    }
+}
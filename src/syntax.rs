@@ -0,0 +1,6 @@
+// the lexer and parser on their own, for tools that drive the parser with
+// tokens of their own rather than a file's (a token is a 'Kind' at a
+// 'Location', made with 'Token::from((location, kind))')
+pub use super::frontend::{
+    parse_expr, tokens, Diagnostic, Expr, Kind, Locatable, Location, SubExpr, Token,
+};
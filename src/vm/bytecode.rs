@@ -0,0 +1,222 @@
+use super::super::frontend::{float_literal, quote, Location};
+use super::super::reflect::Type;
+
+use std::fmt;
+
+// an instruction for the stack machine, which pops its operands off the stack
+// (the last of them on top) and pushes its result. Every instruction fits in
+// twelve bytes, so anything that wouldn't (an int, a float, a string, the type an
+// injection carries or the location a runtime error is reported at) is kept in a table
+// alongside the code and referred to by its index
+#[derive(Clone, Copy)]
+pub enum Op {
+    Unit,
+    Bool(bool),
+    Int(u32),
+    Float(u32),
+    // a new string, with what's in the nth string in the table
+    Str(u32),
+    What,
+    // the nth local of the function that's running (its argument is the
+    // first, if it takes one), the nth value its closure captured, or the
+    // closure itself (which is how a recursive function refers to itself)
+    Local(u32),
+    Captured(u32),
+    Itself,
+    // a closure for the nth function, capturing what the running closure
+    // captured (which is how a function defined in a 'let rec' refers to the
+    // others)
+    Sibling(u32),
+    // pops a value into a local
+    Store(u32),
+    Pop,
+    Neg,
+    Not,
+    Add,
+    Sub,
+    Mul,
+    Lt,
+    FloatAdd,
+    FloatSub,
+    FloatMul,
+    FloatDiv,
+    FloatLt,
+    FloatEq,
+    // both '=' and '==', which compare anything on the heap by address
+    Eq,
+    Concat,
+    // pushes -1, 0 or 1, as the first string comes before, is the same as or
+    // comes after the second
+    Compare,
+    Print,
+    Div(u32),
+    Mod(u32),
+    Jump(u32),
+    // pops a bool, and jumps if it's false
+    JumpUnless(u32),
+    Pair,
+    Fst,
+    Snd,
+    Inl(u32),
+    Inr(u32),
+    // pops an injection and pushes what's in it, jumping if it's a right one
+    Case(u32),
+    Ref,
+    Deref,
+    Assign,
+    // pops a length and the value every element starts out as; then an array
+    // and an index; then an array, an index and the value to write there
+    MakeArray(u32),
+    Index(u32),
+    Update(u32),
+    // pops a list and pushes its head or its tail, or whether it's empty
+    // ('Cons' pops a head and then a tail to put it on)
+    Nil,
+    Cons,
+    Head(u32),
+    Tail(u32),
+    IsEmpty,
+    // makes a closure for the nth function, capturing the values on top of the
+    // stack (as many as the second operand says)
+    Closure(u32, u32),
+    // as 'Closure', but for a lazy value's code or a new thread's
+    Lazy(u32, u32),
+    Force(u32),
+    Chan,
+    Spawn(u32, u32),
+    Send(u32),
+    Recv(u32),
+    // starts the body of a 'try', whose handler's code starts at the target
+    // (which is where an exception goes, with the exception pushed and
+    // anything the body had on the stack or called gone), and then finishes
+    // it ('EndTry') if it gets that far
+    Try(u32),
+    EndTry,
+    // pops an exception, which goes to the innermost 'try' that's running
+    Raise(u32),
+    // pops an argument and a closure and calls it
+    Call,
+    // as 'Call', but in place of the function that's running, so that the
+    // function it calls returns straight to its caller (and recursion in tail
+    // position runs in constant space)
+    TailCall,
+    Return,
+}
+
+// the code for a function, or for a lazy value or thread (which don't take an
+// argument), or for the program itself
+pub struct Function {
+    // what it's called in listings
+    pub name: String,
+    pub param: bool,
+    // how many locals it needs at once, counting its argument
+    pub locals: usize,
+    pub code: Vec<Op>,
+}
+
+// a whole program, compiled for the stack machine (the first function is the
+// program itself)
+pub struct Bytecode {
+    pub functions: Vec<Function>,
+    pub ints: Vec<i64>,
+    pub floats: Vec<f64>,
+    pub strings: Vec<String>,
+    pub types: Vec<Type>,
+    pub locations: Vec<Location>,
+}
+
+impl Bytecode {
+    fn fmt_op(&self, f: &mut fmt::Formatter, op: Op) -> fmt::Result {
+        use self::Op::*;
+        match op {
+            Unit => write!(f, "unit"),
+            Bool(b) => write!(f, "bool {}", b),
+            Int(i) => write!(f, "int {}", self.ints[i as usize]),
+            Float(x) => write!(f, "float {}", float_literal(self.floats[x as usize])),
+            Str(s) => write!(f, "string {}", quote(&self.strings[s as usize])),
+            What => write!(f, "what"),
+            Local(n) => write!(f, "local {}", n),
+            Captured(n) => write!(f, "captured {}", n),
+            Itself => write!(f, "itself"),
+            Sibling(function) => write!(f, "sibling {}", function),
+            Store(n) => write!(f, "store {}", n),
+            Pop => write!(f, "pop"),
+            Neg => write!(f, "neg"),
+            Not => write!(f, "not"),
+            Add => write!(f, "add"),
+            Sub => write!(f, "sub"),
+            Mul => write!(f, "mul"),
+            Lt => write!(f, "lt"),
+            FloatAdd => write!(f, "float add"),
+            FloatSub => write!(f, "float sub"),
+            FloatMul => write!(f, "float mul"),
+            FloatDiv => write!(f, "float div"),
+            FloatLt => write!(f, "float lt"),
+            FloatEq => write!(f, "float eq"),
+            Eq => write!(f, "eq"),
+            Concat => write!(f, "concat"),
+            Compare => write!(f, "compare"),
+            Print => write!(f, "print"),
+            Div(_) => write!(f, "div"),
+            Mod(_) => write!(f, "mod"),
+            Jump(target) => write!(f, "jump {}", target),
+            JumpUnless(target) => write!(f, "jump unless {}", target),
+            Pair => write!(f, "pair"),
+            Fst => write!(f, "fst"),
+            Snd => write!(f, "snd"),
+            Inl(t) => write!(f, "inl {}", self.types[t as usize]),
+            Inr(t) => write!(f, "inr {}", self.types[t as usize]),
+            Case(target) => write!(f, "case {}", target),
+            Ref => write!(f, "ref"),
+            Deref => write!(f, "deref"),
+            Assign => write!(f, "assign"),
+            MakeArray(_) => write!(f, "make array"),
+            Index(_) => write!(f, "index"),
+            Update(_) => write!(f, "update"),
+            Nil => write!(f, "nil"),
+            Cons => write!(f, "cons"),
+            Head(_) => write!(f, "head"),
+            Tail(_) => write!(f, "tail"),
+            IsEmpty => write!(f, "is empty"),
+            Closure(function, n) => write!(f, "closure {} {}", function, n),
+            Lazy(function, n) => write!(f, "lazy {} {}", function, n),
+            Force(_) => write!(f, "force"),
+            Chan => write!(f, "chan"),
+            Spawn(function, n) => write!(f, "spawn {} {}", function, n),
+            Send(_) => write!(f, "send"),
+            Recv(_) => write!(f, "recv"),
+            Try(target) => write!(f, "try {}", target),
+            EndTry => write!(f, "end try"),
+            Raise(_) => write!(f, "raise"),
+            Call => write!(f, "call"),
+            TailCall => write!(f, "tail call"),
+            Return => write!(f, "return"),
+        }
+    }
+}
+
+// a listing of every function, numbered (as closures refer to them), with each
+// of their instructions numbered too (as jumps refer to them)
+impl fmt::Display for Bytecode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, function) in self.functions.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(
+                f,
+                "{} '{}': ({}{} locals)",
+                i,
+                function.name,
+                if function.param { "argument, " } else { "" },
+                function.locals
+            )?;
+            for (pc, op) in function.code.iter().enumerate() {
+                write!(f, "{:>6}  ", pc)?;
+                self.fmt_op(f, *op)?;
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,568 @@
+use super::super::frontend::ast::{self, Expr, Free, Lambda, UnOp};
+use super::bytecode::{Bytecode, Function, Op};
+
+use std::collections::HashMap;
+
+// the function being compiled: what's in scope in it, and its code so far
+struct Body<'a> {
+    // the variables bound by the function so far that are still in scope,
+    // innermost last (each one's local is where it is in this list)
+    locals: Vec<&'a str>,
+    // the most locals it's needed at once
+    needed: usize,
+    captured: Vec<&'a str>,
+    itself: Option<&'a str>,
+    // the functions it was defined together with in a 'let rec', and where
+    // each is in the table
+    siblings: Vec<(&'a str, u32)>,
+    code: Vec<Op>,
+}
+
+impl<'a> Body<'a> {
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    // where the next instruction goes, as a jump target
+    fn here(&self) -> u32 {
+        self.code.len() as u32
+    }
+
+    // points a jump emitted earlier at the next instruction
+    fn patch(&mut self, jump: usize) {
+        let here = self.here();
+        match self.code[jump] {
+            Op::Jump(ref mut target)
+            | Op::JumpUnless(ref mut target)
+            | Op::Case(ref mut target)
+            | Op::Try(ref mut target) => *target = here,
+            _ => unreachable!(),
+        }
+    }
+
+    fn bind(&mut self, v: &'a str) -> u32 {
+        self.locals.push(v);
+        self.needed = self.needed.max(self.locals.len());
+        self.locals.len() as u32 - 1
+    }
+
+    // a function's argument shadows the name it refers to itself by (and the
+    // names of its siblings), which shadows anything it captured
+    fn load(&self, v: &str) -> Op {
+        if let Some(local) = self.locals.iter().rposition(|local| *local == v) {
+            Op::Local(local as u32)
+        } else if self.itself == Some(v) {
+            Op::Itself
+        } else if let Some(&(_, function)) = self.siblings.iter().find(|(f, _)| *f == v) {
+            Op::Sibling(function)
+        } else {
+            match self.captured.iter().position(|captured| *captured == v) {
+                Some(captured) => Op::Captured(captured as u32),
+                // the program has already been checked, so every variable is
+                // bound
+                None => unreachable!(),
+            }
+        }
+    }
+}
+
+struct Compiler {
+    bytecode: Bytecode,
+    // where each int is in the table, so that each is only kept once
+    ints: HashMap<i64, u32>,
+    // the same for floats, by their bits
+    floats: HashMap<u64, u32>,
+}
+
+impl Compiler {
+    fn int(&mut self, i: i64) -> u32 {
+        let ints = &mut self.bytecode.ints;
+        *self.ints.entry(i).or_insert_with(|| {
+            ints.push(i);
+            ints.len() as u32 - 1
+        })
+    }
+
+    fn float(&mut self, f: f64) -> u32 {
+        let floats = &mut self.bytecode.floats;
+        *self.floats.entry(f.to_bits()).or_insert_with(|| {
+            floats.push(f);
+            floats.len() as u32 - 1
+        })
+    }
+
+    // compiles the code for a function (which can refer to itself by 'itself',
+    // if it's recursive) into the next free place in the table, returning
+    // where it went along with the variables its closure has to capture, in
+    // the order it expects them
+    fn function<'a>(
+        &mut self,
+        name: String,
+        itself: Option<&'a str>,
+        param: Option<&'a str>,
+        body: &'a Expr,
+    ) -> (u32, Vec<&'a str>) {
+        let mut captured = body
+            .fv()
+            .into_iter()
+            .map(|v| v.as_str())
+            .filter(|v| Some(*v) != param && Some(*v) != itself)
+            .collect::<Vec<_>>();
+        captured.sort();
+        let index = self.reserve(name, param.is_some());
+        self.compile_into(index, itself, vec![], param, body, captured.clone());
+        (index, captured)
+    }
+
+    // takes the next free place in the table for a function that's compiled
+    // later
+    fn reserve(&mut self, name: String, param: bool) -> u32 {
+        self.bytecode.functions.push(Function {
+            name,
+            param,
+            locals: 0,
+            code: vec![],
+        });
+        self.bytecode.functions.len() as u32 - 1
+    }
+
+    fn compile_into<'a>(
+        &mut self,
+        index: u32,
+        itself: Option<&'a str>,
+        siblings: Vec<(&'a str, u32)>,
+        param: Option<&'a str>,
+        body: &'a Expr,
+        captured: Vec<&'a str>,
+    ) {
+        let index = index as usize;
+        let mut function = Body {
+            locals: vec![],
+            needed: 0,
+            captured,
+            itself,
+            siblings,
+            code: vec![],
+        };
+        if let Some(param) = param {
+            function.bind(param);
+        }
+        self.expr(&mut function, body, true);
+        function.emit(Op::Return);
+        self.bytecode.functions[index].locals = function.needed;
+        self.bytecode.functions[index].code = function.code;
+    }
+
+    // compiles functions defined together, which all capture the same values
+    // (so each can make a closure of any of the others when it refers to it),
+    // then makes a closure for each and binds it
+    fn group<'a>(&mut self, body: &mut Body<'a>, functions: &'a [(String, Lambda)]) {
+        let group = functions
+            .iter()
+            .map(|(f, (v, _))| (f.as_str(), self.reserve(f.clone(), true), v.as_str()))
+            .collect::<Vec<_>>();
+        let mut captured = functions
+            .iter()
+            .flat_map(|(_, (v, code))| code.fv().into_iter().filter(move |w| *w != v))
+            .map(|v| v.as_str())
+            .filter(|v| functions.iter().all(|(f, _)| f != v))
+            .collect::<Vec<_>>();
+        captured.sort();
+        captured.dedup();
+        for (&(f, index, v), (_, (_, code))) in group.iter().zip(functions) {
+            let siblings = group
+                .iter()
+                .filter(|&&(g, _, _)| g != f)
+                .map(|&(g, index, _)| (g, index))
+                .collect();
+            self.compile_into(index, Some(f), siblings, Some(v), code, captured.clone());
+        }
+        for &(f, index, _) in group.iter() {
+            for v in captured.iter() {
+                body.emit(body.load(v));
+            }
+            body.emit(Op::Closure(index, captured.len() as u32));
+            let local = body.bind(f);
+            body.emit(Op::Store(local));
+        }
+    }
+
+    // compiles a closure's code, then the code that makes it (capturing what
+    // it needs from the function being compiled)
+    fn closure<'a>(
+        &mut self,
+        body: &mut Body<'a>,
+        name: String,
+        itself: Option<&'a str>,
+        param: Option<&'a str>,
+        code: &'a Expr,
+        make: fn(u32, u32) -> Op,
+    ) {
+        let (function, captured) = self.function(name, itself, param, code);
+        for v in captured.iter() {
+            body.emit(body.load(v));
+        }
+        body.emit(make(function, captured.len() as u32));
+    }
+
+    fn lambda<'a>(&mut self, body: &mut Body<'a>, name: Option<&'a str>, lambda: &'a Lambda) {
+        let (ref v, ref code) = *lambda;
+        self.closure(
+            body,
+            name.unwrap_or("fun").to_string(),
+            name,
+            Some(v),
+            code,
+            Op::Closure,
+        );
+    }
+
+    // compiles an operator, once its left operand has been compiled
+    fn operator<'a>(&mut self, body: &mut Body<'a>, expr: &'a Expr, tail: bool) {
+        use self::Expr::*;
+        match *expr {
+            BinOp(ast::BinOp::And, _, ref right) => {
+                let short = body.emit(Op::JumpUnless(0));
+                self.expr(body, right, tail);
+                let end = body.emit(Op::Jump(0));
+                body.patch(short);
+                body.emit(Op::Bool(false));
+                body.patch(end);
+            }
+            BinOp(ast::BinOp::Or, _, ref right) => {
+                let long = body.emit(Op::JumpUnless(0));
+                body.emit(Op::Bool(true));
+                let end = body.emit(Op::Jump(0));
+                body.patch(long);
+                self.expr(body, right, tail);
+                body.patch(end);
+            }
+            BinOp(op, _, ref right) => {
+                self.expr(body, right, false);
+                body.emit(match op {
+                    ast::BinOp::Add => Op::Add,
+                    ast::BinOp::Sub => Op::Sub,
+                    ast::BinOp::Mul => Op::Mul,
+                    ast::BinOp::Lt => Op::Lt,
+                    ast::BinOp::FloatAdd => Op::FloatAdd,
+                    ast::BinOp::FloatSub => Op::FloatSub,
+                    ast::BinOp::FloatMul => Op::FloatMul,
+                    ast::BinOp::FloatDiv => Op::FloatDiv,
+                    ast::BinOp::FloatLt => Op::FloatLt,
+                    ast::BinOp::FloatEq => Op::FloatEq,
+                    ast::BinOp::Eq | ast::BinOp::PhysEq => Op::Eq,
+                    ast::BinOp::Concat => Op::Concat,
+                    ast::BinOp::Compare => Op::Compare,
+                    _ => unreachable!(),
+                });
+            }
+            Div(ref location, _, ref right) | Mod(ref location, _, ref right) => {
+                self.expr(body, right, false);
+                self.bytecode.locations.push(location.clone());
+                let location = self.bytecode.locations.len() as u32 - 1;
+                body.emit(match *expr {
+                    Div(..) => Op::Div(location),
+                    _ => Op::Mod(location),
+                });
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // compiles an expression, which leaves its value on top of the stack (a
+    // call in tail position replaces the function that's running)
+    fn expr<'a>(&mut self, body: &mut Body<'a>, expr: &'a Expr, tail: bool) {
+        use self::Expr::*;
+        match *expr {
+            Unit => {
+                body.emit(Op::Unit);
+            }
+            What => {
+                body.emit(Op::What);
+            }
+            Var(ref v) => {
+                body.emit(body.load(v));
+            }
+            Int(i) => {
+                let i = self.int(i);
+                body.emit(Op::Int(i));
+            }
+            Float(f) => {
+                let f = self.float(f);
+                body.emit(Op::Float(f));
+            }
+            Bool(b) => {
+                body.emit(Op::Bool(b));
+            }
+            Str(ref string) => {
+                self.bytecode.strings.push(string.clone());
+                body.emit(Op::Str(self.bytecode.strings.len() as u32 - 1));
+            }
+            UnOp(op, ref sub) => {
+                self.expr(body, sub, false);
+                body.emit(match op {
+                    self::UnOp::Neg => Op::Neg,
+                    self::UnOp::Not => Op::Not,
+                });
+            }
+            BinOp(..) | Div(..) | Mod(..) => {
+                // a chain ('a + b + c') is compiled from its leftmost operand
+                // on, in a loop, as it can be too long to recurse down
+                let (first, operators) = ast::chain(expr);
+                self.expr(body, first, false);
+                let n = operators.len();
+                for (i, operator) in operators.into_iter().enumerate() {
+                    self.operator(body, operator, tail && i + 1 == n);
+                }
+            }
+            If(ref condition, ref left, ref right) => {
+                self.expr(body, condition, false);
+                let otherwise = body.emit(Op::JumpUnless(0));
+                self.expr(body, left, tail);
+                let end = body.emit(Op::Jump(0));
+                body.patch(otherwise);
+                self.expr(body, right, tail);
+                body.patch(end);
+            }
+            Pair(ref left, ref right) => {
+                self.expr(body, left, false);
+                self.expr(body, right, false);
+                body.emit(Op::Pair);
+            }
+            Fst(ref sub) => {
+                self.expr(body, sub, false);
+                body.emit(Op::Fst);
+            }
+            Snd(ref sub) => {
+                self.expr(body, sub, false);
+                body.emit(Op::Snd);
+            }
+            Inl(ref sub, ref type_expr) | Inr(ref sub, ref type_expr) => {
+                self.expr(body, sub, false);
+                self.bytecode.types.push(type_expr.into());
+                let t = self.bytecode.types.len() as u32 - 1;
+                body.emit(match *expr {
+                    Inl(..) => Op::Inl(t),
+                    _ => Op::Inr(t),
+                });
+            }
+            Case(ref sub, (ref x, ref left), (ref y, ref right)) => {
+                self.expr(body, sub, false);
+                let otherwise = body.emit(Op::Case(0));
+                let local = body.bind(x);
+                body.emit(Op::Store(local));
+                self.expr(body, left, tail);
+                body.locals.pop();
+                let end = body.emit(Op::Jump(0));
+                body.patch(otherwise);
+                let local = body.bind(y);
+                body.emit(Op::Store(local));
+                self.expr(body, right, tail);
+                body.locals.pop();
+                body.patch(end);
+            }
+            While(ref condition, ref sub) => {
+                let start = body.here();
+                self.expr(body, condition, false);
+                let end = body.emit(Op::JumpUnless(0));
+                self.expr(body, sub, false);
+                body.emit(Op::Pop);
+                body.emit(Op::Jump(start));
+                body.patch(end);
+                body.emit(Op::Unit);
+            }
+            // the bound is kept in a local of its own (which nothing can refer
+            // to by name), and the loop stops after the counter reaches it rather
+            // than when it passes it, as it might not be able to
+            For(ref from, ref to, (ref v, ref sub)) => {
+                self.expr(body, from, false);
+                self.expr(body, to, false);
+                let bound = body.bind("");
+                body.emit(Op::Store(bound));
+                let counter = body.bind(v);
+                body.emit(Op::Store(counter));
+                body.emit(Op::Local(bound));
+                body.emit(Op::Local(counter));
+                body.emit(Op::Lt);
+                body.emit(Op::Not);
+                let skip = body.emit(Op::JumpUnless(0));
+                let start = body.here();
+                self.expr(body, sub, false);
+                body.emit(Op::Pop);
+                body.emit(Op::Local(counter));
+                body.emit(Op::Local(bound));
+                body.emit(Op::Lt);
+                let end = body.emit(Op::JumpUnless(0));
+                let one = self.int(1);
+                body.emit(Op::Local(counter));
+                body.emit(Op::Int(one));
+                body.emit(Op::Add);
+                body.emit(Op::Store(counter));
+                body.emit(Op::Jump(start));
+                body.patch(skip);
+                body.patch(end);
+                body.locals.pop();
+                body.locals.pop();
+                body.emit(Op::Unit);
+            }
+            Seq(ref seq) => match seq.split_last() {
+                Some((last, rest)) => {
+                    for sub in rest {
+                        self.expr(body, sub, false);
+                        body.emit(Op::Pop);
+                    }
+                    self.expr(body, last, tail);
+                }
+                None => {
+                    body.emit(Op::Unit);
+                }
+            },
+            Ref(ref sub) => {
+                self.expr(body, sub, false);
+                body.emit(Op::Ref);
+            }
+            Deref(ref sub) => {
+                self.expr(body, sub, false);
+                body.emit(Op::Deref);
+            }
+            Lazy(ref sub) => self.closure(body, "lazy".to_string(), None, None, sub, Op::Lazy),
+            Force(ref location, ref sub) => {
+                self.expr(body, sub, false);
+                self.bytecode.locations.push(location.clone());
+                body.emit(Op::Force(self.bytecode.locations.len() as u32 - 1));
+            }
+            Chan => {
+                body.emit(Op::Chan);
+            }
+            Spawn(ref sub) => self.closure(body, "spawn".to_string(), None, None, sub, Op::Spawn),
+            Send(ref location, ref left, ref right) => {
+                self.expr(body, left, false);
+                self.expr(body, right, false);
+                self.bytecode.locations.push(location.clone());
+                body.emit(Op::Send(self.bytecode.locations.len() as u32 - 1));
+            }
+            Recv(ref location, ref sub) => {
+                self.expr(body, sub, false);
+                self.bytecode.locations.push(location.clone());
+                body.emit(Op::Recv(self.bytecode.locations.len() as u32 - 1));
+            }
+            Raise(ref location, ref sub) => {
+                self.expr(body, sub, false);
+                self.bytecode.locations.push(location.clone());
+                body.emit(Op::Raise(self.bytecode.locations.len() as u32 - 1));
+            }
+            // nothing in the body is in tail position, as the 'try' has to be
+            // finished when it is
+            Try(ref sub, (ref v, ref handler)) => {
+                let handle = body.emit(Op::Try(0));
+                self.expr(body, sub, false);
+                body.emit(Op::EndTry);
+                let end = body.emit(Op::Jump(0));
+                body.patch(handle);
+                let local = body.bind(v);
+                body.emit(Op::Store(local));
+                self.expr(body, handler, tail);
+                body.locals.pop();
+                body.patch(end);
+            }
+            Assign(ref left, ref right) => {
+                self.expr(body, left, false);
+                self.expr(body, right, false);
+                body.emit(Op::Assign);
+            }
+            MakeArray(ref location, ref left, ref right)
+            | Index(ref location, ref left, ref right) => {
+                self.expr(body, left, false);
+                self.expr(body, right, false);
+                self.bytecode.locations.push(location.clone());
+                let location = self.bytecode.locations.len() as u32 - 1;
+                body.emit(match *expr {
+                    MakeArray(..) => Op::MakeArray(location),
+                    _ => Op::Index(location),
+                });
+            }
+            Update(ref location, ref left, ref index, ref right) => {
+                self.expr(body, left, false);
+                self.expr(body, index, false);
+                self.expr(body, right, false);
+                self.bytecode.locations.push(location.clone());
+                body.emit(Op::Update(self.bytecode.locations.len() as u32 - 1));
+            }
+            Nil => {
+                body.emit(Op::Nil);
+            }
+            Cons(ref left, ref right) => {
+                self.expr(body, left, false);
+                self.expr(body, right, false);
+                body.emit(Op::Cons);
+            }
+            Head(ref location, ref sub) | Tail(ref location, ref sub) => {
+                self.expr(body, sub, false);
+                self.bytecode.locations.push(location.clone());
+                let location = self.bytecode.locations.len() as u32 - 1;
+                body.emit(match *expr {
+                    Head(..) => Op::Head(location),
+                    _ => Op::Tail(location),
+                });
+            }
+            IsEmpty(ref sub) => {
+                self.expr(body, sub, false);
+                body.emit(Op::IsEmpty);
+            }
+            Print(ref sub) => {
+                self.expr(body, sub, false);
+                body.emit(Op::Print);
+            }
+            Expect(ref sub, _) | Attributed(_, ref sub) => self.expr(body, sub, tail),
+            Lambda(ref lambda) => self.lambda(body, None, lambda),
+            App(ref left, ref right) => {
+                self.expr(body, left, false);
+                self.expr(body, right, false);
+                body.emit(if tail { Op::TailCall } else { Op::Call });
+            }
+            // a chain of definitions ('let x = e in let y = e in ...') is
+            // compiled in a loop, as a program of top-level definitions is one
+            Let(..) | LetFun(..) | LetRec(..) => {
+                let (definitions, rest) = ast::definitions(expr);
+                let len = body.locals.len();
+                for definition in definitions {
+                    match *definition {
+                        Let(ref v, ref sub, _) => {
+                            self.expr(body, sub, false);
+                            let local = body.bind(v);
+                            body.emit(Op::Store(local));
+                        }
+                        LetFun(ref f, ref lambda, _) => {
+                            self.lambda(body, Some(f), lambda);
+                            let local = body.bind(f);
+                            body.emit(Op::Store(local));
+                        }
+                        LetRec(ref functions, _) => self.group(body, functions),
+                        _ => unreachable!(),
+                    }
+                }
+                self.expr(body, rest, tail);
+                body.locals.truncate(len);
+            }
+        }
+    }
+}
+
+// compiles a (checked) program for the stack machine
+pub fn compile(expr: &Expr) -> Bytecode {
+    let mut compiler = Compiler {
+        bytecode: Bytecode {
+            functions: vec![],
+            ints: vec![],
+            floats: vec![],
+            strings: vec![],
+            types: vec![],
+            locations: vec![],
+        },
+        ints: HashMap::new(),
+        floats: HashMap::new(),
+    };
+    compiler.function("<top level>".to_string(), None, None, expr);
+    compiler.bytecode
+}
@@ -0,0 +1,667 @@
+use super::frontend::log;
+use super::interp::{compare, Input, Output, Schedule, MAIN};
+use super::{reflect, IntWidth};
+
+mod bytecode;
+mod compile;
+
+pub use self::bytecode::{Bytecode, Op};
+pub use self::compile::compile;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+// a stack machine that runs bytecode, which doesn't depend on anything about
+// the machine it runs on. Every thread a program spawns has a stack of its own
+// (threads don't use the host's, so they all run on the same host thread), and
+// they take turns as they do in the interpreter: a thread runs until it
+// finishes or has to wait, and then the first of the threads that are ready to
+// run takes over (or, with a seed, one of them picked at random), so a program
+// runs in the same order in both
+
+pub struct Closure {
+    function: u32,
+    captured: Vec<Value>,
+}
+
+pub enum Thunk {
+    Delayed(Rc<Closure>),
+    // being computed, so forcing it again would never finish
+    Forcing,
+    Forced(Value),
+}
+
+// the threads waiting to send on a channel (along with what they're sending),
+// or to receive from it
+#[derive(Default)]
+pub struct Channel {
+    senders: VecDeque<(usize, Value)>,
+    receivers: VecDeque<usize>,
+}
+
+// anything that lives on the heap in compiled code is behind an 'Rc' here, so
+// that '==' can compare it by address in the same way (injections refer to the
+// type they carry by where it is in the bytecode's table)
+#[derive(Clone)]
+pub enum Value {
+    Unit,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Pair(Rc<(Value, Value)>),
+    Inl(Rc<Value>, u32),
+    Inr(Rc<Value>, u32),
+    Ref(Rc<RefCell<Value>>),
+    Lazy(Rc<RefCell<Thunk>>),
+    Chan(Rc<RefCell<Channel>>),
+    Array(Rc<RefCell<Vec<Value>>>),
+    Nil,
+    Cons(Rc<(Value, Value)>),
+    Fun(Rc<Closure>),
+    Str(Rc<str>),
+}
+
+impl Value {
+    fn same(&self, other: &Value) -> bool {
+        use self::Value::*;
+        match (self, other) {
+            (Unit, Unit) | (Nil, Nil) => true,
+            (Int(a), Int(b)) => a == b,
+            (Float(a), Float(b)) => a == b,
+            (Bool(a), Bool(b)) => a == b,
+            (Pair(a), Pair(b)) | (Cons(a), Cons(b)) => Rc::ptr_eq(a, b),
+            (Inl(a, _), Inl(b, _)) | (Inr(a, _), Inr(b, _)) => Rc::ptr_eq(a, b),
+            (Ref(a), Ref(b)) => Rc::ptr_eq(a, b),
+            (Lazy(a), Lazy(b)) => Rc::ptr_eq(a, b),
+            (Chan(a), Chan(b)) => Rc::ptr_eq(a, b),
+            (Array(a), Array(b)) => Rc::ptr_eq(a, b),
+            (Fun(a), Fun(b)) => Rc::ptr_eq(a, b),
+            (Str(a), Str(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    fn int(self) -> i64 {
+        match self {
+            Value::Int(i) => i,
+            _ => unreachable!(),
+        }
+    }
+
+    fn float(self) -> f64 {
+        match self {
+            Value::Float(f) => f,
+            _ => unreachable!(),
+        }
+    }
+
+    fn string(self) -> Rc<str> {
+        match self {
+            Value::Str(string) => string,
+            _ => unreachable!(),
+        }
+    }
+
+    fn array(self) -> Rc<RefCell<Vec<Value>>> {
+        match self {
+            Value::Array(elements) => elements,
+            _ => unreachable!(),
+        }
+    }
+
+    fn boolean(self) -> bool {
+        match self {
+            Value::Bool(b) => b,
+            _ => unreachable!(),
+        }
+    }
+}
+
+// how many calls deep a thread can get (calls in tail position don't count), so
+// that unbounded recursion fails rather than using up all of the host's memory
+const DEPTH: usize = 1 << 20;
+
+// a call that hasn't returned yet: the closure it called, the next instruction
+// it runs, and where its locals start on its thread's stack (along with the lazy
+// value it's computing, if that's what it's doing)
+struct Frame {
+    closure: Rc<Closure>,
+    pc: usize,
+    base: usize,
+    forcing: Option<Rc<RefCell<Thunk>>>,
+}
+
+// a 'try' whose body is running: how many frames its thread had saved and how
+// high its stack was when the body started, and where the handler's code is
+struct Handler {
+    frames: usize,
+    height: usize,
+    pc: usize,
+}
+
+#[derive(Default)]
+struct Thread {
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+}
+
+// why a thread stopped running
+enum Stop {
+    Finished(Value),
+    // waiting on a channel, at the location of the instruction it's waiting at
+    Waiting(u32),
+}
+
+pub struct Machine<'b> {
+    bytecode: &'b Bytecode,
+    input: Input,
+    output: Output,
+    // arithmetic wraps around at this many bits, as it does in compiled code
+    width: IntWidth,
+    // every thread a program has spawned, by its number ('None' once it's
+    // finished, or while it's running)
+    threads: Vec<Option<Thread>>,
+    ready: VecDeque<usize>,
+    schedule: Schedule,
+}
+
+impl<'b> Machine<'b> {
+    pub fn new(bytecode: &'b Bytecode, input: Input, width: IntWidth) -> Machine<'b> {
+        Machine {
+            bytecode,
+            input,
+            output: Output::Stdout,
+            width,
+            threads: vec![],
+            ready: VecDeque::new(),
+            schedule: Schedule::InOrder,
+        }
+    }
+
+    // picks the thread that takes over from one that finishes or has to wait
+    // with a generator seeded with 'seed', as the interpreter does
+    pub fn schedule(&mut self, seed: u64) {
+        self.schedule = Schedule::Seeded(seed);
+    }
+
+    // keeps what the program prints, rather than printing it, until it's taken
+    // with 'output'
+    pub fn capture_output(&mut self) {
+        self.output = Output::Captured(String::new());
+    }
+
+    pub fn output(&mut self) -> String {
+        self.output.take()
+    }
+
+    pub fn reflect(&self, value: &Value) -> reflect::Value {
+        use self::Value::*;
+        match *value {
+            Unit => reflect::Value::Unit,
+            Int(i) => reflect::Value::Int(i),
+            Float(f) => reflect::Value::Float(f),
+            Bool(b) => reflect::Value::Bool(b),
+            Pair(ref pair) => reflect::Value::Pair(
+                Box::new(self.reflect(&pair.0)),
+                Box::new(self.reflect(&pair.1)),
+            ),
+            Inl(ref value, t) => reflect::Value::Inl(
+                Box::new(self.reflect(value)),
+                self.bytecode.types[t as usize].clone(),
+            ),
+            Inr(ref value, t) => reflect::Value::Inr(
+                Box::new(self.reflect(value)),
+                self.bytecode.types[t as usize].clone(),
+            ),
+            Ref(ref value) => reflect::Value::Ref(Box::new(self.reflect(&value.borrow()))),
+            Lazy(ref thunk) => match *thunk.borrow() {
+                Thunk::Forced(ref value) => {
+                    reflect::Value::Lazy(Some(Box::new(self.reflect(value))))
+                }
+                _ => reflect::Value::Lazy(None),
+            },
+            Chan(_) => reflect::Value::Chan,
+            Array(ref elements) => reflect::Value::Array(
+                elements
+                    .borrow()
+                    .iter()
+                    .map(|value| self.reflect(value))
+                    .collect(),
+            ),
+            Nil | Cons(_) => {
+                let mut elements = vec![];
+                let mut list = value;
+                while let Cons(ref cell) = *list {
+                    elements.push(self.reflect(&cell.0));
+                    list = &cell.1;
+                }
+                reflect::Value::List(elements)
+            }
+            Fun(_) => reflect::Value::Fun,
+            Str(ref string) => reflect::Value::Str(string.to_string()),
+        }
+    }
+
+    // a thread that's about to call a closure, with its argument (if it takes
+    // one) in the first of its locals
+    fn enter(&self, stack: &mut Vec<Value>, closure: Rc<Closure>, arg: Option<Value>) -> Frame {
+        let function = &self.bytecode.functions[closure.function as usize];
+        let base = stack.len();
+        stack.extend(arg);
+        stack.resize(base + function.locals, Value::Unit);
+        Frame {
+            closure,
+            pc: 0,
+            base,
+            forcing: None,
+        }
+    }
+
+    // saves the frame of a thread that's making a call, unless it's already as
+    // deep as it can get
+    fn push(frames: &mut Vec<Frame>, frame: Frame) -> Result<(), String> {
+        if frames.len() + 1 >= DEPTH {
+            return Err(format!(
+                "stack overflow (the limit is {} calls deep)",
+                DEPTH
+            ));
+        }
+        frames.push(frame);
+        Ok(())
+    }
+
+    fn error(&self, location: u32, message: &str) -> String {
+        log::runtime_error(&self.bytecode.locations[location as usize], message)
+    }
+
+    // runs a thread until it finishes or has to wait
+    fn execute(&mut self, number: usize, thread: &mut Thread) -> Result<Stop, String> {
+        use self::Op::*;
+        let bytecode = self.bytecode;
+        let functions = &bytecode.functions;
+        let stack = &mut thread.stack;
+        let mut frame = thread.frames.pop().unwrap();
+        let mut code = &functions[frame.closure.function as usize].code[..];
+        macro_rules! pop {
+            () => {
+                stack.pop().unwrap()
+            };
+        }
+        loop {
+            let op = code[frame.pc];
+            frame.pc += 1;
+            let value = match op {
+                Unit => Value::Unit,
+                Bool(b) => Value::Bool(b),
+                Int(i) => Value::Int(bytecode.ints[i as usize]),
+                Float(f) => Value::Float(bytecode.floats[f as usize]),
+                Str(s) => Value::Str(Rc::from(bytecode.strings[s as usize].as_str())),
+                What => Value::Int(self.input.read(self.width)?),
+                Local(n) => stack[frame.base + n as usize].clone(),
+                Captured(n) => frame.closure.captured[n as usize].clone(),
+                Itself => Value::Fun(frame.closure.clone()),
+                Sibling(function) => Value::Fun(Rc::new(self::Closure {
+                    function,
+                    captured: frame.closure.captured.clone(),
+                })),
+                Store(n) => {
+                    stack[frame.base + n as usize] = pop!();
+                    continue;
+                }
+                Pop => {
+                    pop!();
+                    continue;
+                }
+                Neg => Value::Int(self.width.wrap(pop!().int().wrapping_neg())),
+                Not => Value::Bool(!pop!().boolean()),
+                Add | Sub | Mul | Lt => {
+                    let right = pop!().int();
+                    let left = pop!().int();
+                    match op {
+                        Add => Value::Int(self.width.wrap(left.wrapping_add(right))),
+                        Sub => Value::Int(self.width.wrap(left.wrapping_sub(right))),
+                        Mul => Value::Int(self.width.wrap(left.wrapping_mul(right))),
+                        _ => Value::Bool(left < right),
+                    }
+                }
+                FloatAdd | FloatSub | FloatMul | FloatDiv | FloatLt | FloatEq => {
+                    let right = pop!().float();
+                    let left = pop!().float();
+                    match op {
+                        FloatAdd => Value::Float(left + right),
+                        FloatSub => Value::Float(left - right),
+                        FloatMul => Value::Float(left * right),
+                        FloatDiv => Value::Float(left / right),
+                        FloatLt => Value::Bool(left < right),
+                        _ => Value::Bool(left == right),
+                    }
+                }
+                Eq => {
+                    let right = pop!();
+                    Value::Bool(pop!().same(&right))
+                }
+                Concat => {
+                    let right = pop!().string();
+                    let left = pop!().string();
+                    Value::Str(Rc::from(format!("{}{}", left, right)))
+                }
+                Compare => {
+                    let right = pop!().string();
+                    Value::Int(compare(&pop!().string(), &right))
+                }
+                Print => {
+                    self.output.write(&pop!().string());
+                    Value::Unit
+                }
+                Div(location) => {
+                    let right = pop!().int();
+                    let left = pop!().int();
+                    match right {
+                        0 => return Err(self.error(location, "division by zero")),
+                        right => match left.checked_div(right) {
+                            Some(i) if self.width.wrap(i) == i => Value::Int(i),
+                            _ => return Err(self.error(location, "overflow in division")),
+                        },
+                    }
+                }
+                Mod(location) => {
+                    let right = pop!().int();
+                    let left = pop!().int();
+                    match right {
+                        0 => return Err(self.error(location, "division by zero")),
+                        right => Value::Int(left.wrapping_rem(right)),
+                    }
+                }
+                Jump(target) => {
+                    frame.pc = target as usize;
+                    continue;
+                }
+                JumpUnless(target) => {
+                    if !pop!().boolean() {
+                        frame.pc = target as usize;
+                    }
+                    continue;
+                }
+                Pair => {
+                    let right = pop!();
+                    let left = pop!();
+                    Value::Pair(Rc::new((left, right)))
+                }
+                Fst | Snd => match pop!() {
+                    Value::Pair(pair) => match op {
+                        Fst => pair.0.clone(),
+                        _ => pair.1.clone(),
+                    },
+                    _ => unreachable!(),
+                },
+                Inl(t) => Value::Inl(Rc::new(pop!()), t),
+                Inr(t) => Value::Inr(Rc::new(pop!()), t),
+                Case(target) => match pop!() {
+                    Value::Inl(value, _) => (*value).clone(),
+                    Value::Inr(value, _) => {
+                        frame.pc = target as usize;
+                        (*value).clone()
+                    }
+                    _ => unreachable!(),
+                },
+                Ref => Value::Ref(Rc::new(RefCell::new(pop!()))),
+                Deref => match pop!() {
+                    Value::Ref(value) => value.borrow().clone(),
+                    _ => unreachable!(),
+                },
+                Assign => {
+                    let value = pop!();
+                    match pop!() {
+                        Value::Ref(cell) => *cell.borrow_mut() = value,
+                        _ => unreachable!(),
+                    }
+                    Value::Unit
+                }
+                MakeArray(location) => {
+                    let value = pop!();
+                    match pop!().int() {
+                        length if length < 0 => {
+                            return Err(self.error(location, "negative array length"))
+                        }
+                        length => Value::Array(Rc::new(RefCell::new(vec![value; length as usize]))),
+                    }
+                }
+                Index(location) => {
+                    let i = pop!().int();
+                    let elements = pop!().array();
+                    let element = usize::try_from(i)
+                        .ok()
+                        .and_then(|i| elements.borrow().get(i).cloned());
+                    match element {
+                        Some(value) => value,
+                        None => return Err(self.error(location, "array index out of bounds")),
+                    }
+                }
+                Update(location) => {
+                    let value = pop!();
+                    let i = pop!().int();
+                    let elements = pop!().array();
+                    let mut elements = elements.borrow_mut();
+                    match usize::try_from(i).ok().and_then(|i| elements.get_mut(i)) {
+                        Some(slot) => *slot = value,
+                        None => return Err(self.error(location, "array index out of bounds")),
+                    }
+                    Value::Unit
+                }
+                Nil => Value::Nil,
+                Cons => {
+                    let right = pop!();
+                    let left = pop!();
+                    Value::Cons(Rc::new((left, right)))
+                }
+                Head(location) | Tail(location) => match pop!() {
+                    Value::Cons(cell) => match op {
+                        Head(_) => cell.0.clone(),
+                        _ => cell.1.clone(),
+                    },
+                    _ => {
+                        let message = match op {
+                            Head(_) => "head of empty list",
+                            _ => "tail of empty list",
+                        };
+                        return Err(self.error(location, message));
+                    }
+                },
+                IsEmpty => Value::Bool(matches!(pop!(), Value::Nil)),
+                Closure(function, n) | Lazy(function, n) | Spawn(function, n) => {
+                    let captured = stack.split_off(stack.len() - n as usize);
+                    let closure = Rc::new(self::Closure { function, captured });
+                    match op {
+                        Closure(..) => Value::Fun(closure),
+                        Lazy(..) => Value::Lazy(Rc::new(RefCell::new(Thunk::Delayed(closure)))),
+                        _ => {
+                            let mut spawned = Thread::default();
+                            let frame = self.enter(&mut spawned.stack, closure, None);
+                            spawned.frames.push(frame);
+                            self.threads.push(Some(spawned));
+                            self.ready.push_back(self.threads.len() - 1);
+                            Value::Unit
+                        }
+                    }
+                }
+                Force(location) => match pop!() {
+                    Value::Lazy(thunk) => {
+                        let state = std::mem::replace(&mut *thunk.borrow_mut(), Thunk::Forcing);
+                        match state {
+                            Thunk::Delayed(closure) => {
+                                Self::push(&mut thread.frames, frame)?;
+                                frame = self.enter(stack, closure, None);
+                                frame.forcing = Some(thunk);
+                                code = &functions[frame.closure.function as usize].code[..];
+                                continue;
+                            }
+                            Thunk::Forcing => {
+                                return Err(self.error(
+                                    location,
+                                    "lazy value forced while it was being computed",
+                                ))
+                            }
+                            Thunk::Forced(value) => {
+                                *thunk.borrow_mut() = Thunk::Forced(value.clone());
+                                value
+                            }
+                        }
+                    }
+                    _ => unreachable!(),
+                },
+                Chan => Value::Chan(Rc::new(RefCell::new(Channel::default()))),
+                Send(location) => {
+                    let value = pop!();
+                    let channel = match pop!() {
+                        Value::Chan(channel) => channel,
+                        _ => unreachable!(),
+                    };
+                    stack.push(Value::Unit);
+                    let receiver = channel.borrow_mut().receivers.pop_front();
+                    match receiver {
+                        // what a thread receives is left on top of its stack
+                        // for it, as that's where 'Recv' leaves it
+                        Some(receiver) => {
+                            let waiting = self.threads[receiver].as_mut().unwrap();
+                            waiting.stack.push(value);
+                            self.ready.push_back(receiver);
+                        }
+                        None => {
+                            channel.borrow_mut().senders.push_back((number, value));
+                            thread.frames.push(frame);
+                            return Ok(Stop::Waiting(location));
+                        }
+                    }
+                    continue;
+                }
+                Recv(location) => {
+                    let channel = match pop!() {
+                        Value::Chan(channel) => channel,
+                        _ => unreachable!(),
+                    };
+                    let sender = channel.borrow_mut().senders.pop_front();
+                    match sender {
+                        Some((sender, value)) => {
+                            self.ready.push_back(sender);
+                            value
+                        }
+                        None => {
+                            channel.borrow_mut().receivers.push_back(number);
+                            thread.frames.push(frame);
+                            return Ok(Stop::Waiting(location));
+                        }
+                    }
+                }
+                Try(target) => {
+                    thread.handlers.push(Handler {
+                        frames: thread.frames.len(),
+                        height: stack.len(),
+                        pc: target as usize,
+                    });
+                    continue;
+                }
+                EndTry => {
+                    thread.handlers.pop();
+                    continue;
+                }
+                // the frame that started the 'try' is the one that was running
+                // when it was called from (if anything has been called since)
+                Raise(location) => {
+                    let exception = pop!().int();
+                    let handler = match thread.handlers.pop() {
+                        Some(handler) => handler,
+                        None => {
+                            return Err(
+                                self.error(location, &format!("uncaught exception {}", exception))
+                            )
+                        }
+                    };
+                    if thread.frames.len() > handler.frames {
+                        thread.frames.truncate(handler.frames + 1);
+                        frame = thread.frames.pop().unwrap();
+                        code = &functions[frame.closure.function as usize].code[..];
+                    }
+                    stack.truncate(handler.height);
+                    frame.pc = handler.pc;
+                    Value::Int(exception)
+                }
+                Call | TailCall => {
+                    let arg = pop!();
+                    let closure = match pop!() {
+                        Value::Fun(closure) => closure,
+                        _ => unreachable!(),
+                    };
+                    if let TailCall = op {
+                        stack.truncate(frame.base);
+                        let forcing = frame.forcing.take();
+                        frame = self.enter(stack, closure, Some(arg));
+                        frame.forcing = forcing;
+                    } else {
+                        Self::push(&mut thread.frames, frame)?;
+                        frame = self.enter(stack, closure, Some(arg));
+                    }
+                    code = &functions[frame.closure.function as usize].code[..];
+                    continue;
+                }
+                Return => {
+                    let value = pop!();
+                    stack.truncate(frame.base);
+                    if let Some(thunk) = frame.forcing {
+                        *thunk.borrow_mut() = Thunk::Forced(value.clone());
+                    }
+                    match thread.frames.pop() {
+                        Some(caller) => {
+                            frame = caller;
+                            code = &functions[frame.closure.function as usize].code[..];
+                            value
+                        }
+                        None => return Ok(Stop::Finished(value)),
+                    }
+                }
+            };
+            stack.push(value);
+        }
+    }
+
+    // runs a program to completion (a program ends when its main thread does,
+    // and deadlocks where its main thread is waiting if every thread is
+    // waiting)
+    pub fn run(&mut self) -> Result<Value, String> {
+        let closure = Rc::new(Closure {
+            function: 0,
+            captured: vec![],
+        });
+        let mut main = Thread::default();
+        let frame = self.enter(&mut main.stack, closure, None);
+        main.frames.push(frame);
+        self.threads = vec![Some(main)];
+        self.ready.clear();
+        let mut running = MAIN;
+        let mut waiting = None;
+        loop {
+            let mut thread = self.threads[running].take().unwrap();
+            match self.execute(running, &mut thread)? {
+                Stop::Finished(value) if running == MAIN => return Ok(value),
+                Stop::Finished(_) => (),
+                Stop::Waiting(location) => {
+                    if running == MAIN {
+                        waiting = Some(location);
+                    }
+                    self.threads[running] = Some(thread);
+                }
+            }
+            running = match self.schedule.next(&mut self.ready) {
+                Some(next) => next,
+                None => {
+                    return Err(self.error(
+                        waiting.unwrap(),
+                        "deadlock: every thread is waiting on a channel",
+                    ))
+                }
+            };
+        }
+    }
+}
@@ -0,0 +1,38 @@
+extern crate slang;
+
+mod common;
+
+use slang::{CompilerOptions, Instruction, Representation};
+
+#[test]
+fn parses_every_representation() {
+    for (name, representation) in [
+        ("untagged", Representation::Untagged),
+        ("tagged", Representation::Tagged),
+        ("boxed", Representation::Boxed),
+    ] {
+        let options = CompilerOptions {
+            representation,
+            ..CompilerOptions::default()
+        };
+        for input in common::examples() {
+            let (code, _) = slang::generate(&input, &options).unwrap();
+            let parsed = slang::parse_assembly(&code.to_string());
+            assert!(
+                parsed.is_ok(),
+                "{} ({}): {}",
+                input.display(),
+                name,
+                parsed.err().unwrap()
+            );
+            let calls = |instructions: &[Instruction]| {
+                instructions
+                    .iter()
+                    .filter(|instruction| matches!(instruction, Instruction::CallRuntime(_)))
+                    .count()
+            };
+            let expected = code.instructions().cloned().collect::<Vec<_>>();
+            assert_eq!(calls(&parsed.unwrap()), calls(&expected));
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// helpers shared by the tests that give the compiler whole programs (each test
+// file only uses some of them)
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+static PROGRAMS: AtomicUsize = AtomicUsize::new(0);
+
+// writes a program's source to 'name.slang' in a directory of its own (so that
+// whatever's written alongside it goes too), passing its path to 'f' and
+// removing the directory again once it's done
+pub fn with_source<T>(name: &str, source: &str, f: impl FnOnce(&Path) -> T) -> T {
+    let dir = env::temp_dir().join(format!(
+        "slang-test-{}-{}-{}",
+        std::process::id(),
+        PROGRAMS.fetch_add(1, Ordering::SeqCst),
+        name
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join(format!("{}.slang", name));
+    fs::write(&input, source).unwrap();
+    let result = f(&input);
+    fs::remove_dir_all(&dir).ok();
+    result
+}
+
+// runs 'f' on a thread with as much stack as the compiler gets when it's run
+// from the command line
+pub fn with_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    thread::Builder::new()
+        .stack_size(slang::STACK_SIZE)
+        .spawn(f)
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+// the programs in 'examples' that compile ('if.slang' doesn't type check, as
+// its condition is an 'int'), in order of their names
+pub fn examples() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let mut inputs = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "slang")
+                && !path.ends_with("if.slang")
+        })
+        .collect::<Vec<_>>();
+    inputs.sort();
+    inputs
+}
@@ -0,0 +1,38 @@
+extern crate slang;
+
+mod common;
+
+use common::with_source;
+use slang::CompilerOptions;
+
+// type checks a program, giving the error it's rejected with (if it is)
+fn check(name: &str, source: &str) -> Result<(), String> {
+    with_source(name, source, |input| {
+        slang::check(input, &CompilerOptions::default()).map(|_| ())
+    })
+}
+
+#[test]
+fn ordered_types() {
+    // everything the message for '<' says it can compare
+    let source = "
+let a : {x: int, y: float} = {x = 1, y = 2.0} in
+let b : {x: int, y: float} = {x = 1, y = 3.0} in
+a < b && (1 :: []) < (2 :: []) && (1, true, \"a\") < (1, true, \"b\")
+  && (inl int true) < (inr bool 2) && ((), 1.5) < ((), 2.5)
+end end
+";
+    assert_eq!(check("ordered", source), Ok(()));
+}
+
+#[test]
+fn unordered_types() {
+    let error = check("unordered", "(1, ref 2) < (1, ref 3)").unwrap_err();
+    assert!(
+        error.contains(
+            "'<' can only compare ints, floats, bools, strings, '()', and pairs, tuples, records, lists and injections of them, found 'int * int ref', which contains 'int ref'"
+        ),
+        "{}",
+        error
+    );
+}
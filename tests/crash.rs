@@ -0,0 +1,88 @@
+extern crate slang;
+
+mod common;
+
+use common::with_source;
+use slang::CompilerOptions;
+use std::process::Command;
+
+const UNBOUNDED: &str =
+    "let f (n : int) : int = if n = 0 then 0 else 1 + f (n - 1) end in f 100000000 end";
+
+// what a program that crashes is reported as, compiled into memory
+fn crash(name: &str, source: &str, options: CompilerOptions) -> String {
+    with_source(name, source, |input| slang::jit(input, &options)).unwrap_err()
+}
+
+#[test]
+fn stack_overflow() {
+    let error = crash("overflow", UNBOUNDED, CompilerOptions::default());
+    assert!(error.contains("likely a stack overflow"), "{}", error);
+}
+
+#[test]
+fn stack_overflow_with_comments() {
+    // with '-C' every value is kept in the frame, which is written above the
+    // stack pointer, so the fault isn't just below it
+    let options = CompilerOptions {
+        comments: true,
+        ..CompilerOptions::default()
+    };
+    let error = crash("commented", UNBOUNDED, options);
+    assert!(error.contains("likely a stack overflow"), "{}", error);
+}
+
+#[test]
+fn curried_stack_overflow() {
+    // the fault is in the runtime, which applies the curried function (this is
+    // linked into an executable, as a crash in the runtime's allocator can't be
+    // recovered from in memory)
+    let source = "let f (a : int) (b : int) : int = if a = 0 then b else 1 + f (a - 1) b end in f 100000000 1 end";
+    let error = with_source("curried", source, |input| {
+        let compiled = Command::new(env!("CARGO_BIN_EXE_slang"))
+            .arg("-L")
+            .arg(input)
+            .output()
+            .unwrap();
+        assert!(compiled.status.success());
+        let run = Command::new(input.with_extension("")).output().unwrap();
+        String::from_utf8(run.stderr).unwrap()
+    });
+    assert!(error.contains("likely a stack overflow"), "{}", error);
+}
+
+// what the interpreter makes of a program, on a thread with 'stack' bytes of
+// stack
+fn interpret(name: &str, source: &str, stack: usize) -> Result<slang::Value, String> {
+    let source = source.to_string();
+    let name = name.to_string();
+    std::thread::Builder::new()
+        .stack_size(stack)
+        .spawn(move || {
+            with_source(&name, &source, |input| {
+                slang::interpret(input, slang::Input::Random(0), &CompilerOptions::default())
+            })
+        })
+        .unwrap()
+        .join()
+        .unwrap()
+        .map(|(value, _)| value)
+}
+
+#[test]
+fn interpreted_stack_overflow() {
+    // however much stack the interpreter is given, running out of it fails the
+    // program rather than the interpreter
+    for stack in [4 << 20, slang::STACK_SIZE] {
+        let error = interpret("interpreted", UNBOUNDED, stack).unwrap_err();
+        assert!(error.contains("recursion limit exceeded"), "{}", error);
+    }
+}
+
+#[test]
+fn interpreted_recursion() {
+    let source =
+        "let sum (n : int) : int = if n = 0 then 0 else n + sum (n - 1) end in sum 4000 end";
+    let value = interpret("sum", source, slang::STACK_SIZE).unwrap();
+    assert_eq!(value, 8002000i64.into());
+}
@@ -0,0 +1,75 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// what the daemon answers to some lines of requests
+fn answers(requests: &str) -> Vec<String> {
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_slang"))
+        .arg("daemon")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    daemon
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(requests.as_bytes())
+        .unwrap();
+    let output = daemon.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+const RUN: &str =
+    r#"{"jsonrpc": "2.0", "id": 1, "method": "run", "params": {"source": "? + 1", "input": [41]}}"#;
+
+#[test]
+fn deeply_nested_request() {
+    // far deeper than there's stack to read it recursively
+    let nested = format!("{}{}", "[".repeat(3_000_000), "]".repeat(3_000_000));
+    let answers = answers(&format!("{}\n{}\n", nested, RUN));
+    assert_eq!(answers.len(), 2, "{:?}", answers);
+    assert!(answers[0].contains("-32700"), "{}", answers[0]);
+    assert!(
+        answers[0].contains("nested more than 128 deep"),
+        "{}",
+        answers[0]
+    );
+    // and the daemon carries on answering
+    assert!(answers[1].contains(r#""value":"42""#), "{}", answers[1]);
+}
+
+// a request for 'check' with an extra parameter nested in arrays, so that it's
+// two deeper than that in all
+fn nested(arrays: usize) -> String {
+    format!(
+        r#"{{"jsonrpc": "2.0", "id": 1, "method": "check", "params": {{"source": "1", "extra": {}{}}}}}"#,
+        "[".repeat(arrays),
+        "]".repeat(arrays)
+    )
+}
+
+#[test]
+fn nested_as_deep_as_allowed() {
+    let answers = answers(&format!("{}\n{}\n", nested(126), nested(127)));
+    assert!(answers[0].contains(r#""success":true"#), "{}", answers[0]);
+    assert!(answers[1].contains("-32700"), "{}", answers[1]);
+}
+
+#[test]
+fn unbounded_recursion() {
+    let recursion = r#"{"jsonrpc": "2.0", "id": 1, "method": "run", "params": {"source": "let f (n : int) : int = 1 + f n in f 1 end"}}"#;
+    let answers = answers(&format!("{}\n{}\n", recursion, RUN));
+    assert_eq!(answers.len(), 2, "{:?}", answers);
+    assert!(
+        answers[0].contains("recursion limit exceeded"),
+        "{}",
+        answers[0]
+    );
+    // and the daemon carries on answering
+    assert!(answers[1].contains(r#""value":"42""#), "{}", answers[1]);
+}
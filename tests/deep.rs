@@ -0,0 +1,91 @@
+extern crate slang;
+
+mod common;
+
+use common::{with_source, with_stack};
+use slang::{CompilerOptions, GeneratedCode};
+use std::time::{Duration, Instant};
+
+// far longer than any of these take to compile (even unoptimised), but far less
+// than they take if anything in the backend is quadratic in the program's length
+const TIME_LIMIT: Duration = Duration::from_secs(30);
+
+// compiles a program to assembly (with the default options, so through the
+// register allocator) on a thread with as much stack as the compiler usually
+// gets, checking that it doesn't take too long
+fn generate(name: &str, source: String) -> Result<GeneratedCode, String> {
+    let start = Instant::now();
+    let result = with_source(name, &source, |input| {
+        let input = input.to_path_buf();
+        with_stack(move || slang::generate(&input, &CompilerOptions::default()))
+    });
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed < TIME_LIMIT,
+        "compiling '{}' took {:?}",
+        name,
+        elapsed
+    );
+    result.map(|(code, warnings)| {
+        assert!(warnings.is_empty());
+        code
+    })
+}
+
+#[test]
+fn long_sum() {
+    let source = vec!["1"; 10_000].join(" + ");
+    assert!(generate("sum", source).is_ok());
+}
+
+#[test]
+fn long_sequence() {
+    let source = format!(
+        "let r : int ref = ref 0 in begin {}; !r end end",
+        vec!["r := !r + 1"; 20_000].join("; ")
+    );
+    let code = generate("sequence", source).unwrap();
+    // each statement's temporaries go out of use before the next, so the frame
+    // doesn't grow with the number of statements
+    assert!(
+        code.frames()[0] <= 256,
+        "frame of {} bytes",
+        code.frames()[0]
+    );
+}
+
+#[test]
+fn wide_tuple() {
+    // every component is computed before the tuple is built, so they're all
+    // live at once
+    let source = format!(
+        "let r : int ref = ref 0 in #1 ({}) end",
+        vec!["!r + 1"; 10_000].join(", ")
+    );
+    assert!(generate("tuple", source).is_ok());
+}
+
+#[test]
+fn many_definitions() {
+    // each function calls the one before, so none of them are unused
+    let functions = (1..5_000)
+        .map(|i| format!("let f{} (x : int) : int = f{} x + 1\n", i, i - 1))
+        .collect::<String>();
+    let source = format!(
+        "let f0 (x : int) : int = x\n{}let main = f4999 0",
+        functions
+    );
+    assert!(generate("definitions", source).is_ok());
+}
+
+#[test]
+fn far_component() {
+    let source = format!(
+        "let t = ({}) in #10000 t end",
+        (0..10_000)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    assert!(generate("component", source).is_ok());
+}
@@ -0,0 +1,57 @@
+extern crate slang;
+
+mod common;
+
+use common::{with_source, with_stack};
+use slang::CompilerOptions;
+
+// checks a program on a thread with as much stack as the compiler usually gets
+fn check(name: &str, source: String) -> Result<Vec<String>, String> {
+    with_source(name, &source, |input| {
+        let input = input.to_path_buf();
+        with_stack(move || slang::check(&input, &CompilerOptions::default()))
+    })
+}
+
+fn bracketed(levels: usize) -> String {
+    format!("{}1{}", "(".repeat(levels), ")".repeat(levels))
+}
+
+#[test]
+fn brackets_count_once() {
+    assert!(check("half", bracketed(256)).is_ok());
+}
+
+#[test]
+fn nested_as_deep_as_allowed() {
+    // the whole program is one level, and each pair of brackets another
+    assert!(check("limit", bracketed(511)).is_ok());
+    let error = check("over", bracketed(512)).unwrap_err();
+    assert!(error.contains("column 513"), "{}", error);
+    assert!(
+        error.contains("expression too deeply nested (the limit is 512)"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn operators_count_as_deep_as_they_nest() {
+    // as 'check_depth' counts them, each negation is a level
+    let negated = format!("{}1", "- ".repeat(511));
+    assert!(check("negated", negated).is_ok());
+    let error = check("negated-over", format!("{}1", "- ".repeat(512))).unwrap_err();
+    assert!(error.contains("(the limit is 512)"), "{}", error);
+}
+
+#[test]
+fn definitions_count_once() {
+    // a program of top-level definitions (or a module of them) is one long
+    // chain of 'let's, but they're all at the same level
+    let definitions = (0..5_000)
+        .map(|i| format!("let x{} : int = {}\n", i, i))
+        .collect::<String>();
+    assert!(check("definitions", format!("{}let main = x4999", definitions)).is_ok());
+    let module = format!("module M = struct\n{}end in M.x4999 end", definitions);
+    assert!(check("module", module).is_ok());
+}
@@ -0,0 +1,84 @@
+extern crate slang;
+
+mod common;
+
+use common::with_source;
+use slang::CompilerOptions;
+use std::fs;
+
+// the decision trees written out for a program's matches
+fn trees(name: &str, source: &str) -> String {
+    with_source(name, source, |input| {
+        let output = input.with_extension("match-tree");
+        let warnings =
+            slang::compile_match_trees(input, &output, &CompilerOptions::default()).unwrap();
+        assert!(warnings.is_empty());
+        fs::read_to_string(&output).unwrap()
+    })
+}
+
+// the last arm is reached from four places (whenever an earlier one fails to
+// match), and is big enough to be shared
+const SHARED: &str = "
+let f (x : int * (bool + int)) (l : int list) : int = match (x, l) with
+  | ((0, inl true), _) -> 1
+  | ((_, inr n), []) -> n
+  | ((1, _), h :: _) -> h
+  | (_, _) -> let a = 1 in let b = 2 in a * b + a * a + b * b - a - b + 7 end end
+end in
+f (2, inl int false) [] + f (0, inl int true) (3 :: []) + f (5, inr bool 4) []
+  + f (1, inl int false) (9 :: []) + f (1, inr bool 2) (5 :: []) + f (7, inr bool 2) (5 :: [])
+end
+";
+
+#[test]
+fn shares_repeated_arms() {
+    let trees = trees("shared", SHARED);
+    assert_eq!(trees.matches("join match.1.k1 =").count(), 1, "{}", trees);
+    assert_eq!(trees.matches("jump match.1.k1").count(), 4, "{}", trees);
+    assert_eq!(trees.matches("arm 4:").count(), 1, "{}", trees);
+    assert_eq!(slang::jit_run(SHARED), Ok(41));
+}
+
+#[test]
+fn copies_arms_that_call_last() {
+    // sharing the last arm would stop 'f (d, c)' being a call in tail
+    // position
+    let source = "
+let rec f (x : int * bool) : int = match x with
+  | (0, _) -> 0
+  | (1, true) -> 1
+  | (n, b) -> let m = n - 1 in let c = if b then false else true end in let d = m + 0 in f (d, c) end end end
+end in f (100000, true) end
+";
+    let trees = trees("tail", source);
+    assert!(!trees.contains("join"), "{}", trees);
+    assert_eq!(trees.matches("arm 3:").count(), 2, "{}", trees);
+    assert_eq!(slang::jit_run(source), Ok(0));
+}
+
+#[test]
+fn tests_the_most_needed_column_first() {
+    // every arm but the last tests the second component, but only the first
+    // tests the first, so the second is tested first (and the first only on
+    // one path)
+    let source = "
+let f (x : int * bool) : int = match x with
+  | (0, true) -> 0
+  | (_, false) -> 1
+  | (_, true) -> 2
+end in f (0, true) end
+";
+    let trees = trees("column", source);
+    let tests = trees
+        .lines()
+        .filter(|line| line.trim_start().starts_with("if "))
+        .map(|line| line.trim())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        tests,
+        vec!["if match.1.2 then", "if match.1.1 = 0 then"],
+        "{}",
+        trees
+    );
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use common::with_source;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// what the REPL prints for some lines of input
+fn session(lines: &str) -> String {
+    let mut repl = Command::new(env!("CARGO_BIN_EXE_slang"))
+        .arg("--repl")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    repl.stdin
+        .take()
+        .unwrap()
+        .write_all(lines.as_bytes())
+        .unwrap();
+    let output = repl.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+const POLYMORPHIC: &str = "let id = fun x -> x end
+let pair : forall 'a 'b. 'a -> 'b -> 'a * 'b = fun a b -> (a, b) end
+let x = 1
+let f = fun y -> (x, y) end
+let x = true
+";
+
+#[test]
+fn polymorphic_definitions() {
+    let output = session(&format!(
+        "{}id 1\nid true\npair 1 \"a\"\nf 2\n",
+        POLYMORPHIC
+    ));
+    assert!(output.contains("id : forall 'a. 'a -> 'a"), "{}", output);
+    assert!(output.contains("it1 : int = 1"), "{}", output);
+    assert!(output.contains("it2 : bool = true"), "{}", output);
+    assert!(
+        output.contains("it3 : int * string = (1, \"a\")"),
+        "{}",
+        output
+    );
+    // 'f' still refers to the 'x' that was in scope when it was defined
+    assert!(output.contains("it4 : int * int = (1, 2)"), "{}", output);
+}
+
+#[test]
+fn polymorphic_uses_polymorphic() {
+    let output = session(&format!(
+        "{}let g = fun y -> pair (id y) (f y) end\ng false\n",
+        POLYMORPHIC
+    ));
+    assert!(
+        output.contains("it1 : bool * (int * bool) = (false, (1, false))"),
+        "{}",
+        output
+    );
+}
+
+// the session saved (or snapshotted) to a file, then what a new session that
+// opens (or restores) it prints for some more lines of input
+fn reopened(save: &str, open: &str, lines: &str) -> String {
+    with_source("session", "", |input| {
+        let saved = input.with_extension("saved");
+        let saved = saved.display();
+        session(&format!("{}{} {}\n", POLYMORPHIC, save, saved));
+        session(&format!("{} {}\n{}", open, saved, lines))
+    })
+}
+
+#[test]
+fn saved_polymorphic_definitions() {
+    let output = reopened(":save", ":open", "id 1\npair true 2\nf \"a\"\n");
+    assert!(output.contains("it1 : int = 1"), "{}", output);
+    assert!(
+        output.contains("it2 : bool * int = (true, 2)"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("it3 : int * string = (1, \"a\")"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn snapshotted_polymorphic_definitions() {
+    let output = reopened(":snapshot", ":restore", "id 1\npair true 2\nf \"a\"\n");
+    assert!(output.contains("it1 : int = 1"), "{}", output);
+    assert!(
+        output.contains("it2 : bool * int = (true, 2)"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("it3 : int * string = (1, \"a\")"),
+        "{}",
+        output
+    );
+}
@@ -0,0 +1,39 @@
+extern crate slang;
+
+mod common;
+
+use slang::CompilerOptions;
+use std::path::Path;
+
+// how many callee-saved registers the functions in a program save, and how many
+// of those some path through them skips saving
+fn saved_registers(input: &Path) -> (usize, usize) {
+    let (code, _) = slang::generate(input, &CompilerOptions::default()).unwrap();
+    code.functions()
+        .iter()
+        .map(|function| function.saved_registers())
+        .fold((0, 0), |(a, b), (c, d)| (a + c, b + d))
+}
+
+#[test]
+fn examples() {
+    let mut counts = vec![];
+    for input in common::examples() {
+        let saved = saved_registers(&input);
+        counts.push((input.file_name().unwrap().to_owned(), saved));
+    }
+    let saving = counts.iter().filter(|(_, (saved, _))| *saved > 0).count();
+    let saved = counts.iter().map(|(_, (saved, _))| saved).sum::<usize>();
+    let skipped = counts
+        .iter()
+        .map(|(_, (_, skipped))| skipped)
+        .sum::<usize>();
+    // the only one skipped is in 'fib', where a register only needed across the
+    // recursive calls is only saved on the path that makes them
+    assert_eq!(
+        (counts.len(), saving, saved, skipped),
+        (9, 6, 20, 1),
+        "{:?}",
+        counts
+    );
+}
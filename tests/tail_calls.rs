@@ -0,0 +1,67 @@
+extern crate slang;
+
+mod common;
+
+use common::{with_source, with_stack};
+use slang::{CompilerOptions, Limits, TestCase};
+use std::process::Command;
+
+// what a program prints, linked into an executable (which runs on the usual
+// 8MB stack, unlike the compiler's threads)
+fn run(name: &str, source: &str) -> String {
+    with_source(name, source, |input| {
+        let compiled = Command::new(env!("CARGO_BIN_EXE_slang"))
+            .arg("-L")
+            .arg(input)
+            .output()
+            .unwrap();
+        assert!(compiled.status.success());
+        let run = Command::new(input.with_extension("")).output().unwrap();
+        assert!(
+            run.status.success(),
+            "{}",
+            String::from_utf8_lossy(&run.stderr)
+        );
+        String::from_utf8(run.stdout).unwrap()
+    })
+}
+
+#[test]
+fn curried() {
+    let source = "let loop (n : int) (acc : int) : int = if n = 0 then acc else loop (n - 1) (acc + n) end in loop 1000000 0 end";
+    assert_eq!(run("loop", source).trim(), "500000500000");
+}
+
+#[test]
+fn curried_arguments_swapped() {
+    // each argument is one of the parameters it replaces, so they're all read
+    // before any is written
+    let source = "let swap (a : int) (b : int) (k : int) : int = if k = 0 then a - b else swap b a (k - 1) end in swap 5 3 1000001 end";
+    assert_eq!(run("swap", source).trim(), "-2");
+}
+
+const LOOP: &str = "let loop (n : int) (acc : int) : int = if n = 0 then acc else loop (n - 1) (acc + n) end in loop 300000 0 end";
+
+#[test]
+fn interpreted() {
+    // far deeper than the interpreter could recurse, even on the compiler's
+    // stack, so each call has to carry on from the one before
+    let value = with_source("interpreted", LOOP, |input| {
+        let input = input.to_path_buf();
+        with_stack(move || {
+            slang::interpret(&input, slang::Input::Random(0), &CompilerOptions::default())
+        })
+    });
+    assert_eq!(value.unwrap().0, 45000150000i64.into());
+}
+
+#[test]
+fn graded() {
+    let case = TestCase {
+        input: vec![],
+        expected: 45000150000i64.into(),
+        schedule: None,
+    };
+    let report = slang::grade(LOOP, vec![case], Limits::default()).unwrap();
+    assert!(report.all_passed());
+}